@@ -0,0 +1,101 @@
+use std::time::Duration;
+
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_QUEUE_CAPACITY: usize = 256;
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(45);
+// where the server listens in development
+pub const DEFAULT_URL: &str = "ws://127.0.0.1:9797/ws";
+
+/// How a Client connects to the server. The defaults are the web client's.
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    pub(crate) url: String,
+    pub(crate) auto_reconnect: bool,
+    pub(crate) initial_backoff: Duration,
+    pub(crate) max_backoff: Duration,
+    pub(crate) max_attempts: Option<u32>,
+    pub(crate) heartbeat_interval: Duration,
+    pub(crate) heartbeat_timeout: Duration,
+    pub(crate) request_timeout: Duration,
+    pub(crate) queue_capacity: usize,
+    pub(crate) bearer_token: Option<String>,
+}
+
+impl ClientConfig {
+    /// Connect to the server's WebSocket endpoint, ws://, or to the Unix domain socket
+    /// it listens on with --unix-socket, eg. unix:/run/hydra/hydra.sock
+    pub fn new(url: &str) -> ClientConfig {
+        ClientConfig {
+            url: url.to_string(),
+            auto_reconnect: true,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+            max_attempts: None,
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            heartbeat_timeout: DEFAULT_HEARTBEAT_TIMEOUT,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            bearer_token: None,
+        }
+    }
+
+    /// Whether to reconnect, with backoff, whenever the connection drops. On by default. When
+    /// off, the client stays disconnected until Client::reconnect is called.
+    pub fn auto_reconnect(mut self, enabled: bool) -> ClientConfig {
+        self.auto_reconnect = enabled;
+        self
+    }
+
+    /// Wait `initial` before the first attempt to reconnect, doubling the wait after every
+    /// failed attempt up to `max`. Half a second up to 10 seconds by default.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> ClientConfig {
+        self.initial_backoff = initial;
+        self.max_backoff = max.max(initial);
+        self
+    }
+
+    /// Give up after this many attempts to reconnect in a row fail, leaving the client
+    /// disconnected until Client::reconnect is called. Unlimited by default.
+    pub fn max_attempts(mut self, attempts: u32) -> ClientConfig {
+        self.max_attempts = Some(attempts);
+        self
+    }
+
+    /// Ping the server every `interval` while connected, and drop the connection, to be
+    /// reconnected, once nothing has come back from the server for `timeout`. Every 15 seconds,
+    /// with a timeout of 45, by default, and a zero interval turns it off. The server closes
+    /// connections which send nothing for a minute, so a client which only listens for pushes
+    /// needs it.
+    pub fn heartbeat(mut self, interval: Duration, timeout: Duration) -> ClientConfig {
+        self.heartbeat_interval = interval;
+        self.heartbeat_timeout = timeout;
+        self
+    }
+
+    /// How long send_request waits for a response before giving up. 30 seconds by default.
+    pub fn request_timeout(mut self, timeout: Duration) -> ClientConfig {
+        self.request_timeout = timeout;
+        self
+    }
+
+    /// How many requests to hold while disconnected. Once there are that many, the oldest is
+    /// dropped, failing as Cancelled, to make room for each new one. 256 by default.
+    pub fn queue_capacity(mut self, capacity: usize) -> ClientConfig {
+        self.queue_capacity = capacity;
+        self
+    }
+
+    /// Send `token` as an `Authorization: Bearer` header whenever connecting, eg. the server's
+    /// admin token, which requests such as DeleteIngressLog need. None by default.
+    pub fn bearer_token(mut self, token: &str) -> ClientConfig {
+        self.bearer_token = Some(token.to_string());
+        self
+    }
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self::new(DEFAULT_URL)
+    }
+}
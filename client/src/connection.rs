@@ -0,0 +1,266 @@
+//! The task which owns the WebSocket: it writes what the client sends, hands what arrives to the
+//! client, keeps the heartbeat, and reconnects when the connection drops. The WebSocket runs over
+//! TCP, or over a Unix domain socket for a server on the same host listening with --unix-socket.
+
+use std::{
+    sync::{atomic::Ordering, Weak},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use futures_util::{Sink, SinkExt, StreamExt};
+use hydra_proto as proto;
+use log::{error, info, warn};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::mpsc,
+    time::Interval,
+};
+use tokio_tungstenite::{
+    tungstenite::{
+        self,
+        client::IntoClientRequest,
+        error::UrlError,
+        http::{header, HeaderValue},
+    },
+    WebSocketStream,
+};
+
+use crate::{ClientConfig, ConnectionState, Inner};
+
+/// The start of the URL of a server's Unix domain socket, eg. unix:/run/hydra/hydra.sock
+pub const UNIX_SCHEME: &str = "unix:";
+
+/// What's asked for over a Unix domain socket, whose URL has no room for a host or path
+const UNIX_REQUEST: &str = "ws://localhost/ws";
+
+/// The stream a WebSocket runs over, TCP or a Unix domain socket
+pub(crate) trait Io: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Io for T {}
+
+pub(crate) type Socket = WebSocketStream<Box<dyn Io>>;
+
+pub(crate) async fn open(url: &str, bearer_token: Option<&str>) -> hydra_error::Result<Socket> {
+    let unavailable = |e: &dyn std::fmt::Display| {
+        hydra_error::Error::unavailable(format!("Could not connect to {}: {}", url, e))
+    };
+    let (request, stream) = match url.strip_prefix(UNIX_SCHEME) {
+        Some(path) => (
+            UNIX_REQUEST,
+            connect_unix(path).await.map_err(|e| unavailable(&e))?,
+        ),
+        None => (url, connect_tcp(url).await.map_err(|e| unavailable(&e))?),
+    };
+    let mut request = request.into_client_request().map_err(|e| unavailable(&e))?;
+    if let Some(token) = bearer_token {
+        let value = HeaderValue::from_str(&format!("Bearer {}", token)).map_err(|_| {
+            hydra_error::Error::invalid_request("The bearer token isn't a valid header value")
+        })?;
+        request.headers_mut().insert(header::AUTHORIZATION, value);
+    }
+    match tokio_tungstenite::client_async(request, stream).await {
+        Ok((socket, _)) => Ok(socket),
+        Err(e) => Err(unavailable(&e)),
+    }
+}
+
+async fn connect_tcp(url: &str) -> Result<Box<dyn Io>, tungstenite::Error> {
+    let request = url.into_client_request()?;
+    let uri = request.uri();
+    // the client is built without TLS
+    if uri.scheme_str() == Some("wss") {
+        return Err(tungstenite::Error::Url(UrlError::TlsFeatureNotEnabled));
+    }
+    let port = uri.port_u16().unwrap_or(80);
+    // without the brackets around an IPv6 address
+    let host = (uri.host().unwrap_or_default())
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    Ok(Box::new(TcpStream::connect((host, port)).await?))
+}
+
+#[cfg(unix)]
+async fn connect_unix(path: &str) -> std::io::Result<Box<dyn Io>> {
+    Ok(Box::new(tokio::net::UnixStream::connect(path).await?))
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(_path: &str) -> std::io::Result<Box<dyn Io>> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "Unix domain sockets aren't available on this platform",
+    ))
+}
+
+pub(crate) fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Serve connections for as long as the client is around, reconnecting as configured. Only
+/// holds on to the client while it's handling something, so dropping the client ends it.
+pub(crate) async fn run(inner: Weak<Inner>, mut socket: Socket) {
+    loop {
+        serve(&inner, socket).await;
+        let Some(client) = inner.upgrade() else {
+            return;
+        };
+        client.connection_lost();
+        if !client.config.auto_reconnect {
+            client.stopped();
+            return;
+        }
+        let config = client.config.clone();
+        drop(client);
+        socket = match reconnect(&inner, &config).await {
+            Some(socket) => socket,
+            None => return,
+        };
+    }
+}
+
+/// Try to connect again, backing off between attempts, until it works, the client is dropped or
+/// it runs out of attempts
+async fn reconnect(inner: &Weak<Inner>, config: &ClientConfig) -> Option<Socket> {
+    let mut delay = config.initial_backoff;
+    let mut attempts = 0;
+    loop {
+        tokio::time::sleep(delay).await;
+        inner.upgrade()?.set_state(ConnectionState::Connecting);
+        let url = inner.upgrade()?.url();
+        match open(&url, config.bearer_token.as_deref()).await {
+            Ok(socket) => return Some(socket),
+            Err(e) => warn!("reconnect: {}", e),
+        }
+        let client = inner.upgrade()?;
+        client.set_state(ConnectionState::Closed);
+        attempts += 1;
+        if config.max_attempts.is_some_and(|max| attempts >= max) {
+            warn!("reconnect: giving up after {} attempts", attempts);
+            client.stopped();
+            return None;
+        }
+        delay = (delay * 2).min(config.max_backoff);
+    }
+}
+
+/// Carry messages both ways on one connection until it drops
+async fn serve(inner: &Weak<Inner>, socket: Socket) {
+    let (sender, mut outgoing) = mpsc::unbounded_channel();
+    let (interval, timeout) = match inner.upgrade() {
+        Some(client) => {
+            client.opened(sender);
+            (
+                client.config.heartbeat_interval,
+                client.config.heartbeat_timeout,
+            )
+        }
+        None => return,
+    };
+    info!("serve: connected");
+    let (mut sink, mut stream) = socket.split();
+    let mut heartbeat = (!interval.is_zero())
+        .then(|| tokio::time::interval_at(tokio::time::Instant::now() + interval, interval));
+    let mut last_heard = Instant::now();
+    let mut next_ping = 0;
+    loop {
+        tokio::select! {
+            frame = stream.next() => {
+                let bytes = match frame {
+                    Some(Ok(tungstenite::Message::Binary(bytes))) => bytes,
+                    Some(Ok(tungstenite::Message::Close(frame))) => {
+                        info!("serve: the server closed the connection: {:?}", frame);
+                        break;
+                    }
+                    Some(Ok(_)) => {
+                        last_heard = Instant::now();
+                        continue;
+                    }
+                    Some(Err(e)) => {
+                        warn!("serve: connection failed: {}", e);
+                        break;
+                    }
+                    None => break,
+                };
+                last_heard = Instant::now();
+                let Some(client) = inner.upgrade() else {
+                    break;
+                };
+                let reply = match bincode::deserialize(&bytes) {
+                    Ok(message) => client.handle_message(message),
+                    Err(e) => {
+                        error!("serve: failed to decode message: {}", e);
+                        None
+                    }
+                };
+                let going_away = client.going_away.swap(false, Ordering::SeqCst);
+                drop(client);
+                if let Some(reply) = reply {
+                    if let Err(e) = send(&mut sink, &reply).await {
+                        warn!("serve: failed to send: {}", e);
+                        break;
+                    }
+                }
+                if going_away {
+                    let _ = sink.close().await;
+                    break;
+                }
+            }
+            message = outgoing.recv() => match message {
+                Some(message) => {
+                    if let Err(e) = send(&mut sink, &message).await {
+                        warn!("serve: failed to send: {}", e);
+                        break;
+                    }
+                }
+                // the client has been dropped
+                None => {
+                    let _ = sink.close().await;
+                    break;
+                }
+            },
+            _ = tick(&mut heartbeat) => {
+                if last_heard.elapsed() > timeout {
+                    warn!("serve: nothing heard from the server for {:?}, dropping the connection", timeout);
+                    break;
+                }
+                let ping = proto::Ping {
+                    sequence: next_ping,
+                    sent_at: unix_millis(),
+                };
+                next_ping += 1;
+                if let Err(e) = send(&mut sink, &proto::Message::Ping(ping)).await {
+                    warn!("serve: failed to send: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+async fn send<S>(sink: &mut S, message: &proto::Message) -> Result<(), tungstenite::Error>
+where
+    S: Sink<tungstenite::Message, Error = tungstenite::Error> + Unpin,
+{
+    let bytes = match bincode::serialize(message) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            error!("send: failed to encode message: {}", e);
+            return Ok(());
+        }
+    };
+    sink.send(tungstenite::Message::Binary(bytes)).await
+}
+
+/// The next heartbeat, or never if they're turned off
+async fn tick(heartbeat: &mut Option<Interval>) {
+    match heartbeat {
+        Some(heartbeat) => {
+            heartbeat.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
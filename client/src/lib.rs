@@ -0,0 +1,769 @@
+//! A native client for hydra, for backend services and command line tools rather than browsers.
+//! It speaks the same WebSocket protocol as the web client, over tokio-tungstenite, and has the
+//! same request, response and subscription API: requests are queued while disconnected and sent
+//! once connected, fail with a RequestError if no response comes, and subscription handlers are
+//! told to resync whenever the client notices it may have missed pushes. The connection is pinged
+//! to keep it alive, and reconnected with backoff when it drops, or when the server says it's
+//! going away.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+pub use hydra_proto as proto;
+use log::{debug, info, warn};
+use tokio::sync::{mpsc, oneshot, watch};
+use ulid::Ulid;
+
+pub use config::{ClientConfig, DEFAULT_URL};
+pub use connection::UNIX_SCHEME;
+
+mod config;
+mod connection;
+
+/// The state of the client's connection to the server
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ConnectionState {
+    Connecting,
+    Open,
+    Closed,
+}
+
+/// Why a request didn't get a response
+#[derive(Clone, PartialEq, Debug)]
+pub enum RequestError {
+    /// No response arrived within the request timeout
+    Timeout,
+    /// The request was abandoned, e.g. because it was dropped from a full outgoing queue
+    Cancelled,
+    /// The connection closed after the request was sent, so its response will never arrive
+    Disconnected,
+    /// The server responded with an error
+    Rejected(proto::ErrorPayload),
+}
+
+impl RequestError {
+    fn unexpected_response() -> Self {
+        RequestError::Rejected(proto::ErrorPayload::new(
+            proto::ErrorKind::Internal,
+            "Unexpected response",
+        ))
+    }
+
+    pub fn kind(&self) -> proto::ErrorKind {
+        match self {
+            RequestError::Timeout => proto::ErrorKind::Unavailable,
+            RequestError::Cancelled => proto::ErrorKind::Cancelled,
+            RequestError::Disconnected => proto::ErrorKind::Unavailable,
+            RequestError::Rejected(payload) => payload.kind,
+        }
+    }
+
+    /// The request field the server found fault with, if it said
+    pub fn field(&self) -> Option<&str> {
+        match self {
+            RequestError::Rejected(payload) => payload.field.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// How long the server asked us to wait before retrying, if it did
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            RequestError::Rejected(payload) => payload.retry_after_ms.map(Duration::from_millis),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestError::Timeout => write!(f, "Request timed out"),
+            RequestError::Cancelled => write!(f, "Request was cancelled"),
+            RequestError::Disconnected => {
+                write!(f, "Connection closed before the response arrived")
+            }
+            RequestError::Rejected(payload) => {
+                write!(f, "Request was rejected: {}", payload.message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+/// Delivered to a subscription's handler
+pub enum SubscriptionEvent {
+    /// A push from the server, e.g. ResponsePayload::IngressLogAppended
+    Push(Box<proto::ResponsePayload>),
+    /// Messages from the server were lost, so pushes may have been missed. Anything built up from
+    /// them should be refetched.
+    Resync,
+}
+
+type SubscriptionHandler = Arc<dyn Fn(SubscriptionEvent) + Send + Sync>;
+
+/// A request awaiting its response
+struct PendingRequest {
+    sender: oneshot::Sender<Result<proto::Response, RequestError>>,
+    // whether it has gone out on the current connection, rather than still being queued
+    sent: bool,
+}
+
+/// Where requests go: out on the connection, while there is one, or else into the queue
+#[derive(Default)]
+struct Link {
+    outgoing: Option<mpsc::UnboundedSender<proto::Message>>,
+    queue: VecDeque<proto::Request>,
+}
+
+struct Inner {
+    config: ClientConfig,
+    // where to connect, which starts out as the config's URL and follows the server's
+    // GoingAway.reconnect_to
+    url: Mutex<String>,
+    // set when the server has said it's going away, telling the connection task to reconnect
+    going_away: AtomicBool,
+    // locked before pending whenever both are
+    link: Mutex<Link>,
+    // Requests awaiting a response, by request id
+    pending: Mutex<HashMap<usize, PendingRequest>>,
+    // Handlers for pushes, by subscription id
+    subscriptions: Mutex<HashMap<usize, SubscriptionHandler>>,
+    // The sequence number we expect on the next message from the server. The server numbers the
+    // messages on each connection, so any other number means some were dropped.
+    next_sequence: AtomicU64,
+    next_request_id: AtomicUsize,
+    state: watch::Sender<ConnectionState>,
+    // whether the connection task is running, or has given up until Client::reconnect
+    running: AtomicBool,
+    // how long the server took to answer the last Ping
+    round_trip: Mutex<Option<Duration>>,
+}
+
+/// A connection to the server. Clones share it, and it's closed once they've all been dropped.
+#[derive(Clone)]
+pub struct Client {
+    inner: Arc<Inner>,
+}
+
+impl Client {
+    /// Connect to the server, failing if it can't be reached. Once connected, the connection is
+    /// kept up as configured.
+    pub async fn connect(config: ClientConfig) -> hydra_error::Result<Client> {
+        let socket = connection::open(&config.url, config.bearer_token.as_deref()).await?;
+        let inner = Arc::new(Inner {
+            url: Mutex::new(config.url.clone()),
+            going_away: AtomicBool::new(false),
+            config,
+            link: Mutex::new(Link::default()),
+            pending: Mutex::new(HashMap::new()),
+            subscriptions: Mutex::new(HashMap::new()),
+            next_sequence: AtomicU64::new(0),
+            next_request_id: AtomicUsize::new(0),
+            state: watch::channel(ConnectionState::Connecting).0,
+            running: AtomicBool::new(true),
+            round_trip: Mutex::new(None),
+        });
+        tokio::spawn(connection::run(Arc::downgrade(&inner), socket));
+        Ok(Client { inner })
+    }
+
+    /// Wait until the connection is open
+    pub async fn ready(&self) {
+        let mut states = self.inner.state.subscribe();
+        // the sender lives as long as the client, so this can't fail
+        let _ = states
+            .wait_for(|state| *state == ConnectionState::Open)
+            .await;
+    }
+
+    /// Connect again straight away after the connection dropped, for clients configured without
+    /// auto_reconnect, or which have run out of attempts. Does nothing while connected or still
+    /// trying to reconnect.
+    pub async fn reconnect(&self) -> hydra_error::Result<()> {
+        if self.inner.running.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        self.inner.set_state(ConnectionState::Connecting);
+        let bearer_token = self.inner.config.bearer_token.as_deref();
+        match connection::open(&self.inner.url(), bearer_token).await {
+            Ok(socket) => {
+                tokio::spawn(connection::run(Arc::downgrade(&self.inner), socket));
+                Ok(())
+            }
+            Err(e) => {
+                self.inner.stopped();
+                Err(e)
+            }
+        }
+    }
+
+    pub fn connection_state(&self) -> ConnectionState {
+        *self.inner.state.borrow()
+    }
+
+    /// The ConnectionState, to be watched for changes, eg. to report reconnections
+    pub fn state_changes(&self) -> watch::Receiver<ConnectionState> {
+        self.inner.state.subscribe()
+    }
+
+    /// How long the server took to answer the last heartbeat Ping, or None if it hasn't
+    /// answered one yet
+    pub fn round_trip(&self) -> Option<Duration> {
+        *self.inner.round_trip.lock().unwrap()
+    }
+
+    /// Send a request and wait for the matching response
+    pub async fn send_request(
+        &self,
+        request: proto::Request,
+    ) -> Result<proto::Response, RequestError> {
+        let request_id = request.id;
+        let (sender, receiver) = oneshot::channel();
+        self.inner.pending.lock().unwrap().insert(
+            request_id,
+            PendingRequest {
+                sender,
+                sent: false,
+            },
+        );
+        self.inner.send_or_queue(request);
+
+        match tokio::time::timeout(self.inner.config.request_timeout, receiver).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(RequestError::Cancelled),
+            Err(_) => {
+                warn!("send_request: request {} timed out", request_id);
+                self.inner.pending.lock().unwrap().remove(&request_id);
+                // so the server doesn't keep working on something nobody is waiting for
+                let cancel =
+                    self.build_request(proto::RequestPayload::Cancel(proto::CancelRequest {
+                        request_id,
+                    }));
+                self.inner.send_or_queue(cancel);
+                Err(RequestError::Timeout)
+            }
+        }
+    }
+
+    /// Build and send a request for the given payload, returning the response payload
+    pub async fn request(
+        &self,
+        payload: proto::RequestPayload,
+    ) -> Result<proto::ResponsePayload, RequestError> {
+        let request = self.build_request(payload);
+        Ok(self.send_request(request).await?.payload)
+    }
+
+    /// Subscribe to pushes for a tree, returning the subscription id. The handler is called with
+    /// each push, and with SubscriptionEvent::Resync if the client notices it may have missed
+    /// some. Handlers are called from the connection's task, so should hand off anything slow,
+    /// eg. over a channel.
+    pub async fn subscribe<F>(&self, tree: &str, handler: F) -> Result<usize, RequestError>
+    where
+        F: Fn(SubscriptionEvent) + Send + Sync + 'static,
+    {
+        self.subscribe_sampled(tree, None, handler).await
+    }
+
+    /// Like subscribe, but with `sample_above` set the server only pushes a sample of the writes
+    /// from any source busier than that many a second, along with IngressLogsSampled summaries of
+    /// what it left out. See proto::SubscribeRequest.
+    pub async fn subscribe_sampled<F>(
+        &self,
+        tree: &str,
+        sample_above: Option<u32>,
+        handler: F,
+    ) -> Result<usize, RequestError>
+    where
+        F: Fn(SubscriptionEvent) + Send + Sync + 'static,
+    {
+        let request = proto::SubscribeRequest {
+            tree: tree.to_string(),
+            sample_above,
+            keys: vec![],
+            debounce_ms: None,
+            prefix: None,
+        };
+        self.subscribe_with(request, handler).await
+    }
+
+    /// Watch particular keys of a tree, which is pushed a KeyChanged whenever one of them is
+    /// written or removed. With a debounce, rapid changes to a key are pushed as one carrying the
+    /// latest value. See proto::SubscribeRequest.
+    pub async fn watch_keys<F>(
+        &self,
+        tree: &str,
+        keys: Vec<Vec<u8>>,
+        debounce: Option<Duration>,
+        handler: F,
+    ) -> Result<usize, RequestError>
+    where
+        F: Fn(SubscriptionEvent) + Send + Sync + 'static,
+    {
+        let request = proto::SubscribeRequest {
+            tree: tree.to_string(),
+            sample_above: None,
+            keys,
+            debounce_ms: debounce.map(|debounce| debounce.as_millis() as u32),
+            prefix: None,
+        };
+        self.subscribe_with(request, handler).await
+    }
+
+    /// Subscribe to every key of a tree starting with `prefix`, which is pushed a RecordChanged
+    /// whenever one of them is written or removed. See proto::SubscribeRequest.
+    pub async fn subscribe_prefix<F>(
+        &self,
+        tree: &str,
+        prefix: Vec<u8>,
+        handler: F,
+    ) -> Result<usize, RequestError>
+    where
+        F: Fn(SubscriptionEvent) + Send + Sync + 'static,
+    {
+        let request = proto::SubscribeRequest {
+            tree: tree.to_string(),
+            sample_above: None,
+            keys: vec![],
+            debounce_ms: None,
+            prefix: Some(prefix),
+        };
+        self.subscribe_with(request, handler).await
+    }
+
+    /// Fetch a page of ingress logs and follow it: `handler` is pushed a LiveQueryItem for every
+    /// log written after the page on the cursor's side of it which matches the filter, until the
+    /// returned subscription id is unsubscribed from. See proto::RequestPayload::LiveQuery.
+    pub async fn live_query<F>(
+        &self,
+        request: proto::FetchIngressLogsRequest,
+        handler: F,
+    ) -> Result<(usize, proto::FetchIngressLogsResponse), RequestError>
+    where
+        F: Fn(SubscriptionEvent) + Send + Sync + 'static,
+    {
+        let payload = proto::RequestPayload::LiveQuery(request);
+        self.subscribe_to(payload, handler, |payload| match payload {
+            proto::ResponsePayload::LiveQuery(page) => Some(page),
+            _ => None,
+        })
+        .await
+    }
+
+    async fn subscribe_with<F>(
+        &self,
+        request: proto::SubscribeRequest,
+        handler: F,
+    ) -> Result<usize, RequestError>
+    where
+        F: Fn(SubscriptionEvent) + Send + Sync + 'static,
+    {
+        let payload = proto::RequestPayload::Subscribe(request);
+        let subscribed = self.subscribe_to(payload, handler, |payload| match payload {
+            proto::ResponsePayload::Subscribed => Some(()),
+            _ => None,
+        });
+        Ok(subscribed.await?.0)
+    }
+
+    /// Make a request which starts a subscription, `accept` picking what's wanted out of its
+    /// response
+    async fn subscribe_to<F, T>(
+        &self,
+        payload: proto::RequestPayload,
+        handler: F,
+        accept: impl FnOnce(proto::ResponsePayload) -> Option<T>,
+    ) -> Result<(usize, T), RequestError>
+    where
+        F: Fn(SubscriptionEvent) + Send + Sync + 'static,
+    {
+        let request = self.build_request(payload);
+        // the request id doubles as the subscription id. Pushes can arrive before the response,
+        // so the handler has to be in place before the request is sent
+        let subscription_id = request.id;
+        self.inner
+            .subscriptions
+            .lock()
+            .unwrap()
+            .insert(subscription_id, Arc::new(handler));
+
+        let result = match self.send_request(request).await {
+            Ok(proto::Response {
+                payload: proto::ResponsePayload::Error(payload),
+                ..
+            }) => Err(RequestError::Rejected(payload)),
+            Ok(response) => accept(response.payload)
+                .map(|accepted| (subscription_id, accepted))
+                .ok_or_else(RequestError::unexpected_response),
+            Err(e) => Err(e),
+        };
+        if result.is_err() {
+            self.inner
+                .subscriptions
+                .lock()
+                .unwrap()
+                .remove(&subscription_id);
+        }
+        result
+    }
+
+    /// Stop receiving pushes for a subscription
+    pub async fn unsubscribe(&self, subscription_id: usize) -> Result<(), RequestError> {
+        self.inner
+            .subscriptions
+            .lock()
+            .unwrap()
+            .remove(&subscription_id);
+        let payload = self
+            .request(proto::RequestPayload::Unsubscribe(
+                proto::UnsubscribeRequest { subscription_id },
+            ))
+            .await?;
+        match payload {
+            proto::ResponsePayload::Unsubscribed => Ok(()),
+            proto::ResponsePayload::Error(payload) => Err(RequestError::Rejected(payload)),
+            _ => Err(RequestError::unexpected_response()),
+        }
+    }
+
+    /// Wrap a payload in a Request with a fresh id. Mutating requests are given an idempotency key
+    /// so that if we have to retry after an ambiguous failure the server won't apply them twice.
+    /// Retries must resend the same Request rather than building a new one. Each is given a
+    /// trace id too, which the server's logs of it carry.
+    pub fn build_request(&self, payload: proto::RequestPayload) -> proto::Request {
+        let id = self.inner.next_request_id.fetch_add(1, Ordering::SeqCst);
+        let idempotency_key = if payload.is_mutation() {
+            Some(Ulid::new())
+        } else {
+            None
+        };
+        let trace_id = Ulid::new();
+        debug!("build_request: request {} has trace id {}", id, trace_id);
+
+        proto::Request {
+            id,
+            idempotency_key,
+            trace_id: Some(trace_id),
+            payload,
+        }
+    }
+}
+
+impl Inner {
+    fn url(&self) -> String {
+        self.url.lock().unwrap().clone()
+    }
+
+    fn set_state(&self, state: ConnectionState) {
+        if self.state.send_replace(state) != state {
+            info!("set_state: {:?}", state);
+        }
+    }
+
+    /// Send a request now if the connection is open, otherwise queue it until it is
+    fn send_or_queue(&self, request: proto::Request) {
+        let mut link = self.link.lock().unwrap();
+        if let Some(outgoing) = &link.outgoing {
+            if let Some(pending) = self.pending.lock().unwrap().get_mut(&request.id) {
+                pending.sent = true;
+            }
+            // if the connection has just gone, the request is failed along with the others sent
+            // on it
+            let _ = outgoing.send(proto::Message::Request(request));
+            return;
+        }
+
+        if link.queue.len() >= self.config.queue_capacity {
+            warn!("send_or_queue: outgoing queue is full, dropping the oldest request");
+            if let Some(dropped) = link.queue.pop_front() {
+                // fails the request rather than leaving it to time out
+                self.pending.lock().unwrap().remove(&dropped.id);
+            }
+        }
+        link.queue.push_back(request);
+    }
+
+    /// A connection has opened: send everything which was queued, then whatever comes next
+    fn opened(&self, outgoing: mpsc::UnboundedSender<proto::Message>) {
+        let mut link = self.link.lock().unwrap();
+        // the server numbers each connection's messages from 0
+        self.next_sequence.store(0, Ordering::SeqCst);
+        let mut pending = self.pending.lock().unwrap();
+        for request in link.queue.drain(..) {
+            // requests which have already timed out aren't worth sending
+            if let Some(waiting) = pending.get_mut(&request.id) {
+                waiting.sent = true;
+                let _ = outgoing.send(proto::Message::Request(request));
+            }
+        }
+        drop(pending);
+        link.outgoing = Some(outgoing);
+        self.set_state(ConnectionState::Open);
+    }
+
+    /// The connection has dropped. The server abandons a connection's requests when it goes
+    /// away, so those sent on it fail. Requests still queued are kept, to be sent once we
+    /// reconnect.
+    fn connection_lost(&self) {
+        self.link.lock().unwrap().outgoing = None;
+        self.set_state(ConnectionState::Closed);
+        let in_flight: Vec<PendingRequest> = {
+            let mut pending = self.pending.lock().unwrap();
+            let ids: Vec<usize> = pending
+                .iter()
+                .filter(|(_, pending)| pending.sent)
+                .map(|(id, _)| *id)
+                .collect();
+            ids.iter().filter_map(|id| pending.remove(id)).collect()
+        };
+        for pending in in_flight {
+            let _ = pending.sender.send(Err(RequestError::Disconnected));
+        }
+    }
+
+    /// The connection task has given up, until Client::reconnect
+    fn stopped(&self) {
+        self.running.store(false, Ordering::SeqCst);
+        self.set_state(ConnectionState::Closed);
+    }
+
+    /// Deal with a message from the server, returning the reply if it needs one
+    fn handle_message(&self, message: proto::Message) -> Option<proto::Message> {
+        match message {
+            proto::Message::Response(response) => {
+                let expected = self
+                    .next_sequence
+                    .swap(response.sequence + 1, Ordering::SeqCst);
+                if response.sequence != expected {
+                    warn!(
+                        "handle_message: expected message {} but got {}, resyncing subscriptions",
+                        expected, response.sequence
+                    );
+                    self.resync();
+                }
+
+                if matches!(
+                    response.payload,
+                    proto::ResponsePayload::IngressLogAppended(_)
+                        | proto::ResponsePayload::IngressLogsSampled(_)
+                        | proto::ResponsePayload::Alert(_)
+                        | proto::ResponsePayload::KeyChanged(_)
+                        | proto::ResponsePayload::RecordChanged(_)
+                        | proto::ResponsePayload::LiveQueryItem(_)
+                ) {
+                    self.push(response);
+                    return None;
+                }
+                if let proto::ResponsePayload::GoingAway(going_away) = &response.payload {
+                    info!("handle_message: the server is going away, reconnecting");
+                    if let Some(url) = &going_away.reconnect_to {
+                        *self.url.lock().unwrap() = url.clone();
+                    }
+                    self.going_away.store(true, Ordering::SeqCst);
+                    return None;
+                }
+                // the request is still pending, and the client doesn't report progress yet
+                if let proto::ResponsePayload::TagProgress(progress) = &response.payload {
+                    debug!(
+                        "request {} has tagged {} of {} logs scanned",
+                        response.request_id, progress.changed, progress.scanned
+                    );
+                    return None;
+                }
+
+                let pending = self.pending.lock().unwrap().remove(&response.request_id);
+                match pending {
+                    // the requester may have given up waiting, which is fine
+                    Some(pending) => {
+                        let _ = pending.sender.send(Ok(response));
+                    }
+                    None => warn!("handle_message: no pending request {}", response.request_id),
+                }
+                None
+            }
+            proto::Message::Ping(ping) => {
+                Some(proto::Message::Pong(ping.pong(connection::unix_millis())))
+            }
+            proto::Message::Pong(pong) => {
+                let elapsed = connection::unix_millis().saturating_sub(pong.ping_sent_at);
+                *self.round_trip.lock().unwrap() = Some(Duration::from_millis(elapsed));
+                None
+            }
+            proto::Message::Request(_) => {
+                warn!("handle_message: unexpected request from server");
+                None
+            }
+        }
+    }
+
+    fn push(&self, response: proto::Response) {
+        // clone the handler out so that it can subscribe or unsubscribe
+        let handler = self
+            .subscriptions
+            .lock()
+            .unwrap()
+            .get(&response.request_id)
+            .cloned();
+        match handler {
+            Some(handler) => handler(SubscriptionEvent::Push(Box::new(response.payload))),
+            None => warn!("push: no subscription {}", response.request_id),
+        }
+    }
+
+    /// Tell every subscription that it may have missed pushes
+    fn resync(&self) {
+        let handlers: Vec<SubscriptionHandler> = self
+            .subscriptions
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect();
+        for handler in handlers {
+            handler(SubscriptionEvent::Resync);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::Message as Frame;
+
+    use super::*;
+
+    /// Stands in for the server: answers GetKv with the key as the value, a watch with two
+    /// pushes, with a message missing between them, and Hello by going away to `moved`, if it's
+    /// given. Anything else drops the connection.
+    async fn fake_server(listener: TcpListener, moved: Option<String>) {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let moved = moved.clone();
+            tokio::spawn(async move {
+                let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+                let mut sequence = 0;
+                while let Some(Ok(Frame::Binary(bytes))) = socket.next().await {
+                    let proto::Message::Request(request) = bincode::deserialize(&bytes).unwrap()
+                    else {
+                        continue;
+                    };
+                    let changed = |changes| {
+                        proto::ResponsePayload::KeyChanged(proto::KeyChanged {
+                            key: b"a".to_vec(),
+                            value: None,
+                            changes,
+                        })
+                    };
+                    let responses = match request.payload {
+                        proto::RequestPayload::GetKv(get) => {
+                            vec![proto::ResponsePayload::GetKv(proto::GetKvResponse {
+                                value: Some(get.key.into_bytes()),
+                            })]
+                        }
+                        proto::RequestPayload::Subscribe(_) => {
+                            vec![proto::ResponsePayload::Subscribed, changed(1), changed(2)]
+                        }
+                        proto::RequestPayload::Hello if moved.is_some() => {
+                            vec![proto::ResponsePayload::GoingAway(proto::GoingAway {
+                                reconnect_to: moved.clone(),
+                                retry_after_ms: 1000,
+                            })]
+                        }
+                        _ => return,
+                    };
+                    let count = responses.len();
+                    for (n, payload) in responses.into_iter().enumerate() {
+                        let response = proto::Response {
+                            request_id: request.id,
+                            sequence,
+                            trace_id: request.trace_id,
+                            payload,
+                        };
+                        let bytes = bincode::serialize(&proto::Message::Response(response));
+                        socket.send(Frame::Binary(bytes.unwrap())).await.unwrap();
+                        sequence += if n + 2 == count { 2 } else { 1 };
+                    }
+                }
+            });
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let elsewhere = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let moved = format!("ws://{}/ws", elsewhere.local_addr().unwrap());
+        let config = ClientConfig::new(&format!("ws://{}/ws", listener.local_addr().unwrap()))
+            .backoff(Duration::from_millis(10), Duration::from_millis(10));
+        tokio::spawn(fake_server(listener, Some(moved.clone())));
+        tokio::spawn(fake_server(elsewhere, None));
+        let client = Client::connect(config).await.unwrap();
+        let get = |key: &str| {
+            proto::RequestPayload::GetKv(proto::GetKvRequest {
+                tenant: "test".to_string(),
+                key: key.to_string(),
+            })
+        };
+
+        // requests made before the connection opens are queued, and sent once it does
+        let response = client.request(get("hello")).await.unwrap();
+        assert!(matches!(
+            response,
+            proto::ResponsePayload::GetKv(proto::GetKvResponse { value: Some(v) }) if v == b"hello"
+        ));
+        assert_eq!(client.connection_state(), ConnectionState::Open);
+
+        // pushes go to the subscription's handler, which is told to resync after a gap
+        let (sender, mut events) = mpsc::unbounded_channel();
+        let subscription_id = client
+            .watch_keys("kv", vec![b"a".to_vec()], None, move |event| {
+                let event = match event {
+                    SubscriptionEvent::Push(payload) => match *payload {
+                        proto::ResponsePayload::KeyChanged(changed) => changed.changes.to_string(),
+                        _ => "other".to_string(),
+                    },
+                    SubscriptionEvent::Resync => "resync".to_string(),
+                };
+                let _ = sender.send(event);
+            })
+            .await
+            .unwrap();
+        assert_eq!(subscription_id, 1);
+        let mut received = vec![];
+        for _ in 0..3 {
+            received.push(events.recv().await.unwrap());
+        }
+        assert_eq!(received, ["1", "resync", "2"]);
+
+        // a request in flight when the connection drops fails, and the client reconnects
+        let dropped = client
+            .request(proto::RequestPayload::Unsubscribe(
+                proto::UnsubscribeRequest { subscription_id },
+            ))
+            .await;
+        assert!(matches!(dropped, Err(RequestError::Disconnected)));
+        client.ready().await;
+        let response = client.request(get("again")).await.unwrap();
+        assert!(matches!(
+            response,
+            proto::ResponsePayload::GetKv(proto::GetKvResponse { value: Some(v) }) if v == b"again"
+        ));
+        assert!(client.build_request(get("read")).idempotency_key.is_none());
+
+        // a server which is going away has the client reconnect where it says
+        let hello = client.request(proto::RequestPayload::Hello).await;
+        assert!(matches!(hello, Err(RequestError::Disconnected)));
+        assert_eq!(client.inner.url(), moved);
+        client.ready().await;
+        assert!(client.request(get("moved")).await.is_ok());
+    }
+}
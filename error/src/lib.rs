@@ -0,0 +1,364 @@
+//! Errors shared by the server and clients, classified with a proto::ErrorKind which survives the
+//! trip through anyhow, across the wire as an ErrorPayload, and out to JavaScript.
+//!
+//! Code which uses anyhow internally can classify an error by returning a `Classified` (or
+//! anything wrapping one as context or source). It's found again when the anyhow::Error is turned
+//! into an `Error`, which is what handlers return. Unclassified errors are `Storage` if they come
+//! from sled, `Serialization` if they come from bincode or serde_json (with the storage and codecs
+//! features), and otherwise `Internal`.
+
+use std::{fmt, time::Duration};
+
+pub use hydra_proto::{ErrorKind, ErrorPayload};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// An error which carries its kind, and can travel inside an anyhow::Error without losing it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Classified {
+    pub kind: ErrorKind,
+    pub message: String,
+    // the request field which was at fault
+    pub field: Option<String>,
+    pub retry_after: Option<Duration>,
+}
+
+impl Classified {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+            field: None,
+            retry_after: None,
+        }
+    }
+
+    pub fn field(mut self, field: impl Into<String>) -> Self {
+        self.field = Some(field.into());
+        self
+    }
+
+    pub fn retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = Some(retry_after);
+        self
+    }
+}
+
+impl fmt::Display for Classified {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Classified {}
+
+/// What fallible handlers return. Anything convertible to anyhow::Error converts to this with
+/// `?`, keeping the kind and details of the first `Classified` in its chain.
+pub struct Error {
+    kind: ErrorKind,
+    field: Option<String>,
+    retry_after: Option<Duration>,
+    inner: anyhow::Error,
+}
+
+impl<E> From<E> for Error
+where
+    E: Into<anyhow::Error>,
+{
+    fn from(err: E) -> Self {
+        let inner = err.into();
+        match inner
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<Classified>())
+        {
+            Some(classified) => Self {
+                kind: classified.kind,
+                field: classified.field.clone(),
+                retry_after: classified.retry_after,
+                inner,
+            },
+            None => Self {
+                kind: kind_of(&inner),
+                field: None,
+                retry_after: None,
+                inner,
+            },
+        }
+    }
+}
+
+/// The kind of an unclassified error, from the errors in its chain
+#[cfg_attr(
+    not(any(feature = "storage", feature = "codecs")),
+    allow(unused_variables)
+)]
+fn kind_of(err: &anyhow::Error) -> ErrorKind {
+    #[cfg(feature = "storage")]
+    if err.chain().any(|cause| cause.is::<sled::Error>()) {
+        return ErrorKind::Storage;
+    }
+    #[cfg(feature = "codecs")]
+    if err.chain().any(|cause| {
+        // bincode's errors are boxed, so may be either
+        cause.is::<bincode::Error>()
+            || cause.is::<bincode::ErrorKind>()
+            || cause.is::<serde_json::Error>()
+    }) {
+        return ErrorKind::Serialization;
+    }
+    ErrorKind::Internal
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Classified::new(kind, message).into()
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::NotFound, message)
+    }
+
+    pub fn invalid_request(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::InvalidRequest, message)
+    }
+
+    /// An invalid request, naming the field which was at fault
+    pub fn invalid_field(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Classified::new(ErrorKind::InvalidRequest, message)
+            .field(field)
+            .into()
+    }
+
+    pub fn rate_limited(retry_after: Duration, message: impl Into<String>) -> Self {
+        Classified::new(ErrorKind::RateLimited, message)
+            .retry_after(retry_after)
+            .into()
+    }
+
+    /// The error a client received, eg. to pass it on to JavaScript
+    pub fn from_payload(payload: &ErrorPayload) -> Self {
+        let mut classified = Classified::new(payload.kind, payload.message.clone());
+        classified.field = payload.field.clone();
+        classified.retry_after = payload.retry_after_ms.map(Duration::from_millis);
+        classified.into()
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::Conflict, message)
+    }
+
+    pub fn unavailable(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::Unavailable, message)
+    }
+
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::Unauthorized, message)
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::Forbidden, message)
+    }
+
+    pub fn cancelled() -> Self {
+        Self::new(ErrorKind::Cancelled, "Request was cancelled")
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn field(&self) -> Option<&str> {
+        self.field.as_deref()
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.retry_after
+    }
+
+    /// The error a sled transaction failed with: the one it was aborted with, or a Storage error
+    /// if sled itself failed. TransactionError converts with `?` as well, but would then be
+    /// Internal, whatever it was aborted with.
+    #[cfg(feature = "storage")]
+    pub fn from_transaction(err: sled::transaction::TransactionError<Error>) -> Self {
+        match err {
+            sled::transaction::TransactionError::Abort(err) => err,
+            sled::transaction::TransactionError::Storage(err) => err.into(),
+        }
+    }
+
+    /// Back to an anyhow::Error, which still contains the classification
+    pub fn into_anyhow(self) -> anyhow::Error {
+        self.inner
+    }
+
+    /// The error as sent to clients, with the messages of all its causes
+    pub fn payload(&self) -> ErrorPayload {
+        ErrorPayload {
+            field: self.field.clone(),
+            retry_after_ms: self.retry_after.map(|wait| wait.as_millis() as u64),
+            ..ErrorPayload::new(self.kind, format!("{:#}", self.inner))
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#}", self.inner)
+    }
+}
+
+impl fmt::Debug for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} error: {:?}", self.kind, self.inner)
+    }
+}
+
+impl From<Error> for ErrorPayload {
+    fn from(err: Error) -> Self {
+        err.payload()
+    }
+}
+
+#[cfg(feature = "axum")]
+impl axum::response::IntoResponse for Error {
+    fn into_response(self) -> axum::response::Response {
+        use axum::http::{header, StatusCode};
+
+        let status = match self.kind {
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::InvalidRequest => StatusCode::BAD_REQUEST,
+            ErrorKind::Conflict => StatusCode::CONFLICT,
+            ErrorKind::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            // nginx's "client closed request", as nobody is waiting for the answer
+            ErrorKind::Cancelled => StatusCode::from_u16(499).unwrap(),
+            ErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorKind::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorKind::Forbidden => StatusCode::FORBIDDEN,
+            ErrorKind::Storage | ErrorKind::Serialization => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorKind::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+        };
+        let message = match (self.kind, &self.field) {
+            (ErrorKind::Internal, _) => format!("Something went wrong: {:#}", self.inner),
+            (_, Some(field)) => format!("{}: {:#}", field, self.inner),
+            _ => format!("{:#}", self.inner),
+        };
+        let mut response = (status, message).into_response();
+        if let Some(wait) = self.retry_after {
+            // whole seconds, rounded up so the client doesn't come back too soon
+            let seconds = wait.as_millis().div_ceil(1000);
+            if let Ok(value) = seconds.to_string().parse() {
+                response.headers_mut().insert(header::RETRY_AFTER, value);
+            }
+        }
+        response
+    }
+}
+
+/// A gRPC status with the code nearest the kind. The kind itself, and the field, travel as
+/// `hydra-error-kind` and `hydra-error-field` metadata.
+#[cfg(feature = "grpc")]
+impl From<Error> for tonic::Status {
+    fn from(err: Error) -> Self {
+        use tonic::Code;
+
+        let code = match err.kind {
+            ErrorKind::NotFound => Code::NotFound,
+            ErrorKind::InvalidRequest => Code::InvalidArgument,
+            ErrorKind::Conflict => Code::Aborted,
+            ErrorKind::Unavailable => Code::Unavailable,
+            ErrorKind::Cancelled => Code::Cancelled,
+            ErrorKind::Internal => Code::Internal,
+            ErrorKind::Unauthorized => Code::Unauthenticated,
+            ErrorKind::Forbidden => Code::PermissionDenied,
+            ErrorKind::Storage | ErrorKind::Serialization => Code::Internal,
+            ErrorKind::RateLimited => Code::ResourceExhausted,
+        };
+        let mut status = tonic::Status::new(code, format!("{:#}", err.inner));
+        let metadata = status.metadata_mut();
+        if let Ok(kind) = err.kind.as_str().parse() {
+            metadata.insert("hydra-error-kind", kind);
+        }
+        if let Some(field) = err.field.as_deref().and_then(|field| field.parse().ok()) {
+            metadata.insert("hydra-error-field", field);
+        }
+        status
+    }
+}
+
+/// A JavaScript Error whose name is the kind, eg. `NotFound`, with `field` and `retryAfterMs`
+/// properties when they're known
+#[cfg(feature = "wasm")]
+impl From<Error> for wasm_bindgen::JsValue {
+    fn from(err: Error) -> Self {
+        let js_error = js_sys::Error::new(&format!("{:#}", err.inner));
+        js_error.set_name(err.kind.as_str());
+        if let Some(field) = &err.field {
+            let _ = js_sys::Reflect::set(&js_error, &"field".into(), &field.as_str().into());
+        }
+        if let Some(wait) = err.retry_after {
+            let ms = wait.as_millis() as f64;
+            let _ = js_sys::Reflect::set(&js_error, &"retryAfterMs".into(), &ms.into());
+        }
+        js_error.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn test_classification_survives_anyhow() {
+        let err: Error = anyhow!("disk on fire").into();
+        assert_eq!(err.kind(), ErrorKind::Internal);
+
+        // as the error itself, as context, and as a source further down
+        let classified = anyhow::Error::new(Classified::new(ErrorKind::NotFound, "no record 7"));
+        assert_eq!(Error::from(classified).kind(), ErrorKind::NotFound);
+
+        let wrapped = Err::<(), _>(Classified::new(ErrorKind::Conflict, "stale snapshot"))
+            .context("Failed to export")
+            .unwrap_err();
+        let err = Error::from(wrapped);
+        assert_eq!(err.kind(), ErrorKind::Conflict);
+        assert_eq!(
+            err.payload(),
+            ErrorPayload::new(ErrorKind::Conflict, "Failed to export: stale snapshot")
+        );
+
+        let err = Error::from(Error::cancelled().into_anyhow().context("while scanning"));
+        assert_eq!(err.kind(), ErrorKind::Cancelled);
+
+        // details survive too, and travel in the payload
+        let err = Error::from(
+            Error::invalid_field("tag", "Tags can't be empty")
+                .into_anyhow()
+                .context("Failed to tag"),
+        );
+        assert_eq!(err.field(), Some("tag"));
+        let payload = Error::rate_limited(Duration::from_millis(1500), "Slow down").payload();
+        assert_eq!(payload.kind, ErrorKind::RateLimited);
+        assert_eq!(payload.retry_after_ms, Some(1500));
+        let received = Error::from_payload(&err.payload());
+        assert_eq!(received.payload(), err.payload());
+    }
+
+    #[cfg(all(feature = "storage", feature = "codecs"))]
+    #[test]
+    fn test_classification_by_source() {
+        let decode = serde_json::from_str::<u32>("nope").unwrap_err();
+        let err = Error::from(anyhow::Error::from(decode).context("Failed to read record"));
+        assert_eq!(err.kind(), ErrorKind::Serialization);
+        let decode = bincode::deserialize::<String>(&[1]).unwrap_err();
+        assert_eq!(Error::from(decode).kind(), ErrorKind::Serialization);
+        let storage = sled::Error::Unsupported("test".to_string());
+        assert_eq!(Error::from(storage).kind(), ErrorKind::Storage);
+
+        use sled::transaction::TransactionError;
+        let aborted = TransactionError::Abort(Error::conflict("already taken"));
+        assert_eq!(Error::from_transaction(aborted).kind(), ErrorKind::Conflict);
+        let failed = TransactionError::Storage(sled::Error::Unsupported("test".to_string()));
+        assert_eq!(Error::from_transaction(failed).kind(), ErrorKind::Storage);
+    }
+}
@@ -1,28 +1,180 @@
-use hydra_web::client::Client;
+use std::rc::Rc;
+
+use futures_signals::signal::SignalExt as _;
+use hydra_web::{
+    client::{Client, ClientConfig, ConnectionState},
+    logging::{init_logging, LoggingConfig},
+    proto,
+};
 use leptos::*;
 
+const PAGE_SIZE: usize = 20;
+const PREVIEW_BYTES: usize = 120;
+
 fn main() {
     console_error_panic_hook::set_once();
+    init_logging(LoggingConfig::default()).unwrap();
 
-    let client = Client::new().unwrap();
-    leptos::mount_to_body(|| view! { <App client=client/> })
+    // served by trunk rather than the server, so the server's URL can't be derived from the page
+    let config = ClientConfig::new().url("ws://127.0.0.1:9797/ws");
+    let client = Rc::new(Client::new(Some(config)).unwrap());
+    leptos::mount_to_body(move || {
+        view! {
+            <ConnectionBanner client=client.clone()/>
+            <IngressLogTable client=client.clone()/>
+        }
+    })
 }
 
+/// Says so while the client isn't connected, rather than leaving the table looking stale
 #[component]
-fn App(client: Client) -> impl IntoView {
-    let (count, set_count) = create_signal(0);
+fn ConnectionBanner(client: Rc<Client>) -> impl IntoView {
+    let state = create_signal_from_stream(Box::pin(client.state_signal().to_stream()));
 
-    view! {
-        <button
-            on:click=move |_| {
-                // on stable, this is set_count.set(3);
-                set_count(3);
-                client.send_message(&format!("the counter state is {}", count()));
+    move || {
+        let message = match state()? {
+            ConnectionState::Open | ConnectionState::None => return None,
+            ConnectionState::Connecting => "Connecting…",
+            ConnectionState::Closed | ConnectionState::Error => "Reconnecting…",
+        };
+        Some(view! { <p class="connection-state">{message}</p> })
+    }
+}
+
+#[derive(Clone)]
+struct Row {
+    key: Vec<u8>,
+    date: String,
+    method: String,
+    host: String,
+    path: String,
+    body: String,
+}
+
+/// A page of ingress logs, newest first
+#[derive(Clone, Default)]
+struct Page {
+    rows: Vec<Row>,
+    has_more_before: bool,
+    has_more_after: bool,
+}
+
+impl From<proto::FetchIngressLogsResponse> for Page {
+    fn from(response: proto::FetchIngressLogsResponse) -> Self {
+        let rows = response
+            .items
+            .into_iter()
+            .map(|item| {
+                let body = match item.preview {
+                    Some(preview) if preview.truncated => {
+                        format!("{}…", String::from_utf8_lossy(&preview.content))
+                    }
+                    Some(preview) => String::from_utf8_lossy(&preview.content).into_owned(),
+                    None => String::from_utf8_lossy(&item.log.body).into_owned(),
+                };
+                Row {
+                    key: item.key,
+                    date: item.log.date.format("%Y-%m-%d %H:%M:%S").to_string(),
+                    method: item.log.method,
+                    host: item.log.host,
+                    path: item.log.path,
+                    body,
+                }
+            })
+            .collect();
+        Self {
+            rows,
+            has_more_before: response.has_more_before,
+            has_more_after: response.has_more_after,
+        }
+    }
+}
+
+/// Pages through the captured ingress logs with FetchIngressLogs. Previous and Next page from the
+/// first and last rows shown, with PaginatedCursor::Before and After.
+#[component]
+fn IngressLogTable(client: Rc<Client>) -> impl IntoView {
+    let (page, set_page) = create_signal(Page::default());
+    let (error, set_error) = create_signal(None::<String>);
+
+    let load = move |cursor: proto::PaginatedCursor| {
+        let client = client.clone();
+        spawn_local(async move {
+            let request = proto::RequestPayload::FetchIngressLogs(proto::FetchIngressLogsRequest {
+                direction: proto::Direction::Descending,
+                limit: PAGE_SIZE,
+                cursor,
+                preview_bytes: Some(PREVIEW_BYTES),
+                snapshot: None,
+                source: None,
+                filter: None,
+                thumbnails: false,
+            });
+            // requests made before the connection opens are queued until it does
+            match client.request(request).await {
+                Ok(proto::ResponsePayload::FetchIngressLogs(response)) => {
+                    set_page(Page::from(response));
+                    set_error(None);
+                }
+                Ok(proto::ResponsePayload::Error(e)) => set_error(Some(e.to_string())),
+                Ok(_) => set_error(Some("Unexpected response".to_string())),
+                Err(e) => set_error(Some(e.to_string())),
             }
-        >
-            "Click me: "
-            // on stable, this is move || count.get();
-            {move || count()}
-        </button>
+        });
+    };
+
+    // the newest page
+    load(proto::PaginatedCursor::StartingWith(vec![]));
+
+    let load_previous = load.clone();
+    let previous = move |_| {
+        if let Some(first) = page.with_untracked(|page| page.rows.first().map(|r| r.key.clone())) {
+            load_previous(proto::PaginatedCursor::Before(first));
+        }
+    };
+    let next = move |_| {
+        if let Some(last) = page.with_untracked(|page| page.rows.last().map(|r| r.key.clone())) {
+            load(proto::PaginatedCursor::After(last));
+        }
+    };
+
+    view! {
+        <div class="ingress-logs">
+            {move || error().map(|error| view! { <p class="error">{error}</p> })}
+            <table>
+                <thead>
+                    <tr>
+                        <th>"Date"</th>
+                        <th>"Method"</th>
+                        <th>"Host"</th>
+                        <th>"Path"</th>
+                        <th>"Body"</th>
+                    </tr>
+                </thead>
+                <tbody>
+                    <For
+                        each=move || page().rows
+                        key=|row| row.key.clone()
+                        children=|row| {
+                            view! {
+                                <tr>
+                                    <td>{row.date}</td>
+                                    <td>{row.method}</td>
+                                    <td>{row.host}</td>
+                                    <td>{row.path}</td>
+                                    <td><code>{row.body}</code></td>
+                                </tr>
+                            }
+                        }
+                    />
+                </tbody>
+            </table>
+            <button disabled=move || !page().has_more_before on:click=previous>
+                "Previous"
+            </button>
+            <button disabled=move || !page().has_more_after on:click=next>
+                "Next"
+            </button>
+        </div>
     }
 }
@@ -20,7 +20,9 @@ export const AppStateProvider: React.FC<{ children: React.ReactNode }> = ({ chil
     // initializing = true;
     init_hydra().then(async () => {
       console.log('init done');
-      const newClient = hydra.Client.new();
+      hydra.init_logging(new hydra.LoggingConfig('info').module('hydra_web::client', 'warn'));
+      // the dev server isn't the hydra server, so the URL can't be derived from the page
+      const newClient = hydra.Client.new(new hydra.ClientConfig().url('ws://127.0.0.1:9797/ws'));
       await newClient.ready();
       setAppState({ client: newClient });
     });
@@ -0,0 +1,22 @@
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+/// A change in the state of one of the server's alert rules. Every change is stored in the
+/// `alerts` tree, keyed by id, and pushed to subscribers of that tree.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Alert {
+    pub id: Ulid,
+    pub date: chrono::DateTime<chrono::Utc>,
+    // the name of the rule
+    pub rule: String,
+    pub state: AlertState,
+    // the number of ingress logs within the rule's window when it was evaluated
+    pub value: i64,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AlertState {
+    Raised,
+    Resolved,
+}
@@ -0,0 +1,492 @@
+//! The causal DAG model: events which name their precursors, identified by a timestamp and
+//! a hash over their contents, so that replicas can compare and merge their histories.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+pub mod checkpoint;
+pub mod compact;
+pub mod resolve;
+
+// ulid and a sha256 hash for lexicographic ordering
+// When merging two IDs, use the earliest timestamp
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct ID {
+    pub timestamp: i64,
+    pub hash: [u8; 32],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Event {
+    pub id: ID, // what should this be?
+    pub precursors: BTreeSet<ID>,
+}
+
+/// A replica's view of the DAG, reduced to its basis: the set of events which summarize
+/// everything it has seen
+#[derive(Debug, Default)]
+pub struct Node {
+    pub basis: BTreeSet<Event>,
+}
+
+impl ID {
+    pub fn new(precursors: &BTreeSet<ID>) -> Self {
+        let timestamp = chrono::Utc::now().timestamp();
+        Self::with_ts(timestamp, precursors)
+    }
+    pub fn with_ts(timestamp: i64, precursors: &BTreeSet<ID>) -> Self {
+        let mut hasher = Sha256::new();
+        // timestamp is serving double duty as the payload for events which don't carry one
+        hasher.update(timestamp.to_be_bytes());
+        for precursor in precursors {
+            hasher.update(precursor.hash);
+        }
+        let hash = hasher.finalize().into();
+        Self { timestamp, hash }
+    }
+    /// An ID whose hash covers a payload as well, so the event vouches for exactly that payload
+    pub fn with_payload(timestamp: i64, payload: &[u8], precursors: &BTreeSet<ID>) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(timestamp.to_be_bytes());
+        hasher.update(Sha256::digest(payload));
+        for precursor in precursors {
+            hasher.update(precursor.hash);
+        }
+        let hash = hasher.finalize().into();
+        Self { timestamp, hash }
+    }
+    // include the last 2 digits of the timestamp (decimal) and the last 2 digits of the hash (hex)
+    pub fn human_readable(&self) -> String {
+        let ts = self.timestamp.to_string();
+        let len = ts.len();
+        let ts_last_2 = &ts[len - 3.min(len)..];
+        let hash = hex::encode(self.hash);
+        let hash_last_2 = &hash[hash.len() - 3.min(hash.len())..];
+        format!("{}.{}", ts_last_2, hash_last_2)
+    }
+}
+
+impl fmt::Display for ID {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.human_readable())
+    }
+}
+
+impl Event {
+    pub fn new(precursors: BTreeSet<ID>) -> Self {
+        let id = ID::new(&precursors);
+        Self { id, precursors }
+    }
+    pub fn with_ts(timestamp: i64, precursors: BTreeSet<ID>) -> Self {
+        let id = ID::with_ts(timestamp, &precursors);
+        Self { id, precursors }
+    }
+    pub fn with_payload(timestamp: i64, payload: &[u8], precursors: BTreeSet<ID>) -> Self {
+        let id = ID::with_payload(timestamp, payload, &precursors);
+        Self { id, precursors }
+    }
+    /// Whether this event was made with Event::with_payload for exactly this payload
+    pub fn covers(&self, payload: &[u8]) -> bool {
+        ID::with_payload(self.id.timestamp, payload, &self.precursors) == self.id
+    }
+    pub fn merge(&self, other: Event) -> Event {
+        let timestamp = self.id.timestamp.max(other.id.timestamp);
+        let mut precursors = self.precursors.clone();
+        precursors.extend(other.precursors);
+        Event {
+            id: ID::with_ts(timestamp, &precursors),
+            precursors,
+        }
+    }
+}
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}[{}]",
+            self.id.human_readable(),
+            self.precursors
+                .iter()
+                .map(|id| id.human_readable())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+}
+
+impl Node {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_seed(seed: &Event) -> Self {
+        let mut node = Self::new();
+        node.basis.insert(seed.clone());
+        node
+    }
+    pub fn new_event(&mut self, ts: i64, precursors: BTreeSet<ID>) -> Event {
+        let event = Event::with_ts(ts, precursors);
+        self.merge_or_insert(event.clone());
+        event
+    }
+    pub fn receive_events<'a, I>(&mut self, events: I)
+    where
+        I: IntoIterator<Item = &'a Event>,
+    {
+        for event in events {
+            self.merge_or_insert(event.clone());
+        }
+    }
+    // If the event can be merged with an existing event, merge them and replace the existing event with the merged event
+    pub fn merge_or_insert(&mut self, event: Event) {
+        if let Some(overlap) = self
+            .basis
+            .iter()
+            .find(|e| e.precursors.contains(&event.id))
+            .cloned()
+        {
+            let merged_event = overlap.merge(event);
+            self.basis.remove(&overlap);
+            self.basis.insert(merged_event);
+        } else {
+            self.basis.insert(event);
+        }
+    }
+    pub fn readable_basis(&self) -> String {
+        self.basis
+            .iter()
+            .map(|e| e.id.human_readable())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Node({})", self.readable_basis())
+    }
+}
+
+/// Returned when inserting an event whose precursors haven't been seen yet. The caller should
+/// hold on to the event and retry once the missing precursors have arrived.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingPrecursors(pub BTreeSet<ID>);
+
+impl fmt::Display for MissingPrecursors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Missing precursors: {}",
+            self.0
+                .iter()
+                .map(|id| id.human_readable())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+}
+
+impl std::error::Error for MissingPrecursors {}
+
+/// How far back Dag::locator looks, beyond which histories which don't meet are sent in full
+const MAX_LOCATOR_DEPTH: usize = 1 << 16;
+
+/// The full history of events, as opposed to the summarized basis of a Node
+#[derive(Debug, Default)]
+pub struct Dag {
+    events: BTreeMap<ID, Event>,
+    // events which no other event names as a precursor
+    heads: BTreeSet<ID>,
+    // events elided by compaction, and the summary standing in for each, see compact.rs
+    tombstones: BTreeMap<ID, ID>,
+}
+
+impl Dag {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an event, returning false if it was already present, or has since been elided.
+    /// Events must be inserted after their precursors, so the DAG is always closed under
+    /// ancestry, though a precursor which has been elided will do, see Dag::resolve.
+    pub fn insert(&mut self, event: Event) -> Result<bool, MissingPrecursors> {
+        if self.knows(&event.id) {
+            return Ok(false);
+        }
+        let missing: BTreeSet<ID> = event
+            .precursors
+            .iter()
+            .filter(|id| !self.knows(id))
+            .cloned()
+            .collect();
+        if !missing.is_empty() {
+            return Err(MissingPrecursors(missing));
+        }
+
+        for precursor in &event.precursors {
+            if let Some(id) = self.resolve(precursor).cloned() {
+                self.heads.remove(&id);
+            }
+        }
+        self.heads.insert(event.id.clone());
+        self.events.insert(event.id.clone(), event);
+        Ok(true)
+    }
+
+    /// The events nothing else builds on yet, ie. the precursors for the next local event
+    pub fn heads(&self) -> &BTreeSet<ID> {
+        &self.heads
+    }
+
+    /// Every event the given event transitively depends on, not including itself. Elided
+    /// events are given as the summaries standing in for them.
+    pub fn ancestors(&self, id: &ID) -> BTreeSet<ID> {
+        let mut ancestors = BTreeSet::new();
+        let mut stack: Vec<&ID> = match self.events.get(id) {
+            Some(event) => event.precursors.iter().collect(),
+            None => return ancestors,
+        };
+        while let Some(id) = stack.pop() {
+            let Some(id) = self.resolve(id) else {
+                continue;
+            };
+            if ancestors.insert(id.clone()) {
+                if let Some(event) = self.events.get(id) {
+                    stack.extend(event.precursors.iter());
+                }
+            }
+        }
+        ancestors
+    }
+
+    pub fn get(&self, id: &ID) -> Option<&Event> {
+        self.events.get(id)
+    }
+
+    pub fn contains(&self, id: &ID) -> bool {
+        self.events.contains_key(id)
+    }
+
+    /// The events leading up to `want`, each after its precursors, for a replica which has
+    /// `have` and everything before them. The walk back stops at anything in `have`, so events
+    /// reachable around it, through merges, may be included even if the replica has them.
+    /// At most `limit` are returned, and as they're in order, those can be inserted by the
+    /// replica as they are. Ask for the rest with them added to `have`.
+    pub fn missing(&self, want: &[ID], have: &[ID], limit: usize) -> Vec<Event> {
+        let have: BTreeSet<&ID> = have.iter().collect();
+        let mut seen: BTreeSet<&ID> = BTreeSet::new();
+        let mut missing = Vec::new();
+        // walked depth first, emitting each event once all its precursors have been
+        let mut stack: Vec<(&Event, bool)> = Vec::new();
+        for id in want {
+            if let Some(event) = self.events.get(id) {
+                stack.push((event, false));
+            }
+        }
+        while let Some((event, expanded)) = stack.pop() {
+            if missing.len() >= limit {
+                break;
+            }
+            if expanded {
+                missing.push(event.clone());
+                continue;
+            }
+            if have.contains(&event.id) || !seen.insert(&event.id) {
+                continue;
+            }
+            stack.push((event, true));
+            for precursor in &event.precursors {
+                let event = self.resolve(precursor).and_then(|id| self.events.get(id));
+                if let Some(event) = event {
+                    if !seen.contains(&event.id) {
+                        stack.push((event, false));
+                    }
+                }
+            }
+        }
+        missing
+    }
+
+    /// A sample of the history behind the heads for a replica to find where its own meets it:
+    /// the heads, then their ancestors at exponentially growing distances, following the
+    /// latest precursor each time
+    pub fn locator(&self) -> Vec<ID> {
+        let mut locator = BTreeSet::new();
+        for head in &self.heads {
+            let mut id = head;
+            let mut step = 1;
+            let mut distance = 0;
+            let mut next = 0;
+            while distance <= MAX_LOCATOR_DEPTH {
+                if distance == next {
+                    locator.insert(id.clone());
+                    next += step;
+                    step *= 2;
+                }
+                let precursor = self.events.get(id).and_then(|e| e.precursors.last());
+                let Some(precursor) = precursor.and_then(|id| self.resolve(id)) else {
+                    // the oldest, which every replica with anything in common has
+                    locator.insert(id.clone());
+                    break;
+                };
+                id = precursor;
+                distance += 1;
+            }
+        }
+        locator.into_iter().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_or_insert() {
+        let seed = Event::with_ts(0, BTreeSet::new());
+
+        // unrelated events are simply added to the basis
+        let mut a = Node::with_seed(&seed);
+        let e1 = a.new_event(1, BTreeSet::new());
+        assert_eq!(a.basis.len(), 2);
+
+        // receiving the same events in any order converges on the same basis
+        let mut b = Node::with_seed(&seed);
+        let mut c = Node::with_seed(&seed);
+        b.new_event(2, BTreeSet::new());
+        c.new_event(3, BTreeSet::new());
+        b.receive_events(&a.basis);
+        c.receive_events(&b.basis);
+        a.receive_events(&c.basis);
+        b.receive_events(&a.basis);
+        assert_eq!(a.basis, b.basis);
+        assert_eq!(a.basis, c.basis);
+        assert_eq!(a.basis.len(), 4);
+
+        // an event named as a precursor by one in the basis is merged into it
+        let e4 = Event::with_ts(4, BTreeSet::from([e1.id.clone()]));
+        let mut d = Node::with_seed(&e4);
+        d.merge_or_insert(e1.clone());
+        assert_eq!(d.basis.len(), 1);
+        let merged = d.basis.iter().next().unwrap();
+        assert_eq!(merged.id.timestamp, 4);
+        assert_eq!(merged.precursors, BTreeSet::from([e1.id]));
+        assert_eq!(*merged, e4.merge(Event::with_ts(1, BTreeSet::new())));
+    }
+
+    #[test]
+    fn test_dag() {
+        let mut dag = Dag::new();
+        let seed = Event::with_ts(0, BTreeSet::new());
+        let e1 = Event::with_ts(1, BTreeSet::from([seed.id.clone()]));
+        let e2 = Event::with_ts(2, BTreeSet::from([seed.id.clone()]));
+        let e3 = Event::with_ts(3, BTreeSet::from([e1.id.clone(), e2.id.clone()]));
+
+        assert_eq!(
+            dag.insert(e1.clone()),
+            Err(MissingPrecursors(BTreeSet::from([seed.id.clone()])))
+        );
+        assert_eq!(dag.insert(seed.clone()), Ok(true));
+        assert_eq!(dag.insert(seed.clone()), Ok(false));
+        dag.insert(e1.clone()).unwrap();
+        dag.insert(e2.clone()).unwrap();
+        assert_eq!(dag.heads(), &BTreeSet::from([e1.id.clone(), e2.id.clone()]));
+
+        dag.insert(e3.clone()).unwrap();
+        assert_eq!(dag.heads(), &BTreeSet::from([e3.id.clone()]));
+        assert_eq!(
+            dag.ancestors(&e3.id),
+            BTreeSet::from([seed.id.clone(), e1.id.clone(), e2.id])
+        );
+        assert_eq!(dag.ancestors(&e1.id), BTreeSet::from([seed.id.clone()]));
+        assert!(dag.ancestors(&seed.id).is_empty());
+        assert_eq!(dag.len(), 4);
+
+        // an event with a payload vouches for it, and nothing else
+        let e4 = Event::with_payload(4, b"payload", BTreeSet::from([e3.id.clone()]));
+        assert!(e4.covers(b"payload"));
+        assert!(!e4.covers(b"tampered"));
+        assert!(!e3.covers(b"payload"));
+        assert_ne!(
+            e4.id,
+            Event::with_payload(4, b"payload", BTreeSet::new()).id
+        );
+
+        // events survive a round trip through the wire format
+        let bytes = bincode::serialize(&e3).unwrap();
+        assert_eq!(bincode::deserialize::<Event>(&bytes).unwrap(), e3);
+    }
+
+    // bring `to` up to date with `from`, as a peer asking for what it's missing would, in
+    // batches of `limit`, returning how many events were sent
+    fn pull(from: &Dag, to: &mut Dag, limit: usize) -> usize {
+        let mut sent = 0;
+        let want: Vec<ID> = from.heads().iter().cloned().collect();
+        let mut have: Vec<ID> = to.heads().iter().cloned().collect();
+        have.extend(to.locator());
+        loop {
+            let batch = from.missing(&want, &have, limit);
+            if batch.is_empty() {
+                return sent;
+            }
+            assert!(batch.len() <= limit);
+            sent += batch.len();
+            // including those it already had, which its heads don't show
+            for event in batch {
+                have.push(event.id.clone());
+                to.insert(event).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_missing() {
+        // a long shared history, then each side carries on by itself
+        let mut a = Dag::new();
+        let mut previous = BTreeSet::new();
+        for ts in 0..1000 {
+            let event = Event::with_ts(ts, previous);
+            previous = BTreeSet::from([event.id.clone()]);
+            a.insert(event).unwrap();
+        }
+        let mut b = Dag::new();
+        assert_eq!(pull(&a, &mut b, 64), 1000);
+        assert_eq!(a.heads(), b.heads());
+
+        let shared = previous.clone();
+        let mut previous = shared.clone();
+        for ts in 1000..1005 {
+            let event = Event::with_ts(ts, previous);
+            previous = BTreeSet::from([event.id.clone()]);
+            a.insert(event).unwrap();
+        }
+        // a branch and a merge on b
+        let left = Event::with_ts(2000, shared.clone());
+        let right = Event::with_ts(2001, shared);
+        let merge = Event::with_ts(2002, BTreeSet::from([left.id.clone(), right.id.clone()]));
+        for event in [left, right, merge] {
+            b.insert(event).unwrap();
+        }
+
+        // only what's new, or a little more, is sent either way
+        let sent = pull(&b, &mut a, 2);
+        assert!((3..10).contains(&sent), "sent {}", sent);
+        let sent = pull(&a, &mut b, 2);
+        assert!((5..10).contains(&sent), "sent {}", sent);
+        assert_eq!(a.heads(), b.heads());
+        assert_eq!(a.heads().len(), 2);
+        assert_eq!(a.len(), 1008);
+        assert_eq!(b.len(), 1008);
+    }
+}
@@ -0,0 +1,229 @@
+//! Checkpoints: a node's signed commitment to its DAG as it stood at some point, so that clients
+//! can later confirm that the events they saw then are still there, unchanged and in the same
+//! place.
+//!
+//! A checkpoint covers the first `event_count` events the node added, in the order it added
+//! them, as the leaves of a Merkle tree, and is signed with the node's ed25519 checkpoint key.
+//! A client holding a checkpoint asks for inclusion proofs of events against it. The proofs are
+//! worked out from the events the node holds now, so if its history has been rewritten since, by
+//! changing, removing or reordering events, they won't match the checkpoint's root.
+
+use std::collections::BTreeSet;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::ID;
+
+/// A signed Merkle root over the first `event_count` events a node added
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    // counts up from 0 on each node
+    pub sequence: u64,
+    pub event_count: u64,
+    pub root: [u8; 32],
+    // the node's heads as of the checkpoint, its basis for the next local event
+    pub heads: Vec<ID>,
+    pub created_at_ms: i64,
+    // ed25519, by the node's checkpoint key, over everything above
+    pub signature: Vec<u8>,
+}
+
+impl Checkpoint {
+    pub fn sign(
+        key: &SigningKey,
+        sequence: u64,
+        leaves: &[ID],
+        heads: BTreeSet<ID>,
+        created_at_ms: i64,
+    ) -> Self {
+        let mut checkpoint = Self {
+            sequence,
+            event_count: leaves.len() as u64,
+            root: MerkleTree::new(leaves).root(),
+            heads: heads.into_iter().collect(),
+            created_at_ms,
+            signature: vec![],
+        };
+        checkpoint.signature = key.sign(&checkpoint.signed_bytes()).to_bytes().to_vec();
+        checkpoint
+    }
+
+    /// Whether the checkpoint was signed by the holder of this public key, and hasn't been
+    /// changed since
+    pub fn verify(&self, public_key: &[u8; 32]) -> bool {
+        let Ok(key) = VerifyingKey::from_bytes(public_key) else {
+            return false;
+        };
+        let Ok(signature) = Signature::from_slice(&self.signature) else {
+            return false;
+        };
+        key.verify(&self.signed_bytes(), &signature).is_ok()
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        let signed = (
+            self.sequence,
+            self.event_count,
+            self.root,
+            &self.heads,
+            self.created_at_ms,
+        );
+        bincode::serialize(&signed).expect("checkpoints can always be serialized")
+    }
+}
+
+/// A Merkle tree over event ids. Leaves and inner nodes are hashed with different prefixes, so
+/// one can't pass for the other, and a node without a sibling is carried up a level as it is.
+pub struct MerkleTree {
+    // from the leaves up to the root
+    levels: Vec<Vec<[u8; 32]>>,
+}
+
+fn leaf_hash(id: &ID) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([0]);
+    hasher.update(id.timestamp.to_be_bytes());
+    hasher.update(id.hash);
+    hasher.finalize().into()
+}
+
+fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([1]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+impl MerkleTree {
+    pub fn new(leaves: &[ID]) -> Self {
+        let mut levels = vec![leaves.iter().map(leaf_hash).collect::<Vec<_>>()];
+        while levels.last().unwrap().len() > 1 {
+            let next = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => node_hash(left, right),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+        Self { levels }
+    }
+
+    /// The root hash, which for no leaves at all is the hash of nothing
+    pub fn root(&self) -> [u8; 32] {
+        match self.levels.last().unwrap().first() {
+            Some(root) => *root,
+            None => Sha256::digest([]).into(),
+        }
+    }
+
+    /// A proof that the leaf at `index` is in the tree, if there is one
+    pub fn prove(&self, index: usize) -> Option<InclusionProof> {
+        if index >= self.levels[0].len() {
+            return None;
+        }
+        let mut steps = Vec::new();
+        let mut position = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling = position ^ 1;
+            if let Some(hash) = level.get(sibling) {
+                steps.push(ProofStep {
+                    sibling: *hash,
+                    sibling_on_left: sibling < position,
+                });
+            }
+            position /= 2;
+        }
+        Some(InclusionProof {
+            index: index as u64,
+            steps,
+        })
+    }
+}
+
+/// The hashes needed to get from an event's leaf to a checkpoint's root
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct InclusionProof {
+    // the event's position among those the checkpoint covers
+    pub index: u64,
+    pub steps: Vec<ProofStep>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ProofStep {
+    pub sibling: [u8; 32],
+    pub sibling_on_left: bool,
+}
+
+impl InclusionProof {
+    /// Whether this proves the event is covered by the tree with this root
+    pub fn verify(&self, id: &ID, root: &[u8; 32]) -> bool {
+        let hash = self
+            .steps
+            .iter()
+            .fold(leaf_hash(id), |hash, step| match step.sibling_on_left {
+                true => node_hash(&step.sibling, &hash),
+                false => node_hash(&hash, &step.sibling),
+            });
+        hash == *root
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dag::Event;
+
+    #[test]
+    fn test_checkpoint() {
+        let mut previous = BTreeSet::new();
+        let events: Vec<ID> = (0..7)
+            .map(|ts| {
+                let event = Event::with_ts(ts, previous.clone());
+                previous = BTreeSet::from([event.id.clone()]);
+                event.id
+            })
+            .collect();
+
+        // every event, including the odd one out, can be proven against the root
+        let tree = MerkleTree::new(&events);
+        let root = tree.root();
+        for (index, id) in events.iter().enumerate() {
+            let proof = tree.prove(index).unwrap();
+            assert!(proof.verify(id, &root), "event {}", index);
+            assert!(!proof.verify(&events[(index + 1) % 7], &root));
+        }
+        assert!(tree.prove(7).is_none());
+        assert_ne!(MerkleTree::new(&events[..6]).root(), root);
+        assert_eq!(MerkleTree::new(&[]).root(), MerkleTree::new(&[]).root());
+
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let public_key = key.verifying_key().to_bytes();
+        let checkpoint = Checkpoint::sign(&key, 0, &events, previous, 1000);
+        assert_eq!(checkpoint.event_count, 7);
+        assert_eq!(checkpoint.root, root);
+        assert!(checkpoint.verify(&public_key));
+        let other_key = SigningKey::from_bytes(&[8; 32]).verifying_key().to_bytes();
+        assert!(!checkpoint.verify(&other_key));
+
+        // a log rewritten since, even just reordered, no longer matches
+        let tampered = Checkpoint {
+            event_count: 6,
+            ..checkpoint.clone()
+        };
+        assert!(!tampered.verify(&public_key));
+        let mut reordered = events.clone();
+        reordered.swap(2, 3);
+        let proof = MerkleTree::new(&reordered).prove(2).unwrap();
+        assert!(!proof.verify(&events[3], &checkpoint.root));
+        let proof = MerkleTree::new(&events).prove(3).unwrap();
+        assert!(proof.verify(&events[3], &checkpoint.root));
+    }
+}
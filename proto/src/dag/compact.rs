@@ -0,0 +1,184 @@
+//! Compacting the DAG: once an event is known to every replica, the history behind it can be
+//! elided, replaced by a single summary event and a tombstone for each event it stands in for.
+//!
+//! Events made before the compaction may still turn up afterwards naming elided events as their
+//! precursors, eg. from a replica which was partitioned away while the others merged. They're
+//! accepted, and the elided precursors they name are resolved to the summary covering them, the
+//! "apology" for having forgotten the events themselves. The summary covers more than such an
+//! event knew of, so its ancestry is overstated, never understated: anything it did build on is
+//! still behind it.
+//!
+//! Compaction is local to a replica. Tombstones aren't exchanged when syncing, so a replica can
+//! only take events behind a compaction from a peer which still has the events they name.
+
+use std::collections::BTreeSet;
+
+use sha2::{Digest, Sha256};
+
+use super::{Dag, Event, ID};
+
+/// What a compaction removed, and the summary it added in their place
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Compaction {
+    pub summary: Event,
+    pub elided: BTreeSet<ID>,
+}
+
+impl Event {
+    /// An event standing in for elided ones, with no precursors of its own: its hash covers
+    /// exactly the elided ids, and it's as late as the latest of them
+    pub fn summary(elided: &BTreeSet<ID>) -> Event {
+        let timestamp = elided.iter().map(|id| id.timestamp).max().unwrap_or(0);
+        Event::with_payload(timestamp, &summary_payload(elided), BTreeSet::new())
+    }
+
+    /// Whether this is the summary of exactly these elided events
+    pub fn summarizes(&self, elided: &BTreeSet<ID>) -> bool {
+        self.precursors.is_empty() && *self == Event::summary(elided)
+    }
+}
+
+fn summary_payload(elided: &BTreeSet<ID>) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    for id in elided {
+        hasher.update(id.timestamp.to_be_bytes());
+        hasher.update(id.hash);
+    }
+    hasher.finalize().to_vec()
+}
+
+impl Dag {
+    /// Elide every event behind `through`, including the summaries of earlier compactions,
+    /// replacing them with one summary. `through` and everything not behind it are kept. None if
+    /// `through` isn't in the DAG or has nothing behind it.
+    pub fn compact(&mut self, through: &ID) -> Option<Compaction> {
+        if !self.events.contains_key(through) {
+            return None;
+        }
+        let elided = self.ancestors(through);
+        if elided.is_empty() {
+            return None;
+        }
+        let summary = Event::summary(&elided);
+        for id in &elided {
+            self.events.remove(id);
+        }
+        // tombstones always name the latest summary, so lookups take one step
+        for standing_in in self.tombstones.values_mut() {
+            if elided.contains(standing_in) {
+                *standing_in = summary.id.clone();
+            }
+        }
+        for id in &elided {
+            self.tombstones.insert(id.clone(), summary.id.clone());
+        }
+        self.events.insert(summary.id.clone(), summary.clone());
+        Some(Compaction { summary, elided })
+    }
+
+    /// The event in the DAG standing for `id`: the event itself, or the summary it was elided
+    /// into. None if it's never been seen.
+    pub fn resolve<'a>(&'a self, id: &'a ID) -> Option<&'a ID> {
+        match self.events.contains_key(id) {
+            true => Some(id),
+            false => self.tombstones.get(id),
+        }
+    }
+
+    pub fn is_elided(&self, id: &ID) -> bool {
+        self.tombstones.contains_key(id)
+    }
+
+    /// Whether the event is in the DAG, or was and has been elided
+    pub fn knows(&self, id: &ID) -> bool {
+        self.resolve(id).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dag::MissingPrecursors;
+
+    #[test]
+    fn test_compact() {
+        // the PoC's history: 1, 2 and 3 are made concurrently on 0, then merged into 4
+        let mut dag = Dag::new();
+        let e0 = Event::with_ts(0, BTreeSet::new());
+        let on_e0 = BTreeSet::from([e0.id.clone()]);
+        let e1 = Event::with_ts(1, on_e0.clone());
+        let e2 = Event::with_ts(2, on_e0.clone());
+        let e3 = Event::with_ts(3, on_e0);
+        let e4 = Event::with_ts(
+            4,
+            BTreeSet::from([e1.id.clone(), e2.id.clone(), e3.id.clone()]),
+        );
+        for event in [&e0, &e1, &e2, &e3, &e4] {
+            dag.insert(event.clone()).unwrap();
+        }
+
+        let compaction = dag.compact(&e4.id).unwrap();
+        let elided = BTreeSet::from([e0.id.clone(), e1.id.clone(), e2.id.clone(), e3.id.clone()]);
+        assert_eq!(compaction.elided, elided);
+        let summary = compaction.summary;
+        assert!(summary.summarizes(&elided));
+        assert!(!summary.summarizes(&BTreeSet::from([e0.id.clone()])));
+        assert_eq!(summary.id.timestamp, 3);
+        assert_eq!(dag.len(), 2);
+        assert_eq!(dag.heads(), &BTreeSet::from([e4.id.clone()]));
+        assert_eq!(dag.ancestors(&e4.id), BTreeSet::from([summary.id.clone()]));
+        assert_eq!(dag.resolve(&e2.id), Some(&summary.id));
+        assert_eq!(dag.resolve(&e4.id), Some(&e4.id));
+        assert!(dag.is_elided(&e1.id) && !dag.is_elided(&e4.id));
+        // nothing is left behind the summary to compact
+        assert_eq!(dag.compact(&summary.id), None);
+
+        // an event made on 2 before the merge arrives late, and is accepted on the summary
+        let late = Event::with_ts(5, BTreeSet::from([e2.id.clone()]));
+        assert_eq!(dag.insert(late.clone()), Ok(true));
+        assert_eq!(
+            dag.heads(),
+            &BTreeSet::from([e4.id.clone(), late.id.clone()])
+        );
+        assert_eq!(
+            dag.ancestors(&late.id),
+            BTreeSet::from([summary.id.clone()])
+        );
+        // elided events arriving again are already known
+        assert_eq!(dag.insert(e1.clone()), Ok(false));
+        assert_eq!(dag.len(), 3);
+        // unlike ones never seen
+        let stranger = Event::with_ts(6, BTreeSet::new());
+        let orphan = Event::with_ts(7, BTreeSet::from([stranger.id.clone()]));
+        assert_eq!(
+            dag.insert(orphan),
+            Err(MissingPrecursors(BTreeSet::from([stranger.id])))
+        );
+
+        // compacting again folds the first summary into the next, and the first compaction's
+        // tombstones follow it
+        let e8 = Event::with_ts(8, BTreeSet::from([e4.id.clone(), late.id.clone()]));
+        dag.insert(e8.clone()).unwrap();
+        let again = dag.compact(&e8.id).unwrap();
+        assert_eq!(
+            again.elided,
+            BTreeSet::from([summary.id.clone(), e4.id.clone(), late.id.clone()])
+        );
+        assert_eq!(dag.resolve(&e2.id), Some(&again.summary.id));
+        assert_eq!(dag.resolve(&summary.id), Some(&again.summary.id));
+        assert_eq!(dag.len(), 2);
+
+        // and a reference to an event elided the first time still resolves
+        let later = Event::with_ts(9, BTreeSet::from([e3.id.clone()]));
+        dag.insert(later.clone()).unwrap();
+        assert_eq!(
+            dag.ancestors(&later.id),
+            BTreeSet::from([again.summary.id.clone()])
+        );
+        assert_eq!(
+            dag.missing(std::slice::from_ref(&later.id), &[], 10),
+            vec![again.summary.clone(), later]
+        );
+        assert!(dag.locator().contains(&again.summary.id));
+    }
+}
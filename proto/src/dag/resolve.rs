@@ -0,0 +1,418 @@
+//! Resolving concurrent updates to the same entity as replicated events are applied.
+//!
+//! An event may carry an Update to one entity. When a replica applies it, any earlier updates to
+//! that entity among the event's ancestors are superseded. Updates which aren't, having been made
+//! without knowing of each other, are concurrent, and the strategy registered for the entity's
+//! payload type decides its value from them: last-writer-wins by hybrid logical clock unless
+//! another is registered. Which updates are concurrent depends only on the DAG, so replicas which
+//! have applied the same events agree on every value whatever order they applied them in. Every
+//! resolution is recorded, with the strategy and the updates it was given, so a surprising value
+//! can be traced back.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{Dag, Event, MissingPrecursors, ID};
+
+/// A hybrid logical clock timestamp: milliseconds of wall time, then a counter which orders
+/// events within a millisecond, or made while a clock which ran ahead is caught up with, then the
+/// node which made it, so that no two nodes' timestamps ever tie
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Hlc {
+    pub wall_ms: i64,
+    pub counter: u32,
+    pub node: u32,
+}
+
+impl fmt::Display for Hlc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}@{}", self.wall_ms, self.counter, self.node)
+    }
+}
+
+/// A node's hybrid logical clock. Its timestamps always move forwards, and always come after any
+/// it has observed from other nodes, however far their clocks are out from its own.
+#[derive(Debug, Clone)]
+pub struct Clock {
+    last: Hlc,
+}
+
+impl Clock {
+    pub fn new(node: u32) -> Self {
+        Self {
+            last: Hlc {
+                wall_ms: i64::MIN,
+                counter: 0,
+                node,
+            },
+        }
+    }
+
+    /// A timestamp for a local event
+    pub fn now(&mut self) -> Hlc {
+        self.tick(chrono::Utc::now().timestamp_millis())
+    }
+
+    /// A timestamp for a local event, at wall time `wall_ms`
+    pub fn tick(&mut self, wall_ms: i64) -> Hlc {
+        self.last = match wall_ms > self.last.wall_ms {
+            true => Hlc {
+                wall_ms,
+                counter: 0,
+                ..self.last
+            },
+            false => Hlc {
+                counter: self.last.counter + 1,
+                ..self.last
+            },
+        };
+        self.last
+    }
+
+    /// Take account of a timestamp received from another node, at wall time `wall_ms`
+    pub fn observe(&mut self, remote: Hlc, wall_ms: i64) -> Hlc {
+        let latest = self.last.wall_ms.max(remote.wall_ms);
+        self.last = match (latest == self.last.wall_ms, latest == remote.wall_ms) {
+            _ if wall_ms > latest => Hlc {
+                wall_ms,
+                counter: 0,
+                ..self.last
+            },
+            (true, true) => Hlc {
+                counter: self.last.counter.max(remote.counter) + 1,
+                ..self.last
+            },
+            (true, false) => Hlc {
+                counter: self.last.counter + 1,
+                ..self.last
+            },
+            (false, _) => Hlc {
+                wall_ms: remote.wall_ms,
+                counter: remote.counter + 1,
+                ..self.last
+            },
+        };
+        self.last
+    }
+}
+
+/// A change to one entity, carried by an event
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Update {
+    pub entity: String,
+    // picks the strategy which resolves concurrent updates, see Resolver
+    pub payload_type: String,
+    pub hlc: Hlc,
+    pub value: Vec<u8>,
+}
+
+/// One of the concurrent updates a strategy is given, with the event which carried it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Candidate {
+    pub event: ID,
+    pub update: Update,
+}
+
+/// Decides an entity's value from concurrent updates to it
+pub trait Strategy: Send + Sync {
+    /// Recorded with each resolution
+    fn name(&self) -> &str;
+
+    /// The value, from at least two updates, none of which knew of the others, in HLC order.
+    /// It must depend on nothing else, so that every replica comes to the same value.
+    fn resolve(&self, candidates: &[Candidate]) -> Vec<u8>;
+}
+
+/// Takes the value of the update with the latest HLC timestamp
+pub struct LastWriterWins;
+
+impl Strategy for LastWriterWins {
+    fn name(&self) -> &str {
+        "last-writer-wins"
+    }
+
+    fn resolve(&self, candidates: &[Candidate]) -> Vec<u8> {
+        candidates
+            .iter()
+            .max_by_key(|candidate| candidate.update.hlc)
+            .map(|candidate| candidate.update.value.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// A strategy made from a merge function, eg. to take the union of sets or sum counters
+pub struct MergeFunction<F> {
+    name: String,
+    merge: F,
+}
+
+impl<F> MergeFunction<F>
+where
+    F: Fn(&[Candidate]) -> Vec<u8> + Send + Sync,
+{
+    pub fn new(name: &str, merge: F) -> Self {
+        Self {
+            name: name.to_string(),
+            merge,
+        }
+    }
+}
+
+impl<F> Strategy for MergeFunction<F>
+where
+    F: Fn(&[Candidate]) -> Vec<u8> + Send + Sync,
+{
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn resolve(&self, candidates: &[Candidate]) -> Vec<u8> {
+        (self.merge)(candidates)
+    }
+}
+
+/// The strategy for each payload type, falling back to last-writer-wins
+#[derive(Default)]
+pub struct Resolver {
+    strategies: HashMap<String, Box<dyn Strategy>>,
+}
+
+impl Resolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resolve concurrent updates of `payload_type` with `strategy`
+    pub fn register(mut self, payload_type: &str, strategy: impl Strategy + 'static) -> Self {
+        self.strategies
+            .insert(payload_type.to_string(), Box::new(strategy));
+        self
+    }
+
+    pub fn strategy(&self, payload_type: &str) -> &dyn Strategy {
+        match self.strategies.get(payload_type) {
+            Some(strategy) => strategy.as_ref(),
+            None => &LastWriterWins,
+        }
+    }
+}
+
+/// A record of concurrent updates being resolved
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Resolution {
+    pub entity: String,
+    pub strategy: String,
+    // the event whose application found the updates concurrent
+    pub applied: ID,
+    pub inputs: Vec<Candidate>,
+    pub value: Vec<u8>,
+}
+
+/// What's known of one entity
+#[derive(Debug, Default)]
+struct Entity {
+    value: Vec<u8>,
+    // the updates to it which no later update has superseded, by the event which carried them
+    current: BTreeMap<ID, Update>,
+}
+
+/// A replica's DAG, and the entities its events' updates describe
+pub struct Replica {
+    dag: Dag,
+    resolver: Resolver,
+    entities: BTreeMap<String, Entity>,
+    resolutions: Vec<Resolution>,
+}
+
+impl Replica {
+    pub fn new(resolver: Resolver) -> Self {
+        Self {
+            dag: Dag::new(),
+            resolver,
+            entities: BTreeMap::new(),
+            resolutions: Vec::new(),
+        }
+    }
+
+    /// Apply an event, local or replicated, and the update it carries, if any. Events must be
+    /// applied after their precursors. Returns the resolution if the update was concurrent with
+    /// others to the same entity, and nothing if it wasn't, or the event was already applied.
+    pub fn apply(
+        &mut self,
+        event: Event,
+        update: Option<Update>,
+    ) -> Result<Option<&Resolution>, MissingPrecursors> {
+        let id = event.id.clone();
+        if !self.dag.insert(event)? {
+            return Ok(None);
+        }
+        let Some(update) = update else {
+            return Ok(None);
+        };
+        // events are applied after their precursors, so an update can supersede those already
+        // applied, but never be superseded by one
+        let ancestors = self.dag.ancestors(&id);
+        let entity = self.entities.entry(update.entity.clone()).or_default();
+        entity.current.retain(|event, _| !ancestors.contains(event));
+        entity.current.insert(id.clone(), update.clone());
+        if entity.current.len() == 1 {
+            entity.value = update.value;
+            return Ok(None);
+        }
+
+        let mut inputs: Vec<Candidate> = entity
+            .current
+            .iter()
+            .map(|(event, update)| Candidate {
+                event: event.clone(),
+                update: update.clone(),
+            })
+            .collect();
+        inputs.sort_by(|a, b| (a.update.hlc, &a.event).cmp(&(b.update.hlc, &b.event)));
+        let strategy = self.resolver.strategy(&update.payload_type);
+        entity.value = strategy.resolve(&inputs);
+        self.resolutions.push(Resolution {
+            entity: update.entity,
+            strategy: strategy.name().to_string(),
+            applied: id,
+            value: entity.value.clone(),
+            inputs,
+        });
+        Ok(self.resolutions.last())
+    }
+
+    /// An entity's value, if any update to it has been applied
+    pub fn value(&self, entity: &str) -> Option<&[u8]> {
+        self.entities
+            .get(entity)
+            .map(|entity| entity.value.as_slice())
+    }
+
+    /// Every resolution made so far, oldest first
+    pub fn resolutions(&self) -> &[Resolution] {
+        &self.resolutions
+    }
+
+    pub fn dag(&self) -> &Dag {
+        &self.dag
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    fn update(entity: &str, payload_type: &str, hlc: Hlc, value: &[u8]) -> Update {
+        Update {
+            entity: entity.to_string(),
+            payload_type: payload_type.to_string(),
+            hlc,
+            value: value.to_vec(),
+        }
+    }
+
+    /// Sums the counters, each a big-endian u64
+    fn sum() -> MergeFunction<impl Fn(&[Candidate]) -> Vec<u8> + Send + Sync> {
+        MergeFunction::new("sum", |candidates: &[Candidate]| {
+            let total: u64 = candidates
+                .iter()
+                .map(|c| u64::from_be_bytes(c.update.value[..8].try_into().unwrap()))
+                .sum();
+            total.to_be_bytes().to_vec()
+        })
+    }
+
+    #[test]
+    fn test_resolution() {
+        // a clock never goes backwards, and always moves past what it observes
+        let mut a = Clock::new(1);
+        let mut b = Clock::new(2);
+        let first = a.tick(100);
+        assert!(a.tick(90) > first);
+        let ahead = b.tick(500);
+        let observed = a.observe(ahead, 120);
+        assert!(observed > ahead && observed.node == 1);
+        assert!(a.tick(130) > observed);
+        assert_eq!(a.tick(600).counter, 0);
+
+        let seed = Event::with_ts(0, BTreeSet::new());
+        let root = BTreeSet::from([seed.id.clone()]);
+        let e1 = Event::with_ts(1, root.clone());
+        let e2 = Event::with_ts(2, root.clone());
+        let e3 = Event::with_ts(3, root.clone());
+        let hlc = |wall_ms, node| Hlc {
+            wall_ms,
+            counter: 0,
+            node,
+        };
+        let u1 = update("title", "text", hlc(20, 1), b"from a");
+        let u2 = update("title", "text", hlc(10, 2), b"from b");
+        let c1 = update("likes", "counter", hlc(10, 1), &2u64.to_be_bytes());
+        let c2 = update("likes", "counter", hlc(11, 2), &3u64.to_be_bytes());
+
+        let replica = || Replica::new(Resolver::new().register("counter", sum()));
+        let mut forwards = replica();
+        let mut backwards = replica();
+        forwards.apply(seed.clone(), None).unwrap();
+        backwards.apply(seed.clone(), None).unwrap();
+        assert!(forwards
+            .apply(e1.clone(), Some(u1.clone()))
+            .unwrap()
+            .is_none());
+        let resolution = forwards
+            .apply(e2.clone(), Some(u2.clone()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(resolution.strategy, "last-writer-wins");
+        assert_eq!(resolution.applied, e2.id);
+        assert_eq!(
+            resolution.inputs,
+            vec![
+                Candidate {
+                    event: e2.id.clone(),
+                    update: u2.clone()
+                },
+                Candidate {
+                    event: e1.id.clone(),
+                    update: u1.clone()
+                },
+            ]
+        );
+        assert_eq!(forwards.value("title"), Some(&b"from a"[..]));
+
+        // applied in the other order, the same value is reached
+        backwards.apply(e2.clone(), Some(u2)).unwrap();
+        backwards.apply(e1.clone(), Some(u1)).unwrap();
+        assert_eq!(backwards.value("title"), Some(&b"from a"[..]));
+        // applying an event again changes nothing
+        assert!(backwards.apply(e1.clone(), None).unwrap().is_none());
+        assert_eq!(backwards.resolutions().len(), 1);
+
+        // a registered strategy is used for its payload type
+        forwards.apply(e3.clone(), Some(c1)).unwrap();
+        let e4 = Event::with_ts(4, BTreeSet::from([e2.id.clone()]));
+        let resolution = forwards.apply(e4.clone(), Some(c2)).unwrap().unwrap();
+        assert_eq!(resolution.strategy, "sum");
+        assert_eq!(forwards.value("likes"), Some(&5u64.to_be_bytes()[..]));
+
+        // an update made knowing of every other supersedes them without a resolution
+        let e5 = Event::with_ts(
+            5,
+            BTreeSet::from([e1.id.clone(), e2.id.clone(), e3.id.clone()]),
+        );
+        let u5 = update("title", "text", hlc(1, 3), b"settled");
+        assert!(forwards.apply(e5, Some(u5)).unwrap().is_none());
+        assert_eq!(forwards.value("title"), Some(&b"settled"[..]));
+        assert_eq!(forwards.resolutions().len(), 2);
+
+        // events can't be applied before their precursors
+        let orphan = Event::with_ts(6, BTreeSet::from([e4.id.clone()]));
+        assert!(replica().apply(orphan, None).is_err());
+    }
+}
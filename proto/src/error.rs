@@ -0,0 +1,99 @@
+use serde::{Deserialize, Serialize};
+
+/// What went wrong with a request, broadly enough for a client to decide what to do about it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ErrorKind {
+    /// The thing the request refers to doesn't exist
+    NotFound,
+    /// The request itself is malformed or can't be satisfied as asked. Retrying won't help.
+    InvalidRequest,
+    /// The request conflicts with the current state, eg. a stale snapshot
+    Conflict,
+    /// The server is too busy or shutting down. Retrying later may succeed.
+    Unavailable,
+    /// The request was cancelled before it finished
+    Cancelled,
+    /// Anything else, usually a bug or a storage failure
+    Internal,
+    /// The request didn't carry the credentials it needs, eg. a capture token
+    Unauthorized,
+    /// Reading or writing the database failed
+    Storage,
+    /// A message or stored record couldn't be encoded or decoded
+    Serialization,
+    /// The client is making requests too quickly. Retrying after `retry_after_ms` will succeed.
+    RateLimited,
+    /// The request may not be made at all, eg. a WebSocket from an origin which isn't allowed.
+    /// Other credentials won't help.
+    Forbidden,
+}
+
+impl ErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::NotFound => "NotFound",
+            ErrorKind::InvalidRequest => "InvalidRequest",
+            ErrorKind::Conflict => "Conflict",
+            ErrorKind::Unavailable => "Unavailable",
+            ErrorKind::Cancelled => "Cancelled",
+            ErrorKind::Internal => "Internal",
+            ErrorKind::Unauthorized => "Unauthorized",
+            ErrorKind::Storage => "Storage",
+            ErrorKind::Serialization => "Serialization",
+            ErrorKind::RateLimited => "RateLimited",
+            ErrorKind::Forbidden => "Forbidden",
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The response to a request which failed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorPayload {
+    pub kind: ErrorKind,
+    pub message: String,
+    // the request field which was at fault, for InvalidRequest
+    pub field: Option<String>,
+    // how long to wait before retrying, for RateLimited
+    pub retry_after_ms: Option<u64>,
+    // the failed request's trace id, to find the server's logs of it by
+    pub trace_id: Option<ulid::Ulid>,
+}
+
+impl ErrorPayload {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+            field: None,
+            retry_after_ms: None,
+            trace_id: None,
+        }
+    }
+
+    pub fn field(mut self, field: impl Into<String>) -> Self {
+        self.field = Some(field.into());
+        self
+    }
+
+    pub fn retry_after_ms(mut self, ms: u64) -> Self {
+        self.retry_after_ms = Some(ms);
+        self
+    }
+
+    pub fn trace_id(mut self, trace_id: ulid::Ulid) -> Self {
+        self.trace_id = Some(trace_id);
+        self
+    }
+}
+
+impl std::fmt::Display for ErrorPayload {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.kind, self.message)
+    }
+}
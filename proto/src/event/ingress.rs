@@ -1,12 +1,14 @@
-use std::{collections::HashMap, net::SocketAddr};
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+};
 
 use serde::{Deserialize, Serialize};
 use ulid::Ulid;
-use wasm_bindgen::prelude::*;
 // use crate::query::Record;
 use bytes::Bytes;
 
-use crate::record::{Direction, PaginatedCursor, Record};
+use crate::record::{Direction, PaginatedCursor, Record, SnapshotToken};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct IngressLog {
@@ -19,6 +21,8 @@ pub struct IngressLog {
     pub query: HashMap<String, String>,
     pub headers: HashMap<String, String>,
     pub body: Bytes,
+    // the trace id of the capture which stored it, see Request::trace_id
+    pub trace_id: Option<Ulid>,
 }
 impl Record for IngressLog {
     type ID = Ulid;
@@ -32,12 +36,199 @@ pub struct FetchIngressLogsRequest {
     pub direction: Direction,
     pub limit: usize,
     pub cursor: PaginatedCursor,
+    // If set, bodies are replaced with a preview of at most this many bytes
+    pub preview_bytes: Option<usize>,
+    // Read as of the snapshot returned with a previous page, rather than the latest data
+    pub snapshot: Option<SnapshotToken>,
+    // Fetch the logs captured for this source (see --sources) rather than the shared ingress tree
+    pub source: Option<String>,
+    // Only fetch the logs which match, paging through them as if there were no others
+    pub filter: Option<IngressLogFilter>,
+    // Include a thumbnail of each log with an image body
+    pub thumbnails: bool,
+}
+
+/// Narrows a fetch down to the logs which match every field which is set
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct IngressLogFilter {
+    // Matched ignoring case
+    pub method: Option<String>,
+    // Matched ignoring case, against the Host header as it was sent
+    pub host: Option<String>,
+    // The start of the path below /ingress, with or without a leading slash
+    pub path_prefix: Option<String>,
+    // Captured at or after this time
+    pub from: Option<chrono::DateTime<chrono::Utc>>,
+    // Captured before this time
+    pub to: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl IngressLogFilter {
+    /// Whether a log matches the method, host and path prefix. The time range is left to the
+    /// caller, as logs are stored in capture order and so it's better applied to the keys read.
+    pub fn matches(&self, log: &IngressLog) -> bool {
+        let method = self
+            .method
+            .as_ref()
+            .is_none_or(|method| log.method.eq_ignore_ascii_case(method));
+        let host = self
+            .host
+            .as_ref()
+            .is_none_or(|host| log.host.eq_ignore_ascii_case(host));
+        let path = self
+            .path_prefix
+            .as_ref()
+            .is_none_or(|prefix| log.path.starts_with(prefix.trim_start_matches('/')));
+        method && host && path
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct IngressLogItem {
+    pub key: Vec<u8>,
+    pub log: IngressLog,
+    // Present when previews were requested, in which case log.body is left empty
+    pub preview: Option<BodyPreview>,
+    // Sorted, see TagIngressLogsRequest
+    pub tags: Vec<String>,
+    // Present when thumbnails were requested and the body is an image which could be scaled down
+    pub thumbnail: Option<Thumbnail>,
+    // The tree's computed fields which could be derived from the log, by name, eg. the event type
+    // from a JSON body, so lists can show them without fetching bodies
+    pub computed: BTreeMap<String, String>,
+}
+
+/// Add a tag to, or remove it from, every log which matches a filter, eg. to triage a burst of
+/// related captures at once. The server works through the logs in batches, pushing a TagProgress
+/// with this request's id after each one, and answers with the totals once it's done.
+#[derive(Serialize, Deserialize)]
+pub struct TagIngressLogsRequest {
+    // Tag the logs captured for this source rather than those in the shared ingress tree
+    pub source: Option<String>,
+    pub filter: IngressLogFilter,
+    pub tag: String,
+    // Remove the tag rather than add it
+    pub remove: bool,
+    // Count what would change without changing anything
+    pub dry_run: bool,
+}
+
+/// Find the logs most like one already captured, eg. a request which caused a problem, without
+/// having to compose a filter: those with the same method, host and path, and a body of a
+/// similar shape and size, most similar first. Only the most recent logs are compared.
+#[derive(Serialize, Deserialize)]
+pub struct FindSimilarRequest {
+    // The log to compare with, as in IngressLogItem::key
+    pub key: Vec<u8>,
+    // Where the log is, and the others are looked for: this source's logs rather than the shared
+    // ingress tree
+    pub source: Option<String>,
+    pub limit: usize,
+    // If set, bodies are replaced with a preview of at most this many bytes
+    pub preview_bytes: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FindSimilarResponse {
+    // Most similar first, not including the log compared with
+    pub items: Vec<SimilarLog>,
+    // How many logs were compared, the most recent first
+    pub scanned: u64,
+}
+
+/// A single log by its event id, eg. for a detail pane, with its body in full
+#[derive(Serialize, Deserialize)]
+pub struct FetchIngressLogRequest {
+    pub event_id: Ulid,
+    // Look in this source's logs rather than the shared ingress tree
+    pub source: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FetchIngressLogResponse {
+    // None if there's no such log
+    pub log: Option<IngressLog>,
+}
+
+/// Remove a log, eg. because it captured a secret: delete it outright, or redact it, stripping its
+/// body, headers and query but keeping the rest. Either way a tombstone is left in its place.
+#[derive(Serialize, Deserialize)]
+pub struct RemoveIngressLogRequest {
+    pub event_id: Ulid,
+    // The log is one of this source's rather than in the shared ingress tree
+    pub source: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RemoveIngressLogResponse {
+    // None if there's no such log
+    pub tombstone: Option<IngressLogTombstone>,
+}
+
+/// A record that a log was deleted or redacted, which doesn't hold anything the log did. Pushed
+/// to subscribers of the ingress_tombstones tree as IngressLogRemoved.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct IngressLogTombstone {
+    pub event_id: Ulid,
+    // The tree the log was in, eg. ingress or ingress/github
+    pub tree: String,
+    pub removed_at: chrono::DateTime<chrono::Utc>,
+    // Whether the log was kept, without its body, headers and query, rather than deleted
+    pub redacted: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SimilarLog {
+    pub item: IngressLogItem,
+    // From 0, nothing in common, to 1, the same method, host and path, and a body with the same
+    // JSON keys and length
+    pub score: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
+pub struct TagProgress {
+    // Logs read so far
+    pub scanned: u64,
+    // Of those, the logs which match the filter
+    pub matched: u64,
+    // Of those, the logs which were (or on a dry run, would be) changed, the rest being tagged
+    // or untagged already
+    pub changed: u64,
+}
+
+/// A size-limited rendition of a body, for list views.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BodyPreview {
+    // The start of the body, cut on a character boundary for text.
+    // JSON bodies are pretty-printed before being cut.
+    pub content: Bytes,
+    pub json: bool,
+    // If true the UI should offer to load the full body
+    pub truncated: bool,
+    pub full_length: usize,
+}
+
+/// A small rendition of an image body, to be shown inline, eg. as a data URL.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Thumbnail {
+    // image/png, or image/jpeg for a JPEG's own embedded thumbnail
+    pub content_type: String,
+    pub width: u32,
+    pub height: u32,
+    pub data: Bytes,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct FetchIngressLogsResponse {
-    pub items: Vec<(Vec<u8>, IngressLog)>,
+    pub items: Vec<IngressLogItem>,
     pub limit: usize,
     pub has_more_before: bool,
     pub has_more_after: bool,
+    // The snapshot this page was read from, to be passed along when fetching adjacent pages
+    pub snapshot: SnapshotToken,
+    // How many logs there are in all, as of the snapshot. Only given for fetches without a filter
+    pub total_count: Option<u64>,
+    // How many of those come before the page's first item, in the order it's displayed. Only
+    // given when the page is near enough the start or the end of them to be worked out
+    pub position: Option<u64>,
 }
@@ -0,0 +1,35 @@
+use serde::{Deserialize, Serialize};
+
+use crate::record::SnapshotToken;
+
+/// Opaque marker of how far an export has progressed. Passing it back in a subsequent
+/// ExportRequest resumes the export after the last acknowledged key instead of starting over.
+/// Every chunk of an export is read from the snapshot taken when the first chunk was read, while
+/// the server still has it. Once it's gone, eg. after a restart, the export carries on after the
+/// key from a new snapshot.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ContinuationToken {
+    pub tree: String,
+    pub after: Vec<u8>,
+    pub snapshot: Option<SnapshotToken>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportRequest {
+    pub tree: String,
+    pub continuation: Option<ContinuationToken>,
+    pub limit: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportRecord {
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ExportChunk {
+    pub records: Vec<ExportRecord>,
+    // None once the export is complete
+    pub continuation: Option<ContinuationToken>,
+}
@@ -0,0 +1,32 @@
+use serde::{Deserialize, Serialize};
+
+/// Sent by either side of a connection to check that the other is still there, as proxies
+/// silently drop WebSockets which have been idle for a while. The other side answers with a Pong
+/// straight away, ahead of any requests it is busy with.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Ping {
+    // counts up from 0 on each connection
+    pub sequence: u64,
+    // milliseconds since the Unix epoch, by the sender's clock
+    pub sent_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Pong {
+    // the sequence number of the Ping being answered
+    pub sequence: u64,
+    // the Ping's sent_at, so its sender can work out the round trip time by its own clock
+    pub ping_sent_at: u64,
+    // milliseconds since the Unix epoch, by the clock of the side answering
+    pub sent_at: u64,
+}
+
+impl Ping {
+    pub fn pong(&self, now: u64) -> Pong {
+        Pong {
+            sequence: self.sequence,
+            ping_sent_at: self.sent_at,
+            sent_at: now,
+        }
+    }
+}
@@ -0,0 +1,32 @@
+use serde::{Deserialize, Serialize};
+
+/// Fetch a value from the application key-value store
+#[derive(Serialize, Deserialize)]
+pub struct GetKvRequest {
+    pub tenant: String,
+    pub key: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetKvResponse {
+    pub value: Option<Vec<u8>>,
+}
+
+/// Set (or delete, if value is None) a value in the application key-value store.
+/// If `expected` is provided the write only happens if the current value matches it,
+/// where Some(None) means the key must not currently exist.
+#[derive(Serialize, Deserialize)]
+pub struct SetKvRequest {
+    pub tenant: String,
+    pub key: String,
+    pub value: Option<Vec<u8>>,
+    pub expected: Option<Option<Vec<u8>>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SetKvResponse {
+    // false if the compare-and-swap failed
+    pub success: bool,
+    // the value after the operation, or the conflicting value if the compare-and-swap failed
+    pub current: Option<Vec<u8>>,
+}
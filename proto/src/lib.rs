@@ -1,7 +1,24 @@
+pub mod alert;
+pub mod dag;
+pub mod error;
 pub mod event;
+pub mod export;
+pub mod heartbeat;
+pub mod kv;
 pub mod message;
+pub mod metrics;
 pub mod record;
+pub mod subscription;
+pub mod sync;
 
+pub use alert::*;
+pub use error::*;
 pub use event::*;
+pub use export::*;
+pub use heartbeat::*;
+pub use kv::*;
 pub use message::*;
+pub use metrics::*;
 pub use record::*;
+pub use subscription::*;
+pub use sync::*;
@@ -1,31 +1,298 @@
-use crate::event::ingress::{FetchIngressLogsRequest, FetchIngressLogsResponse};
+use crate::alert::Alert;
+use crate::error::ErrorPayload;
+use crate::event::ingress::{
+    FetchIngressLogRequest, FetchIngressLogResponse, FetchIngressLogsRequest,
+    FetchIngressLogsResponse, FindSimilarRequest, FindSimilarResponse, IngressLog, IngressLogItem,
+    IngressLogTombstone, RemoveIngressLogRequest, RemoveIngressLogResponse, TagIngressLogsRequest,
+    TagProgress,
+};
+use crate::export::{ExportChunk, ExportRequest};
+use crate::heartbeat::{Ping, Pong};
+use crate::kv::{GetKvRequest, GetKvResponse, SetKvRequest, SetKvResponse};
+use crate::metrics::ReadStatsResponse;
+use crate::record::{
+    CreateRecordRequest, CreateRecordResponse, DeleteRecordRequest, DeleteRecordResponse,
+    FetchInvalidRecordsRequest, FetchInvalidRecordsResponse, GetRecordRequest, GetRecordResponse,
+    SetCollectionSchemaRequest, SetCollectionSchemaResponse, UpdateRecordRequest,
+    UpdateRecordResponse,
+};
+use crate::subscription::{
+    IngressLogsSampled, KeyChanged, RecordChanged, ReplayDeliveriesRequest,
+    ReplayDeliveriesResponse, SubscribeRequest, UnsubscribeRequest,
+};
+use crate::sync::{
+    AdvertiseHeads, EventsReceived, RequestMissing, SendEvents, VerifyBasisRequest,
+    VerifyBasisResponse,
+};
+use bytes::Bytes;
 use serde::{Deserialize, Serialize};
+use ulid::Ulid;
 
+// responses are most of what's sent, so boxing them would cost an allocation for each
+#[allow(clippy::large_enum_variant)]
 #[derive(Serialize, Deserialize)]
 pub enum Message {
     Request(Request),
     Response(Response),
+    Ping(Ping),
+    Pong(Pong),
+}
+
+/// How Messages are encoded on a WebSocket connection. Connections start out exchanging bincode in
+/// binary frames. A client which can't speak bincode (browser devtools, websocat) can send the text
+/// frame `encoding:json` to switch to JSON text frames for the rest of the connection, and
+/// `encoding:bincode` to switch back. `encoding:cbor` and `encoding:msgpack` switch to CBOR or
+/// MessagePack in binary frames, which unlike bincode describe themselves, so clients in other
+/// languages can use off the shelf libraries for them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Bincode,
+    Json,
+    Cbor,
+    MessagePack,
+}
+
+impl Encoding {
+    /// The text frame which switches a connection to this encoding
+    pub fn negotiation(&self) -> &'static str {
+        match self {
+            Encoding::Bincode => "encoding:bincode",
+            Encoding::Json => "encoding:json",
+            Encoding::Cbor => "encoding:cbor",
+            Encoding::MessagePack => "encoding:msgpack",
+        }
+    }
+
+    /// The encoding a text frame asks for, if it is a negotiation frame
+    pub fn from_negotiation(text: &str) -> Option<Self> {
+        [
+            Encoding::Bincode,
+            Encoding::Json,
+            Encoding::Cbor,
+            Encoding::MessagePack,
+        ]
+        .into_iter()
+        .find(|encoding| text.trim() == encoding.negotiation())
+    }
+}
+
+/// How large Response payloads are compressed on a WebSocket connection. They aren't until the
+/// client sends the text frame `compression:deflate` or `compression:zstd`, after which payloads
+/// over a few KiB are sent as ResponsePayload::Compressed, and `compression:none` stops it again.
+/// A server built without zstd ignores `compression:zstd`, so clients which ask for it should
+/// still expect uncompressed payloads.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Deflate,
+    Zstd,
+}
+
+impl Compression {
+    /// The text frame which turns this compression on
+    pub fn negotiation(&self) -> &'static str {
+        match self {
+            Compression::Deflate => "compression:deflate",
+            Compression::Zstd => "compression:zstd",
+        }
+    }
+
+    /// The text frame which turns compression off
+    pub const NEGOTIATE_NONE: &'static str = "compression:none";
+
+    /// The compression a text frame asks for, if it is a compression negotiation frame: Some(None)
+    /// if it turns compression off
+    pub fn from_negotiation(text: &str) -> Option<Option<Self>> {
+        if text.trim() == Self::NEGOTIATE_NONE {
+            return Some(None);
+        }
+        [Compression::Deflate, Compression::Zstd]
+            .into_iter()
+            .find(|compression| text.trim() == compression.negotiation())
+            .map(Some)
+    }
+}
+
+/// A ResponsePayload encoded as the connection's Messages are, JSON for JSON connections, then
+/// compressed. Decompress and decode it for the payload the response would otherwise have had.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CompressedPayload {
+    pub compression: Compression,
+    pub payload: Bytes,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Request {
     pub id: usize,
+    // Set by the client for mutating requests so that retries after an ambiguous failure
+    // (e.g. connection dropped after sending) are not applied twice
+    pub idempotency_key: Option<Ulid>,
+    // Ties the client's logs of the request to the server's, which are in a span with it. The
+    // server assigns one to requests which don't set it, and answers with it either way.
+    pub trace_id: Option<Ulid>,
     pub payload: RequestPayload,
 }
 
 #[derive(Serialize, Deserialize)]
 pub enum RequestPayload {
     FetchIngressLogs(FetchIngressLogsRequest),
+    Export(ExportRequest),
+    GetKv(GetKvRequest),
+    SetKv(SetKvRequest),
+    Subscribe(SubscribeRequest),
+    Unsubscribe(UnsubscribeRequest),
+    CreateRecord(CreateRecordRequest),
+    UpdateRecord(UpdateRecordRequest),
+    DeleteRecord(DeleteRecordRequest),
+    Cancel(CancelRequest),
+    // Sent by a client once it has connected, see HelloResponse
+    Hello,
+    // An admin request for the server's per-tree read statistics, see ReadStatsResponse
+    ReadStats,
+    GetRecord(GetRecordRequest),
+    SetCollectionSchema(SetCollectionSchemaRequest),
+    FetchInvalidRecords(FetchInvalidRecordsRequest),
+    ReplayDeliveries(ReplayDeliveriesRequest),
+    TagIngressLogs(TagIngressLogsRequest),
+    // Sent between servers to keep their DAGs in step, see sync.rs
+    AdvertiseHeads(AdvertiseHeads),
+    RequestMissing(RequestMissing),
+    SendEvents(SendEvents),
+    VerifyBasis(VerifyBasisRequest),
+    FindSimilar(FindSimilarRequest),
+    // A fetch which then follows what it fetched: answered with the page, as FetchIngressLogs
+    // would be, after which every log written on the cursor's side of it which matches the
+    // filter is pushed as a LiveQueryItem, with the request's id, until the request is cancelled
+    // or unsubscribed from. Writes made while the page is read are pushed after it, so none are
+    // missed or seen twice. The snapshot has to be left unset.
+    LiveQuery(FetchIngressLogsRequest),
+    FetchIngressLog(FetchIngressLogRequest),
+    DeleteIngressLog(RemoveIngressLogRequest),
+    RedactIngressLog(RemoveIngressLogRequest),
+}
+
+impl RequestPayload {
+    /// Whether this request changes server state, and so should carry an idempotency key
+    pub fn is_mutation(&self) -> bool {
+        match self {
+            RequestPayload::FetchIngressLogs(_) => false,
+            RequestPayload::Export(_) => false,
+            RequestPayload::GetKv(_) => false,
+            RequestPayload::SetKv(_) => true,
+            RequestPayload::Subscribe(_) => false,
+            RequestPayload::Unsubscribe(_) => false,
+            RequestPayload::CreateRecord(_) => true,
+            RequestPayload::UpdateRecord(_) => true,
+            RequestPayload::DeleteRecord(_) => true,
+            RequestPayload::Cancel(_) => false,
+            RequestPayload::Hello => false,
+            RequestPayload::ReadStats => false,
+            RequestPayload::GetRecord(_) => false,
+            RequestPayload::SetCollectionSchema(_) => true,
+            RequestPayload::FetchInvalidRecords(_) => false,
+            RequestPayload::ReplayDeliveries(_) => false,
+            RequestPayload::TagIngressLogs(request) => !request.dry_run,
+            RequestPayload::AdvertiseHeads(_) => false,
+            RequestPayload::RequestMissing(_) => false,
+            RequestPayload::SendEvents(_) => true,
+            RequestPayload::VerifyBasis(_) => false,
+            RequestPayload::FindSimilar(_) => false,
+            RequestPayload::LiveQuery(_) => false,
+            RequestPayload::FetchIngressLog(_) => false,
+            RequestPayload::DeleteIngressLog(_) => true,
+            RequestPayload::RedactIngressLog(_) => true,
+        }
+    }
+}
+
+/// Ask the server to stop working on an earlier request, eg. because the client has given up
+/// waiting for it. The cancelled request is answered with an Error if it hadn't already been
+/// answered; the cancel request itself gets no response.
+#[derive(Serialize, Deserialize)]
+pub struct CancelRequest {
+    pub request_id: usize,
+}
+
+/// Hints about what a newly connected client is likely to ask for first, eg. the newest page of
+/// ingress logs, so that it can warm its cache before the UI issues its first queries. Each hint
+/// is a read request which can be sent as is. There may be none.
+#[derive(Serialize, Deserialize)]
+pub struct HelloResponse {
+    pub prefetch: Vec<RequestPayload>,
+    // identifies this connection, eg. for ReplayDeliveriesRequest when reporting a problem
+    pub session: Ulid,
+}
+
+/// Pushed to every connection when the server starts draining, eg. ahead of a restart, and not
+/// tied to any request, so its request_id means nothing. The server takes no new connections
+/// while draining, and exits once the open ones have gone or a timeout has passed, so clients
+/// should finish up and reconnect, to reconnect_to if it's given and otherwise to wherever they
+/// connected before, which behind a load balancer will be another server.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct GoingAway {
+    pub reconnect_to: Option<String>,
+    // how long before connecting to this server again might work
+    pub retry_after_ms: u64,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Response {
     pub request_id: usize,
+    // Position of this message among all those the server has sent on this connection, starting
+    // from 0 and assigned as it is queued. A gap means a message was dropped.
+    pub sequence: u64,
+    // The trace id of the request answered. Pushes have none.
+    pub trace_id: Option<Ulid>,
     pub payload: ResponsePayload,
 }
 
 #[derive(Serialize, Deserialize)]
 pub enum ResponsePayload {
     FetchIngressLogs(FetchIngressLogsResponse),
-    Error(String),
+    Export(ExportChunk),
+    GetKv(GetKvResponse),
+    SetKv(SetKvResponse),
+    Subscribed,
+    Unsubscribed,
+    // Pushed to subscribers of the ingress tree whenever a new log is captured
+    IngressLogAppended(IngressLog),
+    CreateRecord(CreateRecordResponse),
+    UpdateRecord(UpdateRecordResponse),
+    DeleteRecord(DeleteRecordResponse),
+    // Pushed to sampling subscribers in place of skipped IngressLogAppended pushes
+    IngressLogsSampled(IngressLogsSampled),
+    Hello(HelloResponse),
+    // Pushed to subscribers of the alerts tree whenever an alert rule is raised or resolved
+    Alert(Alert),
+    // Pushed to key watches, see SubscribeRequest
+    KeyChanged(KeyChanged),
+    ReadStats(ReadStatsResponse),
+    GetRecord(GetRecordResponse),
+    SetCollectionSchema(SetCollectionSchemaResponse),
+    FetchInvalidRecords(FetchInvalidRecordsResponse),
+    ReplayDeliveries(ReplayDeliveriesResponse),
+    // The totals once a TagIngressLogsRequest is done
+    TagIngressLogs(TagProgress),
+    // Pushed with the id of a TagIngressLogsRequest as it works through the logs
+    TagProgress(TagProgress),
+    Error(ErrorPayload),
+    // The peer's heads, the answer to AdvertiseHeads
+    AdvertiseHeads(AdvertiseHeads),
+    // The answer to RequestMissing
+    SendEvents(SendEvents),
+    EventsReceived(EventsReceived),
+    GoingAway(GoingAway),
+    VerifyBasis(VerifyBasisResponse),
+    FindSimilar(FindSimilarResponse),
+    // Pushed to prefix subscriptions, see SubscribeRequest
+    RecordChanged(RecordChanged),
+    LiveQuery(FetchIngressLogsResponse),
+    // Pushed to a LiveQuery for each log written or rewritten since its page, without a thumbnail
+    LiveQueryItem(IngressLogItem),
+    FetchIngressLog(FetchIngressLogResponse),
+    DeleteIngressLog(RemoveIngressLogResponse),
+    RedactIngressLog(RemoveIngressLogResponse),
+    // Pushed to subscribers of the ingress_tombstones tree whenever a log is deleted or redacted
+    IngressLogRemoved(IngressLogTombstone),
+    // Any other payload, compressed, once the connection has negotiated a Compression
+    Compressed(CompressedPayload),
 }
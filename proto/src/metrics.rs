@@ -0,0 +1,21 @@
+use serde::{Deserialize, Serialize};
+
+/// Answers RequestPayload::ReadStats: every tree read since the server started, ranked by the
+/// number of bytes read from it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ReadStatsResponse {
+    pub trees: Vec<TreeReadStats>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct TreeReadStats {
+    pub tree: String,
+    // point reads
+    pub reads: u64,
+    pub scans: u64,
+    // the items yielded by all those scans
+    pub scanned: u64,
+    // keys and values returned by reads and scans
+    pub bytes_read: u64,
+    pub mean_scan_length: u64,
+}
@@ -1,5 +1,5 @@
 use serde::{Deserialize, Serialize};
-use wasm_bindgen::prelude::*;
+use ulid::Ulid;
 
 pub trait Record: serde::de::DeserializeOwned {
     type ID: Clone;
@@ -26,8 +26,105 @@ pub enum PaginatedCursor {
     After(Vec<u8>),
     // We are pointing to the key before this one
     Before(Vec<u8>),
-    // We are pointing to this key and forward
+    // We are pointing to this key and forward. An empty key is the first page
     StartingWith(Vec<u8>),
-    // We are pointing to this key and backwards
+    // We are pointing to this key and backwards. An empty key is the last page
     EndingWith(Vec<u8>),
 }
+
+/// A logical point in the server's write history. Reads which carry a snapshot token see the
+/// data as it was at that point, so paging through results isn't disturbed by concurrent writes.
+/// Tokens only remain valid for a bounded number of subsequent writes, and not across restarts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotToken {
+    // Identifies the server run which issued the token
+    pub epoch: Ulid,
+    pub sequence: u64,
+}
+
+/// Store a new record in the named collection. The server assigns its id.
+#[derive(Serialize, Deserialize)]
+pub struct CreateRecordRequest {
+    pub collection: String,
+    pub value: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateRecordResponse {
+    pub id: Ulid,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetRecordRequest {
+    pub collection: String,
+    pub id: Ulid,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GetRecordResponse {
+    // None if there is no such record
+    pub value: Option<Vec<u8>>,
+}
+
+/// Replace the value of an existing record. Fails if the record doesn't exist.
+#[derive(Serialize, Deserialize)]
+pub struct UpdateRecordRequest {
+    pub collection: String,
+    pub id: Ulid,
+    pub value: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UpdateRecordResponse {
+    pub previous: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeleteRecordRequest {
+    pub collection: String,
+    pub id: Ulid,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeleteRecordResponse {
+    // None if there was no such record
+    pub previous: Option<Vec<u8>>,
+}
+
+/// Register a JSON Schema, as JSON text, which the records of a collection are expected to
+/// match, or remove it with None. Records which don't match are still stored, but are marked
+/// invalid and can be listed with FetchInvalidRecordsRequest. The collection's existing records
+/// are checked against the new schema straight away.
+#[derive(Serialize, Deserialize)]
+pub struct SetCollectionSchemaRequest {
+    pub collection: String,
+    pub schema: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SetCollectionSchemaResponse {
+    // how many of the collection's existing records don't match the new schema
+    pub invalid: u64,
+}
+
+/// List a collection's records which don't match its schema, in id order
+#[derive(Serialize, Deserialize)]
+pub struct FetchInvalidRecordsRequest {
+    pub collection: String,
+    // start after this record, to page through them
+    pub after: Option<Ulid>,
+    pub limit: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct InvalidRecord {
+    pub id: Ulid,
+    // why the record doesn't match, one message per violation
+    pub errors: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FetchInvalidRecordsResponse {
+    pub records: Vec<InvalidRecord>,
+    pub has_more: bool,
+}
@@ -0,0 +1,94 @@
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+/// Register interest in new records written to a tree. Pushes are delivered as Responses
+/// carrying the request id of the Subscribe request, which doubles as the subscription id.
+///
+/// With `sample_above` set, any one source (a client address, for the ingress tree) which writes
+/// more than that many records a second only has a sample of them pushed while it stays that busy.
+/// Quiet sources are unaffected. What was left out is reported in IngressLogsSampled pushes, so
+/// counts built from the pushes can still be kept accurate.
+///
+/// With `keys` set, the subscription is a watch on just those keys instead, which can be made on
+/// any tree. Every write or removal of one of them is pushed as a KeyChanged. With `debounce_ms`
+/// set as well, the changes to a key within that many milliseconds of the first one are held
+/// back and pushed as one, carrying the latest value, so a hot key doesn't flood the client.
+///
+/// With `prefix` set, the subscription is to every key in the tree starting with it, again on any
+/// tree, and every write or removal of one is pushed as a RecordChanged. An empty prefix covers
+/// the whole tree.
+///
+/// A connection can have at most 64 subscriptions at once, and they're all dropped when it closes.
+#[derive(Serialize, Deserialize)]
+pub struct SubscribeRequest {
+    pub tree: String,
+    pub sample_above: Option<u32>,
+    pub keys: Vec<Vec<u8>>,
+    pub debounce_ms: Option<u32>,
+    pub prefix: Option<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UnsubscribeRequest {
+    pub subscription_id: usize,
+}
+
+/// Pushed to a key watch when one of its keys is written or removed
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct KeyChanged {
+    pub key: Vec<u8>,
+    // None once the key has been removed
+    pub value: Option<Vec<u8>>,
+    // the number of changes this push stands for, more than one if they were debounced
+    pub changes: u32,
+}
+
+/// Pushed to a prefix subscription when a key under its prefix is written or removed
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RecordChanged {
+    pub key: Vec<u8>,
+    // None once the record has been removed
+    pub value: Option<Vec<u8>>,
+}
+
+/// Pushed to a sampling subscription after a second in which a source went over its threshold
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct IngressLogsSampled {
+    pub source: String,
+    // pushes made for the source during the second, and those left out
+    pub delivered: u64,
+    pub skipped: u64,
+}
+
+/// An admin request for what the server pushed to a session's subscriptions, eg. to find out
+/// why a user's dashboard missed an event. The server only keeps this when started with
+/// --log-deliveries-for, and then only for that long, and for at most the latest 1000 pushes of
+/// each subscription. Sampling summaries aren't kept.
+#[derive(Serialize, Deserialize)]
+pub struct ReplayDeliveriesRequest {
+    // as given in the session's HelloResponse
+    pub session: Ulid,
+    // just this subscription's, rather than all of them
+    pub subscription_id: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReplayDeliveriesResponse {
+    // in the order they were pushed
+    pub deliveries: Vec<Delivery>,
+}
+
+/// One push made to a subscription
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Delivery {
+    pub subscription_id: usize,
+    // the sequence number the push was given, see Response
+    pub sequence: u64,
+    pub tree: String,
+    // of the record which was pushed
+    pub key: Vec<u8>,
+    // milliseconds since the epoch
+    pub pushed_at: u64,
+    // the push was dropped because the client was too far behind, so it never saw it
+    pub dropped: bool,
+}
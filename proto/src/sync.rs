@@ -0,0 +1,66 @@
+//! Messages for keeping two servers' DAGs in step. A node advertises its heads to a peer, which
+//! answers with its own, then asks for the events behind any heads it hasn't seen with
+//! RequestMissing, and sends the peer what it has missed with SendEvents.
+//!
+//! Clients can also check a node's DAG against a checkpoint they hold with VerifyBasisRequest,
+//! see dag::checkpoint.
+
+use serde::{Deserialize, Serialize};
+
+use crate::dag::{
+    checkpoint::{Checkpoint, InclusionProof},
+    Event, ID,
+};
+
+/// The events nothing builds on yet, and a sample of the history behind them, see
+/// Dag::locator. Sent by a node to a peer, which answers with its own.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AdvertiseHeads {
+    pub heads: Vec<ID>,
+    pub locator: Vec<ID>,
+}
+
+/// Ask for the events leading up to `want`, given the sender has `have` and everything before
+/// them. Answered with SendEvents, in order, with at most `limit` events. Ask again for the rest,
+/// with the events already sent added to `have`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RequestMissing {
+    pub want: Vec<ID>,
+    pub have: Vec<ID>,
+    pub limit: usize,
+}
+
+/// Events, each after its precursors, which the receiver either has or has been sent before it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SendEvents {
+    pub events: Vec<Event>,
+}
+
+/// The answer to SendEvents
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EventsReceived {
+    // how many of the events were new to the receiver
+    pub added: usize,
+}
+
+/// Ask for proofs that events are covered by a checkpoint, the one numbered `checkpoint` or
+/// else the latest, eg. one the client was given earlier, to confirm the node's history hasn't
+/// been rewritten since
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VerifyBasisRequest {
+    pub checkpoint: Option<u64>,
+    pub events: Vec<ID>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VerifyBasisResponse {
+    // as the node has it stored, which should be identical to the client's copy
+    pub checkpoint: Checkpoint,
+    pub latest: Checkpoint,
+    // the node's checkpoint key, which clients should check against one they trust rather than
+    // take from here
+    pub public_key: [u8; 32],
+    // for each requested event, in order, its proof against the checkpoint's root, or None if the
+    // node can't place it among the events the checkpoint covers
+    pub proofs: Vec<Option<InclusionProof>>,
+}
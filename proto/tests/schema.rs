@@ -0,0 +1,718 @@
+//! Checks that proto/schema.json describes the bincode encoding of the Rust protocol types.
+//!
+//! Sample messages are encoded with bincode, decoded using nothing but the schema, and the
+//! result compared with the same messages converted to JSON by serde.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use bytes::Bytes;
+use hydra_proto::*;
+use serde_json::{json, Map, Value};
+use ulid::Ulid;
+
+fn registry() -> Map<String, Value> {
+    let schema = include_str!("../schema.json");
+    serde_json::from_str(schema).expect("schema.json is not valid JSON")
+}
+
+struct Decoder<'a> {
+    registry: &'a Map<String, Value>,
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> &'a [u8] {
+        assert!(self.bytes.len() >= n, "ran out of bytes");
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        head
+    }
+
+    fn uint(&mut self, n: usize) -> u64 {
+        let mut buf = [0u8; 8];
+        buf[..n].copy_from_slice(self.take(n));
+        u64::from_le_bytes(buf)
+    }
+
+    fn len(&mut self) -> usize {
+        self.uint(8) as usize
+    }
+
+    fn format(&mut self, format: &Value) -> Value {
+        if let Some(primitive) = format.as_str() {
+            return match primitive {
+                "UNIT" => Value::Null,
+                "BOOL" => Value::Bool(self.uint(1) != 0),
+                "U8" => json!(self.uint(1)),
+                "U16" => json!(self.uint(2)),
+                "U32" => json!(self.uint(4)),
+                "U64" => json!(self.uint(8)),
+                "I64" => json!(self.uint(8) as i64),
+                "F32" => json!(f32::from_bits(self.uint(4) as u32)),
+                "STR" => {
+                    let len = self.len();
+                    json!(std::str::from_utf8(self.take(len)).expect("invalid utf8"))
+                }
+                "BYTES" => {
+                    let len = self.len();
+                    json!(self.take(len))
+                }
+                other => panic!("unknown primitive {}", other),
+            };
+        }
+
+        let (kind, inner) = single(format);
+        match kind {
+            "TYPENAME" => self.container(inner.as_str().unwrap()),
+            "OPTION" => match self.uint(1) {
+                0 => Value::Null,
+                _ => self.format(inner),
+            },
+            "SEQ" => {
+                let len = self.len();
+                Value::Array((0..len).map(|_| self.format(inner)).collect())
+            }
+            "MAP" => {
+                let len = self.len();
+                let mut map = Map::new();
+                for _ in 0..len {
+                    let key = self.format(&inner["KEY"]);
+                    let value = self.format(&inner["VALUE"]);
+                    map.insert(
+                        key.as_str().expect("map keys must be strings").into(),
+                        value,
+                    );
+                }
+                Value::Object(map)
+            }
+            "TUPLE" => Value::Array(
+                inner
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|f| self.format(f))
+                    .collect(),
+            ),
+            "TUPLEARRAY" => {
+                let size = inner["SIZE"].as_u64().unwrap();
+                Value::Array((0..size).map(|_| self.format(&inner["CONTENT"])).collect())
+            }
+            other => panic!("unknown format {}", other),
+        }
+    }
+
+    fn fields(&mut self, fields: &Value) -> Value {
+        let mut object = Map::new();
+        for field in fields.as_array().unwrap() {
+            let (name, format) = single(field);
+            object.insert(name.to_string(), self.format(format));
+        }
+        Value::Object(object)
+    }
+
+    fn container(&mut self, name: &str) -> Value {
+        let definition = self
+            .registry
+            .get(name)
+            .unwrap_or_else(|| panic!("{} is not in the schema", name));
+        let (kind, inner) = single(definition);
+        match kind {
+            "STRUCT" => self.fields(inner),
+            "NEWTYPESTRUCT" => self.format(inner),
+            "ENUM" => {
+                let index = self.uint(4);
+                let variant = inner
+                    .get(index.to_string())
+                    .unwrap_or_else(|| panic!("{} has no variant {}", name, index));
+                let (variant_name, variant_format) = single(variant);
+                let value = match variant_format.as_str() {
+                    Some("UNIT") => return json!(variant_name),
+                    _ => {
+                        let (kind, inner) = single(variant_format);
+                        match kind {
+                            "NEWTYPE" => self.format(inner),
+                            "STRUCT" => self.fields(inner),
+                            other => panic!("unsupported variant format {}", other),
+                        }
+                    }
+                };
+                json!({ variant_name: value })
+            }
+            other => panic!("unsupported container {}", other),
+        }
+    }
+}
+
+fn single(value: &Value) -> (&str, &Value) {
+    let object = value.as_object().expect("expected an object");
+    assert_eq!(object.len(), 1, "expected a single key in {}", value);
+    let (key, value) = object.iter().next().unwrap();
+    (key.as_str(), value)
+}
+
+fn decode(registry: &Map<String, Value>, name: &str, bytes: &[u8]) -> Value {
+    let mut decoder = Decoder { registry, bytes };
+    let value = decoder.container(name);
+    assert!(decoder.bytes.is_empty(), "{} left bytes unread", name);
+    value
+}
+
+fn check<T: serde::Serialize>(registry: &Map<String, Value>, name: &str, value: &T) {
+    let bytes = bincode::serialize(value).unwrap();
+    assert_eq!(
+        decode(registry, name, &bytes),
+        serde_json::to_value(value).unwrap(),
+        "schema for {} does not match the Rust type",
+        name
+    );
+}
+
+/// Messages can also be exchanged as JSON, so every one must survive a round trip through it
+fn check_json(message: &Message) {
+    let json = serde_json::to_string(message).unwrap();
+    let decoded: Message = serde_json::from_str(&json)
+        .unwrap_or_else(|e| panic!("{} does not round trip through JSON: {}", json, e));
+    assert_eq!(serde_json::to_string(&decoded).unwrap(), json);
+}
+
+fn variants(registry: &Map<String, Value>, name: &str) -> BTreeSet<String> {
+    let (_, variants) = single(&registry[name]);
+    variants
+        .as_object()
+        .unwrap()
+        .values()
+        .map(|variant| single(variant).0.to_string())
+        .collect()
+}
+
+// Exhaustive, so that adding a variant fails to compile until it has a sample here
+fn request_name(payload: &RequestPayload) -> &'static str {
+    match payload {
+        RequestPayload::FetchIngressLogs(_) => "FetchIngressLogs",
+        RequestPayload::Export(_) => "Export",
+        RequestPayload::GetKv(_) => "GetKv",
+        RequestPayload::SetKv(_) => "SetKv",
+        RequestPayload::Subscribe(_) => "Subscribe",
+        RequestPayload::Unsubscribe(_) => "Unsubscribe",
+        RequestPayload::CreateRecord(_) => "CreateRecord",
+        RequestPayload::UpdateRecord(_) => "UpdateRecord",
+        RequestPayload::DeleteRecord(_) => "DeleteRecord",
+        RequestPayload::Cancel(_) => "Cancel",
+        RequestPayload::Hello => "Hello",
+        RequestPayload::ReadStats => "ReadStats",
+        RequestPayload::GetRecord(_) => "GetRecord",
+        RequestPayload::SetCollectionSchema(_) => "SetCollectionSchema",
+        RequestPayload::FetchInvalidRecords(_) => "FetchInvalidRecords",
+        RequestPayload::ReplayDeliveries(_) => "ReplayDeliveries",
+        RequestPayload::TagIngressLogs(_) => "TagIngressLogs",
+        RequestPayload::AdvertiseHeads(_) => "AdvertiseHeads",
+        RequestPayload::RequestMissing(_) => "RequestMissing",
+        RequestPayload::SendEvents(_) => "SendEvents",
+        RequestPayload::VerifyBasis(_) => "VerifyBasis",
+        RequestPayload::FindSimilar(_) => "FindSimilar",
+        RequestPayload::LiveQuery(_) => "LiveQuery",
+        RequestPayload::FetchIngressLog(_) => "FetchIngressLog",
+        RequestPayload::DeleteIngressLog(_) => "DeleteIngressLog",
+        RequestPayload::RedactIngressLog(_) => "RedactIngressLog",
+    }
+}
+
+fn response_name(payload: &ResponsePayload) -> &'static str {
+    match payload {
+        ResponsePayload::FetchIngressLogs(_) => "FetchIngressLogs",
+        ResponsePayload::Export(_) => "Export",
+        ResponsePayload::GetKv(_) => "GetKv",
+        ResponsePayload::SetKv(_) => "SetKv",
+        ResponsePayload::Subscribed => "Subscribed",
+        ResponsePayload::Unsubscribed => "Unsubscribed",
+        ResponsePayload::IngressLogAppended(_) => "IngressLogAppended",
+        ResponsePayload::CreateRecord(_) => "CreateRecord",
+        ResponsePayload::UpdateRecord(_) => "UpdateRecord",
+        ResponsePayload::DeleteRecord(_) => "DeleteRecord",
+        ResponsePayload::IngressLogsSampled(_) => "IngressLogsSampled",
+        ResponsePayload::Hello(_) => "Hello",
+        ResponsePayload::Alert(_) => "Alert",
+        ResponsePayload::KeyChanged(_) => "KeyChanged",
+        ResponsePayload::ReadStats(_) => "ReadStats",
+        ResponsePayload::GetRecord(_) => "GetRecord",
+        ResponsePayload::SetCollectionSchema(_) => "SetCollectionSchema",
+        ResponsePayload::FetchInvalidRecords(_) => "FetchInvalidRecords",
+        ResponsePayload::ReplayDeliveries(_) => "ReplayDeliveries",
+        ResponsePayload::TagIngressLogs(_) => "TagIngressLogs",
+        ResponsePayload::TagProgress(_) => "TagProgress",
+        ResponsePayload::Error(_) => "Error",
+        ResponsePayload::AdvertiseHeads(_) => "AdvertiseHeads",
+        ResponsePayload::SendEvents(_) => "SendEvents",
+        ResponsePayload::EventsReceived(_) => "EventsReceived",
+        ResponsePayload::GoingAway(_) => "GoingAway",
+        ResponsePayload::VerifyBasis(_) => "VerifyBasis",
+        ResponsePayload::FindSimilar(_) => "FindSimilar",
+        ResponsePayload::RecordChanged(_) => "RecordChanged",
+        ResponsePayload::LiveQuery(_) => "LiveQuery",
+        ResponsePayload::LiveQueryItem(_) => "LiveQueryItem",
+        ResponsePayload::FetchIngressLog(_) => "FetchIngressLog",
+        ResponsePayload::DeleteIngressLog(_) => "DeleteIngressLog",
+        ResponsePayload::RedactIngressLog(_) => "RedactIngressLog",
+        ResponsePayload::IngressLogRemoved(_) => "IngressLogRemoved",
+        ResponsePayload::Compressed(_) => "Compressed",
+    }
+}
+
+fn snapshot() -> SnapshotToken {
+    SnapshotToken {
+        epoch: Ulid::from_parts(1, 2),
+        sequence: 42,
+    }
+}
+
+fn tombstone() -> IngressLogTombstone {
+    IngressLogTombstone {
+        event_id: Ulid::from_parts(3, 4),
+        tree: "ingress/github".to_string(),
+        removed_at: chrono::DateTime::from_timestamp(1_700_000_100, 0).unwrap(),
+        redacted: true,
+    }
+}
+
+fn ingress_log() -> IngressLog {
+    IngressLog {
+        event_id: Ulid::from_parts(3, 4),
+        date: chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+        // SocketAddr is human readable in JSON, so it is checked separately below
+        remote_addr: None,
+        method: "POST".to_string(),
+        host: "example.com".to_string(),
+        path: "hooks".to_string(),
+        query: HashMap::from([("a".to_string(), "1".to_string())]),
+        headers: HashMap::from([("content-type".to_string(), "text/plain".to_string())]),
+        body: Bytes::from_static(b"hello"),
+        trace_id: Some(Ulid::from_parts(9, 10)),
+    }
+}
+
+fn dag_events() -> Vec<dag::Event> {
+    let first = dag::Event::with_payload(1_700_000_000, b"first", BTreeSet::new());
+    let second =
+        dag::Event::with_payload(1_700_000_001, b"second", BTreeSet::from([first.id.clone()]));
+    vec![first, second]
+}
+
+fn checkpoint() -> dag::checkpoint::Checkpoint {
+    let leaves: Vec<dag::ID> = dag_events().into_iter().map(|e| e.id).collect();
+    dag::checkpoint::Checkpoint {
+        sequence: 3,
+        event_count: 2,
+        root: dag::checkpoint::MerkleTree::new(&leaves).root(),
+        heads: vec![leaves[1].clone()],
+        created_at_ms: 1_700_000_002_000,
+        signature: vec![9; 64],
+    }
+}
+
+fn request_samples() -> Vec<RequestPayload> {
+    vec![
+        RequestPayload::FetchIngressLogs(FetchIngressLogsRequest {
+            direction: Direction::Descending,
+            limit: 10,
+            cursor: PaginatedCursor::Before(vec![1, 2]),
+            preview_bytes: Some(64),
+            snapshot: Some(snapshot()),
+            source: Some("github".to_string()),
+            filter: Some(IngressLogFilter {
+                method: Some("POST".to_string()),
+                host: None,
+                path_prefix: Some("hooks/".to_string()),
+                from: chrono::DateTime::from_timestamp(1_700_000_000, 0),
+                to: None,
+            }),
+            thumbnails: true,
+        }),
+        RequestPayload::Export(ExportRequest {
+            tree: "ingress".to_string(),
+            continuation: Some(ContinuationToken {
+                tree: "ingress".to_string(),
+                after: vec![9],
+                snapshot: Some(snapshot()),
+            }),
+            limit: 100,
+        }),
+        RequestPayload::GetKv(GetKvRequest {
+            tenant: "t".to_string(),
+            key: "k".to_string(),
+        }),
+        RequestPayload::SetKv(SetKvRequest {
+            tenant: "t".to_string(),
+            key: "k".to_string(),
+            value: Some(vec![1]),
+            expected: Some(None),
+        }),
+        RequestPayload::Subscribe(SubscribeRequest {
+            tree: "ingress".to_string(),
+            sample_above: Some(100),
+            keys: vec![],
+            debounce_ms: None,
+            prefix: None,
+        }),
+        RequestPayload::Subscribe(SubscribeRequest {
+            tree: "kv".to_string(),
+            sample_above: None,
+            keys: vec![b"tenant\0counter".to_vec()],
+            debounce_ms: Some(250),
+            prefix: None,
+        }),
+        RequestPayload::Subscribe(SubscribeRequest {
+            tree: "records/notes".to_string(),
+            sample_above: None,
+            keys: vec![],
+            debounce_ms: None,
+            prefix: Some(b"2024-".to_vec()),
+        }),
+        RequestPayload::Unsubscribe(UnsubscribeRequest { subscription_id: 3 }),
+        RequestPayload::CreateRecord(CreateRecordRequest {
+            collection: "notes".to_string(),
+            value: vec![1, 2],
+        }),
+        RequestPayload::UpdateRecord(UpdateRecordRequest {
+            collection: "notes".to_string(),
+            id: Ulid::from_parts(7, 8),
+            value: vec![3],
+        }),
+        RequestPayload::DeleteRecord(DeleteRecordRequest {
+            collection: "notes".to_string(),
+            id: Ulid::from_parts(7, 8),
+        }),
+        RequestPayload::Cancel(CancelRequest { request_id: 4 }),
+        RequestPayload::Hello,
+        RequestPayload::ReadStats,
+        RequestPayload::GetRecord(GetRecordRequest {
+            collection: "notes".to_string(),
+            id: Ulid::from_parts(7, 8),
+        }),
+        RequestPayload::SetCollectionSchema(SetCollectionSchemaRequest {
+            collection: "notes".to_string(),
+            schema: Some(r#"{"type":"object"}"#.to_string()),
+        }),
+        RequestPayload::FetchInvalidRecords(FetchInvalidRecordsRequest {
+            collection: "notes".to_string(),
+            after: Some(Ulid::from_parts(7, 8)),
+            limit: 20,
+        }),
+        RequestPayload::ReplayDeliveries(ReplayDeliveriesRequest {
+            session: Ulid::from_parts(9, 10),
+            subscription_id: Some(3),
+        }),
+        RequestPayload::TagIngressLogs(TagIngressLogsRequest {
+            source: None,
+            filter: IngressLogFilter {
+                host: Some("hooks.github.com".to_string()),
+                ..Default::default()
+            },
+            tag: "flaky".to_string(),
+            remove: false,
+            dry_run: true,
+        }),
+        RequestPayload::AdvertiseHeads(AdvertiseHeads {
+            heads: vec![dag_events()[1].id.clone()],
+            locator: dag_events().into_iter().map(|e| e.id).collect(),
+        }),
+        RequestPayload::RequestMissing(RequestMissing {
+            want: vec![dag_events()[1].id.clone()],
+            have: vec![],
+            limit: 1000,
+        }),
+        RequestPayload::SendEvents(SendEvents {
+            events: dag_events(),
+        }),
+        RequestPayload::VerifyBasis(VerifyBasisRequest {
+            checkpoint: Some(3),
+            events: vec![dag_events()[0].id.clone()],
+        }),
+        RequestPayload::FindSimilar(FindSimilarRequest {
+            key: vec![1],
+            source: Some("github".to_string()),
+            limit: 20,
+            preview_bytes: Some(256),
+        }),
+        RequestPayload::LiveQuery(FetchIngressLogsRequest {
+            direction: Direction::Descending,
+            limit: 50,
+            cursor: PaginatedCursor::StartingWith(vec![]),
+            preview_bytes: None,
+            snapshot: None,
+            source: None,
+            filter: Some(IngressLogFilter {
+                host: Some("api.github.com".to_string()),
+                ..Default::default()
+            }),
+            thumbnails: false,
+        }),
+        RequestPayload::FetchIngressLog(FetchIngressLogRequest {
+            event_id: Ulid::from_parts(3, 4),
+            source: Some("github".to_string()),
+        }),
+        RequestPayload::DeleteIngressLog(RemoveIngressLogRequest {
+            event_id: Ulid::from_parts(3, 4),
+            source: None,
+        }),
+        RequestPayload::RedactIngressLog(RemoveIngressLogRequest {
+            event_id: Ulid::from_parts(3, 4),
+            source: Some("github".to_string()),
+        }),
+    ]
+}
+
+fn response_samples() -> Vec<ResponsePayload> {
+    vec![
+        ResponsePayload::FetchIngressLogs(FetchIngressLogsResponse {
+            items: vec![IngressLogItem {
+                key: vec![1],
+                log: ingress_log(),
+                preview: Some(BodyPreview {
+                    content: Bytes::from_static(b"hel"),
+                    json: false,
+                    truncated: true,
+                    full_length: 5,
+                }),
+                tags: vec!["flaky".to_string()],
+                thumbnail: Some(Thumbnail {
+                    content_type: "image/png".to_string(),
+                    width: 128,
+                    height: 64,
+                    data: Bytes::from_static(b"\x89PNG"),
+                }),
+                computed: BTreeMap::from([("event".to_string(), "push".to_string())]),
+            }],
+            limit: 10,
+            has_more_before: false,
+            has_more_after: true,
+            snapshot: snapshot(),
+            total_count: Some(120),
+            position: Some(40),
+        }),
+        ResponsePayload::Export(ExportChunk {
+            records: vec![ExportRecord {
+                key: vec![1],
+                value: vec![2, 3],
+            }],
+            continuation: None,
+        }),
+        ResponsePayload::GetKv(GetKvResponse { value: None }),
+        ResponsePayload::SetKv(SetKvResponse {
+            success: false,
+            current: Some(vec![4]),
+        }),
+        ResponsePayload::Subscribed,
+        ResponsePayload::Unsubscribed,
+        ResponsePayload::IngressLogAppended(ingress_log()),
+        ResponsePayload::CreateRecord(CreateRecordResponse {
+            id: Ulid::from_parts(7, 8),
+        }),
+        ResponsePayload::UpdateRecord(UpdateRecordResponse { previous: vec![1] }),
+        ResponsePayload::DeleteRecord(DeleteRecordResponse { previous: None }),
+        ResponsePayload::IngressLogsSampled(IngressLogsSampled {
+            source: "203.0.113.7".to_string(),
+            delivered: 110,
+            skipped: 90,
+        }),
+        ResponsePayload::Hello(HelloResponse {
+            prefetch: vec![RequestPayload::FetchIngressLogs(FetchIngressLogsRequest {
+                direction: Direction::Descending,
+                limit: 50,
+                cursor: PaginatedCursor::StartingWith(vec![]),
+                preview_bytes: Some(256),
+                snapshot: None,
+                source: None,
+                filter: None,
+                thumbnails: false,
+            })],
+            session: Ulid::from_parts(9, 10),
+        }),
+        ResponsePayload::Alert(Alert {
+            id: Ulid::from_parts(9, 10),
+            date: chrono::DateTime::from_timestamp(1_700_000_060, 0).unwrap(),
+            rule: "github-burst".to_string(),
+            state: AlertState::Raised,
+            value: 120,
+            message: "120 ingress logs in the last 60s, above 100".to_string(),
+        }),
+        ResponsePayload::KeyChanged(KeyChanged {
+            key: b"tenant\0counter".to_vec(),
+            value: Some(vec![7]),
+            changes: 3,
+        }),
+        ResponsePayload::ReadStats(ReadStatsResponse {
+            trees: vec![TreeReadStats {
+                tree: "ingress".to_string(),
+                reads: 0,
+                scans: 12,
+                scanned: 600,
+                bytes_read: 123_456,
+                mean_scan_length: 50,
+            }],
+        }),
+        ResponsePayload::GetRecord(GetRecordResponse {
+            value: Some(vec![5]),
+        }),
+        ResponsePayload::SetCollectionSchema(SetCollectionSchemaResponse { invalid: 3 }),
+        ResponsePayload::FetchInvalidRecords(FetchInvalidRecordsResponse {
+            records: vec![InvalidRecord {
+                id: Ulid::from_parts(7, 8),
+                errors: vec![r#""name" is a required property"#.to_string()],
+            }],
+            has_more: false,
+        }),
+        ResponsePayload::ReplayDeliveries(ReplayDeliveriesResponse {
+            deliveries: vec![Delivery {
+                subscription_id: 3,
+                sequence: 57,
+                tree: "ingress".to_string(),
+                key: b"test|01".to_vec(),
+                pushed_at: 1_700_000_000_000,
+                dropped: true,
+            }],
+        }),
+        ResponsePayload::TagIngressLogs(TagProgress {
+            scanned: 1000,
+            matched: 120,
+            changed: 100,
+        }),
+        ResponsePayload::TagProgress(TagProgress {
+            scanned: 500,
+            matched: 60,
+            changed: 50,
+        }),
+        ResponsePayload::Error(ErrorPayload::new(ErrorKind::NotFound, "oops")),
+        ResponsePayload::Error(
+            ErrorPayload::new(ErrorKind::RateLimited, "slow down")
+                .field("tag")
+                .retry_after_ms(1500)
+                .trace_id(Ulid::from_parts(9, 10)),
+        ),
+        ResponsePayload::AdvertiseHeads(AdvertiseHeads {
+            heads: vec![],
+            locator: vec![],
+        }),
+        ResponsePayload::SendEvents(SendEvents {
+            events: dag_events(),
+        }),
+        ResponsePayload::EventsReceived(EventsReceived { added: 2 }),
+        ResponsePayload::GoingAway(GoingAway {
+            reconnect_to: Some("wss://hydra.example.com/ws".to_string()),
+            retry_after_ms: 5000,
+        }),
+        ResponsePayload::VerifyBasis(VerifyBasisResponse {
+            checkpoint: checkpoint(),
+            latest: checkpoint(),
+            public_key: [4; 32],
+            proofs: vec![
+                dag::checkpoint::MerkleTree::new(&[dag_events()[0].id.clone()]).prove(0),
+                None,
+            ],
+        }),
+        ResponsePayload::FindSimilar(FindSimilarResponse {
+            items: vec![SimilarLog {
+                item: IngressLogItem {
+                    key: vec![2],
+                    log: ingress_log(),
+                    preview: None,
+                    tags: vec![],
+                    thumbnail: None,
+                    computed: BTreeMap::new(),
+                },
+                score: 0.75,
+            }],
+            scanned: 120,
+        }),
+        ResponsePayload::RecordChanged(RecordChanged {
+            key: b"2024-06".to_vec(),
+            value: None,
+        }),
+        ResponsePayload::LiveQuery(FetchIngressLogsResponse {
+            items: vec![],
+            limit: 50,
+            has_more_before: false,
+            has_more_after: false,
+            snapshot: snapshot(),
+            total_count: None,
+            position: None,
+        }),
+        ResponsePayload::LiveQueryItem(IngressLogItem {
+            key: vec![3],
+            log: ingress_log(),
+            preview: None,
+            tags: vec![],
+            thumbnail: None,
+            computed: BTreeMap::new(),
+        }),
+        ResponsePayload::FetchIngressLog(FetchIngressLogResponse {
+            log: Some(ingress_log()),
+        }),
+        ResponsePayload::DeleteIngressLog(RemoveIngressLogResponse { tombstone: None }),
+        ResponsePayload::RedactIngressLog(RemoveIngressLogResponse {
+            tombstone: Some(tombstone()),
+        }),
+        ResponsePayload::IngressLogRemoved(tombstone()),
+        ResponsePayload::Compressed(CompressedPayload {
+            compression: Compression::Zstd,
+            payload: Bytes::from_static(b"\x28\xb5\x2f\xfd"),
+        }),
+    ]
+}
+
+#[test]
+fn test_schema_matches_types() {
+    let registry = registry();
+
+    let requests = request_samples();
+    let names: BTreeSet<String> = requests.iter().map(|p| request_name(p).into()).collect();
+    assert_eq!(names, variants(&registry, "RequestPayload"));
+    for (id, payload) in requests.into_iter().enumerate() {
+        let request = Request {
+            id,
+            idempotency_key: Some(Ulid::from_parts(5, 6)),
+            trace_id: Some(Ulid::from_parts(7, 8)),
+            payload,
+        };
+        let message = Message::Request(request);
+        check(&registry, "Message", &message);
+        check_json(&message);
+    }
+
+    let responses = response_samples();
+    let names: BTreeSet<String> = responses.iter().map(|p| response_name(p).into()).collect();
+    assert_eq!(names, variants(&registry, "ResponsePayload"));
+    for (request_id, payload) in responses.into_iter().enumerate() {
+        let response = Response {
+            request_id,
+            sequence: request_id as u64 + 100,
+            // pushes have none
+            trace_id: (request_id % 2 == 0).then(|| Ulid::from_parts(7, 8)),
+            payload,
+        };
+        let message = Message::Response(response);
+        check(&registry, "Message", &message);
+        check_json(&message);
+    }
+
+    let names: BTreeSet<String> = ["Request", "Response", "Ping", "Pong"]
+        .into_iter()
+        .map(String::from)
+        .collect();
+    assert_eq!(names, variants(&registry, "Message"));
+    let ping = Ping {
+        sequence: 7,
+        sent_at: 1_700_000_000_000,
+    };
+    for message in [
+        Message::Pong(ping.pong(1_700_000_000_040)),
+        Message::Ping(ping),
+    ] {
+        check(&registry, "Message", &message);
+        check_json(&message);
+    }
+
+    for addr in ["127.0.0.1:9797", "[::1]:9797"] {
+        let addr: std::net::SocketAddr = addr.parse().unwrap();
+        decode(&registry, "SocketAddr", &bincode::serialize(&addr).unwrap());
+    }
+}
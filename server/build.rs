@@ -0,0 +1,35 @@
+//! Generates the server side of the gRPC service, see src/grpc.rs. Its messages are written by
+//! hand in src/grpc/messages.rs, as hydra.proto describes them, so protoc isn't needed.
+
+use tonic_build::manual::{Builder, Method, Service};
+
+fn main() {
+    let method = |name: &str, route_name: &str, input: &str, output: &str| {
+        Method::builder()
+            .name(name)
+            .route_name(route_name)
+            .input_type(format!("crate::grpc::messages::{}", input))
+            .output_type(format!("crate::grpc::messages::{}", output))
+            .codec_path("tonic::codec::ProstCodec")
+    };
+    let service = Service::builder()
+        .name("Ingress")
+        .package("hydra.v1")
+        .method(method("capture", "Capture", "CaptureRequest", "CaptureResponse").build())
+        .method(
+            method(
+                "fetch_ingress_logs",
+                "FetchIngressLogs",
+                "FetchIngressLogsRequest",
+                "FetchIngressLogsResponse",
+            )
+            .build(),
+        )
+        .method(
+            method("subscribe", "Subscribe", "SubscribeRequest", "IngressLog")
+                .server_streaming()
+                .build(),
+        )
+        .build();
+    Builder::new().build_client(false).compile(&[service]);
+}
@@ -0,0 +1,319 @@
+//! When records were last read or written, so that retention decisions can be based on how data
+//! is actually used rather than on its age alone.
+//!
+//! Tracking is off unless the server is started with `--track-access`. Accesses are noted in
+//! memory and written to the `access` tree in batches, and a record which is used over and over
+//! is written at most once per RESOLUTION, so keeping track costs a small fraction of the reads
+//! it follows. `GET /usage?older_than_days=N` reports, for each collection, the records created
+//! more than N days ago which have never been accessed, or not since then.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use crate::{
+    error::AppError,
+    handler::record::{side_key, COLLECTION_PREFIX},
+    storage::StorageEngine,
+    AppState,
+};
+
+pub const ACCESS_TREE: &str = "access";
+
+/// Holds when tracking first started, as it says nothing about accesses before then
+const SINCE_KEY: &[u8] = b"since";
+
+/// How often noted accesses are written out
+pub const FLUSH_EVERY: Duration = Duration::from_secs(30);
+
+/// A record's stored timestamp isn't rewritten until it is at least this much out of date
+const RESOLUTION: Duration = Duration::from_secs(3600);
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn decode_millis(value: &[u8]) -> Option<u64> {
+    value.try_into().ok().map(u64::from_be_bytes)
+}
+
+pub struct AccessTracker {
+    enabled: bool,
+    // by access key, when the record was last accessed, or None if it has since been deleted
+    pending: Mutex<HashMap<Vec<u8>, Option<u64>>>,
+}
+
+impl AccessTracker {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            pending: Mutex::default(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Note that a record was read or written. Accesses between flushes are coalesced, so only
+    /// the latest is written.
+    pub fn record(&self, tree: &str, key: &[u8], now: SystemTime) {
+        if self.enabled {
+            let mut pending = self.pending.lock().unwrap();
+            pending.insert(side_key(tree, key), Some(unix_millis(now)));
+        }
+    }
+
+    /// Note that a record was deleted, so that its timestamp is dropped with it
+    pub fn forget(&self, tree: &str, key: &[u8]) {
+        if self.enabled {
+            let mut pending = self.pending.lock().unwrap();
+            pending.insert(side_key(tree, key), None);
+        }
+    }
+
+    /// Write out the accesses noted since the last flush as one batch, returning how many
+    /// timestamps were written or removed
+    pub fn flush(&self, storage: &StorageEngine) -> Result<usize> {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        if pending.is_empty() {
+            return Ok(0);
+        }
+        // written directly rather than through the storage engine, as nothing subscribes to
+        // accesses and they'd otherwise show up in every tree's read statistics
+        let tree = storage.subtree(ACCESS_TREE)?;
+        let resolution = RESOLUTION.as_millis() as u64;
+        let mut batch = sled::Batch::default();
+        let mut written = 0;
+        for (key, accessed) in pending {
+            match accessed {
+                Some(accessed) => {
+                    let stored = tree.get(&key)?.as_deref().and_then(decode_millis);
+                    if stored.is_some_and(|stored| accessed < stored.saturating_add(resolution)) {
+                        continue;
+                    }
+                    batch.insert(key, &accessed.to_be_bytes());
+                }
+                None => batch.remove(key),
+            }
+            written += 1;
+        }
+        tree.apply_batch(batch)?;
+        Ok(written)
+    }
+
+    /// Mark when tracking started, unless it already has
+    pub fn start(&self, storage: &StorageEngine, now: SystemTime) -> Result<()> {
+        if self.enabled {
+            let tree = storage.subtree(ACCESS_TREE)?;
+            let now = unix_millis(now).to_be_bytes();
+            let _ = tree.compare_and_swap(SINCE_KEY, None as Option<&[u8]>, Some(&now[..]))?;
+        }
+        Ok(())
+    }
+}
+
+/// Write out noted accesses every FLUSH_EVERY for as long as the server runs. The server flushes
+/// once more itself as it shuts down.
+pub fn spawn_access_flush(state: AppState) -> Result<()> {
+    if !state.access.enabled() {
+        return Ok(());
+    }
+    state.access.start(&state.storage, SystemTime::now())?;
+    println!("Tracking record accesses");
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(FLUSH_EVERY);
+        loop {
+            interval.tick().await;
+            let flush_state = state.clone();
+            // sled calls block, so keep them off the async runtime
+            let result =
+                tokio::task::spawn_blocking(move || flush_state.access.flush(&flush_state.storage))
+                    .await;
+            match result {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => println!("Failed to write record accesses: {:?}", e),
+                Err(e) => println!("Writing record accesses panicked: {:?}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// How much of a collection's data older than the cutoff is going unused
+#[derive(Serialize, Default, Debug, PartialEq)]
+pub struct CollectionUsage {
+    pub records: u64,
+    pub bytes: u64,
+    // created before the cutoff and never accessed since tracking started
+    pub never_accessed: u64,
+    pub never_accessed_bytes: u64,
+    // created before the cutoff, and last accessed before it too
+    pub idle: u64,
+    pub idle_bytes: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct UsageReport {
+    pub older_than_days: u64,
+    // when tracking started, in milliseconds since the epoch. Records created before then may
+    // have been accessed without it being noted.
+    pub tracking_since: Option<u64>,
+    pub collections: BTreeMap<String, CollectionUsage>,
+}
+
+/// Tally up every collection's usage, counting records created more than `older_than_days` ago
+/// as unused if they haven't been accessed since then either
+pub fn usage(
+    storage: &StorageEngine,
+    older_than_days: u64,
+    now: SystemTime,
+) -> Result<UsageReport> {
+    let age = Duration::from_secs(older_than_days.saturating_mul(24 * 3600));
+    let cutoff = unix_millis(now.checked_sub(age).unwrap_or(UNIX_EPOCH));
+    let access = storage.subtree(ACCESS_TREE)?;
+
+    let mut collections = BTreeMap::new();
+    for name in storage.db.tree_names() {
+        let name = String::from_utf8_lossy(&name).into_owned();
+        let Some(collection) = name.strip_prefix(COLLECTION_PREFIX) else {
+            continue;
+        };
+        let mut usage = CollectionUsage::default();
+        for entry in storage.subtree(&name)?.iter() {
+            let (key, value) = entry?;
+            let bytes = (key.len() + value.len()) as u64;
+            usage.records += 1;
+            usage.bytes += bytes;
+
+            // ids are ulids, so the key says when the record was created
+            let Ok(id) = <[u8; 16]>::try_from(key.as_ref()).map(Ulid::from_bytes) else {
+                continue;
+            };
+            if id.timestamp_ms() >= cutoff {
+                continue;
+            }
+            let accessed = access.get(side_key(&name, &key))?;
+            match accessed.as_deref().and_then(decode_millis) {
+                None => {
+                    usage.never_accessed += 1;
+                    usage.never_accessed_bytes += bytes;
+                }
+                Some(accessed) if accessed < cutoff => {
+                    usage.idle += 1;
+                    usage.idle_bytes += bytes;
+                }
+                Some(_) => {}
+            }
+        }
+        collections.insert(collection.to_string(), usage);
+    }
+
+    Ok(UsageReport {
+        older_than_days,
+        tracking_since: access.get(SINCE_KEY)?.as_deref().and_then(decode_millis),
+        collections,
+    })
+}
+
+#[derive(Deserialize)]
+pub struct UsageParams {
+    older_than_days: Option<u64>,
+}
+
+/// GET /usage, which reports on records older than 30 days unless `older_than_days` says
+/// otherwise
+pub async fn usage_report(
+    State(state): State<AppState>,
+    Query(params): Query<UsageParams>,
+) -> Result<Json<UsageReport>, AppError> {
+    if !state.access.enabled() {
+        return Err(AppError::not_found(
+            "Record accesses aren't tracked, start the server with --track-access",
+        ));
+    }
+    let older_than_days = params.older_than_days.unwrap_or(30);
+    let report = tokio::task::spawn_blocking(move || {
+        usage(&state.storage, older_than_days, SystemTime::now())
+    })
+    .await
+    .map_err(anyhow::Error::from)??;
+    Ok(Json(report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage() {
+        let storage = StorageEngine::new_test().unwrap();
+        let tracker = AccessTracker::new(true);
+        let now = SystemTime::now();
+        let days_ago = |days: u64| now - Duration::from_secs(days * 24 * 3600);
+        tracker.start(&storage, days_ago(100)).unwrap();
+
+        let create = |days: u64, value: &[u8]| {
+            let key = Ulid::from_datetime(days_ago(days)).to_bytes();
+            storage.insert("records/notes", key, value).unwrap();
+            key
+        };
+        create(60, b"four");
+        let idle = create(50, b"five!");
+        let read = create(40, b"six!!!");
+        let recent = create(1, b"seven!!");
+        let deleted = create(70, b"x");
+
+        tracker.record("records/notes", &idle, days_ago(45));
+        tracker.record("records/notes", &read, days_ago(20));
+        tracker.record("records/notes", &deleted, days_ago(10));
+        assert_eq!(tracker.flush(&storage).unwrap(), 3);
+        // reads of the same record are coalesced
+        tracker.record("records/notes", &read, days_ago(3));
+        tracker.record("records/notes", &read, days_ago(2));
+        assert_eq!(tracker.flush(&storage).unwrap(), 1);
+        // and aren't written again until the stored time is out of date
+        tracker.record(
+            "records/notes",
+            &read,
+            days_ago(2) + Duration::from_secs(60),
+        );
+        assert_eq!(tracker.flush(&storage).unwrap(), 0);
+
+        storage.remove("records/notes", deleted).unwrap();
+        tracker.forget("records/notes", &deleted);
+        tracker.flush(&storage).unwrap();
+        let access = storage.subtree(ACCESS_TREE).unwrap();
+        assert!(access
+            .get(side_key("records/notes", &deleted))
+            .unwrap()
+            .is_none());
+
+        let report = usage(&storage, 30, now).unwrap();
+        assert_eq!(report.tracking_since, Some(unix_millis(days_ago(100))));
+        let notes = &report.collections["notes"];
+        let bytes = |value: &[u8]| (16 + value.len()) as u64;
+        assert_eq!(notes.records, 4);
+        assert_eq!(notes.never_accessed, 1);
+        assert_eq!(notes.never_accessed_bytes, bytes(b"four"));
+        assert_eq!(notes.idle, 1);
+        assert_eq!(notes.idle_bytes, bytes(b"five!"));
+
+        // nothing is noted when tracking is off
+        let off = AccessTracker::new(false);
+        off.record("records/notes", &recent, now);
+        assert_eq!(off.flush(&storage).unwrap(), 0);
+    }
+}
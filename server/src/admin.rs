@@ -0,0 +1,163 @@
+//! The /admin endpoints, which snapshot, drain and otherwise operate on the whole server, and
+//! /export and /import, which read and write whole trees, so need the admin token as an
+//! `Authorization: Bearer` header. The token is read from the file given
+//! with --admin-token-file, or the HYDRA_ADMIN_TOKEN environment variable. Without one, every
+//! admin request is refused, so a server started with the defaults can't be drained or emptied by
+//! whoever can reach its port.
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use axum::{
+    extract::{Request, State},
+    http::HeaderMap,
+    middleware::{self, Next},
+    response::Response,
+    routing::{delete, get, post},
+    Router,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::ServerConfig,
+    error::AppError,
+    handler::{export, import, ingress::bearer_token, removal},
+    shutdown, snapshots, AppState,
+};
+
+/// Where the admin token is read from if --admin-token-file isn't given
+pub const ADMIN_TOKEN_VAR: &str = "HYDRA_ADMIN_TOKEN";
+
+/// The admin token of servers made for tests
+#[cfg(test)]
+pub const TEST_TOKEN: &str = "adm1n";
+
+/// Who may use the admin endpoints
+#[derive(Default, Debug)]
+pub struct AdminAuth {
+    // Digests are compared rather than the tokens themselves, as for sources. None refuses all.
+    digest: Option<Vec<u8>>,
+}
+
+impl AdminAuth {
+    pub fn new(token: Option<&str>) -> Result<Self> {
+        let digest = match token.map(str::trim) {
+            Some("") => bail!("The admin token is empty"),
+            Some(token) => Some(Sha256::digest(token.as_bytes()).to_vec()),
+            None => None,
+        };
+        Ok(Self { digest })
+    }
+
+    pub fn from_config(config: &ServerConfig) -> Result<Self> {
+        Self::new(configured_token(config)?.as_deref())
+    }
+
+    /// Whether a request with these headers carries the admin token
+    pub fn accepts(&self, headers: &HeaderMap) -> bool {
+        match (&self.digest, bearer_token(headers)) {
+            (Some(digest), Some(token)) => Sha256::digest(token.as_bytes()).as_slice() == digest,
+            _ => false,
+        }
+    }
+
+    pub fn check(&self, headers: &HeaderMap) -> Result<(), AppError> {
+        match self.digest {
+            None => Err(AppError::unauthorized(format!(
+                "Admin endpoints are turned off, start the server with --admin-token-file or {}",
+                ADMIN_TOKEN_VAR
+            ))),
+            Some(_) if !self.accepts(headers) => Err(AppError::unauthorized(
+                "Admin endpoints need the admin token as a bearer token",
+            )),
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+/// The admin token the config gives, if any. Sync peers present it too, see sync.rs.
+pub fn configured_token(config: &ServerConfig) -> Result<Option<String>> {
+    Ok(match &config.admin_token_file {
+        Some(path) => Some(load_token(path)?),
+        None => std::env::var(ADMIN_TOKEN_VAR).ok(),
+    })
+}
+
+fn load_token(path: &Path) -> Result<String> {
+    let token = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read the admin token in {}", path.display()))?;
+    Ok(token.trim().to_string())
+}
+
+/// Middleware refusing requests which don't carry the admin token
+pub async fn require(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    state.admin.check(request.headers())?;
+    Ok(next.run(request).await)
+}
+
+/// The admin endpoints, behind `require`
+pub fn routes(state: &AppState) -> Router<AppState> {
+    let routes = Router::new()
+        .route("/admin/snapshot", post(snapshots::snapshot))
+        .route("/admin/drain", post(shutdown::drain_connections))
+        .route("/admin/logs/:event_id", delete(removal::delete))
+        .route("/admin/logs/:event_id/redact", post(removal::redact))
+        // wildcards, as source trees are named ingress/<id>
+        .route("/export/*tree", get(export::export))
+        .route("/import/*tree", post(import::import));
+
+    #[cfg(feature = "heap-profiling")]
+    let routes = {
+        println!("WARNING: heap profiling is enabled, see /admin/heap");
+        routes.route("/admin/heap", axum::routing::get(crate::heap::heap_profile))
+    };
+
+    routes.route_layer(middleware::from_fn_with_state(state.clone(), require))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{header, Method, StatusCode},
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_admin_auth() {
+        let state = AppState::new_test().unwrap();
+        let app = routes(&state).with_state(state);
+        let snapshot = |authorization: Option<&str>| {
+            let mut request = axum::http::Request::builder()
+                .method(Method::POST)
+                .uri("/admin/snapshot");
+            if let Some(authorization) = authorization {
+                request = request.header(header::AUTHORIZATION, authorization);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        for refused in [None, Some("Bearer wrong"), Some(TEST_TOKEN)] {
+            let response = snapshot(refused).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{:?}", refused);
+        }
+        // let through, to find snapshots are turned off
+        let response = snapshot(Some(&format!("Bearer {}", TEST_TOKEN)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // without a token, nothing is let through
+        let auth = AdminAuth::new(None).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, "Bearer ".parse().unwrap());
+        assert!(auth.check(&headers).is_err());
+        assert!(AdminAuth::new(Some(" ")).is_err());
+    }
+}
@@ -0,0 +1,297 @@
+use std::{collections::HashSet, path::Path, sync::Mutex, time::Duration};
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Utc};
+use hydra_proto as proto;
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use crate::{
+    signal::{GroupBy, ViewDefinition, ViewEngine},
+    storage::StorageEngine,
+    AppState,
+};
+
+/// Where every change in an alert's state is recorded. It can be subscribed to.
+pub const ALERTS_TREE: &str = "alerts";
+
+/// How often the rules are evaluated
+const EVALUATE_EVERY: Duration = Duration::from_secs(10);
+
+/// A threshold on the number of ingress logs captured within a trailing window, eg.
+///
+/// ```json
+/// { "name": "github-burst", "host": "hooks.github.com", "window_seconds": 60, "condition": { "above": 100 } }
+/// { "name": "stripe-quiet", "host": "stripe.example.com", "window_seconds": 600, "condition": "silent" }
+/// ```
+#[derive(Deserialize, Clone, Debug)]
+pub struct AlertRule {
+    pub name: String,
+    // only count logs sent to this host, rather than all of them
+    pub host: Option<String>,
+    pub window_seconds: u64,
+    pub condition: AlertCondition,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertCondition {
+    /// More than this many logs within the window
+    Above(i64),
+    /// No logs at all within the window
+    Silent,
+}
+
+impl AlertRule {
+    /// Each rule is evaluated against a view of its own, which shows up on /views under this name
+    fn view_name(&self) -> String {
+        format!("alert:{}", self.name)
+    }
+
+    fn view(&self) -> ViewDefinition {
+        let window = Duration::from_secs(self.window_seconds);
+        match self.host {
+            Some(_) => ViewDefinition::RateBy {
+                group_by: GroupBy::Host,
+                window,
+            },
+            None => ViewDefinition::Rate { window },
+        }
+    }
+
+    fn raised(&self, value: i64) -> bool {
+        match self.condition {
+            AlertCondition::Above(max) => value > max,
+            AlertCondition::Silent => value == 0,
+        }
+    }
+
+    fn describe(&self, value: i64) -> String {
+        let logs = match &self.host {
+            Some(host) => format!("ingress logs for {}", host),
+            None => "ingress logs".to_string(),
+        };
+        match self.condition {
+            AlertCondition::Above(max) => format!(
+                "{} {} in the last {}s, against a limit of {}",
+                value, logs, self.window_seconds, max
+            ),
+            AlertCondition::Silent => {
+                format!("{} {} in the last {}s", value, logs, self.window_seconds)
+            }
+        }
+    }
+}
+
+/// Read the rules from a file holding a JSON array of them
+pub fn load_rules(path: &Path) -> Result<Vec<AlertRule>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read alert rules from {}", path.display()))?;
+    let rules: Vec<AlertRule> = serde_json::from_str(&contents)
+        .with_context(|| format!("Invalid alert rules in {}", path.display()))?;
+
+    let mut names = HashSet::new();
+    for rule in &rules {
+        if !names.insert(rule.name.as_str()) {
+            bail!("Alert rule {} is defined more than once", rule.name);
+        }
+        if rule.window_seconds == 0 {
+            bail!("Alert rule {} has an empty window", rule.name);
+        }
+    }
+    Ok(rules)
+}
+
+struct RuleState {
+    rule: AlertRule,
+    raised: bool,
+    // as of the last evaluation
+    value: i64,
+}
+
+/// Where each rule stands, as reported on /status
+#[derive(Serialize, Debug)]
+pub struct AlertStatus {
+    pub rule: String,
+    pub raised: bool,
+    pub value: i64,
+}
+
+/// The alert rules and whether each is currently raised
+#[derive(Default)]
+pub struct AlertEngine {
+    rules: Mutex<Vec<RuleState>>,
+}
+
+impl AlertEngine {
+    /// Start evaluating a set of rules, defining the views they're evaluated against. Every rule
+    /// starts out resolved, so any which already hold are raised by the first evaluation.
+    pub fn define(
+        &self,
+        views: &ViewEngine,
+        storage: &StorageEngine,
+        rules: Vec<AlertRule>,
+    ) -> Result<()> {
+        let mut states = Vec::with_capacity(rules.len());
+        for rule in rules {
+            views.define(storage, &rule.view_name(), rule.view())?;
+            states.push(RuleState {
+                rule,
+                raised: false,
+                value: 0,
+            });
+        }
+        *self.rules.lock().unwrap() = states;
+        Ok(())
+    }
+
+    /// Evaluate every rule, returning an alert for each one which has been raised or resolved
+    pub fn evaluate(&self, views: &ViewEngine, now: DateTime<Utc>) -> Result<Vec<proto::Alert>> {
+        let mut alerts = Vec::new();
+        for state in self.rules.lock().unwrap().iter_mut() {
+            let rule = &state.rule;
+            let value = views
+                .total_in_window(&rule.view_name(), rule.host.as_deref())
+                .ok_or_else(|| anyhow!("No view for alert rule {}", rule.name))?;
+            state.value = value;
+
+            let raised = rule.raised(value);
+            if raised == state.raised {
+                continue;
+            }
+            state.raised = raised;
+            alerts.push(proto::Alert {
+                id: Ulid::new(),
+                date: now,
+                rule: rule.name.clone(),
+                state: if raised {
+                    proto::AlertState::Raised
+                } else {
+                    proto::AlertState::Resolved
+                },
+                value,
+                message: rule.describe(value),
+            });
+        }
+        Ok(alerts)
+    }
+
+    pub fn status(&self) -> Vec<AlertStatus> {
+        self.rules
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|state| AlertStatus {
+                rule: state.rule.name.clone(),
+                raised: state.raised,
+                value: state.value,
+            })
+            .collect()
+    }
+}
+
+/// Evaluate the rules for as long as the server runs, storing each alert in the alerts tree,
+/// which pushes it to subscribers
+pub fn spawn_alerts(state: AppState, rules: Vec<AlertRule>) -> Result<()> {
+    println!(
+        "Evaluating {} alert rules every {:?}",
+        rules.len(),
+        EVALUATE_EVERY
+    );
+    state.alerts.define(&state.views, &state.storage, rules)?;
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(EVALUATE_EVERY);
+        loop {
+            interval.tick().await;
+            let pass_state = state.clone();
+            // sled calls block, so keep them off the async runtime
+            let result = tokio::task::spawn_blocking(move || {
+                for alert in pass_state.alerts.evaluate(&pass_state.views, Utc::now())? {
+                    println!("Alert {} {:?}: {}", alert.rule, alert.state, alert.message);
+                    pass_state.storage.insert(
+                        ALERTS_TREE,
+                        alert.id.to_string(),
+                        bincode::serialize(&alert)?,
+                    )?;
+                }
+                Ok::<_, anyhow::Error>(())
+            })
+            .await;
+
+            match result {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => println!("Failed to evaluate alert rules: {:?}", e),
+                Err(e) => println!("Evaluating alert rules panicked: {:?}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::ingress_log;
+
+    fn log(host: &str) -> proto::IngressLog {
+        proto::IngressLog {
+            host: host.to_string(),
+            ..ingress_log("hooks", b"")
+        }
+    }
+
+    fn insert(storage: &StorageEngine, log: &proto::IngressLog) {
+        storage
+            .insert(
+                "ingress",
+                format!("test|{}", log.event_id),
+                bincode::serialize(log).unwrap(),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let rules: Vec<AlertRule> = serde_json::from_str(
+            r#"[
+                { "name": "busy", "host": "a", "window_seconds": 60, "condition": { "above": 1 } },
+                { "name": "quiet", "host": "b", "window_seconds": 60, "condition": "silent" },
+                { "name": "anything", "window_seconds": 60, "condition": { "above": 10 } }
+            ]"#,
+        )
+        .unwrap();
+        let storage = StorageEngine::new_test().unwrap();
+        let views = ViewEngine::new();
+        let engine = AlertEngine::default();
+        insert(&storage, &log("a"));
+        insert(&storage, &log("a"));
+        let mut events = storage.subscribe();
+        engine.define(&views, &storage, rules).unwrap();
+
+        let alerts = engine.evaluate(&views, Utc::now()).unwrap();
+        let raised: Vec<_> = alerts
+            .iter()
+            .map(|alert| (alert.rule.as_str(), alert.state, alert.value))
+            .collect();
+        assert_eq!(
+            raised,
+            vec![
+                ("busy", proto::AlertState::Raised, 2),
+                ("quiet", proto::AlertState::Raised, 0),
+            ]
+        );
+        // nothing changes until the counts do
+        assert!(engine.evaluate(&views, Utc::now()).unwrap().is_empty());
+
+        insert(&storage, &log("b"));
+        views.apply(&events.try_recv().unwrap());
+        let alerts = engine.evaluate(&views, Utc::now()).unwrap();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].rule, "quiet");
+        assert_eq!(alerts[0].state, proto::AlertState::Resolved);
+
+        let status = engine.status();
+        assert!(status[0].raised && !status[1].raised && !status[2].raised);
+        assert_eq!(status[2].value, 3);
+    }
+}
@@ -1,18 +1,218 @@
-use std::{ops::Deref, sync::Arc};
+use std::{
+    ops::Deref,
+    sync::{atomic::AtomicUsize, Arc},
+    time::{Duration, Instant},
+};
 
-use crate::storage;
+use crate::{
+    access::AccessTracker,
+    admin::AdminAuth,
+    alerts::AlertEngine,
+    checkpoints::{CheckpointPolicy, Checkpoints},
+    computed::{self, ComputedFields},
+    config::ServerConfig,
+    cors::CorsPolicy,
+    durability::DurableWriter,
+    handler::{events::EventLog, ingress::EventIds},
+    hot::HotCache,
+    idempotency::IdempotencyCache,
+    retention::RetentionStats,
+    schemas::Schemas,
+    shadow::{Shadow, ShadowPolicy},
+    signal::ViewEngine,
+    snapshots::{SnapshotPolicy, Snapshots},
+    sources,
+    sources::Sources,
+    storage,
+    subscription::SubscriptionRegistry,
+    worker::WorkerPool,
+};
 use anyhow::Result;
+use hydra_proto as proto;
+use tokio::sync::watch;
 
 #[derive(Clone)]
 pub struct AppState(Arc<AppStateInner>);
 pub struct AppStateInner {
     pub storage: storage::StorageEngine,
+    pub idempotency: IdempotencyCache,
+    pub workers: WorkerPool,
+    pub subscriptions: SubscriptionRegistry,
+    pub views: ViewEngine,
+    pub retention: RetentionStats,
+    pub alerts: AlertEngine,
+    pub event_ids: EventIds,
+    // the DAG each capture's event is chained on to, see handler/events.rs
+    pub dag: EventLog,
+    // the newest logs in each ingress tree, see hot.rs
+    pub hot: HotCache,
+    pub sources: Sources,
+    pub computed: ComputedFields,
+    pub access: AccessTracker,
+    pub schemas: Schemas,
+    pub snapshots: Snapshots,
+    pub checkpoints: Checkpoints,
+    pub shadow: Shadow,
+    pub writer: DurableWriter,
+    pub started: Instant,
+    // number of open WebSocket connections
+    pub connections: AtomicUsize,
+    // set once the server starts shutting down, telling connections to close
+    pub shutdown: watch::Sender<bool>,
+    // set by POST /admin/drain, telling connections to go, see shutdown.rs
+    pub draining: watch::Sender<Option<proto::GoingAway>>,
+    // see ServerConfig::trust_proxy
+    pub trust_proxy: bool,
+    // see ServerConfig::idle_timeout
+    pub idle_timeout: Option<Duration>,
+    // see cors.rs, None unless --cors-origin is given
+    pub cors: Option<CorsPolicy>,
+    // who may use the /admin endpoints, see admin.rs
+    pub admin: AdminAuth,
+    #[cfg(feature = "chaos")]
+    pub chaos: Option<crate::chaos::FaultPolicy>,
+}
+
+/// How to open the database the server was configured with
+pub fn storage_config(config: &ServerConfig) -> Result<storage::StorageConfig> {
+    let mut storage_config = match &config.db_path {
+        Some(path) => storage::StorageConfig::new(path),
+        None => storage::StorageConfig::from_env()?,
+    }
+    .wait_for_lock(config.wait_for_lock.map(Duration::from_secs))
+    .compression(config.compression)
+    .migrate(config.migrate)
+    .codec(config.storage_codec)
+    .compress_bodies(config.compress_bodies);
+    if let Some(bytes) = config.cache_capacity {
+        storage_config = storage_config.cache_capacity(bytes);
+    }
+    if let Some(ms) = config.flush_every_ms {
+        storage_config = storage_config.flush_every(Duration::from_millis(ms));
+    }
+    Ok(storage_config)
 }
 
 impl AppState {
-    pub fn new() -> Result<Self> {
-        let storage = storage::StorageEngine::new()?;
-        Ok(Self(Arc::new(AppStateInner { storage })))
+    pub fn new(config: &ServerConfig) -> Result<Self> {
+        let storage_config = storage_config(config)?;
+        let sources = match &config.sources {
+            Some(path) => sources::load_sources(path)?,
+            None => Sources::default(),
+        }
+        .durability(config.durability);
+        let computed = match &config.computed_fields {
+            Some(path) => computed::load_fields(path)?,
+            None => ComputedFields::default(),
+        };
+        Self::with_storage(
+            storage::StorageEngine::open(&storage_config)?,
+            config.trust_proxy,
+            Some(Duration::from_secs(config.idle_timeout)).filter(|timeout| !timeout.is_zero()),
+            sources,
+            computed,
+            config.hot_cache,
+            AccessTracker::new(config.track_access),
+            config.log_deliveries_for.is_some(),
+            Snapshots::new(SnapshotPolicy::from_config(config)),
+            Checkpoints::new(CheckpointPolicy::from_config(config)?),
+            ShadowPolicy::from_config(config),
+            CorsPolicy::from_config(config)?,
+            AdminAuth::from_config(config)?,
+        )
+    }
+
+    #[cfg(test)]
+    pub fn new_test() -> Result<Self> {
+        Self::new_test_with_sources(Sources::default())
+    }
+
+    #[cfg(test)]
+    pub fn new_test_with_sources(sources: Sources) -> Result<Self> {
+        Self::with_storage(
+            storage::StorageEngine::new_test()?,
+            false,
+            None,
+            sources,
+            ComputedFields::default(),
+            crate::hot::DEFAULT_CAPACITY,
+            AccessTracker::new(true),
+            true,
+            Snapshots::new(None),
+            Checkpoints::new(None),
+            None,
+            None,
+            AdminAuth::new(Some(crate::admin::TEST_TOKEN))?,
+        )
+    }
+
+    // each is set from its own flag, or left off in tests
+    #[allow(clippy::too_many_arguments)]
+    fn with_storage(
+        storage: storage::StorageEngine,
+        trust_proxy: bool,
+        idle_timeout: Option<Duration>,
+        sources: Sources,
+        computed: ComputedFields,
+        hot_cache: usize,
+        access: AccessTracker,
+        log_deliveries: bool,
+        snapshots: Snapshots,
+        checkpoints: Checkpoints,
+        shadow: Option<ShadowPolicy>,
+        cors: Option<CorsPolicy>,
+        admin: AdminAuth,
+    ) -> Result<Self> {
+        #[cfg(feature = "chaos")]
+        let chaos = crate::chaos::FaultPolicy::from_env()?;
+        #[cfg(feature = "chaos")]
+        if let Some(policy) = &chaos {
+            println!("WARNING: fault injection is enabled: {:?}", policy);
+        }
+
+        storage.add_indexes::<proto::IngressLog>(sources::is_ingress_tree)?;
+        storage.migrate_records::<proto::IngressLog>(sources::is_ingress_tree)?;
+        let mut subscriptions = SubscriptionRegistry::new();
+        if log_deliveries {
+            subscriptions = subscriptions.log_deliveries();
+        }
+        let shadow = Shadow::new(shadow, &storage)?;
+        let dag = EventLog::load(&storage)?;
+        let hot_trees = std::iter::once("ingress".to_string())
+            .chain(sources.ids().into_iter().map(sources::source_tree))
+            .collect();
+        let hot = HotCache::new(&storage, hot_cache, hot_trees)?;
+
+        Ok(Self(Arc::new(AppStateInner {
+            storage,
+            idempotency: IdempotencyCache::new(),
+            workers: WorkerPool::new(),
+            subscriptions,
+            views: ViewEngine::new(),
+            retention: RetentionStats::default(),
+            alerts: AlertEngine::default(),
+            event_ids: EventIds::default(),
+            dag,
+            hot,
+            sources,
+            computed,
+            access,
+            schemas: Schemas::default(),
+            snapshots,
+            checkpoints,
+            shadow,
+            writer: DurableWriter::default(),
+            started: Instant::now(),
+            connections: AtomicUsize::new(0),
+            shutdown: watch::channel(false).0,
+            draining: watch::channel(None).0,
+            trust_proxy,
+            idle_timeout,
+            cors,
+            admin,
+            #[cfg(feature = "chaos")]
+            chaos,
+        })))
     }
 }
 
@@ -0,0 +1,415 @@
+//! `hydra-cli`, for looking at a running hydra's ingress logs from a terminal rather than a
+//! browser. It talks to the server with hydra-client, over the same WebSocket protocol as the web
+//! client, so it sees exactly what a dashboard would.
+//!
+//! - `list` prints a page of logs, newest first, optionally filtered
+//! - `show <event_id>` prints one log in full, as JSON
+//! - `tail [-f]` prints the latest logs, oldest first, then with -f follows new ones as they're
+//!   captured
+//! - `export` writes every log as an NDJSON archive, which `hydra-server import` can load
+//!
+//! Like the web client, it knows servers by name as well as by URL: `--env staging` connects to
+//! the URL given for staging by `--environment staging=<url>` or in HYDRA_ENVIRONMENTS.
+
+use std::{io::Write, path::PathBuf};
+
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use clap::{Parser, Subcommand};
+use hydra_client::{Client, ClientConfig, ConnectionState, SubscriptionEvent, DEFAULT_URL};
+use hydra_proto as proto;
+use serde_json::json;
+use tokio::sync::mpsc;
+use ulid::Ulid;
+
+/// How many records each export request asks for
+const EXPORT_CHUNK: usize = 500;
+
+/// Named servers, as comma separated `name=url` pairs, so they needn't be given every time
+const ENVIRONMENTS_VAR: &str = "HYDRA_ENVIRONMENTS";
+
+/// The name of the server at DEFAULT_URL, as the web client calls it
+const DEFAULT_ENVIRONMENT: &str = "local";
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "hydra-cli",
+    about = "Query and tail a hydra server's ingress logs"
+)]
+struct Cli {
+    /// The server's WebSocket endpoint
+    #[arg(long, default_value = DEFAULT_URL, global = true, conflicts_with = "env")]
+    url: String,
+
+    /// Connect to the named environment's server rather than --url
+    #[arg(long, global = true)]
+    env: Option<String>,
+
+    /// A named server for --env, as `name=url`, adding to those in HYDRA_ENVIRONMENTS
+    #[arg(long = "environment", global = true, value_parser = parse_environment)]
+    environments: Vec<(String, String)>,
+
+    /// Read the logs captured for this source (see the server's --sources) rather than the
+    /// shared ingress tree
+    #[arg(long, global = true)]
+    source: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the newest logs, one per line
+    List {
+        #[arg(long, short = 'n', default_value_t = 20)]
+        limit: usize,
+        /// Only logs sent with this method
+        #[arg(long)]
+        method: Option<String>,
+        /// Only logs sent to this host
+        #[arg(long)]
+        host: Option<String>,
+        /// Only logs whose path below /ingress starts with this
+        #[arg(long)]
+        path_prefix: Option<String>,
+    },
+    /// Print one log in full, as JSON
+    Show { event_id: Ulid },
+    /// Print the latest logs, oldest first
+    Tail {
+        #[arg(long, short = 'n', default_value_t = 10)]
+        lines: usize,
+        /// Keep printing logs as they're captured, until interrupted
+        #[arg(long, short = 'f')]
+        follow: bool,
+    },
+    /// Write every log as an NDJSON archive, as GET /export/ingress does
+    Export {
+        /// Write to this file rather than stdout
+        #[arg(long, short)]
+        out: Option<PathBuf>,
+    },
+}
+
+fn parse_environment(pair: &str) -> Result<(String, String)> {
+    match pair.trim().split_once('=') {
+        Some((name, url)) if !name.is_empty() && !url.is_empty() => {
+            Ok((name.to_string(), url.to_string()))
+        }
+        _ => bail!("{:?} is not an environment, give it as name=url", pair),
+    }
+}
+
+impl Cli {
+    /// The URL of the server to connect to, that of --env if it's given. The last definition of
+    /// an environment wins, so --environment overrides HYDRA_ENVIRONMENTS.
+    fn server_url(&self, variable: Option<&str>) -> Result<String> {
+        let Some(env) = &self.env else {
+            return Ok(self.url.clone());
+        };
+        let mut environments = vec![(DEFAULT_ENVIRONMENT.to_string(), DEFAULT_URL.to_string())];
+        for pair in variable.into_iter().flat_map(|v| v.split(',')) {
+            if !pair.trim().is_empty() {
+                environments.push(
+                    parse_environment(pair)
+                        .with_context(|| format!("Invalid {}", ENVIRONMENTS_VAR))?,
+                );
+            }
+        }
+        environments.extend(self.environments.iter().cloned());
+        match environments.into_iter().rev().find(|(name, _)| name == env) {
+            Some((_, url)) => Ok(url),
+            None => bail!(
+                "Unknown environment {}, give its URL with --environment {}=<url> or in {}",
+                env,
+                env,
+                ENVIRONMENTS_VAR
+            ),
+        }
+    }
+}
+
+/// Make a request and wait for its response, failing if it's an error
+async fn request(
+    client: &Client,
+    payload: proto::RequestPayload,
+) -> Result<proto::ResponsePayload> {
+    match client.request(payload).await? {
+        proto::ResponsePayload::Error(e) => bail!("{}", e),
+        payload => Ok(payload),
+    }
+}
+
+async fn fetch(
+    client: &Client,
+    request: proto::FetchIngressLogsRequest,
+) -> Result<proto::FetchIngressLogsResponse> {
+    match self::request(client, proto::RequestPayload::FetchIngressLogs(request)).await? {
+        proto::ResponsePayload::FetchIngressLogs(response) => Ok(response),
+        _ => bail!("Unexpected response to FetchIngressLogs"),
+    }
+}
+
+fn fetch_request(
+    source: Option<String>,
+    direction: proto::Direction,
+    limit: usize,
+    filter: Option<proto::IngressLogFilter>,
+) -> proto::FetchIngressLogsRequest {
+    proto::FetchIngressLogsRequest {
+        direction,
+        limit,
+        // the oldest logs ascending, or the newest descending
+        cursor: proto::PaginatedCursor::StartingWith(vec![]),
+        preview_bytes: None,
+        snapshot: None,
+        source,
+        filter,
+        thumbnails: false,
+    }
+}
+
+/// The tree a source's logs are captured into, or the shared ingress tree
+fn log_tree(source: Option<&str>) -> String {
+    match source {
+        Some(id) => format!("ingress/{}", id),
+        None => "ingress".to_string(),
+    }
+}
+
+/// A log on one line: when, the request line, and how big its body was
+fn summary(log: &proto::IngressLog) -> String {
+    format!(
+        "{}  {} {:<7} {}/{}  {} bytes",
+        log.date.format("%Y-%m-%d %H:%M:%S%.3f"),
+        log.event_id,
+        log.method,
+        log.host,
+        log.path,
+        log.body.len()
+    )
+}
+
+/// A log in full, with its body as text if it is text, or else base64
+fn detail(log: &proto::IngressLog) -> serde_json::Value {
+    let mut detail = json!({
+        "event_id": log.event_id.to_string(),
+        "date": log.date,
+        "remote_addr": log.remote_addr,
+        "method": log.method,
+        "host": log.host,
+        "path": log.path,
+        "query": log.query,
+        "headers": log.headers,
+    });
+    match std::str::from_utf8(&log.body) {
+        Ok(text) => detail["body"] = json!(text),
+        Err(_) => detail["body_base64"] = json!(URL_SAFE.encode(&log.body)),
+    }
+    detail
+}
+
+async fn list(
+    client: &Client,
+    source: Option<String>,
+    limit: usize,
+    filter: proto::IngressLogFilter,
+) -> Result<()> {
+    let request = fetch_request(source, proto::Direction::Descending, limit, Some(filter));
+    for item in fetch(client, request).await?.items {
+        println!("{}", summary(&item.log));
+    }
+    Ok(())
+}
+
+/// Find a log by its event id. Event ids are minted at capture, so only the logs captured in the
+/// same millisecond need to be read.
+async fn show(client: &Client, source: Option<String>, event_id: Ulid) -> Result<()> {
+    let request = proto::FetchIngressLogRequest { event_id, source };
+    match self::request(client, proto::RequestPayload::FetchIngressLog(request)).await? {
+        proto::ResponsePayload::FetchIngressLog(proto::FetchIngressLogResponse {
+            log: Some(log),
+        }) => {
+            println!("{}", serde_json::to_string_pretty(&detail(&log))?);
+            Ok(())
+        }
+        proto::ResponsePayload::FetchIngressLog(_) => bail!("No log {}", event_id),
+        _ => bail!("Unexpected response to FetchIngressLog"),
+    }
+}
+
+async fn tail(client: &Client, source: Option<String>, lines: usize, follow: bool) -> Result<()> {
+    // subscribed first, so nothing captured while the latest logs are fetched is missed
+    let (sender, mut pushes) = mpsc::unbounded_channel();
+    if follow {
+        client
+            .subscribe(&log_tree(source.as_deref()), move |event| {
+                let _ = sender.send(event);
+            })
+            .await?;
+    }
+    let page = fetch(
+        client,
+        fetch_request(source, proto::Direction::Descending, lines, None),
+    )
+    .await?;
+    for item in page.items.iter().rev() {
+        println!("{}", summary(&item.log));
+    }
+    if !follow {
+        return Ok(());
+    }
+    // logs pushed before the page arrived are printed after it, unless it had them already
+    let newest = page.items.first().map(|item| item.log.event_id);
+    let mut states = client.state_changes();
+    loop {
+        let event = tokio::select! {
+            event = pushes.recv() => event,
+            _ = states.wait_for(|state| *state == ConnectionState::Closed) => {
+                bail!("The server closed the connection")
+            }
+        };
+        let Some(event) = event else {
+            return Ok(());
+        };
+        let payload = match event {
+            SubscriptionEvent::Push(payload) => *payload,
+            SubscriptionEvent::Resync => {
+                println!("(some logs may have been missed)");
+                continue;
+            }
+        };
+        match payload {
+            proto::ResponsePayload::IngressLogAppended(log)
+                if newest.is_none_or(|newest| log.event_id > newest) =>
+            {
+                println!("{}", summary(&log))
+            }
+            proto::ResponsePayload::IngressLogsSampled(sampled) => {
+                println!("({} logs skipped)", sampled.skipped)
+            }
+            _ => {}
+        }
+    }
+}
+
+async fn export(client: &Client, source: Option<String>, out: Option<PathBuf>) -> Result<()> {
+    let mut out: Box<dyn Write> = match &out {
+        Some(path) => Box::new(std::io::BufWriter::new(
+            std::fs::File::create(path)
+                .with_context(|| format!("Could not create {}", path.display()))?,
+        )),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    let tree = log_tree(source.as_deref());
+    let mut continuation = None;
+    let mut exported = 0;
+    loop {
+        let request = proto::ExportRequest {
+            tree: tree.clone(),
+            continuation,
+            limit: EXPORT_CHUNK,
+        };
+        let chunk = match self::request(client, proto::RequestPayload::Export(request)).await? {
+            proto::ResponsePayload::Export(chunk) => chunk,
+            _ => bail!("Unexpected response to Export"),
+        };
+        for record in &chunk.records {
+            let line = json!({
+                "key": URL_SAFE.encode(&record.key),
+                "value": URL_SAFE.encode(&record.value),
+            });
+            writeln!(out, "{}", line)?;
+        }
+        exported += chunk.records.len();
+        match chunk.continuation {
+            Some(token) => continuation = Some(token),
+            None => break,
+        }
+    }
+    out.flush()?;
+    eprintln!("Exported {} records from {}", exported, tree);
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let url = cli.server_url(std::env::var(ENVIRONMENTS_VAR).ok().as_deref())?;
+    // a tail would miss what's captured while reconnecting, so it ends instead
+    let config = ClientConfig::new(&url).auto_reconnect(false);
+    let client = Client::connect(config).await.map_err(|e| e.into_anyhow())?;
+    match cli.command {
+        Command::List {
+            limit,
+            method,
+            host,
+            path_prefix,
+        } => {
+            let filter = proto::IngressLogFilter {
+                method,
+                host,
+                path_prefix,
+                ..Default::default()
+            };
+            list(&client, cli.source, limit, filter).await
+        }
+        Command::Show { event_id } => show(&client, cli.source, event_id).await,
+        Command::Tail { lines, follow } => tail(&client, cli.source, lines, follow).await,
+        Command::Export { out } => export(&client, cli.source, out).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rendering() {
+        let mut log = proto::IngressLog {
+            event_id: Ulid::from_parts(1_700_000_000_000, 1),
+            date: chrono::DateTime::from_timestamp_millis(1_700_000_000_000).unwrap(),
+            remote_addr: None,
+            method: "POST".to_string(),
+            host: "example.com".to_string(),
+            path: "hooks/github".to_string(),
+            query: Default::default(),
+            headers: Default::default(),
+            body: bytes::Bytes::from_static(b"{\"ok\":true}"),
+            trace_id: None,
+        };
+        assert_eq!(
+            summary(&log),
+            format!(
+                "2023-11-14 22:13:20.000  {} POST    example.com/hooks/github  11 bytes",
+                log.event_id
+            )
+        );
+        assert_eq!(detail(&log)["body"], "{\"ok\":true}");
+        log.body = bytes::Bytes::from_static(&[0xff, 0xfe]);
+        assert_eq!(detail(&log)["body_base64"], "__4=");
+        assert!(detail(&log).get("body").is_none());
+    }
+
+    #[test]
+    fn test_environments() {
+        let variable = Some("staging=ws://staging:9797/ws, prod=ws://prod:9797/ws");
+        let url = |args: &[&str]| {
+            let cli = Cli::try_parse_from([&["hydra-cli"], args, &["list"]].concat()).unwrap();
+            cli.server_url(variable)
+        };
+        assert_eq!(url(&[]).unwrap(), DEFAULT_URL);
+        assert_eq!(url(&["--url", "ws://other/ws"]).unwrap(), "ws://other/ws");
+        assert_eq!(url(&["--env", "local"]).unwrap(), DEFAULT_URL);
+        assert_eq!(url(&["--env", "prod"]).unwrap(), "ws://prod:9797/ws");
+        // the command line wins
+        let overridden = url(&["--env", "staging", "--environment", "staging=ws://new/ws"]);
+        assert_eq!(overridden.unwrap(), "ws://new/ws");
+        assert!(url(&["--env", "nowhere"]).is_err());
+        assert!(Cli::try_parse_from(["hydra-cli", "--environment", "staging", "list"]).is_err());
+        assert!(
+            Cli::try_parse_from(["hydra-cli", "--env", "a", "--url", "ws://b/ws", "list"]).is_err()
+        );
+    }
+}
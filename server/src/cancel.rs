@@ -0,0 +1,170 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use hydra_error::{Classified, ErrorKind};
+
+/// How many records a scan reads between checks for cancellation
+pub const CHECK_INTERVAL: usize = 256;
+
+/// How many cancellations to remember for requests which haven't started yet
+const MAX_EARLY_CANCELS: usize = 64;
+
+/// Lets long running work (scans, mostly) notice that nobody is waiting for its result any more,
+/// eg. because the client cancelled the request or disconnected.
+#[derive(Clone)]
+pub struct CancelToken {
+    // this token's own flag is last, preceded by those of the tokens it was derived from
+    flags: Vec<Arc<AtomicBool>>,
+}
+
+impl Default for CancelToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self {
+            flags: vec![Arc::new(AtomicBool::new(false))],
+        }
+    }
+
+    /// A token which is cancelled along with this one, but can also be cancelled by itself
+    pub fn child(&self) -> Self {
+        let mut flags = self.flags.clone();
+        flags.push(Arc::new(AtomicBool::new(false)));
+        Self { flags }
+    }
+
+    pub fn cancel(&self) {
+        if let Some(flag) = self.flags.last() {
+            flag.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flags.iter().any(|flag| flag.load(Ordering::Relaxed))
+    }
+
+    /// Err if cancelled, classified as ErrorKind::Cancelled
+    pub fn check(&self) -> Result<(), Classified> {
+        if self.is_cancelled() {
+            Err(Classified::new(
+                ErrorKind::Cancelled,
+                "Request was cancelled",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Cancel the token when the guard is dropped, eg. along with a future nobody is polling
+    pub fn drop_guard(&self) -> DropGuard {
+        DropGuard(self.clone())
+    }
+}
+
+pub struct DropGuard(CancelToken);
+
+impl Drop for DropGuard {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+/// The requests a connection is working on, so that they can be cancelled by id
+pub struct InFlight {
+    connection: CancelToken,
+    requests: Mutex<InFlightRequests>,
+}
+
+#[derive(Default)]
+struct InFlightRequests {
+    running: HashMap<usize, CancelToken>,
+    // cancellations which arrived while the request was still queued
+    cancelled_early: VecDeque<usize>,
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        Self {
+            connection: CancelToken::new(),
+            requests: Mutex::new(InFlightRequests::default()),
+        }
+    }
+
+    /// Get a token for a request which is about to be handled
+    pub fn start(&self, request_id: usize) -> CancelToken {
+        let token = self.connection.child();
+        let mut requests = self.requests.lock().unwrap();
+        if let Some(index) = requests
+            .cancelled_early
+            .iter()
+            .position(|id| *id == request_id)
+        {
+            requests.cancelled_early.remove(index);
+            token.cancel();
+        }
+        requests.running.insert(request_id, token.clone());
+        token
+    }
+
+    pub fn finish(&self, request_id: usize) {
+        self.requests.lock().unwrap().running.remove(&request_id);
+    }
+
+    pub fn cancel(&self, request_id: usize) {
+        let mut requests = self.requests.lock().unwrap();
+        match requests.running.get(&request_id) {
+            Some(token) => token.cancel(),
+            None => {
+                if requests.cancelled_early.len() == MAX_EARLY_CANCELS {
+                    requests.cancelled_early.pop_front();
+                }
+                requests.cancelled_early.push_back(request_id);
+            }
+        }
+    }
+
+    /// Cancel everything, including requests which haven't started yet
+    pub fn cancel_all(&self) {
+        self.connection.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_flight_cancellation() {
+        let in_flight = InFlight::new();
+
+        let first = in_flight.start(1);
+        assert!(first.check().is_ok());
+        in_flight.cancel(1);
+        assert!(first.check().is_err());
+        in_flight.finish(1);
+
+        // a cancellation can overtake the request it cancels
+        in_flight.cancel(2);
+        assert!(in_flight.start(2).is_cancelled());
+        in_flight.finish(2);
+        assert!(!in_flight.start(3).is_cancelled());
+
+        // dropping the connection cancels both running and future requests
+        in_flight.cancel_all();
+        assert!(in_flight.start(3).is_cancelled());
+        assert!(in_flight.start(4).is_cancelled());
+
+        let token = CancelToken::new();
+        drop(token.drop_guard());
+        assert!(token.is_cancelled());
+    }
+}
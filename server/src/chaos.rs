@@ -0,0 +1,180 @@
+//! Fault injection for the WebSocket transport, enabled with the `chaos` feature.
+//!
+//! Frames passing through a FaultInjector may be dropped, delayed, reordered or corrupted
+//! according to a seedable FaultPolicy, so that client reconnect, retry, and resumption behavior
+//! can be exercised under adverse (but reproducible) network conditions.
+
+use std::{
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use axum::extract::ws::Message;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+// Each connection gets its own rng, seeded from the policy seed plus a connection counter
+static CONNECTION_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FaultPolicy {
+    pub seed: u64,
+    pub drop: f64,
+    pub reorder: f64,
+    pub corrupt: f64,
+    pub delay_ms: Option<Range<u64>>,
+}
+
+impl FaultPolicy {
+    /// Read the policy from HYDRA_CHAOS, eg. `seed=42,drop=0.1,reorder=0.05,corrupt=0.01,delay_ms=0..200`
+    pub fn from_env() -> Result<Option<Self>> {
+        match std::env::var("HYDRA_CHAOS") {
+            Ok(spec) => Ok(Some(spec.parse()?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub fn injector(&self) -> FaultInjector {
+        let connection = CONNECTION_COUNTER.fetch_add(1, Ordering::SeqCst);
+        FaultInjector {
+            policy: self.clone(),
+            rng: StdRng::seed_from_u64(self.seed.wrapping_add(connection)),
+            held: None,
+        }
+    }
+}
+
+impl std::str::FromStr for FaultPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let mut policy = FaultPolicy::default();
+        for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (name, value) = part
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Expected name=value in HYDRA_CHAOS, got {}", part))?;
+            match name {
+                "seed" => policy.seed = value.parse()?,
+                "drop" => policy.drop = value.parse()?,
+                "reorder" => policy.reorder = value.parse()?,
+                "corrupt" => policy.corrupt = value.parse()?,
+                "delay_ms" => {
+                    let (min, max) = value
+                        .split_once("..")
+                        .ok_or_else(|| anyhow!("Expected delay_ms=min..max, got {}", value))?;
+                    policy.delay_ms = Some(min.parse()?..max.parse()?);
+                }
+                _ => return Err(anyhow!("Unknown HYDRA_CHAOS setting {}", name)),
+            }
+        }
+        Ok(policy)
+    }
+}
+
+/// Per-connection fault injection state
+pub struct FaultInjector {
+    policy: FaultPolicy,
+    rng: StdRng,
+    // a frame held back so that it is delivered after the next one
+    held: Option<Message>,
+}
+
+impl FaultInjector {
+    /// Pass a frame through the injector, returning the frames to actually deliver (in order)
+    /// and how long to wait before delivering them. Control frames are never tampered with.
+    pub fn inject(&mut self, frame: Message) -> (Duration, Vec<Message>) {
+        if matches!(frame, Message::Close(_)) {
+            // flush anything we were holding back before the connection goes away
+            let mut frames: Vec<Message> = self.held.take().into_iter().collect();
+            frames.push(frame);
+            return (Duration::ZERO, frames);
+        }
+        if !matches!(frame, Message::Binary(_) | Message::Text(_)) {
+            return (Duration::ZERO, vec![frame]);
+        }
+
+        let delay = match &self.policy.delay_ms {
+            Some(range) if !range.is_empty() => {
+                Duration::from_millis(self.rng.gen_range(range.clone()))
+            }
+            _ => Duration::ZERO,
+        };
+
+        if self.rng.gen_bool(self.policy.drop.clamp(0.0, 1.0)) {
+            println!("chaos: dropping frame");
+            return (delay, vec![]);
+        }
+
+        let frame = if self.rng.gen_bool(self.policy.corrupt.clamp(0.0, 1.0)) {
+            self.corrupt(frame)
+        } else {
+            frame
+        };
+
+        if self.held.is_none() && self.rng.gen_bool(self.policy.reorder.clamp(0.0, 1.0)) {
+            println!("chaos: holding frame back to reorder it");
+            self.held = Some(frame);
+            return (delay, vec![]);
+        }
+
+        let mut frames = vec![frame];
+        frames.extend(self.held.take());
+        (delay, frames)
+    }
+
+    fn corrupt(&mut self, frame: Message) -> Message {
+        match frame {
+            Message::Binary(mut data) if !data.is_empty() => {
+                let i = self.rng.gen_range(0..data.len());
+                data[i] ^= 1 << self.rng.gen_range(0..8);
+                println!("chaos: flipped a bit in byte {} of binary frame", i);
+                Message::Binary(data)
+            }
+            frame => frame,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(policy: &FaultPolicy) -> Vec<Vec<u8>> {
+        let mut injector = FaultInjector {
+            policy: policy.clone(),
+            rng: StdRng::seed_from_u64(policy.seed),
+            held: None,
+        };
+        let mut delivered = vec![];
+        for i in 0u8..100 {
+            let (_, frames) = injector.inject(Message::Binary(vec![i, i, i]));
+            for frame in frames {
+                if let Message::Binary(data) = frame {
+                    delivered.push(data);
+                }
+            }
+        }
+        delivered
+    }
+
+    #[test]
+    fn test_fault_injection() {
+        let policy: FaultPolicy = "seed=7,drop=0.1,reorder=0.1,corrupt=0.1,delay_ms=0..5"
+            .parse()
+            .unwrap();
+        assert_eq!(policy.delay_ms, Some(0..5));
+
+        // the same seed must produce the same faults
+        let delivered = run(&policy);
+        assert_eq!(delivered, run(&policy));
+
+        // some frames were dropped, and some were reordered or corrupted
+        assert!(delivered.len() < 100);
+        let pristine: Vec<Vec<u8>> = (0u8..100).map(|i| vec![i, i, i]).collect();
+        assert!(delivered.iter().zip(&pristine).any(|(a, b)| a != b));
+
+        // a policy with no faults delivers everything untouched
+        assert_eq!(run(&FaultPolicy::default()), pristine);
+    }
+}
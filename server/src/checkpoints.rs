@@ -0,0 +1,332 @@
+//! Checkpoints of the DAG, see hydra_proto::dag::checkpoint, signed with the key in
+//! --checkpoint-key and taken every --checkpoint-every seconds if events have been added since
+//! the last. They're kept in the checkpoints tree, and clients fetch them, along with proofs
+//! that the events they've seen are covered, with VerifyBasis.
+//!
+//! A checkpoint's leaves are the events in the events tree, in the order they were added. Proofs
+//! are worked out from the tree as it is when they're asked for, so they only match a checkpoint
+//! if the events it covered are all still there, unchanged and in the same order.
+
+use std::{collections::HashMap, path::Path, sync::Mutex, time::Duration};
+
+use anyhow::{anyhow, Context, Result};
+use ed25519_dalek::SigningKey;
+use hydra_proto::{
+    self as proto,
+    dag::{
+        checkpoint::{Checkpoint, MerkleTree},
+        Event, ID,
+    },
+};
+
+use crate::{
+    config::ServerConfig,
+    durability::DurableWriter,
+    error::AppError,
+    handler::events::{EventLog, EVENTS_TREE, MAX_EVENTS_PER_BATCH},
+    storage::StorageEngine,
+    AppState,
+};
+
+/// Where checkpoints are kept, by sequence
+pub const CHECKPOINTS_TREE: &str = "checkpoints";
+
+#[derive(Clone, Debug)]
+pub struct CheckpointPolicy {
+    pub key: SigningKey,
+    pub interval: Duration,
+}
+
+impl CheckpointPolicy {
+    /// None unless the config gives a key to sign checkpoints with
+    pub fn from_config(config: &ServerConfig) -> Result<Option<Self>> {
+        let Some(path) = &config.checkpoint_key else {
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            key: load_key(path)?,
+            interval: Duration::from_secs(config.checkpoint_every.max(1)),
+        }))
+    }
+}
+
+/// A key file holds the key's 32 byte seed in hex, eg. from `openssl rand -hex 32`
+pub fn load_key(path: &Path) -> Result<SigningKey> {
+    let hex_seed = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read the checkpoint key in {}", path.display()))?;
+    let seed: [u8; 32] = hex::decode(hex_seed.trim())
+        .ok()
+        .and_then(|seed| seed.try_into().ok())
+        .ok_or_else(|| {
+            anyhow!(
+                "The checkpoint key in {} should be 64 hex digits",
+                path.display()
+            )
+        })?;
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+/// The key checkpoints are signed with, and a lock so only one is taken at a time
+pub struct Checkpoints {
+    policy: Option<CheckpointPolicy>,
+    taking: Mutex<()>,
+}
+
+impl Checkpoints {
+    pub fn new(policy: Option<CheckpointPolicy>) -> Self {
+        Self {
+            policy,
+            taking: Mutex::new(()),
+        }
+    }
+
+    /// How often checkpoints are to be taken, if they're taken at all
+    pub fn interval(&self) -> Option<Duration> {
+        Some(self.policy.as_ref()?.interval)
+    }
+
+    /// The public half of the key checkpoints are signed with
+    pub fn public_key(&self) -> Option<[u8; 32]> {
+        Some(self.policy.as_ref()?.key.verifying_key().to_bytes())
+    }
+
+    /// Take the next checkpoint, unless nothing has been added since the last. The DAG is held
+    /// meanwhile, and relaxed writes written out first, so the checkpoint covers exactly the
+    /// events in the DAG and the heads are among them.
+    pub fn take(
+        &self,
+        storage: &StorageEngine,
+        dag: &EventLog,
+        writer: &DurableWriter,
+    ) -> Result<Option<Checkpoint>> {
+        let Some(policy) = &self.policy else {
+            return Ok(None);
+        };
+        let _taking = self.taking.lock().unwrap();
+        let latest = self.latest(storage)?;
+        let (leaves, heads) = dag.hold(|dag| -> Result<_> {
+            writer.write_queued(storage)?;
+            Ok((leaves(storage, usize::MAX)?, dag.heads().clone()))
+        })?;
+        if latest
+            .as_ref()
+            .is_some_and(|latest| latest.event_count == leaves.len() as u64)
+        {
+            return Ok(None);
+        }
+        let checkpoint = Checkpoint::sign(
+            &policy.key,
+            latest.map_or(0, |latest| latest.sequence + 1),
+            &leaves,
+            heads,
+            chrono::Utc::now().timestamp_millis(),
+        );
+        storage.insert(
+            CHECKPOINTS_TREE,
+            checkpoint.sequence.to_be_bytes(),
+            bincode::serialize(&checkpoint)?,
+        )?;
+        Ok(Some(checkpoint))
+    }
+
+    pub fn latest(&self, storage: &StorageEngine) -> Result<Option<Checkpoint>> {
+        match storage.subtree(CHECKPOINTS_TREE)?.last()? {
+            Some((_, value)) => Ok(Some(bincode::deserialize(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get(&self, storage: &StorageEngine, sequence: u64) -> Result<Option<Checkpoint>> {
+        match storage.get(CHECKPOINTS_TREE, sequence.to_be_bytes())? {
+            Some(value) => Ok(Some(bincode::deserialize(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// A checkpoint, the latest, and proofs against the former for each of the events asked
+    /// about. An event gets no proof if the checkpoint doesn't cover it.
+    pub fn verify_basis(
+        &self,
+        storage: &StorageEngine,
+        request: proto::VerifyBasisRequest,
+    ) -> Result<proto::VerifyBasisResponse, AppError> {
+        let Some(public_key) = self.public_key() else {
+            return Err(AppError::not_found(
+                "Checkpoints are turned off, start the server with --checkpoint-key",
+            ));
+        };
+        if request.events.len() > MAX_EVENTS_PER_BATCH {
+            return Err(AppError::invalid_field(
+                "events",
+                format!(
+                    "At most {} events can be verified at once",
+                    MAX_EVENTS_PER_BATCH
+                ),
+            ));
+        }
+        let latest = self
+            .latest(storage)?
+            .ok_or_else(|| AppError::not_found("No checkpoint has been taken yet"))?;
+        let checkpoint = match request.checkpoint {
+            Some(sequence) => self.get(storage, sequence)?.ok_or_else(|| {
+                AppError::not_found(format!("There is no checkpoint {}", sequence))
+            })?,
+            None => latest.clone(),
+        };
+
+        let leaves = leaves(storage, checkpoint.event_count as usize)?;
+        let tree = MerkleTree::new(&leaves);
+        let positions: HashMap<&ID, usize> = leaves
+            .iter()
+            .enumerate()
+            .map(|(index, id)| (id, index))
+            .collect();
+        let proofs = request
+            .events
+            .iter()
+            .map(|id| tree.prove(*positions.get(id)?))
+            .collect();
+        Ok(proto::VerifyBasisResponse {
+            checkpoint,
+            latest,
+            public_key,
+            proofs,
+        })
+    }
+}
+
+/// The ids of the first `count` events in the events tree, in the order they were added
+fn leaves(storage: &StorageEngine, count: usize) -> Result<Vec<ID>> {
+    let mut leaves = Vec::new();
+    for item in storage.subtree(EVENTS_TREE)?.iter().take(count) {
+        let (_, value) = item?;
+        let event: Event = bincode::deserialize(&value)?;
+        leaves.push(event.id);
+    }
+    Ok(leaves)
+}
+
+/// Take a checkpoint every `interval`, for as long as the server runs
+pub fn spawn_checkpoints(state: AppState, interval: Duration) {
+    println!("Taking a DAG checkpoint every {:?}", interval);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            let pass_state = state.clone();
+            // sled calls block, so keep them off the async runtime
+            let result = tokio::task::spawn_blocking(move || {
+                pass_state.checkpoints.take(
+                    &pass_state.storage,
+                    &pass_state.dag,
+                    &pass_state.writer,
+                )
+            })
+            .await;
+            match result {
+                Ok(Ok(Some(checkpoint))) => println!(
+                    "Took DAG checkpoint {} over {} events",
+                    checkpoint.sequence, checkpoint.event_count
+                ),
+                Ok(Ok(None)) => {}
+                Ok(Err(e)) => println!("Failed to take a DAG checkpoint: {:?}", e),
+                Err(e) => println!("Taking a DAG checkpoint panicked: {:?}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::ingress::EventIds;
+
+    #[test]
+    fn test_checkpoints() {
+        let storage = StorageEngine::new_test().unwrap();
+        let ids = EventIds::default();
+        let dag = EventLog::load(&storage).unwrap();
+        let writer = DurableWriter::default();
+        let capture = |n: i64| {
+            dag.append(&ids, |event_id, precursors| {
+                let event = Event::with_payload(n, &event_id.to_bytes(), precursors);
+                storage.insert(
+                    EVENTS_TREE,
+                    event_id.to_bytes(),
+                    bincode::serialize(&event)?,
+                )?;
+                Ok(event)
+            })
+            .unwrap()
+            .1
+        };
+        let verify = |checkpoints: &Checkpoints, checkpoint, events: &[&Event]| {
+            checkpoints.verify_basis(
+                &storage,
+                proto::VerifyBasisRequest {
+                    checkpoint,
+                    events: events.iter().map(|event| event.id.clone()).collect(),
+                },
+            )
+        };
+
+        let off = Checkpoints::new(None);
+        assert_eq!(off.take(&storage, &dag, &writer).unwrap(), None);
+        assert!(verify(&off, None, &[]).is_err());
+
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let checkpoints = Checkpoints::new(Some(CheckpointPolicy {
+            key: key.clone(),
+            interval: Duration::from_secs(1),
+        }));
+        assert!(verify(&checkpoints, None, &[]).is_err());
+        let events: Vec<Event> = (0..5).map(capture).collect();
+        let first = checkpoints.take(&storage, &dag, &writer).unwrap().unwrap();
+        assert_eq!((first.sequence, first.event_count), (0, 5));
+        assert_eq!(first.heads, vec![events[4].id.clone()]);
+        // nothing new, so no new checkpoint
+        assert_eq!(checkpoints.take(&storage, &dag, &writer).unwrap(), None);
+
+        let later = capture(5);
+        let second = checkpoints.take(&storage, &dag, &writer).unwrap().unwrap();
+        assert_eq!((second.sequence, second.event_count), (1, 6));
+
+        // every event the first checkpoint covers is proven against it, and later ones aren't
+        let response = verify(&checkpoints, Some(0), &[&events[0], &events[3], &later]).unwrap();
+        let public_key = key.verifying_key().to_bytes();
+        assert_eq!(response.public_key, public_key);
+        assert_eq!(response.checkpoint, first);
+        assert_eq!(response.latest, second);
+        assert!(response.checkpoint.verify(&public_key));
+        assert!(response.proofs[0]
+            .as_ref()
+            .unwrap()
+            .verify(&events[0].id, &first.root));
+        assert!(response.proofs[1]
+            .as_ref()
+            .unwrap()
+            .verify(&events[3].id, &first.root));
+        assert_eq!(response.proofs[2], None);
+        let response = verify(&checkpoints, None, &[&later]).unwrap();
+        assert!(response.proofs[0]
+            .as_ref()
+            .unwrap()
+            .verify(&later.id, &second.root));
+        assert!(verify(&checkpoints, Some(2), &[]).is_err());
+
+        // rewriting the log, here by dropping an event, breaks the proofs
+        let (key_of_second, _) = storage
+            .subtree(EVENTS_TREE)
+            .unwrap()
+            .iter()
+            .nth(1)
+            .unwrap()
+            .unwrap();
+        storage.remove(EVENTS_TREE, key_of_second).unwrap();
+        let response = verify(&checkpoints, Some(0), &[&events[3]]).unwrap();
+        assert!(!response.proofs[0]
+            .as_ref()
+            .unwrap()
+            .verify(&events[3].id, &first.root));
+    }
+}
@@ -0,0 +1,153 @@
+//! The codecs Messages and stored records can be encoded with. bincode, the default, is compact
+//! and fast, but it isn't self-describing: it can only be read with exactly the types it was
+//! written with, which in practice means by Rust sharing the proto crate. CBOR and MessagePack
+//! carry field names, so they can be read by off the shelf libraries in any language, and by
+//! types which have since gained optional fields.
+//!
+//! Connections pick theirs with proto::Encoding. Versioned records are written with the codec
+//! --storage-codec picks, and read with whichever they were written with, see
+//! storage/versioned.rs.
+
+use anyhow::Result;
+use hydra_proto as proto;
+use serde::{de::DeserializeOwned, Serialize};
+
+pub trait Codec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>>;
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T>;
+}
+
+pub struct Bincode;
+
+impl Codec for Bincode {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+pub struct Cbor;
+
+impl Codec for Cbor {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        let mut encoded = Vec::new();
+        ciborium::into_writer(value, &mut encoded)?;
+        Ok(encoded)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        Ok(ciborium::from_reader(bytes)?)
+    }
+}
+
+pub struct MessagePack;
+
+impl Codec for MessagePack {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        // structs as maps of their fields, rather than arrays, so they describe themselves
+        Ok(rmp_serde::to_vec_named(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+}
+
+/// A codec picked at runtime, eg. with --storage-codec
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum CodecKind {
+    #[default]
+    Bincode,
+    Cbor,
+    #[value(name = "msgpack")]
+    MessagePack,
+}
+
+impl Codec for CodecKind {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        match self {
+            CodecKind::Bincode => Bincode.encode(value),
+            CodecKind::Cbor => Cbor.encode(value),
+            CodecKind::MessagePack => MessagePack.encode(value),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        match self {
+            CodecKind::Bincode => Bincode.decode(bytes),
+            CodecKind::Cbor => Cbor.decode(bytes),
+            CodecKind::MessagePack => MessagePack.decode(bytes),
+        }
+    }
+}
+
+impl CodecKind {
+    /// The codec of a connection's binary frames. JSON connections still read them as bincode.
+    pub fn of_frames(encoding: proto::Encoding) -> Self {
+        match encoding {
+            proto::Encoding::Bincode | proto::Encoding::Json => CodecKind::Bincode,
+            proto::Encoding::Cbor => CodecKind::Cbor,
+            proto::Encoding::MessagePack => CodecKind::MessagePack,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use proto::IngressLog;
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::test_util::ingress_log;
+
+    #[test]
+    fn test_codecs() {
+        let message = proto::Message::Response(proto::Response {
+            request_id: 4,
+            sequence: 0,
+            trace_id: None,
+            payload: proto::ResponsePayload::IngressLogAppended(IngressLog {
+                remote_addr: Some("127.0.0.1:80".parse().unwrap()),
+                query: HashMap::from([("a".to_string(), "1".to_string())]),
+                ..ingress_log("hooks", b"{}")
+            }),
+        });
+        for codec in [CodecKind::Bincode, CodecKind::Cbor, CodecKind::MessagePack] {
+            let encoded = codec.encode(&message).unwrap();
+            let decoded: proto::Message = codec.decode(&encoded).unwrap();
+            assert_eq!(
+                codec.encode(&decoded).unwrap(),
+                encoded,
+                "{:?} didn't round trip",
+                codec
+            );
+        }
+
+        // the self-describing codecs can be read by a type which has gained an optional field
+        #[derive(Serialize)]
+        struct Before {
+            host: String,
+        }
+        #[derive(Deserialize)]
+        struct After {
+            host: String,
+            #[serde(default)]
+            port: Option<u16>,
+        }
+        let before = Before {
+            host: "example.com".to_string(),
+        };
+        for codec in [CodecKind::Cbor, CodecKind::MessagePack] {
+            let after: After = codec.decode(&codec.encode(&before).unwrap()).unwrap();
+            assert_eq!((after.host.as_str(), after.port), ("example.com", None));
+        }
+        assert!(CodecKind::Bincode
+            .decode::<After>(&CodecKind::Bincode.encode(&before).unwrap())
+            .is_err());
+    }
+}
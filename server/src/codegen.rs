@@ -0,0 +1,703 @@
+//! `hydra-server codegen --lang rust|ts`, which writes typed wrappers for every record collection,
+//! so apps get checked types for their records rather than hand-writing JSON to and from bytes.
+//!
+//! Each collection's record type comes from its JSON Schema (see schemas.rs): objects with
+//! `properties` become structs or interfaces, with the properties which aren't `required` made
+//! optional, and anything the schema doesn't pin down becomes a JSON value. Collections without a
+//! schema get a plain JSON value. Each collection gets get, create, update, delete and watch
+//! functions. The Rust wrappers use the web client, and also cover fetching and watching ingress
+//! logs; the TypeScript ones speak the JSON encoding through a transport the app provides.
+//!
+//! With --protocol it writes the protocol's own types instead, see protocol.rs.
+
+pub mod protocol;
+
+use std::collections::BTreeSet;
+
+use anyhow::Result;
+use serde_json::Value;
+
+use crate::{handler::record::COLLECTION_PREFIX, schemas::SCHEMA_TREE, storage::StorageEngine};
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lang {
+    Rust,
+    Ts,
+}
+
+/// A collection and its schema, if it has one
+pub struct Collection {
+    pub name: String,
+    pub schema: Option<Value>,
+}
+
+/// Every collection which has a schema or has been written to, by name
+pub fn collections(storage: &StorageEngine) -> Result<Vec<Collection>> {
+    let mut names = BTreeSet::new();
+    for tree in storage.db.tree_names() {
+        if let Some(name) = tree.strip_prefix(COLLECTION_PREFIX.as_bytes()) {
+            names.insert(String::from_utf8_lossy(name).into_owned());
+        }
+    }
+    let schemas = storage.subtree(SCHEMA_TREE)?;
+    for tree in schemas.iter().keys() {
+        if let Some(name) = tree?.strip_prefix(COLLECTION_PREFIX.as_bytes()) {
+            names.insert(String::from_utf8_lossy(name).into_owned());
+        }
+    }
+    names
+        .into_iter()
+        .map(|name| {
+            let schema = match schemas.get(format!("{}{}", COLLECTION_PREFIX, name))? {
+                // schemas are checked when they're set, so this only fails if one was corrupted
+                Some(text) => Some(serde_json::from_slice(&text)?),
+                None => None,
+            };
+            Ok(Collection { name, schema })
+        })
+        .collect()
+}
+
+/// The shape of a value, as far as a schema says
+#[derive(Debug, Clone, PartialEq)]
+enum Shape {
+    String,
+    Integer,
+    Number,
+    Boolean,
+    Array(Box<Shape>),
+    // one of the named structs
+    Struct(String),
+    Any,
+}
+
+#[derive(Debug)]
+struct Field {
+    name: String,
+    shape: Shape,
+    // the property needn't be present
+    optional: bool,
+    // the property can be null
+    nullable: bool,
+}
+
+#[derive(Debug)]
+struct StructDef {
+    name: String,
+    fields: Vec<Field>,
+}
+
+/// The shape a schema describes, adding a struct for each object with properties to `structs`.
+/// Returns whether the value can also be null.
+fn shape(schema: &Value, name: &str, structs: &mut Vec<StructDef>) -> (Shape, bool) {
+    let (type_name, nullable) = match schema.get("type") {
+        Some(Value::String(type_name)) => (type_name.as_str(), false),
+        // eg. ["string", "null"]
+        Some(Value::Array(types)) => {
+            let nullable = types.iter().any(|t| t == "null");
+            let mut others = types
+                .iter()
+                .filter_map(Value::as_str)
+                .filter(|t| *t != "null");
+            match (others.next(), others.next()) {
+                (Some(type_name), None) => (type_name, nullable),
+                _ => return (Shape::Any, nullable),
+            }
+        }
+        _ => return (Shape::Any, false),
+    };
+    let shape = match type_name {
+        "string" => Shape::String,
+        "integer" => Shape::Integer,
+        "number" => Shape::Number,
+        "boolean" => Shape::Boolean,
+        "array" => {
+            let items = match schema.get("items") {
+                Some(items) => shape(items, &format!("{}Item", name), structs).0,
+                None => Shape::Any,
+            };
+            Shape::Array(Box::new(items))
+        }
+        "object" => match schema.get("properties").and_then(Value::as_object) {
+            Some(properties) => {
+                let required: BTreeSet<&str> = schema
+                    .get("required")
+                    .and_then(Value::as_array)
+                    .map(|required| required.iter().filter_map(Value::as_str).collect())
+                    .unwrap_or_default();
+                // reserved before the fields, so parents come before the structs they contain
+                let index = structs.len();
+                structs.push(StructDef {
+                    name: name.to_string(),
+                    fields: vec![],
+                });
+                let fields = properties
+                    .iter()
+                    .map(|(property, schema)| {
+                        let field_type = format!("{}{}", name, pascal_case(property));
+                        let (shape, nullable) = shape(schema, &field_type, structs);
+                        Field {
+                            name: property.clone(),
+                            shape,
+                            optional: !required.contains(property.as_str()),
+                            nullable,
+                        }
+                    })
+                    .collect();
+                structs[index].fields = fields;
+                Shape::Struct(name.to_string())
+            }
+            None => Shape::Any,
+        },
+        _ => Shape::Any,
+    };
+    (shape, nullable)
+}
+
+fn pascal_case(name: &str) -> String {
+    let pascal: String = name
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let mut chars = word.chars();
+            let first = chars.next().map(|c| c.to_ascii_uppercase());
+            first.into_iter().chain(chars).collect::<String>()
+        })
+        .collect();
+    match pascal.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() => pascal,
+        _ => format!("Record{}", pascal),
+    }
+}
+
+fn snake_case(name: &str) -> String {
+    name.chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_lowercase(),
+            false => '_',
+        })
+        .collect()
+}
+
+const RUST_KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern",
+    "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub",
+    "ref", "return", "self", "static", "struct", "super", "trait", "true", "type", "unsafe", "use",
+    "where", "while", "yield",
+];
+
+/// A Rust identifier for a property
+fn rust_field(name: &str) -> String {
+    let ident = snake_case(name);
+    match ident.chars().next() {
+        Some(c) if c.is_ascii_digit() => format!("_{}", ident),
+        None => "_".to_string(),
+        _ if RUST_KEYWORDS.contains(&ident.as_str()) => format!("r#{}", ident),
+        _ => ident,
+    }
+}
+
+fn rust_type(shape: &Shape) -> String {
+    match shape {
+        Shape::String => "String".to_string(),
+        Shape::Integer => "i64".to_string(),
+        Shape::Number => "f64".to_string(),
+        Shape::Boolean => "bool".to_string(),
+        Shape::Array(items) => format!("Vec<{}>", rust_type(items)),
+        Shape::Struct(name) => name.clone(),
+        Shape::Any => "serde_json::Value".to_string(),
+    }
+}
+
+fn ts_type(shape: &Shape) -> String {
+    match shape {
+        Shape::String => "string".to_string(),
+        Shape::Integer | Shape::Number => "number".to_string(),
+        Shape::Boolean => "boolean".to_string(),
+        Shape::Array(items) => format!("{}[]", ts_type(items)),
+        Shape::Struct(name) => name.clone(),
+        Shape::Any => "unknown".to_string(),
+    }
+}
+
+/// A TypeScript property name, quoted unless it's a valid identifier
+fn ts_property(name: &str) -> String {
+    let identifier = name.chars().next().is_some_and(|c| !c.is_ascii_digit())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$');
+    match identifier {
+        true => name.to_string(),
+        false => format!("{:?}", name),
+    }
+}
+
+/// The type a collection's records are, and the structs it's made of
+fn record_type(collection: &Collection) -> (Shape, Vec<StructDef>) {
+    let mut structs = vec![];
+    let name = pascal_case(&collection.name);
+    let shape = match &collection.schema {
+        Some(schema) => shape(schema, &name, &mut structs).0,
+        None => Shape::Any,
+    };
+    (shape, structs)
+}
+
+pub fn generate(lang: Lang, collections: &[Collection]) -> String {
+    match lang {
+        Lang::Rust => generate_rust(collections),
+        Lang::Ts => generate_ts(collections),
+    }
+}
+
+const RUST_PRELUDE: &str = r#"//! Generated by `hydra-server codegen --lang rust`. Regenerate rather than editing by hand.
+
+#![allow(dead_code)]
+
+use hydra_web::{
+    client::{Client, RequestError, SubscriptionEvent},
+    proto,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use ulid::Ulid;
+
+fn unexpected(payload: proto::ResponsePayload) -> RequestError {
+    match payload {
+        proto::ResponsePayload::Error(payload) => RequestError::Rejected(payload),
+        _ => RequestError::Rejected(proto::ErrorPayload::new(
+            proto::ErrorKind::Internal,
+            "Unexpected response",
+        )),
+    }
+}
+
+fn serialization_error(e: serde_json::Error) -> RequestError {
+    RequestError::Rejected(proto::ErrorPayload::new(
+        proto::ErrorKind::Serialization,
+        e.to_string(),
+    ))
+}
+
+fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, RequestError> {
+    serde_json::to_vec(value).map_err(serialization_error)
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, RequestError> {
+    serde_json::from_slice(bytes).map_err(serialization_error)
+}
+
+/// A page of ingress logs, see proto::FetchIngressLogsRequest
+pub async fn fetch_ingress_logs(
+    client: &Client,
+    request: proto::FetchIngressLogsRequest,
+) -> Result<proto::FetchIngressLogsResponse, RequestError> {
+    match client
+        .request(proto::RequestPayload::FetchIngressLogs(request))
+        .await?
+    {
+        proto::ResponsePayload::FetchIngressLogs(response) => Ok(response),
+        other => Err(unexpected(other)),
+    }
+}
+
+/// Call `handler` with each log captured from now on, for one source or the shared ingress tree.
+/// Returns the subscription id, to unsubscribe with.
+pub async fn watch_ingress_logs(
+    client: &Client,
+    source: Option<&str>,
+    handler: impl Fn(proto::IngressLog) + 'static,
+) -> Result<usize, RequestError> {
+    let tree = match source {
+        Some(source) => format!("ingress/{}", source),
+        None => "ingress".to_string(),
+    };
+    client
+        .subscribe(&tree, move |event| {
+            if let SubscriptionEvent::Push(push) = event {
+                if let proto::ResponsePayload::IngressLogAppended(log) = *push {
+                    handler(log);
+                }
+            }
+        })
+        .await
+}
+"#;
+
+const RUST_COLLECTION: &str = r#"
+/// Get a record of the `{name}` collection, if it exists
+pub async fn get_{fn}(client: &Client, id: Ulid) -> Result<Option<{type}>, RequestError> {
+    let request = proto::GetRecordRequest {
+        collection: "{name}".to_string(),
+        id,
+    };
+    match client
+        .request(proto::RequestPayload::GetRecord(request))
+        .await?
+    {
+        proto::ResponsePayload::GetRecord(response) => {
+            response.value.as_deref().map(decode).transpose()
+        }
+        other => Err(unexpected(other)),
+    }
+}
+
+/// Add a record to the `{name}` collection, returning its id
+pub async fn create_{fn}(client: &Client, value: &{type}) -> Result<Ulid, RequestError> {
+    let request = proto::CreateRecordRequest {
+        collection: "{name}".to_string(),
+        value: encode(value)?,
+    };
+    match client
+        .request(proto::RequestPayload::CreateRecord(request))
+        .await?
+    {
+        proto::ResponsePayload::CreateRecord(response) => Ok(response.id),
+        other => Err(unexpected(other)),
+    }
+}
+
+/// Replace a record of the `{name}` collection
+pub async fn update_{fn}(client: &Client, id: Ulid, value: &{type}) -> Result<(), RequestError> {
+    let request = proto::UpdateRecordRequest {
+        collection: "{name}".to_string(),
+        id,
+        value: encode(value)?,
+    };
+    match client
+        .request(proto::RequestPayload::UpdateRecord(request))
+        .await?
+    {
+        proto::ResponsePayload::UpdateRecord(_) => Ok(()),
+        other => Err(unexpected(other)),
+    }
+}
+
+/// Delete a record of the `{name}` collection, returning whether it existed
+pub async fn delete_{fn}(client: &Client, id: Ulid) -> Result<bool, RequestError> {
+    let request = proto::DeleteRecordRequest {
+        collection: "{name}".to_string(),
+        id,
+    };
+    match client
+        .request(proto::RequestPayload::DeleteRecord(request))
+        .await?
+    {
+        proto::ResponsePayload::DeleteRecord(response) => Ok(response.previous.is_some()),
+        other => Err(unexpected(other)),
+    }
+}
+
+/// Call `handler` whenever one of these records of the `{name}` collection is written (with
+/// its new value) or deleted (with None). Returns the subscription id, to unsubscribe with.
+pub async fn watch_{fn}(
+    client: &Client,
+    ids: &[Ulid],
+    handler: impl Fn(Ulid, Result<Option<{type}>, RequestError>) + 'static,
+) -> Result<usize, RequestError> {
+    let keys = ids.iter().map(|id| id.to_bytes().to_vec()).collect();
+    client
+        .watch_keys("records/{name}", keys, None, move |event| {
+            if let SubscriptionEvent::Push(push) = event {
+                if let proto::ResponsePayload::KeyChanged(changed) = *push {
+                    if let Ok(key) = <[u8; 16]>::try_from(changed.key.as_slice()) {
+                        let value = changed.value.as_deref().map(decode).transpose();
+                        handler(Ulid::from_bytes(key), value);
+                    }
+                }
+            }
+        })
+        .await
+}
+"#;
+
+fn generate_rust(collections: &[Collection]) -> String {
+    let mut out = RUST_PRELUDE.to_string();
+    for collection in collections {
+        let (shape, structs) = record_type(collection);
+        let type_name = pascal_case(&collection.name);
+        out.push('\n');
+        if shape != Shape::Struct(type_name.clone()) {
+            out.push_str(&format!(
+                "/// A record of the `{}` collection\npub type {} = {};\n",
+                collection.name,
+                type_name,
+                rust_type(&shape)
+            ));
+        }
+        for (n, def) in structs.iter().enumerate() {
+            if n > 0 {
+                out.push('\n');
+            }
+            out.push_str("#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]\n");
+            out.push_str(&format!("pub struct {} {{\n", def.name));
+            for field in &def.fields {
+                let ident = rust_field(&field.name);
+                let mut attributes = vec![];
+                if ident.trim_start_matches("r#") != field.name {
+                    attributes.push(format!("rename = {:?}", field.name));
+                }
+                let mut field_type = rust_type(&field.shape);
+                if field.optional || field.nullable {
+                    field_type = format!("Option<{}>", field_type);
+                }
+                if field.optional {
+                    attributes.push("default".to_string());
+                    attributes.push("skip_serializing_if = \"Option::is_none\"".to_string());
+                }
+                if !attributes.is_empty() {
+                    out.push_str(&format!("    #[serde({})]\n", attributes.join(", ")));
+                }
+                out.push_str(&format!("    pub {}: {},\n", ident, field_type));
+            }
+            out.push_str("}\n");
+        }
+        out.push_str(
+            &RUST_COLLECTION
+                .replace("{name}", &collection.name)
+                .replace("{fn}", &snake_case(&collection.name))
+                .replace("{type}", &type_name),
+        );
+    }
+    out
+}
+
+const TS_PRELUDE: &str = r#"// Generated by `hydra-server codegen --lang ts`. Regenerate rather than editing by hand.
+
+/**
+ * Talks to hydra in its JSON encoding (after sending the text frame `encoding:json`): `request`
+ * sends a RequestPayload, eg. `{ GetRecord: { ... } }`, and resolves with the ResponsePayload, and
+ * `subscribe` sends a SubscribeRequest, calls `onPush` with each ResponsePayload pushed to it, and
+ * resolves with the subscription id.
+ */
+export interface HydraTransport {
+  request(payload: object): Promise<any>;
+  subscribe(request: object, onPush: (payload: any) => void): Promise<number>;
+}
+
+/** An error response from hydra, named by its kind, eg. `NotFound` */
+export class HydraError extends Error {
+  constructor(
+    public kind: string,
+    message: string,
+    public field?: string,
+    public retryAfterMs?: number,
+  ) {
+    super(message);
+    this.name = kind;
+  }
+}
+
+function expect(response: any, variant: string): any {
+  if (response && response.Error) {
+    const error = response.Error;
+    throw new HydraError(error.kind, error.message, error.field ?? undefined, error.retry_after_ms ?? undefined);
+  }
+  if (!response || !(variant in response)) {
+    throw new HydraError("Internal", "Unexpected response");
+  }
+  return response[variant];
+}
+
+function encode(value: unknown): number[] {
+  return Array.from(new TextEncoder().encode(JSON.stringify(value)));
+}
+
+function decode<T>(bytes: number[]): T {
+  return JSON.parse(new TextDecoder().decode(new Uint8Array(bytes)));
+}
+
+const ULID_ALPHABET = "0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+function ulidBytes(id: string): number[] {
+  let value = 0n;
+  for (const c of id.toUpperCase()) {
+    value = (value << 5n) | BigInt(ULID_ALPHABET.indexOf(c));
+  }
+  const bytes = [];
+  for (let i = 15; i >= 0; i--) {
+    bytes.push(Number((value >> BigInt(i * 8)) & 0xffn));
+  }
+  return bytes;
+}
+
+function ulidString(bytes: number[]): string {
+  let value = bytes.reduce((value, byte) => (value << 8n) | BigInt(byte), 0n);
+  let id = "";
+  for (let i = 0; i < 26; i++) {
+    id = ULID_ALPHABET[Number(value & 31n)] + id;
+    value >>= 5n;
+  }
+  return id;
+}
+"#;
+
+const TS_COLLECTION: &str = r#"
+/** Get a record of the `{name}` collection, or null if it doesn't exist */
+export async function get{Fn}(transport: HydraTransport, id: string): Promise<{type} | null> {
+  const response = expect(await transport.request({ GetRecord: { collection: "{name}", id } }), "GetRecord");
+  return response.value ? decode<{type}>(response.value) : null;
+}
+
+/** Add a record to the `{name}` collection, resolving with its id */
+export async function create{Fn}(transport: HydraTransport, value: {type}): Promise<string> {
+  const response = expect(
+    await transport.request({ CreateRecord: { collection: "{name}", value: encode(value) } }),
+    "CreateRecord",
+  );
+  return response.id;
+}
+
+/** Replace a record of the `{name}` collection */
+export async function update{Fn}(transport: HydraTransport, id: string, value: {type}): Promise<void> {
+  expect(
+    await transport.request({ UpdateRecord: { collection: "{name}", id, value: encode(value) } }),
+    "UpdateRecord",
+  );
+}
+
+/** Delete a record of the `{name}` collection, resolving with whether it existed */
+export async function delete{Fn}(transport: HydraTransport, id: string): Promise<boolean> {
+  const response = expect(await transport.request({ DeleteRecord: { collection: "{name}", id } }), "DeleteRecord");
+  return response.previous !== null;
+}
+
+/**
+ * Call `onChange` whenever one of these records of the `{name}` collection is written (with its
+ * new value) or deleted (with null). Resolves with the subscription id.
+ */
+export function watch{Fn}(
+  transport: HydraTransport,
+  ids: string[],
+  onChange: (id: string, value: {type} | null) => void,
+): Promise<number> {
+  const request = { tree: "records/{name}", sample_above: null, keys: ids.map(ulidBytes), debounce_ms: null };
+  return transport.subscribe(request, (payload) => {
+    const changed = payload.KeyChanged;
+    if (changed) {
+      onChange(ulidString(changed.key), changed.value ? decode<{type}>(changed.value) : null);
+    }
+  });
+}
+"#;
+
+fn generate_ts(collections: &[Collection]) -> String {
+    let mut out = TS_PRELUDE.to_string();
+    for collection in collections {
+        let (shape, structs) = record_type(collection);
+        let type_name = pascal_case(&collection.name);
+        out.push('\n');
+        if shape != Shape::Struct(type_name.clone()) {
+            out.push_str(&format!(
+                "/** A record of the `{}` collection */\nexport type {} = {};\n",
+                collection.name,
+                type_name,
+                ts_type(&shape)
+            ));
+        }
+        for (n, def) in structs.iter().enumerate() {
+            if n > 0 {
+                out.push('\n');
+            }
+            out.push_str(&format!("export interface {} {{\n", def.name));
+            for field in &def.fields {
+                let mut field_type = ts_type(&field.shape);
+                if field.nullable {
+                    field_type = format!("{} | null", field_type);
+                }
+                let optional = if field.optional { "?" } else { "" };
+                out.push_str(&format!(
+                    "  {}{}: {};\n",
+                    ts_property(&field.name),
+                    optional,
+                    field_type
+                ));
+            }
+            out.push_str("}\n");
+        }
+        out.push_str(
+            &TS_COLLECTION
+                .replace("{name}", &collection.name)
+                .replace("{Fn}", &pascal_case(&collection.name))
+                .replace("{type}", &type_name),
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AppState;
+    use serde_json::json;
+
+    #[test]
+    fn test_codegen() {
+        let state = AppState::new_test().unwrap();
+        let schema = json!({
+            "type": "object",
+            "required": ["title", "author"],
+            "properties": {
+                "title": { "type": "string" },
+                "type": { "type": "string" },
+                "author": {
+                    "type": "object",
+                    "properties": { "display-name": { "type": ["string", "null"] } }
+                },
+                "tags": { "type": "array", "items": { "type": "string" } },
+                "stars": { "type": "integer" },
+                "extra": {}
+            }
+        });
+        state
+            .schemas
+            .set(
+                &state.storage,
+                "records/blog_posts",
+                Some(&schema.to_string()),
+            )
+            .unwrap();
+        // collections without schemas are included too
+        state
+            .storage
+            .insert("records/scratch", b"a", b"{}".to_vec())
+            .unwrap();
+
+        let collections = collections(&state.storage).unwrap();
+        let names: Vec<_> = collections.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["blog_posts", "scratch"]);
+
+        let rust = generate(Lang::Rust, &collections);
+        for expected in [
+            "pub struct BlogPosts {",
+            "    pub title: String,",
+            "    #[serde(default, skip_serializing_if = \"Option::is_none\")]\n    pub r#type: Option<String>,",
+            "    pub author: BlogPostsAuthor,",
+            "    #[serde(rename = \"display-name\", default, skip_serializing_if = \"Option::is_none\")]\n    pub display_name: Option<String>,",
+            "    pub tags: Option<Vec<String>>,",
+            "    pub stars: Option<i64>,",
+            "    pub extra: Option<serde_json::Value>,",
+            "pub async fn get_blog_posts(client: &Client, id: Ulid) -> Result<Option<BlogPosts>, RequestError> {",
+            "pub async fn watch_blog_posts(",
+            ".watch_keys(\"records/blog_posts\", keys, None, move |event| {",
+            "pub type Scratch = serde_json::Value;",
+            "pub async fn fetch_ingress_logs(",
+        ] {
+            assert!(rust.contains(expected), "{} not in\n{}", expected, rust);
+        }
+
+        let ts = generate(Lang::Ts, &collections);
+        for expected in [
+            "export interface BlogPosts {\n  author: BlogPostsAuthor;\n",
+            "  \"display-name\"?: string | null;",
+            "  tags?: string[];",
+            "  stars?: number;",
+            "export async function getBlogPosts(transport: HydraTransport, id: string): Promise<BlogPosts | null> {",
+            "export function watchBlogPosts(",
+            "export type Scratch = unknown;",
+        ] {
+            assert!(ts.contains(expected), "{} not in\n{}", expected, ts);
+        }
+
+        assert_eq!(pascal_case("2024-reports"), "Record2024Reports");
+    }
+}
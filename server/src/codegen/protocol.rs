@@ -0,0 +1,174 @@
+//! `hydra-server codegen --lang ts --protocol`, which writes TypeScript declarations of every
+//! protocol type, eg. Message, Request, Response, IngressLog and PaginatedCursor, so JavaScript
+//! frontends which don't use the web client can speak the JSON encoding with checked types.
+//!
+//! They're generated from proto/schema.json, which the proto crate's tests keep in step with the
+//! Rust types, and describe the types as serde_json writes them: structs as objects, options as
+//! null when they're absent, and enums externally tagged, eg. `"Hello"` or `{ GetKv: { ... } }`.
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::{Map, Value};
+
+use super::ts_property;
+
+const SCHEMA: &str = include_str!("../../../proto/schema.json");
+
+const PRELUDE: &str = r#"// Generated by `hydra-server codegen --lang ts --protocol`. Regenerate rather than editing by hand.
+//
+// The types of hydra's protocol in its JSON encoding, which a client switches to by sending the
+// text frame `encoding:json`. Integers are numbers, so 64 bit ones above 2^53 lose precision.
+"#;
+
+// types serde writes as strings in human readable encodings such as JSON, whatever the schema
+// says their bincode is
+const JSON_STRINGS: &[&str] = &["SocketAddr"];
+
+/// TypeScript declarations of the protocol's types
+pub fn generate_ts() -> Result<String> {
+    let registry: Map<String, Value> = serde_json::from_str(SCHEMA)?;
+    let mut out = PRELUDE.to_string();
+    for (name, container) in &registry {
+        out.push('\n');
+        if JSON_STRINGS.contains(&name.as_str()) {
+            out.push_str(&format!("export type {} = string;\n", name));
+            continue;
+        }
+        let (kind, inner) = single(container)?;
+        match kind {
+            "STRUCT" => out.push_str(&format!(
+                "export interface {} {}\n",
+                name,
+                fields(inner, "")?
+            )),
+            "NEWTYPESTRUCT" => {
+                out.push_str(&format!("export type {} = {};\n", name, ts_format(inner)?))
+            }
+            "TUPLESTRUCT" => out.push_str(&format!("export type {} = {};\n", name, tuple(inner)?)),
+            "ENUM" => out.push_str(&format!("export type {} =\n{};\n", name, variants(inner)?)),
+            other => bail!("{} is a {}, which can't be declared", name, other),
+        }
+    }
+    Ok(out)
+}
+
+/// The only key of a schema object, and its value
+fn single(value: &Value) -> Result<(&str, &Value)> {
+    match value
+        .as_object()
+        .map(|object| object.iter().collect::<Vec<_>>())
+    {
+        Some(entries) if entries.len() == 1 => Ok((entries[0].0.as_str(), entries[0].1)),
+        _ => Err(anyhow!("Expected an object with one key, not {}", value)),
+    }
+}
+
+/// An object type with a property per field, its lines indented by `indent`
+fn fields(fields: &Value, indent: &str) -> Result<String> {
+    let mut out = "{\n".to_string();
+    for field in fields
+        .as_array()
+        .ok_or_else(|| anyhow!("Expected fields"))?
+    {
+        let (name, format) = single(field)?;
+        out.push_str(&format!(
+            "{}  {}: {};\n",
+            indent,
+            ts_property(name),
+            ts_format(format)?
+        ));
+    }
+    out.push_str(indent);
+    out.push('}');
+    Ok(out)
+}
+
+fn tuple(formats: &Value) -> Result<String> {
+    let formats = (formats
+        .as_array()
+        .ok_or_else(|| anyhow!("Expected a tuple"))?
+        .iter())
+    .map(ts_format)
+    .collect::<Result<Vec<_>>>()?;
+    Ok(format!("[{}]", formats.join(", ")))
+}
+
+/// A union with a member per variant, in the order they're numbered
+fn variants(variants: &Value) -> Result<String> {
+    let mut numbered = (variants
+        .as_object()
+        .ok_or_else(|| anyhow!("Expected variants"))?
+        .iter())
+    .map(|(index, variant)| Ok((index.parse::<u32>()?, variant)))
+    .collect::<Result<Vec<_>>>()?;
+    numbered.sort_by_key(|(index, _)| *index);
+    let members = numbered
+        .into_iter()
+        .map(|(_, variant)| {
+            let (name, format) = single(variant)?;
+            if format == "UNIT" {
+                return Ok(format!("  | {:?}", name));
+            }
+            let (kind, inner) = single(format)?;
+            let value = match kind {
+                "NEWTYPE" => ts_format(inner)?,
+                "STRUCT" => fields(inner, "    ")?,
+                "TUPLE" => tuple(inner)?,
+                other => bail!("Variant {} is a {}, which can't be declared", name, other),
+            };
+            Ok(format!("  | {{ {}: {} }}", ts_property(name), value))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(members.join("\n"))
+}
+
+/// The TypeScript type of a value of a schema format
+fn ts_format(format: &Value) -> Result<String> {
+    if let Some(primitive) = format.as_str() {
+        return Ok(match primitive {
+            "STR" | "CHAR" => "string",
+            "BOOL" => "boolean",
+            "U8" | "U16" | "U32" | "U64" | "I8" | "I16" | "I32" | "I64" | "F32" | "F64" => "number",
+            // serde writes byte buffers as arrays of numbers
+            "BYTES" => "number[]",
+            "UNIT" => "null",
+            other => bail!("Unknown primitive {}", other),
+        }
+        .to_string());
+    }
+    let (kind, inner) = single(format)?;
+    Ok(match kind {
+        "TYPENAME" => inner
+            .as_str()
+            .ok_or_else(|| anyhow!("Expected a type name"))?
+            .to_string(),
+        "OPTION" => format!("{} | null", ts_format(inner)?),
+        "SEQ" => format!("Array<{}>", ts_format(inner)?),
+        "TUPLEARRAY" => format!("Array<{}>", ts_format(&inner["CONTENT"])?),
+        // JSON object keys are always strings
+        "MAP" => format!("Record<string, {}>", ts_format(&inner["VALUE"])?),
+        "TUPLE" => tuple(inner)?,
+        other => bail!("Unknown format {}", other),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_protocol_ts() {
+        let ts = generate_ts().unwrap();
+        for expected in [
+            "export type Message =\n  | { Request: Request }\n  | { Response: Response }\n",
+            "export interface Request {\n  id: number;\n  idempotency_key: string | null;\n  trace_id: string | null;\n  payload: RequestPayload;\n}\n",
+            "  | \"Hello\"\n",
+            "export interface IngressLog {\n  event_id: string;\n  date: string;\n  remote_addr: SocketAddr | null;\n",
+            "  query: Record<string, string>;\n",
+            "  body: number[];\n",
+            "export type PaginatedCursor =\n  | { After: Array<number> }\n",
+            "export type SocketAddr = string;\n",
+        ] {
+            assert!(ts.contains(expected), "{} not in\n{}", expected, ts);
+        }
+    }
+}
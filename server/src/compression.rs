@@ -0,0 +1,90 @@
+//! Compressing large payloads, both those sent on connections which have negotiated a
+//! proto::Compression and stored records, with --compress-bodies, see storage/versioned.rs.
+//! deflate is always available, but zstd needs the compression feature, as it needs a C compiler
+//! to build.
+
+use std::io::{Read, Write};
+
+use anyhow::{bail, Result};
+use flate2::{read::DeflateDecoder, write::DeflateEncoder};
+use hydra_proto as proto;
+
+/// Payloads and records smaller than this are left alone, as compressing them saves too little to
+/// be worth the time
+pub const COMPRESS_ABOVE: usize = 4096;
+
+/// Whether this build can compress with it
+pub fn available(compression: proto::Compression) -> bool {
+    match compression {
+        proto::Compression::Deflate => true,
+        proto::Compression::Zstd => cfg!(feature = "compression"),
+    }
+}
+
+pub fn compress(compression: proto::Compression, data: &[u8]) -> Result<Vec<u8>> {
+    match compression {
+        proto::Compression::Deflate => {
+            let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data)?;
+            Ok(encoder.finish()?)
+        }
+        #[cfg(feature = "compression")]
+        proto::Compression::Zstd => Ok(zstd::stream::encode_all(data, 0)?),
+        #[cfg(not(feature = "compression"))]
+        proto::Compression::Zstd => unavailable(),
+    }
+}
+
+pub fn decompress(compression: proto::Compression, data: &[u8]) -> Result<Vec<u8>> {
+    match compression {
+        proto::Compression::Deflate => {
+            let mut decompressed = Vec::new();
+            DeflateDecoder::new(data).read_to_end(&mut decompressed)?;
+            Ok(decompressed)
+        }
+        #[cfg(feature = "compression")]
+        proto::Compression::Zstd => Ok(zstd::stream::decode_all(data)?),
+        #[cfg(not(feature = "compression"))]
+        proto::Compression::Zstd => unavailable(),
+    }
+}
+
+#[cfg(not(feature = "compression"))]
+fn unavailable<T>() -> Result<T> {
+    bail!("zstd was requested, but the server was built without the compression feature")
+}
+
+/// Parse --compress-bodies
+pub fn parse(name: &str) -> Result<proto::Compression> {
+    let compression = match name {
+        "deflate" => proto::Compression::Deflate,
+        "zstd" => proto::Compression::Zstd,
+        _ => bail!("{} is not a compression, use deflate or zstd", name),
+    };
+    if !available(compression) {
+        bail!("zstd needs the server to be built with the compression feature, use deflate");
+    }
+    Ok(compression)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let data = b"{\"event\":\"push\"}".repeat(1000);
+        for compression in [proto::Compression::Deflate, proto::Compression::Zstd] {
+            if !available(compression) {
+                assert!(compress(compression, &data).is_err());
+                assert!(parse("zstd").is_err());
+                continue;
+            }
+            let compressed = compress(compression, &data).unwrap();
+            assert!(compressed.len() < data.len() / 10);
+            assert_eq!(decompress(compression, &compressed).unwrap(), data);
+        }
+        assert!(decompress(proto::Compression::Deflate, b"not deflate").is_err());
+        assert!(parse("gzip").is_err());
+    }
+}
@@ -0,0 +1,307 @@
+//! Computed fields: values derived from each ingress log as it's fetched, eg. the event type from
+//! a JSON body, and sent along with it in IngressLogItem::computed, so lists can show them without
+//! fetching bodies.
+//!
+//! They're configured with `--computed-fields FILE`, a JSON object of field lists by tree, where
+//! the shared tree is `ingress` and a source's is `ingress/<id>`:
+//!
+//! ```json
+//! {
+//!     "ingress/github": [
+//!         { "name": "event", "header": "x-github-event" },
+//!         { "name": "repo", "json": "$.repository.full_name" },
+//!         { "name": "client", "user_agent": "family" }
+//!     ]
+//! }
+//! ```
+//!
+//! A field whose value can't be found in a log, eg. a JSON path in a body which isn't JSON, is
+//! left out of that log's fields.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use hydra_proto::IngressLog;
+use serde::Deserialize;
+use serde_json::Value;
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ComputedField {
+    pub name: String,
+    #[serde(flatten)]
+    pub source: FieldSource,
+}
+
+/// Where a field's value comes from
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldSource {
+    // a value in the JSON body; strings are given as they are, anything else as JSON
+    Json(JsonPath),
+    // a request header, ignoring case
+    Header(String),
+    UserAgent(UserAgentPart),
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UserAgentPart {
+    // the browser, or the program, which sent the request, eg. Firefox or GitHub-Hookshot
+    Family,
+}
+
+/// A path to a value in a JSON document, eg. `$.event.type` or `$.items[0].id`. Only object
+/// members and array elements can be named.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub struct JsonPath(Vec<Segment>);
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Member(String),
+    Element(usize),
+}
+
+impl FromStr for JsonPath {
+    type Err = anyhow::Error;
+
+    fn from_str(path: &str) -> Result<Self> {
+        let mut rest = path
+            .strip_prefix('$')
+            .ok_or_else(|| anyhow!("JSON path {:?} must start with $", path))?;
+        let mut segments = Vec::new();
+        while !rest.is_empty() {
+            if let Some(member) = rest.strip_prefix('.') {
+                let end = member.find(['.', '[']).unwrap_or(member.len());
+                if end == 0 {
+                    bail!("JSON path {:?} has an empty member name", path);
+                }
+                segments.push(Segment::Member(member[..end].to_string()));
+                rest = &member[end..];
+            } else if let Some(element) = rest.strip_prefix('[') {
+                let (index, after) = element
+                    .split_once(']')
+                    .ok_or_else(|| anyhow!("JSON path {:?} has an unclosed [", path))?;
+                let index = index
+                    .parse()
+                    .with_context(|| format!("JSON path {:?} has a bad index", path))?;
+                segments.push(Segment::Element(index));
+                rest = after;
+            } else {
+                bail!(
+                    "JSON path {:?} should continue with . or [ at {:?}",
+                    path,
+                    rest
+                );
+            }
+        }
+        Ok(Self(segments))
+    }
+}
+
+impl TryFrom<String> for JsonPath {
+    type Error = anyhow::Error;
+
+    fn try_from(path: String) -> Result<Self> {
+        path.parse()
+    }
+}
+
+impl JsonPath {
+    pub fn find<'a>(&self, mut value: &'a Value) -> Option<&'a Value> {
+        for segment in &self.0 {
+            value = match segment {
+                Segment::Member(name) => value.as_object()?.get(name)?,
+                Segment::Element(index) => value.as_array()?.get(*index)?,
+            };
+        }
+        Some(value)
+    }
+}
+
+/// The configured fields, by tree
+#[derive(Default, Debug)]
+pub struct ComputedFields(HashMap<String, Vec<ComputedField>>);
+
+impl ComputedFields {
+    pub fn new(fields: HashMap<String, Vec<ComputedField>>) -> Result<Self> {
+        for (tree, fields) in &fields {
+            if !crate::sources::is_ingress_tree(tree) {
+                bail!("Computed fields are for ingress trees, not {}", tree);
+            }
+            for (i, field) in fields.iter().enumerate() {
+                if fields[..i].iter().any(|other| other.name == field.name) {
+                    bail!(
+                        "Computed field {} is defined more than once for {}",
+                        field.name,
+                        tree
+                    );
+                }
+            }
+        }
+        Ok(Self(fields))
+    }
+
+    /// The values of a tree's fields for a log, leaving out any which aren't there
+    pub fn compute(&self, tree: &str, log: &IngressLog) -> BTreeMap<String, String> {
+        let Some(fields) = self.0.get(tree) else {
+            return BTreeMap::new();
+        };
+        // parsed once, and only if a field needs it
+        let mut body: Option<Option<Value>> = None;
+        fields
+            .iter()
+            .filter_map(|field| {
+                let value = match &field.source {
+                    FieldSource::Json(path) => {
+                        let body =
+                            body.get_or_insert_with(|| serde_json::from_slice(&log.body).ok());
+                        match path.find(body.as_ref()?)? {
+                            Value::String(s) => s.clone(),
+                            value => value.to_string(),
+                        }
+                    }
+                    FieldSource::Header(name) => header(log, name)?.to_string(),
+                    FieldSource::UserAgent(UserAgentPart::Family) => {
+                        user_agent_family(header(log, "user-agent")?)?
+                    }
+                };
+                Some((field.name.clone(), value))
+            })
+            .collect()
+    }
+}
+
+fn header<'a>(log: &'a IngressLog, name: &str) -> Option<&'a str> {
+    log.headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+/// The family of a User-Agent: the browser for browsers, which all claim to be Mozilla and
+/// several of which claim to be each other, and otherwise the first product named, eg. curl for
+/// `curl/8.4.0` or GitHub-Hookshot for `GitHub-Hookshot/1a2b3c`
+fn user_agent_family(user_agent: &str) -> Option<String> {
+    // most specific first, as eg. Edge also names Chrome and Safari
+    const BROWSERS: &[(&str, &str)] = &[
+        ("Edg/", "Edge"),
+        ("OPR/", "Opera"),
+        ("Firefox/", "Firefox"),
+        ("Chrome/", "Chrome"),
+        ("Safari/", "Safari"),
+        ("MSIE ", "Internet Explorer"),
+        ("Trident/", "Internet Explorer"),
+    ];
+    if user_agent.starts_with("Mozilla/") {
+        if let Some((_, family)) = BROWSERS
+            .iter()
+            .find(|(token, _)| user_agent.contains(token))
+        {
+            return Some(family.to_string());
+        }
+    }
+    let product = user_agent.split_whitespace().next()?;
+    let name = product.split('/').next()?;
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+pub fn load_fields(path: &Path) -> Result<ComputedFields> {
+    let json = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read computed fields from {}", path.display()))?;
+    let fields = serde_json::from_str(&json)
+        .with_context(|| format!("Could not parse computed fields in {}", path.display()))?;
+    ComputedFields::new(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::ingress_log;
+
+    fn log(headers: &[(&str, &str)], body: &str) -> IngressLog {
+        IngressLog {
+            headers: headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            ..ingress_log("hooks", body.as_bytes())
+        }
+    }
+
+    #[test]
+    fn test_computed_fields() {
+        let fields: ComputedFields = ComputedFields::new(
+            serde_json::from_str(
+                r#"{
+                    "ingress": [
+                        { "name": "type", "json": "$.event.type" },
+                        { "name": "first", "json": "$.items[0]" },
+                        { "name": "count", "json": "$.count" },
+                        { "name": "delivery", "header": "X-Delivery" },
+                        { "name": "client", "user_agent": "family" }
+                    ]
+                }"#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        let json = log(
+            &[
+                ("x-delivery", "d-1"),
+                ("user-agent", "GitHub-Hookshot/1a2b3c"),
+            ],
+            r#"{"event": {"type": "push"}, "items": [{"id": 1}], "count": 3}"#,
+        );
+        assert_eq!(
+            fields.compute("ingress", &json),
+            BTreeMap::from([
+                ("type".to_string(), "push".to_string()),
+                ("first".to_string(), r#"{"id":1}"#.to_string()),
+                ("count".to_string(), "3".to_string()),
+                ("delivery".to_string(), "d-1".to_string()),
+                ("client".to_string(), "GitHub-Hookshot".to_string()),
+            ])
+        );
+
+        // whatever can't be found is left out
+        let text = log(&[], "not json");
+        assert!(fields.compute("ingress", &text).is_empty());
+        assert!(fields.compute("ingress/github", &json).is_empty());
+
+        let family = |ua| user_agent_family(ua);
+        assert_eq!(
+            family("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36 Edg/124.0.0.0"),
+            Some("Edge".to_string())
+        );
+        assert_eq!(
+            family("Mozilla/5.0 (Macintosh; Intel Mac OS X 14_4) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.4 Safari/605.1.15"),
+            Some("Safari".to_string())
+        );
+        assert_eq!(
+            family("Mozilla/5.0 (X11; Linux x86_64; rv:125.0) Gecko/20100101 Firefox/125.0"),
+            Some("Firefox".to_string())
+        );
+        assert_eq!(family("curl/8.4.0"), Some("curl".to_string()));
+        assert_eq!(family(""), None);
+
+        // mistakes are caught when the file is loaded
+        assert!("event.type".parse::<JsonPath>().is_err());
+        assert!("$.items[x]".parse::<JsonPath>().is_err());
+        assert!("$..type".parse::<JsonPath>().is_err());
+        assert!(ComputedFields::new(HashMap::from([("records".to_string(), vec![])])).is_err());
+        let twice = vec![
+            ComputedField {
+                name: "a".to_string(),
+                source: FieldSource::Header("x".to_string()),
+            };
+            2
+        ];
+        assert!(ComputedFields::new(HashMap::from([("ingress".to_string(), twice)])).is_err());
+    }
+}
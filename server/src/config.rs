@@ -0,0 +1,281 @@
+use std::{net::SocketAddr, path::PathBuf};
+
+use clap::{Args, Parser, Subcommand};
+use hydra_proto as proto;
+
+use crate::{codec::CodecKind, compression};
+
+/// Where the server listens for HTTP and WebSocket connections
+pub const LISTEN_ADDR: &str = "0.0.0.0:9797";
+
+#[derive(Parser, Debug, Clone)]
+#[command(name = "hydra-server", about = "Hydra server")]
+pub struct ServerConfig {
+    /// Path to the sled database directory (defaults to $HYDRA_DATA_DIR/sled, or ~/.hydra/sled
+    /// if that isn't set)
+    #[arg(long, global = true)]
+    pub db_path: Option<PathBuf>,
+
+    /// Maximum size of the database's page cache, in bytes
+    #[arg(long, value_name = "BYTES", global = true)]
+    pub cache_capacity: Option<u64>,
+
+    /// How often writes are flushed to disk in the background
+    #[arg(long, value_name = "MILLISECONDS", global = true)]
+    pub flush_every_ms: Option<u64>,
+
+    /// Compress the database on disk. Needs the server to be built with the compression feature.
+    #[arg(long, global = true)]
+    pub compression: bool,
+
+    /// Upgrade a database written by an older hydra to the current storage format. Without this
+    /// such a database is refused, so take a backup first.
+    #[arg(long, global = true)]
+    pub migrate: bool,
+
+    /// The codec to store ingress logs with. cbor and msgpack are larger than bincode, but can be
+    /// read without hydra's types, eg. from a backup by another language's tools.
+    #[arg(long, value_enum, default_value_t, global = true)]
+    pub storage_codec: CodecKind,
+
+    /// Store ingress logs larger than a few KiB, ie. those with large bodies, compressed with
+    /// deflate or zstd, which needs the compression feature. Unlike --compression it only
+    /// compresses what's large enough to be worth it, and can be turned on for an existing
+    /// database, as logs are read however they were written.
+    #[arg(long, value_name = "COMPRESSION", value_parser = parse_compression, global = true)]
+    pub compress_bodies: Option<proto::Compression>,
+
+    /// If the database is locked by another hydra instance, keep retrying for up to this many
+    /// seconds rather than exiting immediately
+    #[arg(long, value_name = "SECONDS", global = true)]
+    pub wait_for_lock: Option<u64>,
+
+    /// Record the client address of ingress requests from their Forwarded or X-Forwarded-For
+    /// headers rather than the connection. Only use this behind a proxy which sets them, as
+    /// anyone else can forge them.
+    #[arg(long, global = true)]
+    pub trust_proxy: bool,
+
+    /// Load the `<collection>.ndjson` files in this directory on startup. Each file is only ever
+    /// loaded once, unless its contents change.
+    #[arg(long, value_name = "DIR", global = true)]
+    pub fixtures: Option<PathBuf>,
+
+    /// Remove ingress logs once they are older than this
+    #[arg(long, value_name = "SECONDS", global = true)]
+    pub retain_for: Option<u64>,
+
+    /// Keep at most this many logs in each ingress tree, removing the oldest first
+    #[arg(long, value_name = "COUNT", global = true)]
+    pub retain_count: Option<usize>,
+
+    /// How often to remove logs which --retain-for or --retain-count no longer allow
+    #[arg(long, value_name = "SECONDS", default_value_t = 60, global = true)]
+    pub prune_every: u64,
+
+    /// Close WebSocket connections which send nothing for this long, as the client has probably
+    /// gone without closing them. Clients keep their connections alive with Pings. 0 never closes
+    /// idle connections.
+    #[arg(long, value_name = "SECONDS", default_value_t = 60, global = true)]
+    pub idle_timeout: u64,
+
+    /// Capture the webhook providers listed in this JSON file into trees of their own, each
+    /// behind its own token, see sources.rs
+    #[arg(long, value_name = "FILE", global = true)]
+    pub sources: Option<PathBuf>,
+
+    /// Derive the fields listed in this JSON file from each log fetched, see computed.rs
+    #[arg(long, value_name = "FILE", global = true)]
+    pub computed_fields: Option<PathBuf>,
+
+    /// How durable captures and imports are once acknowledged, for the shared ingress tree and
+    /// sources which don't set their own, see durability.rs
+    #[arg(long, value_enum, default_value_t, global = true)]
+    pub durability: crate::durability::Durability,
+
+    /// Keep this many of the newest logs in each ingress tree in memory, to serve the newest page
+    /// of logs without reading the database. 0 turns the cache off. See hot.rs.
+    #[arg(long, value_name = "COUNT", default_value_t = crate::hot::DEFAULT_CAPACITY, global = true)]
+    pub hot_cache: usize,
+
+    /// Note when records were last read or written, for the usage report at /usage
+    #[arg(long, global = true)]
+    pub track_access: bool,
+
+    /// Keep a log of what's pushed to each subscription for this long, so a session's deliveries
+    /// can be replayed with ReplayDeliveries when debugging a client
+    #[arg(long, value_name = "SECONDS", global = true)]
+    pub log_deliveries_for: Option<u64>,
+
+    /// Keep snapshots of the database in this directory, taken with POST /admin/snapshot or every
+    /// --snapshot-every seconds. Restore one with `hydra-server restore`.
+    #[arg(long, value_name = "DIR", global = true)]
+    pub snapshot_dir: Option<PathBuf>,
+
+    /// Take a snapshot into --snapshot-dir this often
+    #[arg(long, value_name = "SECONDS", requires = "snapshot_dir", global = true)]
+    pub snapshot_every: Option<u64>,
+
+    /// How many snapshots to keep in --snapshot-dir, removing the oldest first
+    #[arg(long, value_name = "COUNT", default_value_t = 7, global = true)]
+    pub snapshot_keep: usize,
+
+    /// Mirror captures to the hydra at this http:// URL, eg. a staging server, without ever
+    /// holding up or failing them if it's slow or down. See shadow.rs.
+    #[arg(long, value_name = "URL", global = true)]
+    pub shadow_to: Option<crate::shadow::ShadowTarget>,
+
+    /// Mirror WebSocket mutations to --shadow-to as well as captures
+    #[arg(long, requires = "shadow_to", global = true)]
+    pub shadow_mutations: bool,
+
+    /// How many requests may wait to be mirrored, dropping the oldest beyond that
+    #[arg(long, value_name = "COUNT", default_value_t = 100_000, global = true)]
+    pub shadow_outbox_limit: usize,
+
+    /// Keep the DAG in step with the hydra whose WebSocket endpoint is at this ws:// URL, eg.
+    /// ws://replica:9797/ws, pulling its events and pushing ours. Can be given more than once.
+    /// See sync.rs.
+    #[arg(long, value_name = "URL", global = true)]
+    pub sync_peer: Vec<String>,
+
+    /// How often to sync with each --sync-peer
+    #[arg(long, value_name = "SECONDS", default_value_t = 10, global = true)]
+    pub sync_every: u64,
+
+    /// Sign a checkpoint of the DAG every --checkpoint-every seconds with the ed25519 key whose
+    /// seed is in this file, as 64 hex digits, so clients can check with VerifyBasis that the log
+    /// hasn't been rewritten since. See checkpoints.rs.
+    #[arg(long, value_name = "FILE", global = true)]
+    pub checkpoint_key: Option<PathBuf>,
+
+    /// How often to take a checkpoint, if events have been added since the last
+    #[arg(long, value_name = "SECONDS", default_value_t = 300, global = true)]
+    pub checkpoint_every: u64,
+
+    /// The token the /admin endpoints need, from this file, as a bearer token. Otherwise it's
+    /// read from HYDRA_ADMIN_TOKEN, and if that isn't set either they refuse every request. See
+    /// admin.rs.
+    #[arg(long, value_name = "FILE", global = true)]
+    pub admin_token_file: Option<PathBuf>,
+
+    /// Raise alerts according to the rules in this JSON file
+    #[arg(long, value_name = "FILE", global = true)]
+    pub alerts: Option<PathBuf>,
+
+    /// Also serve the gRPC service described by hydra.proto on this address, eg. 0.0.0.0:9798,
+    /// see grpc.rs
+    #[arg(long, value_name = "ADDR", global = true)]
+    pub grpc_listen: Option<SocketAddr>,
+
+    /// Let browser apps on this origin, eg. https://dash.example.com, call the HTTP endpoints and
+    /// open the WebSocket. Repeat it for each origin, or give * for any. See cors.rs.
+    #[arg(long = "cors-origin", value_name = "ORIGIN", global = true)]
+    pub cors_origins: Vec<String>,
+
+    /// A request header cross-origin requests may send, repeated for each. Content-Type and
+    /// Authorization if none are given.
+    #[arg(long = "cors-header", value_name = "HEADER", global = true)]
+    pub cors_headers: Vec<String>,
+
+    /// A method cross-origin requests may use, repeated for each. Those webhooks are captured with
+    /// if none are given.
+    #[arg(long = "cors-method", value_name = "METHOD", global = true)]
+    pub cors_methods: Vec<String>,
+
+    /// Also serve on a Unix domain socket at this path, for clients on the same host
+    #[cfg(unix)]
+    #[arg(long, value_name = "PATH", global = true)]
+    pub unix_socket: Option<PathBuf>,
+
+    /// Permissions of the --unix-socket file, in octal, eg. 660 to let only the server's user and
+    /// group connect. Otherwise they're left to the umask.
+    #[cfg(unix)]
+    #[arg(long, value_name = "MODE", value_parser = parse_mode, global = true)]
+    pub unix_socket_mode: Option<u32>,
+
+    /// Check that the database, codecs and port all work, print a report and exit, failing if
+    /// any check did, rather than starting the server
+    #[arg(long)]
+    pub self_test: bool,
+
+    /// Also check that this HOST:PORT accepts connections when running --self-test. Can be given
+    /// more than once.
+    #[arg(long, value_name = "HOST:PORT")]
+    pub self_test_peer: Vec<String>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[cfg(unix)]
+fn parse_compression(name: &str) -> Result<proto::Compression, String> {
+    compression::parse(name).map_err(|e| e.to_string())
+}
+
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| format!("{} is not a file mode, eg. 660", mode))
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Run a development server: a throwaway database seeded with sample data, the wasm client
+    /// served at /dev, and the client rebuilt whenever the proto or web sources change
+    Dev(DevConfig),
+    /// Restore an archive made by GET /export/*tree into the database, then exit
+    Import(ImportConfig),
+    /// Restore a snapshot taken with --snapshot-dir into a new database at --db-path, then exit
+    Restore(RestoreConfig),
+    /// Write typed client wrappers for every record collection, from their schemas, then exit
+    Codegen(CodegenConfig),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct DevConfig {
+    /// How many sample ingress logs to seed the database with
+    #[arg(long, default_value_t = 25)]
+    pub seed: usize,
+
+    /// Don't build the wasm client with wasm-pack, eg. if you're running it some other way
+    #[arg(long)]
+    pub no_wasm: bool,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ImportConfig {
+    /// The ingress tree to restore the archive into
+    #[arg(long, default_value = "ingress")]
+    pub tree: String,
+
+    /// The archive's format, if it isn't clear from the file's extension (.cbor for CBOR)
+    #[arg(long, value_enum)]
+    pub format: Option<crate::handler::export::ArchiveFormat>,
+
+    /// The archive to restore
+    pub file: PathBuf,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct RestoreConfig {
+    /// The snapshot to restore
+    pub snapshot: PathBuf,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct CodegenConfig {
+    /// The language to write the wrappers in
+    #[arg(long, value_enum)]
+    pub lang: crate::codegen::Lang,
+
+    /// Where to write them, rather than to stdout
+    #[arg(long, short)]
+    pub out: Option<PathBuf>,
+
+    /// Write declarations of the protocol's types, eg. Message and IngressLog, rather than
+    /// wrappers for the record collections. Only for --lang ts, as Rust uses the proto crate.
+    #[arg(long)]
+    pub protocol: bool,
+}
@@ -0,0 +1,432 @@
+use std::{
+    net::SocketAddr,
+    ops::ControlFlow,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket};
+use futures_util::{stream::SplitSink, SinkExt, StreamExt};
+use hydra_proto as proto;
+use tokio::{
+    sync::{mpsc, watch},
+    task::JoinHandle,
+};
+
+use crate::{
+    appstate::AppState,
+    cancel::InFlight,
+    compression, encoding,
+    encoding::ConnectionEncoding,
+    error::AppError,
+    handle_request,
+    outbound::{self, PongSender},
+};
+
+/// How many outbound messages may be queued for a connection before pushes are dropped
+const OUTBOUND_CAPACITY: usize = 64;
+
+/// How many requests may be read ahead of the one being handled
+const REQUEST_CAPACITY: usize = 16;
+
+/// How long to wait for a client to answer our Close frame when the server shuts down
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Per-connection context for requests which outlive their own response, such as subscriptions
+pub struct Connection {
+    pub id: usize,
+    pub outbound: outbound::OutboundSender,
+    pub requests: Arc<InFlight>,
+    // whether the upgrade carried the admin token, see admin.rs
+    pub admin: bool,
+}
+
+impl Connection {
+    /// Refuse a request only admins may make, unless this is an admin's connection
+    pub fn require_admin(&self, request: &str) -> Result<(), AppError> {
+        match self.admin {
+            true => Ok(()),
+            false => Err(AppError::unauthorized(format!(
+                "{} needs the admin token when connecting",
+                request
+            ))),
+        }
+    }
+}
+
+/// Drives one WebSocket connection. The socket is split three ways:
+///
+/// - a reader task, which decodes frames, applies cancellations and negotiation and answers Pings
+///   as soon as they arrive, and queues requests for the actor
+/// - the actor itself, which handles requests one at a time, in order
+/// - a writer task, the only thing which writes to the socket, fed by the connection's outbound
+///   channel. Responses and subscription pushes both go through it, so they can't interleave.
+///
+/// The connection ends when the client goes away, or sends nothing for the idle timeout, or when
+/// the server shuts down, in which case the request being handled is cancelled and answered, and
+/// the client is sent a Close frame. When the server starts draining, the client is pushed a
+/// GoingAway, and is left to go by itself.
+pub struct ConnectionActor {
+    who: SocketAddr,
+    state: AppState,
+    connection: Connection,
+    requests: mpsc::Receiver<proto::Request>,
+    shutdown: watch::Receiver<bool>,
+    // until GoingAway has been pushed, see shutdown.rs
+    draining: Option<watch::Receiver<Option<proto::GoingAway>>>,
+    reader: JoinHandle<()>,
+    writer: JoinHandle<SplitSink<WebSocket, Message>>,
+}
+
+/// Why a connection's main loop stopped
+enum Exit {
+    // the client disconnected or closed the connection
+    Disconnected,
+    // responses can no longer be queued
+    Unwritable,
+    ShuttingDown,
+}
+
+impl ConnectionActor {
+    /// Run a newly upgraded socket until the connection ends
+    pub async fn run(mut socket: WebSocket, who: SocketAddr, state: AppState, admin: bool) {
+        println!("Connected to {}", who);
+
+        // Send a ping (unsupported by some browsers) just to kick things off
+        if socket.send(Message::Ping(vec![1, 2, 3])).await.is_ok() {
+            println!("Pinged {who}...");
+        } else {
+            println!("Could not send ping to {who}!");
+            return;
+        }
+
+        let actor = Self::start(socket, who, state, admin);
+        actor.state.connections.fetch_add(1, Ordering::Relaxed);
+        let state = actor.state.clone();
+        actor.run_until_closed().await;
+        state.connections.fetch_sub(1, Ordering::Relaxed);
+
+        println!("Websocket context {who} destroyed");
+    }
+
+    fn start(socket: WebSocket, who: SocketAddr, state: AppState, admin: bool) -> Self {
+        let (mut sender, mut receiver) = socket.split();
+
+        let (outbound, mut outbound_receiver) = outbound::channel(OUTBOUND_CAPACITY);
+        let encoding = ConnectionEncoding::default();
+        let writer_encoding = encoding.clone();
+        // hands the sink back once every sender is gone and the queue is drained
+        let writer = tokio::spawn(async move {
+            while let Some(message) = outbound_receiver.recv().await {
+                if let Err(e) = send_message(&mut sender, message, &writer_encoding).await {
+                    println!("Failed to send message to {who}: {:?}", e);
+                    break;
+                }
+            }
+            sender
+        });
+
+        let connection = Connection {
+            id: state.subscriptions.next_connection_id(),
+            outbound,
+            requests: Arc::new(InFlight::new()),
+            admin,
+        };
+
+        let (request_sender, request_receiver) = mpsc::channel(REQUEST_CAPACITY);
+        let requests = connection.requests.clone();
+        let pongs = connection.outbound.pong_sender();
+        let idle_timeout = state.idle_timeout;
+        #[cfg(feature = "chaos")]
+        let mut injector = state.chaos.as_ref().map(|policy| policy.injector());
+        let reader = tokio::spawn(async move {
+            'receive: loop {
+                let next = match idle_timeout {
+                    Some(timeout) => match tokio::time::timeout(timeout, receiver.next()).await {
+                        Ok(next) => next,
+                        Err(_) => {
+                            println!("{who} has been idle for {:?}, closing", timeout);
+                            break;
+                        }
+                    },
+                    None => receiver.next().await,
+                };
+                let Some(msg) = next else {
+                    break;
+                };
+                if let Ok(msg) = msg {
+                    #[cfg(feature = "chaos")]
+                    let frames = match injector.as_mut() {
+                        Some(injector) => {
+                            let (delay, frames) = injector.inject(msg);
+                            tokio::time::sleep(delay).await;
+                            frames
+                        }
+                        None => vec![msg],
+                    };
+                    #[cfg(not(feature = "chaos"))]
+                    let frames = vec![msg];
+
+                    for msg in frames {
+                        match process_message(msg, who, &requests, &pongs, &encoding) {
+                            ControlFlow::Break(()) => break 'receive,
+                            ControlFlow::Continue(Some(request)) => {
+                                // Waits while the connection is busy with earlier requests, so a
+                                // client which isn't keeping up with its responses is not sent
+                                // any more work. If the actor has stopped taking requests, keep
+                                // reading anyway so that a closing handshake can complete.
+                                if request_sender.send(request).await.is_err() {
+                                    println!("Dropping request from {who}, connection is closing");
+                                }
+                            }
+                            ControlFlow::Continue(None) => {}
+                        }
+                    }
+                } else {
+                    println!("client {who} abruptly disconnected");
+                    break;
+                }
+            }
+            // nobody is left to read the results of anything still running or queued
+            requests.cancel_all();
+        });
+
+        Self {
+            who,
+            shutdown: state.shutdown.subscribe(),
+            draining: Some(state.draining.subscribe()),
+            state,
+            connection,
+            requests: request_receiver,
+            reader,
+            writer,
+        }
+    }
+
+    async fn run_until_closed(mut self) {
+        let exit = self.handle_requests().await;
+        let Self {
+            who,
+            state,
+            connection,
+            requests,
+            reader,
+            writer,
+            ..
+        } = self;
+
+        drop(requests);
+        connection.requests.cancel_all();
+        state.subscriptions.remove_connection(connection.id);
+        // dropping the last OutboundSender lets the writer flush what's queued and finish
+        drop(connection);
+        let sender = writer.await;
+
+        match (exit, sender) {
+            (Exit::ShuttingDown, Ok(mut sender)) => {
+                let close = Message::Close(Some(CloseFrame {
+                    code: close_code::AWAY,
+                    reason: "Server is shutting down".into(),
+                }));
+                if sender.send(close).await.is_ok() {
+                    // the reader stops once the client's Close comes back
+                    if tokio::time::timeout(CLOSE_TIMEOUT, reader).await.is_err() {
+                        println!("{who} did not answer our Close frame");
+                    }
+                    return;
+                }
+                reader.abort();
+            }
+            _ => reader.abort(),
+        }
+    }
+
+    async fn handle_requests(&mut self) -> Exit {
+        loop {
+            let request = tokio::select! {
+                request = self.requests.recv() => match request {
+                    Some(request) => request,
+                    None => return Exit::Disconnected,
+                },
+                _ = shutting_down(&mut self.shutdown) => return Exit::ShuttingDown,
+                going_away = going_away(&mut self.draining) => {
+                    self.draining = None;
+                    // not an answer to anything, see proto::GoingAway
+                    let push = proto::Response {
+                        request_id: 0,
+                        sequence: 0,
+                        trace_id: None,
+                        payload: proto::ResponsePayload::GoingAway(going_away),
+                    };
+                    if self.connection.outbound.send(push).await.is_err() {
+                        return Exit::Unwritable;
+                    }
+                    continue;
+                }
+            };
+
+            let handling = handle_request(request, &self.connection, &self.state);
+            tokio::pin!(handling);
+            let response = tokio::select! {
+                response = &mut handling => response,
+                _ = shutting_down(&mut self.shutdown) => {
+                    // have the request wrap up early, but still answer it before closing
+                    self.connection.requests.cancel_all();
+                    handling.await
+                }
+            };
+
+            // a live query's pushes are held back until its page has been queued
+            let live = match &response.payload {
+                proto::ResponsePayload::LiveQuery(page) => {
+                    Some((response.request_id, page.snapshot.sequence))
+                }
+                _ => None,
+            };
+            if self.connection.outbound.send(response).await.is_err() {
+                println!("Connection to {} is no longer writable", self.who);
+                return Exit::Unwritable;
+            }
+            if let Some((request_id, snapshot)) = live {
+                self.state
+                    .subscriptions
+                    .release(self.connection.id, request_id, snapshot);
+            }
+        }
+    }
+}
+
+/// helper to print contents of messages to stdout. Has special treatment for Close.
+/// Returns the request to handle if the message was one, but applies cancellations, encoding and
+/// compression negotiation and Pings immediately.
+fn process_message(
+    msg: Message,
+    who: SocketAddr,
+    requests: &InFlight,
+    pongs: &PongSender,
+    encoding: &ConnectionEncoding,
+) -> ControlFlow<(), Option<proto::Request>> {
+    let message = match msg {
+        Message::Text(t) => {
+            println!(">>> {who} sent str: {t:?}");
+            if let Some(negotiated) = proto::Encoding::from_negotiation(&t) {
+                println!("{who} switched to {:?} encoding", negotiated);
+                encoding.set(negotiated);
+                None
+            } else if let Some(negotiated) = proto::Compression::from_negotiation(&t) {
+                match negotiated.filter(|compression| !compression::available(*compression)) {
+                    Some(unavailable) => println!(
+                        "{who} asked for {:?} compression, but it isn't available",
+                        unavailable
+                    ),
+                    None => {
+                        println!("{who} switched to {:?} compression", negotiated);
+                        encoding.set_compression(negotiated);
+                    }
+                }
+                None
+            } else if encoding.get() == proto::Encoding::Json {
+                encoding::decode_json(&t)
+                    .map_err(|e| println!("Failed to deserialize JSON message: {:?}", e))
+                    .ok()
+            } else {
+                None
+            }
+        }
+        Message::Binary(d) => {
+            println!(">>> {} sent {} bytes: {:?}", who, d.len(), d);
+
+            // Deserialize the binary message into a Message enum
+            encoding
+                .decode(&d)
+                .map_err(|_| println!("Failed to deserialize message"))
+                .ok()
+        }
+        Message::Close(c) => {
+            if let Some(cf) = c {
+                println!(
+                    ">>> {} sent close with code {} and reason `{}`",
+                    who, cf.code, cf.reason
+                );
+            } else {
+                println!(">>> {who} somehow sent close message without CloseFrame");
+            }
+            return ControlFlow::Break(());
+        }
+
+        Message::Pong(v) => {
+            println!(">>> {who} sent pong with {v:?}");
+            None
+        }
+        // You should never need to manually handle Message::Ping, as axum's websocket library
+        // will do so for you automagically by replying with Pong and copying the v according to
+        // spec. But if you need the contents of the pings you can see them here.
+        Message::Ping(v) => {
+            println!(">>> {who} sent ping with {v:?}");
+            None
+        }
+    };
+
+    match message {
+        Some(proto::Message::Request(proto::Request {
+            payload: proto::RequestPayload::Cancel(cancel),
+            ..
+        })) => {
+            println!("{who} cancelled request {}", cancel.request_id);
+            requests.cancel(cancel.request_id);
+        }
+        Some(proto::Message::Request(request)) => {
+            return ControlFlow::Continue(Some(request));
+        }
+        Some(proto::Message::Ping(ping)) => {
+            let pong = ping.pong(unix_millis());
+            if pongs.try_send(pong).is_err() {
+                println!("Could not answer ping {} from {who}", ping.sequence);
+            }
+        }
+        Some(proto::Message::Response(_) | proto::Message::Pong(_)) => {
+            println!("Unexpected response message from client");
+        }
+        None => {}
+    }
+    ControlFlow::Continue(None)
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// Resolves once the server starts shutting down
+async fn shutting_down(shutdown: &mut watch::Receiver<bool>) {
+    if shutdown.wait_for(|shutdown| *shutdown).await.is_err() {
+        // the server is gone without ever shutting down, so it never will
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Resolves once the server starts draining, unless the connection has already been told
+async fn going_away(
+    draining: &mut Option<watch::Receiver<Option<proto::GoingAway>>>,
+) -> proto::GoingAway {
+    if let Some(draining) = draining {
+        if let Ok(going_away) = draining.wait_for(Option::is_some).await {
+            if let Some(going_away) = going_away.clone() {
+                return going_away;
+            }
+        }
+    }
+    std::future::pending().await
+}
+
+/// Serialize a message in the connection's encoding and write it to the socket
+async fn send_message(
+    sender: &mut SplitSink<WebSocket, Message>,
+    message: proto::Message,
+    encoding: &ConnectionEncoding,
+) -> Result<()> {
+    sender.send(encoding.encode(&message)?).await?;
+    Ok(())
+}
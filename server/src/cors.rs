@@ -0,0 +1,235 @@
+//! Cross-origin access for browser apps hosted elsewhere, eg. a dashboard on another domain.
+//! It's off unless --cors-origin is given, in which case responses carry the CORS headers letting
+//! the listed origins read them, and preflight requests are answered, rather than captured when
+//! they're sent to /ingress. OPTIONS requests which aren't preflights are still captured.
+//!
+//! Browsers don't apply CORS to WebSockets, but do say which origin a handshake comes from, so
+//! once origins are listed, /ws refuses browsers on any other, as another site's page could
+//! otherwise talk to the server as whoever is visiting it. The server's own origin, and clients
+//! which aren't browsers and so send no Origin, are always let in.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method},
+    middleware::{self, Next},
+    response::Response,
+    Router,
+};
+use tower::{Layer, ServiceExt};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+use crate::{config::ServerConfig, error::AppError, trace::TRACE_HEADER};
+
+/// Request headers cross-origin requests may send, unless --cors-header says otherwise
+const DEFAULT_HEADERS: &[HeaderName] = &[header::CONTENT_TYPE, header::AUTHORIZATION];
+
+/// Methods cross-origin requests may use, unless --cors-method says otherwise: those webhooks
+/// are captured with
+const DEFAULT_METHODS: &[Method] = &[
+    Method::GET,
+    Method::POST,
+    Method::PUT,
+    Method::PATCH,
+    Method::DELETE,
+    Method::OPTIONS,
+    Method::HEAD,
+];
+
+/// How long browsers may cache a preflight's answer
+const PREFLIGHT_MAX_AGE: Duration = Duration::from_secs(600);
+
+#[derive(Clone, Debug)]
+pub struct CorsPolicy {
+    // None allows any origin
+    origins: Option<Vec<HeaderValue>>,
+    headers: Vec<HeaderName>,
+    methods: Vec<Method>,
+}
+
+impl CorsPolicy {
+    /// None unless the config lists origins
+    pub fn from_config(config: &ServerConfig) -> Result<Option<Self>> {
+        if config.cors_origins.is_empty() {
+            return Ok(None);
+        }
+        let origins = match config.cors_origins.iter().any(|origin| origin == "*") {
+            true => None,
+            false => Some(
+                (config.cors_origins.iter())
+                    // browsers send origins without a trailing slash
+                    .map(|origin| parse("origin", origin.trim_end_matches('/')))
+                    .collect::<Result<_>>()?,
+            ),
+        };
+        let headers = match &config.cors_headers[..] {
+            [] => DEFAULT_HEADERS.to_vec(),
+            headers => (headers.iter())
+                .map(|name| parse("header", name))
+                .collect::<Result<_>>()?,
+        };
+        let methods = match &config.cors_methods[..] {
+            [] => DEFAULT_METHODS.to_vec(),
+            methods => (methods.iter())
+                .map(|method| parse("method", &method.to_ascii_uppercase()))
+                .collect::<Result<_>>()?,
+        };
+        Ok(Some(Self {
+            origins,
+            headers,
+            methods,
+        }))
+    }
+
+    /// Add the CORS headers to each of the router's responses, and answer preflights
+    pub fn apply<S>(&self, router: Router<S>) -> Router<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        router.layer(middleware::from_fn_with_state(
+            self.layer(),
+            answer_preflights,
+        ))
+    }
+
+    fn layer(&self) -> CorsLayer {
+        let origins = match &self.origins {
+            Some(origins) => AllowOrigin::list(origins.clone()),
+            None => AllowOrigin::any(),
+        };
+        CorsLayer::new()
+            .allow_origin(origins)
+            .allow_headers(self.headers.clone())
+            .allow_methods(self.methods.clone())
+            // so a client told to back off can read how long for, and which trace to look up
+            .expose_headers([header::RETRY_AFTER, HeaderName::from_static(TRACE_HEADER)])
+            .max_age(PREFLIGHT_MAX_AGE)
+    }
+
+    /// Refuse a WebSocket handshake from a browser on an origin which isn't listed
+    pub fn check_websocket(&self, headers: &HeaderMap) -> Result<(), AppError> {
+        let (Some(origins), Some(origin)) = (&self.origins, headers.get(header::ORIGIN)) else {
+            return Ok(());
+        };
+        // the origin's host and port, against the Host the handshake was sent to
+        let authority = (origin.to_str().ok())
+            .and_then(|origin| origin.split_once("://"))
+            .map(|(_, authority)| authority);
+        let same_origin = authority.is_some_and(|authority| {
+            headers
+                .get(header::HOST)
+                .is_some_and(|host| host.as_bytes() == authority.as_bytes())
+        });
+        if same_origin || origins.contains(origin) {
+            return Ok(());
+        }
+        Err(AppError::forbidden(format!(
+            "Origin {} may not open the WebSocket, see --cors-origin",
+            String::from_utf8_lossy(origin.as_bytes())
+        )))
+    }
+}
+
+/// CorsLayer takes every OPTIONS request for a preflight, so those which aren't, having no
+/// Access-Control-Request-Method, are passed by it to be captured
+async fn answer_preflights(
+    State(cors): State<CorsLayer>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if request.method() == Method::OPTIONS
+        && !(request.headers()).contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+    {
+        return next.run(request).await;
+    }
+    match cors.layer(next).oneshot(request).await {
+        Ok(response) => response,
+        Err(infallible) => match infallible {},
+    }
+}
+
+fn parse<T: std::str::FromStr>(what: &str, value: &str) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| anyhow!("Invalid CORS {} {:?}", what, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::{
+        body::Body,
+        extract::ConnectInfo,
+        http::{Request, StatusCode},
+        Extension,
+    };
+    use clap::Parser;
+
+    use super::*;
+    use crate::{handler::ingress::capture_route, AppState};
+
+    #[tokio::test]
+    async fn test_cors() {
+        let config = ServerConfig::parse_from([
+            "hydra-server",
+            "--cors-origin",
+            "https://dash.example.com/",
+        ]);
+        let cors = CorsPolicy::from_config(&config).unwrap().unwrap();
+        let state = AppState::new_test().unwrap();
+        let app = cors
+            .apply(Router::new().route("/ingress", capture_route()))
+            .layer(Extension(ConnectInfo(SocketAddr::from((
+                [127, 0, 0, 1],
+                0,
+            )))))
+            .with_state(state.clone());
+        let options = |preflight: bool| {
+            let mut request = Request::builder()
+                .method(Method::OPTIONS)
+                .uri("/ingress")
+                .header(header::HOST, "hydra.example.com")
+                .header(header::ORIGIN, "https://dash.example.com");
+            if preflight {
+                request = request.header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST");
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+        let captured = || state.storage.subtree("ingress").unwrap().len();
+
+        // a preflight is answered, and not captured
+        let response = options(true).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let allowed = response.headers();
+        assert_eq!(
+            allowed[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://dash.example.com"
+        );
+        assert!(allowed[header::ACCESS_CONTROL_ALLOW_METHODS]
+            .to_str()
+            .unwrap()
+            .contains("POST"));
+        assert_eq!(captured(), 0);
+        // but other OPTIONS requests still are
+        let response = options(false).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(captured(), 1);
+
+        let handshake = |origin: Option<&'static str>| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::HOST, "hydra.example.com".parse().unwrap());
+            if let Some(origin) = origin {
+                headers.insert(header::ORIGIN, origin.parse().unwrap());
+            }
+            cors.check_websocket(&headers)
+        };
+        assert!(handshake(Some("https://dash.example.com")).is_ok());
+        assert!(handshake(Some("https://hydra.example.com")).is_ok());
+        assert!(handshake(None).is_ok());
+        let refused = handshake(Some("https://evil.example.com")).unwrap_err();
+        assert_eq!(refused.kind(), hydra_error::ErrorKind::Forbidden);
+    }
+}
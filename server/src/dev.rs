@@ -0,0 +1,256 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+use axum::{response::Html, routing::get, Router};
+use bytes::Bytes;
+use hydra_proto as proto;
+use tower_http::services::ServeDir;
+
+use crate::{
+    appstate::AppState,
+    config::{DevConfig, ServerConfig},
+    handler::ingress::INGRESS_PREFIX,
+};
+
+/// How often the watcher checks the proto and web sources for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Directories (relative to the repo root) whose changes trigger a rebuild of the wasm client
+const WATCHED_DIRS: [&str; 2] = ["proto/src", "web/src"];
+
+const INDEX_HTML: &str = include_str!("dev/index.html");
+
+/// State for `hydra-server dev`, which collapses the usual multi-step startup (server, database,
+/// wasm-pack, sample data) into a single command
+pub struct DevSession {
+    config: DevConfig,
+    root: PathBuf,
+    db_path: PathBuf,
+    // whether we created the database, and so should delete it on exit
+    temporary: bool,
+}
+
+impl DevSession {
+    /// Point the server at a throwaway database unless one was given with --db-path
+    pub fn new(dev_config: DevConfig, config: &mut ServerConfig) -> Result<Self> {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .parent()
+            .ok_or_else(|| anyhow!("Failed to find the repository root"))?
+            .to_path_buf();
+
+        let temporary = config.db_path.is_none();
+        let db_path = config.db_path.get_or_insert_with(|| {
+            std::env::temp_dir().join(format!("hydra-dev-{}", ulid::Ulid::new()))
+        });
+
+        Ok(Self {
+            config: dev_config,
+            root,
+            db_path: db_path.clone(),
+            temporary,
+        })
+    }
+
+    /// Fill the ingress tree with sample logs, unless it already has some
+    pub fn seed(&self, state: &AppState) -> Result<usize> {
+        if !state.storage.subtree("ingress")?.is_empty() {
+            return Ok(0);
+        }
+
+        let mut generator = ulid::Generator::new();
+        let now = chrono::Utc::now();
+        let count = self.config.seed;
+        for i in 0..count {
+            // spread the samples over the past few hours, oldest first
+            let date = now - chrono::Duration::minutes(((count - i) * 7) as i64);
+            let event_id = generator
+                .generate_from_datetime(date.into())
+                .map_err(|e| anyhow!("Failed to generate a sample event id: {:?}", e))?;
+            let log = sample_log(i, event_id, date);
+            state.storage.insert(
+                "ingress",
+                format!("{}{}", INGRESS_PREFIX, event_id),
+                state.storage.encode(&log)?,
+            )?;
+        }
+        Ok(count)
+    }
+
+    /// Serve the wasm package at /pkg and a page which loads it at /dev
+    pub fn routes(&self, app: Router<AppState>) -> Router<AppState> {
+        app.route("/dev", get(|| async { Html(INDEX_HTML) }))
+            .nest_service("/pkg", ServeDir::new(self.root.join("web/pkg")))
+    }
+
+    /// Build the wasm client, start watching for changes and print where everything is
+    pub fn start(&self) {
+        println!();
+        println!("Hydra dev server");
+        println!("  database:  {}", self.db_path.display());
+        println!("  websocket: ws://127.0.0.1:9797/ws");
+        println!("  ingress:   curl -X POST http://127.0.0.1:9797/ingress -d 'hello'");
+        println!("  client:    http://127.0.0.1:9797/dev");
+        println!();
+
+        if !self.config.no_wasm {
+            let root = self.root.clone();
+            std::thread::spawn(move || {
+                build_wasm(&root);
+                watch(&root);
+            });
+        }
+    }
+
+    /// Clean up once the server has shut down, deleting the database if we created it
+    pub fn finish(self) {
+        if self.temporary {
+            println!("Removing {}", self.db_path.display());
+            let _ = std::fs::remove_dir_all(&self.db_path);
+        }
+    }
+}
+
+fn sample_log(
+    i: usize,
+    event_id: ulid::Ulid,
+    date: chrono::DateTime<chrono::Utc>,
+) -> proto::IngressLog {
+    let (method, path, content_type, body) = match i % 3 {
+        0 => (
+            "POST",
+            "hooks/github",
+            "application/json",
+            format!(r#"{{"action":"opened","number":{},"sample":true}}"#, i),
+        ),
+        1 => (
+            "POST",
+            "hooks/form",
+            "application/x-www-form-urlencoded",
+            format!("name=sample&index={}", i),
+        ),
+        _ => ("GET", "ping", "text/plain", String::new()),
+    };
+
+    proto::IngressLog {
+        event_id,
+        date,
+        remote_addr: None,
+        method: method.to_string(),
+        host: "127.0.0.1:9797".to_string(),
+        path: path.to_string(),
+        query: HashMap::from([("sample".to_string(), i.to_string())]),
+        headers: HashMap::from([("content-type".to_string(), content_type.to_string())]),
+        body: Bytes::from(body),
+        trace_id: None,
+    }
+}
+
+/// Build web/pkg with wasm-pack, reporting rather than failing if it isn't installed
+fn build_wasm(root: &Path) {
+    println!("Building the wasm client...");
+    let status = Command::new("wasm-pack")
+        .args(["build", "--target", "web", "--dev"])
+        .current_dir(root.join("web"))
+        .status();
+    match status {
+        Ok(status) if status.success() => {
+            println!("Wasm client built, see http://127.0.0.1:9797/dev")
+        }
+        Ok(status) => println!(
+            "wasm-pack failed ({}), waiting for changes to retry",
+            status
+        ),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => println!(
+            "wasm-pack is not installed, so the client at /dev won't work. \
+             See https://rustwasm.github.io/wasm-pack/installer/"
+        ),
+        Err(e) => println!("Failed to run wasm-pack: {:?}", e),
+    }
+}
+
+/// Poll the watched directories, rebuilding the wasm client whenever something in them changes
+fn watch(root: &Path) {
+    let mut last = latest_modification(root);
+    loop {
+        std::thread::sleep(WATCH_INTERVAL);
+        let current = latest_modification(root);
+        if current == last {
+            continue;
+        }
+        if current[0] != last[0] {
+            println!(
+                "proto changed. Rebuilding the client; restart the dev server to pick it up here too"
+            );
+        } else {
+            println!("web changed. Rebuilding the client");
+        }
+        build_wasm(root);
+        // anything modified during the build is picked up on the next pass
+        last = current;
+    }
+}
+
+fn latest_modification(root: &Path) -> Vec<Option<SystemTime>> {
+    WATCHED_DIRS
+        .iter()
+        .map(|dir| newest_in(&root.join(dir)))
+        .collect()
+}
+
+fn newest_in(path: &Path) -> Option<SystemTime> {
+    let metadata = std::fs::metadata(path).ok()?;
+    if !metadata.is_dir() {
+        return metadata.modified().ok();
+    }
+    std::fs::read_dir(path)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| newest_in(&entry.path()))
+        .chain(metadata.modified().ok())
+        .max()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::versioned;
+    use clap::Parser;
+
+    #[test]
+    fn test_seed() {
+        let mut config = ServerConfig::parse_from(["hydra-server"]);
+        let dev = DevSession::new(
+            DevConfig {
+                seed: 5,
+                no_wasm: true,
+            },
+            &mut config,
+        )
+        .unwrap();
+        assert!(dev.temporary);
+        assert_eq!(config.db_path.as_ref(), Some(&dev.db_path));
+
+        let state = AppState::new(&config).unwrap();
+        assert_eq!(dev.seed(&state).unwrap(), 5);
+        // a database which already has data is left alone
+        assert_eq!(dev.seed(&state).unwrap(), 0);
+
+        let logs: Vec<proto::IngressLog> = state
+            .storage
+            .subtree("ingress")
+            .unwrap()
+            .iter()
+            .map(|item| versioned::decode(&item.unwrap().1).unwrap())
+            .collect();
+        assert_eq!(logs.len(), 5);
+        assert!(logs.windows(2).all(|pair| pair[0].date < pair[1].date));
+
+        drop(state);
+        std::fs::remove_dir_all(&dev.db_path).unwrap();
+    }
+}
@@ -0,0 +1,241 @@
+//! How durable captures and imports are by the time they're acknowledged, set per ingress tree.
+//!
+//! - `default` writes before acknowledging, and leaves sled to flush to disk every
+//!   --flush-every-ms, so a crash of the machine can lose the last moments of captures.
+//! - `strict` flushes to disk before acknowledging, so an acknowledged capture survives anything
+//!   short of losing the disk, at the cost of a flush per capture.
+//! - `relaxed` acknowledges as soon as a capture is queued, and writes what's queued in batches
+//!   every RELAXED_WRITE_EVERY, so even the server crashing loses what's queued, and a capture can
+//!   take that long to show up in fetches and subscriptions.
+//!
+//! The shared ingress tree, and every source which doesn't set its own `durability` in the
+//! sources file, take --durability's mode.
+
+use std::{sync::Mutex, time::Duration};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{error::AppError, storage::StorageEngine, AppState};
+
+/// How often relaxed writes are written out
+pub const RELAXED_WRITE_EVERY: Duration = Duration::from_millis(50);
+
+/// Relaxed writes beyond this many are written straight away, along with those queued before
+/// them, rather than queued, so a burst can't grow the queue without limit
+const MAX_QUEUED: usize = 10_000;
+
+#[derive(clap::ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Durability {
+    #[default]
+    Default,
+    Strict,
+    Relaxed,
+}
+
+struct QueuedWrite {
+    tree: String,
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+/// Writes captured and imported records as their tree's durability asks
+#[derive(Default)]
+pub struct DurableWriter {
+    queued: Mutex<Vec<QueuedWrite>>,
+}
+
+impl DurableWriter {
+    /// Write records to a tree, or queue them if it's relaxed. Once this returns they can be
+    /// acknowledged, after `settle` for a strict tree.
+    pub fn write(
+        &self,
+        storage: &StorageEngine,
+        durability: Durability,
+        tree: &str,
+        records: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> Result<()> {
+        let records = records
+            .into_iter()
+            .map(|(key, value)| (tree, key, value))
+            .collect();
+        self.write_together(storage, durability, records)
+    }
+
+    /// Write records to any number of trees, eg. a capture and its DAG event, all together or
+    /// not at all, or queue them if it's relaxed, to be written out together with everything else
+    /// queued
+    pub fn write_together(
+        &self,
+        storage: &StorageEngine,
+        durability: Durability,
+        records: Vec<(&str, Vec<u8>, Vec<u8>)>,
+    ) -> Result<()> {
+        let records: Vec<_> = records
+            .into_iter()
+            .map(|(tree, key, value)| QueuedWrite {
+                tree: tree.to_string(),
+                key,
+                value,
+            })
+            .collect();
+        if durability == Durability::Relaxed {
+            let mut queued = self.queued.lock().unwrap();
+            if queued.len() + records.len() <= MAX_QUEUED {
+                queued.extend(records);
+                return Ok(());
+            }
+        }
+        // anything queued goes first, so subscribers see records in the order they arrived
+        self.write_queued(storage)?;
+        write_all(storage, records)
+    }
+
+    /// Wait for what's been written to reach the disk, if the tree is strict
+    pub async fn settle(&self, storage: &StorageEngine, durability: Durability) -> Result<()> {
+        if durability == Durability::Strict {
+            storage.db.flush_async().await?;
+        }
+        Ok(())
+    }
+
+    /// Write out every queued write, returning how many there were
+    pub fn write_queued(&self, storage: &StorageEngine) -> Result<usize> {
+        let queued = std::mem::take(&mut *self.queued.lock().unwrap());
+        let count = queued.len();
+        write_all(storage, queued)?;
+        Ok(count)
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.lock().unwrap().len()
+    }
+}
+
+/// Write records in one transaction, so a crash or failure part way leaves none of them written
+fn write_all(storage: &StorageEngine, records: Vec<QueuedWrite>) -> Result<()> {
+    match &records[..] {
+        [] => Ok(()),
+        [write] => {
+            storage.insert(&write.tree, &write.key, &write.value[..])?;
+            Ok(())
+        }
+        _ => {
+            let mut trees: Vec<&str> = records.iter().map(|write| write.tree.as_str()).collect();
+            trees.sort();
+            trees.dedup();
+            storage
+                .transaction(&trees, |tx| {
+                    for write in &records {
+                        tx.insert(&write.tree, &write.key, &write.value[..])?;
+                    }
+                    Ok(())
+                })
+                .map_err(AppError::into_anyhow)
+        }
+    }
+}
+
+/// Write out relaxed writes every RELAXED_WRITE_EVERY for as long as the server runs, if any tree
+/// is relaxed. The server writes out what's left itself as it shuts down.
+pub fn spawn_relaxed_writes(state: AppState) {
+    if !state.sources.any_relaxed() {
+        return;
+    }
+    println!("Writing relaxed captures every {:?}", RELAXED_WRITE_EVERY);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RELAXED_WRITE_EVERY);
+        loop {
+            interval.tick().await;
+            let write_state = state.clone();
+            // sled calls block, so keep them off the async runtime
+            let written = tokio::task::spawn_blocking(move || {
+                write_state.writer.write_queued(&write_state.storage)
+            })
+            .await;
+            match written {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => println!("Failed to write relaxed captures: {:?}", e),
+                Err(e) => println!("Relaxed write task failed: {:?}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_durable_writer() {
+        let storage = StorageEngine::new_test().unwrap();
+        let writer = DurableWriter::default();
+        let record = |n: u16| (n.to_be_bytes().to_vec(), vec![1]);
+
+        writer
+            .write(&storage, Durability::Strict, "ingress", vec![record(0)])
+            .unwrap();
+        writer.settle(&storage, Durability::Strict).await.unwrap();
+        assert!(storage.get("ingress", record(0).0).unwrap().is_some());
+
+        // relaxed writes are only queued
+        writer
+            .write(
+                &storage,
+                Durability::Relaxed,
+                "ingress/github",
+                vec![record(1)],
+            )
+            .unwrap();
+        assert_eq!(writer.queued(), 1);
+        assert!(storage
+            .get("ingress/github", record(1).0)
+            .unwrap()
+            .is_none());
+        assert_eq!(writer.write_queued(&storage).unwrap(), 1);
+        assert!(storage
+            .get("ingress/github", record(1).0)
+            .unwrap()
+            .is_some());
+
+        // until there are too many queued, when they're all written straight away
+        let burst: Vec<_> = (2..MAX_QUEUED as u16 + 3).map(record).collect();
+        writer
+            .write(
+                &storage,
+                Durability::Relaxed,
+                "ingress",
+                burst[..2].to_vec(),
+            )
+            .unwrap();
+        writer
+            .write(
+                &storage,
+                Durability::Relaxed,
+                "ingress",
+                burst[2..].to_vec(),
+            )
+            .unwrap();
+        assert_eq!(writer.queued(), 0);
+        assert_eq!(storage.subtree("ingress").unwrap().len(), MAX_QUEUED + 2);
+
+        // a default write writes what was queued before it first
+        writer
+            .write(
+                &storage,
+                Durability::Relaxed,
+                "ingress/stripe",
+                vec![record(0)],
+            )
+            .unwrap();
+        writer
+            .write(&storage, Durability::Default, "ingress", vec![record(1)])
+            .unwrap();
+        assert_eq!(writer.queued(), 0);
+        assert!(storage
+            .get("ingress/stripe", record(0).0)
+            .unwrap()
+            .is_some());
+    }
+}
@@ -0,0 +1,219 @@
+use std::sync::{
+    atomic::{AtomicU8, Ordering},
+    Arc,
+};
+
+use anyhow::Result;
+use axum::extract::ws::Message;
+use hydra_proto as proto;
+
+use crate::{
+    codec::{Codec, CodecKind},
+    compression::{self, COMPRESS_ABOVE},
+};
+
+// in the order they're numbered in ConnectionEncoding
+const ENCODINGS: [proto::Encoding; 4] = [
+    proto::Encoding::Bincode,
+    proto::Encoding::Json,
+    proto::Encoding::Cbor,
+    proto::Encoding::MessagePack,
+];
+
+// in the order they're numbered in ConnectionEncoding, after 0 for none
+const COMPRESSIONS: [proto::Compression; 2] =
+    [proto::Compression::Deflate, proto::Compression::Zstd];
+
+/// The encoding and compression a connection has negotiated, shared by the tasks reading and
+/// writing its socket. See proto::Encoding and proto::Compression for how they are negotiated.
+#[derive(Clone, Default)]
+pub struct ConnectionEncoding {
+    // the index of the encoding in ENCODINGS
+    current: Arc<AtomicU8>,
+    // 0 for none, otherwise one more than the index of the compression in COMPRESSIONS
+    compression: Arc<AtomicU8>,
+}
+
+impl ConnectionEncoding {
+    pub fn get(&self) -> proto::Encoding {
+        ENCODINGS[self.current.load(Ordering::Relaxed) as usize]
+    }
+
+    pub fn set(&self, encoding: proto::Encoding) {
+        let index = ENCODINGS.iter().position(|known| *known == encoding);
+        self.current
+            .store(index.unwrap_or_default() as u8, Ordering::Relaxed);
+    }
+
+    pub fn compression(&self) -> Option<proto::Compression> {
+        match self.compression.load(Ordering::Relaxed) {
+            0 => None,
+            index => COMPRESSIONS.get(index as usize - 1).copied(),
+        }
+    }
+
+    /// Compress large Response payloads from now on, or stop if None
+    pub fn set_compression(&self, compression: Option<proto::Compression>) {
+        let index = compression
+            .and_then(|compression| COMPRESSIONS.iter().position(|known| *known == compression))
+            .map_or(0, |index| index + 1);
+        self.compression.store(index as u8, Ordering::Relaxed);
+    }
+
+    /// Encode a message as a frame in the connection's current encoding, with a Response's
+    /// payload compressed if the connection has asked for that and it's large
+    pub fn encode(&self, message: &proto::Message) -> Result<Message> {
+        let compressed;
+        let message = match (self.compression(), message) {
+            (Some(compression), proto::Message::Response(response)) => {
+                match self.compress(compression, &response.payload)? {
+                    Some(payload) => {
+                        compressed = proto::Message::Response(proto::Response {
+                            request_id: response.request_id,
+                            sequence: response.sequence,
+                            trace_id: response.trace_id,
+                            payload,
+                        });
+                        &compressed
+                    }
+                    None => message,
+                }
+            }
+            _ => message,
+        };
+        Ok(match self.get() {
+            proto::Encoding::Json => Message::Text(serde_json::to_string(message)?),
+            encoding => Message::Binary(CodecKind::of_frames(encoding).encode(message)?),
+        })
+    }
+
+    /// The payload compressed, if it's large enough to be worth it
+    fn compress(
+        &self,
+        compression: proto::Compression,
+        payload: &proto::ResponsePayload,
+    ) -> Result<Option<proto::ResponsePayload>> {
+        let encoded = match self.get() {
+            proto::Encoding::Json => serde_json::to_vec(payload)?,
+            encoding => CodecKind::of_frames(encoding).encode(payload)?,
+        };
+        if encoded.len() <= COMPRESS_ABOVE {
+            return Ok(None);
+        }
+        let compressed = compression::compress(compression, &encoded)?;
+        Ok(Some(proto::ResponsePayload::Compressed(
+            proto::CompressedPayload {
+                compression,
+                payload: compressed.into(),
+            },
+        )))
+    }
+
+    /// Decode a binary frame: CBOR or MessagePack if the connection has switched to them, and
+    /// otherwise bincode, whether it's switched to JSON or not
+    pub fn decode(&self, frame: &[u8]) -> Result<proto::Message> {
+        CodecKind::of_frames(self.get()).decode(frame)
+    }
+}
+
+/// Decode a JSON text frame
+pub fn decode_json(text: &str) -> Result<proto::Message> {
+    Ok(serde_json::from_str(text)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiated_encoding() {
+        let encoding = ConnectionEncoding::default();
+        let message = proto::Message::Request(proto::Request {
+            id: 3,
+            idempotency_key: None,
+            trace_id: None,
+            payload: proto::RequestPayload::Cancel(proto::CancelRequest { request_id: 2 }),
+        });
+        assert!(matches!(encoding.encode(&message), Ok(Message::Binary(_))));
+
+        encoding.set(proto::Encoding::from_negotiation("encoding:json").unwrap());
+        let Ok(Message::Text(text)) = encoding.encode(&message) else {
+            panic!("expected a JSON text frame");
+        };
+        assert!(matches!(
+            decode_json(&text),
+            Ok(proto::Message::Request(proto::Request { id: 3, .. }))
+        ));
+
+        assert!(proto::Encoding::from_negotiation("hello").is_none());
+        encoding.set(proto::Encoding::from_negotiation("encoding:msgpack").unwrap());
+        let Ok(Message::Binary(frame)) = encoding.encode(&message) else {
+            panic!("expected a MessagePack binary frame");
+        };
+        assert!(bincode::deserialize::<proto::Message>(&frame).is_err());
+        assert!(matches!(
+            encoding.decode(&frame),
+            Ok(proto::Message::Request(proto::Request { id: 3, .. }))
+        ));
+
+        encoding.set(proto::Encoding::from_negotiation("encoding:bincode\n").unwrap());
+        assert_eq!(encoding.get(), proto::Encoding::Bincode);
+    }
+
+    #[test]
+    fn test_compressed_payloads() {
+        let encoding = ConnectionEncoding::default();
+        let response = |value: &[u8]| {
+            proto::Message::Response(proto::Response {
+                request_id: 4,
+                sequence: 0,
+                trace_id: None,
+                payload: proto::ResponsePayload::GetKv(proto::GetKvResponse {
+                    value: Some(value.to_vec()),
+                }),
+            })
+        };
+        let large = response(&[7; 64 * 1024]);
+        let Ok(Message::Binary(uncompressed)) = encoding.encode(&large) else {
+            panic!("expected a bincode binary frame");
+        };
+
+        let negotiated = proto::Compression::from_negotiation("compression:deflate").unwrap();
+        encoding.set_compression(negotiated);
+        assert_eq!(encoding.compression(), Some(proto::Compression::Deflate));
+        let Ok(Message::Binary(frame)) = encoding.encode(&large) else {
+            panic!("expected a bincode binary frame");
+        };
+        assert!(frame.len() < uncompressed.len() / 10);
+        let Ok(proto::Message::Response(proto::Response {
+            request_id: 4,
+            payload: proto::ResponsePayload::Compressed(compressed),
+            ..
+        })) = encoding.decode(&frame)
+        else {
+            panic!("expected a compressed response");
+        };
+        let payload = compression::decompress(compressed.compression, &compressed.payload).unwrap();
+        assert!(matches!(
+            bincode::deserialize(&payload),
+            Ok(proto::ResponsePayload::GetKv(proto::GetKvResponse { value: Some(value) }))
+                if value.len() == 64 * 1024
+        ));
+
+        // small payloads, and messages other than Responses, are left alone
+        let Ok(Message::Binary(frame)) = encoding.encode(&response(b"small")) else {
+            panic!("expected a bincode binary frame");
+        };
+        assert!(matches!(
+            encoding.decode(&frame),
+            Ok(proto::Message::Response(proto::Response {
+                payload: proto::ResponsePayload::GetKv(_),
+                ..
+            }))
+        ));
+
+        encoding.set_compression(proto::Compression::from_negotiation("compression:none").unwrap());
+        assert_eq!(encoding.compression(), None);
+        assert_eq!(encoding.encode(&large).unwrap().into_data(), uncompressed);
+    }
+}
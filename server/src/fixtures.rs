@@ -0,0 +1,117 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use hydra_proto as proto;
+use sha2::{Digest, Sha256};
+
+use crate::{error::AppError, handler, handler::ingress::INGRESS_PREFIX, AppState};
+
+/// Where the hashes of fixture files which have been loaded are kept
+const META_TREE: &str = "meta";
+const FIXTURE_KEY_PREFIX: &str = "fixture|";
+
+/// Load every `<collection>.ndjson` file in a directory, one record per line, skipping files
+/// which have been loaded before. Files are recognised by the hash of their contents, so editing
+/// one loads it again in full.
+///
+/// `ingress.ndjson` holds ingress logs, as JSON IngressLogs, which keep their event ids. Any other
+/// file is a record collection of that name, with each line stored as a record's value.
+pub fn load_fixtures(state: &AppState, dir: &Path) -> Result<usize> {
+    let mut paths: Vec<_> = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read fixtures from {}", dir.display()))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    paths.retain(|path| path.extension().is_some_and(|ext| ext == "ndjson"));
+    paths.sort();
+
+    let mut loaded = 0;
+    for path in paths {
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read fixture {}", path.display()))?;
+        let hash = hex::encode(Sha256::digest(&contents));
+        let key = format!("{}{}", FIXTURE_KEY_PREFIX, hash);
+        if state.storage.subtree(META_TREE)?.contains_key(&key)? {
+            continue;
+        }
+
+        let collection = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_else(|| anyhow!("Invalid fixture file name {}", path.display()))?;
+        let count = load_file(state, collection, &contents)
+            .with_context(|| format!("Failed to load fixture {}", path.display()))?;
+        // only once the whole file is in, so a failed load is retried on the next start
+        state
+            .storage
+            .insert(META_TREE, key, path.display().to_string().into_bytes())?;
+        println!("Loaded {} records from {}", count, path.display());
+        loaded += count;
+    }
+    Ok(loaded)
+}
+
+fn load_file(state: &AppState, collection: &str, contents: &str) -> Result<usize> {
+    let mut count = 0;
+    for (number, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line_context = || format!("line {}", number + 1);
+        if collection == "ingress" {
+            let log: proto::IngressLog = serde_json::from_str(line).with_context(line_context)?;
+            state.storage.insert(
+                "ingress",
+                format!("{}{}", INGRESS_PREFIX, log.event_id),
+                state.storage.encode(&log)?,
+            )?;
+        } else {
+            let value: serde_json::Value = serde_json::from_str(line).with_context(line_context)?;
+            let request = proto::CreateRecordRequest {
+                collection: collection.to_string(),
+                value: serde_json::to_vec(&value)?,
+            };
+            handler::record::create_record(request, state).map_err(AppError::into_anyhow)?;
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_fixtures() {
+        let dir = std::env::temp_dir().join(format!("hydra-fixtures-{}", ulid::Ulid::new()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("ingress.ndjson"),
+            r#"{"event_id":"01HZX3K6Y0QJ7V8T4R2N5M9B1C","date":"2024-06-01T12:00:00Z","remote_addr":null,"method":"POST","host":"localhost","path":"hooks/github","query":{},"headers":{},"body":"hello"}"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("notes.ndjson"),
+            "{\"title\":\"one\"}\n\n{\"title\":\"two\"}\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("README.md"), "not a fixture").unwrap();
+
+        let state = AppState::new_test().unwrap();
+        assert_eq!(load_fixtures(&state, &dir).unwrap(), 3);
+        // loading again changes nothing
+        assert_eq!(load_fixtures(&state, &dir).unwrap(), 0);
+        assert_eq!(state.storage.subtree("ingress").unwrap().len(), 1);
+        assert_eq!(state.storage.subtree("records/notes").unwrap().len(), 2);
+
+        // an edited file is loaded again
+        std::fs::write(dir.join("notes.ndjson"), "{\"title\":\"three\"}\n").unwrap();
+        assert_eq!(load_fixtures(&state, &dir).unwrap(), 1);
+
+        std::fs::write(dir.join("bad.ndjson"), "{\"title\":").unwrap();
+        let err = load_fixtures(&state, &dir).unwrap_err();
+        assert!(format!("{:#}", err).contains("line 1"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,264 @@
+//! A gRPC service, served alongside the WebSocket API with --grpc-listen, for backend services in
+//! other languages which would rather generate a client from hydra.proto than speak the WebSocket
+//! protocol. It has three RPCs:
+//!
+//! - Capture, which captures a request as if it had been sent to /ingress/<path>
+//! - FetchIngressLogs, which pages through logs as FetchIngressLogsRequest does
+//! - Subscribe, which streams each log captured in a tree from then on
+//!
+//! The service is generated by build.rs, and its messages, in messages.rs, are converted to and
+//! from the proto crate's types, so each RPC is handled as its WebSocket counterpart is.
+
+pub mod messages;
+
+use std::{net::SocketAddr, pin::Pin};
+
+use anyhow::anyhow;
+use axum::http::{header, HeaderMap, Method};
+use futures_util::{Stream, StreamExt};
+use hydra_proto as proto;
+use tokio::task::JoinHandle;
+use tonic::{
+    transport::{server::TcpIncoming, Server},
+    Request, Response, Status,
+};
+
+use self::messages::{
+    CaptureRequest, CaptureResponse, FetchIngressLogsRequest, FetchIngressLogsResponse, IngressLog,
+    SubscribeRequest,
+};
+use crate::{
+    cancel::CancelToken,
+    error::AppError,
+    handler::{
+        batch::{capture_all, Capture},
+        ingress::{destination, fetch_ingress_logs, log_tree},
+        stream::captured_logs,
+    },
+    trace::{self, TRACE_HEADER},
+    worker::JobClass,
+    AppState,
+};
+
+mod generated {
+    include!(concat!(env!("OUT_DIR"), "/hydra.v1.Ingress.rs"));
+}
+
+use generated::ingress_server::{Ingress, IngressServer};
+
+pub struct IngressService {
+    state: AppState,
+}
+
+impl IngressService {
+    pub fn new(state: AppState) -> Self {
+        Self { state }
+    }
+}
+
+/// Serve the service on `addr` until the server shuts down
+pub fn spawn(addr: SocketAddr, state: AppState) -> anyhow::Result<JoinHandle<()>> {
+    // bound now, so a port which is taken stops startup
+    let incoming = TcpIncoming::new(addr, true, None)
+        .map_err(|e| anyhow!("Could not listen for gRPC on {}: {}", addr, e))?;
+    let mut shutdown = state.shutdown.subscribe();
+    println!("Serving gRPC on {}", addr);
+    Ok(tokio::spawn(async move {
+        let result = Server::builder()
+            .add_service(IngressServer::new(IngressService::new(state)))
+            .serve_with_incoming_shutdown(incoming, async move {
+                drop(shutdown.wait_for(|shutdown| *shutdown).await)
+            })
+            .await;
+        if let Err(e) = result {
+            println!("gRPC server failed: {:?}", e);
+        }
+    }))
+}
+
+type LogStream = Pin<Box<dyn Stream<Item = Result<IngressLog, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Ingress for IngressService {
+    async fn capture(
+        &self,
+        request: Request<CaptureRequest>,
+    ) -> Result<Response<CaptureResponse>, Status> {
+        let remote_addr = request.remote_addr();
+        // as an HTTP capture takes it from its header
+        let trace_id = (request.metadata().get(TRACE_HEADER))
+            .and_then(|value| value.to_str().ok()?.parse().ok())
+            .unwrap_or_else(trace::new_id);
+        // where destination looks for the source's token on a single capture
+        let mut authorization = HeaderMap::new();
+        if let Some(value) = (request.metadata().get("authorization"))
+            .and_then(|value| value.to_str().ok()?.parse().ok())
+        {
+            authorization.insert(header::AUTHORIZATION, value);
+        }
+        let request = request.into_inner();
+        let mut token = (!request.token.is_empty())
+            .then(|| ("token".to_string(), request.token))
+            .into_iter()
+            .collect();
+        let (tree, path) = destination(
+            &self.state.sources,
+            request.path,
+            &mut authorization,
+            &mut token,
+        )?;
+        let method = match request.method.as_str() {
+            "" => "POST".to_string(),
+            method => Method::from_bytes(method.as_bytes())
+                .map_err(|_| AppError::invalid_field("method", "Not an HTTP method"))?
+                .to_string(),
+        };
+        let capture = Capture {
+            tree,
+            method,
+            host: request.host,
+            path,
+            query: request.query,
+            headers: request.headers,
+            body: request.body,
+        };
+        let captured = capture_all(&self.state, remote_addr, trace_id, &[capture])
+            .await?
+            .pop()
+            .ok_or_else(|| anyhow!("Nothing was captured"))
+            .map_err(AppError::from)?;
+        let mut response = Response::new(CaptureResponse {
+            event_id: captured.event_id.to_string(),
+            dag_event: captured.dag_event,
+        });
+        if let Ok(value) = trace_id.to_string().parse() {
+            response.metadata_mut().insert(TRACE_HEADER, value);
+        }
+        Ok(response)
+    }
+
+    async fn fetch_ingress_logs(
+        &self,
+        request: Request<FetchIngressLogsRequest>,
+    ) -> Result<Response<FetchIngressLogsResponse>, Status> {
+        let request = proto::FetchIngressLogsRequest::try_from(request.into_inner())?;
+        // the query stops if the client gives up on it
+        let cancel = CancelToken::new();
+        let _cancel_on_drop = cancel.drop_guard();
+        let state = self.state.clone();
+        let response = self
+            .state
+            .workers
+            .try_run(JobClass::Query, move || {
+                fetch_ingress_logs(request, &state, cancel)
+            })
+            .await?;
+        Ok(Response::new(response.into()))
+    }
+
+    type SubscribeStream = LogStream;
+
+    async fn subscribe(
+        &self,
+        request: Request<SubscribeRequest>,
+    ) -> Result<Response<LogStream>, Status> {
+        let source = request.into_inner().source;
+        let tree = log_tree(&self.state, (!source.is_empty()).then_some(&source[..]))?;
+        // tonic streams carry Status, however large it is
+        #[allow(clippy::result_large_err)]
+        let logs = captured_logs(&self.state, tree).map(|captured| match captured {
+            Ok(log) => Ok(IngressLog::from(log)),
+            // the client can't know what it missed, so it's told to fetch it
+            Err(missed) => Err(Status::data_loss(format!(
+                "Fell {} writes behind, so logs may have been missed. Fetch them and subscribe \
+                 again.",
+                missed
+            ))),
+        });
+        Ok(Response::new(Box::pin(logs)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sources::Sources;
+
+    #[tokio::test]
+    async fn test_grpc_service() {
+        let sources = serde_json::from_value(serde_json::json!([
+            { "id": "github", "token": "t0ken" },
+        ]))
+        .unwrap();
+        let state = AppState::new_test_with_sources(Sources::new(sources).unwrap()).unwrap();
+        let service = IngressService::new(state.clone());
+        let capture = |path: &str, token: &str| CaptureRequest {
+            path: path.to_string(),
+            host: "grpc.example.com".to_string(),
+            body: bytes::Bytes::from_static(b"{}"),
+            token: token.to_string(),
+            ..Default::default()
+        };
+
+        let subscribed = service
+            .subscribe(Request::new(SubscribeRequest {
+                source: "github".to_string(),
+            }))
+            .await
+            .unwrap();
+        let mut logs = subscribed.into_inner();
+
+        let shared = service
+            .capture(Request::new(capture("hooks", "")))
+            .await
+            .unwrap()
+            .into_inner();
+        let pushed = service
+            .capture(Request::new(capture("github/push", "t0ken")))
+            .await
+            .unwrap()
+            .into_inner();
+        let refused = service
+            .capture(Request::new(capture("github/push", "")))
+            .await
+            .unwrap_err();
+        assert_eq!(refused.code(), tonic::Code::Unauthenticated);
+        assert_eq!(
+            refused.metadata().get("hydra-error-kind").unwrap(),
+            "Unauthorized"
+        );
+
+        // only the source's log is streamed to its subscriber
+        let streamed = logs.next().await.unwrap().unwrap();
+        assert_eq!(
+            (streamed.event_id.as_str(), streamed.path.as_str()),
+            (pushed.event_id.as_str(), "push")
+        );
+
+        let page = service
+            .fetch_ingress_logs(Request::new(FetchIngressLogsRequest::default()))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(page.items.len(), 1);
+        let log = page.items[0].log.as_ref().unwrap();
+        assert_eq!(
+            (log.event_id.as_str(), log.method.as_str(), &log.body[..]),
+            (shared.event_id.as_str(), "POST", &b"{}"[..])
+        );
+        assert_eq!(page.total_count, Some(1));
+
+        let invalid = FetchIngressLogsRequest {
+            filter: Some(messages::IngressLogFilter {
+                from: "yesterday".to_string(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let invalid = service
+            .fetch_ingress_logs(Request::new(invalid))
+            .await
+            .unwrap_err();
+        assert_eq!(invalid.code(), tonic::Code::InvalidArgument);
+    }
+}
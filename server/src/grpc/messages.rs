@@ -0,0 +1,275 @@
+//! The gRPC service's messages, as hydra.proto declares them, and their conversions to and from
+//! the proto crate's types. Protobuf has no absent strings, so empty ones stand for unset fields.
+
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use hydra_proto as proto;
+
+use crate::error::AppError;
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CaptureRequest {
+    /// Below /ingress, as a webhook would be sent, eg. `github/push` for the github source
+    #[prost(string, tag = "1")]
+    pub path: String,
+    /// POST if empty
+    #[prost(string, tag = "2")]
+    pub method: String,
+    #[prost(string, tag = "3")]
+    pub host: String,
+    #[prost(map = "string, string", tag = "4")]
+    pub query: HashMap<String, String>,
+    #[prost(map = "string, string", tag = "5")]
+    pub headers: HashMap<String, String>,
+    #[prost(bytes = "bytes", tag = "6")]
+    pub body: Bytes,
+    /// The source's token, unless it's given as `authorization: Bearer` metadata
+    #[prost(string, tag = "7")]
+    pub token: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CaptureResponse {
+    #[prost(string, tag = "1")]
+    pub event_id: String,
+    /// The hash of the DAG event chaining the log, in hex
+    #[prost(string, tag = "2")]
+    pub dag_event: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum Direction {
+    NewestFirst = 0,
+    OldestFirst = 1,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum Cursor {
+    #[prost(bytes, tag = "4")]
+    After(Vec<u8>),
+    #[prost(bytes, tag = "5")]
+    Before(Vec<u8>),
+    #[prost(bytes, tag = "6")]
+    StartingWith(Vec<u8>),
+    #[prost(bytes, tag = "7")]
+    EndingWith(Vec<u8>),
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Snapshot {
+    #[prost(string, tag = "1")]
+    pub epoch: String,
+    #[prost(uint64, tag = "2")]
+    pub sequence: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct IngressLogFilter {
+    #[prost(string, tag = "1")]
+    pub method: String,
+    #[prost(string, tag = "2")]
+    pub host: String,
+    #[prost(string, tag = "3")]
+    pub path_prefix: String,
+    /// RFC 3339
+    #[prost(string, tag = "4")]
+    pub from: String,
+    #[prost(string, tag = "5")]
+    pub to: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct FetchIngressLogsRequest {
+    /// The shared ingress tree if empty
+    #[prost(string, tag = "1")]
+    pub source: String,
+    #[prost(enumeration = "Direction", tag = "2")]
+    pub direction: i32,
+    /// DEFAULT_LIMIT if 0
+    #[prost(uint32, tag = "3")]
+    pub limit: u32,
+    /// The first page if unset
+    #[prost(oneof = "Cursor", tags = "4, 5, 6, 7")]
+    pub cursor: Option<Cursor>,
+    #[prost(message, optional, tag = "8")]
+    pub snapshot: Option<Snapshot>,
+    #[prost(message, optional, tag = "9")]
+    pub filter: Option<IngressLogFilter>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct IngressLog {
+    #[prost(string, tag = "1")]
+    pub event_id: String,
+    /// RFC 3339
+    #[prost(string, tag = "2")]
+    pub date: String,
+    #[prost(string, tag = "3")]
+    pub remote_addr: String,
+    #[prost(string, tag = "4")]
+    pub method: String,
+    #[prost(string, tag = "5")]
+    pub host: String,
+    #[prost(string, tag = "6")]
+    pub path: String,
+    #[prost(map = "string, string", tag = "7")]
+    pub query: HashMap<String, String>,
+    #[prost(map = "string, string", tag = "8")]
+    pub headers: HashMap<String, String>,
+    #[prost(bytes = "bytes", tag = "9")]
+    pub body: Bytes,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct IngressLogItem {
+    /// To page on from, in a Cursor
+    #[prost(bytes, tag = "1")]
+    pub key: Vec<u8>,
+    #[prost(message, optional, tag = "2")]
+    pub log: Option<IngressLog>,
+    #[prost(string, repeated, tag = "3")]
+    pub tags: Vec<String>,
+    #[prost(map = "string, string", tag = "4")]
+    pub computed: HashMap<String, String>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct FetchIngressLogsResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub items: Vec<IngressLogItem>,
+    #[prost(bool, tag = "2")]
+    pub has_more_before: bool,
+    #[prost(bool, tag = "3")]
+    pub has_more_after: bool,
+    /// To pass along when fetching adjacent pages
+    #[prost(message, optional, tag = "4")]
+    pub snapshot: Option<Snapshot>,
+    #[prost(uint64, optional, tag = "5")]
+    pub total_count: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SubscribeRequest {
+    /// The shared ingress tree if empty
+    #[prost(string, tag = "1")]
+    pub source: String,
+}
+
+/// The most logs fetched when a request doesn't say
+pub const DEFAULT_LIMIT: usize = 50;
+
+fn set(value: String) -> Option<String> {
+    (!value.is_empty()).then_some(value)
+}
+
+fn date(field: &str, value: String) -> Result<Option<chrono::DateTime<chrono::Utc>>, AppError> {
+    set(value)
+        .map(|value| {
+            chrono::DateTime::parse_from_rfc3339(&value)
+                .map(|date| date.to_utc())
+                .map_err(|e| AppError::invalid_field(field, format!("Not an RFC 3339 date: {}", e)))
+        })
+        .transpose()
+}
+
+impl TryFrom<FetchIngressLogsRequest> for proto::FetchIngressLogsRequest {
+    type Error = AppError;
+
+    fn try_from(request: FetchIngressLogsRequest) -> Result<Self, AppError> {
+        let direction = match request.direction() {
+            Direction::NewestFirst => proto::Direction::Descending,
+            Direction::OldestFirst => proto::Direction::Ascending,
+        };
+        let cursor = match request.cursor {
+            Some(Cursor::After(key)) => proto::PaginatedCursor::After(key),
+            Some(Cursor::Before(key)) => proto::PaginatedCursor::Before(key),
+            Some(Cursor::StartingWith(key)) => proto::PaginatedCursor::StartingWith(key),
+            Some(Cursor::EndingWith(key)) => proto::PaginatedCursor::EndingWith(key),
+            None => proto::PaginatedCursor::StartingWith(Vec::new()),
+        };
+        let snapshot = request
+            .snapshot
+            .map(|snapshot| {
+                Ok::<_, AppError>(proto::SnapshotToken {
+                    epoch: snapshot.epoch.parse().map_err(|_| {
+                        AppError::invalid_field("snapshot", "Not a snapshot this server issued")
+                    })?,
+                    sequence: snapshot.sequence,
+                })
+            })
+            .transpose()?;
+        let filter = request
+            .filter
+            .map(|filter| {
+                Ok::<_, AppError>(proto::IngressLogFilter {
+                    method: set(filter.method),
+                    host: set(filter.host),
+                    path_prefix: set(filter.path_prefix),
+                    from: date("filter.from", filter.from)?,
+                    to: date("filter.to", filter.to)?,
+                })
+            })
+            .transpose()?;
+        Ok(proto::FetchIngressLogsRequest {
+            direction,
+            limit: match request.limit {
+                0 => DEFAULT_LIMIT,
+                limit => limit as usize,
+            },
+            cursor,
+            preview_bytes: None,
+            snapshot,
+            source: set(request.source),
+            filter,
+            thumbnails: false,
+        })
+    }
+}
+
+impl From<proto::SnapshotToken> for Snapshot {
+    fn from(snapshot: proto::SnapshotToken) -> Self {
+        Self {
+            epoch: snapshot.epoch.to_string(),
+            sequence: snapshot.sequence,
+        }
+    }
+}
+
+impl From<proto::IngressLog> for IngressLog {
+    fn from(log: proto::IngressLog) -> Self {
+        Self {
+            event_id: log.event_id.to_string(),
+            date: log.date.to_rfc3339(),
+            remote_addr: log
+                .remote_addr
+                .map_or_else(String::new, |addr| addr.to_string()),
+            method: log.method,
+            host: log.host,
+            path: log.path,
+            query: log.query,
+            headers: log.headers,
+            body: log.body,
+        }
+    }
+}
+
+impl From<proto::FetchIngressLogsResponse> for FetchIngressLogsResponse {
+    fn from(response: proto::FetchIngressLogsResponse) -> Self {
+        Self {
+            items: (response.items.into_iter())
+                .map(|item| IngressLogItem {
+                    key: item.key,
+                    log: Some(item.log.into()),
+                    tags: item.tags,
+                    computed: item.computed.into_iter().collect(),
+                })
+                .collect(),
+            has_more_before: response.has_more_before,
+            has_more_after: response.has_more_after,
+            snapshot: Some(response.snapshot.into()),
+            total_count: response.total_count,
+        }
+    }
+}
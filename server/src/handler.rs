@@ -1,2 +1,12 @@
+pub mod batch;
 pub mod events;
+pub mod export;
+pub mod hello;
+pub mod import;
 pub mod ingress;
+pub mod kv;
+pub mod live;
+pub mod record;
+pub mod removal;
+pub mod similar;
+pub mod stream;
@@ -0,0 +1,386 @@
+//! POST /ingress-batch, for agents which buffer events and flush them periodically, rather than
+//! making a request per event. The body holds many requests, as lines of NDJSON, or as a CBOR
+//! sequence if it's sent as `Content-Type: application/cbor-seq`, and each is captured as if it
+//! had been sent to /ingress/<its path> on its own, with an event id and DAG event of its own. They
+//! are all written together, and the response gives each one's event id, or why it wasn't
+//! captured, in the order they were sent. It isn't under /ingress, where every path is captured.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::SocketAddr,
+};
+
+use axum::{
+    extract::{ConnectInfo, Host, Query, State},
+    http::{header, HeaderMap, Method},
+    Json,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use hydra_proto as proto;
+use proto::dag::{Event, ID};
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use super::{
+    events::EVENTS_TREE,
+    ingress::{destination, forwarded_client, IngressResponse, INGRESS_PREFIX},
+};
+use crate::{durability::Durability, error::AppError, trace::TraceId, AppState};
+
+/// The most requests one batch can hold
+pub const MAX_BATCH_ITEMS: usize = 1000;
+
+/// One request of a batch. Only the path is needed; requests are POSTs to the batch's host
+/// unless they say otherwise.
+#[derive(Deserialize)]
+struct BatchItem {
+    #[serde(default = "post")]
+    method: String,
+    #[serde(default)]
+    path: String,
+    host: Option<String>,
+    #[serde(default)]
+    query: HashMap<String, String>,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    body: Option<BatchBody>,
+    // for binary bodies sent as NDJSON
+    body_base64: Option<String>,
+}
+
+fn post() -> String {
+    Method::POST.to_string()
+}
+
+/// A body as text, as any other JSON value, which is stored as its JSON, or as CBOR bytes
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BatchBody {
+    Text(String),
+    Json(serde_json::Value),
+    Bytes(Bytes),
+}
+
+/// A request which can be captured, and the tree it's captured in
+pub struct Capture {
+    pub tree: String,
+    pub method: String,
+    pub host: String,
+    pub path: String,
+    pub query: HashMap<String, String>,
+    pub headers: HashMap<String, String>,
+    pub body: Bytes,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BatchResponse {
+    pub items: Vec<BatchItemResult>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum BatchItemResult {
+    Captured(IngressResponse),
+    Failed { error: String },
+}
+
+/// POST /ingress-batch
+///
+/// A source's token, given once for the whole batch in a `token` query parameter or an
+/// `Authorization: Bearer` header, lets its requests be captured in the source's tree.
+pub async fn capture_batch(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    Host(host): Host,
+    Query(query): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    TraceId(trace_id): TraceId,
+    body: Bytes,
+) -> Result<Json<BatchResponse>, AppError> {
+    let remote_addr = match state.trust_proxy {
+        true => forwarded_client(&headers).unwrap_or(peer),
+        false => peer,
+    };
+    let cbor = headers
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"application/cbor-seq"));
+    let items = match cbor {
+        true => cbor_items(&body)?,
+        false => ndjson_items(&body),
+    };
+    if items.len() > MAX_BATCH_ITEMS {
+        return Err(AppError::invalid_request(format!(
+            "A batch can hold at most {} requests, not {}",
+            MAX_BATCH_ITEMS,
+            items.len()
+        )));
+    }
+
+    // the batch's token, where destination looks for it on a single capture
+    let token = query.get("token");
+    let authorization: HeaderMap = (headers.get(header::AUTHORIZATION).cloned())
+        .map(|value| (header::AUTHORIZATION, value))
+        .into_iter()
+        .collect();
+    let (mut results, mut captures) = (Vec::new(), Vec::new());
+    for item in items {
+        let capture = item.and_then(|item| {
+            let mut query = HashMap::from_iter(token.map(|t| ("token".to_string(), t.clone())));
+            let (tree, path) = destination(
+                &state.sources,
+                item.path.clone(),
+                &mut authorization.clone(),
+                &mut query,
+            )
+            .map_err(|e| e.to_string())?;
+            prepare(item, tree, path, &host)
+        });
+        match capture {
+            Ok(capture) => {
+                captures.push((results.len(), capture));
+                results.push(None);
+            }
+            Err(error) => results.push(Some(BatchItemResult::Failed { error })),
+        }
+    }
+
+    let (indexes, captures): (Vec<_>, Vec<_>) = captures.into_iter().unzip();
+    let captured = capture_all(&state, Some(remote_addr), trace_id, &captures).await?;
+    for (index, captured) in indexes.into_iter().zip(captured) {
+        results[index] = Some(BatchItemResult::Captured(captured));
+    }
+    println!(
+        "Ingress batch: captured {} of {} requests (trace {})",
+        captures.len(),
+        results.len(),
+        trace_id
+    );
+    Ok(Json(BatchResponse {
+        items: results.into_iter().flatten().collect(),
+    }))
+}
+
+/// Capture requests, each with an event id and DAG event of its own, all written together
+pub async fn capture_all(
+    state: &AppState,
+    remote_addr: Option<SocketAddr>,
+    trace_id: Ulid,
+    captures: &[Capture],
+) -> Result<Vec<IngressResponse>, AppError> {
+    // queued only if every tree written to is relaxed, and flushed if any is strict
+    let durabilities: Vec<_> = (captures.iter())
+        .map(|capture| state.sources.durability_of(&capture.tree))
+        .collect();
+    let durability = [Durability::Strict, Durability::Default, Durability::Relaxed]
+        .into_iter()
+        .find(|durability| durabilities.contains(durability))
+        .unwrap_or_default();
+    let chained = state.dag.append_all(
+        &state.event_ids,
+        captures,
+        |capture, event_id, precursors| {
+            chain(state, remote_addr, trace_id, capture, event_id, precursors)
+        },
+        |writes| {
+            state.writer.write_together(
+                &state.storage,
+                durability,
+                writes.into_iter().flatten().collect(),
+            )
+        },
+    )?;
+    state.writer.settle(&state.storage, durability).await?;
+    Ok(chained
+        .into_iter()
+        .map(|(event_id, event)| IngressResponse {
+            event_id,
+            dag_event: hex::encode(event.id.hash),
+        })
+        .collect())
+}
+
+/// The requests of an NDJSON batch, one per line which isn't blank
+fn ndjson_items(body: &[u8]) -> Vec<Result<BatchItem, String>> {
+    body.split(|&b| b == b'\n')
+        .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+        .map(|line| serde_json::from_slice(line).map_err(|e| e.to_string()))
+        .collect()
+}
+
+/// The requests of a CBOR sequence. An item which isn't a request fails on its own, but one which
+/// isn't CBOR at all fails the batch, as there's no telling where the next one starts.
+fn cbor_items(body: &[u8]) -> Result<Vec<Result<BatchItem, String>>, AppError> {
+    let mut reader = body;
+    let mut items = Vec::new();
+    while !reader.is_empty() {
+        let value: ciborium::Value = ciborium::from_reader(&mut reader).map_err(|e| {
+            AppError::invalid_request(format!("Batch item {} isn't CBOR: {}", items.len(), e))
+        })?;
+        items.push(value.deserialized().map_err(|e| e.to_string()));
+    }
+    Ok(items)
+}
+
+fn prepare(item: BatchItem, tree: String, path: String, host: &str) -> Result<Capture, String> {
+    let method = Method::from_bytes(item.method.as_bytes())
+        .map_err(|_| format!("Invalid method {:?}", item.method))?;
+    let body = match (item.body, item.body_base64) {
+        (Some(_), Some(_)) => return Err("Give body or body_base64, not both".to_string()),
+        (None, Some(encoded)) => STANDARD
+            .decode(encoded)
+            .map_err(|e| format!("Invalid body_base64: {}", e))?
+            .into(),
+        (Some(BatchBody::Text(text)), None) => text.into(),
+        (Some(BatchBody::Json(value)), None) => value.to_string().into(),
+        (Some(BatchBody::Bytes(bytes)), None) => bytes,
+        (None, None) => Bytes::new(),
+    };
+    Ok(Capture {
+        tree,
+        method: method.to_string(),
+        host: item.host.unwrap_or_else(|| host.to_string()),
+        path,
+        query: item.query,
+        headers: item.headers,
+        body,
+    })
+}
+
+type Write<'a> = (&'a str, Vec<u8>, Vec<u8>);
+
+/// The log of a request, and the event chaining it, with the writes storing both
+fn chain<'a>(
+    state: &AppState,
+    remote_addr: Option<SocketAddr>,
+    trace_id: Ulid,
+    capture: &'a Capture,
+    event_id: Ulid,
+    precursors: BTreeSet<ID>,
+) -> anyhow::Result<(Event, Vec<Write<'a>>)> {
+    let log = proto::IngressLog {
+        event_id,
+        date: chrono::Utc::now(),
+        remote_addr,
+        method: capture.method.clone(),
+        host: capture.host.clone(),
+        path: capture.path.clone(),
+        query: capture.query.clone(),
+        headers: capture.headers.clone(),
+        body: capture.body.clone(),
+        trace_id: Some(trace_id),
+    };
+    let encoded = state.storage.encode(&log)?;
+    let event = Event::with_payload(log.date.timestamp(), &encoded, precursors);
+    let writes = vec![
+        (
+            capture.tree.as_str(),
+            format!("{}{}", INGRESS_PREFIX, event_id).into_bytes(),
+            encoded,
+        ),
+        (
+            EVENTS_TREE,
+            event_id.to_bytes().to_vec(),
+            bincode::serialize(&event)?,
+        ),
+    ];
+    Ok((event, writes))
+}
+
+#[cfg(test)]
+mod tests {
+    use ciborium::cbor;
+    use proto::IngressLog;
+
+    use super::*;
+    use crate::{sources::Sources, storage::versioned};
+
+    #[tokio::test]
+    async fn test_capture_batch() {
+        let sources = serde_json::from_value(serde_json::json!([
+            { "id": "github", "token": "t0ken" },
+        ]))
+        .unwrap();
+        let state = AppState::new_test_with_sources(Sources::new(sources).unwrap()).unwrap();
+        let batch = |headers: HeaderMap, token: Option<&str>, body: Vec<u8>| {
+            let query = token.map(|token| ("token".to_string(), token.to_string()));
+            capture_batch(
+                State(state.clone()),
+                ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 9000))),
+                Host("agent.example.com".to_string()),
+                Query(query.into_iter().collect()),
+                headers,
+                TraceId(Ulid::new()),
+                Bytes::from(body),
+            )
+        };
+        let log = |tree: &str, result: &BatchItemResult| -> IngressLog {
+            let BatchItemResult::Captured(captured) = result else {
+                panic!("Not captured");
+            };
+            let key = format!("{}{}", INGRESS_PREFIX, captured.event_id);
+            versioned::decode(&state.storage.get(tree, key).unwrap().unwrap()).unwrap()
+        };
+
+        let ndjson = [
+            r#"{"path": "hooks", "body": "plain text"}"#,
+            r#"{"path": "github/push", "method": "PUT", "body": {"ref": "main"}}"#,
+            "",
+            "not json",
+            r#"{"path": "blob", "body_base64": "AAEC", "headers": {"x-agent": "1"}}"#,
+            r#"{"path": "hooks", "method": "NOT A METHOD"}"#,
+        ]
+        .join("\n");
+        let items = batch(HeaderMap::new(), Some("t0ken"), ndjson.into_bytes())
+            .await
+            .unwrap()
+            .0
+            .items;
+        assert_eq!(items.len(), 5);
+        let plain = log("ingress", &items[0]);
+        assert_eq!(
+            (plain.method.as_str(), plain.host.as_str(), &plain.body[..]),
+            ("POST", "agent.example.com", &b"plain text"[..])
+        );
+        let push = log("ingress/github", &items[1]);
+        assert_eq!(
+            (push.path.as_str(), &push.body[..]),
+            ("push", &br#"{"ref":"main"}"#[..])
+        );
+        assert!(matches!(items[2], BatchItemResult::Failed { .. }));
+        let blob = log("ingress", &items[3]);
+        assert_eq!(
+            (&blob.body[..], blob.headers["x-agent"].as_str()),
+            (&[0, 1, 2][..], "1")
+        );
+        assert!(matches!(items[4], BatchItemResult::Failed { .. }));
+
+        // each request's event builds on the one before, and all are in the DAG
+        let event = |log: &IngressLog| -> Event {
+            let value = state.storage.get(EVENTS_TREE, log.event_id.to_bytes());
+            bincode::deserialize(&value.unwrap().unwrap()).unwrap()
+        };
+        assert!(event(&push).precursors.contains(&event(&plain).id));
+        assert!(event(&blob).precursors.contains(&event(&push).id));
+        assert!(state.dag.contains(&event(&blob).id));
+
+        // a CBOR sequence, whose bodies can be bytes, and without the source's token
+        let mut cbor = Vec::new();
+        for item in [
+            cbor!({ "path" => "bytes", "body" => ciborium::Value::Bytes(vec![0xff]) }),
+            cbor!({ "path" => "github/push" }),
+        ] {
+            ciborium::into_writer(&item.unwrap(), &mut cbor).unwrap();
+        }
+        let headers: HeaderMap = [(
+            header::CONTENT_TYPE,
+            "application/cbor-seq".parse().unwrap(),
+        )]
+        .into_iter()
+        .collect();
+        let items = batch(headers.clone(), None, cbor).await.unwrap().0.items;
+        assert_eq!(&log("ingress", &items[0]).body[..], &[0xff]);
+        assert!(matches!(&items[1], BatchItemResult::Failed { error } if error.contains("token")));
+        assert!(batch(headers, None, b"\xff".to_vec()).await.is_err());
+    }
+}
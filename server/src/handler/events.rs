@@ -1 +1,251 @@
+//! Every captured log is also an event in the DAG, whose hash covers the log as stored and which
+//! names the node's heads at the time as its precursors. The logs so form an append-only chain,
+//! and a log which has been changed or removed since no longer matches its event. Peers exchange
+//! events to keep their DAGs in step, see sync.rs, so the DAG also holds other nodes' events.
 
+use std::{collections::BTreeSet, sync::Mutex};
+
+use anyhow::{anyhow, Result};
+use hydra_proto::{
+    self as proto,
+    dag::{Dag, Event, ID},
+};
+use ulid::Ulid;
+
+use crate::{error::AppError, handler::ingress::EventIds, storage::StorageEngine, AppState};
+
+/// Where the events are kept, in the order they were added: local events under the event id of
+/// the log each covers, and those received from peers under an id of their own
+pub const EVENTS_TREE: &str = "events";
+
+/// The most events sent in answer to one RequestMissing
+pub const MAX_EVENTS_PER_BATCH: usize = 1000;
+
+/// The node's DAG, held in memory and added to the events tree as it grows
+#[derive(Default)]
+pub struct EventLog(Mutex<Dag>);
+
+impl EventLog {
+    /// Read the DAG back from the events tree. Events are stored after their precursors, unless
+    /// those were written with relaxed durability and lost, in which case they're left out.
+    pub fn load(storage: &StorageEngine) -> Result<Self> {
+        let mut dag = Dag::new();
+        for item in storage.subtree(EVENTS_TREE)?.iter() {
+            let (_, value) = item?;
+            let event: Event = bincode::deserialize(&value)?;
+            if let Err(e) = dag.insert(event) {
+                println!("Leaving out a DAG event: {}", e);
+            }
+        }
+        Ok(Self(Mutex::new(dag)))
+    }
+
+    /// The events nothing builds on yet, which the next local event names
+    pub fn heads(&self) -> BTreeSet<ID> {
+        self.0.lock().unwrap().heads().clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    /// What to tell a peer, see proto::AdvertiseHeads
+    pub fn advertise(&self) -> proto::AdvertiseHeads {
+        let dag = self.0.lock().unwrap();
+        proto::AdvertiseHeads {
+            heads: dag.heads().iter().cloned().collect(),
+            locator: dag.locator(),
+        }
+    }
+
+    pub fn contains(&self, id: &ID) -> bool {
+        self.0.lock().unwrap().contains(id)
+    }
+
+    /// Run `f` with the DAG held, so nothing is added to it meanwhile
+    pub fn hold<T>(&self, f: impl FnOnce(&Dag) -> T) -> T {
+        f(&self.0.lock().unwrap())
+    }
+
+    /// See Dag::missing
+    pub fn missing(&self, want: &[ID], have: &[ID], limit: usize) -> Vec<Event> {
+        self.0.lock().unwrap().missing(want, have, limit)
+    }
+
+    /// Chain the next event on to the heads. `make` is given the next event id and the heads,
+    /// and builds and stores the log and its event. The DAG is held until it's done, so events
+    /// are stored in the order they're chained, and a failed write leaves it as it was.
+    pub fn append(
+        &self,
+        ids: &EventIds,
+        make: impl FnOnce(Ulid, BTreeSet<ID>) -> Result<Event>,
+    ) -> Result<(Ulid, Event)> {
+        let mut dag = self.0.lock().unwrap();
+        let event_id = ids.next()?;
+        let event = make(event_id, dag.heads().clone())?;
+        dag.insert(event.clone())
+            .map_err(|e| anyhow!("Could not chain event {}: {}", event, e))?;
+        Ok((event_id, event))
+    }
+
+    /// Chain an event per item on to the heads, each naming the one before as its precursor.
+    /// `make` is given each item with its event id and precursors, and builds its event along with
+    /// what's to be written for it, which `write` then stores all at once. The DAG is held until
+    /// it's done, and left as it was if anything fails.
+    pub fn append_all<T, W>(
+        &self,
+        ids: &EventIds,
+        items: impl IntoIterator<Item = T>,
+        mut make: impl FnMut(T, Ulid, BTreeSet<ID>) -> Result<(Event, W)>,
+        write: impl FnOnce(Vec<W>) -> Result<()>,
+    ) -> Result<Vec<(Ulid, Event)>> {
+        let mut dag = self.0.lock().unwrap();
+        let mut precursors = dag.heads().clone();
+        let (mut chained, mut writes) = (Vec::new(), Vec::new());
+        for item in items {
+            let event_id = ids.next()?;
+            let (event, item_writes) = make(item, event_id, precursors)?;
+            precursors = BTreeSet::from([event.id.clone()]);
+            chained.push((event_id, event));
+            writes.push(item_writes);
+        }
+        write(writes)?;
+        for (_, event) in &chained {
+            dag.insert(event.clone())
+                .map_err(|e| anyhow!("Could not chain event {}: {}", event, e))?;
+        }
+        Ok(chained)
+    }
+
+    /// Add events from a peer, each after its precursors, returning how many were new. Stops at
+    /// the first whose precursors are missing, keeping those before it.
+    pub fn receive(
+        &self,
+        storage: &StorageEngine,
+        ids: &EventIds,
+        events: Vec<Event>,
+    ) -> Result<usize, AppError> {
+        let mut dag = self.0.lock().unwrap();
+        let mut added = 0;
+        for event in events {
+            if dag.contains(&event.id) {
+                continue;
+            }
+            if let Some(missing) = event.precursors.iter().find(|id| !dag.contains(id)) {
+                return Err(AppError::invalid_request(format!(
+                    "Event {} came before its precursor {}",
+                    event, missing
+                )));
+            }
+            storage.insert(
+                EVENTS_TREE,
+                ids.next()?.to_bytes(),
+                bincode::serialize(&event)?,
+            )?;
+            dag.insert(event)
+                .map_err(|e| anyhow!("Could not add a received event: {}", e))?;
+            added += 1;
+        }
+        Ok(added)
+    }
+}
+
+pub fn advertise_heads(
+    request: proto::AdvertiseHeads,
+    state: &AppState,
+) -> Result<proto::AdvertiseHeads, AppError> {
+    let unseen = request
+        .heads
+        .iter()
+        .filter(|id| !state.dag.contains(id))
+        .count();
+    if unseen > 0 {
+        println!("A peer has {} DAG heads this node hasn't seen", unseen);
+    }
+    Ok(state.dag.advertise())
+}
+
+pub fn request_missing(
+    request: proto::RequestMissing,
+    state: &AppState,
+) -> Result<proto::SendEvents, AppError> {
+    let limit = request.limit.min(MAX_EVENTS_PER_BATCH);
+    Ok(proto::SendEvents {
+        events: state.dag.missing(&request.want, &request.have, limit),
+    })
+}
+
+pub fn send_events(
+    request: proto::SendEvents,
+    state: &AppState,
+) -> Result<proto::EventsReceived, AppError> {
+    let added = state
+        .dag
+        .receive(&state.storage, &state.event_ids, request.events)?;
+    Ok(proto::EventsReceived { added })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_log() {
+        let storage = StorageEngine::new_test().unwrap();
+        let ids = EventIds::default();
+        let log = EventLog::load(&storage).unwrap();
+        assert!(log.heads().is_empty());
+
+        let store = |event_id: Ulid, event: &Event| {
+            storage
+                .insert(
+                    EVENTS_TREE,
+                    event_id.to_bytes(),
+                    bincode::serialize(event).unwrap(),
+                )
+                .unwrap();
+        };
+        let (first_id, first) = log
+            .append(&ids, |event_id, precursors| {
+                assert!(precursors.is_empty());
+                let event = Event::with_payload(1, b"first", precursors);
+                store(event_id, &event);
+                Ok(event)
+            })
+            .unwrap();
+        assert_eq!(log.heads(), BTreeSet::from([first.id.clone()]));
+
+        // a failure leaves the DAG alone
+        assert!(log
+            .append(&ids, |_, _| Err(anyhow!("write failed")))
+            .is_err());
+        assert_eq!(log.heads(), BTreeSet::from([first.id.clone()]));
+
+        let (second_id, second) = log
+            .append(&ids, |event_id, precursors| {
+                assert_eq!(precursors, BTreeSet::from([first.id.clone()]));
+                let event = Event::with_payload(2, b"second", precursors);
+                store(event_id, &event);
+                Ok(event)
+            })
+            .unwrap();
+        assert!(first_id < second_id);
+
+        // a peer's events are added once, and only after their precursors
+        let theirs = Event::with_payload(3, b"theirs", BTreeSet::from([first.id.clone()]));
+        let orphan = Event::with_payload(4, b"orphan", BTreeSet::from([theirs.id.clone()]));
+        assert!(log.receive(&storage, &ids, vec![orphan.clone()]).is_err());
+        assert_eq!(
+            log.receive(&storage, &ids, vec![first.clone(), theirs.clone(), orphan])
+                .unwrap(),
+            2
+        );
+        assert_eq!(log.len(), 4);
+
+        // a restart picks up where it left off
+        let reloaded = EventLog::load(&storage).unwrap();
+        assert_eq!(reloaded.len(), 4);
+        assert_eq!(reloaded.heads(), log.heads());
+        assert!(reloaded.heads().contains(&second.id));
+    }
+}
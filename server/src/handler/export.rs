@@ -0,0 +1,450 @@
+use std::ops::Bound;
+
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::header,
+    response::IntoResponse,
+};
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use bytes::Bytes;
+use ciborium::Value;
+use hydra_proto as proto;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cancel::{CancelToken, CHECK_INTERVAL},
+    error::AppError,
+    sources::is_ingress_tree,
+    storage::Scan,
+    worker::JobClass,
+    AppState,
+};
+
+const DEFAULT_CHUNK_SIZE: usize = 1000;
+
+/// The most records a chunk can be asked for, so a client can't have one read, or buffered, that
+/// would exhaust memory
+pub const MAX_CHUNK_SIZE: usize = 10_000;
+
+/// How an exported tree is written out, and an archive being imported is read
+#[derive(Deserialize, clap::ValueEnum, Clone, Copy, Default, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveFormat {
+    /// A JSON object per line, with keys and values base64 encoded
+    #[default]
+    Ndjson,
+    /// A CBOR map per item, each preceded by its length as 4 big endian bytes
+    Cbor,
+}
+
+impl ArchiveFormat {
+    fn content_type(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Ndjson => "application/x-ndjson",
+            ArchiveFormat::Cbor => "application/cbor",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ExportParams {
+    continuation: Option<String>,
+    chunk_size: Option<usize>,
+    #[serde(default)]
+    format: ArchiveFormat,
+}
+
+#[derive(Serialize)]
+struct ExportLine<'a> {
+    key: &'a str,
+    value: &'a str,
+}
+
+#[derive(Serialize)]
+struct ContinuationLine {
+    continuation: String,
+}
+
+/// Only captured logs can be exported, not the server's own trees such as kv or meta
+fn check_tree(tree: &str) -> Result<(), AppError> {
+    if !is_ingress_tree(tree) {
+        return Err(AppError::invalid_field(
+            "tree",
+            format!("Only ingress trees can be exported, not {}", tree),
+        ));
+    }
+    Ok(())
+}
+
+/// Read the next chunk of an export, resuming after the key in the continuation token if present
+pub fn export_chunk(
+    state: &AppState,
+    request: proto::ExportRequest,
+    cancel: &CancelToken,
+) -> Result<proto::ExportChunk, AppError> {
+    check_tree(&request.tree)?;
+    let (after, snapshot) = match request.continuation {
+        Some(token) if token.tree != request.tree => {
+            return Err(AppError::invalid_field(
+                "continuation",
+                format!(
+                    "Continuation token is for tree {} not {}",
+                    token.tree, request.tree
+                ),
+            ))
+        }
+        Some(token) => (Bound::Excluded(token.after), token.snapshot),
+        None => (Bound::Unbounded, None),
+    };
+    if request.limit > MAX_CHUNK_SIZE {
+        return Err(AppError::invalid_field(
+            "limit",
+            format!("Chunks are at most {} records", MAX_CHUNK_SIZE),
+        ));
+    }
+
+    // Every chunk is read from the snapshot taken for the first, so records written while the
+    // export is in progress can't shift it. Snapshots only last so many writes, and not across
+    // restarts, so one which has gone is replaced by a new one rather than failing the export,
+    // which carries on after the key from there.
+    let (records, snapshot) =
+        state
+            .storage
+            .read_as_of_or_now(&request.tree, snapshot.as_ref(), |view| {
+                // Fetch one extra to determine whether there is anything left after this chunk
+                let mut records = Vec::new();
+                for (i, item) in view
+                    .scan((after, Bound::Unbounded), false)
+                    .take(request.limit + 1)
+                    .enumerate()
+                {
+                    if i % CHECK_INTERVAL == 0 {
+                        cancel.check()?;
+                    }
+                    let (key, value) = item?;
+                    records.push(proto::ExportRecord {
+                        key: key.to_vec(),
+                        value: value.to_vec(),
+                    });
+                }
+                Ok::<_, anyhow::Error>(records)
+            })?;
+    let mut records = records?;
+
+    let more_records = records.len() > request.limit;
+    records.truncate(request.limit);
+
+    let continuation = match records.last() {
+        Some(last) if more_records => Some(proto::ContinuationToken {
+            tree: request.tree,
+            after: last.key.clone(),
+            snapshot: Some(snapshot),
+        }),
+        _ => None,
+    };
+
+    Ok(proto::ExportChunk {
+        records,
+        continuation,
+    })
+}
+
+/// Read the next chunk of an export on the worker pool
+pub async fn next_chunk(
+    state: &AppState,
+    request: proto::ExportRequest,
+    cancel: CancelToken,
+) -> Result<proto::ExportChunk, AppError> {
+    let job_state = state.clone();
+    state
+        .workers
+        .try_run(JobClass::Export, move || {
+            export_chunk(&job_state, request, &cancel)
+        })
+        .await
+}
+
+/// GET /export/*tree, with the admin token
+///
+/// Streams every record of the tree as NDJSON lines of base64 encoded keys and values, or with
+/// `?format=cbor` as length-prefixed CBOR maps of the raw bytes. Each chunk is followed by a
+/// `{"continuation": "..."}` item; if the download is interrupted it can be restarted with
+/// `?continuation=<token>` to pick up after the last complete chunk. POST the archive to
+/// /import/*tree to restore it, see import.rs.
+pub async fn export(
+    State(state): State<AppState>,
+    Path(tree): Path<String>,
+    Query(params): Query<ExportParams>,
+) -> Result<impl IntoResponse, AppError> {
+    let continuation = match params.continuation {
+        Some(token) => Some(decode_token(&token)?),
+        None => None,
+    };
+    let limit = params.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE).max(1);
+    if limit > MAX_CHUNK_SIZE {
+        return Err(AppError::invalid_field(
+            "chunk_size",
+            format!("chunk_size is at most {}", MAX_CHUNK_SIZE),
+        ));
+    }
+    let format = params.format;
+
+    // Dropped along with the response (or the stream, once we've started it) if the client
+    // disconnects, stopping the chunk being read
+    let cancel = CancelToken::new();
+    let guard = cancel.drop_guard();
+
+    // Read the first chunk eagerly so that a bad tree or token is reported as an error status
+    let first = next_chunk(
+        &state,
+        proto::ExportRequest {
+            tree: tree.clone(),
+            continuation,
+            limit,
+        },
+        cancel.clone(),
+    )
+    .await?;
+
+    let stream = futures_util::stream::unfold(Some(Ok(first)), move |next| {
+        let _ = &guard;
+        let state = state.clone();
+        let tree = tree.clone();
+        let cancel = cancel.clone();
+        async move {
+            let chunk = match next? {
+                Ok(chunk) => chunk,
+                Err(e) => return Some((Err(e), None)),
+            };
+            let body = render_chunk(&chunk, format);
+            let next = match chunk.continuation {
+                Some(continuation) => {
+                    let request = proto::ExportRequest {
+                        tree,
+                        continuation: Some(continuation),
+                        limit,
+                    };
+                    Some(
+                        next_chunk(&state, request, cancel)
+                            .await
+                            .map_err(AppError::into_anyhow),
+                    )
+                }
+                None => None,
+            };
+            Some((body, next))
+        }
+    });
+
+    Ok((
+        [(header::CONTENT_TYPE, format.content_type())],
+        Body::from_stream(stream),
+    ))
+}
+
+pub fn render_chunk(
+    chunk: &proto::ExportChunk,
+    format: ArchiveFormat,
+) -> Result<Bytes, anyhow::Error> {
+    let mut out = Vec::new();
+    match format {
+        ArchiveFormat::Ndjson => {
+            for record in &chunk.records {
+                let line = ExportLine {
+                    key: &URL_SAFE.encode(&record.key),
+                    value: &URL_SAFE.encode(&record.value),
+                };
+                serde_json::to_writer(&mut out, &line)?;
+                out.push(b'\n');
+            }
+            if let Some(token) = &chunk.continuation {
+                let line = ContinuationLine {
+                    continuation: encode_token(token)?,
+                };
+                serde_json::to_writer(&mut out, &line)?;
+                out.push(b'\n');
+            }
+        }
+        ArchiveFormat::Cbor => {
+            for record in &chunk.records {
+                let item = Value::Map(vec![
+                    (Value::from("key"), Value::Bytes(record.key.clone())),
+                    (Value::from("value"), Value::Bytes(record.value.clone())),
+                ]);
+                write_cbor(&mut out, &item)?;
+            }
+            if let Some(token) = &chunk.continuation {
+                let item = Value::Map(vec![(
+                    Value::from("continuation"),
+                    Value::from(encode_token(token)?),
+                )]);
+                write_cbor(&mut out, &item)?;
+            }
+        }
+    }
+    Ok(Bytes::from(out))
+}
+
+fn write_cbor(out: &mut Vec<u8>, item: &Value) -> Result<(), anyhow::Error> {
+    let mut encoded = Vec::new();
+    ciborium::into_writer(item, &mut encoded)?;
+    out.extend_from_slice(&u32::try_from(encoded.len())?.to_be_bytes());
+    out.extend_from_slice(&encoded);
+    Ok(())
+}
+
+fn encode_token(token: &proto::ContinuationToken) -> Result<String, anyhow::Error> {
+    Ok(URL_SAFE.encode(bincode::serialize(token)?))
+}
+
+fn decode_token(token: &str) -> Result<proto::ContinuationToken, AppError> {
+    URL_SAFE
+        .decode(token)
+        .ok()
+        .and_then(|bytes| bincode::deserialize(&bytes).ok())
+        .ok_or_else(|| AppError::invalid_field("continuation", "Invalid continuation token"))
+}
+
+#[cfg(test)]
+mod tests {
+    use hydra_error::ErrorKind;
+
+    use super::*;
+    use crate::storage::snapshot::HISTORY_CAPACITY;
+
+    #[tokio::test]
+    async fn test_chunk_size_limit() {
+        let state = AppState::new_test().unwrap();
+        let request = proto::ExportRequest {
+            tree: "ingress/archived".to_string(),
+            continuation: None,
+            limit: usize::MAX,
+        };
+        let err = export_chunk(&state, request, &CancelToken::new())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidRequest);
+
+        let params = ExportParams {
+            continuation: None,
+            chunk_size: Some(MAX_CHUNK_SIZE + 1),
+            format: ArchiveFormat::Ndjson,
+        };
+        let err = export(
+            State(state),
+            Path("ingress/archived".to_string()),
+            Query(params),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidRequest);
+    }
+
+    #[tokio::test]
+    async fn test_export_allowlist() {
+        use axum::http::{Method, StatusCode};
+        use tower::ServiceExt;
+
+        let state = AppState::new_test().unwrap();
+        state.storage.insert("kv", "secret", "s3cret").unwrap();
+        state.storage.insert("ingress/github", "log", "{}").unwrap();
+        let export = |tree: &str| {
+            let request = proto::ExportRequest {
+                tree: tree.to_string(),
+                continuation: None,
+                limit: 10,
+            };
+            export_chunk(&state, request, &CancelToken::new())
+        };
+        for tree in ["kv", "meta", "ingress_tombstones"] {
+            let err = export(tree).err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidRequest, "{}", tree);
+        }
+        assert_eq!(export("ingress/github").unwrap().records.len(), 1);
+
+        // over HTTP, only with the admin token, and source trees too
+        let app = crate::admin::routes(&state).with_state(state.clone());
+        let get = |uri: &str, authorization: Option<String>| {
+            let mut request = axum::http::Request::builder().method(Method::GET).uri(uri);
+            if let Some(authorization) = authorization {
+                request = request.header(header::AUTHORIZATION, authorization);
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+        let admin = || Some(format!("Bearer {}", crate::admin::TEST_TOKEN));
+        let response = get("/export/ingress/github", None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = get("/export/ingress/github", admin()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = get("/export/kv", admin()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_resume_expired_snapshot() {
+        let state = AppState::new_test().unwrap();
+        for n in 0..10u8 {
+            state
+                .storage
+                .insert("ingress/archived", [n], vec![n])
+                .unwrap();
+        }
+        let chunk = |continuation| {
+            let request = proto::ExportRequest {
+                tree: "ingress/archived".to_string(),
+                continuation,
+                limit: 4,
+            };
+            export_chunk(&state, request, &CancelToken::new()).unwrap()
+        };
+        let keys = |chunk: &proto::ExportChunk| -> Vec<u8> {
+            chunk.records.iter().map(|record| record.key[0]).collect()
+        };
+        let first = chunk(None);
+        assert_eq!(keys(&first), [0, 1, 2, 3]);
+        let token = first.continuation.unwrap();
+
+        // while the snapshot lasts, writes since don't show
+        state
+            .storage
+            .insert("ingress/archived", [5], vec![50])
+            .unwrap();
+        let second = chunk(Some(token.clone()));
+        assert_eq!(second.records[1].value, [5]);
+        assert_eq!(
+            second.continuation.as_ref().unwrap().snapshot,
+            token.snapshot
+        );
+
+        // once it's been pruned, the export carries on after the key from a new snapshot
+        for n in 0..HISTORY_CAPACITY {
+            state
+                .storage
+                .insert("other", n.to_be_bytes(), vec![])
+                .unwrap();
+        }
+        let resumed = chunk(Some(token.clone()));
+        assert_eq!(keys(&resumed), [4, 5, 6, 7]);
+        assert_eq!(resumed.records[1].value, [50]);
+        let renewed = resumed.continuation.unwrap();
+        assert_ne!(renewed.snapshot, token.snapshot);
+        assert_eq!(keys(&chunk(Some(renewed))), [8, 9]);
+
+        // as does one from before a restart, or without a snapshot
+        let restarted = proto::ContinuationToken {
+            snapshot: Some(proto::SnapshotToken {
+                epoch: ulid::Ulid::new(),
+                sequence: 1,
+            }),
+            ..token.clone()
+        };
+        assert_eq!(keys(&chunk(Some(restarted))), [4, 5, 6, 7]);
+        let unpinned = proto::ContinuationToken {
+            snapshot: None,
+            ..token
+        };
+        assert_eq!(keys(&chunk(Some(unpinned))), [4, 5, 6, 7]);
+    }
+}
@@ -0,0 +1,58 @@
+use hydra_proto as proto;
+use ulid::Ulid;
+
+use crate::{error::AppError, AppState};
+
+/// Size of the page of newest ingress logs clients are told to prefetch
+const PREFETCH_PAGE_SIZE: usize = 50;
+/// Bodies in the prefetched page are previews of at most this many bytes, as for a list view
+const PREFETCH_PREVIEW_BYTES: usize = 256;
+
+/// What a newly connected client should prefetch. Dashboards open on the newest ingress logs,
+/// so that page is suggested whenever there are any. There's no access control yet, so every
+/// client gets the same hints.
+pub fn hello(state: &AppState, session: Ulid) -> Result<proto::HelloResponse, AppError> {
+    let mut prefetch = Vec::new();
+    if !state.storage.subtree("ingress")?.is_empty() {
+        prefetch.push(proto::RequestPayload::FetchIngressLogs(
+            proto::FetchIngressLogsRequest {
+                direction: proto::Direction::Descending,
+                limit: PREFETCH_PAGE_SIZE,
+                cursor: proto::PaginatedCursor::StartingWith(vec![]),
+                preview_bytes: Some(PREFETCH_PREVIEW_BYTES),
+                snapshot: None,
+                source: None,
+                filter: None,
+                thumbnails: false,
+            },
+        ));
+    }
+    Ok(proto::HelloResponse { prefetch, session })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefetch_hints() {
+        let state = AppState::new_test().unwrap();
+        let session = Ulid::new();
+        let response = hello(&state, session).unwrap();
+        assert!(response.prefetch.is_empty());
+        assert_eq!(response.session, session);
+
+        state.storage.insert("ingress", "test|1", vec![]).unwrap();
+        let hints = hello(&state, session).unwrap().prefetch;
+        assert!(matches!(
+            hints.as_slice(),
+            [proto::RequestPayload::FetchIngressLogs(
+                proto::FetchIngressLogsRequest {
+                    direction: proto::Direction::Descending,
+                    limit: PREFETCH_PAGE_SIZE,
+                    ..
+                }
+            )]
+        ));
+    }
+}
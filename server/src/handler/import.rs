@@ -0,0 +1,306 @@
+//! Restoring archives made by GET /export/*tree, eg. to move captured logs to another hydra
+//! instance or to restore a backup. Records keep the keys they were exported with, and are
+//! written through the storage engine, so indexes, views and subscribers see them as usual.
+//!
+//! Archives are read as they arrive, so an import never holds more than a chunk of one in memory.
+//! Only ingress trees can be imported into, and an import is as durable as its tree's captures,
+//! see durability.rs.
+
+use std::{io::Read, path::Path as FilePath};
+
+use anyhow::{anyhow, bail, Context};
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    Json,
+};
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use ciborium::Value;
+use futures_util::StreamExt;
+use hydra_proto as proto;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::ImportConfig, error::AppError, handler::export::ArchiveFormat,
+    sources::is_ingress_tree, AppState,
+};
+
+/// The largest CBOR item accepted, so a corrupt length can't make us allocate without limit
+const MAX_ITEM_BYTES: usize = 64 * 1024 * 1024;
+
+/// How much of an archive file is read at a time
+const READ_CHUNK_BYTES: usize = 64 * 1024;
+
+#[derive(Deserialize)]
+struct ImportLine {
+    key: Option<String>,
+    value: Option<String>,
+}
+
+/// Reads the records out of an archive a chunk at a time, as it arrives
+pub struct ArchiveReader {
+    format: ArchiveFormat,
+    // the start of an item which hasn't completely arrived yet
+    partial: Vec<u8>,
+}
+
+impl ArchiveReader {
+    pub fn new(format: ArchiveFormat) -> Self {
+        Self {
+            format,
+            partial: Vec::new(),
+        }
+    }
+
+    /// The records completed by the next chunk of the archive. Continuation items are skipped.
+    pub fn push(&mut self, chunk: &[u8]) -> anyhow::Result<Vec<proto::ExportRecord>> {
+        self.partial.extend_from_slice(chunk);
+        let mut records = Vec::new();
+        let mut start = 0;
+        loop {
+            let rest = &self.partial[start..];
+            let (item, len) = match self.format {
+                ArchiveFormat::Ndjson => match rest.iter().position(|&b| b == b'\n') {
+                    Some(end) => (&rest[..end], end + 1),
+                    None => break,
+                },
+                ArchiveFormat::Cbor => {
+                    let Some(prefix) = rest.get(..4) else {
+                        break;
+                    };
+                    let item_len = u32::from_be_bytes(prefix.try_into()?) as usize;
+                    if item_len > MAX_ITEM_BYTES {
+                        bail!("Archive item of {} bytes is too large", item_len);
+                    }
+                    match rest.get(4..4 + item_len) {
+                        Some(item) => (item, 4 + item_len),
+                        None => break,
+                    }
+                }
+            };
+            records.extend(self.parse(item)?);
+            start += len;
+        }
+        self.partial.drain(..start);
+        Ok(records)
+    }
+
+    /// The last record of the archive, for NDJSON without a trailing newline. Errs if the archive
+    /// stopped part way through an item.
+    pub fn finish(self) -> anyhow::Result<Option<proto::ExportRecord>> {
+        if self.partial.is_empty() {
+            return Ok(None);
+        }
+        match self.format {
+            ArchiveFormat::Ndjson => self.parse(&self.partial),
+            ArchiveFormat::Cbor => bail!("Archive ends part way through an item"),
+        }
+    }
+
+    fn parse(&self, item: &[u8]) -> anyhow::Result<Option<proto::ExportRecord>> {
+        let (key, value) = match self.format {
+            ArchiveFormat::Ndjson => {
+                if item.iter().all(u8::is_ascii_whitespace) {
+                    return Ok(None);
+                }
+                let line: ImportLine = serde_json::from_slice(item)?;
+                match (line.key, line.value) {
+                    (Some(key), Some(value)) => (URL_SAFE.decode(key)?, URL_SAFE.decode(value)?),
+                    _ => return Ok(None),
+                }
+            }
+            ArchiveFormat::Cbor => {
+                let Value::Map(fields) = ciborium::from_reader(item)? else {
+                    bail!("Archive item is not a map");
+                };
+                let field = |name: &str| {
+                    fields.iter().find_map(|(k, v)| match (k, v) {
+                        (Value::Text(k), Value::Bytes(v)) if k == name => Some(v.clone()),
+                        _ => None,
+                    })
+                };
+                match (field("key"), field("value")) {
+                    (Some(key), Some(value)) => (key, value),
+                    _ => return Ok(None),
+                }
+            }
+        };
+        Ok(Some(proto::ExportRecord { key, value }))
+    }
+}
+
+fn check_tree(tree: &str) -> Result<(), AppError> {
+    if !is_ingress_tree(tree) {
+        return Err(AppError::invalid_field(
+            "tree",
+            format!("Only ingress trees can be imported into, not {}", tree),
+        ));
+    }
+    Ok(())
+}
+
+/// Write records to a tree as its durability asks, returning how many were written
+fn restore(state: &AppState, tree: &str, records: Vec<proto::ExportRecord>) -> anyhow::Result<u64> {
+    let count = records.len() as u64;
+    state.writer.write(
+        &state.storage,
+        state.sources.durability_of(tree),
+        tree,
+        records
+            .into_iter()
+            .map(|record| (record.key, record.value))
+            .collect(),
+    )?;
+    Ok(count)
+}
+
+#[derive(Deserialize)]
+pub struct ImportParams {
+    #[serde(default)]
+    format: ArchiveFormat,
+}
+
+#[derive(Serialize)]
+pub struct ImportSummary {
+    imported: u64,
+}
+
+/// POST /import/*tree, with an archive from GET /export/*tree as the body and the same `format`,
+/// and the admin token
+pub async fn import(
+    State(state): State<AppState>,
+    Path(tree): Path<String>,
+    Query(params): Query<ImportParams>,
+    body: Body,
+) -> Result<Json<ImportSummary>, AppError> {
+    check_tree(&tree)?;
+    let mut reader = ArchiveReader::new(params.format);
+    let mut stream = body.into_data_stream();
+    let mut imported = 0;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| anyhow!("Failed to read archive: {}", e))?;
+        let records = reader.push(&chunk).map_err(invalid_archive)?;
+        let (job_state, job_tree) = (state.clone(), tree.clone());
+        // sled calls block, so keep them off the async runtime
+        imported += tokio::task::spawn_blocking(move || restore(&job_state, &job_tree, records))
+            .await
+            .map_err(anyhow::Error::from)??;
+    }
+    let last = reader.finish().map_err(invalid_archive)?;
+    imported += restore(&state, &tree, last.into_iter().collect())?;
+    state
+        .writer
+        .settle(&state.storage, state.sources.durability_of(&tree))
+        .await?;
+    println!("Imported {} records into {}", imported, tree);
+    Ok(Json(ImportSummary { imported }))
+}
+
+fn invalid_archive(e: anyhow::Error) -> AppError {
+    AppError::invalid_request(format!("Invalid archive: {:#}", e))
+}
+
+/// `hydra-server import`, which restores an archive file without starting the server
+pub fn import_file(state: &AppState, config: &ImportConfig) -> anyhow::Result<u64> {
+    check_tree(&config.tree).map_err(AppError::into_anyhow)?;
+    let format = config
+        .format
+        .unwrap_or(match archive_extension(&config.file) {
+            Some("cbor") => ArchiveFormat::Cbor,
+            _ => ArchiveFormat::Ndjson,
+        });
+    let mut file = std::fs::File::open(&config.file)
+        .with_context(|| format!("Could not open {}", config.file.display()))?;
+    let mut reader = ArchiveReader::new(format);
+    let mut buffer = vec![0; READ_CHUNK_BYTES];
+    let mut imported = 0;
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        imported += restore(state, &config.tree, reader.push(&buffer[..read])?)?;
+    }
+    let last = reader.finish()?;
+    imported += restore(state, &config.tree, last.into_iter().collect())?;
+    // the server isn't running to write out relaxed writes later
+    state.writer.write_queued(&state.storage)?;
+    Ok(imported)
+}
+
+fn archive_extension(path: &FilePath) -> Option<&str> {
+    path.extension().and_then(|extension| extension.to_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::export::render_chunk;
+
+    fn records() -> Vec<proto::ExportRecord> {
+        (0..20u8)
+            .map(|n| proto::ExportRecord {
+                key: vec![b'k', n],
+                value: vec![n; n as usize],
+            })
+            .collect()
+    }
+
+    fn read_back(format: ArchiveFormat, archive: &[u8], chunk_size: usize) -> Vec<Vec<u8>> {
+        let mut reader = ArchiveReader::new(format);
+        let mut read = Vec::new();
+        for chunk in archive.chunks(chunk_size) {
+            read.extend(reader.push(chunk).unwrap());
+        }
+        read.extend(reader.finish().unwrap());
+        read.into_iter()
+            .flat_map(|record| [record.key, record.value])
+            .collect()
+    }
+
+    #[test]
+    fn test_archive_round_trip() {
+        let state = AppState::new_test().unwrap();
+        let expected: Vec<Vec<u8>> = records()
+            .into_iter()
+            .flat_map(|record| [record.key, record.value])
+            .collect();
+        let chunk = proto::ExportChunk {
+            records: records(),
+            continuation: Some(proto::ContinuationToken {
+                tree: "ingress".to_string(),
+                after: vec![1],
+                snapshot: Some(proto::SnapshotToken {
+                    epoch: ulid::Ulid::new(),
+                    sequence: 0,
+                }),
+            }),
+        };
+        for format in [ArchiveFormat::Ndjson, ArchiveFormat::Cbor] {
+            let archive = render_chunk(&chunk, format).unwrap();
+            // however the archive is split up as it arrives
+            for chunk_size in [1, 7, archive.len()] {
+                assert_eq!(read_back(format, &archive, chunk_size), expected);
+            }
+        }
+
+        // a final NDJSON line needn't end with a newline, but CBOR can't stop part way
+        let ndjson = render_chunk(&chunk, ArchiveFormat::Ndjson).unwrap();
+        let trimmed = ndjson.strip_suffix(b"\n").unwrap();
+        assert_eq!(read_back(ArchiveFormat::Ndjson, trimmed, 100), expected);
+        let cbor = render_chunk(&chunk, ArchiveFormat::Cbor).unwrap();
+        let mut reader = ArchiveReader::new(ArchiveFormat::Cbor);
+        reader.push(&cbor[..cbor.len() - 1]).unwrap();
+        assert!(reader.finish().is_err());
+        let mut reader = ArchiveReader::new(ArchiveFormat::Ndjson);
+        assert!(reader.push(b"not json\n").is_err());
+
+        assert_eq!(restore(&state, "ingress", records()).unwrap(), 20);
+        assert_eq!(
+            state.storage.get("ingress", [b'k', 3]).unwrap().as_deref(),
+            Some(&[3, 3, 3][..])
+        );
+        assert!(check_tree("ingress/github").is_ok());
+        assert!(check_tree("schemas").is_err());
+    }
+}
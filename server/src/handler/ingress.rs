@@ -1,93 +1,537 @@
 use anyhow::anyhow;
 use axum::{
-    extract::{ws::WebSocket, Host, Path, Query, State},
-    http::{HeaderMap, Method},
+    extract::{ConnectInfo, Host, OriginalUri, Path, Query, State},
+    http::{HeaderMap, Method, Uri},
     response::IntoResponse,
+    routing::{on, MethodFilter, MethodRouter},
     Json,
 };
+use base64::{engine::general_purpose::STANDARD, Engine};
 use bytes::Bytes;
-use futures_util::stream::SplitSink;
 use hydra_proto as proto;
-use proto::IngressLog;
+use proto::{dag::Event, IngressLog};
 use serde::{Deserialize, Serialize};
 use sled::IVec;
-use std::{collections::HashMap, net::SocketAddr};
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, SocketAddr},
+    ops::{Bound, RangeBounds},
+    sync::Mutex,
+};
 use ulid::Ulid;
 
 use crate::{
+    cancel::CancelToken,
+    codec::{Codec, CodecKind},
     error::AppError,
+    handler::events::EVENTS_TREE,
+    outbound::OutboundSender,
     query::{
         fetch_paginated, fetch_records, FetchRecordQuery, FetchRecordResult, PaginatedFetchRequest,
     },
+    sources::Sources,
+    storage::{
+        index::{IndexSpec, Indexed},
+        versioned::{self, RecordMigration, Versioned},
+    },
+    tags, thumbnail,
+    trace::TraceId,
     AppState,
 };
 
+/// Namespace of the keys captured logs are stored under in the ingress tree
+pub const INGRESS_PREFIX: &str = "test|";
+
+/// The log gained its trace id in version 2, see storage/versioned.rs
+impl Versioned for IngressLog {
+    const VERSION: u8 = 2;
+
+    fn migrations() -> Vec<Box<dyn RecordMigration>> {
+        vec![Box::new(AddTraceId)]
+    }
+}
+
+/// The first version of the log
 #[derive(Serialize, Deserialize)]
-struct IngressResponse {
+struct IngressLogV1 {
     event_id: Ulid,
+    date: chrono::DateTime<chrono::Utc>,
+    remote_addr: Option<SocketAddr>,
+    method: String,
+    host: String,
+    path: String,
+    query: HashMap<String, String>,
+    headers: HashMap<String, String>,
+    body: Bytes,
+}
+
+/// Logs captured before trace ids have none
+struct AddTraceId;
+
+impl RecordMigration for AddTraceId {
+    fn from(&self) -> u8 {
+        1
+    }
+
+    fn migrate(&self, codec: CodecKind, record: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let log: IngressLogV1 = codec.decode(record)?;
+        codec.encode(&IngressLog {
+            event_id: log.event_id,
+            date: log.date,
+            remote_addr: log.remote_addr,
+            method: log.method,
+            host: log.host,
+            path: log.path,
+            query: log.query,
+            headers: log.headers,
+            body: log.body,
+            trace_id: None,
+        })
+    }
+}
+
+/// Captured logs can be found by when they were captured (as milliseconds since the epoch, so
+/// that dates can be read by range), by host ignoring case, and by method
+impl Indexed for IngressLog {
+    fn indexes() -> Vec<IndexSpec<Self>> {
+        vec![
+            IndexSpec {
+                name: "date",
+                value: |log| {
+                    (log.date.timestamp_millis().max(0) as u64)
+                        .to_be_bytes()
+                        .to_vec()
+                },
+            },
+            IndexSpec {
+                name: "host",
+                value: |log| log.host.to_ascii_lowercase().into_bytes(),
+            },
+            IndexSpec {
+                name: "method",
+                value: |log| log.method.to_ascii_uppercase().into_bytes(),
+            },
+        ]
+    }
 }
 
-pub async fn capture(
+/// Where webhooks are captured. Everything under it is captured too, eg. /ingress/hooks/github
+const INGRESS_ROUTE: &str = "/ingress";
+
+/// Hands out event ids. Each one sorts after every id handed out before it, even within the same
+/// millisecond, where fresh ULIDs would be ordered at random, so a log is always stored after any
+/// log whose capture had been answered before it arrived.
+#[derive(Default)]
+pub struct EventIds(Mutex<ulid::Generator>);
+
+impl EventIds {
+    pub fn next(&self) -> anyhow::Result<Ulid> {
+        self.0
+            .lock()
+            .unwrap()
+            .generate()
+            .map_err(|e| anyhow!("Could not generate an event id: {}", e))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct IngressResponse {
+    pub event_id: Ulid,
+    // the hash of the DAG event chaining the log, see handler/events.rs
+    pub dag_event: String,
+}
+
+/// Routes capture for every method a webhook might plausibly be sent with. Mount it on both
+/// /ingress and /ingress/*path.
+pub fn capture_route() -> MethodRouter<AppState> {
+    let methods = MethodFilter::GET
+        .or(MethodFilter::POST)
+        .or(MethodFilter::PUT)
+        .or(MethodFilter::PATCH)
+        .or(MethodFilter::DELETE)
+        .or(MethodFilter::OPTIONS)
+        .or(MethodFilter::HEAD);
+    on(methods, capture)
+}
+
+// one argument per extractor
+#[allow(clippy::too_many_arguments)]
+async fn capture(
     state: State<AppState>,
-    // uncommenting these causes an error
-    // remote_addr: Option<SocketAddr>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
     method: Method,
     Host(host): Host,
-    path: Path<Vec<String>>,
-    Query(query): Query<HashMap<String, String>>,
-    headers: HeaderMap,
+    OriginalUri(uri): OriginalUri,
+    Query(mut query): Query<HashMap<String, String>>,
+    mut headers: HeaderMap,
+    TraceId(trace_id): TraceId,
     body: Bytes,
 ) -> Result<impl IntoResponse, AppError> {
-    let event_id = ulid::Ulid::new();
-    let key = format!("test|{}", event_id);
+    let remote_addr = match state.trust_proxy {
+        true => forwarded_client(&headers).unwrap_or(peer),
+        false => peer,
+    };
+    // before the source's token is taken out
+    let shadowed = state.shadow.capture(&method, &uri, &mut headers, &body);
+    let (tree, path) = destination(
+        &state.sources,
+        captured_path(&uri),
+        &mut headers,
+        &mut query,
+    )?;
 
-    println!("Ingress request: {:?}", event_id);
+    let durability = state.sources.durability_of(&tree);
+    let (event_id, event) = state.dag.append(&state.event_ids, |event_id, precursors| {
+        println!("Ingress request: {:?} (trace {})", event_id, trace_id);
 
-    let log = proto::IngressLog {
+        let log = proto::IngressLog {
+            event_id,
+            remote_addr: Some(remote_addr),
+            method: method.to_string(),
+            host,
+            path,
+            query,
+            date: chrono::Utc::now(),
+            body,
+            headers: headers
+                .iter()
+                .map(|(k, v)| {
+                    (
+                        k.to_string(),
+                        String::from_utf8_lossy(v.as_bytes()).into_owned(),
+                    )
+                })
+                .collect(),
+            trace_id: Some(trace_id),
+        };
+        let key = format!("{}{}", INGRESS_PREFIX, event_id);
+        let encoded = state.storage.encode(&log)?;
+        let event = Event::with_payload(log.date.timestamp(), &encoded, precursors);
+
+        // the log and the event covering it are written together, so neither is ever seen
+        // without the other
+        state.writer.write_together(
+            &state.storage,
+            durability,
+            vec![
+                (&tree, key.into_bytes(), encoded),
+                (
+                    EVENTS_TREE,
+                    event_id.to_bytes().to_vec(),
+                    bincode::serialize(&event)?,
+                ),
+            ],
+        )?;
+        Ok(event)
+    })?;
+    state.writer.settle(&state.storage, durability).await?;
+    if let Some(item) = shadowed {
+        state.shadow.enqueue(&state.storage, item);
+    }
+
+    Ok(Json(IngressResponse {
         event_id,
-        remote_addr: None,
-        method: method.to_string(),
-        host,
-        path: path.join("/").to_string(),
-        query,
-        date: chrono::Utc::now(),
-        body,
-        headers: headers
-            .iter()
-            .map(|(k, v)| (k.to_string(), v.to_str().unwrap().to_string()))
-            .collect(),
+        dag_event: hex::encode(event.id.hash),
+    }))
+}
+
+/// The tree a capture is stored in, and the path it's stored with. Captures under the id of a
+/// configured source go to its tree, with the path below the id, as long as they carry its token
+/// in a `token` query parameter or an `Authorization: Bearer` header. The token is then left out
+/// of what's stored. Anything else goes to the shared ingress tree.
+pub fn destination(
+    sources: &Sources,
+    path: String,
+    headers: &mut HeaderMap,
+    query: &mut HashMap<String, String>,
+) -> Result<(String, String), AppError> {
+    let (first, rest) = path.split_once('/').unwrap_or((&path, ""));
+    let Some(source) = sources.get(first) else {
+        return Ok(("ingress".to_string(), path));
     };
+    let token = match query.remove("token") {
+        Some(token) => Some(token),
+        None => bearer_token(headers).inspect(|_| {
+            headers.remove("authorization");
+        }),
+    };
+    match token {
+        Some(token) if source.accepts(&token) => Ok((source.tree(), rest.to_string())),
+        _ => Err(AppError::unauthorized(format!(
+            "Captures for source {} need its token",
+            source.id
+        ))),
+    }
+}
 
-    let handle = state.storage.subtree("ingress")?;
-    handle.insert(key, bincode::serialize(&log)?)?;
+pub fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get("authorization")?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then(|| token.trim().to_string())
+}
+
+/// The path a request was captured under, relative to INGRESS_ROUTE and exactly as it was sent,
+/// so eg. trailing slashes and percent-encoding are kept
+fn captured_path(uri: &Uri) -> String {
+    let path = uri.path();
+    let path = path.strip_prefix(INGRESS_ROUTE).unwrap_or(path);
+    path.strip_prefix('/').unwrap_or(path).to_string()
+}
 
-    Ok(Json(IngressResponse { event_id }))
+/// The original client of a request which came through proxies, from the standard Forwarded
+/// header if present, or else X-Forwarded-For. Addresses without a port are given port 0.
+/// None if neither header names a client, eg. `for=unknown` or an obfuscated identifier.
+pub fn forwarded_client(headers: &HeaderMap) -> Option<SocketAddr> {
+    if let Some(forwarded) = headers.get("forwarded").and_then(|v| v.to_str().ok()) {
+        // `for=192.0.2.60;proto=http, for="[2001:db8::1]:4711"`: the first element is the client
+        let first = forwarded.split(',').next()?;
+        return first
+            .split(';')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+            .and_then(|(_, value)| parse_node(value));
+    }
+    let forwarded_for = headers.get("x-forwarded-for")?.to_str().ok()?;
+    parse_node(forwarded_for.split(',').next()?)
+}
+
+/// An address as it appears in a forwarding header: an IP, optionally with a port, and
+/// possibly quoted
+fn parse_node(value: &str) -> Option<SocketAddr> {
+    let value = value.trim().trim_matches('"');
+    if let Ok(addr) = value.parse::<SocketAddr>() {
+        return Some(addr);
+    }
+    // IPv6 addresses are bracketed in Forwarded, even without a port
+    let ip = value.trim_start_matches('[').trim_end_matches(']');
+    ip.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 0))
 }
 
 pub fn fetch_ingress_logs(
     request: proto::FetchIngressLogsRequest,
     state: &AppState,
-    sender: &SplitSink<WebSocket, axum::extract::ws::Message>,
+    cancel: CancelToken,
 ) -> Result<proto::FetchIngressLogsResponse, AppError> {
+    let tree = log_tree(state, request.source.as_deref())?;
+    // the newest page, unfiltered, is the one most asked for, and usually in the hot cache
+    let newest = request.filter.is_none()
+        && request.snapshot.is_none()
+        && request.direction == proto::Direction::Descending
+        && matches!(&request.cursor, proto::PaginatedCursor::StartingWith(start) if start.is_empty());
     let paginated_request = PaginatedFetchRequest {
-        tree: "ingress",
+        tree: tree.clone(),
         cursor: request.cursor,
         direction: request.direction,
         limit: request.limit,
+        snapshot: request.snapshot,
+        cancel,
+        prefix: INGRESS_PREFIX.as_bytes().to_vec(),
+        bounds: request
+            .filter
+            .as_ref()
+            .map_or((Bound::Unbounded, Bound::Unbounded), captured_between),
+        count: request.filter.is_none(),
+    };
+    let filter = request.filter.unwrap_or_default();
+    let paginated_response = match newest
+        .then(|| state.hot.newest(&tree, request.limit))
+        .flatten()
+    {
+        Some(page) => {
+            let mut page = page?;
+            page.total_count = Some(state.storage.count(
+                &tree,
+                INGRESS_PREFIX.as_bytes(),
+                Some(&page.snapshot),
+            )?);
+            page
+        }
+        None => fetch_paginated::<IngressLog>(state, paginated_request, |log| filter.matches(log))?,
     };
-    let paginated_response = fetch_paginated::<IngressLog>(state, paginated_request)?;
     Ok(proto::FetchIngressLogsResponse {
         items: paginated_response
             .items
             .into_iter()
-            .map(|crate::query::FetchResultItem { key, item }| (key, item))
-            .collect(),
+            .map(|crate::query::FetchResultItem { key, item }| {
+                let tags = tags::tags_of(&state.storage, &tree, &key)?;
+                let thumbnail = match request.thumbnails {
+                    true => thumbnail::thumbnail_of(&state.storage, &tree, &key, &item)?,
+                    false => None,
+                };
+                let computed = state.computed.compute(&tree, &item);
+                Ok(to_item(
+                    key,
+                    item,
+                    tags,
+                    thumbnail,
+                    computed,
+                    request.preview_bytes,
+                ))
+            })
+            .collect::<anyhow::Result<_>>()?,
         limit: paginated_response.limit,
         has_more_before: paginated_response.has_more_before,
         has_more_after: paginated_response.has_more_after,
+        total_count: paginated_response.total_count,
+        position: paginated_response.position,
+        snapshot: paginated_response.snapshot,
     })
 }
 
+/// Tag or untag the logs matching a filter, pushing progress to `outbound` as it goes
+pub fn tag_ingress_logs(
+    request: proto::TagIngressLogsRequest,
+    request_id: usize,
+    outbound: &OutboundSender,
+    state: &AppState,
+    cancel: CancelToken,
+) -> Result<proto::TagProgress, AppError> {
+    let tree = log_tree(state, request.source.as_deref())?;
+    let (from, to) = captured_between(&request.filter);
+    let range = match from {
+        Bound::Unbounded => (Bound::Included(INGRESS_PREFIX.as_bytes().to_vec()), to),
+        from => (from, to),
+    };
+    let filter = &request.filter;
+    let host = (filter.host.as_ref()).map(|host| ("host", host.to_ascii_lowercase()));
+    let method = (filter.method.as_ref()).map(|method| ("method", method.to_ascii_uppercase()));
+    let logs = state.storage.subtree(&tree)?;
+    // the host or method index narrows down which logs are read, though each is still checked
+    // against the whole filter
+    let entries: Box<dyn Iterator<Item = sled::Result<(IVec, IVec)>>> = match host.or(method) {
+        Some((index, value)) => {
+            let keys = state.storage.indexed(&tree, index, value.as_bytes())?;
+            Box::new(
+                keys.into_iter()
+                    .filter(move |key| range.contains(&key.to_vec()))
+                    .filter_map(|key| {
+                        let value = logs.get(&key).transpose()?;
+                        Some(value.map(|value| (key, value)))
+                    }),
+            )
+        }
+        None => Box::new(logs.range(range)),
+    };
+    tags::bulk_tag(
+        &state.storage,
+        &tree,
+        entries,
+        &request,
+        &cancel,
+        |progress| {
+            // progress is only a courtesy, so it's dropped rather than waited for
+            let _ = outbound.try_send(proto::Response {
+                request_id,
+                sequence: 0,
+                trace_id: None,
+                payload: proto::ResponsePayload::TagProgress(progress.clone()),
+            });
+        },
+    )
+}
+
+/// A log by its event id
+pub fn fetch_ingress_log(
+    request: proto::FetchIngressLogRequest,
+    state: &AppState,
+) -> Result<proto::FetchIngressLogResponse, AppError> {
+    let tree = log_tree(state, request.source.as_deref())?;
+    let key = format!("{}{}", INGRESS_PREFIX, request.event_id);
+    let log = match state.storage.get(&tree, key)? {
+        Some(value) => Some(versioned::decode(&value)?),
+        None => None,
+    };
+    Ok(proto::FetchIngressLogResponse { log })
+}
+
+#[derive(Deserialize)]
+pub struct LogParams {
+    pub source: Option<String>,
+}
+
+/// GET /logs/:event_id
+///
+/// A log as JSON, with its body base64 encoded, from the shared ingress tree or with `?source=`
+/// from a source's logs. It isn't under /ingress, as GET requests there are captured.
+pub async fn log_detail(
+    State(state): State<AppState>,
+    Path(event_id): Path<Ulid>,
+    Query(params): Query<LogParams>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let request = proto::FetchIngressLogRequest {
+        event_id,
+        source: params.source,
+    };
+    let log = fetch_ingress_log(request, &state)?
+        .log
+        .ok_or_else(|| AppError::not_found(format!("No log {}", event_id)))?;
+    let mut detail = serde_json::to_value(&log)?;
+    detail["body"] = STANDARD.encode(&log.body).into();
+    Ok(Json(detail))
+}
+
+/// The tree holding a source's logs, or the shared ingress tree
+pub fn log_tree(state: &AppState, source: Option<&str>) -> Result<String, AppError> {
+    match source {
+        Some(id) => Ok(state
+            .sources
+            .get(id)
+            .ok_or_else(|| AppError::not_found(format!("No ingress source {}", id)))?
+            .tree()),
+        None => Ok("ingress".to_string()),
+    }
+}
+
+/// The range of keys of the logs captured within a filter's time range. Keys end with event ids,
+/// which are ULIDs minted at capture, so the range is exact to the millisecond.
+pub fn captured_between(filter: &proto::IngressLogFilter) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let key = |date: &chrono::DateTime<chrono::Utc>| {
+        let id = Ulid::from_parts(date.timestamp_millis().max(0) as u64, 0);
+        format!("{}{}", INGRESS_PREFIX, id).into_bytes()
+    };
+    (
+        filter
+            .from
+            .as_ref()
+            .map_or(Bound::Unbounded, |from| Bound::Included(key(from))),
+        filter
+            .to
+            .as_ref()
+            .map_or(Bound::Unbounded, |to| Bound::Excluded(key(to))),
+    )
+}
+
+/// Wrap a log for a fetch response, swapping its body for a preview if requested
+pub fn to_item(
+    key: Vec<u8>,
+    mut log: IngressLog,
+    tags: Vec<String>,
+    thumbnail: Option<proto::Thumbnail>,
+    computed: BTreeMap<String, String>,
+    preview_bytes: Option<usize>,
+) -> proto::IngressLogItem {
+    let preview = preview_bytes.map(|max_bytes| {
+        let content_type = log
+            .headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("content-type"))
+            .map(|(_, v)| v.as_str());
+        let preview = crate::preview::body_preview(&log.body, content_type, max_bytes);
+        log.body = Bytes::new();
+        preview
+    });
+    proto::IngressLogItem {
+        key,
+        log,
+        preview,
+        tags,
+        thumbnail,
+        computed,
+    }
+}
+
 // // render_ingress_logs_html(items, params.get("limit"), has_more_before, has_more_after)
 // fn render_ingress_logs_html(
 //     items: Vec<(IVec, IngressLog)>,
@@ -229,3 +673,562 @@ pub fn fetch_ingress_logs(
 
 //     Ok(axum::response::Html(html))
 // }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeSet,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+    };
+
+    use hydra_error::ErrorKind;
+
+    use super::*;
+    use crate::test_util::ingress_log;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.parse().unwrap(), value.parse().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_captured_path() {
+        let path = |uri: &str| captured_path(&uri.parse().unwrap());
+        assert_eq!(path("/ingress"), "");
+        assert_eq!(path("/ingress/"), "");
+        assert_eq!(path("/ingress/hooks/github?sample=1"), "hooks/github");
+        assert_eq!(path("/ingress/a//b%20c/"), "a//b%20c/");
+    }
+
+    #[test]
+    fn test_forwarded_client() {
+        let client = |pairs| forwarded_client(&headers(pairs)).map(|addr| addr.to_string());
+
+        assert_eq!(client(&[]), None);
+        assert_eq!(
+            client(&[("x-forwarded-for", "203.0.113.7, 10.0.0.1")]),
+            Some("203.0.113.7:0".to_string())
+        );
+        assert_eq!(
+            client(&[(
+                "forwarded",
+                r#"for="[2001:db8::1]:4711";proto=https, for=10.0.0.1"#
+            )]),
+            Some("[2001:db8::1]:4711".to_string())
+        );
+        assert_eq!(
+            client(&[("forwarded", "proto=http;For=192.0.2.60")]),
+            Some("192.0.2.60:0".to_string())
+        );
+        // Forwarded wins, even when it doesn't say who the client is
+        assert_eq!(
+            client(&[
+                ("forwarded", "for=unknown"),
+                ("x-forwarded-for", "203.0.113.7")
+            ]),
+            None
+        );
+    }
+
+    /// Capture a request the way the router would, returning the event id from the response
+    async fn capture_at(
+        state: &AppState,
+        uri: &str,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Result<Ulid, AppError> {
+        let uri: Uri = uri.parse().unwrap();
+        let query = Query::try_from_uri(&uri).unwrap();
+        let response = capture(
+            State(state.clone()),
+            ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 9000))),
+            Method::POST,
+            Host("stress.example.com".to_string()),
+            OriginalUri(uri),
+            query,
+            headers,
+            TraceId(Ulid::new()),
+            body,
+        )
+        .await?
+        .into_response();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let response = serde_json::from_slice::<IngressResponse>(&body).unwrap();
+        // the response names the event chaining the log
+        let event = state
+            .storage
+            .get(EVENTS_TREE, response.event_id.to_bytes())
+            .unwrap()
+            .unwrap();
+        let event: Event = bincode::deserialize(&event).unwrap();
+        assert_eq!(response.dag_event, hex::encode(event.id.hash));
+        Ok(response.event_id)
+    }
+
+    async fn capture_one(state: &AppState, n: usize) -> Ulid {
+        capture_at(
+            state,
+            "/ingress/stress",
+            HeaderMap::new(),
+            Bytes::from(n.to_string()),
+        )
+        .await
+        .unwrap()
+    }
+
+    fn stored_ids(state: &AppState) -> Vec<Ulid> {
+        let tree = state.storage.subtree("ingress").unwrap();
+        tree.scan_prefix(INGRESS_PREFIX)
+            .map(|item| {
+                let (key, value) = item.unwrap();
+                let log: IngressLog = versioned::decode(&value).unwrap();
+                assert_eq!(
+                    key,
+                    format!("{}{}", INGRESS_PREFIX, log.event_id).as_bytes()
+                );
+                log.event_id
+            })
+            .collect()
+    }
+
+    // A capture, by the ticks of a shared clock at which it was sent and answered
+    struct Timed {
+        sent: u64,
+        answered: u64,
+        event_id: Ulid,
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn test_concurrent_capture_order() {
+        const CLIENTS: usize = 32;
+        const CAPTURES: usize = 100;
+        let state = AppState::new_test().unwrap();
+        let clock = Arc::new(AtomicU64::new(0));
+
+        let clients = (0..CLIENTS).map(|client| {
+            let state = state.clone();
+            let clock = clock.clone();
+            tokio::spawn(async move {
+                let mut captures = Vec::new();
+                for i in 0..CAPTURES {
+                    let sent = clock.fetch_add(1, Ordering::SeqCst);
+                    let event_id = capture_one(&state, client * CAPTURES + i).await;
+                    let answered = clock.fetch_add(1, Ordering::SeqCst);
+                    captures.push(Timed {
+                        sent,
+                        answered,
+                        event_id,
+                    });
+                }
+                captures
+            })
+        });
+        let mut captures = Vec::new();
+        for client in clients {
+            let client = client.await.unwrap();
+            // each client sends its next capture once the last is answered
+            assert!(client.windows(2).all(|w| w[0].event_id < w[1].event_id));
+            captures.extend(client);
+        }
+
+        // every capture was stored once, under its own id, in id order
+        let mut ids: Vec<Ulid> = captures.iter().map(|c| c.event_id).collect();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), CLIENTS * CAPTURES);
+        assert_eq!(stored_ids(&state), ids);
+
+        // and chained in the same order, each event covering its log as it was stored
+        let ingress = state.storage.subtree("ingress").unwrap();
+        let events = state.storage.subtree(EVENTS_TREE).unwrap();
+        assert_eq!(events.len(), ids.len());
+        let mut heads = BTreeSet::new();
+        for (item, event_id) in events.iter().zip(&ids) {
+            let (key, value) = item.unwrap();
+            assert_eq!(key, event_id.to_bytes());
+            let event: Event = bincode::deserialize(&value).unwrap();
+            assert_eq!(event.precursors, heads);
+            let log = ingress
+                .get(format!("{}{}", INGRESS_PREFIX, event_id))
+                .unwrap()
+                .unwrap();
+            assert!(event.covers(&log));
+            heads = BTreeSet::from([event.id]);
+        }
+        assert_eq!(state.dag.heads(), heads);
+
+        // across clients too, a capture sorts after every one answered before it was sent
+        captures.sort_by_key(|c| c.answered);
+        let mut latest = Vec::with_capacity(captures.len());
+        for capture in &captures {
+            let before = latest.last().copied().unwrap_or(Ulid::nil());
+            latest.push(before.max(capture.event_id));
+        }
+        for capture in &captures {
+            let answered_before = captures.partition_point(|c| c.answered < capture.sent);
+            if answered_before > 0 {
+                assert!(latest[answered_before - 1] < capture.event_id);
+            }
+        }
+    }
+
+    fn fetch_all(state: &AppState, snapshot: Option<proto::SnapshotToken>) -> Vec<Vec<u8>> {
+        let mut keys: Vec<Vec<u8>> = Vec::new();
+        let mut cursor = proto::PaginatedCursor::StartingWith(vec![]);
+        let mut snapshot = snapshot;
+        loop {
+            let request = proto::FetchIngressLogsRequest {
+                direction: proto::Direction::Ascending,
+                limit: 50,
+                cursor,
+                preview_bytes: None,
+                snapshot,
+                source: None,
+                filter: None,
+                thumbnails: false,
+            };
+            let page = fetch_ingress_logs(request, state, CancelToken::new()).unwrap();
+            keys.extend(page.items.into_iter().map(|item| item.key));
+            if !page.has_more_after {
+                return keys;
+            }
+            cursor = proto::PaginatedCursor::After(keys.last().unwrap().clone());
+            snapshot = snapshot.map(|_| page.snapshot);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn test_pagination_during_captures() {
+        let state = AppState::new_test().unwrap();
+        for n in 0..500 {
+            capture_one(&state, n).await;
+        }
+        let seeded = stored_ids(&state);
+
+        let writers: Vec<_> = (0..8)
+            .map(|writer| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    for n in 0..200 {
+                        capture_one(&state, 1000 + writer * 200 + n).await;
+                    }
+                })
+            })
+            .collect();
+
+        let snapshot = {
+            let page = fetch_ingress_logs(
+                proto::FetchIngressLogsRequest {
+                    direction: proto::Direction::Ascending,
+                    limit: 1,
+                    cursor: proto::PaginatedCursor::StartingWith(vec![]),
+                    preview_bytes: None,
+                    snapshot: None,
+                    source: None,
+                    filter: None,
+                    thumbnails: false,
+                },
+                &state,
+                CancelToken::new(),
+            )
+            .unwrap();
+            page.snapshot
+        };
+        let reader = {
+            let state = state.clone();
+            tokio::task::spawn_blocking(move || {
+                (0..5)
+                    .map(|_| (fetch_all(&state, None), fetch_all(&state, Some(snapshot))))
+                    .collect::<Vec<_>>()
+            })
+        };
+        for writer in writers {
+            writer.await.unwrap();
+        }
+
+        let seeded: Vec<Vec<u8>> = seeded
+            .iter()
+            .map(|id| format!("{}{}", INGRESS_PREFIX, id).into_bytes())
+            .collect();
+        let passes = reader.await.unwrap();
+        for (latest, pinned) in &passes {
+            // pages never repeat or skip back, and never lose logs which were already there
+            for keys in [latest, pinned] {
+                assert!(keys.windows(2).all(|w| w[0] < w[1]));
+                assert!(seeded.iter().all(|key| keys.binary_search(key).is_ok()));
+            }
+            // paging from a snapshot sees the same logs however many are captured meanwhile
+            assert_eq!(pinned, &passes[0].1);
+        }
+        assert_eq!(fetch_all(&state, None).len(), 500 + 8 * 200);
+    }
+
+    #[tokio::test]
+    async fn test_source_capture() {
+        let sources = serde_json::from_value(serde_json::json!([
+            { "id": "github", "token": "t0ken" },
+            { "id": "stripe", "token": "s3cret" },
+        ]))
+        .unwrap();
+        let state = AppState::new_test_with_sources(Sources::new(sources).unwrap()).unwrap();
+        let log = |tree: &str, id: Ulid| -> IngressLog {
+            let key = format!("{}{}", INGRESS_PREFIX, id);
+            let value = state.storage.get(tree, key).unwrap().unwrap();
+            versioned::decode(&value).unwrap()
+        };
+
+        let id = capture_at(
+            &state,
+            "/ingress/github/push?token=t0ken&delivery=1",
+            HeaderMap::new(),
+            Bytes::new(),
+        )
+        .await
+        .unwrap();
+        let captured = log("ingress/github", id);
+        assert_eq!(captured.path, "push");
+        // the token isn't stored with the log
+        assert_eq!(captured.query.keys().collect::<Vec<_>>(), vec!["delivery"]);
+
+        let id = capture_at(
+            &state,
+            "/ingress/stripe",
+            headers(&[("authorization", "Bearer s3cret")]),
+            Bytes::new(),
+        )
+        .await
+        .unwrap();
+        assert!(!log("ingress/stripe", id)
+            .headers
+            .contains_key("authorization"));
+
+        for (uri, headers) in [
+            ("/ingress/github", HeaderMap::new()),
+            ("/ingress/github?token=s3cret", HeaderMap::new()),
+            (
+                "/ingress/github",
+                headers(&[("authorization", "Basic t0ken")]),
+            ),
+        ] {
+            let err = capture_at(&state, uri, headers, Bytes::new())
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Unauthorized);
+        }
+
+        // anything else is captured as before
+        let id = capture_at(
+            &state,
+            "/ingress/gitlab/push",
+            HeaderMap::new(),
+            Bytes::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(log("ingress", id).path, "gitlab/push");
+
+        let fetch = |source: &str| {
+            let request = proto::FetchIngressLogsRequest {
+                direction: proto::Direction::Ascending,
+                limit: 10,
+                cursor: proto::PaginatedCursor::StartingWith(vec![]),
+                preview_bytes: None,
+                snapshot: None,
+                source: Some(source.to_string()),
+                filter: None,
+                thumbnails: false,
+            };
+            fetch_ingress_logs(request, &state, CancelToken::new())
+        };
+        assert_eq!(fetch("github").unwrap().items.len(), 1);
+        assert_eq!(fetch("stripe").unwrap().items.len(), 1);
+        let err = fetch("gitlab").err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_log_detail() {
+        let state = AppState::new_test().unwrap();
+        let id = capture_at(
+            &state,
+            "/ingress/hooks",
+            HeaderMap::new(),
+            Bytes::from_static(b"\xff\x00"),
+        )
+        .await
+        .unwrap();
+        let detail = |event_id, source: Option<&str>| {
+            log_detail(
+                State(state.clone()),
+                Path(event_id),
+                Query(LogParams {
+                    source: source.map(str::to_string),
+                }),
+            )
+        };
+
+        let Json(found) = detail(id, None).await.unwrap();
+        assert_eq!(found["event_id"], id.to_string());
+        assert_eq!(found["path"], "hooks");
+        assert_eq!(found["body"], "/wA=");
+
+        let missing = detail(Ulid::new(), None).await.err().unwrap();
+        assert_eq!(missing.kind(), ErrorKind::NotFound);
+        let no_source = detail(id, Some("github")).await.err().unwrap();
+        assert_eq!(no_source.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_trace_id_migration() {
+        let state = AppState::new_test().unwrap();
+        let old = IngressLogV1 {
+            event_id: Ulid::new(),
+            date: chrono::Utc::now(),
+            remote_addr: None,
+            method: "POST".to_string(),
+            host: "old.example.com".to_string(),
+            path: "hooks".to_string(),
+            query: HashMap::new(),
+            headers: HashMap::new(),
+            body: Bytes::from_static(b"{}"),
+        };
+        let key = format!("{}{}", INGRESS_PREFIX, old.event_id);
+        // written before versions were recorded, to a tree not yet migrated
+        (state.storage)
+            .insert("legacy", &key, bincode::serialize(&old).unwrap())
+            .unwrap();
+        (state.storage)
+            .migrate_records::<IngressLog>(|tree| tree == "legacy")
+            .unwrap();
+        let value = state.storage.get("legacy", &key).unwrap().unwrap();
+        let log: IngressLog = versioned::decode(&value).unwrap();
+        assert_eq!(
+            (log.event_id, log.host.as_str()),
+            (old.event_id, "old.example.com")
+        );
+        assert_eq!(log.trace_id, None);
+    }
+
+    #[test]
+    fn test_fetch_filters() {
+        let state = AppState::new_test().unwrap();
+        // a log a second, numbered by the random part of its event id
+        let captured =
+            |n: u64| chrono::DateTime::from_timestamp(1_700_000_000 + n as i64, 0).unwrap();
+        for n in 0..30u64 {
+            let path = match n < 15 {
+                true => format!("hooks/github/{}", n),
+                false => format!("other/{}", n),
+            };
+            let log = IngressLog {
+                event_id: Ulid::from_parts(captured(n).timestamp_millis() as u64, n as u128),
+                date: captured(n),
+                method: if n % 2 == 0 { "POST" } else { "GET" }.to_string(),
+                host: if n % 3 == 0 {
+                    "a.example.com"
+                } else {
+                    "b.example.com"
+                }
+                .to_string(),
+                ..ingress_log(&path, b"")
+            };
+            let key = format!("{}{}", INGRESS_PREFIX, log.event_id);
+            state
+                .storage
+                .insert("ingress", key, bincode::serialize(&log).unwrap())
+                .unwrap();
+        }
+
+        // every matching log, paging through them three at a time
+        let fetch = |filter: proto::IngressLogFilter, direction: proto::Direction| {
+            let mut numbers = Vec::new();
+            let mut cursor = proto::PaginatedCursor::StartingWith(vec![]);
+            loop {
+                let request = proto::FetchIngressLogsRequest {
+                    direction,
+                    limit: 3,
+                    cursor,
+                    preview_bytes: None,
+                    snapshot: None,
+                    source: None,
+                    filter: Some(filter.clone()),
+                    thumbnails: false,
+                };
+                let page = fetch_ingress_logs(request, &state, CancelToken::new()).unwrap();
+                assert!(page.items.len() <= 3);
+                let last = page.items.last().map(|item| item.key.clone());
+                numbers.extend(
+                    page.items
+                        .iter()
+                        .map(|item| item.log.event_id.random() as u64),
+                );
+                match last {
+                    Some(last) if page.has_more_after => {
+                        cursor = proto::PaginatedCursor::After(last)
+                    }
+                    _ => return numbers,
+                }
+            }
+        };
+
+        let posted_to_github = proto::IngressLogFilter {
+            method: Some("post".to_string()),
+            path_prefix: Some("/hooks/github".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            fetch(posted_to_github, proto::Direction::Ascending),
+            vec![0, 2, 4, 6, 8, 10, 12, 14]
+        );
+
+        let between = proto::IngressLogFilter {
+            method: Some("GET".to_string()),
+            from: Some(captured(10)),
+            to: Some(captured(20)),
+            ..Default::default()
+        };
+        assert_eq!(
+            fetch(between, proto::Direction::Descending),
+            vec![19, 17, 15, 13, 11]
+        );
+
+        let host = proto::IngressLogFilter {
+            host: Some("A.example.com".to_string()),
+            from: Some(captured(20)),
+            ..Default::default()
+        };
+        assert_eq!(fetch(host, proto::Direction::Ascending), vec![21, 24, 27]);
+
+        // a page which ends on the last match says there are no more, however many logs follow
+        let request = proto::FetchIngressLogsRequest {
+            direction: proto::Direction::Ascending,
+            limit: 6,
+            cursor: proto::PaginatedCursor::StartingWith(vec![]),
+            preview_bytes: None,
+            snapshot: None,
+            source: None,
+            filter: Some(proto::IngressLogFilter {
+                path_prefix: Some("hooks/github/1".to_string()),
+                ..Default::default()
+            }),
+            thumbnails: false,
+        };
+        let page = fetch_ingress_logs(request, &state, CancelToken::new()).unwrap();
+        assert_eq!(page.items.len(), 6);
+        assert!(!page.has_more_after);
+
+        let nothing = proto::IngressLogFilter {
+            from: Some(captured(20)),
+            to: Some(captured(10)),
+            ..Default::default()
+        };
+        assert!(fetch(nothing, proto::Direction::Ascending).is_empty());
+    }
+}
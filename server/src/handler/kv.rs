@@ -0,0 +1,68 @@
+use hydra_proto as proto;
+
+use crate::{error::AppError, AppState};
+
+const KV_TREE: &str = "kv";
+
+/// Keys are scoped per tenant as `{tenant}\0{key}` so tenants can't read each other's values
+fn scoped_key(tenant: &str, key: &str) -> Result<Vec<u8>, AppError> {
+    if tenant.is_empty() || tenant.contains('\0') {
+        return Err(AppError::invalid_field(
+            "tenant",
+            format!("Invalid tenant name {:?}", tenant),
+        ));
+    }
+    let mut scoped = Vec::with_capacity(tenant.len() + key.len() + 1);
+    scoped.extend_from_slice(tenant.as_bytes());
+    scoped.push(0);
+    scoped.extend_from_slice(key.as_bytes());
+    Ok(scoped)
+}
+
+pub fn get_kv(
+    request: proto::GetKvRequest,
+    state: &AppState,
+) -> Result<proto::GetKvResponse, AppError> {
+    let key = scoped_key(&request.tenant, &request.key)?;
+    let value = state.storage.get(KV_TREE, key)?;
+    Ok(proto::GetKvResponse {
+        value: value.map(|v| v.to_vec()),
+    })
+}
+
+pub fn set_kv(
+    request: proto::SetKvRequest,
+    state: &AppState,
+) -> Result<proto::SetKvResponse, AppError> {
+    let key = scoped_key(&request.tenant, &request.key)?;
+
+    let Some(expected) = request.expected else {
+        // unconditional write
+        match &request.value {
+            Some(value) => state.storage.insert(KV_TREE, &key, value.clone())?,
+            None => state.storage.remove(KV_TREE, &key)?,
+        };
+        return Ok(proto::SetKvResponse {
+            success: true,
+            current: request.value,
+        });
+    };
+
+    let result = state.storage.compare_and_swap(
+        KV_TREE,
+        &key,
+        expected.as_deref(),
+        request.value.clone(),
+    )?;
+
+    Ok(match result {
+        Ok(()) => proto::SetKvResponse {
+            success: true,
+            current: request.value,
+        },
+        Err(conflict) => proto::SetKvResponse {
+            success: false,
+            current: conflict.current.map(|v| v.to_vec()),
+        },
+    })
+}
@@ -0,0 +1,219 @@
+//! Live queries: a fetch of a page of ingress logs which then follows it, pushing every log
+//! written on the cursor's side of the page which matches its filter. See
+//! proto::RequestPayload::LiveQuery.
+//!
+//! The subscription is made before the page is read, and the page is read as of a snapshot taken
+//! after that, so every write is either in the page or after its snapshot. Those after are held
+//! back until the page has been queued, see SubscriptionRegistry::release, so they reach the
+//! client after it.
+
+use std::ops::Bound;
+
+use hydra_proto as proto;
+
+use crate::{
+    cancel::CancelToken,
+    connection::Connection,
+    error::AppError,
+    handler::ingress::{captured_between, fetch_ingress_logs, log_tree, to_item, INGRESS_PREFIX},
+    query::prefix_range,
+    subscription::live::{ItemBuilder, LiveQuery},
+    tags,
+    worker::JobClass,
+    AppState,
+};
+
+/// The keys on the cursor's side of a page, those the client would page on to
+fn window(
+    cursor: &proto::PaginatedCursor,
+    direction: proto::Direction,
+) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    use proto::{Direction::*, PaginatedCursor::*};
+    match (cursor, direction) {
+        (After(key), Ascending) | (Before(key), Descending) => {
+            (Bound::Excluded(key.clone()), Bound::Unbounded)
+        }
+        (After(key), Descending) | (Before(key), Ascending) => {
+            (Bound::Unbounded, Bound::Excluded(key.clone()))
+        }
+        (StartingWith(key), Ascending) | (EndingWith(key), Descending) if !key.is_empty() => {
+            (Bound::Included(key.clone()), Bound::Unbounded)
+        }
+        (StartingWith(key), Descending) | (EndingWith(key), Ascending) if !key.is_empty() => {
+            (Bound::Unbounded, Bound::Included(key.clone()))
+        }
+        _ => (Bound::Unbounded, Bound::Unbounded),
+    }
+}
+
+pub async fn live_query(
+    request: proto::FetchIngressLogsRequest,
+    request_id: usize,
+    connection: &Connection,
+    state: &AppState,
+    cancel: CancelToken,
+) -> Result<proto::FetchIngressLogsResponse, AppError> {
+    if request.snapshot.is_some() {
+        return Err(AppError::invalid_field(
+            "snapshot",
+            "Live queries follow the latest logs, so can't be read at a snapshot",
+        ));
+    }
+    let tree = log_tree(state, request.source.as_deref())?;
+    let mut ranges = vec![
+        prefix_range(INGRESS_PREFIX.as_bytes()),
+        window(&request.cursor, request.direction),
+    ];
+    ranges.extend(request.filter.as_ref().map(captured_between));
+
+    let item_state = state.clone();
+    let item_tree = tree.clone();
+    let preview_bytes = request.preview_bytes;
+    let item: ItemBuilder = Box::new(move |key, log| {
+        let tags = tags::tags_of(&item_state.storage, &item_tree, &key)?;
+        let computed = item_state.computed.compute(&item_tree, &log);
+        Ok(to_item(key, log, tags, None, computed, preview_bytes))
+    });
+    let live = LiveQuery::new(
+        ranges,
+        request.filter.clone().unwrap_or_default(),
+        item,
+        cancel.clone(),
+    );
+    state.subscriptions.subscribe_live(
+        connection.id,
+        request_id,
+        tree,
+        live,
+        connection.outbound.clone(),
+    )?;
+
+    // taken once the subscription is in place, so nothing written after it can be missed. Reading
+    // at a snapshot also keeps the page out of the hot cache, which can be a few writes behind.
+    let request = proto::FetchIngressLogsRequest {
+        snapshot: Some(state.storage.token()),
+        ..request
+    };
+    let job_state = state.clone();
+    let page = state
+        .workers
+        .try_run(JobClass::Query, move || {
+            fetch_ingress_logs(request, &job_state, cancel)
+        })
+        .await;
+    if page.is_err() {
+        state.subscriptions.unsubscribe(connection.id, request_id);
+    }
+    page
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use proto::IngressLog;
+    use ulid::Ulid;
+
+    use super::*;
+    use crate::{cancel::InFlight, outbound, subscription::spawn_broker, test_util::ingress_log};
+
+    #[tokio::test]
+    async fn test_live_query() {
+        let state = AppState::new_test().unwrap();
+        spawn_broker(state.clone());
+        let store = |n: u64, host: &str| {
+            let log = IngressLog {
+                event_id: Ulid::from_parts(1_700_000_000_000 + n, 0),
+                host: host.to_string(),
+                ..ingress_log("hooks", n.to_string().as_bytes())
+            };
+            let key = format!("{}{}", INGRESS_PREFIX, log.event_id).into_bytes();
+            state
+                .storage
+                .insert("ingress", &key, bincode::serialize(&log).unwrap())
+                .unwrap();
+            key
+        };
+        let (outbound, mut receiver) = outbound::channel(16);
+        let connection = Connection {
+            id: state.subscriptions.next_connection_id(),
+            outbound,
+            requests: Arc::new(InFlight::new()),
+            admin: false,
+        };
+        let request = |cursor| proto::FetchIngressLogsRequest {
+            direction: proto::Direction::Descending,
+            limit: 10,
+            cursor,
+            preview_bytes: None,
+            snapshot: None,
+            source: None,
+            filter: Some(proto::IngressLogFilter {
+                host: Some("github.com".to_string()),
+                ..Default::default()
+            }),
+            thumbnails: false,
+        };
+        let first = store(1, "github.com");
+        store(2, "stripe.com");
+
+        let page = live_query(
+            request(proto::PaginatedCursor::StartingWith(vec![])),
+            7,
+            &connection,
+            &state,
+            CancelToken::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(page.items.len(), 1);
+        // written after the page was read, but before it was sent, so held back
+        let held = store(3, "github.com");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(receiver.try_recv().is_err());
+
+        // as if sent, after which what was held back and anything new that matches is pushed
+        state
+            .subscriptions
+            .release(connection.id, 7, page.snapshot.sequence);
+        store(4, "stripe.com");
+        let later = store(5, "github.com");
+        let mut pushed = Vec::new();
+        while pushed.len() < 2 {
+            let message = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            match message {
+                proto::Message::Response(proto::Response {
+                    request_id: 7,
+                    payload: proto::ResponsePayload::LiveQueryItem(item),
+                    ..
+                }) => pushed.push(item.key),
+                _ => panic!("expected a LiveQueryItem push"),
+            }
+        }
+        assert_eq!(pushed, vec![held, later]);
+
+        // the page's window: a page of logs older than the first has nothing newer to follow
+        let (lower, upper) = window(
+            &proto::PaginatedCursor::After(first.clone()),
+            proto::Direction::Descending,
+        );
+        assert_eq!((lower, upper), (Bound::Unbounded, Bound::Excluded(first)));
+        let err = live_query(
+            proto::FetchIngressLogsRequest {
+                snapshot: Some(page.snapshot),
+                ..request(proto::PaginatedCursor::StartingWith(vec![]))
+            },
+            8,
+            &connection,
+            &state,
+            CancelToken::new(),
+        )
+        .await
+        .err()
+        .unwrap();
+        assert_eq!(err.field(), Some("snapshot"));
+    }
+}
@@ -0,0 +1,215 @@
+use std::time::SystemTime;
+
+use hydra_proto as proto;
+use ulid::Ulid;
+
+use crate::{error::AppError, schemas, AppState};
+
+/// Collections live in their own trees under this prefix, so clients can't touch internal trees
+pub const COLLECTION_PREFIX: &str = "records/";
+
+fn collection_tree(collection: &str) -> Result<String, AppError> {
+    let valid = !collection.is_empty()
+        && collection
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid {
+        return Err(AppError::invalid_field(
+            "collection",
+            format!("Invalid collection name {:?}", collection),
+        ));
+    }
+    Ok(format!("{}{}", COLLECTION_PREFIX, collection))
+}
+
+/// The key for a record in trees which keep something about records on the side, eg. when
+/// they were last accessed: the record's collection tree, a 0 byte, then the record's own key
+pub fn side_key(tree: &str, key: &[u8]) -> Vec<u8> {
+    let mut side_key = Vec::with_capacity(tree.len() + 1 + key.len());
+    side_key.extend_from_slice(tree.as_bytes());
+    side_key.push(0);
+    side_key.extend_from_slice(key);
+    side_key
+}
+
+pub fn create_record(
+    request: proto::CreateRecordRequest,
+    state: &AppState,
+) -> Result<proto::CreateRecordResponse, AppError> {
+    let tree = collection_tree(&request.collection)?;
+    let id = Ulid::new();
+    // ids are ulids, so records sort by creation time
+    state
+        .storage
+        .insert(&tree, id.to_bytes(), &*request.value)?;
+    state
+        .schemas
+        .check(&state.storage, &tree, &id.to_bytes(), &request.value)?;
+    Ok(proto::CreateRecordResponse { id })
+}
+
+pub fn get_record(
+    request: proto::GetRecordRequest,
+    state: &AppState,
+) -> Result<proto::GetRecordResponse, AppError> {
+    let tree = collection_tree(&request.collection)?;
+    let key = request.id.to_bytes();
+    let value = state.storage.get(&tree, key)?;
+    if value.is_some() {
+        state.access.record(&tree, &key, SystemTime::now());
+    }
+    Ok(proto::GetRecordResponse {
+        value: value.map(|v| v.to_vec()),
+    })
+}
+
+pub fn update_record(
+    request: proto::UpdateRecordRequest,
+    state: &AppState,
+) -> Result<proto::UpdateRecordResponse, AppError> {
+    let tree = collection_tree(&request.collection)?;
+    let key = request.id.to_bytes();
+    loop {
+        let Some(previous) = state.storage.get(&tree, key)? else {
+            return Err(AppError::not_found(format!(
+                "No record {} in {}",
+                request.id, request.collection
+            )));
+        };
+        // compare-and-swap so that we never resurrect a record deleted in the meantime
+        let swapped = state.storage.compare_and_swap(
+            &tree,
+            key,
+            Some(&previous),
+            Some(request.value.clone()),
+        )?;
+        if swapped.is_ok() {
+            state
+                .schemas
+                .check(&state.storage, &tree, &key, &request.value)?;
+            state.access.record(&tree, &key, SystemTime::now());
+            return Ok(proto::UpdateRecordResponse {
+                previous: previous.to_vec(),
+            });
+        }
+    }
+}
+
+pub fn delete_record(
+    request: proto::DeleteRecordRequest,
+    state: &AppState,
+) -> Result<proto::DeleteRecordResponse, AppError> {
+    let tree = collection_tree(&request.collection)?;
+    let key = request.id.to_bytes();
+    let previous = state.storage.remove(&tree, key)?;
+    if previous.is_some() {
+        state.schemas.forget(&state.storage, &tree, &key)?;
+        state.access.forget(&tree, &key);
+    }
+    Ok(proto::DeleteRecordResponse {
+        previous: previous.map(|v| v.to_vec()),
+    })
+}
+
+pub fn set_collection_schema(
+    request: proto::SetCollectionSchemaRequest,
+    state: &AppState,
+) -> Result<proto::SetCollectionSchemaResponse, AppError> {
+    let tree = collection_tree(&request.collection)?;
+    let invalid = state
+        .schemas
+        .set(&state.storage, &tree, request.schema.as_deref())?;
+    Ok(proto::SetCollectionSchemaResponse { invalid })
+}
+
+/// The most invalid records returned at once
+const MAX_INVALID_RECORDS: u32 = 1000;
+
+pub fn fetch_invalid_records(
+    request: proto::FetchInvalidRecordsRequest,
+    state: &AppState,
+) -> Result<proto::FetchInvalidRecordsResponse, AppError> {
+    let tree = collection_tree(&request.collection)?;
+    let limit = request.limit.min(MAX_INVALID_RECORDS) as usize;
+    let (records, has_more) =
+        schemas::invalid_records(&state.storage, &tree, request.after, limit)?;
+    Ok(proto::FetchInvalidRecordsResponse { records, has_more })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_crud() {
+        let state = AppState::new_test().unwrap();
+        let collection = "notes".to_string();
+
+        let created = create_record(
+            proto::CreateRecordRequest {
+                collection: collection.clone(),
+                value: b"one".to_vec(),
+            },
+            &state,
+        )
+        .unwrap();
+
+        let updated = update_record(
+            proto::UpdateRecordRequest {
+                collection: collection.clone(),
+                id: created.id,
+                value: b"two".to_vec(),
+            },
+            &state,
+        )
+        .unwrap();
+        assert_eq!(updated.previous, b"one");
+        let request = proto::GetRecordRequest {
+            collection: collection.clone(),
+            id: created.id,
+        };
+        let fetched = get_record(request, &state).unwrap();
+        assert_eq!(fetched.value.as_deref(), Some(&b"two"[..]));
+
+        let deleted = delete_record(
+            proto::DeleteRecordRequest {
+                collection: collection.clone(),
+                id: created.id,
+            },
+            &state,
+        )
+        .unwrap();
+        assert_eq!(deleted.previous.as_deref(), Some(&b"two"[..]));
+
+        // the record is gone, so it can neither be updated nor deleted again
+        let request = proto::UpdateRecordRequest {
+            collection: collection.clone(),
+            id: created.id,
+            value: b"three".to_vec(),
+        };
+        assert_eq!(
+            update_record(request, &state).err().map(|e| e.kind()),
+            Some(proto::ErrorKind::NotFound)
+        );
+        let request = proto::DeleteRecordRequest {
+            collection: collection.clone(),
+            id: created.id,
+        };
+        assert!(delete_record(request, &state).unwrap().previous.is_none());
+        let request = proto::GetRecordRequest {
+            collection,
+            id: created.id,
+        };
+        assert!(get_record(request, &state).unwrap().value.is_none());
+
+        // collections can't reach outside of their prefix
+        let request = proto::CreateRecordRequest {
+            collection: "../ingress".to_string(),
+            value: vec![],
+        };
+        assert_eq!(
+            create_record(request, &state).err().map(|e| e.kind()),
+            Some(proto::ErrorKind::InvalidRequest)
+        );
+    }
+}
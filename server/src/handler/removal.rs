@@ -0,0 +1,276 @@
+//! Deleting and redacting ingress logs, as captured webhooks often carry secrets which have to be
+//! got rid of. Deleting removes a log outright; redacting keeps it, as a record that the request
+//! was made, but strips its body, headers and query. Either way a tombstone is written to
+//! TOMBSTONES_TREE along with the change, and pushed to its subscribers, so UIs can drop the log
+//! from what they show. The DAG event covering the log only holds a hash of it, so is left be.
+
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Path, Query, State},
+    Json,
+};
+use bytes::Bytes;
+use hydra_proto as proto;
+use proto::{IngressLog, IngressLogTombstone};
+use ulid::Ulid;
+
+use super::{
+    ingress::{log_tree, LogParams, INGRESS_PREFIX},
+    record::side_key,
+};
+use crate::{
+    error::AppError,
+    storage::{transaction::abort, versioned},
+    tags, thumbnail, AppState,
+};
+
+/// Tombstones of deleted and redacted logs, keyed by their tree and key
+pub const TOMBSTONES_TREE: &str = "ingress_tombstones";
+
+pub fn delete_ingress_log(
+    request: proto::RemoveIngressLogRequest,
+    state: &AppState,
+) -> Result<proto::RemoveIngressLogResponse, AppError> {
+    remove(request, state, false)
+}
+
+pub fn redact_ingress_log(
+    request: proto::RemoveIngressLogRequest,
+    state: &AppState,
+) -> Result<proto::RemoveIngressLogResponse, AppError> {
+    remove(request, state, true)
+}
+
+fn remove(
+    request: proto::RemoveIngressLogRequest,
+    state: &AppState,
+    redact: bool,
+) -> Result<proto::RemoveIngressLogResponse, AppError> {
+    let tree = log_tree(state, request.source.as_deref())?;
+    let key = format!("{}{}", INGRESS_PREFIX, request.event_id).into_bytes();
+    // a log captured with relaxed durability may still be queued
+    state.writer.write_queued(&state.storage)?;
+
+    let tombstone = IngressLogTombstone {
+        event_id: request.event_id,
+        tree: tree.clone(),
+        removed_at: chrono::Utc::now(),
+        redacted: redact,
+    };
+    let encoded_tombstone = bincode::serialize(&tombstone).map_err(anyhow::Error::from)?;
+    let removed = state.storage.transaction(&[&tree, TOMBSTONES_TREE], |tx| {
+        let Some(value) = tx.get(&tree, &key)? else {
+            return Ok(false);
+        };
+        if redact {
+            let log: IngressLog = match versioned::decode(&value) {
+                Ok(log) => log,
+                Err(e) => return abort(e),
+            };
+            match state.storage.encode(&redacted(log)) {
+                Ok(redacted) => tx.insert(&tree, &key, redacted)?,
+                Err(e) => return abort(e),
+            };
+        } else {
+            tx.remove(&tree, &key)?;
+        }
+        tx.insert(
+            TOMBSTONES_TREE,
+            side_key(&tree, &key),
+            encoded_tombstone.clone(),
+        )?;
+        Ok(true)
+    })?;
+    if !removed {
+        return Ok(proto::RemoveIngressLogResponse { tombstone: None });
+    }
+
+    if !redact {
+        tags::forget(&state.storage, &tree, &key)?;
+    }
+    thumbnail::forget(&state.storage, &tree, &key)?;
+    println!(
+        "{} log {} from {}",
+        if redact { "Redacted" } else { "Deleted" },
+        request.event_id,
+        tree
+    );
+    Ok(proto::RemoveIngressLogResponse {
+        tombstone: Some(tombstone),
+    })
+}
+
+/// The log with only what says when a request was made and where to
+fn redacted(log: IngressLog) -> IngressLog {
+    IngressLog {
+        query: HashMap::new(),
+        headers: HashMap::new(),
+        body: Bytes::new(),
+        ..log
+    }
+}
+
+/// DELETE /admin/logs/:event_id
+///
+/// Delete a log, from the shared ingress tree or with `?source=` from a source's logs, answering
+/// with its tombstone, or a 404 if there's no such log
+pub async fn delete(
+    State(state): State<AppState>,
+    Path(event_id): Path<Ulid>,
+    Query(params): Query<LogParams>,
+) -> Result<Json<IngressLogTombstone>, AppError> {
+    removed(event_id, params, &state, false)
+}
+
+/// POST /admin/logs/:event_id/redact
+///
+/// Redact a log, as DELETE /admin/logs/:event_id deletes one
+pub async fn redact(
+    State(state): State<AppState>,
+    Path(event_id): Path<Ulid>,
+    Query(params): Query<LogParams>,
+) -> Result<Json<IngressLogTombstone>, AppError> {
+    removed(event_id, params, &state, true)
+}
+
+fn removed(
+    event_id: Ulid,
+    params: LogParams,
+    state: &AppState,
+    redact: bool,
+) -> Result<Json<IngressLogTombstone>, AppError> {
+    let request = proto::RemoveIngressLogRequest {
+        event_id,
+        source: params.source,
+    };
+    remove(request, state, redact)?
+        .tombstone
+        .map(Json)
+        .ok_or_else(|| AppError::not_found(format!("No log {}", event_id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::ingress_log;
+    use hydra_error::ErrorKind;
+
+    #[test]
+    fn test_remove_ingress_logs() {
+        let state = AppState::new_test().unwrap();
+        let mut events = state.storage.subscribe();
+        let capture = |body: &[u8]| {
+            let event_id = Ulid::new();
+            let log = IngressLog {
+                event_id,
+                query: HashMap::from([("token".to_string(), "s3cret".to_string())]),
+                headers: HashMap::from([("authorization".to_string(), "s3cret".to_string())]),
+                ..ingress_log("hooks", body)
+            };
+            let encoded = state.storage.encode(&log).unwrap();
+            state
+                .storage
+                .insert(
+                    "ingress",
+                    format!("{}{}", INGRESS_PREFIX, event_id),
+                    encoded,
+                )
+                .unwrap();
+            event_id
+        };
+        let request = |event_id| proto::RemoveIngressLogRequest {
+            event_id,
+            source: None,
+        };
+        let stored = |event_id| {
+            let key = format!("{}{}", INGRESS_PREFIX, event_id);
+            (state.storage.get("ingress", key).unwrap())
+                .map(|value| versioned::decode::<IngressLog>(&value).unwrap())
+        };
+        let (redacting, deleting) = (capture(b"s3cret"), capture(b"s3cret"));
+
+        let tombstone = redact_ingress_log(request(redacting), &state)
+            .unwrap()
+            .tombstone
+            .unwrap();
+        assert!(tombstone.redacted);
+        let redacted = stored(redacting).unwrap();
+        assert_eq!(
+            (redacted.method.as_str(), redacted.path.as_str()),
+            ("POST", "hooks")
+        );
+        assert!(
+            redacted.body.is_empty() && redacted.headers.is_empty() && redacted.query.is_empty()
+        );
+
+        let tombstone = delete_ingress_log(request(deleting), &state)
+            .unwrap()
+            .tombstone
+            .unwrap();
+        assert!(!tombstone.redacted);
+        assert!(stored(deleting).is_none());
+        let key = format!("{}{}", INGRESS_PREFIX, deleting).into_bytes();
+        let kept = state
+            .storage
+            .get(TOMBSTONES_TREE, side_key("ingress", &key))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            bincode::deserialize::<IngressLogTombstone>(&kept).unwrap(),
+            tombstone
+        );
+
+        // subscribers hear of the removal, and of the tombstone
+        let mut heard = vec![];
+        while let Ok(event) = events.try_recv() {
+            heard.push((event.tree, event.value.is_some()));
+        }
+        assert!(heard.contains(&("ingress".to_string(), false)));
+        assert!(heard.contains(&(TOMBSTONES_TREE.to_string(), true)));
+
+        // a log which isn't there, or has already been deleted, has no tombstone to show
+        assert!(delete_ingress_log(request(deleting), &state)
+            .unwrap()
+            .tombstone
+            .is_none());
+        let source = proto::RemoveIngressLogRequest {
+            event_id: redacting,
+            source: Some("github".to_string()),
+        };
+        let err = redact_ingress_log(source, &state).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_removal_needs_admin() {
+        use axum::{
+            body::Body,
+            http::{header, Method, StatusCode},
+        };
+        use tower::ServiceExt;
+
+        let state = AppState::new_test().unwrap();
+        let app = crate::admin::routes(&state).with_state(state);
+        let event_id = Ulid::new();
+        for (method, uri) in [
+            (Method::DELETE, format!("/admin/logs/{}", event_id)),
+            (Method::POST, format!("/admin/logs/{}/redact", event_id)),
+        ] {
+            let remove = |authorization: Option<String>| {
+                let mut request = axum::http::Request::builder()
+                    .method(method.clone())
+                    .uri(&uri);
+                if let Some(authorization) = authorization {
+                    request = request.header(header::AUTHORIZATION, authorization);
+                }
+                app.clone().oneshot(request.body(Body::empty()).unwrap())
+            };
+            let response = remove(None).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{}", uri);
+            let admin = format!("Bearer {}", crate::admin::TEST_TOKEN);
+            let response = remove(Some(admin)).await.unwrap();
+            assert_ne!(response.status(), StatusCode::UNAUTHORIZED, "{}", uri);
+        }
+    }
+}
@@ -0,0 +1,253 @@
+//! Query by example: ranking the most recent logs by how much they have in common with one
+//! already captured. Each is scored out of 1:
+//!
+//! - 0.15 for the same method, and 0.15 for the same host, both ignoring case
+//! - 0.3 for the path, by how many of its leading segments are the same
+//! - 0.3 for the body's shape: for JSON bodies, how many of the paths to their values they share,
+//!   and otherwise whether neither is JSON
+//! - 0.1 for the body's size, by how close the two lengths are
+
+use std::collections::BTreeSet;
+
+use hydra_proto::{self as proto, IngressLog};
+use serde_json::Value;
+
+use crate::{
+    cancel::CancelToken,
+    error::AppError,
+    handler::ingress::{log_tree, to_item, INGRESS_PREFIX},
+    storage::versioned,
+    tags, AppState,
+};
+
+/// How many of the most recent logs are compared
+pub const MAX_SCANNED: usize = 10_000;
+
+/// The most similar logs returned at once
+pub const MAX_SIMILAR: usize = 100;
+
+/// How many logs are compared between checks for cancellation
+const CANCEL_CHECK_EVERY: u64 = 1000;
+
+/// What's compared of a log, worked out once for the example
+struct Shape<'a> {
+    log: &'a IngressLog,
+    segments: Vec<&'a str>,
+    // None if the body isn't JSON
+    keys: Option<BTreeSet<String>>,
+}
+
+impl<'a> Shape<'a> {
+    fn of(log: &'a IngressLog) -> Self {
+        Self {
+            log,
+            segments: log.path.split('/').filter(|s| !s.is_empty()).collect(),
+            keys: serde_json::from_slice(&log.body)
+                .ok()
+                .map(|body: Value| json_paths(&body)),
+        }
+    }
+
+    fn similarity(&self, other: &Shape) -> f32 {
+        let same = |a: &str, b: &str| match a.eq_ignore_ascii_case(b) {
+            true => 1.0,
+            false => 0.0,
+        };
+        let body = match (&self.keys, &other.keys) {
+            (Some(ours), Some(theirs)) => overlap(ours, theirs),
+            (None, None) => 1.0,
+            _ => 0.0,
+        };
+        0.15 * same(&self.log.method, &other.log.method)
+            + 0.15 * same(&self.log.host, &other.log.host)
+            + 0.3 * path_similarity(&self.segments, &other.segments)
+            + 0.3 * body
+            + 0.1 * ratio(self.log.body.len(), other.log.body.len())
+    }
+}
+
+/// The paths to every value in a JSON document, eg. `event.type` and `items[].id`, with the
+/// elements of an array all under the same path
+fn json_paths(value: &Value) -> BTreeSet<String> {
+    fn walk(value: &Value, path: &str, paths: &mut BTreeSet<String>) {
+        match value {
+            Value::Object(members) => {
+                for (name, member) in members {
+                    let path = match path.is_empty() {
+                        true => name.clone(),
+                        false => format!("{}.{}", path, name),
+                    };
+                    paths.insert(path.clone());
+                    walk(member, &path, paths);
+                }
+            }
+            Value::Array(elements) => {
+                let path = format!("{}[]", path);
+                for element in elements {
+                    walk(element, &path, paths);
+                }
+            }
+            _ => {}
+        }
+    }
+    let mut paths = BTreeSet::new();
+    walk(value, "", &mut paths);
+    paths
+}
+
+/// How much two sets have in common, as the share of all their members which both have. Two
+/// empty sets, eg. two `{}` bodies, are the same.
+fn overlap(a: &BTreeSet<String>, b: &BTreeSet<String>) -> f32 {
+    let all = a.union(b).count();
+    match all {
+        0 => 1.0,
+        all => a.intersection(b).count() as f32 / all as f32,
+    }
+}
+
+/// The share of the longer path's segments which the two start with
+fn path_similarity(a: &[&str], b: &[&str]) -> f32 {
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 1.0;
+    }
+    let shared = a.iter().zip(b).take_while(|(a, b)| a == b).count();
+    shared as f32 / longest as f32
+}
+
+fn ratio(a: usize, b: usize) -> f32 {
+    match a.max(b) {
+        0 => 1.0,
+        largest => a.min(b) as f32 / largest as f32,
+    }
+}
+
+pub fn find_similar(
+    request: proto::FindSimilarRequest,
+    state: &AppState,
+    cancel: CancelToken,
+) -> Result<proto::FindSimilarResponse, AppError> {
+    let tree = log_tree(state, request.source.as_deref())?;
+    let example: IngressLog = match state.storage.get(&tree, &request.key)? {
+        Some(value) => versioned::decode(&value)?,
+        None => return Err(AppError::not_found("No such log to compare with")),
+    };
+    let example = Shape::of(&example);
+
+    let mut scanned = 0;
+    let mut ranked = Vec::new();
+    let logs = state.storage.subtree(&tree)?;
+    for entry in logs.scan_prefix(INGRESS_PREFIX).rev().take(MAX_SCANNED) {
+        let (key, value) = entry?;
+        scanned += 1;
+        if scanned % CANCEL_CHECK_EVERY == 0 {
+            cancel.check()?;
+        }
+        if *key == *request.key {
+            continue;
+        }
+        let log: IngressLog = versioned::decode(&value)?;
+        let score = example.similarity(&Shape::of(&log));
+        ranked.push((score, key.to_vec(), log));
+    }
+    // a stable sort, so the most recent come first among equals
+    ranked.sort_by(|(a, _, _), (b, _, _)| b.total_cmp(a));
+    ranked.truncate(request.limit.min(MAX_SIMILAR));
+
+    Ok(proto::FindSimilarResponse {
+        items: ranked
+            .into_iter()
+            .map(|(score, key, log)| {
+                let tags = tags::tags_of(&state.storage, &tree, &key)?;
+                let computed = state.computed.compute(&tree, &log);
+                Ok(proto::SimilarLog {
+                    item: to_item(key, log, tags, None, computed, request.preview_bytes),
+                    score,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?,
+        scanned,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use ulid::Ulid;
+
+    use super::*;
+    use crate::test_util::ingress_log;
+
+    #[test]
+    fn test_find_similar() {
+        let state = AppState::new_test().unwrap();
+        let store = |n: u64, method: &str, path: &str, body: &str| {
+            let log = IngressLog {
+                event_id: Ulid::from_parts(1_700_000_000_000 + n, 0),
+                method: method.to_string(),
+                ..ingress_log(path, body.as_bytes())
+            };
+            let key = format!("{}{}", INGRESS_PREFIX, log.event_id).into_bytes();
+            state
+                .storage
+                .insert("ingress", &key, bincode::serialize(&log).unwrap())
+                .unwrap();
+            key
+        };
+        let problem = store(
+            0,
+            "POST",
+            "hooks/github",
+            r#"{"action": "opened", "pull_request": {"id": 1, "labels": [{"name": "bug"}]}}"#,
+        );
+        let same_shape = store(
+            1,
+            "POST",
+            "hooks/github",
+            r#"{"action": "closed", "pull_request": {"id": 22, "labels": []}}"#,
+        );
+        let other_event = store(
+            2,
+            "POST",
+            "hooks/github",
+            r#"{"ref": "main", "commits": []}"#,
+        );
+        let other_path = store(3, "POST", "hooks/stripe", r#"{"type": "charge.succeeded"}"#);
+        let unrelated = store(4, "GET", "health", "ok");
+
+        let find = |key: &[u8], limit| {
+            find_similar(
+                proto::FindSimilarRequest {
+                    key: key.to_vec(),
+                    source: None,
+                    limit,
+                    preview_bytes: None,
+                },
+                &state,
+                CancelToken::new(),
+            )
+        };
+        let found = find(&problem, 10).unwrap();
+        assert_eq!(found.scanned, 5);
+        let keys: Vec<&[u8]> = found.items.iter().map(|s| &s.item.key[..]).collect();
+        assert_eq!(
+            keys,
+            vec![&same_shape, &other_event, &other_path, &unrelated]
+        );
+        let scores: Vec<f32> = found.items.iter().map(|s| s.score).collect();
+        assert!(
+            scores.windows(2).all(|pair| pair[0] > pair[1]),
+            "{:?}",
+            scores
+        );
+        assert!(scores[0] > 0.9 && scores[3] < 0.2, "{:?}", scores);
+
+        assert_eq!(find(&problem, 1).unwrap().items.len(), 1);
+        let err = find(b"test|nothing", 10).err().unwrap();
+        assert_eq!(err.kind(), hydra_error::ErrorKind::NotFound);
+
+        assert_eq!(
+            json_paths(&serde_json::json!({"a": {"b": [{"c": 1}, {"d": 2}]}})),
+            BTreeSet::from(["a", "a.b", "a.b[].c", "a.b[].d"].map(String::from))
+        );
+    }
+}
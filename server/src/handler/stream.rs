@@ -0,0 +1,196 @@
+//! GET /ingress-stream, which tails the logs captured from then on as Server-Sent Events, for
+//! dashboards and `curl -N` which don't want to speak the WebSocket protocol. Each log is sent as
+//! a `log` event whose data is a JSON summary of it, without its headers, query or body, and whose
+//! id is its event id. A client which falls behind is sent a `lagged` event saying how many writes
+//! it missed, and carries on from there. It isn't under /ingress, where every path is captured.
+
+use std::{convert::Infallible, time::Duration};
+
+use axum::{
+    extract::{Query, State},
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures_util::{Stream, StreamExt};
+use hydra_proto as proto;
+use proto::IngressLog;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use ulid::Ulid;
+
+use super::ingress::{log_tree, INGRESS_PREFIX};
+use crate::{
+    error::AppError,
+    storage::{versioned, StorageOp},
+    AppState,
+};
+
+/// How often an idle stream is sent a comment, so proxies don't time it out
+const KEEP_ALIVE_EVERY: Duration = Duration::from_secs(15);
+
+/// Each log captured in a tree from now on, until the server shuts down. Logs which are rewritten,
+/// ie. redacted, aren't new, so aren't sent again. Err gives how many writes a subscriber which
+/// fell behind missed, in which case it may have missed logs.
+pub fn captured_logs(
+    state: &AppState,
+    tree: String,
+) -> impl Stream<Item = Result<IngressLog, u64>> + Send + 'static {
+    let events = state.storage.subscribe();
+    let shutdown = state.shutdown.subscribe();
+    // streams end when the server shuts down, so they don't hold it up
+    futures_util::stream::unfold(
+        (events, shutdown, tree),
+        |(mut events, mut shutdown, tree)| async move {
+            loop {
+                let event = tokio::select! {
+                    event = events.recv() => event,
+                    _ = shutdown.wait_for(|shutdown| *shutdown) => return None,
+                };
+                let captured = match event {
+                    Ok(event)
+                        if event.tree == tree
+                            && event.op == StorageOp::Insert
+                            && event.previous.is_none()
+                            && event.key.starts_with(INGRESS_PREFIX.as_bytes()) =>
+                    {
+                        let value = event.value.unwrap_or_default();
+                        match versioned::decode(&value) {
+                            Ok(log) => Ok(log),
+                            Err(e) => {
+                                println!("Failed to decode {} log for a stream: {:?}", tree, e);
+                                continue;
+                            }
+                        }
+                    }
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(missed)) => Err(missed),
+                    Err(RecvError::Closed) => return None,
+                };
+                return Some((captured, (events, shutdown, tree)));
+            }
+        },
+    )
+}
+
+#[derive(Deserialize)]
+pub struct StreamParams {
+    // a source's logs rather than the shared ingress tree's
+    source: Option<String>,
+    // only logs whose path starts with this, with or without a leading slash
+    path_prefix: Option<String>,
+}
+
+/// What's sent of each log
+#[derive(Serialize, Deserialize)]
+pub struct LogSummary {
+    pub event_id: Ulid,
+    pub date: chrono::DateTime<chrono::Utc>,
+    pub source: Option<String>,
+    pub method: String,
+    pub host: String,
+    pub path: String,
+    pub body_bytes: usize,
+}
+
+/// GET /ingress-stream
+pub async fn stream(
+    State(state): State<AppState>,
+    Query(params): Query<StreamParams>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AppError> {
+    let tree = log_tree(&state, params.source.as_deref())?;
+    let filter = proto::IngressLogFilter {
+        path_prefix: params.path_prefix,
+        ..Default::default()
+    };
+    let source = params.source;
+    let events = captured_logs(&state, tree).filter_map(move |captured| {
+        let event = match captured {
+            Ok(log) if !filter.matches(&log) => None,
+            Ok(log) => {
+                let summary = LogSummary {
+                    event_id: log.event_id,
+                    date: log.date,
+                    source: source.clone(),
+                    method: log.method,
+                    host: log.host,
+                    path: log.path,
+                    body_bytes: log.body.len(),
+                };
+                Event::default()
+                    .event("log")
+                    .id(log.event_id.to_string())
+                    .json_data(summary)
+                    .ok()
+            }
+            Err(missed) => Some(
+                Event::default()
+                    .event("lagged")
+                    .data(format!(r#"{{"missed":{}}}"#, missed)),
+            ),
+        };
+        std::future::ready(event.map(Ok))
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::new().interval(KEEP_ALIVE_EVERY)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::response::IntoResponse;
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::handler::batch::{capture_all, Capture};
+
+    #[tokio::test]
+    async fn test_stream() {
+        let state = AppState::new_test().unwrap();
+        let params = StreamParams {
+            source: None,
+            path_prefix: Some("/hooks".to_string()),
+        };
+        let response = stream(State(state.clone()), Query(params))
+            .await
+            .unwrap()
+            .into_response();
+        let mut body = response.into_body().into_data_stream();
+
+        let capture = |path: &str| Capture {
+            tree: "ingress".to_string(),
+            method: "POST".to_string(),
+            host: "example.com".to_string(),
+            path: path.to_string(),
+            query: HashMap::new(),
+            headers: HashMap::new(),
+            body: Bytes::from_static(b"{\"secret\":1}"),
+        };
+        let captured = capture_all(
+            &state,
+            None,
+            Ulid::new(),
+            &[capture("other"), capture("hooks/github")],
+        )
+        .await
+        .unwrap();
+
+        // only the log under the prefix is sent, and only its summary
+        let frame = tokio::time::timeout(Duration::from_secs(5), body.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let frame = String::from_utf8(frame.to_vec()).unwrap();
+        assert!(frame.starts_with("event: log\n"), "{}", frame);
+        assert!(frame.contains(&format!("id: {}\n", captured[1].event_id)));
+        let data = frame
+            .lines()
+            .find_map(|line| line.strip_prefix("data: "))
+            .unwrap();
+        let summary: LogSummary = serde_json::from_str(data).unwrap();
+        assert_eq!(
+            (summary.path.as_str(), summary.source, summary.body_bytes),
+            ("hooks/github", None, 12)
+        );
+        assert!(!frame.contains("secret"));
+    }
+}
@@ -0,0 +1,227 @@
+//! Allocation tracking, enabled with the `heap-profiling` feature.
+//!
+//! Wraps the system allocator to keep running totals of heap usage, and records a backtrace
+//! for roughly one allocation per SAMPLE_INTERVAL bytes allocated. Samples are forgotten when
+//! their allocation is freed, so the report shows where the memory which is still live came
+//! from, eg. to find what is growing in a long-running server.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    backtrace::Backtrace,
+    cell::Cell,
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use axum::http::header;
+use axum::response::IntoResponse;
+
+/// On average, one allocation is sampled per this many bytes allocated on a thread
+const SAMPLE_INTERVAL: usize = 512 * 1024;
+
+/// How many allocation sites to include in a report
+const REPORT_SITES: usize = 50;
+
+#[global_allocator]
+static ALLOCATOR: TrackingAllocator = TrackingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static FREED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static SAMPLED: AtomicUsize = AtomicUsize::new(0);
+static SAMPLES: Mutex<BTreeMap<usize, Sample>> = Mutex::new(BTreeMap::new());
+
+thread_local! {
+    // set while the profiler itself is allocating, so that it doesn't recurse into itself
+    static IN_PROFILER: Cell<bool> = const { Cell::new(false) };
+    // set while formatting a report: symbolizing holds the backtrace lock, which capturing a
+    // sample would try to take again
+    static NO_SAMPLING: Cell<bool> = const { Cell::new(false) };
+    static UNTIL_SAMPLE: Cell<usize> = const { Cell::new(SAMPLE_INTERVAL) };
+}
+
+struct Sample {
+    size: usize,
+    backtrace: Arc<Backtrace>,
+}
+
+pub struct TrackingAllocator;
+
+unsafe impl GlobalAlloc for TrackingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            record_alloc(ptr, layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            record_alloc(ptr, layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        record_free(ptr, layout.size());
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            record_free(ptr, layout.size());
+            record_alloc(new_ptr, new_size);
+        }
+        new_ptr
+    }
+}
+
+/// Run f unless the profiler is already running on this thread
+fn guarded<R>(f: impl FnOnce() -> R) -> Option<R> {
+    IN_PROFILER
+        .try_with(|in_profiler| {
+            if in_profiler.get() {
+                return None;
+            }
+            in_profiler.set(true);
+            let result = f();
+            in_profiler.set(false);
+            Some(result)
+        })
+        .ok()
+        .flatten()
+}
+
+fn record_alloc(ptr: *mut u8, size: usize) {
+    let allocated = ALLOCATED.fetch_add(size, Ordering::Relaxed) + size;
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    PEAK.fetch_max(
+        allocated.saturating_sub(FREED.load(Ordering::Relaxed)),
+        Ordering::Relaxed,
+    );
+
+    if NO_SAMPLING.try_with(Cell::get).unwrap_or(true) {
+        return;
+    }
+    let sample = UNTIL_SAMPLE
+        .try_with(|until| match until.get().checked_sub(size) {
+            Some(remaining) if remaining > 0 => {
+                until.set(remaining);
+                false
+            }
+            _ => {
+                until.set(SAMPLE_INTERVAL);
+                true
+            }
+        })
+        .unwrap_or(false);
+
+    if sample {
+        guarded(|| {
+            let backtrace = Arc::new(Backtrace::force_capture());
+            SAMPLES
+                .lock()
+                .unwrap()
+                .insert(ptr as usize, Sample { size, backtrace });
+            SAMPLED.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+}
+
+fn record_free(ptr: *mut u8, size: usize) {
+    FREED.fetch_add(size, Ordering::Relaxed);
+    if SAMPLED.load(Ordering::Relaxed) > 0 {
+        guarded(|| {
+            if SAMPLES.lock().unwrap().remove(&(ptr as usize)).is_some() {
+                SAMPLED.fetch_sub(1, Ordering::Relaxed);
+            }
+        });
+    }
+}
+
+/// Render the current heap statistics and the allocation sites holding the most sampled memory
+pub fn report() -> String {
+    let allocated = ALLOCATED.load(Ordering::Relaxed);
+    let freed = FREED.load(Ordering::Relaxed);
+
+    // copy the samples out, so that formatting them doesn't happen under the lock
+    let samples: Vec<(usize, Arc<Backtrace>)> = guarded(|| {
+        SAMPLES
+            .lock()
+            .unwrap()
+            .values()
+            .map(|sample| (sample.size, sample.backtrace.clone()))
+            .collect()
+    })
+    .unwrap_or_default();
+
+    let mut sites: HashMap<String, (usize, usize)> = HashMap::new();
+    NO_SAMPLING.with(|no_sampling| no_sampling.set(true));
+    for (size, backtrace) in &samples {
+        let site = sites.entry(backtrace.to_string()).or_default();
+        site.0 += size;
+        site.1 += 1;
+    }
+    NO_SAMPLING.with(|no_sampling| no_sampling.set(false));
+    let mut sites: Vec<(String, (usize, usize))> = sites.into_iter().collect();
+    sites.sort_by_key(|(_, (bytes, _))| std::cmp::Reverse(*bytes));
+
+    let mut out = String::new();
+    let _ = writeln!(out, "live bytes:       {}", allocated.saturating_sub(freed));
+    let _ = writeln!(out, "peak live bytes:  {}", PEAK.load(Ordering::Relaxed));
+    let _ = writeln!(out, "total allocated:  {}", allocated);
+    let _ = writeln!(
+        out,
+        "allocations:      {}",
+        ALLOCATIONS.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "live samples:     {} (one per ~{} bytes allocated)",
+        samples.len(),
+        SAMPLE_INTERVAL
+    );
+    for (backtrace, (bytes, count)) in sites.iter().take(REPORT_SITES) {
+        let _ = writeln!(
+            out,
+            "\n--- {} sampled bytes in {} allocations\n{}",
+            bytes, count, backtrace
+        );
+    }
+    out
+}
+
+/// GET /admin/heap
+pub async fn heap_profile() -> impl IntoResponse {
+    // symbolizing backtraces is slow, keep it off the async runtime threads
+    let report = tokio::task::spawn_blocking(report)
+        .await
+        .unwrap_or_else(|e| format!("Failed to build heap report: {:?}", e));
+    ([(header::CONTENT_TYPE, "text/plain")], report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heap_report() {
+        let before = ALLOCATED.load(Ordering::Relaxed);
+        // larger than the sample interval, so it is certain to be sampled
+        let big = vec![1u8; SAMPLE_INTERVAL * 2];
+        assert!(ALLOCATED.load(Ordering::Relaxed) >= before + big.len());
+
+        let report = report();
+        assert!(report.contains("live bytes:"));
+        assert!(report.contains("test_heap_report"));
+        drop(big);
+    }
+}
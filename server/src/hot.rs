@@ -0,0 +1,293 @@
+//! A cache of the newest logs in each ingress tree, so the page asked for most, the newest logs
+//! first with no filter, as a dashboard or live tail shows on opening, is served from memory
+//! without reading sled. `--hot-cache` sets how many logs are kept per tree, 0 turning it off.
+//!
+//! Each tree's newest logs are read at startup, and kept up to date by a storage hook, which
+//! unlike a subscriber to the event bus sees every write. Hooks run once their write has been
+//! applied, so two writes' hooks can run in either order. The cache applies writes strictly in
+//! sequence, holding back any which arrive early, so what it holds is always a tree as of some
+//! snapshot, and a page from it comes with that snapshot's token to page on from.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::Result;
+use hydra_proto::{self as proto, IngressLog};
+use serde::Serialize;
+use sled::IVec;
+
+use crate::{
+    handler::ingress::INGRESS_PREFIX,
+    query::{FetchResultItem, PaginatedFetchResponse},
+    storage::{versioned, StorageEngine, StorageEvent, StorageOp},
+};
+
+/// Logs kept per tree unless --hot-cache says otherwise
+pub const DEFAULT_CAPACITY: usize = 500;
+
+pub struct HotCache {
+    capacity: usize,
+    inner: Arc<Mutex<Inner>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Inner {
+    trees: HashMap<String, Ring>,
+    epoch: ulid::Ulid,
+    // every write up to this sequence has been applied
+    through: u64,
+    // writes whose hooks ran before those of writes before them, by sequence
+    early: BTreeMap<u64, StorageEvent>,
+}
+
+/// The newest logs in a tree
+struct Ring {
+    logs: BTreeMap<IVec, IVec>,
+    // whether these are all the tree's logs, rather than the newest of more
+    whole: bool,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct HotCacheStats {
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+    // hits as a share of every fetch which asked for the newest page
+    pub hit_rate: f64,
+    pub trees: BTreeMap<String, usize>,
+}
+
+impl HotCache {
+    /// Read in the newest logs of each of `trees`, and keep them up to date from then on
+    pub fn new(storage: &StorageEngine, capacity: usize, trees: Vec<String>) -> Result<Self> {
+        let token = storage.token();
+        let mut inner = Inner {
+            trees: HashMap::new(),
+            epoch: token.epoch,
+            through: token.sequence,
+            early: BTreeMap::new(),
+        };
+        if capacity > 0 {
+            for tree in trees {
+                let mut logs = BTreeMap::new();
+                for item in storage
+                    .subtree(&tree)?
+                    .scan_prefix(INGRESS_PREFIX)
+                    .rev()
+                    .take(capacity + 1)
+                {
+                    let (key, value) = item?;
+                    logs.insert(key, value);
+                }
+                let whole = logs.len() <= capacity;
+                if !whole {
+                    logs.pop_first();
+                }
+                inner.trees.insert(tree, Ring { logs, whole });
+            }
+        }
+        let inner = Arc::new(Mutex::new(inner));
+        if capacity > 0 {
+            let hooked = inner.clone();
+            storage.add_hook(move |event| hooked.lock().unwrap().written(event, capacity));
+        }
+        Ok(Self {
+            capacity,
+            inner,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })
+    }
+
+    /// The newest `limit` logs in a tree, newest first, if they're all in the cache
+    pub fn newest(
+        &self,
+        tree: &str,
+        limit: usize,
+    ) -> Option<Result<PaginatedFetchResponse<IngressLog>>> {
+        let inner = self.inner.lock().unwrap();
+        let ring = inner.trees.get(tree)?;
+        if limit > ring.logs.len() && !ring.whole {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        let items = ring
+            .logs
+            .iter()
+            .rev()
+            .take(limit)
+            .map(|(key, value)| {
+                Ok(FetchResultItem {
+                    key: key.to_vec(),
+                    item: versioned::decode(value)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>();
+        Some(items.map(|items| PaginatedFetchResponse {
+            // the newest are first
+            position: (!items.is_empty()).then_some(0),
+            items,
+            limit,
+            has_more_before: false,
+            has_more_after: ring.logs.len() > limit || !ring.whole,
+            snapshot: proto::SnapshotToken {
+                epoch: inner.epoch,
+                sequence: inner.through,
+            },
+            // the cache doesn't count the logs, see StorageEngine::count
+            total_count: None,
+        }))
+    }
+
+    pub fn stats(&self) -> HotCacheStats {
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        HotCacheStats {
+            capacity: self.capacity,
+            hits,
+            misses,
+            hit_rate: match hits + misses {
+                0 => 0.0,
+                all => hits as f64 / all as f64,
+            },
+            trees: (self.inner.lock().unwrap().trees.iter())
+                .map(|(tree, ring)| (tree.clone(), ring.logs.len()))
+                .collect(),
+        }
+    }
+}
+
+impl Inner {
+    fn written(&mut self, event: &StorageEvent, capacity: usize) {
+        if event.sequence <= self.through {
+            return;
+        }
+        self.early.insert(event.sequence, event.clone());
+        while let Some(event) = self.early.remove(&(self.through + 1)) {
+            self.through = event.sequence;
+            self.apply(&event, capacity);
+        }
+    }
+
+    fn apply(&mut self, event: &StorageEvent, capacity: usize) {
+        if !event.key.starts_with(INGRESS_PREFIX.as_bytes()) {
+            return;
+        }
+        let Some(ring) = self.trees.get_mut(&event.tree) else {
+            return;
+        };
+        match (&event.op, &event.value) {
+            (StorageOp::Insert, Some(value)) => {
+                // older than the oldest kept, and there are older still which aren't
+                let oldest = ring.logs.first_key_value().map(|(key, _)| key);
+                if !ring.whole && oldest.is_some_and(|oldest| event.key < *oldest) {
+                    return;
+                }
+                ring.logs.insert(event.key.clone(), value.clone());
+                if ring.logs.len() > capacity {
+                    ring.logs.pop_first();
+                    ring.whole = false;
+                }
+            }
+            _ => {
+                ring.logs.remove(&event.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ulid::Ulid;
+
+    use super::*;
+    use crate::test_util::ingress_log;
+
+    fn store(storage: &StorageEngine, n: u64) -> Vec<u8> {
+        let log = IngressLog {
+            event_id: Ulid::from_parts(1_700_000_000_000 + n, 0),
+            ..ingress_log("hooks", n.to_string().as_bytes())
+        };
+        let key = format!("{}{}", INGRESS_PREFIX, log.event_id).into_bytes();
+        storage
+            .insert("ingress", &key, bincode::serialize(&log).unwrap())
+            .unwrap();
+        key
+    }
+
+    fn bodies(page: &PaginatedFetchResponse<IngressLog>) -> Vec<String> {
+        page.items
+            .iter()
+            .map(|item| String::from_utf8_lossy(&item.item.body).into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_hot_cache() {
+        let storage = StorageEngine::new_test().unwrap();
+        for n in 0..3 {
+            store(&storage, n);
+        }
+        let cache = HotCache::new(&storage, 4, vec!["ingress".to_string()]).unwrap();
+
+        // all of a small tree is there, however much is asked for
+        let page = cache.newest("ingress", 10).unwrap().unwrap();
+        assert_eq!(bodies(&page), vec!["2", "1", "0"]);
+        assert!(!page.has_more_after);
+
+        // new logs push out the oldest
+        let mut keys = Vec::new();
+        for n in 3..6 {
+            keys.push(store(&storage, n));
+        }
+        let page = cache.newest("ingress", 2).unwrap().unwrap();
+        assert_eq!(bodies(&page), vec!["5", "4"]);
+        assert!(page.has_more_after);
+        assert_eq!(page.snapshot, storage.token());
+        assert_eq!(
+            bodies(&cache.newest("ingress", 4).unwrap().unwrap()),
+            vec!["5", "4", "3", "2"]
+        );
+        // more than the cache holds has to be read from sled
+        assert!(cache.newest("ingress", 5).is_none());
+        assert!(cache.newest("other", 1).is_none());
+
+        // removals are followed, and whatever's older than the oldest kept is left out
+        storage.remove("ingress", &keys[2]).unwrap();
+        store(&storage, 1);
+        assert_eq!(
+            bodies(&cache.newest("ingress", 3).unwrap().unwrap()),
+            vec!["4", "3", "2"]
+        );
+
+        // writes whose hooks run out of order are applied in order
+        let mut inner = cache.inner.lock().unwrap();
+        let through = inner.through;
+        let event = |sequence, n: u64| StorageEvent {
+            tree: "ingress".to_string(),
+            key: IVec::from(format!("{}{}", INGRESS_PREFIX, Ulid::from_parts(n, 0)).as_bytes()),
+            op: StorageOp::Insert,
+            value: Some(IVec::from(&b"log"[..])),
+            previous: None,
+            sequence,
+            published: std::time::Instant::now(),
+        };
+        inner.written(&event(through + 2, 1_800_000_000_002), 4);
+        assert_eq!(inner.through, through);
+        inner.written(&event(through + 1, 1_800_000_000_001), 4);
+        assert_eq!(inner.through, through + 2);
+        assert!(inner.early.is_empty());
+        drop(inner);
+
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (4, 1));
+        assert_eq!(stats.trees, BTreeMap::from([("ingress".to_string(), 4)]));
+    }
+}
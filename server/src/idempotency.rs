@@ -0,0 +1,250 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use hydra_proto as proto;
+use tokio::sync::watch;
+use ulid::Ulid;
+
+/// How long we remember the result of a mutating request
+const DEFAULT_TTL: Duration = Duration::from_secs(300);
+
+/// The most results remembered at once. Beyond this the oldest are forgotten early, so a retry
+/// of one of those is applied again.
+const MAX_ENTRIES: usize = 10_000;
+
+/// The most bytes of encoded results remembered at once, as for MAX_ENTRIES. A result larger
+/// than this isn't remembered at all.
+const MAX_BYTES: usize = 64 * 1024 * 1024;
+
+/// Short-lived cache of mutation results keyed by the client supplied idempotency key.
+///
+/// If a client retries a request after an ambiguous failure (eg. the connection dropped after
+/// the request was sent) we replay the original result rather than applying the mutation twice.
+/// A key is reserved while its request is being handled, and a retry which arrives meanwhile
+/// waits to replay its result. Errors aren't remembered, so a retry of a failed request is
+/// applied afresh.
+pub struct IdempotencyCache {
+    ttl: Duration,
+    max_entries: usize,
+    max_bytes: usize,
+    entries: Mutex<Entries>,
+}
+
+enum Entry {
+    // Closed once the request holding the key has finished, one way or the other
+    InFlight(watch::Receiver<()>),
+    // payloads are stored bincode encoded, as ResponsePayload is not Clone
+    Done(Instant, Vec<u8>),
+}
+
+#[derive(Default)]
+struct Entries {
+    by_key: HashMap<Ulid, Entry>,
+    // Finished keys, oldest first, for expiry and eviction
+    finished: VecDeque<(Instant, Ulid)>,
+    // Size of the payloads of finished keys
+    bytes: usize,
+}
+
+/// What a request with an idempotency key should do
+pub enum Reserved<'a> {
+    /// Apply it, then complete the reservation with its result
+    Apply(Reservation<'a>),
+    /// Answer it with the result of the request which applied it before
+    Replay(Box<proto::ResponsePayload>),
+}
+
+/// A key held by the request applying it. Dropped without being completed, eg. because the
+/// request failed or was abandoned, the key is released for a retry to apply.
+pub struct Reservation<'a> {
+    cache: &'a IdempotencyCache,
+    key: Ulid,
+    encoded: Option<Vec<u8>>,
+    // Dropped after the entry is updated, waking any retries waiting on it
+    _finished: watch::Sender<()>,
+}
+
+impl IdempotencyCache {
+    pub fn new() -> Self {
+        Self::with_limits(DEFAULT_TTL, MAX_ENTRIES, MAX_BYTES)
+    }
+
+    pub fn with_limits(ttl: Duration, max_entries: usize, max_bytes: usize) -> Self {
+        Self {
+            ttl,
+            max_entries,
+            max_bytes,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// Reserve a key for a request to apply, or if a request with it has already been applied,
+    /// its result. If one is being applied now, this waits for it to finish.
+    pub async fn reserve(&self, key: Ulid) -> Reserved<'_> {
+        loop {
+            let mut in_flight = {
+                let mut entries = self.entries.lock().unwrap();
+                self.prune(&mut entries);
+                match entries.by_key.get(&key) {
+                    Some(Entry::InFlight(finished)) => finished.clone(),
+                    Some(Entry::Done(_, encoded)) => match bincode::deserialize(encoded) {
+                        Ok(payload) => return Reserved::Replay(Box::new(payload)),
+                        Err(_) => return Reserved::Apply(self.hold(&mut entries, key)),
+                    },
+                    None => return Reserved::Apply(self.hold(&mut entries, key)),
+                }
+            };
+            // errors once the reservation is dropped, which is all this waits for
+            let _ = in_flight.changed().await;
+        }
+    }
+
+    fn hold(&self, entries: &mut Entries, key: Ulid) -> Reservation<'_> {
+        let (finished, in_flight) = watch::channel(());
+        if let Some(Entry::Done(_, encoded)) =
+            entries.by_key.insert(key, Entry::InFlight(in_flight))
+        {
+            entries.bytes -= encoded.len();
+        }
+        Reservation {
+            cache: self,
+            key,
+            encoded: None,
+            _finished: finished,
+        }
+    }
+
+    /// Forget results which have expired, and the oldest while over the limits
+    fn prune(&self, entries: &mut Entries) {
+        while let Some(&(finished, key)) = entries.finished.front() {
+            let over = entries.finished.len() > self.max_entries || entries.bytes > self.max_bytes;
+            if !over && finished.elapsed() <= self.ttl {
+                break;
+            }
+            entries.finished.pop_front();
+            // the key may have been reserved again since, once this result expired
+            if let Some(Entry::Done(done, encoded)) = entries.by_key.get(&key) {
+                if *done == finished {
+                    entries.bytes -= encoded.len();
+                    entries.by_key.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+impl Reservation<'_> {
+    /// Remember the result of the request, for retries to replay
+    pub fn complete(mut self, payload: &proto::ResponsePayload) {
+        self.encoded = bincode::serialize(payload).ok();
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        let cache = self.cache;
+        let mut entries = cache.entries.lock().unwrap();
+        match self.encoded.take() {
+            Some(encoded) if encoded.len() <= cache.max_bytes => {
+                let now = Instant::now();
+                entries.bytes += encoded.len();
+                entries.by_key.insert(self.key, Entry::Done(now, encoded));
+                entries.finished.push_back((now, self.key));
+                cache.prune(&mut entries);
+            }
+            _ => {
+                entries.by_key.remove(&self.key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn payload(value: &str) -> proto::ResponsePayload {
+        proto::ResponsePayload::SetKv(proto::SetKvResponse {
+            success: true,
+            current: Some(value.as_bytes().to_vec()),
+        })
+    }
+
+    fn replayed(reserved: Reserved) -> Option<String> {
+        match reserved {
+            Reserved::Replay(payload) => match *payload {
+                proto::ResponsePayload::SetKv(response) => {
+                    String::from_utf8(response.current?).ok()
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_retries() {
+        let cache = Arc::new(IdempotencyCache::new());
+        let key = Ulid::new();
+        let Reserved::Apply(first) = cache.reserve(key).await else {
+            panic!("a new key should be reserved");
+        };
+
+        // a retry while the first is still being applied waits for its result
+        let retry = tokio::spawn({
+            let cache = cache.clone();
+            async move { replayed(cache.reserve(key).await) }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!retry.is_finished());
+        first.complete(&payload("a"));
+        assert_eq!(retry.await.unwrap().as_deref(), Some("a"));
+
+        // one which failed is released for the retry to apply
+        let key = Ulid::new();
+        let Reserved::Apply(failed) = cache.reserve(key).await else {
+            panic!("a new key should be reserved");
+        };
+        let retry = tokio::spawn({
+            let cache = cache.clone();
+            async move { matches!(cache.reserve(key).await, Reserved::Apply(_)) }
+        });
+        drop(failed);
+        assert!(retry.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        let cache = IdempotencyCache::with_limits(DEFAULT_TTL, 2, 1024);
+        let keys: Vec<Ulid> = (0..3).map(|_| Ulid::new()).collect();
+        for key in &keys {
+            if let Reserved::Apply(reservation) = cache.reserve(*key).await {
+                reservation.complete(&payload("a"));
+            }
+        }
+        // the oldest is forgotten to make room
+        assert!(matches!(cache.reserve(keys[0]).await, Reserved::Apply(_)));
+        assert_eq!(replayed(cache.reserve(keys[2]).await).as_deref(), Some("a"));
+
+        // as are results too large to keep
+        let large = Ulid::new();
+        if let Reserved::Apply(reservation) = cache.reserve(large).await {
+            reservation.complete(&payload(&"a".repeat(2048)));
+        }
+        assert!(matches!(cache.reserve(large).await, Reserved::Apply(_)));
+        assert!(cache.entries.lock().unwrap().bytes <= 1024);
+
+        // and ones which have expired
+        let cache = IdempotencyCache::with_limits(Duration::ZERO, 2, 1024);
+        if let Reserved::Apply(reservation) = cache.reserve(large).await {
+            reservation.complete(&payload("a"));
+        }
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert!(matches!(cache.reserve(large).await, Reserved::Apply(_)));
+    }
+}
@@ -1,85 +1,336 @@
+mod access;
+mod admin;
+mod alerts;
 mod appstate;
+mod cancel;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod checkpoints;
+mod codec;
+mod codegen;
+mod compression;
+mod computed;
+mod config;
+mod connection;
+mod cors;
+mod dev;
+mod durability;
+mod encoding;
 mod error;
+mod fixtures;
+mod grpc;
 mod handler;
+#[cfg(feature = "heap-profiling")]
+mod heap;
+mod hot;
+mod idempotency;
+mod outbound;
+mod preview;
 mod query;
+mod retention;
+mod schemas;
+mod selftest;
+mod shadow;
+mod shutdown;
 mod signal;
+mod snapshots;
+mod sources;
+mod spill;
 mod storage;
+mod subscription;
+mod sync;
+mod tags;
+#[cfg(test)]
+mod test_util;
+mod thumbnail;
+mod trace;
+#[cfg(unix)]
+mod unix;
+mod worker;
 
-use axum::extract::ws::CloseFrame;
-use axum::extract::{connect_info::ConnectInfo, State};
+use axum::extract::{connect_info::ConnectInfo, Path, State};
 use core::panic;
 use error::AppError;
-use futures_util::stream::SplitSink;
 use handler::ingress::fetch_ingress_logs;
-use std::{borrow::Cow, net::SocketAddr, ops::ControlFlow};
+use serde_json::json;
+use std::{net::SocketAddr, sync::atomic::Ordering, time::Duration};
 
 use appstate::AppState;
+use clap::Parser;
+use config::{Command, ServerConfig, LISTEN_ADDR};
+use connection::{Connection, ConnectionActor};
 
 use anyhow::Result;
 use axum::{
-    extract::ws::{Message, WebSocket, WebSocketUpgrade},
-    http::StatusCode,
+    extract::ws::WebSocketUpgrade,
+    http::{HeaderMap, StatusCode},
+    middleware,
     response::IntoResponse,
     routing::{get, post},
-    Router,
+    Json, Router,
 };
 
 use axum_extra::{headers, TypedHeader};
-use bincode::{deserialize, serialize};
-use futures_util::{SinkExt, StreamExt};
 use hydra_proto as proto;
 use tower::ServiceBuilder;
-use tower_http::trace::{DefaultMakeSpan, DefaultOnRequest, DefaultOnResponse, TraceLayer};
-use tracing::{info, Level};
+use tower_http::trace::{DefaultOnRequest, DefaultOnResponse, TraceLayer};
+use tracing::{info, Instrument, Level};
+use ulid::Ulid;
+use worker::JobClass;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // initialize tracing
     tracing_subscriber::fmt().with_max_level(Level::INFO).init();
-    let state = AppState::new()?;
+    let mut config = ServerConfig::parse();
+    if config.self_test {
+        return selftest::run(&config);
+    }
+    let dev = match config.command.take() {
+        Some(Command::Dev(dev_config)) => Some(dev::DevSession::new(dev_config, &mut config)?),
+        Some(Command::Import(import_config)) => {
+            let state = AppState::new(&config)?;
+            let imported = handler::import::import_file(&state, &import_config)?;
+            println!("Imported {} records into {}", imported, import_config.tree);
+            return Ok(());
+        }
+        Some(Command::Restore(restore_config)) => {
+            let storage_config = appstate::storage_config(&config)?;
+            let restored =
+                storage::backup::restore_snapshot(&restore_config.snapshot, &storage_config)?;
+            println!(
+                "Restored {} entries in {} trees into {}",
+                restored.entries,
+                restored.trees,
+                restored.path.display()
+            );
+            return Ok(());
+        }
+        Some(Command::Codegen(codegen_config)) => {
+            let code = match (codegen_config.protocol, codegen_config.lang) {
+                (true, codegen::Lang::Ts) => codegen::protocol::generate_ts()?,
+                (true, codegen::Lang::Rust) => {
+                    anyhow::bail!("--protocol is only for --lang ts; Rust clients use hydra-proto")
+                }
+                (false, lang) => {
+                    let storage =
+                        storage::StorageEngine::open(&appstate::storage_config(&config)?)?;
+                    codegen::generate(lang, &codegen::collections(&storage)?)
+                }
+            };
+            match &codegen_config.out {
+                Some(path) => std::fs::write(path, code)?,
+                None => print!("{}", code),
+            }
+            return Ok(());
+        }
+        None => None,
+    };
+    let state = AppState::new(&config)?;
+    spill::remove_leftovers(&state.storage)?;
+    subscription::spawn_broker(state.clone());
+    signal::spawn_views(state.clone())?;
+    if let Some(path) = &config.alerts {
+        alerts::spawn_alerts(state.clone(), alerts::load_rules(path)?)?;
+    }
+    if let Some(dir) = &config.fixtures {
+        fixtures::load_fixtures(&state, dir)?;
+    }
+    if let Some(policy) = retention::RetentionPolicy::from_config(&config) {
+        retention::spawn_retention(state.clone(), policy);
+    }
+    access::spawn_access_flush(state.clone())?;
+    durability::spawn_relaxed_writes(state.clone());
+    if let Some(interval) = state.snapshots.interval() {
+        snapshots::spawn_snapshots(state.clone(), interval);
+    }
+    if let Some(interval) = state.checkpoints.interval() {
+        checkpoints::spawn_checkpoints(state.clone(), interval);
+    }
+    shadow::spawn_shadow(state.clone());
+    if let Some(policy) = sync::SyncPolicy::from_config(&config)? {
+        sync::spawn_sync(state.clone(), policy);
+    }
+    if let Some(seconds) = config.log_deliveries_for {
+        subscription::deliveries::spawn_pruning(state.clone(), Duration::from_secs(seconds));
+    }
 
     // build our application with a route and middleware
     let app = Router::new()
-        .route("/", get(root))
-        .route("/ingress", post(handler::ingress::capture))
+        .route("/", get(status))
+        .route("/health", get(health))
+        .route("/status", get(status))
+        .route("/views", get(views))
+        .route("/views/:name", get(view))
+        .route("/usage", get(access::usage_report))
+        .route("/ingress", handler::ingress::capture_route())
+        .route("/ingress-batch", post(handler::batch::capture_batch))
+        .route("/ingress-stream", get(handler::stream::stream))
+        .route("/ingress/*path", handler::ingress::capture_route())
+        .route("/logs/:event_id", get(handler::ingress::log_detail))
         .route("/ws", get(ws_handler))
-        .with_state(state)
-        .layer(
-            ServiceBuilder::new()
-                .layer(
-                    TraceLayer::new_for_http()
-                        .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
-                        .on_request(DefaultOnRequest::new().level(Level::INFO))
-                        .on_response(DefaultOnResponse::new().level(Level::INFO)),
-                )
-                .into_inner(),
-        );
+        .merge(admin::routes(&state));
+
+    let app = match &dev {
+        Some(dev) => {
+            let seeded = dev.seed(&state)?;
+            println!("Seeded {} sample ingress logs", seeded);
+            dev.routes(app)
+        }
+        None => app,
+    };
+
+    // outside every route, so preflights are answered before they'd be captured
+    let app = match &state.cors {
+        Some(cors) => cors.apply(app),
+        None => app,
+    };
+
+    let app = app.with_state(state.clone()).layer(
+        ServiceBuilder::new()
+            // first, so the trace id is there for the span
+            .layer(middleware::from_fn(trace::assign))
+            .layer(
+                TraceLayer::new_for_http()
+                    .make_span_with(trace::http_span)
+                    .on_request(DefaultOnRequest::new().level(Level::INFO))
+                    .on_response(DefaultOnResponse::new().level(Level::INFO)),
+            )
+            .into_inner(),
+    );
+
+    let mut unix_listener: Option<tokio::task::JoinHandle<()>> = None;
+    #[cfg(unix)]
+    if let Some(path) = &config.unix_socket {
+        let listener = unix::bind(path, config.unix_socket_mode)?;
+        unix_listener = Some(unix::spawn_listener(
+            listener,
+            path.clone(),
+            app.clone(),
+            state.clone(),
+        ));
+    }
+
+    let grpc_server = match config.grpc_listen {
+        Some(addr) => Some(grpc::spawn(addr, state.clone())?),
+        None => None,
+    };
 
     // run our app with hyper, listening globally on port 3000
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:9797").await.unwrap();
+    let listener = tokio::net::TcpListener::bind(LISTEN_ADDR).await.unwrap();
     tracing::debug!("listening on {}", listener.local_addr().unwrap());
+    if let Some(dev) = &dev {
+        dev.start();
+    }
 
     // axum::serve(listener, app).await.unwrap();
     axum::serve(
         listener,
         app.into_make_service_with_connect_info::<SocketAddr>(),
     )
+    .with_graceful_shutdown(shutdown::signal(state.clone()))
     .await
     .unwrap();
 
+    if let Some(unix_listener) = unix_listener {
+        let _ = unix_listener.await;
+    }
+    if let Some(grpc_server) = grpc_server {
+        let _ = grpc_server.await;
+    }
+    shutdown::drain(&state, shutdown::SHUTDOWN_TIMEOUT).await?;
+    if let Some(dev) = dev {
+        dev.finish();
+    }
+
     Ok(())
 }
 
-pub async fn root() -> Result<String, StatusCode> {
-    Ok("Hello, world!".to_string())
+/// Liveness check for load balancers and process supervisors. Fails while the server is
+/// draining, so load balancers stop sending it connections.
+async fn health(State(state): State<AppState>) -> (StatusCode, Json<serde_json::Value>) {
+    let (code, status) = match state.draining.borrow().is_some() {
+        true => (StatusCode::SERVICE_UNAVAILABLE, "draining"),
+        false => (StatusCode::OK, "ok"),
+    };
+    (
+        code,
+        Json(json!({
+            "status": status,
+            "uptime_seconds": state.started.elapsed().as_secs(),
+        })),
+    )
+}
+
+/// A summary of the server's state for monitoring. Counting records walks every tree, so this
+/// is not something to poll at a high rate.
+async fn status(State(state): State<AppState>) -> Result<Json<serde_json::Value>, AppError> {
+    let db = &state.storage.db;
+    let mut trees = serde_json::Map::new();
+    for name in db.tree_names() {
+        let tree = db.open_tree(&name)?;
+        trees.insert(
+            String::from_utf8_lossy(&name).into_owned(),
+            json!(tree.len()),
+        );
+    }
+
+    Ok(Json(json!({
+        "status": "ok",
+        "uptime_seconds": state.started.elapsed().as_secs(),
+        "connections": state.connections.load(Ordering::Relaxed),
+        "draining": state.draining.borrow().is_some(),
+        "storage": {
+            "format_version": storage::format::recorded_version(db)?,
+            "size_on_disk": db.size_on_disk()?,
+            "trees": trees,
+        },
+        "subscription_shards": state.subscriptions.stats(),
+        "retention": state.retention.snapshot(),
+        "alerts": state.alerts.status(),
+        "sources": state.sources.ids(),
+        "shadow": state.shadow.status(&state.storage)?,
+        "relaxed_queued": state.writer.queued(),
+        "hot_cache": state.hot.stats(),
+        "dag": {
+            "events": state.dag.len(),
+            "heads": state.dag.heads().iter().map(|id| hex::encode(id.hash)).collect::<Vec<_>>(),
+        },
+        "checkpoints": {
+            "public_key": state.checkpoints.public_key().map(hex::encode),
+            "latest": state.checkpoints.latest(&state.storage)?.map(|latest| latest.sequence),
+        },
+    })))
+}
+
+/// The current output of every incrementally maintained view
+async fn views(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(serde_json::Value::Object(state.views.outputs()))
+}
+
+async fn view(
+    State(state): State<AppState>,
+    Path(name): Path<String>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    state
+        .views
+        .output(&name)
+        .map(Json)
+        .ok_or_else(|| AppError::not_found(format!("No view named {}", name)))
 }
 
 async fn ws_handler(
     ws: WebSocketUpgrade,
     user_agent: Option<TypedHeader<headers::UserAgent>>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request_headers: HeaderMap,
     State(state): State<AppState>,
-) -> impl IntoResponse {
+) -> Result<impl IntoResponse, AppError> {
+    shutdown::refuse_while_draining(&state)?;
+    if let Some(cors) = &state.cors {
+        cors.check_websocket(&request_headers)?;
+    }
+    let admin = state.admin.accepts(&request_headers);
     info!("Upgrading connection");
     let user_agent = if let Some(TypedHeader(user_agent)) = user_agent {
         user_agent.to_string()
@@ -89,115 +340,257 @@ async fn ws_handler(
     println!("`{user_agent}` at {addr} connected.");
     // finalize the upgrade process by returning upgrade callback.
     // we can customize the callback by sending additional info such as address.
-    ws.on_upgrade(move |socket| handle_socket(socket, addr, state))
+    Ok(ws.on_upgrade(move |socket| ConnectionActor::run(socket, addr, state, admin)))
 }
 
-/// Actual websocket statemachine (one will be spawned per connection)
-async fn handle_socket(mut socket: WebSocket, who: SocketAddr, state: AppState) {
-    println!("Connected to {}", who);
-
-    // Send a ping (unsupported by some browsers) just to kick things off
-    if socket.send(Message::Ping(vec![1, 2, 3])).await.is_ok() {
-        println!("Pinged {who}...");
-    } else {
-        println!("Could not send ping to {who}!");
-        return;
-    }
-
-    let (mut sender, mut receiver) = socket.split();
-
-    // Process each incoming message
-    while let Some(msg) = receiver.next().await {
-        if let Ok(msg) = msg {
-            if process_message(msg, who, &sender, &state).await.is_break() {
-                break;
-            }
-        } else {
-            println!("client {who} abruptly disconnected");
-            break;
-        }
-    }
-
-    println!("Websocket context {who} destroyed");
+/// Handle a request in a span with its trace id, which is given one if the client didn't
+async fn handle_request(
+    request: proto::Request,
+    connection: &Connection,
+    state: &AppState,
+) -> proto::Response {
+    let trace_id = request.trace_id.unwrap_or_else(trace::new_id);
+    let span = tracing::info_span!("request", request_id = request.id, %trace_id);
+    respond(request, trace_id, connection, state)
+        .instrument(span)
+        .await
 }
 
-/// helper to print contents of messages to stdout. Has special treatment for Close.
-async fn process_message(
-    msg: Message,
-    who: SocketAddr,
-    sender: &SplitSink<WebSocket, Message>,
+async fn respond(
+    request: proto::Request,
+    trace_id: Ulid,
+    connection: &Connection,
     state: &AppState,
-) -> ControlFlow<(), ()> {
-    match msg {
-        Message::Text(t) => {
-            println!(">>> {who} sent str: {t:?}");
-        }
-        Message::Binary(d) => {
-            println!(">>> {} sent {} bytes: {:?}", who, d.len(), d);
-
-            // Deserialize the binary message into a Message enum
-            if let Ok(message) = deserialize::<proto::Message>(&d) {
-                match message {
-                    proto::Message::Request(request) => {
-                        handle_request(request, sender, state);
-                    }
-                    proto::Message::Response(_) => {
-                        println!("Unexpected response message from client");
-                    }
-                }
+) -> proto::Response {
+    // Replay the original result if this is a retry of a mutation we've already applied, or
+    // wait for it if it's still being applied
+    let idempotency_key = request
+        .idempotency_key
+        .filter(|_| request.payload.is_mutation());
+    let reservation = match idempotency_key {
+        Some(key) => match state.idempotency.reserve(key).await {
+            idempotency::Reserved::Apply(reservation) => Some(reservation),
+            idempotency::Reserved::Replay(payload) => {
+                println!("Replaying cached response for idempotency key {}", key);
+                return proto::Response {
+                    request_id: request.id,
+                    // assigned by the connection's OutboundSender
+                    sequence: 0,
+                    trace_id: Some(trace_id),
+                    payload: *payload,
+                };
+            }
+        },
+        None => None,
+    };
+
+    let shadowed = state.shadow.mutation(&request);
+    let cancel = connection.requests.start(request.id);
+    let result = match request.payload {
+        proto::RequestPayload::FetchIngressLogs(fetch_request) => {
+            let job_state = state.clone();
+            state
+                .workers
+                .try_run(JobClass::Query, move || {
+                    fetch_ingress_logs(fetch_request, &job_state, cancel)
+                })
+                .await
+                .map(proto::ResponsePayload::FetchIngressLogs)
+        }
+        proto::RequestPayload::TagIngressLogs(tag_request) => {
+            let job_state = state.clone();
+            let outbound = connection.outbound.clone();
+            let request_id = request.id;
+            state
+                .workers
+                .try_run(JobClass::Export, move || {
+                    handler::ingress::tag_ingress_logs(
+                        tag_request,
+                        request_id,
+                        &outbound,
+                        &job_state,
+                        cancel,
+                    )
+                })
+                .await
+                .map(proto::ResponsePayload::TagIngressLogs)
+        }
+        proto::RequestPayload::Export(export_request) => {
+            handler::export::next_chunk(state, export_request, cancel)
+                .await
+                .map(proto::ResponsePayload::Export)
+        }
+        proto::RequestPayload::GetKv(get_request) => {
+            handler::kv::get_kv(get_request, state).map(proto::ResponsePayload::GetKv)
+        }
+        proto::RequestPayload::SetKv(set_request) => {
+            handler::kv::set_kv(set_request, state).map(proto::ResponsePayload::SetKv)
+        }
+        proto::RequestPayload::Subscribe(subscribe_request) => state
+            .subscriptions
+            .subscribe(
+                connection.id,
+                request.id,
+                subscribe_request,
+                connection.outbound.clone(),
+            )
+            .map(|_| proto::ResponsePayload::Subscribed)
+            .map_err(AppError::from),
+        proto::RequestPayload::ReplayDeliveries(replay_request) => {
+            if state.subscriptions.logs_deliveries() {
+                subscription::deliveries::replay(
+                    &state.storage,
+                    replay_request.session,
+                    replay_request.subscription_id,
+                )
+                .map(|deliveries| {
+                    proto::ResponsePayload::ReplayDeliveries(proto::ReplayDeliveriesResponse {
+                        deliveries,
+                    })
+                })
+                .map_err(AppError::from)
             } else {
-                println!("Failed to deserialize message");
+                Err(AppError::not_found(
+                    "Deliveries aren't logged, start the server with --log-deliveries-for",
+                ))
             }
         }
-        Message::Close(c) => {
-            if let Some(cf) = c {
-                println!(
-                    ">>> {} sent close with code {} and reason `{}`",
-                    who, cf.code, cf.reason
-                );
+        proto::RequestPayload::Unsubscribe(unsubscribe_request) => {
+            connection
+                .requests
+                .finish(unsubscribe_request.subscription_id);
+            if state
+                .subscriptions
+                .unsubscribe(connection.id, unsubscribe_request.subscription_id)
+            {
+                Ok(proto::ResponsePayload::Unsubscribed)
             } else {
-                println!(">>> {who} somehow sent close message without CloseFrame");
+                Err(AppError::not_found(format!(
+                    "No subscription {} on this connection",
+                    unsubscribe_request.subscription_id
+                )))
             }
-            return ControlFlow::Break(());
         }
-
-        Message::Pong(v) => {
-            println!(">>> {who} sent pong with {v:?}");
+        proto::RequestPayload::CreateRecord(create_request) => {
+            handler::record::create_record(create_request, state)
+                .map(proto::ResponsePayload::CreateRecord)
+        }
+        proto::RequestPayload::GetRecord(get_request) => {
+            handler::record::get_record(get_request, state).map(proto::ResponsePayload::GetRecord)
+        }
+        proto::RequestPayload::SetCollectionSchema(schema_request) => {
+            handler::record::set_collection_schema(schema_request, state)
+                .map(proto::ResponsePayload::SetCollectionSchema)
+        }
+        proto::RequestPayload::FetchInvalidRecords(invalid_request) => {
+            handler::record::fetch_invalid_records(invalid_request, state)
+                .map(proto::ResponsePayload::FetchInvalidRecords)
+        }
+        proto::RequestPayload::UpdateRecord(update_request) => {
+            handler::record::update_record(update_request, state)
+                .map(proto::ResponsePayload::UpdateRecord)
+        }
+        proto::RequestPayload::DeleteRecord(delete_request) => {
+            handler::record::delete_record(delete_request, state)
+                .map(proto::ResponsePayload::DeleteRecord)
         }
-        // You should never need to manually handle Message::Ping, as axum's websocket library
-        // will do so for you automagically by replying with Pong and copying the v according to
-        // spec. But if you need the contents of the pings you can see them here.
-        Message::Ping(v) => {
-            println!(">>> {who} sent ping with {v:?}");
+        proto::RequestPayload::Hello => handler::hello::hello(state, connection.outbound.session())
+            .map(proto::ResponsePayload::Hello),
+        proto::RequestPayload::ReadStats => Ok(proto::ResponsePayload::ReadStats(
+            proto::ReadStatsResponse {
+                trees: state.storage.read_stats(),
+            },
+        )),
+        proto::RequestPayload::AdvertiseHeads(advertisement) => {
+            handler::events::advertise_heads(advertisement, state)
+                .map(proto::ResponsePayload::AdvertiseHeads)
         }
+        proto::RequestPayload::RequestMissing(missing_request) => {
+            handler::events::request_missing(missing_request, state)
+                .map(proto::ResponsePayload::SendEvents)
+        }
+        proto::RequestPayload::SendEvents(events) => connection
+            .require_admin("SendEvents")
+            .and_then(|_| handler::events::send_events(events, state))
+            .map(proto::ResponsePayload::EventsReceived),
+        proto::RequestPayload::VerifyBasis(verify_request) => {
+            let job_state = state.clone();
+            state
+                .workers
+                .try_run(JobClass::Query, move || {
+                    job_state
+                        .checkpoints
+                        .verify_basis(&job_state.storage, verify_request)
+                })
+                .await
+                .map(proto::ResponsePayload::VerifyBasis)
+        }
+        proto::RequestPayload::LiveQuery(fetch_request) => {
+            handler::live::live_query(fetch_request, request.id, connection, state, cancel)
+                .await
+                .map(proto::ResponsePayload::LiveQuery)
+        }
+        proto::RequestPayload::FetchIngressLog(log_request) => {
+            handler::ingress::fetch_ingress_log(log_request, state)
+                .map(proto::ResponsePayload::FetchIngressLog)
+        }
+        proto::RequestPayload::DeleteIngressLog(remove_request) => connection
+            .require_admin("DeleteIngressLog")
+            .and_then(|_| handler::removal::delete_ingress_log(remove_request, state))
+            .map(proto::ResponsePayload::DeleteIngressLog),
+        proto::RequestPayload::RedactIngressLog(remove_request) => connection
+            .require_admin("RedactIngressLog")
+            .and_then(|_| handler::removal::redact_ingress_log(remove_request, state))
+            .map(proto::ResponsePayload::RedactIngressLog),
+        proto::RequestPayload::FindSimilar(similar_request) => {
+            let job_state = state.clone();
+            state
+                .workers
+                .try_run(JobClass::Query, move || {
+                    handler::similar::find_similar(similar_request, &job_state, cancel)
+                })
+                .await
+                .map(proto::ResponsePayload::FindSimilar)
+        }
+        // applied as they arrive, in process_message
+        proto::RequestPayload::Cancel(_) => Err(AppError::invalid_request(
+            "Cancel requests have no response",
+        )),
+    };
+    // a live query runs on until it's cancelled
+    if !matches!(result, Ok(proto::ResponsePayload::LiveQuery(_))) {
+        connection.requests.finish(request.id);
     }
-    ControlFlow::Continue(())
-}
 
-async fn handle_request(
-    request: proto::Request,
-    sender: &SplitSink<WebSocket, Message>,
-    state: &AppState,
-) -> proto::Response {
-    let response_payload = match request.payload {
-        proto::RequestPayload::FetchIngressLogs(fetch_request) => {
-            match fetch_ingress_logs(fetch_request, state, sender) {
-                Ok(fetch_response) => proto::ResponsePayload::FetchIngressLogs(fetch_response),
-                Err(e) => {
-                    println!("Error fetching ingress logs: {:?}", e);
-                    // You might want to define an error variant for ResponsePayload
-                    // to handle this case more gracefully
-                    return proto::Response {
-                        request_id: request.id,
-                        payload: proto::ResponsePayload::Error(format!("{:?}", e)),
-                    };
-                }
-            }
+    let response_payload = match result {
+        Ok(payload) => payload,
+        Err(e) => {
+            println!(
+                "Error handling request {} (trace {}): {:?}",
+                request.id, trace_id, e
+            );
+            return proto::Response {
+                request_id: request.id,
+                // assigned by the connection's OutboundSender
+                sequence: 0,
+                trace_id: Some(trace_id),
+                payload: proto::ResponsePayload::Error(e.payload().trace_id(trace_id)),
+            };
         }
     };
 
+    if let Some(reservation) = reservation {
+        reservation.complete(&response_payload);
+    }
+    // only what the primary applied is mirrored
+    if let Some(item) = shadowed {
+        state.shadow.enqueue(&state.storage, item);
+    }
+
     proto::Response {
         request_id: request.id,
+        sequence: 0,
+        trace_id: Some(trace_id),
         payload: response_payload,
     }
 }
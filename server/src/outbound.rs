@@ -0,0 +1,137 @@
+use std::sync::{Arc, Mutex};
+
+use hydra_proto as proto;
+use tokio::sync::mpsc::{self, error::SendError, error::TrySendError};
+use ulid::Ulid;
+
+/// The sending half of a connection's outbound queue, shared by its responses and pushes.
+///
+/// Each response is stamped with the connection's next sequence number as it is queued, and the
+/// queue is written to the socket in order, so the client sees the numbers go up by one. A push
+/// dropped because the queue is full still uses up its number, so the client can tell it missed
+/// something and resync rather than silently falling out of date.
+#[derive(Clone)]
+pub struct OutboundSender {
+    sender: mpsc::Sender<proto::Message>,
+    // held while stamping and queueing, so that queue order always matches sequence order
+    next_sequence: Arc<Mutex<u64>>,
+    // identifies the connection's session, within which the sequence numbers are counted
+    session: Ulid,
+}
+
+pub fn channel(capacity: usize) -> (OutboundSender, mpsc::Receiver<proto::Message>) {
+    let (sender, receiver) = mpsc::channel(capacity);
+    let outbound = OutboundSender {
+        sender,
+        next_sequence: Arc::new(Mutex::new(0)),
+        session: Ulid::new(),
+    };
+    (outbound, receiver)
+}
+
+impl OutboundSender {
+    /// Queue a response, waiting for space if the queue is full
+    pub async fn send(&self, mut response: proto::Response) -> Result<(), SendError<()>> {
+        let permit = self.sender.reserve().await?;
+        let mut next_sequence = self.next_sequence.lock().unwrap();
+        response.sequence = *next_sequence;
+        *next_sequence += 1;
+        permit.send(proto::Message::Response(response));
+        Ok(())
+    }
+
+    /// Queue a response only if there is space for it right now, returning the sequence number
+    /// it was given. A response which isn't queued still uses up its number, which comes back
+    /// with the error.
+    pub fn try_send(&self, mut response: proto::Response) -> Result<u64, TrySendError<u64>> {
+        let mut next_sequence = self.next_sequence.lock().unwrap();
+        let sequence = *next_sequence;
+        response.sequence = sequence;
+        *next_sequence += 1;
+        self.sender
+            .try_send(proto::Message::Response(response))
+            .map(|_| sequence)
+            .map_err(|e| match e {
+                TrySendError::Full(_) => TrySendError::Full(sequence),
+                TrySendError::Closed(_) => TrySendError::Closed(sequence),
+            })
+    }
+
+    pub fn session(&self) -> Ulid {
+        self.session
+    }
+
+    pub fn pong_sender(&self) -> PongSender {
+        PongSender {
+            sender: self.sender.downgrade(),
+        }
+    }
+}
+
+/// Answers a client's Pings through its connection's outbound queue. It doesn't hold the queue
+/// open, so the connection's reader can keep one without stopping the writer from finishing.
+pub struct PongSender {
+    sender: mpsc::WeakSender<proto::Message>,
+}
+
+impl PongSender {
+    /// Queue a Pong if there is space for it right now. Pongs aren't responses, so they don't
+    /// use up a sequence number.
+    pub fn try_send(&self, pong: proto::Pong) -> Result<(), TrySendError<()>> {
+        let Some(sender) = self.sender.upgrade() else {
+            return Err(TrySendError::Closed(()));
+        };
+        sender
+            .try_send(proto::Message::Pong(pong))
+            .map_err(|e| match e {
+                TrySendError::Full(_) => TrySendError::Full(()),
+                TrySendError::Closed(_) => TrySendError::Closed(()),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(request_id: usize) -> proto::Response {
+        proto::Response {
+            request_id,
+            sequence: 0,
+            trace_id: None,
+            payload: proto::ResponsePayload::Unsubscribed,
+        }
+    }
+
+    fn sequence(message: proto::Message) -> u64 {
+        match message {
+            proto::Message::Response(response) => response.sequence,
+            _ => panic!("expected a response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sequence_numbers() {
+        let (outbound, mut receiver) = channel(2);
+        outbound.send(response(0)).await.unwrap();
+        assert_eq!(outbound.try_send(response(1)), Ok(1));
+        // the queue is full, so this one is dropped
+        assert_eq!(outbound.try_send(response(2)), Err(TrySendError::Full(2)));
+
+        assert_eq!(sequence(receiver.recv().await.unwrap()), 0);
+        // pongs don't take a sequence number
+        let ping = proto::Ping {
+            sequence: 0,
+            sent_at: 0,
+        };
+        outbound.pong_sender().try_send(ping.pong(1)).unwrap();
+        assert_eq!(sequence(receiver.recv().await.unwrap()), 1);
+        assert!(matches!(
+            receiver.recv().await,
+            Some(proto::Message::Pong(proto::Pong { sequence: 0, .. }))
+        ));
+        outbound.send(response(3)).await.unwrap();
+        // leaving a gap where the dropped push would have been
+        assert_eq!(sequence(receiver.recv().await.unwrap()), 3);
+    }
+}
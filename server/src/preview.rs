@@ -0,0 +1,77 @@
+use bytes::Bytes;
+use hydra_proto as proto;
+
+/// Generate a preview of a body of at most max_bytes, based on its content type
+pub fn body_preview(
+    body: &Bytes,
+    content_type: Option<&str>,
+    max_bytes: usize,
+) -> proto::BodyPreview {
+    let content_type = content_type.unwrap_or_default().to_ascii_lowercase();
+
+    if content_type.contains("json") {
+        if let Ok(value) = serde_json::from_slice::<serde_json::Value>(body) {
+            if let Ok(pretty) = serde_json::to_string_pretty(&value) {
+                let cut = truncate_str(&pretty, max_bytes);
+                return proto::BodyPreview {
+                    content: Bytes::copy_from_slice(cut.as_bytes()),
+                    json: true,
+                    truncated: cut.len() < pretty.len(),
+                    full_length: body.len(),
+                };
+            }
+        }
+    }
+
+    // Text bodies (or anything which happens to be valid UTF-8) are cut on a char boundary
+    let content = match std::str::from_utf8(body) {
+        Ok(text) => Bytes::copy_from_slice(truncate_str(text, max_bytes).as_bytes()),
+        Err(_) => body.slice(..body.len().min(max_bytes)),
+    };
+
+    proto::BodyPreview {
+        truncated: content.len() < body.len(),
+        content,
+        json: false,
+        full_length: body.len(),
+    }
+}
+
+/// The longest prefix of s that is at most max_bytes long and ends on a char boundary
+fn truncate_str(s: &str, max_bytes: usize) -> &str {
+    if s.len() <= max_bytes {
+        return s;
+    }
+    let mut end = max_bytes;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_body_preview() {
+        // "é" is two bytes, so cutting at 4 would split it
+        let body = Bytes::from("abcé€def");
+        let preview = body_preview(&body, Some("text/plain"), 4);
+        assert_eq!(&preview.content[..], "abc".as_bytes());
+        assert!(preview.truncated);
+        assert_eq!(preview.full_length, body.len());
+
+        let body = Bytes::from(r#"{"a":1}"#);
+        let preview = body_preview(&body, Some("application/json"), 100);
+        assert_eq!(&preview.content[..], b"{\n  \"a\": 1\n}");
+        assert!(preview.json);
+        assert!(!preview.truncated);
+
+        // binary bodies are cut at exactly max_bytes
+        let body = Bytes::from(vec![0xff; 10]);
+        let preview = body_preview(&body, None, 4);
+        assert_eq!(preview.content.len(), 4);
+        assert!(preview.truncated);
+    }
+}
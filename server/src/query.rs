@@ -4,7 +4,15 @@ use hydra_proto as proto;
 use sled::IVec;
 use ulid::Ulid;
 
-use crate::{appstate::AppState, error::AppError};
+use crate::{
+    appstate::AppState,
+    cancel::{CancelToken, CHECK_INTERVAL},
+    error::AppError,
+    storage::{versioned, Scan},
+};
+
+/// How far a page's position is looked for from either end of the records it's paging through
+pub const MAX_POSITION_SCAN: usize = 10_000;
 
 pub trait Key {
     type Bytes: AsRef<[u8]>;
@@ -42,15 +50,15 @@ impl Key for usize {
 pub enum FetchCursor<K: Key> {
     None,
     Excluding(K),
-    // Including(K),
+    Including(K),
 }
 
 impl<K: Key> FetchCursor<K> {
-    fn into_bound(self) -> Bound<K::Bytes> {
+    fn into_bound(self) -> Bound<Vec<u8>> {
         match self {
             FetchCursor::None => Bound::Unbounded,
-            FetchCursor::Excluding(k) => Bound::Excluded(k.as_bytes()),
-            // FetchCursor::Including(k) => Bound::Included(k.as_bytes()),
+            FetchCursor::Excluding(k) => Bound::Excluded(k.as_bytes().as_ref().to_vec()),
+            FetchCursor::Including(k) => Bound::Included(k.as_bytes().as_ref().to_vec()),
         }
     }
 }
@@ -59,6 +67,11 @@ pub struct FetchRecordQuery<K: Key> {
     pub cursor: FetchCursor<K>,
     pub limit: usize,
     pub order: proto::Direction,
+    pub cancel: CancelToken,
+    // only keys starting with this are fetched. Empty means the whole tree
+    pub prefix: Vec<u8>,
+    // and of those, only the keys within these bounds
+    pub bounds: (Bound<Vec<u8>>, Bound<Vec<u8>>),
 }
 
 impl<K: Key> FetchRecordQuery<K> {
@@ -67,6 +80,9 @@ impl<K: Key> FetchRecordQuery<K> {
             cursor: FetchCursor::None,
             limit: 100,
             order: proto::Direction::Ascending,
+            cancel: CancelToken::new(),
+            prefix: Vec::new(),
+            bounds: (Bound::Unbounded, Bound::Unbounded),
         }
     }
 
@@ -84,6 +100,88 @@ impl<K: Key> FetchRecordQuery<K> {
         self.order = order;
         self
     }
+
+    /// Stop the scan early if this token is cancelled
+    pub fn cancel(mut self, token: CancelToken) -> Self {
+        self.cancel = token;
+        self
+    }
+
+    /// Only fetch keys which start with the prefix, in either direction
+    pub fn prefix(mut self, prefix: &[u8]) -> Self {
+        self.prefix = prefix.to_vec();
+        self
+    }
+
+    /// Only fetch keys within these bounds, eg. to narrow a prefix of time ordered keys down to
+    /// a time range
+    pub fn bounds(mut self, bounds: (Bound<Vec<u8>>, Bound<Vec<u8>>)) -> Self {
+        self.bounds = bounds;
+        self
+    }
+}
+
+/// The range of keys which start with a prefix
+pub fn prefix_range(prefix: &[u8]) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    if prefix.is_empty() {
+        return (Bound::Unbounded, Bound::Unbounded);
+    }
+    // the first key after all those with the prefix: drop any trailing 0xff bytes and bump the last
+    let mut end = prefix.to_vec();
+    while end.last() == Some(&0xff) {
+        end.pop();
+    }
+    let upper = match end.last_mut() {
+        Some(last) => {
+            *last += 1;
+            Bound::Excluded(end)
+        }
+        // the prefix is all 0xff, so nothing sorts after it
+        None => Bound::Unbounded,
+    };
+    (Bound::Included(prefix.to_vec()), upper)
+}
+
+/// The tighter of two lower bounds
+fn max_lower(a: Bound<Vec<u8>>, b: Bound<Vec<u8>>) -> Bound<Vec<u8>> {
+    match (&a, &b) {
+        (Bound::Unbounded, _) => b,
+        (_, Bound::Unbounded) => a,
+        (Bound::Included(x) | Bound::Excluded(x), Bound::Included(y) | Bound::Excluded(y)) => {
+            if x > y || (x == y && matches!(a, Bound::Excluded(_))) {
+                a
+            } else {
+                b
+            }
+        }
+    }
+}
+
+/// The tighter of two upper bounds
+fn min_upper(a: Bound<Vec<u8>>, b: Bound<Vec<u8>>) -> Bound<Vec<u8>> {
+    match (&a, &b) {
+        (Bound::Unbounded, _) => b,
+        (_, Bound::Unbounded) => a,
+        (Bound::Included(x) | Bound::Excluded(x), Bound::Included(y) | Bound::Excluded(y)) => {
+            if x < y || (x == y && matches!(a, Bound::Excluded(_))) {
+                a
+            } else {
+                b
+            }
+        }
+    }
+}
+
+/// Whether a range contains no keys at all. Ranges like these make BTreeMap::range panic.
+fn is_empty_range(range: &(Bound<Vec<u8>>, Bound<Vec<u8>>)) -> bool {
+    match range {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (
+            Bound::Included(start) | Bound::Excluded(start),
+            Bound::Included(end) | Bound::Excluded(end),
+        ) => start >= end,
+        _ => false,
+    }
 }
 
 pub struct FetchRecordResult<T: proto::Record> {
@@ -100,30 +198,48 @@ impl<T: proto::Record> FetchRecordResult<T> {
 
 use std::ops::Bound;
 
-pub fn fetch_records<T: proto::Record, K: Key>(
-    tree: &sled::Tree,
+/// Fetch the records which satisfy a predicate, skipping over the others. The limit counts
+/// matching records only, as does `more_records`, so a sparse predicate may scan a long way:
+/// the query's cancel token is checked as the scan goes rather than as records match.
+pub fn fetch_records<T: proto::Record, K: Key, S: Scan>(
+    tree: &S,
     query: FetchRecordQuery<K>,
+    predicate: impl Fn(&T) -> bool,
 ) -> Result<FetchRecordResult<T>, AppError> {
     let limit = query.limit;
     let fetch_limit = limit + 1; // Fetch one extra to determine if there are more records
 
     let mut items = Vec::with_capacity(fetch_limit);
 
-    match query.order {
-        proto::Direction::Ascending => {
-            let iter = tree.range((query.cursor.into_bound(), Bound::Unbounded));
-            for item in iter.take(fetch_limit) {
-                let (key, value) = item?;
-                items.push((key, bincode::deserialize(&value)?));
-            }
+    // the query's bounds, then the cursor, narrow the prefix's range
+    let (lower, upper) = prefix_range(&query.prefix);
+    let (lower, upper) = (
+        max_lower(lower, query.bounds.0),
+        min_upper(upper, query.bounds.1),
+    );
+    let range = match query.order {
+        proto::Direction::Ascending => (max_lower(lower, query.cursor.into_bound()), upper),
+        proto::Direction::Descending => (lower, min_upper(upper, query.cursor.into_bound())),
+    };
+    if is_empty_range(&range) {
+        return Ok(FetchRecordResult {
+            items,
+            more_records: false,
+            order: query.order,
+        });
+    }
+
+    let reverse = query.order == proto::Direction::Descending;
+    for (i, item) in tree.scan(range, reverse).enumerate() {
+        if i % CHECK_INTERVAL == 0 {
+            query.cancel.check()?;
         }
-        proto::Direction::Descending => {
-            let iter = tree
-                .range((Bound::Unbounded, query.cursor.into_bound()))
-                .rev();
-            for item in iter.take(fetch_limit) {
-                let (key, value) = item?;
-                items.push((key, bincode::deserialize(&value)?));
+        let (key, value) = item?;
+        let record: T = versioned::decode(&value)?;
+        if predicate(&record) {
+            items.push((key, record));
+            if items.len() == fetch_limit {
+                break;
             }
         }
     }
@@ -139,10 +255,19 @@ pub fn fetch_records<T: proto::Record, K: Key>(
 }
 
 pub struct PaginatedFetchRequest {
-    pub tree: &'static str,
+    pub tree: String,
     pub cursor: proto::PaginatedCursor,
     pub limit: usize,
     pub direction: proto::Direction,
+    pub snapshot: Option<proto::SnapshotToken>,
+    pub cancel: CancelToken,
+    // restricts the fetch to keys with this prefix, see FetchRecordQuery::prefix
+    pub prefix: Vec<u8>,
+    // and within these bounds, see FetchRecordQuery::bounds
+    pub bounds: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+    // whether the predicate passes every record, so the page's total_count and position can be
+    // given. They're only given for fetches without bounds, too
+    pub count: bool,
 }
 
 pub struct PaginatedFetchResponse<T> {
@@ -150,6 +275,47 @@ pub struct PaginatedFetchResponse<T> {
     pub limit: usize,
     pub has_more_before: bool,
     pub has_more_after: bool,
+    pub snapshot: proto::SnapshotToken,
+    // how many records there are under the prefix, as of the snapshot
+    pub total_count: Option<u64>,
+    // how many of them come before the page's first item, in the order it's displayed
+    pub position: Option<u64>,
+}
+
+/// Where a key is among those in a range
+enum Offset {
+    // this many keys come before it
+    FromStart(u64),
+    // this many come after it
+    FromEnd(u64),
+}
+
+/// Find a key by reading the range from both ends at once, no more than MAX_POSITION_SCAN keys
+/// from either, so it's only found near one end or the other
+fn offset_of<S: Scan>(
+    view: &S,
+    range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+    key: &[u8],
+    reverse: bool,
+) -> Result<Option<Offset>, AppError> {
+    let mut forward = view.scan(range.clone(), reverse);
+    let mut backward = view.scan(range, !reverse);
+    for n in 0..MAX_POSITION_SCAN as u64 {
+        match forward.next() {
+            Some(item) => {
+                if *item?.0 == *key {
+                    return Ok(Some(Offset::FromStart(n)));
+                }
+            }
+            None => return Ok(None),
+        }
+        if let Some(item) = backward.next() {
+            if *item?.0 == *key {
+                return Ok(Some(Offset::FromEnd(n)));
+            }
+        }
+    }
+    Ok(None)
 }
 
 pub struct FetchResultItem<T> {
@@ -157,12 +323,12 @@ pub struct FetchResultItem<T> {
     pub item: T,
 }
 
+/// Fetch a page of the records which satisfy the predicate, see fetch_records
 pub fn fetch_paginated<T: proto::Record>(
     state: &AppState,
     request: PaginatedFetchRequest,
+    predicate: impl Fn(&T) -> bool,
 ) -> Result<PaginatedFetchResponse<T>, AppError> {
-    let tree = state.storage.subtree(request.tree)?;
-
     let mut query = FetchRecordQuery::new();
 
     let display_order = request.direction;
@@ -189,14 +355,59 @@ pub fn fetch_paginated<T: proto::Record>(
             // display order ascending 5,6 -> after 6 -> ascending 7,8
             // display order descending 6,5 -> after 5 -> descending 4,3
         }
-        _ => (FetchCursor::None, display_order),
+        // an empty key is the start, or the end, of the whole range
+        proto::PaginatedCursor::StartingWith(ref start) if start.is_empty() => {
+            (FetchCursor::None, display_order)
+        }
+        proto::PaginatedCursor::EndingWith(ref end) if end.is_empty() => {
+            (FetchCursor::None, display_order.inverse())
+        }
+        proto::PaginatedCursor::StartingWith(ref start) => {
+            has_more_before = true;
+            (FetchCursor::Including(start.clone()), display_order)
+
+            // display order ascending 5,6 -> starting with 5 -> ascending 5,6
+            // display order descending 6,5 -> starting with 6 -> descending 6,5
+        }
+        proto::PaginatedCursor::EndingWith(ref end) => {
+            has_more_after = true;
+            (FetchCursor::Including(end.clone()), display_order.inverse())
+
+            // display order ascending 5,6 -> ending with 6 -> descending 6,5
+            // display order descending 6,5 -> ending with 5 -> ascending 5,6
+        }
     };
 
     query = query.cursor(cursor);
     query = query.direction(query_order);
     query = query.limit(request.limit);
-
-    let fetch_result = crate::query::fetch_records::<T, _>(&tree, query)?;
+    query = query.cancel(request.cancel);
+    query = query.prefix(&request.prefix);
+    let count = request.count && request.bounds == (Bound::Unbounded, Bound::Unbounded);
+    query = query.bounds(request.bounds);
+
+    let (fetched, snapshot) =
+        state
+            .storage
+            .read_as_of(&request.tree, request.snapshot.as_ref(), |view| {
+                let fetch_result = fetch_records::<T, _, _>(view, query, predicate)?;
+                // the first item as it's displayed
+                let first = match display_order == query_order {
+                    true => fetch_result.items.first(),
+                    false => fetch_result.items.last(),
+                };
+                let offset = match first {
+                    Some((key, _)) if count => offset_of(
+                        view,
+                        prefix_range(&request.prefix),
+                        key,
+                        display_order == proto::Direction::Descending,
+                    )?,
+                    _ => None,
+                };
+                Ok::<_, AppError>((fetch_result, offset))
+            })?;
+    let (fetch_result, offset) = fetched?;
 
     if query_order == display_order {
         has_more_after = fetch_result.more_records;
@@ -209,6 +420,20 @@ pub fn fetch_paginated<T: proto::Record>(
         items.reverse();
     }
 
+    let total_count = match count {
+        true => Some(
+            state
+                .storage
+                .count(&request.tree, &request.prefix, Some(&snapshot))?,
+        ),
+        false => None,
+    };
+    let position = match (offset, total_count) {
+        (Some(Offset::FromStart(before)), _) => Some(before),
+        (Some(Offset::FromEnd(after)), Some(total)) => Some(total.saturating_sub(after + 1)),
+        _ => None,
+    };
+
     return Ok(PaginatedFetchResponse {
         items: items
             .into_iter()
@@ -220,6 +445,9 @@ pub fn fetch_paginated<T: proto::Record>(
         limit: request.limit,
         has_more_before,
         has_more_after,
+        snapshot,
+        total_count,
+        position,
     });
 }
 
@@ -266,7 +494,7 @@ mod tests {
         let query = FetchRecordQuery::<usize>::new()
             .limit(5)
             .direction(Direction::Ascending);
-        let result = fetch_records::<TestRecord, _>(&tree, query).unwrap();
+        let result = fetch_records::<TestRecord, _, _>(&tree, query, |_| true).unwrap();
 
         //the first 5 should be the oldest 5
         assert_eq!(result.items.len(), 5);
@@ -279,7 +507,7 @@ mod tests {
         let query = FetchRecordQuery::<usize>::new()
             .cursor(FetchCursor::Excluding(4))
             .limit(5);
-        let result = fetch_records::<TestRecord, _>(&tree, query).unwrap();
+        let result = fetch_records::<TestRecord, _, _>(&tree, query, |_| true).unwrap();
         assert_eq!(result.items.len(), 5);
         assert_eq!(result.ids(), &[5, 6, 7, 8, 9]);
 
@@ -290,19 +518,25 @@ mod tests {
         let query = FetchRecordQuery::<usize>::new()
             .cursor(FetchCursor::Excluding(9))
             .limit(5);
-        let result = fetch_records::<TestRecord, _>(&tree, query).unwrap();
+        let result = fetch_records::<TestRecord, _, _>(&tree, query, |_| true).unwrap();
         assert_eq!(result.items.len(), 2);
         assert_eq!(result.ids(), &[10, 11]);
 
         // "next page" button is not shown
         assert!(!result.more_records);
 
+        // the user navigated away before the page loaded
+        let cancel = CancelToken::new();
+        cancel.cancel();
+        let query = FetchRecordQuery::<usize>::new().cancel(cancel);
+        assert!(fetch_records::<TestRecord, _, _>(&tree, query, |_| true).is_err());
+
         // user clicks "previous page" button
         let query = FetchRecordQuery::<usize>::new()
             .cursor(FetchCursor::Excluding(10))
             .limit(5)
             .direction(Direction::Descending);
-        let result = fetch_records::<TestRecord, _>(&tree, query).unwrap();
+        let result = fetch_records::<TestRecord, _, _>(&tree, query, |_| true).unwrap();
         assert_eq!(result.items.len(), 5);
         assert_eq!(result.ids(), &[9, 8, 7, 6, 5]);
         // "previous page" button is shown
@@ -313,7 +547,7 @@ mod tests {
             .cursor(FetchCursor::Excluding(5))
             .limit(5)
             .direction(Direction::Descending);
-        let result = fetch_records::<TestRecord, _>(&tree, query).unwrap();
+        let result = fetch_records::<TestRecord, _, _>(&tree, query, |_| true).unwrap();
         assert_eq!(result.items.len(), 5);
         assert_eq!(result.ids(), &[4, 3, 2, 1, 0]);
 
@@ -324,7 +558,7 @@ mod tests {
         let query = FetchRecordQuery::<usize>::new()
             .cursor(FetchCursor::Excluding(0))
             .limit(5);
-        let result = fetch_records::<TestRecord, _>(&tree, query).unwrap();
+        let result = fetch_records::<TestRecord, _, _>(&tree, query, |_| true).unwrap();
         assert_eq!(result.items.len(), 5);
         assert_eq!(result.ids(), &[1, 2, 3, 4, 5]);
         assert!(result.more_records);
@@ -334,7 +568,7 @@ mod tests {
             .cursor(FetchCursor::Excluding(0))
             .limit(5)
             .direction(Direction::Descending);
-        let result = fetch_records::<TestRecord, _>(&tree, query).unwrap();
+        let result = fetch_records::<TestRecord, _, _>(&tree, query, |_| true).unwrap();
         assert_eq!(result.items.len(), 0);
         assert!(!result.more_records);
 
@@ -343,8 +577,219 @@ mod tests {
             .cursor(FetchCursor::Excluding(11))
             .limit(5)
             .direction(Direction::Ascending);
-        let result = fetch_records::<TestRecord, _>(&tree, query).unwrap();
+        let result = fetch_records::<TestRecord, _, _>(&tree, query, |_| true).unwrap();
         assert_eq!(result.items.len(), 0);
         assert!(!result.more_records);
     }
+
+    #[test]
+    fn test_fetch_prefix() {
+        let storage = StorageEngine::new_test().unwrap();
+        let tree = storage.subtree("test").unwrap();
+
+        // two namespaces either side of the one we're interested in
+        for (prefix, ids) in [("a|", 0..3), ("b|", 3..8), ("c|", 8..10)] {
+            for id in ids {
+                let record = TestRecord {
+                    id,
+                    value: format!("test value {}", id),
+                };
+                let key = format!("{}{}", prefix, id);
+                tree.insert(key.as_bytes(), bincode::serialize(&record).unwrap())
+                    .unwrap();
+            }
+        }
+
+        let query = FetchRecordQuery::<Vec<u8>>::new().prefix(b"b|").limit(3);
+        let result = fetch_records::<TestRecord, _, _>(&tree, query, |_| true).unwrap();
+        assert_eq!(result.ids(), &[3, 4, 5]);
+        assert!(result.more_records);
+
+        let query = FetchRecordQuery::<Vec<u8>>::new()
+            .prefix(b"b|")
+            .cursor(FetchCursor::Excluding(b"b|5".to_vec()))
+            .limit(3);
+        let result = fetch_records::<TestRecord, _, _>(&tree, query, |_| true).unwrap();
+        assert_eq!(result.ids(), &[6, 7]);
+        assert!(!result.more_records);
+
+        let query = FetchRecordQuery::<Vec<u8>>::new()
+            .prefix(b"b|")
+            .direction(Direction::Descending)
+            .limit(10);
+        let result = fetch_records::<TestRecord, _, _>(&tree, query, |_| true).unwrap();
+        assert_eq!(result.ids(), &[7, 6, 5, 4, 3]);
+
+        // a cursor from outside the prefix doesn't let the fetch escape it
+        let query = FetchRecordQuery::<Vec<u8>>::new()
+            .prefix(b"b|")
+            .cursor(FetchCursor::Excluding(b"a|0".to_vec()))
+            .direction(Direction::Descending);
+        let result = fetch_records::<TestRecord, _, _>(&tree, query, |_| true).unwrap();
+        assert!(result.items.is_empty());
+
+        assert_eq!(
+            prefix_range(&[1, 0xff]),
+            (Bound::Included(vec![1, 0xff]), Bound::Excluded(vec![2]))
+        );
+        assert_eq!(
+            prefix_range(&[0xff]),
+            (Bound::Included(vec![0xff]), Bound::Unbounded)
+        );
+    }
+
+    #[test]
+    fn test_fetch_paginated_inclusive() {
+        let state = AppState::new_test().unwrap();
+        for id in 0usize..8 {
+            let record = TestRecord {
+                id,
+                value: format!("test value {}", id),
+            };
+            state
+                .storage
+                .insert(
+                    "test",
+                    id.to_be_bytes(),
+                    bincode::serialize(&record).unwrap(),
+                )
+                .unwrap();
+        }
+        let fetch = |cursor, direction| {
+            let request = PaginatedFetchRequest {
+                tree: "test".to_string(),
+                cursor,
+                limit: 3,
+                direction,
+                snapshot: None,
+                cancel: CancelToken::new(),
+                prefix: Vec::new(),
+                bounds: (Bound::Unbounded, Bound::Unbounded),
+                count: false,
+            };
+            let page = fetch_paginated::<TestRecord>(&state, request, |_| true).unwrap();
+            let ids: Vec<usize> = page.items.iter().map(|item| item.item.id).collect();
+            (ids, page.has_more_before, page.has_more_after)
+        };
+        let key = |id: usize| id.to_be_bytes().to_vec();
+        use proto::PaginatedCursor::*;
+
+        // the page starts or ends with the cursor's key, in the order it's displayed
+        assert_eq!(
+            fetch(StartingWith(key(2)), Direction::Ascending),
+            (vec![2, 3, 4], true, true)
+        );
+        assert_eq!(
+            fetch(StartingWith(key(2)), Direction::Descending),
+            (vec![2, 1, 0], true, false)
+        );
+        assert_eq!(
+            fetch(EndingWith(key(2)), Direction::Ascending),
+            (vec![0, 1, 2], false, true)
+        );
+        assert_eq!(
+            fetch(EndingWith(key(5)), Direction::Descending),
+            (vec![7, 6, 5], false, true)
+        );
+        // a key which isn't there starts or ends the page where it would be
+        let mut between = key(4);
+        between.push(0);
+        assert_eq!(
+            fetch(StartingWith(between), Direction::Ascending),
+            (vec![5, 6, 7], true, false)
+        );
+        // and an empty one at the very start, or end
+        assert_eq!(
+            fetch(StartingWith(vec![]), Direction::Descending),
+            (vec![7, 6, 5], false, true)
+        );
+        assert_eq!(
+            fetch(EndingWith(vec![]), Direction::Descending),
+            (vec![2, 1, 0], true, false)
+        );
+    }
+
+    #[test]
+    fn test_fetch_paginated_position() {
+        let state = AppState::new_test().unwrap();
+        let total = MAX_POSITION_SCAN * 2 + 10;
+        for id in 0..total {
+            let record = TestRecord {
+                id,
+                value: String::new(),
+            };
+            state
+                .storage
+                .insert(
+                    "test",
+                    id.to_be_bytes(),
+                    bincode::serialize(&record).unwrap(),
+                )
+                .unwrap();
+        }
+        let fetch = |cursor, direction, count| {
+            let request = PaginatedFetchRequest {
+                tree: "test".to_string(),
+                cursor,
+                limit: 10,
+                direction,
+                snapshot: None,
+                cancel: CancelToken::new(),
+                prefix: Vec::new(),
+                bounds: (Bound::Unbounded, Bound::Unbounded),
+                count,
+            };
+            let page = fetch_paginated::<TestRecord>(&state, request, |_| true).unwrap();
+            (page.total_count, page.position)
+        };
+        let key = |id: usize| id.to_be_bytes().to_vec();
+        use proto::PaginatedCursor::*;
+        let total = Some(total as u64);
+
+        assert_eq!(
+            fetch(StartingWith(vec![]), Direction::Ascending, true),
+            (total, Some(0))
+        );
+        // counted in the order the page is displayed
+        assert_eq!(
+            fetch(After(key(29)), Direction::Ascending, true),
+            (total, Some(30))
+        );
+        assert_eq!(
+            fetch(After(key(29)), Direction::Descending, true),
+            (total, total.map(|total| total - 29))
+        );
+        // too far from either end to be worked out
+        assert_eq!(
+            fetch(
+                StartingWith(key(MAX_POSITION_SCAN + 5)),
+                Direction::Ascending,
+                true
+            ),
+            (total, None)
+        );
+        // and neither is given unless asked for
+        assert_eq!(
+            fetch(StartingWith(vec![]), Direction::Ascending, false),
+            (None, None)
+        );
+
+        // as of the page's snapshot
+        let (_, snapshot) = state.storage.read_as_of("test", None, |_| ()).unwrap();
+        state.storage.remove("test", key(0)).unwrap();
+        let request = PaginatedFetchRequest {
+            tree: "test".to_string(),
+            cursor: StartingWith(vec![]),
+            limit: 10,
+            direction: Direction::Ascending,
+            snapshot: Some(snapshot),
+            cancel: CancelToken::new(),
+            prefix: Vec::new(),
+            bounds: (Bound::Unbounded, Bound::Unbounded),
+            count: true,
+        };
+        let page = fetch_paginated::<TestRecord>(&state, request, |_| true).unwrap();
+        assert_eq!(page.total_count, total);
+        assert_eq!(page.items[0].item.id, 0);
+    }
 }
@@ -0,0 +1,222 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use serde::Serialize;
+use ulid::Ulid;
+
+use crate::{
+    config::ServerConfig,
+    sources::is_ingress_tree,
+    spill::{SpillBuffer, SPILL_ABOVE},
+    storage::StorageEngine,
+    tags, thumbnail, AppState,
+};
+
+/// How long ingress logs are kept, and how many of them. Either limit may be left unset.
+#[derive(Clone, Debug)]
+pub struct RetentionPolicy {
+    pub max_age: Option<Duration>,
+    // per ingress tree
+    pub max_count: Option<usize>,
+    pub interval: Duration,
+}
+
+impl RetentionPolicy {
+    /// None unless the config limits either the age or the number of logs
+    pub fn from_config(config: &ServerConfig) -> Option<Self> {
+        if config.retain_for.is_none() && config.retain_count.is_none() {
+            return None;
+        }
+        Some(Self {
+            max_age: config.retain_for.map(Duration::from_secs),
+            max_count: config.retain_count,
+            interval: Duration::from_secs(config.prune_every.max(1)),
+        })
+    }
+}
+
+/// Running totals for the pruning task, as reported on /status
+#[derive(Default)]
+pub struct RetentionStats {
+    passes: AtomicU64,
+    removed: AtomicU64,
+    last_removed: AtomicU64,
+    failures: AtomicU64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RetentionSnapshot {
+    pub passes: u64,
+    pub removed: u64,
+    // by the most recent pass
+    pub last_removed: u64,
+    pub failures: u64,
+}
+
+impl RetentionStats {
+    pub fn snapshot(&self) -> RetentionSnapshot {
+        RetentionSnapshot {
+            passes: self.passes.load(Ordering::Relaxed),
+            removed: self.removed.load(Ordering::Relaxed),
+            last_removed: self.last_removed.load(Ordering::Relaxed),
+            failures: self.failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Trees which hold ingress logs
+fn ingress_trees(storage: &StorageEngine) -> Vec<String> {
+    storage
+        .db
+        .tree_names()
+        .into_iter()
+        .map(|name| String::from_utf8_lossy(&name).into_owned())
+        .filter(|name| is_ingress_tree(name))
+        .collect()
+}
+
+/// When a log was captured, from the ULID its key ends with
+fn captured_at(key: &[u8]) -> Option<SystemTime> {
+    let key = std::str::from_utf8(key).ok()?;
+    let id = key.rsplit('|').next()?;
+    Ulid::from_string(id).ok().map(|id| id.datetime())
+}
+
+/// Remove the logs in a tree which the policy no longer allows, returning how many were removed.
+///
+/// Every key in an ingress tree is a common prefix followed by the log's ULID, so keys sort by
+/// capture time and only the start of the tree needs to be looked at. Removals go through the
+/// storage engine so that subscribers and views see them.
+pub fn prune(
+    storage: &StorageEngine,
+    tree: &str,
+    policy: &RetentionPolicy,
+    now: SystemTime,
+) -> Result<u64> {
+    let subtree = storage.subtree(tree)?;
+    let excess = policy
+        .max_count
+        .map_or(0, |max| subtree.len().saturating_sub(max));
+    let cutoff = policy
+        .max_age
+        .and_then(|age| now.checked_sub(age))
+        .unwrap_or(UNIX_EPOCH);
+
+    // gathered before any are removed, as removing them while iterating the tree could miss some
+    let mut expired = SpillBuffer::new(storage, SPILL_ABOVE);
+    for key in subtree.iter().keys() {
+        let key = key?;
+        let too_old = captured_at(&key).is_some_and(|captured| captured < cutoff);
+        if expired.len() >= excess && !too_old {
+            break;
+        }
+        expired.push(key.to_vec(), &())?;
+    }
+
+    let mut removed = 0;
+    for item in expired.into_items()? {
+        let (key, ()) = item?;
+        if storage.remove(tree, &key)?.is_some() {
+            tags::forget(storage, tree, &key)?;
+            thumbnail::forget(storage, tree, &key)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Prune every ingress tree according to the policy for as long as the server runs
+pub fn spawn_retention(state: AppState, policy: RetentionPolicy) {
+    println!(
+        "Pruning ingress logs every {:?}: {:?}",
+        policy.interval, policy
+    );
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(policy.interval);
+        loop {
+            interval.tick().await;
+            let pass_state = state.clone();
+            let pass_policy = policy.clone();
+            // sled calls block, so keep them off the async runtime
+            let result = tokio::task::spawn_blocking(move || {
+                let now = SystemTime::now();
+                let mut removed = 0;
+                for tree in ingress_trees(&pass_state.storage) {
+                    removed += prune(&pass_state.storage, &tree, &pass_policy, now)?;
+                }
+                Ok::<_, anyhow::Error>(removed)
+            })
+            .await;
+
+            let stats = &state.retention;
+            stats.passes.fetch_add(1, Ordering::Relaxed);
+            match result {
+                Ok(Ok(removed)) => {
+                    stats.removed.fetch_add(removed, Ordering::Relaxed);
+                    stats.last_removed.store(removed, Ordering::Relaxed);
+                    if removed > 0 {
+                        println!("Pruned {} ingress logs", removed);
+                    }
+                }
+                Ok(Err(e)) => {
+                    stats.failures.fetch_add(1, Ordering::Relaxed);
+                    println!("Failed to prune ingress logs: {:?}", e);
+                }
+                Err(e) => {
+                    stats.failures.fetch_add(1, Ordering::Relaxed);
+                    println!("Pruning ingress logs panicked: {:?}", e);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert(storage: &StorageEngine, captured: SystemTime) {
+        let id = Ulid::from_datetime(captured);
+        storage
+            .insert("ingress", format!("test|{}", id), vec![])
+            .unwrap();
+    }
+
+    #[test]
+    fn test_prune() {
+        let storage = StorageEngine::new_test().unwrap();
+        let now = SystemTime::now();
+        let hours_ago = |hours: u64| now - Duration::from_secs(hours * 3600);
+        for hours in [5, 4, 3, 2, 1, 0] {
+            insert(&storage, hours_ago(hours));
+        }
+        let mut events = storage.subscribe();
+
+        let by_age = RetentionPolicy {
+            max_age: Some(Duration::from_secs(3 * 3600 + 60)),
+            max_count: None,
+            interval: Duration::from_secs(60),
+        };
+        assert_eq!(prune(&storage, "ingress", &by_age, now).unwrap(), 2);
+        assert_eq!(storage.subtree("ingress").unwrap().len(), 4);
+        // removals are published like any other
+        assert!(events.try_recv().is_ok());
+
+        let by_count = RetentionPolicy {
+            max_age: None,
+            max_count: Some(1),
+            ..by_age
+        };
+        assert_eq!(prune(&storage, "ingress", &by_count, now).unwrap(), 3);
+        let tree = storage.subtree("ingress").unwrap();
+        let (newest, _) = tree.first().unwrap().unwrap();
+        assert!(captured_at(&newest).unwrap() > hours_ago(1));
+
+        // nothing left to prune
+        assert_eq!(prune(&storage, "ingress", &by_count, now).unwrap(), 0);
+        assert_eq!(ingress_trees(&storage), vec!["ingress".to_string()]);
+    }
+}
@@ -0,0 +1,259 @@
+//! JSON Schemas registered for record collections, so that a producer which changes the shape of
+//! what it writes is noticed straight away rather than when something downstream breaks.
+//!
+//! Schemas are kept in the `schemas` tree by collection tree. Records which don't match their
+//! collection's schema are stored all the same, but the reasons they don't match are kept in the
+//! `invalid` tree, under the record's side key, until they're fixed, deleted, or the schema changes.
+
+use std::{
+    collections::HashMap,
+    ops::Bound,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::Result;
+use hydra_proto as proto;
+use jsonschema::JSONSchema;
+use ulid::Ulid;
+
+use crate::{error::AppError, handler::record::side_key, storage::StorageEngine};
+
+pub const SCHEMA_TREE: &str = "schemas";
+pub const INVALID_TREE: &str = "invalid";
+
+fn compile(text: &str) -> Result<JSONSchema, String> {
+    let schema: serde_json::Value =
+        serde_json::from_str(text).map_err(|e| format!("Schema is not valid JSON: {}", e))?;
+    JSONSchema::compile(&schema).map_err(|e| format!("Invalid schema: {}", e))
+}
+
+/// Why a record's value doesn't match the schema, or nothing if it does
+pub fn violations(schema: &JSONSchema, value: &[u8]) -> Vec<String> {
+    let value: serde_json::Value = match serde_json::from_slice(value) {
+        Ok(value) => value,
+        Err(e) => return vec![format!("Not valid JSON: {}", e)],
+    };
+    let violations = match schema.validate(&value) {
+        Ok(()) => vec![],
+        Err(errors) => errors
+            .map(|error| match error.instance_path.to_string() {
+                path if path.is_empty() => error.to_string(),
+                path => format!("{}: {}", path, error),
+            })
+            .collect(),
+    };
+    violations
+}
+
+/// The compiled schema of every collection which has been written to, by collection tree
+#[derive(Default)]
+pub struct Schemas {
+    // None for collections without a schema
+    compiled: RwLock<HashMap<String, Option<Arc<JSONSchema>>>>,
+}
+
+impl Schemas {
+    fn schema(&self, storage: &StorageEngine, tree: &str) -> Result<Option<Arc<JSONSchema>>> {
+        if let Some(schema) = self.compiled.read().unwrap().get(tree) {
+            return Ok(schema.clone());
+        }
+        // loaded with the lock held, so that it can't race with a new schema being set
+        let mut compiled = self.compiled.write().unwrap();
+        if let Some(schema) = compiled.get(tree) {
+            return Ok(schema.clone());
+        }
+        let schema = match storage.subtree(SCHEMA_TREE)?.get(tree)? {
+            Some(text) => {
+                let schema = compile(std::str::from_utf8(&text)?).map_err(anyhow::Error::msg)?;
+                Some(Arc::new(schema))
+            }
+            None => None,
+        };
+        compiled.insert(tree.to_string(), schema.clone());
+        Ok(schema)
+    }
+
+    /// Check a record which has just been written against its collection's schema, if it has
+    /// one, returning why it doesn't match
+    pub fn check(
+        &self,
+        storage: &StorageEngine,
+        tree: &str,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<Vec<String>> {
+        let Some(schema) = self.schema(storage, tree)? else {
+            return Ok(vec![]);
+        };
+        let violations = violations(&schema, value);
+        mark(storage, tree, key, &violations)?;
+        Ok(violations)
+    }
+
+    /// Forget whether a deleted record matched
+    pub fn forget(&self, storage: &StorageEngine, tree: &str, key: &[u8]) -> Result<()> {
+        storage.remove(INVALID_TREE, side_key(tree, key))?;
+        Ok(())
+    }
+
+    /// Replace a collection's schema, or remove it, then check every record in the collection
+    /// against it, returning how many don't match. Records written while they're being checked
+    /// are checked as they're written, against the new schema.
+    pub fn set(
+        &self,
+        storage: &StorageEngine,
+        tree: &str,
+        schema: Option<&str>,
+    ) -> Result<u64, AppError> {
+        let compiled = schema
+            .map(compile)
+            .transpose()
+            .map_err(AppError::invalid_request)?
+            .map(Arc::new);
+        {
+            let mut cache = self.compiled.write().unwrap();
+            match schema {
+                Some(text) => storage.insert(SCHEMA_TREE, tree, text.as_bytes())?,
+                None => storage.remove(SCHEMA_TREE, tree)?,
+            };
+            cache.insert(tree.to_string(), compiled.clone());
+        }
+
+        for key in storage
+            .subtree(INVALID_TREE)?
+            .scan_prefix(side_key(tree, &[]))
+            .keys()
+        {
+            storage.remove(INVALID_TREE, key?)?;
+        }
+        let Some(schema) = compiled else {
+            return Ok(0);
+        };
+        let mut invalid = 0;
+        for entry in storage.subtree(tree)?.iter() {
+            let (key, value) = entry?;
+            let violations = violations(&schema, &value);
+            if !violations.is_empty() {
+                mark(storage, tree, &key, &violations)?;
+                invalid += 1;
+            }
+        }
+        Ok(invalid)
+    }
+}
+
+fn mark(storage: &StorageEngine, tree: &str, key: &[u8], violations: &[String]) -> Result<()> {
+    let key = side_key(tree, key);
+    if violations.is_empty() {
+        storage.remove(INVALID_TREE, key)?;
+    } else {
+        storage.insert(INVALID_TREE, key, serde_json::to_vec(violations)?)?;
+    }
+    Ok(())
+}
+
+/// Up to `limit` of a collection's records which don't match its schema, starting after the
+/// record `after`, and whether there are more
+pub fn invalid_records(
+    storage: &StorageEngine,
+    tree: &str,
+    after: Option<Ulid>,
+    limit: usize,
+) -> Result<(Vec<proto::InvalidRecord>, bool)> {
+    let prefix = side_key(tree, &[]);
+    let start = match after {
+        Some(id) => Bound::Excluded(side_key(tree, &id.to_bytes())),
+        None => Bound::Included(prefix.clone()),
+    };
+    let mut records = Vec::new();
+    for entry in storage
+        .subtree(INVALID_TREE)?
+        .range::<Vec<u8>, _>((start, Bound::Unbounded))
+    {
+        let (key, value) = entry?;
+        let Some(id) = key.strip_prefix(prefix.as_slice()) else {
+            break;
+        };
+        if records.len() == limit {
+            return Ok((records, true));
+        }
+        let id = <[u8; 16]>::try_from(id)?;
+        records.push(proto::InvalidRecord {
+            id: Ulid::from_bytes(id),
+            errors: serde_json::from_slice(&value)?,
+        });
+    }
+    Ok((records, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schemas() {
+        let storage = StorageEngine::new_test().unwrap();
+        let schemas = Schemas::default();
+        let tree = "records/people";
+        let ids: Vec<_> = (0..4).map(|n| Ulid::from_parts(n, 0).to_bytes()).collect();
+        let values: [&[u8]; 4] = [
+            br#"{"name":"ada"}"#,
+            br#"{"name":7}"#,
+            br#"{}"#,
+            b"not json",
+        ];
+        for (id, value) in ids.iter().zip(values) {
+            storage.insert(tree, id, value).unwrap();
+        }
+
+        let schema =
+            r#"{"type":"object","required":["name"],"properties":{"name":{"type":"string"}}}"#;
+        assert_eq!(schemas.set(&storage, tree, Some(schema)).unwrap(), 3);
+        let (invalid, has_more) = invalid_records(&storage, tree, None, 10).unwrap();
+        assert!(!has_more);
+        let invalid_ids: Vec<_> = invalid.iter().map(|record| record.id.to_bytes()).collect();
+        assert_eq!(invalid_ids, ids[1..]);
+        assert!(invalid[0].errors[0].starts_with("/name: "));
+        assert!(invalid[2].errors[0].starts_with("Not valid JSON"));
+
+        // paging
+        let (page, has_more) = invalid_records(&storage, tree, None, 2).unwrap();
+        assert_eq!(page.len(), 2);
+        assert!(has_more);
+        let (rest, has_more) = invalid_records(&storage, tree, Some(page[1].id), 2).unwrap();
+        assert_eq!(rest.len(), 1);
+        assert!(!has_more);
+
+        // fixing a record clears its mark, breaking it sets one
+        assert!(schemas
+            .check(&storage, tree, &ids[1], br#"{"name":"bob"}"#)
+            .unwrap()
+            .is_empty());
+        assert!(!schemas
+            .check(&storage, tree, &ids[0], b"[]")
+            .unwrap()
+            .is_empty());
+        schemas.forget(&storage, tree, &ids[3]).unwrap();
+        let (invalid, _) = invalid_records(&storage, tree, None, 10).unwrap();
+        let invalid_ids: Vec<_> = invalid.iter().map(|record| record.id.to_bytes()).collect();
+        assert_eq!(invalid_ids, vec![ids[0], ids[2]]);
+
+        // other collections aren't affected
+        assert!(invalid_records(&storage, "records/p", None, 10)
+            .unwrap()
+            .0
+            .is_empty());
+        assert!(schemas
+            .check(&storage, "records/other", &ids[0], b"x")
+            .unwrap()
+            .is_empty());
+
+        // removing the schema clears every mark
+        assert!(schemas.set(&storage, tree, Some("{")).is_err());
+        assert_eq!(schemas.set(&storage, tree, None).unwrap(), 0);
+        assert!(invalid_records(&storage, tree, None, 10)
+            .unwrap()
+            .0
+            .is_empty());
+    }
+}
@@ -0,0 +1,241 @@
+//! `hydra-server --self-test`, which checks that the server could run where it's deployed (the
+//! database opens and can be written and read, messages survive encoding, the port is free, and
+//! any peers given are reachable) and prints a report, without serving anything. It exits with an
+//! error if any check fails, so it can gate a deploy.
+
+use std::{
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use hydra_proto as proto;
+use ulid::Ulid;
+
+use crate::{
+    alerts, appstate, checkpoints, computed,
+    config::{ServerConfig, LISTEN_ADDR},
+    sources,
+    storage::StorageEngine,
+};
+
+/// How long to wait for each peer to accept a connection
+const PEER_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How many records the storage check writes and reads back
+const STORAGE_RECORDS: u8 = 16;
+
+/// The outcome of one check: a description of what was found if it passed, or why it failed
+pub struct Check {
+    pub name: String,
+    pub result: Result<String>,
+    pub took: Duration,
+}
+
+fn check(name: impl Into<String>, run: impl FnOnce() -> Result<String>) -> Check {
+    let start = Instant::now();
+    let result = run();
+    Check {
+        name: name.into(),
+        result,
+        took: start.elapsed(),
+    }
+}
+
+/// Run every check, print the report, and fail if any check did
+pub fn run(config: &ServerConfig) -> Result<()> {
+    let checks = run_checks(config);
+    print!("{}", report(&checks));
+    let failed = checks.iter().filter(|check| check.result.is_err()).count();
+    if failed > 0 {
+        bail!("{} of {} self-test checks failed", failed, checks.len());
+    }
+    Ok(())
+}
+
+fn run_checks(config: &ServerConfig) -> Vec<Check> {
+    let mut checks = vec![check("config", || check_config(config))];
+    let mut storage = None;
+    checks.push(check("storage open", || {
+        let storage_config = appstate::storage_config(config)?;
+        let path = storage_config.path().display().to_string();
+        storage = Some(StorageEngine::open(&storage_config)?);
+        Ok(path)
+    }));
+    match &storage {
+        Some(storage) => checks.extend(check_storage(storage)),
+        None => checks.push(check("storage read/write", || {
+            bail!("Skipped, as the database didn't open")
+        })),
+    }
+    checks.push(check("codec", check_codec));
+    checks.push(check("port", || {
+        let listener = TcpListener::bind(LISTEN_ADDR)
+            .with_context(|| format!("Could not listen on {}", LISTEN_ADDR))?;
+        Ok(format!("{} is free", listener.local_addr()?))
+    }));
+    for peer in &config.self_test_peer {
+        checks.push(check(format!("peer {}", peer), || check_peer(peer)));
+    }
+    checks
+}
+
+/// The files named in the config can be read
+fn check_config(config: &ServerConfig) -> Result<String> {
+    let mut found = Vec::new();
+    if let Some(path) = &config.sources {
+        sources::load_sources(path)?;
+        found.push(format!("sources from {}", path.display()));
+    }
+    if let Some(path) = &config.computed_fields {
+        computed::load_fields(path)?;
+        found.push(format!("computed fields from {}", path.display()));
+    }
+    if let Some(path) = &config.checkpoint_key {
+        checkpoints::load_key(path)?;
+        found.push(format!("checkpoint key from {}", path.display()));
+    }
+    if let Some(path) = &config.alerts {
+        let rules = alerts::load_rules(path)?;
+        found.push(format!("{} alert rules", rules.len()));
+    }
+    Ok(match found.is_empty() {
+        true => "defaults".to_string(),
+        false => found.join(", "),
+    })
+}
+
+/// Write, read and scan records in a scratch tree, which is dropped afterwards
+fn check_storage(storage: &StorageEngine) -> Vec<Check> {
+    let tree = format!("selftest/{}", Ulid::new());
+    let checks = vec![
+        check("storage write", || {
+            for n in 0..STORAGE_RECORDS {
+                storage.insert(&tree, [n], vec![n; n as usize])?;
+            }
+            storage.db.flush()?;
+            Ok(format!("{} records, flushed", STORAGE_RECORDS))
+        }),
+        check("storage read", || {
+            let n = STORAGE_RECORDS - 1;
+            match storage.get(&tree, [n])? {
+                Some(value) if value == vec![n; n as usize] => Ok("read back".to_string()),
+                Some(_) => bail!("Read back a different value to the one written"),
+                None => bail!("Record written is missing"),
+            }
+        }),
+        check("storage scan", || {
+            let keys = storage
+                .subtree(&tree)?
+                .iter()
+                .keys()
+                .collect::<sled::Result<Vec<_>>>()?;
+            let expected: Vec<_> = (0..STORAGE_RECORDS).map(|n| vec![n]).collect();
+            if keys.iter().map(|key| key.to_vec()).ne(expected) {
+                bail!(
+                    "Scanned {} keys, not the {} written",
+                    keys.len(),
+                    STORAGE_RECORDS
+                );
+            }
+            Ok(format!("{} keys in order", keys.len()))
+        }),
+    ];
+    if let Err(e) = storage.db.drop_tree(&tree) {
+        println!("Failed to drop {}: {:?}", tree, e);
+    }
+    checks
+}
+
+/// A message survives bincode and JSON encoding unchanged
+fn check_codec() -> Result<String> {
+    let message = proto::Message::Request(proto::Request {
+        id: 1,
+        idempotency_key: Some(Ulid::new()),
+        trace_id: Some(Ulid::new()),
+        payload: proto::RequestPayload::Cancel(proto::CancelRequest { request_id: 2 }),
+    });
+    let bincode_bytes = bincode::serialize(&message)?;
+    let json = serde_json::to_string(&message)?;
+    let from_bincode: proto::Message = bincode::deserialize(&bincode_bytes)?;
+    let from_json: proto::Message = serde_json::from_str(&json)?;
+    // messages can't be compared directly, so compare what they encode to
+    if bincode::serialize(&from_json)? != bincode_bytes {
+        bail!("JSON round trip changed the message");
+    }
+    if serde_json::to_string(&from_bincode)? != json {
+        bail!("Bincode round trip changed the message");
+    }
+    Ok("bincode and JSON round trips".to_string())
+}
+
+fn check_peer(peer: &str) -> Result<String> {
+    let addr = peer
+        .to_socket_addrs()
+        .with_context(|| format!("Could not resolve {}", peer))?
+        .next()
+        .ok_or_else(|| anyhow!("{} has no addresses", peer))?;
+    TcpStream::connect_timeout(&addr, PEER_TIMEOUT)
+        .with_context(|| format!("Could not connect to {}", addr))?;
+    Ok(format!("connected to {}", addr))
+}
+
+/// A line per check, then a summary
+pub fn report(checks: &[Check]) -> String {
+    let width = checks
+        .iter()
+        .map(|check| check.name.len())
+        .max()
+        .unwrap_or(0);
+    let mut report = String::from("Self-test\n");
+    for check in checks {
+        let (status, detail) = match &check.result {
+            Ok(detail) => ("ok", detail.clone()),
+            Err(e) => ("FAIL", format!("{:#}", e)),
+        };
+        report.push_str(&format!(
+            "  {:<4}  {:<width$}  {:>6.1}ms  {}\n",
+            status,
+            check.name,
+            check.took.as_secs_f64() * 1000.0,
+            detail,
+        ));
+    }
+    let failed = checks.iter().filter(|check| check.result.is_err()).count();
+    report.push_str(&match failed {
+        0 => format!("All {} checks passed\n", checks.len()),
+        _ => format!("{} of {} checks failed\n", failed, checks.len()),
+    });
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_self_test_checks() {
+        let storage = StorageEngine::new_test().unwrap();
+        let mut checks = check_storage(&storage);
+        assert!(checks.iter().all(|check| check.result.is_ok()));
+        // the scratch tree is dropped afterwards
+        assert!(!storage
+            .db
+            .tree_names()
+            .iter()
+            .any(|name| name.starts_with(b"selftest/")));
+        assert!(check_codec().is_ok());
+
+        // a listener which is already bound is a reachable peer
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = listener.local_addr().unwrap().to_string();
+        checks.push(check(format!("peer {}", peer), || check_peer(&peer)));
+        assert!(checks.last().unwrap().result.is_ok());
+        drop(listener);
+        checks.push(check("peer nowhere", || check_peer("nowhere.invalid:1")));
+        let report = report(&checks);
+        assert!(report.contains("  ok    storage write"), "{}", report);
+        assert!(report.contains("  FAIL  peer nowhere"), "{}", report);
+        assert!(report.ends_with("1 of 5 checks failed\n"), "{}", report);
+    }
+}
@@ -0,0 +1,665 @@
+//! Shadowing: mirroring captures, and with --shadow-mutations WebSocket mutations too, to a
+//! secondary hydra given by --shadow-to, eg. a staging server, or a new deployment being checked
+//! before traffic is moved over to it.
+//!
+//! Whatever's to be mirrored is queued in the outbox tree once the primary has applied it, and a
+//! background task sends it on, oldest first. So the primary only ever pays for one local write: a
+//! slow, failing or unreachable shadow never holds up or fails a capture or mutation. Sends which
+//! fail are retried with a growing delay, and when the outbox is full the oldest entries are
+//! dropped. Requests the shadow rejects aren't retried, they're counted, as they're the differences
+//! shadowing is there to find. How far behind the shadow is, and how many requests were sent,
+//! rejected, retried and dropped, is reported under `shadow` on /status.
+//!
+//! Captures are replayed over HTTP as they arrived, tokens included, marked so the shadow doesn't
+//! mirror them again. Mutations are sent over a WebSocket as they were made, each with an
+//! idempotency key (its own, or a new one) so a send retried after a timeout is only applied once.
+//! That also stops a server shadowed to itself going round in circles.
+
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use axum::http::{HeaderMap, Method, Uri};
+use bytes::Bytes;
+use chrono::Utc;
+use futures_util::{SinkExt, StreamExt};
+use hydra_proto as proto;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::Notify,
+};
+use tokio_tungstenite::{tungstenite, MaybeTlsStream, WebSocketStream};
+use ulid::Ulid;
+
+use crate::{config::ServerConfig, storage::StorageEngine, AppState};
+
+pub const OUTBOX_TREE: &str = "shadow/outbox";
+
+/// Marks captures mirrored from another hydra, which aren't mirrored again
+pub const SHADOWED_HEADER: &str = "x-hydra-shadowed";
+
+/// How long a single send may take, connecting included
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The longest to wait before retrying a send which failed
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Headers which describe the connection a request came over rather than the request itself
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "content-length",
+    "host",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// The secondary hydra to mirror to. Only plain HTTP is spoken, so put anything reached over the
+/// internet behind a TLS-terminating proxy on the primary's side.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShadowTarget {
+    host: String,
+    port: u16,
+}
+
+impl FromStr for ShadowTarget {
+    type Err = String;
+
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        let authority = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("{} is not an http:// URL", url))?
+            .trim_end_matches('/');
+        if authority.contains('/') {
+            return Err(format!("{} should name a server, without a path", url));
+        }
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .map_err(|_| format!("{} is not a port, in {}", port, url))?,
+            ),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(format!("{} has no host", url));
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for ShadowTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "http://{}:{}", self.host, self.port)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ShadowPolicy {
+    pub target: ShadowTarget,
+    // mirror WebSocket mutations as well as captures
+    pub mutations: bool,
+    pub outbox_limit: usize,
+}
+
+impl ShadowPolicy {
+    /// None unless the config gives a server to mirror to
+    pub fn from_config(config: &ServerConfig) -> Option<Self> {
+        Some(Self {
+            target: config.shadow_to.clone()?,
+            mutations: config.shadow_mutations,
+            outbox_limit: config.shadow_outbox_limit.max(1),
+        })
+    }
+}
+
+/// A request waiting to be mirrored
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub enum ShadowItem {
+    Capture {
+        method: String,
+        // eg. /ingress/github?token=...
+        path_and_query: String,
+        headers: Vec<(String, Vec<u8>)>,
+        body: Vec<u8>,
+    },
+    Mutation {
+        idempotency_key: Option<Ulid>,
+        // a bincode-encoded RequestPayload, as they can't be cloned
+        payload: Vec<u8>,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Queued {
+    queued_at_ms: i64,
+    item: ShadowItem,
+}
+
+/// How a send the shadow answered went
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Accepted,
+    Rejected(String),
+}
+
+/// What's been mirrored so far, as reported on /status
+#[derive(Serialize, Debug)]
+pub struct ShadowStatus {
+    pub target: String,
+    pub mutations: bool,
+    pub queued: usize,
+    // how long the oldest queued request has been waiting
+    pub lag_ms: u64,
+    pub sent: u64,
+    pub rejected: u64,
+    pub retries: u64,
+    pub dropped: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Default)]
+struct ShadowStats {
+    sent: AtomicU64,
+    rejected: AtomicU64,
+    retries: AtomicU64,
+    dropped: AtomicU64,
+}
+
+/// The outbox of requests to mirror, if shadowing is on
+pub struct Shadow {
+    policy: Option<ShadowPolicy>,
+    queued: AtomicUsize,
+    stats: ShadowStats,
+    last_error: Mutex<Option<String>>,
+    ids: Mutex<ulid::Generator>,
+    // woken whenever something is queued
+    wake: Notify,
+}
+
+impl Shadow {
+    pub fn new(policy: Option<ShadowPolicy>, storage: &StorageEngine) -> Result<Self> {
+        // left over from a previous run, and still to be sent
+        let queued = match policy {
+            Some(_) => storage.subtree(OUTBOX_TREE)?.len(),
+            None => 0,
+        };
+        Ok(Self {
+            policy,
+            queued: AtomicUsize::new(queued),
+            stats: ShadowStats::default(),
+            last_error: Mutex::new(None),
+            ids: Mutex::new(ulid::Generator::new()),
+            wake: Notify::new(),
+        })
+    }
+
+    pub fn policy(&self) -> Option<&ShadowPolicy> {
+        self.policy.as_ref()
+    }
+
+    /// A capture to mirror once it's been stored, if shadowing is on and it wasn't itself
+    /// mirrored. Taken before the request is picked apart, so it's replayed exactly as it arrived.
+    /// The mark of a mirrored capture is removed, so it's stored as it arrived at the primary.
+    pub fn capture(
+        &self,
+        method: &Method,
+        uri: &Uri,
+        headers: &mut HeaderMap,
+        body: &Bytes,
+    ) -> Option<ShadowItem> {
+        if headers.remove(SHADOWED_HEADER).is_some() {
+            return None;
+        }
+        self.policy.as_ref()?;
+        Some(ShadowItem::Capture {
+            method: method.to_string(),
+            path_and_query: uri
+                .path_and_query()
+                .map_or_else(|| uri.path().to_string(), |p| p.to_string()),
+            headers: headers
+                .iter()
+                .filter(|(name, _)| !HOP_BY_HOP.contains(&name.as_str()))
+                .map(|(name, value)| (name.to_string(), value.as_bytes().to_vec()))
+                .collect(),
+            body: body.to_vec(),
+        })
+    }
+
+    /// A mutation to mirror once it's been applied, if shadowing mutations is on
+    pub fn mutation(&self, request: &proto::Request) -> Option<ShadowItem> {
+        if !self.policy.as_ref()?.mutations || !request.payload.is_mutation() {
+            return None;
+        }
+        // not unwrap_or_default, which is the nil id
+        let idempotency_key = match request.idempotency_key {
+            Some(key) => key,
+            None => Ulid::new(),
+        };
+        match bincode::serialize(&request.payload) {
+            Ok(payload) => Some(ShadowItem::Mutation {
+                idempotency_key: Some(idempotency_key),
+                payload,
+            }),
+            Err(e) => {
+                println!("Could not encode request {} to shadow: {:?}", request.id, e);
+                None
+            }
+        }
+    }
+
+    /// Queue a request to be mirrored, dropping the oldest if the outbox is full. Failures are
+    /// only logged, as shadowing mustn't affect the primary.
+    pub fn enqueue(&self, storage: &StorageEngine, item: ShadowItem) {
+        if let Err(e) = self.try_enqueue(storage, item) {
+            println!("Could not queue a request to shadow: {:?}", e);
+            *self.last_error.lock().unwrap() = Some(format!("{:#}", e));
+        }
+    }
+
+    fn try_enqueue(&self, storage: &StorageEngine, item: ShadowItem) -> Result<()> {
+        let Some(policy) = &self.policy else {
+            return Ok(());
+        };
+        let id = self
+            .ids
+            .lock()
+            .unwrap()
+            .generate()
+            .map_err(|e| anyhow!("Could not generate an outbox id: {}", e))?;
+        let queued = Queued {
+            queued_at_ms: Utc::now().timestamp_millis(),
+            item,
+        };
+        let outbox = storage.subtree(OUTBOX_TREE)?;
+        outbox.insert(id.to_bytes(), bincode::serialize(&queued)?)?;
+        if self.queued.fetch_add(1, Ordering::Relaxed) >= policy.outbox_limit
+            && outbox.pop_min()?.is_some()
+        {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    /// Send the oldest queued request. Returns false if there was none. A failed send leaves it
+    /// queued, to be tried again.
+    async fn deliver_next(
+        &self,
+        storage: &StorageEngine,
+        client: &mut ShadowClient,
+    ) -> Result<bool> {
+        let outbox = storage.subtree(OUTBOX_TREE)?;
+        let Some((key, value)) = outbox.first()? else {
+            return Ok(false);
+        };
+        let queued: Queued = bincode::deserialize(&value)?;
+        let outcome = client.send(&queued.item).await;
+        match outcome {
+            Ok(outcome) => {
+                // it may have been dropped to make room meanwhile
+                if outbox.remove(&key)?.is_some() {
+                    self.queued.fetch_sub(1, Ordering::Relaxed);
+                }
+                match outcome {
+                    Outcome::Accepted => self.stats.sent.fetch_add(1, Ordering::Relaxed),
+                    Outcome::Rejected(reason) => {
+                        println!("Shadow rejected a request: {}", reason);
+                        *self.last_error.lock().unwrap() = Some(reason);
+                        self.stats.rejected.fetch_add(1, Ordering::Relaxed)
+                    }
+                };
+                Ok(true)
+            }
+            Err(e) => {
+                self.stats.retries.fetch_add(1, Ordering::Relaxed);
+                *self.last_error.lock().unwrap() = Some(format!("{:#}", e));
+                Err(e)
+            }
+        }
+    }
+
+    pub fn status(&self, storage: &StorageEngine) -> Result<Option<ShadowStatus>> {
+        let Some(policy) = &self.policy else {
+            return Ok(None);
+        };
+        let lag_ms = match storage.subtree(OUTBOX_TREE)?.first()? {
+            Some((_, value)) => {
+                let queued: Queued = bincode::deserialize(&value)?;
+                (Utc::now().timestamp_millis() - queued.queued_at_ms).max(0) as u64
+            }
+            None => 0,
+        };
+        Ok(Some(ShadowStatus {
+            target: policy.target.to_string(),
+            mutations: policy.mutations,
+            queued: self.queued.load(Ordering::Relaxed),
+            lag_ms,
+            sent: self.stats.sent.load(Ordering::Relaxed),
+            rejected: self.stats.rejected.load(Ordering::Relaxed),
+            retries: self.stats.retries.load(Ordering::Relaxed),
+            dropped: self.stats.dropped.load(Ordering::Relaxed),
+            last_error: self.last_error.lock().unwrap().clone(),
+        }))
+    }
+}
+
+/// Sends queued requests to the shadow, keeping a WebSocket open for mutations
+struct ShadowClient {
+    target: ShadowTarget,
+    socket: Option<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+    next_request_id: usize,
+}
+
+impl ShadowClient {
+    fn new(target: ShadowTarget) -> Self {
+        Self {
+            target,
+            socket: None,
+            next_request_id: 0,
+        }
+    }
+
+    async fn send(&mut self, item: &ShadowItem) -> Result<Outcome> {
+        let sent = match item {
+            ShadowItem::Capture {
+                method,
+                path_and_query,
+                headers,
+                body,
+            } => {
+                tokio::time::timeout(
+                    SEND_TIMEOUT,
+                    self.send_capture(method, path_and_query, headers, body),
+                )
+                .await
+            }
+            ShadowItem::Mutation {
+                idempotency_key,
+                payload,
+            } => {
+                let sent = tokio::time::timeout(
+                    SEND_TIMEOUT,
+                    self.send_mutation(*idempotency_key, payload),
+                )
+                .await;
+                if !matches!(sent, Ok(Ok(_))) {
+                    // start afresh, rather than reading a stale response next time
+                    self.socket = None;
+                }
+                sent
+            }
+        };
+        sent.map_err(|_| anyhow!("{} took longer than {:?}", self.target, SEND_TIMEOUT))?
+    }
+
+    /// Replay a capture as a plain HTTP/1.1 request
+    async fn send_capture(
+        &self,
+        method: &str,
+        path_and_query: &str,
+        headers: &[(String, Vec<u8>)],
+        body: &[u8],
+    ) -> Result<Outcome> {
+        let mut stream = TcpStream::connect((self.target.host.as_str(), self.target.port))
+            .await
+            .with_context(|| format!("Could not connect to {}", self.target))?;
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nhost: {}:{}\r\n{}: 1\r\n",
+            method, path_and_query, self.target.host, self.target.port, SHADOWED_HEADER
+        )
+        .into_bytes();
+        for (name, value) in headers {
+            request.extend_from_slice(name.as_bytes());
+            request.extend_from_slice(b": ");
+            request.extend_from_slice(value);
+            request.extend_from_slice(b"\r\n");
+        }
+        request.extend_from_slice(
+            format!(
+                "content-length: {}\r\nconnection: close\r\n\r\n",
+                body.len()
+            )
+            .as_bytes(),
+        );
+        request.extend_from_slice(body);
+        stream.write_all(&request).await?;
+
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line).await?;
+        let status: u16 = status_line
+            .split(' ')
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| anyhow!("{} sent a malformed response", self.target))?;
+        match status {
+            200..=299 => Ok(Outcome::Accepted),
+            // the shadow is overloaded or broken for now, so try again later
+            429 | 500..=599 => bail!(
+                "{} answered {} with {}",
+                self.target,
+                path_and_query,
+                status
+            ),
+            _ => Ok(Outcome::Rejected(format!(
+                "{} {} got {}",
+                method, path_and_query, status
+            ))),
+        }
+    }
+
+    /// Make a mutation over the WebSocket, connecting first if need be
+    async fn send_mutation(
+        &mut self,
+        idempotency_key: Option<Ulid>,
+        payload: &[u8],
+    ) -> Result<Outcome> {
+        let socket = match &mut self.socket {
+            Some(socket) => socket,
+            None => {
+                let url = format!("ws://{}:{}/ws", self.target.host, self.target.port);
+                let (socket, _) = tokio_tungstenite::connect_async(url.as_str())
+                    .await
+                    .with_context(|| format!("Could not connect to {}", url))?;
+                self.socket.insert(socket)
+            }
+        };
+        self.next_request_id += 1;
+        let request_id = self.next_request_id;
+        let message = proto::Message::Request(proto::Request {
+            id: request_id,
+            idempotency_key,
+            // the shadow gives it its own
+            trace_id: None,
+            payload: bincode::deserialize(payload)?,
+        });
+        socket
+            .send(tungstenite::Message::Binary(bincode::serialize(&message)?))
+            .await?;
+        loop {
+            let message = match socket.next().await {
+                Some(message) => message?,
+                None => bail!("{} closed the connection", self.target),
+            };
+            let tungstenite::Message::Binary(bytes) = message else {
+                continue;
+            };
+            // pushes and pongs are of no interest
+            let proto::Message::Response(response) = bincode::deserialize(&bytes)? else {
+                continue;
+            };
+            if response.request_id != request_id {
+                continue;
+            }
+            return match response.payload {
+                proto::ResponsePayload::Error(error) => match error.kind {
+                    proto::ErrorKind::Unavailable | proto::ErrorKind::RateLimited => {
+                        bail!("{} is unavailable: {}", self.target, error.message)
+                    }
+                    _ => Ok(Outcome::Rejected(format!(
+                        "{:?}: {}",
+                        error.kind, error.message
+                    ))),
+                },
+                _ => Ok(Outcome::Accepted),
+            };
+        }
+    }
+}
+
+/// Send queued requests to the shadow for as long as the server runs
+pub fn spawn_shadow(state: AppState) {
+    let Some(policy) = state.shadow.policy().cloned() else {
+        return;
+    };
+    println!(
+        "Shadowing {} to {}",
+        match policy.mutations {
+            true => "captures and mutations",
+            false => "captures",
+        },
+        policy.target
+    );
+    tokio::spawn(async move {
+        let mut client = ShadowClient::new(policy.target);
+        let mut backoff = Duration::from_millis(100);
+        loop {
+            match state.shadow.deliver_next(&state.storage, &mut client).await {
+                Ok(true) => backoff = Duration::from_millis(100),
+                Ok(false) => {
+                    // wait for something to be queued, polling now and then in case a wake up
+                    // was missed
+                    let _ =
+                        tokio::time::timeout(Duration::from_secs(1), state.shadow.wake.notified())
+                            .await;
+                }
+                Err(e) => {
+                    println!(
+                        "Failed to shadow a request, retrying in {:?}: {:#}",
+                        backoff, e
+                    );
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+
+    fn policy(target: ShadowTarget, outbox_limit: usize) -> ShadowPolicy {
+        ShadowPolicy {
+            target,
+            mutations: true,
+            outbox_limit,
+        }
+    }
+
+    fn capture(shadow: &Shadow, n: u8) -> ShadowItem {
+        let uri: Uri = format!("/ingress/github?token=t{}", n).parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-github-event", "push".parse().unwrap());
+        headers.insert("content-length", "1".parse().unwrap());
+        shadow
+            .capture(&Method::POST, &uri, &mut headers, &Bytes::from(vec![n]))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_shadow() {
+        assert_eq!(
+            "http://staging:9797/".parse(),
+            Ok(ShadowTarget {
+                host: "staging".to_string(),
+                port: 9797
+            })
+        );
+        assert!("https://staging".parse::<ShadowTarget>().is_err());
+        assert!("http://staging/ingress".parse::<ShadowTarget>().is_err());
+
+        // a shadow which answers one capture, and then goes away
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target: ShadowTarget = format!("http://{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let received = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 4096];
+            let read = stream.read(&mut request).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&request[..read]).into_owned()
+        });
+
+        let storage = StorageEngine::new_test().unwrap();
+        assert!(Shadow::new(None, &storage)
+            .unwrap()
+            .capture(
+                &Method::GET,
+                &Uri::from_static("/ingress"),
+                &mut HeaderMap::new(),
+                &Bytes::new()
+            )
+            .is_none());
+        let shadow = Shadow::new(Some(policy(target.clone(), 2)), &storage).unwrap();
+        // captures mirrored from elsewhere aren't mirrored again, and lose their mark
+        let mut headers = HeaderMap::new();
+        headers.insert(SHADOWED_HEADER, "1".parse().unwrap());
+        let uri = Uri::from_static("/ingress");
+        assert!(shadow
+            .capture(&Method::POST, &uri, &mut headers, &Bytes::new())
+            .is_none());
+        assert!(headers.is_empty());
+
+        for n in 0..3 {
+            shadow.enqueue(&storage, capture(&shadow, n));
+        }
+        // the oldest was dropped to keep to the limit
+        let status = shadow.status(&storage).unwrap().unwrap();
+        assert_eq!((status.queued, status.dropped), (2, 1));
+
+        let mut client = ShadowClient::new(target);
+        assert!(shadow.deliver_next(&storage, &mut client).await.unwrap());
+        let request = received.await.unwrap();
+        assert!(
+            request.starts_with("POST /ingress/github?token=t1 HTTP/1.1\r\n"),
+            "{}",
+            request
+        );
+        assert!(request.contains("x-github-event: push\r\n"), "{}", request);
+        assert!(request.contains("x-hydra-shadowed: 1\r\n"), "{}", request);
+        assert!(request.contains("content-length: 1\r\n"), "{}", request);
+        assert!(request.ends_with("\r\n\r\n\u{1}"), "{:?}", request);
+
+        // the shadow is gone now, so the next one stays queued to be retried
+        assert!(shadow.deliver_next(&storage, &mut client).await.is_err());
+        let status = shadow.status(&storage).unwrap().unwrap();
+        assert_eq!((status.queued, status.sent, status.retries), (1, 1, 1));
+        assert!(status.last_error.is_some());
+        // and is still there after a restart
+        let restarted = Shadow::new(Some(policy(client.target.clone(), 2)), &storage).unwrap();
+        assert_eq!(restarted.status(&storage).unwrap().unwrap().queued, 1);
+    }
+}
@@ -0,0 +1,260 @@
+//! Shutting down without losing work. On SIGTERM or ctrl-c the server stops accepting
+//! connections and lets HTTP requests in flight finish, while each WebSocket connection answers
+//! the request it's handling and closes with GOING_AWAY (see connection.rs). Once they've gone,
+//! or SHUTDOWN_TIMEOUT has passed, what's left of their subscriptions is dropped and everything
+//! is flushed to disk before the process exits.
+//!
+//! For rolling restarts behind a load balancer, POST /admin/drain first has the server drain: it
+//! refuses new WebSocket connections with a 503 and a Retry-After, fails /health so the load
+//! balancer stops sending it any, and pushes GoingAway to every open connection, telling clients
+//! to reconnect, optionally to `?reconnect_to=URL`. Once they've all gone, or `?timeout=SECONDS`
+//! (DRAIN_TIMEOUT by default) has passed, it shuts down as it would on SIGTERM.
+
+use std::{
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use hydra_error::{Classified, ErrorKind};
+use hydra_proto as proto;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{error::AppError, AppState};
+
+/// How long to give open connections to close once the server is shutting down
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a drain waits for clients to reconnect elsewhere before shutting down anyway
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Resolves on SIGTERM or ctrl-c, or once a drain is over, once every connection has been told to
+/// close
+pub async fn signal(state: AppState) {
+    let mut shutdown = state.shutdown.subscribe();
+    tokio::select! {
+        received = received_signal() => {
+            println!("Received {}, shutting down", received);
+            state.shutdown.send_replace(true);
+        }
+        // see drain_connections
+        _ = shutdown.wait_for(|shutdown| *shutdown) => {}
+    }
+}
+
+async fn received_signal() -> &'static str {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install ctrl-c handler");
+        "ctrl-c"
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+        "SIGTERM"
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<&'static str>();
+    tokio::select! {
+        received = ctrl_c => received,
+        received = terminate => received,
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DrainParams {
+    reconnect_to: Option<String>,
+    // seconds
+    timeout: Option<u64>,
+}
+
+/// POST /admin/drain, which starts draining the server for a restart and says how many
+/// connections it's waiting for
+pub async fn drain_connections(
+    State(state): State<AppState>,
+    Query(params): Query<DrainParams>,
+) -> Result<Json<serde_json::Value>, AppError> {
+    let timeout = params.timeout.map_or(DRAIN_TIMEOUT, Duration::from_secs);
+    let going_away = proto::GoingAway {
+        reconnect_to: params.reconnect_to,
+        // by when this server should have been restarted
+        retry_after_ms: (timeout + SHUTDOWN_TIMEOUT).as_millis() as u64,
+    };
+    let started = state.draining.send_if_modified(|draining| {
+        let start = draining.is_none();
+        if start {
+            *draining = Some(going_away);
+        }
+        start
+    });
+    if !started {
+        return Err(AppError::conflict("The server is already draining"));
+    }
+    let open = state.connections.load(Ordering::Relaxed);
+    println!(
+        "Draining {} connections, shutting down within {:?}",
+        open, timeout
+    );
+    let drain_state = state.clone();
+    tokio::spawn(async move {
+        wait_for_connections(&drain_state, timeout).await;
+        println!("Drained, shutting down");
+        drain_state.shutdown.send_replace(true);
+    });
+    Ok(Json(json!({
+        "connections": open,
+        "timeout_seconds": timeout.as_secs(),
+    })))
+}
+
+/// Why a new connection is refused while draining, with the GoingAway's hint of when to retry
+pub fn refuse_while_draining(state: &AppState) -> Result<(), AppError> {
+    match &*state.draining.borrow() {
+        Some(going_away) => Err(
+            Classified::new(ErrorKind::Unavailable, "The server is draining")
+                .retry_after(Duration::from_millis(going_away.retry_after_ms))
+                .into(),
+        ),
+        None => Ok(()),
+    }
+}
+
+/// Wait up to `timeout` for WebSocket connections to close, returning how many are still open
+async fn wait_for_connections(state: &AppState, timeout: Duration) -> usize {
+    // axum stops tracking WebSocket connections once they're upgraded, so wait for those here
+    let deadline = Instant::now() + timeout;
+    while state.connections.load(Ordering::Relaxed) > 0 && Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    state.connections.load(Ordering::Relaxed)
+}
+
+/// Wait up to `timeout` for WebSocket connections to close, drop the subscriptions of any which
+/// haven't, then write everything to disk
+pub async fn drain(state: &AppState, timeout: Duration) -> Result<()> {
+    let open = wait_for_connections(state, timeout).await;
+    if open > 0 {
+        println!("{} connections did not close within {:?}", open, timeout);
+    }
+    let dropped = state.subscriptions.clear();
+    if dropped > 0 {
+        println!("Dropped {} subscriptions", dropped);
+    }
+    if let Err(e) = state.access.flush(&state.storage) {
+        println!("Failed to write record accesses: {:?}", e);
+    }
+    match state.writer.write_queued(&state.storage) {
+        Ok(0) => {}
+        Ok(written) => println!("Wrote {} relaxed captures", written),
+        Err(e) => println!("Failed to write relaxed captures: {:?}", e),
+    }
+    let flushed = state.storage.db.flush_async().await?;
+    println!("Flushed {} bytes to disk", flushed);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drain() {
+        let state = AppState::new_test().unwrap();
+        let (outbound, _receiver) = crate::outbound::channel(1);
+        let request = hydra_proto::SubscribeRequest {
+            tree: "ingress".to_string(),
+            sample_above: None,
+            keys: vec![],
+            debounce_ms: None,
+            prefix: None,
+        };
+        state
+            .subscriptions
+            .subscribe(7, 1, request, outbound)
+            .unwrap();
+
+        // a connection which never closes holds the drain up until the timeout
+        state.connections.store(1, Ordering::Relaxed);
+        let start = Instant::now();
+        drain(&state, Duration::from_millis(100)).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        // but its subscriptions are dropped
+        assert!(state
+            .subscriptions
+            .stats()
+            .iter()
+            .all(|shard| shard.subscriptions == 0));
+
+        // with no connections it doesn't wait
+        state.connections.store(0, Ordering::Relaxed);
+        state
+            .storage
+            .insert("ingress", b"a", b"1".to_vec())
+            .unwrap();
+        let start = Instant::now();
+        drain(&state, SHUTDOWN_TIMEOUT).await.unwrap();
+        assert!(start.elapsed() < SHUTDOWN_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn test_drain_connections() {
+        let state = AppState::new_test().unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/ws", listener.local_addr().unwrap());
+        let app = axum::Router::new()
+            .route("/ws", axum::routing::get(crate::ws_handler))
+            .with_state(state.clone());
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+            )
+            .await
+            .unwrap();
+        });
+        let config = hydra_client::ClientConfig::new(&url)
+            .backoff(Duration::from_millis(10), Duration::from_millis(10));
+        let client = hydra_client::Client::connect(config.clone()).await.unwrap();
+        client.ready().await;
+        while state.connections.load(Ordering::Relaxed) == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let params = |timeout| DrainParams {
+            reconnect_to: None,
+            timeout: Some(timeout),
+        };
+        let Json(started) = drain_connections(State(state.clone()), Query(params(5)))
+            .await
+            .unwrap();
+        assert_eq!(started["connections"], 1);
+        let again = drain_connections(State(state.clone()), Query(params(5))).await;
+        assert_eq!(again.unwrap_err().kind(), ErrorKind::Conflict);
+
+        // new connections are turned away, with a hint of when to come back
+        let refused = refuse_while_draining(&state).unwrap_err();
+        assert_eq!(refused.kind(), ErrorKind::Unavailable);
+        assert_eq!(refused.retry_after(), Some(Duration::from_secs(15)));
+        assert!(hydra_client::Client::connect(config).await.is_err());
+
+        // the client leaves when it's told to, and the server shuts down without waiting out
+        // the timeout
+        let start = Instant::now();
+        let mut shutdown = state.shutdown.subscribe();
+        shutdown.wait_for(|shutdown| *shutdown).await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_ne!(
+            client.connection_state(),
+            hydra_client::ConnectionState::Open
+        );
+    }
+}
@@ -1 +1,478 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Bound,
+    sync::Mutex,
+    time::Duration,
+};
 
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use hydra_proto as proto;
+use serde_json::{json, Value};
+use sled::IVec;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::{
+    storage::{versioned, Scan, StorageEngine, StorageEvent},
+    AppState,
+};
+
+/// The tree views are computed over
+const SOURCE_TREE: &str = "ingress";
+
+/// A field of an ingress log to group by
+#[derive(Clone, Copy, Debug)]
+pub enum GroupBy {
+    Method,
+    Host,
+    Path,
+}
+
+impl GroupBy {
+    fn key(&self, log: &proto::IngressLog) -> String {
+        match self {
+            GroupBy::Method => log.method.clone(),
+            GroupBy::Host => log.host.clone(),
+            GroupBy::Path => log.path.clone(),
+        }
+    }
+}
+
+/// What a view computes over the ingress logs
+#[derive(Clone, Debug)]
+pub enum ViewDefinition {
+    /// The number of logs in each group
+    Count { group_by: GroupBy },
+    /// The k groups with the most logs
+    TopK { group_by: GroupBy, k: usize },
+    /// Logs per second over a trailing window, going by the date each log was captured
+    Rate { window: Duration },
+    /// The number of logs in each group over a trailing window
+    RateBy { group_by: GroupBy, window: Duration },
+}
+
+/// A view's running state. Every change to the source tree is applied as the retraction of the
+/// record's old value and the addition of its new one, so updates and removals are accounted for
+/// without rescanning anything.
+enum Aggregate {
+    Counts(HashMap<String, i64>),
+    // log counts by the unix second they were captured in
+    Buckets(BTreeMap<i64, i64>),
+    // the same, for each group
+    GroupBuckets(HashMap<String, BTreeMap<i64, i64>>),
+}
+
+struct View {
+    definition: ViewDefinition,
+    aggregate: Aggregate,
+    // sequence number of the last write reflected in the view
+    as_of: u64,
+}
+
+impl View {
+    fn new(definition: ViewDefinition) -> Self {
+        let aggregate = match definition {
+            ViewDefinition::Count { .. } | ViewDefinition::TopK { .. } => {
+                Aggregate::Counts(HashMap::new())
+            }
+            ViewDefinition::Rate { .. } => Aggregate::Buckets(BTreeMap::new()),
+            ViewDefinition::RateBy { .. } => Aggregate::GroupBuckets(HashMap::new()),
+        };
+        Self {
+            definition,
+            aggregate,
+            as_of: 0,
+        }
+    }
+
+    /// Add (diff 1) or retract (diff -1) a log
+    fn apply(&mut self, log: &proto::IngressLog, diff: i64, now: DateTime<Utc>) {
+        match (&self.definition, &mut self.aggregate) {
+            (
+                ViewDefinition::Count { group_by } | ViewDefinition::TopK { group_by, .. },
+                Aggregate::Counts(counts),
+            ) => {
+                let key = group_by.key(log);
+                let count = counts.entry(key.clone()).or_default();
+                *count += diff;
+                if *count <= 0 {
+                    counts.remove(&key);
+                }
+            }
+            (ViewDefinition::Rate { window }, Aggregate::Buckets(buckets)) => {
+                apply_to_buckets(buckets, log, diff, window_start(now, *window));
+            }
+            (ViewDefinition::RateBy { group_by, window }, Aggregate::GroupBuckets(groups)) => {
+                let cutoff = window_start(now, *window);
+                groups.retain(|_, buckets| {
+                    *buckets = buckets.split_off(&cutoff);
+                    !buckets.is_empty()
+                });
+                let buckets = groups.entry(group_by.key(log)).or_default();
+                apply_to_buckets(buckets, log, diff, cutoff);
+                if buckets.is_empty() {
+                    groups.remove(&group_by.key(log));
+                }
+            }
+            _ => unreachable!("aggregate doesn't match the view definition"),
+        }
+    }
+
+    /// The number of logs in the view's window, in one group or all of them. None for views
+    /// which aren't over a window.
+    fn total_in_window(&self, group: Option<&str>, now: DateTime<Utc>) -> Option<i64> {
+        match (&self.definition, &self.aggregate) {
+            (ViewDefinition::Rate { window }, Aggregate::Buckets(buckets)) => {
+                Some(window_total(buckets, now, *window))
+            }
+            (ViewDefinition::RateBy { window, .. }, Aggregate::GroupBuckets(groups)) => {
+                Some(match group {
+                    Some(group) => groups
+                        .get(group)
+                        .map_or(0, |buckets| window_total(buckets, now, *window)),
+                    None => groups
+                        .values()
+                        .map(|buckets| window_total(buckets, now, *window))
+                        .sum(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn output(&self, now: DateTime<Utc>) -> Value {
+        match (&self.definition, &self.aggregate) {
+            (ViewDefinition::Count { .. }, Aggregate::Counts(counts)) => json!(counts),
+            (ViewDefinition::TopK { k, .. }, Aggregate::Counts(counts)) => {
+                let mut top: Vec<(&String, &i64)> = counts.iter().collect();
+                top.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+                top.truncate(*k);
+                json!(top)
+            }
+            (ViewDefinition::Rate { window }, Aggregate::Buckets(buckets)) => {
+                let total = window_total(buckets, now, *window);
+                json!({
+                    "window_seconds": window.as_secs(),
+                    "total": total,
+                    "per_second": total as f64 / window.as_secs_f64().max(1.0),
+                })
+            }
+            (ViewDefinition::RateBy { window, .. }, Aggregate::GroupBuckets(groups)) => {
+                let totals: HashMap<&String, i64> = groups
+                    .iter()
+                    .map(|(group, buckets)| (group, window_total(buckets, now, *window)))
+                    .filter(|(_, total)| *total > 0)
+                    .collect();
+                json!({
+                    "window_seconds": window.as_secs(),
+                    "totals": totals,
+                })
+            }
+            _ => unreachable!("aggregate doesn't match the view definition"),
+        }
+    }
+}
+
+/// Add or retract a log in per-second buckets, first dropping the buckets which have left the
+/// window. They're retracted wholesale, so changes to the logs in them no longer matter.
+fn apply_to_buckets(
+    buckets: &mut BTreeMap<i64, i64>,
+    log: &proto::IngressLog,
+    diff: i64,
+    cutoff: i64,
+) {
+    *buckets = buckets.split_off(&cutoff);
+    let second = log.date.timestamp();
+    if second < cutoff {
+        return;
+    }
+    let count = buckets.entry(second).or_default();
+    *count += diff;
+    if *count <= 0 {
+        buckets.remove(&second);
+    }
+}
+
+fn window_total(buckets: &BTreeMap<i64, i64>, now: DateTime<Utc>, window: Duration) -> i64 {
+    buckets
+        .range(window_start(now, window)..)
+        .map(|(_, count)| count)
+        .sum()
+}
+
+/// The first unix second inside a window ending now
+fn window_start(now: DateTime<Utc>, window: Duration) -> i64 {
+    now.timestamp() - window.as_secs() as i64 + 1
+}
+
+fn decode(value: Option<&IVec>) -> Option<proto::IngressLog> {
+    let value = value?;
+    match versioned::decode(value) {
+        Ok(log) => Some(log),
+        Err(e) => {
+            println!("Failed to decode ingress log for views: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Aggregations over the ingress logs which are kept up to date incrementally, from the
+/// storage event bus, rather than by periodically rescanning the tree.
+pub struct ViewEngine {
+    views: Mutex<BTreeMap<String, View>>,
+}
+
+impl ViewEngine {
+    pub fn new() -> Self {
+        Self {
+            views: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// The views the server maintains out of the box
+    pub fn define_defaults(&self, storage: &StorageEngine) -> Result<()> {
+        self.define(
+            storage,
+            "requests_by_method",
+            ViewDefinition::Count {
+                group_by: GroupBy::Method,
+            },
+        )?;
+        self.define(
+            storage,
+            "requests_by_host",
+            ViewDefinition::Count {
+                group_by: GroupBy::Host,
+            },
+        )?;
+        self.define(
+            storage,
+            "top_paths",
+            ViewDefinition::TopK {
+                group_by: GroupBy::Path,
+                k: 10,
+            },
+        )?;
+        self.define(
+            storage,
+            "requests_per_second",
+            ViewDefinition::Rate {
+                window: Duration::from_secs(60),
+            },
+        )
+    }
+
+    /// Add a view, computing its initial state from a snapshot of the tree. Events for writes
+    /// the snapshot already includes are ignored, so the view can be defined while the bus is
+    /// being followed.
+    pub fn define(
+        &self,
+        storage: &StorageEngine,
+        name: &str,
+        definition: ViewDefinition,
+    ) -> Result<()> {
+        let view = Self::build(storage, definition)?;
+        self.views.lock().unwrap().insert(name.to_string(), view);
+        Ok(())
+    }
+
+    fn build(storage: &StorageEngine, definition: ViewDefinition) -> Result<View> {
+        let mut view = View::new(definition);
+        let now = Utc::now();
+        let (result, token) = storage.read_as_of(SOURCE_TREE, None, |snapshot| {
+            for item in snapshot.scan((Bound::Unbounded, Bound::Unbounded), false) {
+                let (_, value) = item?;
+                if let Some(log) = decode(Some(&value)) {
+                    view.apply(&log, 1, now);
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        })?;
+        result?;
+        view.as_of = token.sequence;
+        Ok(view)
+    }
+
+    /// Recompute every view from scratch, eg. after missing events
+    pub fn rebuild(&self, storage: &StorageEngine) -> Result<()> {
+        let definitions: Vec<(String, ViewDefinition)> = self
+            .views
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, view)| (name.clone(), view.definition.clone()))
+            .collect();
+        for (name, definition) in definitions {
+            self.define(storage, &name, definition)?;
+        }
+        Ok(())
+    }
+
+    pub fn apply(&self, event: &StorageEvent) {
+        if event.tree != SOURCE_TREE {
+            return;
+        }
+        let previous = decode(event.previous.as_ref());
+        let value = decode(event.value.as_ref());
+        let now = Utc::now();
+
+        for view in self.views.lock().unwrap().values_mut() {
+            if event.sequence <= view.as_of {
+                continue;
+            }
+            if let Some(log) = &previous {
+                view.apply(log, -1, now);
+            }
+            if let Some(log) = &value {
+                view.apply(log, 1, now);
+            }
+            view.as_of = event.sequence;
+        }
+    }
+
+    pub fn output(&self, name: &str) -> Option<Value> {
+        let now = Utc::now();
+        self.views
+            .lock()
+            .unwrap()
+            .get(name)
+            .map(|view| view.output(now))
+    }
+
+    /// See View::total_in_window. None if there's no such view, or it isn't over a window.
+    pub fn total_in_window(&self, name: &str, group: Option<&str>) -> Option<i64> {
+        let now = Utc::now();
+        self.views
+            .lock()
+            .unwrap()
+            .get(name)
+            .and_then(|view| view.total_in_window(group, now))
+    }
+
+    /// The output of every view, by name
+    pub fn outputs(&self) -> serde_json::Map<String, Value> {
+        let now = Utc::now();
+        self.views
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, view)| (name.clone(), view.output(now)))
+            .collect()
+    }
+}
+
+/// Define the default views and keep them up to date for as long as the server runs
+pub fn spawn_views(state: AppState) -> Result<()> {
+    // subscribe before the views take their snapshots, so nothing falls between the two
+    let mut events = state.storage.subscribe();
+    state.views.define_defaults(&state.storage)?;
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => state.views.apply(&event),
+                Err(RecvError::Lagged(missed)) => {
+                    println!("Views missed {} events, rebuilding them", missed);
+                    if let Err(e) = state.views.rebuild(&state.storage) {
+                        println!("Failed to rebuild views: {:?}", e);
+                    }
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::ingress_log;
+
+    fn log(method: &str, path: &str) -> proto::IngressLog {
+        proto::IngressLog {
+            method: method.to_string(),
+            ..ingress_log(path, b"")
+        }
+    }
+
+    fn insert(storage: &StorageEngine, key: &str, log: &proto::IngressLog) {
+        storage
+            .insert(SOURCE_TREE, key, bincode::serialize(log).unwrap())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_incremental_views() {
+        let storage = StorageEngine::new_test().unwrap();
+        let mut events = storage.subscribe();
+        insert(&storage, "test|1", &log("GET", "a"));
+        insert(&storage, "test|2", &log("POST", "b"));
+
+        let engine = ViewEngine::new();
+        engine
+            .define(
+                &storage,
+                "by_method",
+                ViewDefinition::Count {
+                    group_by: GroupBy::Method,
+                },
+            )
+            .unwrap();
+        engine
+            .define(
+                &storage,
+                "top_paths",
+                ViewDefinition::TopK {
+                    group_by: GroupBy::Path,
+                    k: 1,
+                },
+            )
+            .unwrap();
+        engine
+            .define(
+                &storage,
+                "rate",
+                ViewDefinition::Rate {
+                    window: Duration::from_secs(60),
+                },
+            )
+            .unwrap();
+
+        // an update retracts the old log, and a removal retracts it entirely
+        insert(&storage, "test|3", &log("GET", "b"));
+        insert(&storage, "test|2", &log("GET", "b"));
+        storage.remove(SOURCE_TREE, "test|1").unwrap();
+
+        // the events for the first two writes are already in the views' snapshots
+        while let Ok(event) = events.try_recv() {
+            engine.apply(&event);
+        }
+
+        assert_eq!(engine.output("by_method").unwrap(), json!({ "GET": 2 }));
+        assert_eq!(engine.output("top_paths").unwrap(), json!([["b", 2]]));
+        assert_eq!(engine.output("rate").unwrap()["total"], json!(2));
+
+        // rebuilding from the tree agrees with the incremental state
+        let before = engine.outputs();
+        engine.rebuild(&storage).unwrap();
+        assert_eq!(engine.outputs()["by_method"], before["by_method"]);
+        assert_eq!(engine.outputs()["top_paths"], before["top_paths"]);
+
+        assert!(engine.output("missing").is_none());
+    }
+
+    #[test]
+    fn test_rate_window() {
+        let now = Utc::now();
+        let mut view = View::new(ViewDefinition::Rate {
+            window: Duration::from_secs(10),
+        });
+        let mut old = log("GET", "a");
+        old.date = now - chrono::Duration::seconds(30);
+        view.apply(&log("GET", "a"), 1, now);
+        view.apply(&old, 1, now);
+        // retracting a log which has already left the window changes nothing
+        view.apply(&old, -1, now);
+        assert_eq!(view.output(now)["total"], json!(1));
+    }
+}
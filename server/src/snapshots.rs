@@ -0,0 +1,174 @@
+//! Taking snapshots of the database into --snapshot-dir, on demand with POST /admin/snapshot and
+//! every --snapshot-every seconds, keeping the newest --snapshot-keep of them. See
+//! storage::backup for the snapshots themselves, and `hydra-server restore` to restore one.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::Result;
+use axum::{extract::State, Json};
+use chrono::Utc;
+
+use crate::{
+    config::ServerConfig,
+    error::AppError,
+    storage::{backup, StorageEngine},
+    AppState,
+};
+
+const SNAPSHOT_EXTENSION: &str = "snapshot";
+
+#[derive(Clone, Debug)]
+pub struct SnapshotPolicy {
+    pub dir: PathBuf,
+    // None only takes snapshots when asked to
+    pub interval: Option<Duration>,
+    pub keep: usize,
+}
+
+impl SnapshotPolicy {
+    /// None unless the config gives somewhere to put snapshots
+    pub fn from_config(config: &ServerConfig) -> Option<Self> {
+        Some(Self {
+            dir: config.snapshot_dir.clone()?,
+            interval: config
+                .snapshot_every
+                .map(|seconds| Duration::from_secs(seconds.max(1))),
+            keep: config.snapshot_keep.max(1),
+        })
+    }
+}
+
+/// Where snapshots go, and a lock so only one is taken at a time
+pub struct Snapshots {
+    policy: Option<SnapshotPolicy>,
+    taking: Mutex<()>,
+}
+
+impl Snapshots {
+    pub fn new(policy: Option<SnapshotPolicy>) -> Self {
+        Self {
+            policy,
+            taking: Mutex::new(()),
+        }
+    }
+
+    /// How often snapshots are to be taken, if they're taken without being asked for
+    pub fn interval(&self) -> Option<Duration> {
+        self.policy.as_ref()?.interval
+    }
+
+    /// Take a snapshot, then remove the oldest beyond the number to keep
+    pub fn take(&self, storage: &StorageEngine) -> Result<Option<backup::SnapshotSummary>> {
+        let Some(policy) = &self.policy else {
+            return Ok(None);
+        };
+        let _taking = self.taking.lock().unwrap();
+        std::fs::create_dir_all(&policy.dir)?;
+        let name = format!(
+            "hydra-{}.{}",
+            Utc::now().format("%Y%m%dT%H%M%S%.3fZ"),
+            SNAPSHOT_EXTENSION
+        );
+        let summary = backup::create_snapshot(storage, &policy.dir.join(name))?;
+        for old in snapshots_in(&policy.dir)?.iter().rev().skip(policy.keep) {
+            std::fs::remove_file(old)?;
+        }
+        Ok(Some(summary))
+    }
+}
+
+/// The snapshots in a directory, oldest first. Their names sort by when they were taken.
+fn snapshots_in(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut snapshots = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path
+            .extension()
+            .is_some_and(|ext| ext == SNAPSHOT_EXTENSION)
+        {
+            snapshots.push(path);
+        }
+    }
+    snapshots.sort();
+    Ok(snapshots)
+}
+
+/// POST /admin/snapshot, which takes a snapshot straight away and describes it
+pub async fn snapshot(
+    State(state): State<AppState>,
+) -> Result<Json<backup::SnapshotSummary>, AppError> {
+    let job_state = state.clone();
+    // sled calls block, so keep them off the async runtime
+    let summary = tokio::task::spawn_blocking(move || job_state.snapshots.take(&job_state.storage))
+        .await
+        .map_err(anyhow::Error::from)??;
+    match summary {
+        Some(summary) => {
+            println!("Took snapshot {}", summary.path.display());
+            Ok(Json(summary))
+        }
+        None => Err(AppError::not_found(
+            "Snapshots are turned off, start the server with --snapshot-dir",
+        )),
+    }
+}
+
+/// Take a snapshot every `interval`, for as long as the server runs
+pub fn spawn_snapshots(state: AppState, interval: Duration) {
+    println!("Taking a snapshot every {:?}", interval);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        // the first tick is immediate, and there's nothing new to snapshot at startup
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let pass_state = state.clone();
+            // sled calls block, so keep them off the async runtime
+            let result =
+                tokio::task::spawn_blocking(move || pass_state.snapshots.take(&pass_state.storage))
+                    .await;
+            match result {
+                Ok(Ok(Some(summary))) => println!(
+                    "Took snapshot {}: {} entries in {}ms",
+                    summary.path.display(),
+                    summary.entries,
+                    summary.took_ms
+                ),
+                Ok(Ok(None)) => {}
+                Ok(Err(e)) => println!("Failed to take a snapshot: {:?}", e),
+                Err(e) => println!("Taking a snapshot panicked: {:?}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_rotation() {
+        let dir = std::env::temp_dir().join(format!("hydra-test-{}", ulid::Ulid::new()));
+        let storage = StorageEngine::new_test().unwrap();
+        storage.insert("ingress", b"a", b"1".to_vec()).unwrap();
+        assert!(Snapshots::new(None).take(&storage).unwrap().is_none());
+
+        let snapshots = Snapshots::new(Some(SnapshotPolicy {
+            dir: dir.clone(),
+            interval: None,
+            keep: 2,
+        }));
+        let mut taken = Vec::new();
+        for _ in 0..3 {
+            taken.push(snapshots.take(&storage).unwrap().unwrap().path);
+            // names are only unique to the millisecond
+            std::thread::sleep(Duration::from_millis(2));
+        }
+        assert_eq!(snapshots_in(&dir).unwrap(), taken[1..]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,162 @@
+//! Webhook providers captured into trees of their own, eg. GitHub and Stripe, so that their
+//! deliveries can be kept, queried and subscribed to apart from each other.
+//!
+//! Sources are configured with `--sources FILE`, a JSON list of `{ "id": ..., "token": ... }`.
+//! A source's deliveries are captured at `/ingress/<id>`, into the `ingress/<id>` tree, and only
+//! when they carry its token. A source can set how durable its captures are with `durability`,
+//! see durability.rs.
+
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::durability::Durability;
+
+/// The tree a source's logs are captured into
+pub fn source_tree(id: &str) -> String {
+    format!("ingress/{}", id)
+}
+
+/// Whether a tree holds captured logs, being the shared ingress tree or a source's
+pub fn is_ingress_tree(name: &str) -> bool {
+    name == "ingress" || name.starts_with("ingress/")
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct IngressSource {
+    pub id: String,
+    token: String,
+    // --durability's mode if not set
+    #[serde(default)]
+    durability: Option<Durability>,
+}
+
+impl IngressSource {
+    pub fn tree(&self) -> String {
+        source_tree(&self.id)
+    }
+
+    /// Whether a capture presenting `token` may be stored. Digests are compared rather than the
+    /// tokens themselves, so the time taken doesn't give away how much of the token was right.
+    pub fn accepts(&self, token: &str) -> bool {
+        Sha256::digest(token.as_bytes()) == Sha256::digest(self.token.as_bytes())
+    }
+}
+
+/// The configured sources, by id, and the durability of the trees which don't set their own
+#[derive(Default, Debug)]
+pub struct Sources {
+    by_id: HashMap<String, IngressSource>,
+    durability: Durability,
+}
+
+impl Sources {
+    pub fn new(sources: Vec<IngressSource>) -> Result<Self> {
+        let mut by_id = HashMap::new();
+        for source in sources {
+            let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+            if source.id.is_empty() || !source.id.chars().all(valid) {
+                bail!(
+                    "Source id {:?} must be letters, digits, - and _ only",
+                    source.id
+                );
+            }
+            if source.token.is_empty() {
+                bail!("Source {} has an empty token", source.id);
+            }
+            if let Some(existing) = by_id.insert(source.id.clone(), source) {
+                bail!("Source {} is defined more than once", existing.id);
+            }
+        }
+        Ok(Self {
+            by_id,
+            durability: Durability::default(),
+        })
+    }
+
+    /// Set the durability of the shared ingress tree, and of sources which don't set their own
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    pub fn get(&self, id: &str) -> Option<&IngressSource> {
+        self.by_id.get(id)
+    }
+
+    /// The ids of every source, sorted
+    pub fn ids(&self) -> Vec<&str> {
+        let mut ids: Vec<&str> = self.by_id.keys().map(String::as_str).collect();
+        ids.sort();
+        ids
+    }
+
+    /// How durable writes to an ingress tree are
+    pub fn durability_of(&self, tree: &str) -> Durability {
+        tree.strip_prefix("ingress/")
+            .and_then(|id| self.by_id.get(id))
+            .and_then(|source| source.durability)
+            .unwrap_or(self.durability)
+    }
+
+    /// Whether any ingress tree is relaxed, and so needs its writes written out
+    pub fn any_relaxed(&self) -> bool {
+        self.durability == Durability::Relaxed
+            || self
+                .by_id
+                .values()
+                .any(|source| source.durability == Some(Durability::Relaxed))
+    }
+}
+
+pub fn load_sources(path: &Path) -> Result<Sources> {
+    let json = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read sources from {}", path.display()))?;
+    let sources = serde_json::from_str(&json)
+        .with_context(|| format!("Could not parse sources in {}", path.display()))?;
+    Sources::new(sources)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(id: &str, token: &str) -> IngressSource {
+        IngressSource {
+            id: id.to_string(),
+            token: token.to_string(),
+            durability: None,
+        }
+    }
+
+    #[test]
+    fn test_sources() {
+        let sources =
+            Sources::new(vec![source("stripe", "s3cret"), source("github", "t0ken")]).unwrap();
+        assert_eq!(sources.ids(), vec!["github", "stripe"]);
+        let github = sources.get("github").unwrap();
+        assert_eq!(github.tree(), "ingress/github");
+        assert!(github.accepts("t0ken"));
+        assert!(!github.accepts("s3cret"));
+        assert!(!github.accepts(""));
+
+        assert!(Sources::new(vec![source("git/hub", "t")]).is_err());
+        assert!(Sources::new(vec![source("github", "")]).is_err());
+        assert!(Sources::new(vec![source("a", "t"), source("a", "u")]).is_err());
+
+        let strict = IngressSource {
+            durability: Some(Durability::Strict),
+            ..source("stripe", "s3cret")
+        };
+        let sources = Sources::new(vec![strict, source("github", "t0ken")])
+            .unwrap()
+            .durability(Durability::Relaxed);
+        assert_eq!(sources.durability_of("ingress"), Durability::Relaxed);
+        assert_eq!(sources.durability_of("ingress/github"), Durability::Relaxed);
+        assert_eq!(sources.durability_of("ingress/stripe"), Durability::Strict);
+        assert!(sources.any_relaxed());
+        assert!(!Sources::default().any_relaxed());
+    }
+}
@@ -0,0 +1,217 @@
+//! Intermediate results which are gathered in memory until they get too big, then spilled to a
+//! temporary tree, so that work which has to collect (and usually sort) everything before it can
+//! go on, eg. replaying deliveries in order, stays within a fixed amount of memory however much
+//! there is.
+//!
+//! Spill trees are named `spill/<ulid>` and dropped once the results have been read back, or
+//! when the server next starts if it stopped part way.
+
+use std::{collections::BTreeMap, marker::PhantomData};
+
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Serialize};
+use ulid::Ulid;
+
+use crate::storage::StorageEngine;
+
+pub const SPILL_PREFIX: &str = "spill/";
+
+/// How many bytes of results are held in memory before they're spilled
+pub const SPILL_ABOVE: usize = 8 * 1024 * 1024;
+
+/// A temporary tree, dropped along with this
+struct SpillTree {
+    db: sled::Db,
+    name: String,
+    tree: sled::Tree,
+}
+
+impl Drop for SpillTree {
+    fn drop(&mut self) {
+        if let Err(e) = self.db.drop_tree(&self.name) {
+            println!("Failed to drop {}: {:?}", self.name, e);
+        }
+    }
+}
+
+/// Results to be read back in order of their keys, a later result replacing an earlier one with
+/// the same key
+pub struct SpillBuffer<T> {
+    db: sled::Db,
+    threshold: usize,
+    pending: BTreeMap<Vec<u8>, Vec<u8>>,
+    pending_bytes: usize,
+    spilled: Option<SpillTree>,
+    len: usize,
+    _items: PhantomData<T>,
+}
+
+impl<T: Serialize + DeserializeOwned> SpillBuffer<T> {
+    pub fn new(storage: &StorageEngine, threshold: usize) -> Self {
+        Self {
+            db: storage.db.clone(),
+            threshold,
+            pending: BTreeMap::new(),
+            pending_bytes: 0,
+            spilled: None,
+            len: 0,
+            _items: PhantomData,
+        }
+    }
+
+    pub fn push(&mut self, key: Vec<u8>, item: &T) -> Result<()> {
+        let value = bincode::serialize(item)?;
+        self.pending_bytes += key.len() + value.len();
+        self.pending.insert(key, value);
+        self.len += 1;
+        if self.pending_bytes > self.threshold {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    /// How many results have been pushed, counting any which were replaced
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    fn spill(&mut self) -> Result<()> {
+        let spill = match &self.spilled {
+            Some(spill) => spill,
+            None => {
+                let name = format!("{}{}", SPILL_PREFIX, Ulid::new());
+                let tree = self.db.open_tree(&name)?;
+                self.spilled.insert(SpillTree {
+                    db: self.db.clone(),
+                    name,
+                    tree,
+                })
+            }
+        };
+        let mut batch = sled::Batch::default();
+        for (key, value) in std::mem::take(&mut self.pending) {
+            batch.insert(key, value);
+        }
+        spill.tree.apply_batch(batch)?;
+        self.pending_bytes = 0;
+        Ok(())
+    }
+
+    /// Read the results back in key order. Spilled results are read from their tree as they're
+    /// needed, and the tree is dropped along with the iterator.
+    pub fn into_items(mut self) -> Result<SpilledItems<T>> {
+        let source = match self.spilled.is_some() {
+            true => {
+                self.spill()?;
+                let spill = self.spilled.take().expect("spilled");
+                Source::Spilled {
+                    iter: Box::new(spill.tree.iter()),
+                    _tree: spill,
+                }
+            }
+            false => Source::Memory(std::mem::take(&mut self.pending).into_iter()),
+        };
+        Ok(SpilledItems {
+            source,
+            _items: PhantomData,
+        })
+    }
+}
+
+enum Source {
+    Memory(std::collections::btree_map::IntoIter<Vec<u8>, Vec<u8>>),
+    // the iterator is dropped before the tree it reads
+    Spilled {
+        iter: Box<sled::Iter>,
+        _tree: SpillTree,
+    },
+}
+
+pub struct SpilledItems<T> {
+    source: Source,
+    _items: PhantomData<T>,
+}
+
+impl<T: DeserializeOwned> Iterator for SpilledItems<T> {
+    type Item = Result<(Vec<u8>, T)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, value) = match &mut self.source {
+            Source::Memory(iter) => iter.next()?,
+            Source::Spilled { iter, .. } => match iter.next()? {
+                Ok((key, value)) => (key.to_vec(), value.to_vec()),
+                Err(e) => return Some(Err(e.into())),
+            },
+        };
+        Some(
+            bincode::deserialize(&value)
+                .map(|item| (key, item))
+                .map_err(Into::into),
+        )
+    }
+}
+
+/// Drop the spill trees left behind by a server which stopped part way through reading them
+pub fn remove_leftovers(storage: &StorageEngine) -> Result<usize> {
+    let mut removed = 0;
+    for name in storage.db.tree_names() {
+        if name.starts_with(SPILL_PREFIX.as_bytes()) {
+            storage.db.drop_tree(&name)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spill_trees(storage: &StorageEngine) -> usize {
+        let names = storage.db.tree_names();
+        names
+            .iter()
+            .filter(|name| name.starts_with(SPILL_PREFIX.as_bytes()))
+            .count()
+    }
+
+    #[test]
+    fn test_spill_buffer() {
+        let storage = StorageEngine::new_test().unwrap();
+
+        // small results stay in memory
+        let mut small = SpillBuffer::new(&storage, 1024);
+        small.push(vec![2], &"b".to_string()).unwrap();
+        small.push(vec![1], &"a".to_string()).unwrap();
+        assert_eq!(spill_trees(&storage), 0);
+        let items: Vec<_> = small.into_items().unwrap().map(Result::unwrap).collect();
+        assert_eq!(
+            items,
+            vec![(vec![1], "a".to_string()), (vec![2], "b".to_string())]
+        );
+
+        // larger ones are spilled, and still come back in order
+        let mut large = SpillBuffer::new(&storage, 64);
+        for n in (0..100u32).rev() {
+            large.push(n.to_be_bytes().to_vec(), &n).unwrap();
+        }
+        assert_eq!(large.len(), 100);
+        assert_eq!(spill_trees(&storage), 1);
+        let mut items = large.into_items().unwrap();
+        let first: Vec<u32> = (&mut items).take(3).map(|item| item.unwrap().1).collect();
+        assert_eq!(first, vec![0, 1, 2]);
+        assert_eq!(items.count(), 97);
+        // the tree goes with the iterator
+        assert_eq!(spill_trees(&storage), 0);
+
+        // as do the trees of buffers which are never read back
+        let mut abandoned = SpillBuffer::new(&storage, 0);
+        abandoned.push(vec![1], &1u8).unwrap();
+        drop(abandoned);
+        assert_eq!(spill_trees(&storage), 0);
+
+        storage.db.open_tree("spill/leftover").unwrap();
+        assert_eq!(remove_leftovers(&storage).unwrap(), 1);
+        assert_eq!(spill_trees(&storage), 0);
+    }
+}
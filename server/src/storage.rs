@@ -1,24 +1,147 @@
-use anyhow::{anyhow, Result};
-use sled::{Config, Db}; // Import Result and anyhow from the anyhow crate
+pub mod backup;
+mod config;
+mod counts;
+pub mod format;
+pub mod index;
+mod metrics;
+pub mod snapshot;
+pub mod transaction;
+pub mod versioned;
+
+use anyhow::{bail, Context, Result};
+use hydra_proto as proto;
+use sled::{CompareAndSwapError, Config, Db, IVec}; // Import Result and anyhow from the anyhow crate
+use std::{
+    ops::Bound,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast;
+
+pub use config::StorageConfig;
+use counts::KeyCounts;
+use index::{Indexed, TreeIndexes};
+use metrics::{ReadMetrics, TreeStats};
+pub use snapshot::ScanIter;
+use snapshot::{Overlay, WriteHistory};
+use transaction::TransactionTree;
+pub use transaction::{Transaction, TransactionResult};
+use versioned::{RecordVersions, Versioned};
+
+use crate::{codec::CodecKind, error::AppError};
+
+/// How many events the bus buffers for slow subscribers before they start lagging
+const EVENT_BUS_CAPACITY: usize = 1024;
+
+/// How often to retry opening a database which is locked by another process
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageOp {
+    Insert,
+    Remove,
+}
+
+/// Notification of a write made through the StorageEngine
+#[derive(Clone, Debug)]
+pub struct StorageEvent {
+    pub tree: String,
+    pub key: IVec,
+    pub op: StorageOp,
+    // The new value for inserts, None for removals
+    pub value: Option<IVec>,
+    // The value which was replaced or removed, if there was one
+    pub previous: Option<IVec>,
+    // Position of the write in the history, as in the sequence of a SnapshotToken
+    pub sequence: u64,
+    // When the write was applied, for measuring how long notifications take
+    pub published: Instant,
+}
+
+type StorageHook = Box<dyn Fn(&StorageEvent) + Send + Sync>;
 
 pub struct StorageEngine {
     pub db: Db,
+    events: broadcast::Sender<StorageEvent>,
+    hooks: RwLock<Vec<StorageHook>>,
+    indexes: RwLock<Vec<TreeIndexes>>,
+    // Writes hold this for writing while they apply, snapshot reads briefly for reading
+    history: RwLock<WriteHistory>,
+    reads: ReadMetrics,
+    // kept up to date while writes hold the history's lock
+    counts: KeyCounts,
+    // what versioned records are written with
+    codec: CodecKind,
+    compress_bodies: Option<proto::Compression>,
 }
 
-impl StorageEngine {
-    // Open the storage engine without any specific column families
-    pub fn new() -> Result<Self> {
-        let dir = dirs::home_dir()
-            .ok_or_else(|| anyhow!("Failed to get home directory"))?
-            .join(".hydra");
+/// Something fetches can scan: a sled tree, or a snapshot view of one
+pub trait Scan {
+    fn scan(&self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>), reverse: bool) -> ScanIter<'_>;
+}
 
-        std::fs::create_dir_all(&dir)?;
+impl Scan for sled::Tree {
+    fn scan(&self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>), reverse: bool) -> ScanIter<'_> {
+        if reverse {
+            Box::new(self.range(range).rev())
+        } else {
+            Box::new(self.range(range))
+        }
+    }
+}
+
+/// A tree as it was at a snapshot
+pub struct SnapshotView<'a> {
+    tree: sled::Tree,
+    overlay: Overlay,
+    history: &'a RwLock<WriteHistory>,
+    stats: Arc<TreeStats>,
+}
 
-        let dbpath = dir.join("sled");
+impl<'a> Scan for SnapshotView<'a> {
+    fn scan(&self, range: (Bound<Vec<u8>>, Bound<Vec<u8>>), reverse: bool) -> ScanIter<'_> {
+        metrics::counted(
+            (self.overlay).scan(&self.tree, self.history, range, reverse),
+            self.stats.clone(),
+        )
+    }
+}
 
-        let db = sled::open(&dbpath)?;
+impl StorageEngine {
+    /// Open the database described by the config. If it is locked by another process and the
+    /// config says to wait for the lock, keep retrying until it is released or we run out of time.
+    pub fn open(config: &StorageConfig) -> Result<Self> {
+        let path = config.path();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
 
-        Ok(Self { db })
+        let sled_config = config.sled_config()?;
+        let deadline = config.wait_for_lock.map(|wait| Instant::now() + wait);
+        loop {
+            match sled_config.open() {
+                Ok(db) => {
+                    format::check(&db, path, config.migrate)?;
+                    return Ok(Self::with_db(db, config.codec, config.compress_bodies));
+                }
+                Err(e) if is_lock_error(&e) => match deadline {
+                    Some(deadline) if Instant::now() < deadline => {
+                        println!(
+                            "Database at {} is locked by another process, retrying...",
+                            path.display()
+                        );
+                        std::thread::sleep(LOCK_RETRY_INTERVAL);
+                    }
+                    _ => bail!(
+                        "The database at {} is locked by another process (is another hydra instance running?). \
+                         Stop the other instance, use --db-path to open a different database, \
+                         or --wait-for-lock to wait for it to be released.",
+                        path.display()
+                    ),
+                },
+                Err(e) => return Err(e.into()),
+            }
+        }
     }
     pub fn new_test() -> Result<Self> {
         let db = Config::new()
@@ -26,8 +149,29 @@ impl StorageEngine {
             .flush_every_ms(None)
             .open()
             .unwrap();
+        format::check(&db, "test".as_ref(), false)?;
 
-        Ok(Self { db })
+        Ok(Self::with_db(db, CodecKind::default(), None))
+    }
+
+    fn with_db(db: Db, codec: CodecKind, compress_bodies: Option<proto::Compression>) -> Self {
+        let (events, _) = broadcast::channel(EVENT_BUS_CAPACITY);
+        Self {
+            db,
+            events,
+            hooks: RwLock::new(Vec::new()),
+            indexes: RwLock::new(Vec::new()),
+            history: RwLock::new(WriteHistory::new()),
+            reads: ReadMetrics::default(),
+            counts: KeyCounts::default(),
+            codec,
+            compress_bodies,
+        }
+    }
+
+    /// Encode a versioned record as it's to be stored, compressed if --compress-bodies says so
+    pub fn encode<T: Versioned>(&self, record: &T) -> Result<Vec<u8>> {
+        versioned::compress(versioned::encode(self.codec, record)?, self.compress_bodies)
     }
 
     // Automatically creates a tree if it does not exist and returns a handle
@@ -35,4 +179,568 @@ impl StorageEngine {
         let tree = self.db.open_tree(name)?;
         Ok(tree)
     }
+
+    /// Read a single key, counting it in the tree's read statistics
+    pub fn get<K: AsRef<[u8]>>(&self, tree: &str, key: K) -> Result<Option<IVec>> {
+        let key = key.as_ref();
+        let value = self.subtree(tree)?.get(key)?;
+        self.reads.tree(tree).record_read(value.as_deref(), key);
+        Ok(value)
+    }
+
+    /// Read statistics for every tree read since the server started, the most read first
+    pub fn read_stats(&self) -> Vec<proto::TreeReadStats> {
+        self.reads.ranking()
+    }
+
+    /// Insert a record and notify hooks and event bus subscribers of the write.
+    /// Writes which should be visible to other subsystems (subscriptions, caches, metrics)
+    /// must go through here rather than directly to the sled tree.
+    pub fn insert<K, V>(&self, tree: &str, key: K, value: V) -> Result<Option<IVec>>
+    where
+        K: AsRef<[u8]>,
+        V: Into<IVec>,
+    {
+        let value = value.into();
+        let (previous, sequence) = {
+            let mut history = self.history.write().unwrap();
+            let previous = self.apply(tree, key.as_ref(), Some(&value), None)??;
+            let sequence = history.record(tree, key.as_ref(), previous.clone());
+            (self.counts).written(tree, key.as_ref(), previous.is_some(), true);
+            (previous, sequence)
+        };
+        self.publish(StorageEvent {
+            tree: tree.to_string(),
+            key: IVec::from(key.as_ref()),
+            op: StorageOp::Insert,
+            value: Some(value),
+            previous: previous.clone(),
+            sequence,
+            published: Instant::now(),
+        });
+        Ok(previous)
+    }
+
+    /// Remove a record, notifying hooks and event bus subscribers if it existed
+    pub fn remove<K: AsRef<[u8]>>(&self, tree: &str, key: K) -> Result<Option<IVec>> {
+        let (previous, sequence) = {
+            let mut history = self.history.write().unwrap();
+            let previous = self.apply(tree, key.as_ref(), None, None)??;
+            let sequence = match previous {
+                Some(_) => history.record(tree, key.as_ref(), previous.clone()),
+                None => 0,
+            };
+            (self.counts).written(tree, key.as_ref(), previous.is_some(), false);
+            (previous, sequence)
+        };
+        if previous.is_some() {
+            self.publish(StorageEvent {
+                tree: tree.to_string(),
+                key: IVec::from(key.as_ref()),
+                op: StorageOp::Remove,
+                value: None,
+                previous: previous.clone(),
+                sequence,
+                published: Instant::now(),
+            });
+        }
+        Ok(previous)
+    }
+
+    /// Atomically replace the value of a key if it currently matches `old` (None meaning absent),
+    /// notifying hooks and event bus subscribers on success
+    pub fn compare_and_swap<K: AsRef<[u8]>>(
+        &self,
+        tree: &str,
+        key: K,
+        old: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<std::result::Result<(), sled::CompareAndSwapError>> {
+        let new = new.map(IVec::from);
+        let (result, sequence) = {
+            let mut history = self.history.write().unwrap();
+            let result = self
+                .apply(tree, key.as_ref(), new.as_ref(), Some(old))?
+                .map(|_| ());
+            let sequence = match result {
+                Ok(()) => {
+                    (self.counts).written(tree, key.as_ref(), old.is_some(), new.is_some());
+                    history.record(tree, key.as_ref(), old.map(IVec::from))
+                }
+                Err(_) => 0,
+            };
+            (result, sequence)
+        };
+        if result.is_ok() {
+            let op = match new {
+                Some(_) => StorageOp::Insert,
+                None => StorageOp::Remove,
+            };
+            self.publish(StorageEvent {
+                tree: tree.to_string(),
+                key: IVec::from(key.as_ref()),
+                op,
+                value: new,
+                previous: old.map(IVec::from),
+                sequence,
+                published: Instant::now(),
+            });
+        }
+        Ok(result)
+    }
+
+    /// Make writes to any of `trees` together, or not at all: `write` reads and writes them
+    /// through the Transaction it's given, and they're only applied if it returns Ok. sled runs it
+    /// again if its reads or writes conflict with another transaction's, so it should do nothing
+    /// but read and write. Indexes are updated in the same transaction, and hooks and event bus
+    /// subscribers hear of each write once it's applied, as they would of single writes.
+    pub fn transaction<T>(
+        &self,
+        trees: &[&str],
+        write: impl Fn(&Transaction) -> TransactionResult<T>,
+    ) -> Result<T, AppError> {
+        use sled::transaction::Transactional;
+
+        let mut history = self.history.write().unwrap();
+        let indexes = self.indexes.read().unwrap();
+        // each tree, followed by its index trees, and where each tree's are among them
+        let mut all = Vec::new();
+        let mut layout = Vec::new();
+        for name in trees {
+            let tree_indexes = indexes.iter().find(|indexes| indexes.applies_to(name));
+            let start = all.len();
+            all.push(self.subtree(name)?);
+            if let Some(tree_indexes) = tree_indexes {
+                all.extend(tree_indexes.trees(&self.db, name)?);
+            }
+            layout.push((*name, start, all.len(), tree_indexes));
+        }
+        let (result, writes) = all[..]
+            .transaction(|views| {
+                let transaction = Transaction::new(
+                    layout
+                        .iter()
+                        .map(|&(name, start, end, tree_indexes)| TransactionTree {
+                            name,
+                            primary: &views[start],
+                            indexes: tree_indexes.map(|indexes| (indexes, &views[start + 1..end])),
+                        })
+                        .collect(),
+                );
+                let result = write(&transaction)?;
+                Ok((result, transaction.into_writes()))
+            })
+            .map_err(AppError::from_transaction)?;
+        let sequences: Vec<u64> = writes
+            .iter()
+            .map(|write| {
+                let (existed, exists) = (write.previous.is_some(), write.value.is_some());
+                self.counts
+                    .written(&write.tree, &write.key, existed, exists);
+                history.record(&write.tree, &write.key, write.previous.clone())
+            })
+            .collect();
+        drop(indexes);
+        drop(history);
+
+        for (write, sequence) in writes.into_iter().zip(sequences) {
+            self.publish(StorageEvent {
+                tree: write.tree,
+                key: write.key,
+                op: match write.value {
+                    Some(_) => StorageOp::Insert,
+                    None => StorageOp::Remove,
+                },
+                value: write.value,
+                previous: write.previous,
+                sequence,
+                published: Instant::now(),
+            });
+        }
+        Ok(result)
+    }
+
+    /// Apply a write to a tree, and to its indexes if it has any. With `expected`, the write is
+    /// only made if the current value matches it. Returns the value which was replaced.
+    fn apply(
+        &self,
+        tree: &str,
+        key: &[u8],
+        new: Option<&IVec>,
+        expected: Option<Option<&[u8]>>,
+    ) -> Result<std::result::Result<Option<IVec>, CompareAndSwapError>> {
+        let primary = self.subtree(tree)?;
+        let indexes = self.indexes.read().unwrap();
+        if let Some(indexes) = indexes.iter().find(|indexes| indexes.applies_to(tree)) {
+            let index_trees = indexes.trees(&self.db, tree)?;
+            return indexes.write(&primary, &index_trees, key, new, expected);
+        }
+        Ok(match (expected, new) {
+            (Some(expected), new) => primary
+                .compare_and_swap(key, expected, new.cloned())?
+                .map(|()| expected.map(IVec::from)),
+            (None, Some(value)) => Ok(primary.insert(key, value.clone())?),
+            (None, None) => Ok(primary.remove(key)?),
+        })
+    }
+
+    /// Keep the indexes declared by a record type on every tree `applies_to` picks out, from now
+    /// on. Trees which already have records are indexed straight away.
+    pub fn add_indexes<T: Indexed>(&self, applies_to: fn(&str) -> bool) -> Result<()> {
+        let indexes = TreeIndexes::new::<T>(applies_to);
+        // writes wait for the indexes to be built, so that none are missed
+        let mut all = self.indexes.write().unwrap();
+        for name in self.db.tree_names() {
+            let tree = String::from_utf8_lossy(&name);
+            if indexes.applies_to(&tree) && indexes.unbuilt(&self.db, &tree)? {
+                println!("Indexing {}", tree);
+                indexes.build(&self.db, &tree)?;
+            }
+        }
+        all.push(indexes);
+        Ok(())
+    }
+
+    /// Bring the records in every tree `applies_to` picks out up to the current version of their
+    /// type, see versioned.rs, returning how many were rewritten. Meant to be run at startup,
+    /// before anything reads them: they're rewritten in place, along with their indexes, but
+    /// hooks and event bus subscribers don't hear of it, and snapshots don't see it.
+    pub fn migrate_records<T: Versioned>(&self, applies_to: fn(&str) -> bool) -> Result<usize> {
+        let versions = RecordVersions::new::<T>(applies_to);
+        let _history = self.history.write().unwrap();
+        let mut migrated = 0;
+        for name in self.db.tree_names() {
+            let tree = String::from_utf8_lossy(&name).into_owned();
+            if !versions.applies_to(&tree) || !versions.outdated(&self.db, &tree)? {
+                continue;
+            }
+            let mut rewritten = 0;
+            for item in self.subtree(&tree)?.iter() {
+                let (key, value) = item?;
+                let upgraded = (versions.upgrade(&value))
+                    .with_context(|| format!("Failed to migrate a record in {}", tree))?;
+                if let Some(upgraded) = upgraded {
+                    self.apply(&tree, &key, Some(&IVec::from(upgraded)), None)??;
+                    rewritten += 1;
+                }
+            }
+            if rewritten > 0 {
+                println!("Migrated {} records in {}", rewritten, tree);
+            }
+            versions.migrated(&self.db, &tree)?;
+            migrated += rewritten;
+        }
+        Ok(migrated)
+    }
+
+    /// The keys of a tree's records found under `value` in one of its indexes, in key order
+    pub fn indexed(&self, tree: &str, index: &str, value: &[u8]) -> Result<Vec<IVec>> {
+        self.indexed_between(tree, index, Bound::Included(value), Bound::Included(value))
+    }
+
+    /// The keys of a tree's records found under values in a range in one of its indexes, in
+    /// order of value, see index::keys_between
+    pub fn indexed_between(
+        &self,
+        tree: &str,
+        index: &str,
+        from: Bound<&[u8]>,
+        to: Bound<&[u8]>,
+    ) -> Result<Vec<IVec>> {
+        let known = (self.indexes.read().unwrap().iter()).any(|indexes| indexes.has(tree, index));
+        if !known {
+            bail!("{} has no index {}", tree, index);
+        }
+        let index_tree = self.subtree(&index::index_tree(tree, index))?;
+        index::keys_between(&index_tree, from, to).collect()
+    }
+
+    /// The snapshot token for the current state of the database
+    pub fn token(&self) -> proto::SnapshotToken {
+        self.history.read().unwrap().token()
+    }
+
+    /// Run a read against a tree as it was at the given snapshot, or as it is now if there
+    /// isn't one, returning the snapshot token the read was made at. Writes aren't held off
+    /// while the read runs, but are hidden from it.
+    pub fn read_as_of<R>(
+        &self,
+        tree: &str,
+        snapshot: Option<&proto::SnapshotToken>,
+        read: impl FnOnce(&SnapshotView) -> R,
+    ) -> Result<(R, proto::SnapshotToken)> {
+        let history = self.history.read().unwrap();
+        let token = snapshot.copied().unwrap_or_else(|| history.token());
+        let overlay = history.overlay(tree, &token)?;
+        drop(history);
+        self.read_with(tree, overlay, token, read)
+    }
+
+    /// As read_as_of, but a snapshot which has expired, having been taken before a restart or too
+    /// many writes ago, is read as it is now rather than failing. The returned token says which.
+    pub fn read_as_of_or_now<R>(
+        &self,
+        tree: &str,
+        snapshot: Option<&proto::SnapshotToken>,
+        read: impl FnOnce(&SnapshotView) -> R,
+    ) -> Result<(R, proto::SnapshotToken)> {
+        let history = self.history.read().unwrap();
+        let token = match snapshot {
+            Some(token) if !history.expired(token) => *token,
+            _ => history.token(),
+        };
+        let overlay = history.overlay(tree, &token)?;
+        drop(history);
+        self.read_with(tree, overlay, token, read)
+    }
+
+    fn read_with<R>(
+        &self,
+        tree: &str,
+        overlay: Overlay,
+        token: proto::SnapshotToken,
+        read: impl FnOnce(&SnapshotView) -> R,
+    ) -> Result<(R, proto::SnapshotToken)> {
+        let view = SnapshotView {
+            tree: self.subtree(tree)?,
+            overlay,
+            history: &self.history,
+            stats: self.reads.tree(tree),
+        };
+        Ok((read(&view), token))
+    }
+
+    /// How many keys in a tree start with `prefix`, as of the given snapshot or now. The first
+    /// count of a prefix reads all its keys, holding writes off while it does, and from then on
+    /// the count is kept up to date as they're written.
+    pub fn count(
+        &self,
+        tree: &str,
+        prefix: &[u8],
+        snapshot: Option<&proto::SnapshotToken>,
+    ) -> Result<u64> {
+        let history = self.history.read().unwrap();
+        let primary = self.subtree(tree)?;
+        let now = self.counts.count(tree, &primary, prefix)?;
+        match snapshot {
+            Some(token) => history.overlay(tree, token)?.count(&primary, prefix, now),
+            None => Ok(now),
+        }
+    }
+
+    /// Subscribe to the bus of storage events. Subscribers which fall too far behind will
+    /// receive a Lagged error, so anything which must see every event should use add_hook instead
+    pub fn subscribe(&self) -> broadcast::Receiver<StorageEvent> {
+        self.events.subscribe()
+    }
+
+    /// Register a hook which is called synchronously for every write, before the write returns
+    pub fn add_hook<F>(&self, hook: F)
+    where
+        F: Fn(&StorageEvent) + Send + Sync + 'static,
+    {
+        self.hooks.write().unwrap().push(Box::new(hook));
+    }
+
+    fn publish(&self, event: StorageEvent) {
+        for hook in self.hooks.read().unwrap().iter() {
+            hook(&event);
+        }
+        // An error here only means there are no subscribers at the moment
+        let _ = self.events.send(event);
+    }
+}
+
+/// sled reports a held lock as a generic io error, so we have to go by the message
+fn is_lock_error(e: &sled::Error) -> bool {
+    matches!(e, sled::Error::Io(io) if io.to_string().contains("could not acquire lock"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[test]
+    fn test_storage_events() {
+        let storage = StorageEngine::new_test().unwrap();
+        let mut receiver = storage.subscribe();
+
+        let hook_calls = Arc::new(AtomicUsize::new(0));
+        let hook_calls2 = hook_calls.clone();
+        storage.add_hook(move |_| {
+            hook_calls2.fetch_add(1, Ordering::SeqCst);
+        });
+
+        storage.insert("test", b"a", b"1".to_vec()).unwrap();
+        storage.remove("test", b"a").unwrap();
+        // removing a missing key is not an event
+        storage.remove("test", b"a").unwrap();
+
+        let event = receiver.try_recv().unwrap();
+        assert_eq!(event.tree, "test");
+        assert_eq!(event.key, b"a");
+        assert_eq!(event.op, StorageOp::Insert);
+        assert_eq!(event.value.as_deref(), Some(&b"1"[..]));
+        assert!(event.previous.is_none());
+        assert_eq!(event.sequence, 1);
+
+        let event = receiver.try_recv().unwrap();
+        assert_eq!(event.op, StorageOp::Remove);
+        assert!(event.value.is_none());
+        assert_eq!(event.previous.as_deref(), Some(&b"1"[..]));
+        assert_eq!(event.sequence, 2);
+
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(hook_calls.load(Ordering::SeqCst), 2);
+    }
+
+    fn keys(view: &SnapshotView, reverse: bool) -> Vec<Vec<u8>> {
+        view.scan((Bound::Unbounded, Bound::Unbounded), reverse)
+            .map(|item| item.unwrap().0.to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn test_snapshot_reads() {
+        let storage = StorageEngine::new_test().unwrap();
+        storage.insert("test", b"b", b"1".to_vec()).unwrap();
+        storage.insert("test", b"d", b"1".to_vec()).unwrap();
+        let (_, snapshot) = storage.read_as_of("test", None, |_| ()).unwrap();
+
+        // writes after the snapshot: an insert, an update and a removal
+        storage.insert("test", b"a", b"2".to_vec()).unwrap();
+        storage.insert("test", b"b", b"2".to_vec()).unwrap();
+        storage.remove("test", b"d").unwrap();
+        storage.insert("other", b"c", b"2".to_vec()).unwrap();
+
+        let (before, _) = storage
+            .read_as_of("test", Some(&snapshot), |view| {
+                let values: Vec<IVec> = view
+                    .scan((Bound::Unbounded, Bound::Unbounded), false)
+                    .map(|item| item.unwrap().1)
+                    .collect();
+                assert_eq!(values, vec![IVec::from(b"1"), IVec::from(b"1")]);
+                (keys(view, false), keys(view, true))
+            })
+            .unwrap();
+        assert_eq!(before.0, vec![b"b".to_vec(), b"d".to_vec()]);
+        assert_eq!(before.1, vec![b"d".to_vec(), b"b".to_vec()]);
+
+        let (now, latest) = storage
+            .read_as_of("test", None, |view| keys(view, false))
+            .unwrap();
+        assert_eq!(now, vec![b"a".to_vec(), b"b".to_vec()]);
+        assert_eq!(latest.sequence, snapshot.sequence + 4);
+
+        // a scan doesn't hold writes off, and doesn't see those made while it runs
+        let (during, _) = storage
+            .read_as_of("test", Some(&latest), |view| {
+                let mut scan = view.scan((Bound::Unbounded, Bound::Unbounded), false);
+                let first = scan.next().unwrap().unwrap();
+                storage.insert("test", b"b", b"3".to_vec()).unwrap();
+                storage.insert("test", b"c", b"3".to_vec()).unwrap();
+                storage.remove("test", b"a").unwrap();
+                let rest: Vec<_> = scan.map(|item| item.unwrap()).collect();
+                (first, rest)
+            })
+            .unwrap();
+        assert_eq!(during.0, (IVec::from(b"a"), IVec::from(b"2")));
+        assert_eq!(during.1, vec![(IVec::from(b"b"), IVec::from(b"2"))]);
+
+        // tokens from another server run are rejected
+        let stale = proto::SnapshotToken {
+            epoch: ulid::Ulid::nil(),
+            ..snapshot
+        };
+        assert!(storage.read_as_of("test", Some(&stale), |_| ()).is_err());
+    }
+
+    #[test]
+    fn test_storage_config() {
+        let path = std::env::temp_dir().join(format!("hydra-test-{}", ulid::Ulid::new()));
+        let config = StorageConfig::new(&path)
+            .cache_capacity(1024 * 1024)
+            .flush_every(Duration::from_millis(100));
+
+        let storage = StorageEngine::open(&config).unwrap();
+        storage.insert("test", b"a", b"1".to_vec()).unwrap();
+        drop(storage);
+        // sled's flusher thread can hold on to the database briefly after it's dropped
+        let reopen = config.clone().wait_for_lock(Some(Duration::from_secs(5)));
+        let storage = StorageEngine::open(&reopen).unwrap();
+        assert!(storage.subtree("test").unwrap().contains_key(b"a").unwrap());
+        drop(storage);
+
+        if !cfg!(feature = "compression") {
+            assert!(StorageEngine::open(&config.clone().compression(true)).is_err());
+        }
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read_stats() {
+        let storage = StorageEngine::new_test().unwrap();
+        for key in ["a", "b", "c"] {
+            storage.insert("busy", key, b"12345".to_vec()).unwrap();
+        }
+        storage.insert("quiet", "a", b"1".to_vec()).unwrap();
+
+        assert!(storage.get("quiet", "a").unwrap().is_some());
+        assert!(storage.get("quiet", "missing").unwrap().is_none());
+        storage
+            .read_as_of("busy", None, |view| {
+                // a scan abandoned part way counts what it read
+                view.scan((Bound::Unbounded, Bound::Unbounded), false)
+                    .take(2)
+                    .count();
+                view.scan((Bound::Unbounded, Bound::Unbounded), true)
+                    .count();
+            })
+            .unwrap();
+
+        let stats = storage.read_stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(
+            (stats[0].tree.as_str(), stats[0].scans, stats[0].scanned),
+            ("busy", 2, 5)
+        );
+        assert_eq!(stats[0].bytes_read, 5 * 6);
+        assert_eq!(stats[0].mean_scan_length, 2);
+        assert_eq!((stats[1].reads, stats[1].bytes_read), (2, 2));
+    }
+
+    #[test]
+    fn test_key_counts() {
+        let storage = StorageEngine::new_test().unwrap();
+        for key in ["a|1", "a|2", "b|1"] {
+            storage.insert("test", key, b"1".to_vec()).unwrap();
+        }
+        assert_eq!(storage.count("test", b"a|", None).unwrap(), 2);
+        let (_, before) = storage.read_as_of("test", None, |_| ()).unwrap();
+
+        // kept up to date by every kind of write, counting only those which add or remove a key
+        storage.insert("test", "a|3", b"1".to_vec()).unwrap();
+        storage.insert("test", "a|3", b"2".to_vec()).unwrap();
+        storage.remove("test", "a|1").unwrap();
+        storage.remove("test", "a|missing").unwrap();
+        storage
+            .compare_and_swap("test", "a|4", None, Some(b"1".to_vec()))
+            .unwrap()
+            .unwrap();
+        storage
+            .transaction(&["test"], |tx| {
+                tx.insert("test", "a|5", &b"1"[..])?;
+                tx.insert("test", "b|2", &b"1"[..])
+            })
+            .unwrap();
+        assert_eq!(storage.count("test", b"a|", None).unwrap(), 4);
+        assert_eq!(storage.count("test", b"", None).unwrap(), 6);
+        // and as of a snapshot, from before those writes
+        assert_eq!(storage.count("test", b"a|", Some(&before)).unwrap(), 2);
+        assert_eq!(storage.count("other", b"", None).unwrap(), 0);
+    }
 }
@@ -0,0 +1,276 @@
+//! Backups of the whole database taken while the server runs, and restoring them.
+//!
+//! A snapshot file is sled's export of every tree: a header naming the trees, then each tree's
+//! entries in turn, each followed by a marker, then the number of entries as a check that the file
+//! is complete. Writes through the StorageEngine are held off while a snapshot is taken, so it's
+//! consistent, and it's written to a temporary file which is only renamed into place once it's
+//! complete. Spill trees are left out, as they're never wanted after a restart.
+
+use std::{
+    cell::RefCell,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    rc::Rc,
+    time::Instant,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Serialize;
+
+use super::{StorageConfig, StorageEngine};
+use crate::spill::SPILL_PREFIX;
+
+const MAGIC: &[u8] = b"HYDRA-SNAPSHOT\0";
+
+/// Bumped whenever the layout of snapshot files changes
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// A snapshot which has been taken, or restored
+#[derive(Serialize, Debug)]
+pub struct SnapshotSummary {
+    pub path: PathBuf,
+    pub trees: usize,
+    pub entries: u64,
+    pub bytes: u64,
+    pub took_ms: u64,
+}
+
+/// Write a consistent snapshot of the database to `path`
+pub fn create_snapshot(storage: &StorageEngine, path: &Path) -> Result<SnapshotSummary> {
+    let start = Instant::now();
+    let partial = partial_path(path);
+    let mut out = BufWriter::new(
+        File::create(&partial)
+            .with_context(|| format!("Could not create {}", partial.display()))?,
+    );
+    out.write_all(MAGIC)?;
+    out.write_all(&SNAPSHOT_VERSION.to_be_bytes())?;
+
+    let (trees, entries) = {
+        // hold writes off so every tree is read as of the same moment
+        let _history = storage.history.read().unwrap();
+        let export: Vec<_> = storage
+            .db
+            .export()
+            .into_iter()
+            .filter(|(_, name, _)| !name.starts_with(SPILL_PREFIX.as_bytes()))
+            .collect();
+        let names: Vec<(&Vec<u8>, &Vec<u8>)> = export
+            .iter()
+            .map(|(collection, name, _)| (collection, name))
+            .collect();
+        bincode::serialize_into(&mut out, &names)?;
+        let trees = export.len();
+        let mut entries = 0u64;
+        for (_, _, items) in export {
+            for item in items {
+                bincode::serialize_into(&mut out, &Some(item))?;
+                entries += 1;
+            }
+            bincode::serialize_into(&mut out, &None::<Vec<Vec<u8>>>)?;
+        }
+        (trees, entries)
+    };
+    out.write_all(&entries.to_be_bytes())?;
+    out.into_inner()
+        .map_err(|e| anyhow!("Failed to write {}: {}", partial.display(), e))?
+        .sync_all()?;
+    std::fs::rename(&partial, path)?;
+    Ok(SnapshotSummary {
+        path: path.to_path_buf(),
+        trees,
+        entries,
+        bytes: std::fs::metadata(path)?.len(),
+        took_ms: start.elapsed().as_millis() as u64,
+    })
+}
+
+fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".partial");
+    PathBuf::from(name)
+}
+
+/// Reads the entries of one tree after another from a snapshot, for sled's import, which can't
+/// be told about errors, so they're kept to be checked once it's done
+struct SnapshotReader {
+    file: BufReader<File>,
+    entries: u64,
+    error: Option<anyhow::Error>,
+}
+
+struct TreeEntries(Rc<RefCell<SnapshotReader>>);
+
+impl Iterator for TreeEntries {
+    type Item = Vec<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut reader = self.0.borrow_mut();
+        if reader.error.is_some() {
+            return None;
+        }
+        match bincode::deserialize_from::<_, Option<Vec<Vec<u8>>>>(&mut reader.file) {
+            Ok(Some(item)) if item.len() == 2 => {
+                reader.entries += 1;
+                Some(item)
+            }
+            Ok(Some(_)) => {
+                reader.error = Some(anyhow!("Snapshot entry is not a key and value"));
+                None
+            }
+            Ok(None) => None,
+            Err(e) => {
+                reader.error = Some(anyhow!("Snapshot is truncated or corrupt: {}", e));
+                None
+            }
+        }
+    }
+}
+
+/// Restore a snapshot into a new database, described by `config`. Its directory mustn't exist
+/// yet, so a restore can never overwrite a database.
+pub fn restore_snapshot(path: &Path, config: &StorageConfig) -> Result<SnapshotSummary> {
+    let start = Instant::now();
+    if config.path().exists() {
+        bail!(
+            "{} already exists. Restore into a new --db-path, then swap it in once the server is \
+             stopped.",
+            config.path().display()
+        );
+    }
+    let mut file = BufReader::new(
+        File::open(path).with_context(|| format!("Could not open {}", path.display()))?,
+    );
+    let mut magic = vec![0; MAGIC.len()];
+    let mut version = [0; 4];
+    file.read_exact(&mut magic)?;
+    file.read_exact(&mut version)?;
+    if magic != MAGIC {
+        bail!("{} is not a hydra snapshot", path.display());
+    }
+    if u32::from_be_bytes(version) != SNAPSHOT_VERSION {
+        bail!(
+            "{} has snapshot version {}, but this hydra reads version {}",
+            path.display(),
+            u32::from_be_bytes(version),
+            SNAPSHOT_VERSION
+        );
+    }
+    let names: Vec<(Vec<u8>, Vec<u8>)> = bincode::deserialize_from(&mut file)?;
+    // sled's import panics on anything but trees
+    if names.iter().any(|(collection, _)| collection != b"tree") {
+        bail!("{} has collections other than trees", path.display());
+    }
+    let trees = names.len();
+    let reader = Rc::new(RefCell::new(SnapshotReader {
+        file,
+        entries: 0,
+        error: None,
+    }));
+
+    // flushed once at the end, and without a background flusher, whose thread can still hold
+    // the database's lock for a moment after it's dropped
+    let db = config.sled_config()?.flush_every_ms(None).open()?;
+    let result = (|| {
+        db.import(
+            names
+                .into_iter()
+                .map(|(collection, name)| (collection, name, TreeEntries(reader.clone())))
+                .collect(),
+        );
+        let mut reader = reader.borrow_mut();
+        if let Some(e) = reader.error.take() {
+            return Err(e);
+        }
+        let mut expected = [0; 8];
+        reader
+            .file
+            .read_exact(&mut expected)
+            .context("Snapshot is truncated")?;
+        if u64::from_be_bytes(expected) != reader.entries {
+            bail!(
+                "Snapshot should have {} entries, but has {}",
+                u64::from_be_bytes(expected),
+                reader.entries
+            );
+        }
+        db.flush()?;
+        Ok(reader.entries)
+    })();
+    drop(db);
+    let entries = match result {
+        Ok(entries) => entries,
+        Err(e) => {
+            // don't leave a partial database which could be mistaken for a good one
+            let _ = std::fs::remove_dir_all(config.path());
+            return Err(e);
+        }
+    };
+    Ok(SnapshotSummary {
+        path: config.path().to_path_buf(),
+        trees,
+        entries,
+        bytes: std::fs::metadata(path)?.len(),
+        took_ms: start.elapsed().as_millis() as u64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let dir = std::env::temp_dir().join(format!("hydra-test-{}", ulid::Ulid::new()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let storage = StorageEngine::new_test().unwrap();
+        for n in 0..100u8 {
+            storage.insert("ingress", [n], vec![n; 10]).unwrap();
+        }
+        storage
+            .insert("collections/notes", b"a", b"1".to_vec())
+            .unwrap();
+        storage
+            .subtree("spill/leftover")
+            .unwrap()
+            .insert(b"x", b"y")
+            .unwrap();
+
+        let snapshot = dir.join("db.snapshot");
+        let taken = create_snapshot(&storage, &snapshot).unwrap();
+        // the records, and the storage format version
+        assert_eq!(taken.entries, 102);
+        assert!(!partial_path(&snapshot).exists());
+
+        let config = StorageConfig::new(dir.join("restored"));
+        let restored = restore_snapshot(&snapshot, &config).unwrap();
+        assert_eq!(restored.entries, taken.entries);
+        // restoring over an existing database is refused
+        assert!(restore_snapshot(&snapshot, &config).is_err());
+        let reopened = StorageEngine::open(&config).unwrap();
+        assert_eq!(reopened.subtree("ingress").unwrap().len(), 100);
+        assert_eq!(
+            reopened.get("collections/notes", b"a").unwrap().as_deref(),
+            Some(&b"1"[..])
+        );
+        assert!(!reopened
+            .db
+            .tree_names()
+            .iter()
+            .any(|name| name.starts_with(SPILL_PREFIX.as_bytes())));
+        drop(reopened);
+
+        // a truncated snapshot doesn't leave a database behind
+        let bytes = std::fs::read(&snapshot).unwrap();
+        let truncated = dir.join("truncated.snapshot");
+        std::fs::write(&truncated, &bytes[..bytes.len() - 20]).unwrap();
+        let config = StorageConfig::new(dir.join("partial"));
+        assert!(restore_snapshot(&truncated, &config).is_err());
+        assert!(!config.path().exists());
+        std::fs::write(&truncated, b"not a snapshot at all").unwrap();
+        assert!(restore_snapshot(&truncated, &config).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,125 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use hydra_proto as proto;
+
+use crate::codec::CodecKind;
+
+/// Environment variable naming the directory hydra keeps its data in, instead of ~/.hydra
+pub const DATA_DIR_ENV: &str = "HYDRA_DATA_DIR";
+
+/// How to open a StorageEngine, eg.
+/// `StorageConfig::new(path).cache_capacity(64 * 1024 * 1024).compression(true)`
+#[derive(Clone, Debug)]
+pub struct StorageConfig {
+    pub(super) path: PathBuf,
+    cache_capacity: Option<u64>,
+    // None leaves flushing to sled's default interval
+    flush_every: Option<Duration>,
+    compression: bool,
+    pub(super) wait_for_lock: Option<Duration>,
+    // upgrade a database with an older storage format rather than refusing to open it
+    pub(super) migrate: bool,
+    pub(super) codec: CodecKind,
+    // None stores versioned records as they're encoded
+    pub(super) compress_bodies: Option<proto::Compression>,
+}
+
+impl StorageConfig {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            cache_capacity: None,
+            flush_every: None,
+            compression: false,
+            wait_for_lock: None,
+            migrate: false,
+            codec: CodecKind::default(),
+            compress_bodies: None,
+        }
+    }
+
+    /// The database in $HYDRA_DATA_DIR, or ~/.hydra if it isn't set
+    pub fn from_env() -> Result<Self> {
+        Ok(Self::new(Self::default_path()?))
+    }
+
+    /// $HYDRA_DATA_DIR/sled or ~/.hydra/sled
+    pub fn default_path() -> Result<PathBuf> {
+        let dir = match std::env::var_os(DATA_DIR_ENV) {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => dirs::home_dir()
+                .ok_or_else(|| anyhow!("Failed to get home directory"))?
+                .join(".hydra"),
+        };
+        Ok(dir.join("sled"))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Maximum size of sled's page cache, in bytes
+    pub fn cache_capacity(mut self, bytes: u64) -> Self {
+        self.cache_capacity = Some(bytes);
+        self
+    }
+
+    /// How often writes are flushed to disk in the background
+    pub fn flush_every(mut self, interval: Duration) -> Self {
+        self.flush_every = Some(interval);
+        self
+    }
+
+    /// Compress data on disk with zstd. Needs the server's compression feature.
+    pub fn compression(mut self, enabled: bool) -> Self {
+        self.compression = enabled;
+        self
+    }
+
+    /// If the database is locked by another process, keep retrying for this long
+    pub fn wait_for_lock(mut self, wait: Option<Duration>) -> Self {
+        self.wait_for_lock = wait;
+        self
+    }
+
+    /// Migrate a database with an older storage format to the current one, see format.rs
+    pub fn migrate(mut self, migrate: bool) -> Self {
+        self.migrate = migrate;
+        self
+    }
+
+    /// The codec to write versioned records with. Those already written are still read with
+    /// the one they were written with.
+    pub fn codec(mut self, codec: CodecKind) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Compress large versioned records, ie. logs with large bodies, as they're written, see
+    /// versioned.rs. Those already written are read however they were written.
+    pub fn compress_bodies(mut self, compression: Option<proto::Compression>) -> Self {
+        self.compress_bodies = compression;
+        self
+    }
+
+    pub(super) fn sled_config(&self) -> Result<sled::Config> {
+        if self.compression && !cfg!(feature = "compression") {
+            bail!("Compression was requested, but the server was built without the compression feature");
+        }
+
+        let mut config = sled::Config::new()
+            .path(&self.path)
+            .use_compression(self.compression);
+        if let Some(bytes) = self.cache_capacity {
+            config = config.cache_capacity(bytes);
+        }
+        if let Some(interval) = self.flush_every {
+            config = config.flush_every_ms(Some(interval.as_millis() as u64));
+        }
+        Ok(config)
+    }
+}
@@ -0,0 +1,52 @@
+//! Counts of the keys under a prefix of a tree, eg. the logs in an ingress tree, so a page can say
+//! how many there are in all without reading them. A prefix is counted the first time it's asked
+//! for, and kept up to date from then on by every write made through the StorageEngine.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use anyhow::Result;
+
+#[derive(Default)]
+pub struct KeyCounts {
+    // by tree, then prefix
+    counted: Mutex<HashMap<String, HashMap<Vec<u8>, u64>>>,
+}
+
+impl KeyCounts {
+    /// Note a write, which `existed` before and `exists` after. Writes must be noted in the order
+    /// they're applied, and not while a prefix is being counted.
+    pub fn written(&self, tree: &str, key: &[u8], existed: bool, exists: bool) {
+        if existed == exists {
+            return;
+        }
+        let mut counted = self.counted.lock().unwrap();
+        let Some(prefixes) = counted.get_mut(tree) else {
+            return;
+        };
+        for (prefix, count) in prefixes.iter_mut() {
+            if key.starts_with(prefix) {
+                *count = match exists {
+                    true => *count + 1,
+                    false => count.saturating_sub(1),
+                };
+            }
+        }
+    }
+
+    /// The number of keys under a prefix, counting them if they haven't been yet. No writes
+    /// may be applied until it returns.
+    pub fn count(&self, name: &str, tree: &sled::Tree, prefix: &[u8]) -> Result<u64> {
+        let mut counted = self.counted.lock().unwrap();
+        let prefixes = counted.entry(name.to_string()).or_default();
+        if let Some(count) = prefixes.get(prefix) {
+            return Ok(*count);
+        }
+        let mut count = 0;
+        for item in tree.scan_prefix(prefix).keys() {
+            item?;
+            count += 1;
+        }
+        prefixes.insert(prefix.to_vec(), count);
+        Ok(count)
+    }
+}
@@ -0,0 +1,195 @@
+//! The version of the database's on-disk layout, so that a hydra binary never reads or writes a
+//! database laid out in a way it doesn't understand.
+//!
+//! The version is kept in the `meta` tree, written when a database is first opened. Opening a
+//! database with a newer version than this binary's is refused, as writing it with an older
+//! layout could corrupt it. Opening one with an older version is refused too unless the config
+//! asks to migrate it, in which case each migration from its version onwards is run in turn.
+//! Every migration records the version it reached as soon as it's done, so a migration which
+//! fails part way is resumed from where it stopped.
+//!
+//! To change the layout, add a migration from the current version to MIGRATIONS and bump
+//! FORMAT_VERSION.
+
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use sled::Db;
+
+/// Where facts about the database itself are kept
+pub const META_TREE: &str = "meta";
+
+const FORMAT_KEY: &[u8] = b"format_version";
+
+/// The layout this binary reads and writes
+pub const FORMAT_VERSION: u32 = 2;
+
+/// Databases written before versions were recorded have the first layout
+const UNVERSIONED: u32 = 1;
+
+/// Rewrites a database from one version of the layout to the next
+pub struct Migration {
+    pub from: u32,
+    pub description: &'static str,
+    pub run: fn(&Db) -> Result<()>,
+}
+
+/// Every migration, in order of the version they migrate from
+pub const MIGRATIONS: &[Migration] = &[Migration {
+    from: 1,
+    description: "keep records in versioned envelopes",
+    run: versioned_envelopes,
+}];
+
+/// Values without an envelope are read as the first version of their record type, see
+/// storage/versioned.rs, so there's nothing to rewrite. The new format only keeps older hydras,
+/// which can't read envelopes, from opening the database.
+fn versioned_envelopes(_: &Db) -> Result<()> {
+    Ok(())
+}
+
+/// The version recorded in a database, if there is one
+pub fn recorded_version(db: &Db) -> Result<Option<u32>> {
+    let meta = db.open_tree(META_TREE)?;
+    match meta.get(FORMAT_KEY)? {
+        Some(value) => {
+            let bytes = value
+                .as_ref()
+                .try_into()
+                .context("The database's format version is corrupt")?;
+            Ok(Some(u32::from_be_bytes(bytes)))
+        }
+        None => Ok(None),
+    }
+}
+
+fn record_version(db: &Db, version: u32) -> Result<()> {
+    db.open_tree(META_TREE)?
+        .insert(FORMAT_KEY, &version.to_be_bytes())?;
+    db.flush()?;
+    Ok(())
+}
+
+/// Check that a database just opened has the current layout, recording it if it's new, or
+/// migrating it if it's older and `migrate` is set
+pub fn check(db: &Db, path: &Path, migrate: bool) -> Result<()> {
+    check_with(db, path, migrate, FORMAT_VERSION, MIGRATIONS)
+}
+
+fn check_with(
+    db: &Db,
+    path: &Path,
+    migrate: bool,
+    current: u32,
+    migrations: &[Migration],
+) -> Result<()> {
+    // sled always has its default tree, so a database with no other trees is new
+    let new = db.tree_names().len() <= 1 && db.is_empty();
+    let version = match recorded_version(db)? {
+        Some(version) => version,
+        None if new => {
+            return record_version(db, current);
+        }
+        None => {
+            record_version(db, UNVERSIONED)?;
+            UNVERSIONED
+        }
+    };
+    if version > current {
+        bail!(
+            "The database at {} was written by a newer hydra (storage format {}, but this binary \
+             only understands up to {}). Run the newer hydra instead, as this one could corrupt it.",
+            path.display(),
+            version,
+            current
+        );
+    }
+    if version == current {
+        return Ok(());
+    }
+    if !migrate {
+        bail!(
+            "The database at {} has storage format {}, but this hydra uses {}. Back it up, then \
+             restart with --migrate to upgrade it.",
+            path.display(),
+            version,
+            current
+        );
+    }
+    for version in version..current {
+        let migration = migrations
+            .iter()
+            .find(|migration| migration.from == version)
+            .with_context(|| format!("No migration from storage format {}", version))?;
+        println!(
+            "Migrating storage format {} to {}: {}",
+            version,
+            version + 1,
+            migration.description
+        );
+        (migration.run)(db)
+            .with_context(|| format!("Migrating from storage format {} failed", version))?;
+        record_version(db, version + 1)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn db() -> Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    fn add_marker(db: &Db) -> Result<()> {
+        db.open_tree("migrated")?.insert(b"marker", &[])?;
+        Ok(())
+    }
+
+    fn fail(_: &Db) -> Result<()> {
+        bail!("disk full")
+    }
+
+    #[test]
+    fn test_format_version() {
+        let path = Path::new("test");
+
+        // new databases get the current version, existing unversioned ones the first
+        let new = db();
+        check_with(&new, path, false, 3, &[]).unwrap();
+        assert_eq!(recorded_version(&new).unwrap(), Some(3));
+        let old = db();
+        old.open_tree("ingress").unwrap().insert(b"a", &[]).unwrap();
+        assert!(check_with(&old, path, false, 1, &[]).is_ok());
+        assert_eq!(recorded_version(&old).unwrap(), Some(1));
+
+        // newer databases are refused, migrating or not
+        assert!(check_with(&new, path, true, 2, &[]).is_err());
+
+        // older ones are only migrated if asked to, resuming after a failed migration
+        let migrations = [
+            Migration {
+                from: 1,
+                description: "add a marker",
+                run: add_marker,
+            },
+            Migration {
+                from: 2,
+                description: "fail",
+                run: fail,
+            },
+        ];
+        let error = check_with(&old, path, false, 3, &migrations).unwrap_err();
+        assert!(error.to_string().contains("--migrate"), "{}", error);
+        assert!(check_with(&old, path, true, 3, &migrations).is_err());
+        assert_eq!(recorded_version(&old).unwrap(), Some(2));
+        assert!(old
+            .open_tree("migrated")
+            .unwrap()
+            .contains_key(b"marker")
+            .unwrap());
+        check_with(&old, path, true, 2, &migrations).unwrap();
+        assert!(check_with(&old, path, true, 4, &migrations[..1]).is_err());
+    }
+}
@@ -0,0 +1,343 @@
+//! Secondary indexes, so that records can be found by something other than their key (eg. ingress
+//! logs by host) without scanning the whole tree.
+//!
+//! A record type declares its indexes by implementing Indexed, and the engine is told which trees
+//! hold that type with StorageEngine::add_indexes. Every write made through the engine to one of
+//! those trees then updates its indexes in the same sled transaction, so an index never disagrees
+//! with the tree it covers. Index `<name>` of tree `<tree>` is kept in the tree
+//! `index/<tree>/<name>`, an empty entry per record keyed by the length of the indexed value as two
+//! bytes, the value, then the record's key.
+
+use std::ops::Bound;
+
+use anyhow::{anyhow, Result};
+use hydra_proto::record::Record;
+use sled::{
+    transaction::{
+        ConflictableTransactionResult, TransactionError, Transactional, TransactionalTree,
+        UnabortableTransactionError,
+    },
+    CompareAndSwapError, IVec, Tree,
+};
+
+use super::versioned;
+
+/// One of a record type's indexes: its name, and the value a record is found under
+pub struct IndexSpec<T> {
+    pub name: &'static str,
+    pub value: fn(&T) -> Vec<u8>,
+}
+
+/// A record type with secondary indexes
+pub trait Indexed: Record + Sized + 'static {
+    fn indexes() -> Vec<IndexSpec<Self>>;
+}
+
+/// The values a stored record is found under in each index, or None for values which aren't
+/// records of the type
+type IndexValues = Box<dyn Fn(&[u8]) -> Option<Vec<Vec<u8>>> + Send + Sync>;
+
+/// The indexes kept on the trees holding one record type
+pub(super) struct TreeIndexes {
+    applies_to: fn(&str) -> bool,
+    names: Vec<&'static str>,
+    // in the order of `names`
+    values: IndexValues,
+}
+
+impl TreeIndexes {
+    pub fn new<T: Indexed>(applies_to: fn(&str) -> bool) -> Self {
+        let specs = T::indexes();
+        let names = specs.iter().map(|spec| spec.name).collect();
+        let values = Box::new(move |value: &[u8]| {
+            let record: T = versioned::decode(value).ok()?;
+            Some(specs.iter().map(|spec| (spec.value)(&record)).collect())
+        });
+        Self {
+            applies_to,
+            names,
+            values,
+        }
+    }
+
+    pub fn applies_to(&self, tree: &str) -> bool {
+        (self.applies_to)(tree)
+    }
+
+    pub fn has(&self, tree: &str, name: &str) -> bool {
+        self.applies_to(tree) && self.names.contains(&name)
+    }
+
+    /// Whether a tree has records but no index entries, as it was written before its indexes
+    /// existed
+    pub fn unbuilt(&self, db: &sled::Db, tree: &str) -> Result<bool> {
+        let Some(name) = self.names.first() else {
+            return Ok(false);
+        };
+        Ok(!db.open_tree(tree)?.is_empty() && db.open_tree(index_tree(tree, name))?.is_empty())
+    }
+
+    /// The index trees of a tree these indexes apply to, in the order of `names`
+    pub fn trees(&self, db: &sled::Db, tree: &str) -> Result<Vec<Tree>> {
+        self.names
+            .iter()
+            .map(|name| Ok(db.open_tree(index_tree(tree, name))?))
+            .collect()
+    }
+
+    /// Index every record of a tree, for trees which were written before their indexes existed
+    pub fn build(&self, db: &sled::Db, tree: &str) -> Result<()> {
+        let index_trees = self.trees(db, tree)?;
+        let mut batches: Vec<_> = index_trees.iter().map(|_| sled::Batch::default()).collect();
+        for entry in db.open_tree(tree)?.iter() {
+            let (key, value) = entry?;
+            for (batch, value) in batches
+                .iter_mut()
+                .zip((self.values)(&value).unwrap_or_default())
+            {
+                batch.insert(entry_key(&value, &key), &[]);
+            }
+        }
+        for (index, batch) in index_trees.iter().zip(batches) {
+            index.apply_batch(batch)?;
+        }
+        Ok(())
+    }
+
+    /// Write `new` to `key` of the primary tree (removing it if None) and update the index trees
+    /// to match, all in one transaction. With `expected`, the write is only made if the current
+    /// value matches it. Returns the value which was replaced.
+    pub fn write(
+        &self,
+        primary: &Tree,
+        index_trees: &[Tree],
+        key: &[u8],
+        new: Option<&IVec>,
+        expected: Option<Option<&[u8]>>,
+    ) -> Result<std::result::Result<Option<IVec>, CompareAndSwapError>> {
+        let mut trees = vec![primary.clone()];
+        trees.extend(index_trees.iter().cloned());
+        let result = trees[..].transaction(
+            |views| -> ConflictableTransactionResult<std::result::Result<_, _>, ()> {
+                let previous = views[0].get(key)?;
+                if let Some(expected) = expected {
+                    if previous.as_deref() != expected {
+                        return Ok(Err(CompareAndSwapError {
+                            current: previous,
+                            proposed: new.cloned(),
+                        }));
+                    }
+                }
+                match new {
+                    Some(value) => views[0].insert(key, value.clone())?,
+                    None => views[0].remove(key)?,
+                };
+                self.update(
+                    &views[1..],
+                    key,
+                    previous.as_deref(),
+                    new.map(|new| &new[..]),
+                )?;
+                Ok(Ok(previous))
+            },
+        );
+        result.map_err(|e| match e {
+            TransactionError::Storage(e) => e.into(),
+            TransactionError::Abort(()) => anyhow!("Indexed write was aborted"),
+        })
+    }
+
+    /// Update the entries in the index trees, as seen from a transaction, for a record whose
+    /// value goes from `previous` to `new`
+    pub fn update(
+        &self,
+        views: &[TransactionalTree],
+        key: &[u8],
+        previous: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<(), UnabortableTransactionError> {
+        let values = |value: Option<&[u8]>| {
+            value
+                .and_then(|value| (self.values)(value))
+                .unwrap_or_default()
+        };
+        let (old_values, new_values) = (values(previous), values(new));
+        for (index, view) in views.iter().enumerate() {
+            let (old, new) = (old_values.get(index), new_values.get(index));
+            if old == new {
+                continue;
+            }
+            if let Some(old) = old {
+                view.remove(entry_key(old, key))?;
+            }
+            if let Some(new) = new {
+                view.insert(entry_key(new, key), &[])?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(super) fn index_tree(tree: &str, name: &str) -> String {
+    format!("index/{}/{}", tree, name)
+}
+
+fn value_prefix(value: &[u8]) -> Vec<u8> {
+    let len = u16::try_from(value.len()).unwrap_or(u16::MAX);
+    let mut prefix = len.to_be_bytes().to_vec();
+    prefix.extend_from_slice(&value[..len as usize]);
+    prefix
+}
+
+fn entry_key(value: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut entry = value_prefix(value);
+    entry.extend_from_slice(key);
+    entry
+}
+
+/// The keys of the records found under values from `from` up to `to`, in order of value then
+/// key. Every value must have the same length (eg. timestamps), or the order isn't the values'.
+pub(super) fn keys_between(
+    index: &Tree,
+    from: Bound<&[u8]>,
+    to: Bound<&[u8]>,
+) -> impl Iterator<Item = Result<IVec>> {
+    // the entries under an excluded value sort after it, so are skipped as they're read
+    let (lower, skip) = match from {
+        Bound::Included(value) => (Bound::Included(value_prefix(value)), None),
+        Bound::Excluded(value) => (
+            Bound::Excluded(value_prefix(value)),
+            Some(value_prefix(value)),
+        ),
+        Bound::Unbounded => (Bound::Unbounded, None),
+    };
+    let upper = match to {
+        Bound::Included(value) => match next_prefix(value_prefix(value)) {
+            Some(next) => Bound::Excluded(next),
+            None => Bound::Unbounded,
+        },
+        Bound::Excluded(value) => Bound::Excluded(value_prefix(value)),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+    let skipped = move |entry: &sled::Result<IVec>| match (&skip, entry) {
+        (Some(skip), Ok(entry)) => entry.starts_with(skip),
+        _ => false,
+    };
+    index
+        .range((lower, upper))
+        .keys()
+        .filter(move |entry| !skipped(entry))
+        .map(|entry| {
+            let entry = entry?;
+            let len = u16::from_be_bytes([entry[0], entry[1]]) as usize;
+            Ok(entry.subslice(2 + len, entry.len() - 2 - len))
+        })
+}
+
+/// The smallest key after every key starting with `prefix`, if there is one
+fn next_prefix(mut prefix: Vec<u8>) -> Option<Vec<u8>> {
+    while let Some(last) = prefix.pop() {
+        if last < 0xff {
+            prefix.push(last + 1);
+            return Some(prefix);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{storage::StorageEngine, test_util::ingress_log};
+    use hydra_proto::IngressLog;
+
+    fn log(n: u64, host: &str) -> (Vec<u8>, Vec<u8>) {
+        let log = IngressLog {
+            event_id: ulid::Ulid::from_parts(n, 0),
+            date: chrono::DateTime::from_timestamp_millis(n as i64).unwrap(),
+            host: host.to_string(),
+            ..ingress_log("hooks", b"")
+        };
+        (
+            log.event_id.to_bytes().to_vec(),
+            bincode::serialize(&log).unwrap(),
+        )
+    }
+
+    fn keys(storage: &StorageEngine, index: &str, value: &[u8]) -> Vec<Vec<u8>> {
+        storage
+            .indexed("ingress", index, value)
+            .unwrap()
+            .into_iter()
+            .map(|key| key.to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn test_indexes() {
+        let storage = StorageEngine::new_test().unwrap();
+        // written before the indexes exist, so indexed when they're added
+        let (a, a_value) = log(1, "github.com");
+        storage.insert("ingress", &a, a_value).unwrap();
+        storage
+            .add_indexes::<IngressLog>(|tree| tree == "ingress")
+            .unwrap();
+
+        let (b, b_value) = log(2, "stripe.com");
+        let (c, c_value) = log(3, "GitHub.com");
+        storage.insert("ingress", &b, b_value).unwrap();
+        storage.insert("ingress", &c, c_value).unwrap();
+        // other trees aren't indexed
+        storage.insert("other", &a, log(1, "x").1).unwrap();
+
+        assert_eq!(
+            keys(&storage, "host", b"github.com"),
+            vec![a.clone(), c.clone()]
+        );
+        assert_eq!(keys(&storage, "host", b"stripe.com"), vec![b.clone()]);
+        assert_eq!(keys(&storage, "method", b"POST").len(), 3);
+
+        // moving a record between values, and removing it
+        let (_, moved) = log(2, "github.com");
+        storage.insert("ingress", &b, moved).unwrap();
+        assert!(keys(&storage, "host", b"stripe.com").is_empty());
+        assert_eq!(keys(&storage, "host", b"github.com").len(), 3);
+        storage.remove("ingress", &a).unwrap();
+        assert_eq!(
+            keys(&storage, "host", b"github.com"),
+            vec![b.clone(), c.clone()]
+        );
+
+        // compare and swap only updates the index if the swap happens
+        let current = storage.get("ingress", &c).unwrap().unwrap();
+        let (_, swapped) = log(3, "stripe.com");
+        assert!(storage
+            .compare_and_swap("ingress", &c, Some(b"stale"), Some(swapped.clone()))
+            .unwrap()
+            .is_err());
+        assert_eq!(keys(&storage, "host", b"stripe.com"), Vec::<Vec<u8>>::new());
+        storage
+            .compare_and_swap("ingress", &c, Some(&current), Some(swapped))
+            .unwrap()
+            .unwrap();
+        assert_eq!(keys(&storage, "host", b"stripe.com"), vec![c.clone()]);
+
+        // dates are fixed width, so can be read by range
+        let between = |from: u64, to: u64| {
+            storage
+                .indexed_between(
+                    "ingress",
+                    "date",
+                    Bound::Included(&from.to_be_bytes()[..]),
+                    Bound::Excluded(&to.to_be_bytes()[..]),
+                )
+                .unwrap()
+                .into_iter()
+                .map(|key| key.to_vec())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(between(0, 3), vec![b.clone()]);
+        assert_eq!(between(2, 4), vec![b, c]);
+        assert!(storage.indexed("ingress", "nope", b"x").is_err());
+    }
+}
@@ -0,0 +1,122 @@
+//! Read-path statistics for each tree, to show which trees are read most heavily and how far
+//! their scans go, eg. when deciding which index or view to add next.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use hydra_proto as proto;
+
+use super::ScanIter;
+
+#[derive(Default)]
+pub struct TreeStats {
+    // point reads
+    reads: AtomicU64,
+    scans: AtomicU64,
+    // items yielded by scans
+    scanned: AtomicU64,
+    // keys and values returned by reads and scans
+    bytes_read: AtomicU64,
+}
+
+impl TreeStats {
+    pub fn record_read(&self, value: Option<&[u8]>, key: &[u8]) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        let bytes = value.map_or(0, |value| key.len() + value.len());
+        self.bytes_read.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self, tree: &str) -> proto::TreeReadStats {
+        let scans = self.scans.load(Ordering::Relaxed);
+        let scanned = self.scanned.load(Ordering::Relaxed);
+        proto::TreeReadStats {
+            tree: tree.to_string(),
+            reads: self.reads.load(Ordering::Relaxed),
+            scans,
+            scanned,
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            mean_scan_length: scanned.checked_div(scans).unwrap_or(0),
+        }
+    }
+}
+
+/// Per-tree read statistics since the server started
+#[derive(Default)]
+pub struct ReadMetrics {
+    trees: RwLock<HashMap<String, Arc<TreeStats>>>,
+}
+
+impl ReadMetrics {
+    pub fn tree(&self, name: &str) -> Arc<TreeStats> {
+        if let Some(stats) = self.trees.read().unwrap().get(name) {
+            return stats.clone();
+        }
+        self.trees
+            .write()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Every tree which has been read, ranked by the number of bytes read from it
+    pub fn ranking(&self) -> Vec<proto::TreeReadStats> {
+        let mut ranking: Vec<_> = self
+            .trees
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, stats)| stats.snapshot(name))
+            .collect();
+        ranking.sort_by(|a, b| {
+            b.bytes_read
+                .cmp(&a.bytes_read)
+                .then_with(|| a.tree.cmp(&b.tree))
+        });
+        ranking
+    }
+}
+
+/// Counts what a scan yields, recording the scan once it's dropped, however far it got
+pub fn counted<'a>(scan: ScanIter<'a>, stats: Arc<TreeStats>) -> ScanIter<'a> {
+    Box::new(CountedScan {
+        scan,
+        stats,
+        scanned: 0,
+        bytes: 0,
+    })
+}
+
+struct CountedScan<'a> {
+    scan: ScanIter<'a>,
+    stats: Arc<TreeStats>,
+    scanned: u64,
+    bytes: u64,
+}
+
+impl Iterator for CountedScan<'_> {
+    type Item = sled::Result<(sled::IVec, sled::IVec)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = self.scan.next()?;
+        if let Ok((key, value)) = &item {
+            self.scanned += 1;
+            self.bytes += (key.len() + value.len()) as u64;
+        }
+        Some(item)
+    }
+}
+
+impl Drop for CountedScan<'_> {
+    fn drop(&mut self) {
+        let stats = &self.stats;
+        stats.scans.fetch_add(1, Ordering::Relaxed);
+        stats.scanned.fetch_add(self.scanned, Ordering::Relaxed);
+        stats.bytes_read.fetch_add(self.bytes, Ordering::Relaxed);
+    }
+}
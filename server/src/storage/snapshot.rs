@@ -0,0 +1,289 @@
+//! Snapshot reads on top of sled, which has no native snapshots.
+//!
+//! Every write made through the StorageEngine is given a sequence number and the value it
+//! replaced is kept in a bounded in-memory history. Reading as of an earlier sequence number
+//! overlays those previous values on top of the live tree, hiding anything written since. Scans
+//! don't hold writes off, but catch up on the history as they go, so that writes made while they
+//! run are hidden from them too.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    iter::Peekable,
+    ops::{Bound, RangeBounds},
+    sync::RwLock,
+};
+
+use anyhow::Result;
+use hydra_error::{Classified, ErrorKind};
+use hydra_proto as proto;
+use sled::IVec;
+use ulid::Ulid;
+
+/// How many writes to remember. Snapshots older than this are rejected.
+pub const HISTORY_CAPACITY: usize = 10_000;
+
+pub type ScanIter<'a> = Box<dyn Iterator<Item = sled::Result<(IVec, IVec)>> + 'a>;
+
+struct HistoryEntry {
+    sequence: u64,
+    tree: String,
+    key: IVec,
+    // The value before this write, None if the key didn't exist
+    previous: Option<IVec>,
+}
+
+pub struct WriteHistory {
+    epoch: Ulid,
+    sequence: u64,
+    entries: VecDeque<HistoryEntry>,
+    // The highest sequence number which has been dropped from the history
+    pruned_through: u64,
+}
+
+impl WriteHistory {
+    pub fn new() -> Self {
+        Self {
+            epoch: Ulid::new(),
+            sequence: 0,
+            entries: VecDeque::new(),
+            pruned_through: 0,
+        }
+    }
+
+    /// The snapshot token for the current state of the database
+    pub fn token(&self) -> proto::SnapshotToken {
+        proto::SnapshotToken {
+            epoch: self.epoch,
+            sequence: self.sequence,
+        }
+    }
+
+    /// Record a write, returning its sequence number
+    pub fn record(&mut self, tree: &str, key: &[u8], previous: Option<IVec>) -> u64 {
+        self.sequence += 1;
+        self.entries.push_back(HistoryEntry {
+            sequence: self.sequence,
+            tree: tree.to_string(),
+            key: IVec::from(key),
+            previous,
+        });
+        while self.entries.len() > HISTORY_CAPACITY {
+            if let Some(entry) = self.entries.pop_front() {
+                self.pruned_through = entry.sequence;
+            }
+        }
+        self.sequence
+    }
+
+    /// Whether the snapshot can no longer be read, as it was taken before the server restarted or
+    /// has been pruned from the history
+    pub fn expired(&self, token: &proto::SnapshotToken) -> bool {
+        token.epoch != self.epoch || token.sequence < self.pruned_through
+    }
+
+    /// The values which keys of the tree had at the snapshot, for keys written since
+    pub fn overlay(&self, tree: &str, token: &proto::SnapshotToken) -> Result<Overlay> {
+        if token.epoch != self.epoch {
+            return Err(Classified::new(
+                ErrorKind::Conflict,
+                "Snapshot was taken before the server restarted",
+            )
+            .into());
+        }
+        if token.sequence > self.sequence {
+            return Err(Classified::new(
+                ErrorKind::InvalidRequest,
+                format!("Snapshot {} does not exist yet", token.sequence),
+            )
+            .into());
+        }
+        if token.sequence < self.pruned_through {
+            return Err(Classified::new(
+                ErrorKind::Conflict,
+                format!(
+                    "Snapshot {} is too old, the oldest available is {}",
+                    token.sequence, self.pruned_through
+                ),
+            )
+            .into());
+        }
+
+        let mut overlay = Overlay {
+            tree: tree.to_string(),
+            changed: BTreeMap::new(),
+            through: token.sequence,
+        };
+        overlay.catch_up(self)?;
+        Ok(overlay)
+    }
+}
+
+/// The keys of a tree which have been written since a snapshot, with their values at the snapshot
+pub struct Overlay {
+    tree: String,
+    changed: BTreeMap<IVec, Option<IVec>>,
+    // The sequence of the latest write taken into account
+    through: u64,
+}
+
+impl Overlay {
+    /// Take in the writes made to the tree since the overlay was last brought up to date,
+    /// returning the keys they're the first to write, with their values at the snapshot
+    fn catch_up(&mut self, history: &WriteHistory) -> Result<Vec<(IVec, Option<IVec>)>> {
+        if history.sequence == self.through {
+            return Ok(Vec::new());
+        }
+        if history.pruned_through > self.through {
+            return Err(Classified::new(
+                ErrorKind::Conflict,
+                "Snapshot expired while it was being read",
+            )
+            .into());
+        }
+        let start = (history.entries).partition_point(|entry| entry.sequence <= self.through);
+        let mut added = Vec::new();
+        for entry in history.entries.range(start..) {
+            // the earliest write after the snapshot holds the value as of the snapshot
+            if entry.tree == self.tree && !self.changed.contains_key(&entry.key) {
+                self.changed
+                    .insert(entry.key.clone(), entry.previous.clone());
+                added.push((entry.key.clone(), entry.previous.clone()));
+            }
+        }
+        self.through = history.sequence;
+        Ok(added)
+    }
+
+    /// How many of the keys under a prefix which there are in the live tree there were at the
+    /// snapshot, given how many there are now
+    pub fn count(&self, tree: &sled::Tree, prefix: &[u8], now: u64) -> Result<u64> {
+        let mut count = now as i64;
+        let changed = (self
+            .changed
+            .range::<[u8], _>((Bound::Included(prefix), Bound::Unbounded)))
+        .take_while(|(key, _)| key.starts_with(prefix));
+        for (key, previous) in changed {
+            count += previous.is_some() as i64 - tree.contains_key(key)? as i64;
+        }
+        Ok(count.max(0) as u64)
+    }
+
+    /// Merge the overlay into a scan of the live tree, catching up on the history as it goes
+    pub fn scan<'a>(
+        &self,
+        tree: &'a sled::Tree,
+        history: &'a RwLock<WriteHistory>,
+        range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+        reverse: bool,
+    ) -> ScanIter<'a> {
+        let live: ScanIter<'a> = if reverse {
+            Box::new(tree.range(range.clone()).rev())
+        } else {
+            Box::new(tree.range(range.clone()))
+        };
+        let bounds = (
+            range.0.as_ref().map(Vec::as_slice),
+            range.1.as_ref().map(Vec::as_slice),
+        );
+        let restored = (self.changed.range::<[u8], _>(bounds))
+            .filter_map(|(key, previous)| Some((key.clone(), previous.clone()?)))
+            .collect();
+
+        Box::new(MergeIter {
+            history,
+            overlay: Overlay {
+                tree: self.tree.clone(),
+                changed: self.changed.clone(),
+                through: self.through,
+            },
+            live: live.peekable(),
+            restored,
+            range,
+            reached: None,
+            reverse,
+        })
+    }
+}
+
+struct MergeIter<'a> {
+    history: &'a RwLock<WriteHistory>,
+    overlay: Overlay,
+    live: Peekable<ScanIter<'a>>,
+    // Values at the snapshot of keys in the range which have been written since, and which the
+    // scan is yet to reach
+    restored: BTreeMap<IVec, IVec>,
+    range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+    // The last key returned
+    reached: Option<IVec>,
+    reverse: bool,
+}
+
+impl<'a> MergeIter<'a> {
+    /// Take in writes made since the scan started, restoring the values they replaced ahead of it
+    fn catch_up(&mut self) -> Result<()> {
+        let added = self.overlay.catch_up(&self.history.read().unwrap())?;
+        for (key, previous) in added {
+            let ahead = match &self.reached {
+                None => true,
+                Some(reached) if self.reverse => key < *reached,
+                Some(reached) => key > *reached,
+            };
+            if let Some(previous) = previous.filter(|_| ahead) {
+                if self.range.contains(&key.to_vec()) {
+                    self.restored.insert(key, previous);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for MergeIter<'a> {
+    type Item = sled::Result<(IVec, IVec)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            // peeked before catching up: writes hold the history's lock while they apply, so
+            // whatever wrote the live value is in the history by then
+            let live = match self.live.peek() {
+                Some(Ok((key, _))) => Some(key.clone()),
+                Some(Err(_)) => return self.live.next(),
+                None => None,
+            };
+            if let Err(e) = self.catch_up() {
+                return Some(Err(sled::Error::Unsupported(e.to_string())));
+            }
+            // skip live values written after the snapshot, they are superseded by the overlay
+            if let Some(key) = &live {
+                if self.overlay.changed.contains_key(key) {
+                    self.live.next();
+                    continue;
+                }
+            }
+
+            let restored = match self.reverse {
+                true => self.restored.last_key_value(),
+                false => self.restored.first_key_value(),
+            };
+            let take_restored = match (&live, restored) {
+                (None, None) => return None,
+                (None, Some(_)) => true,
+                (Some(_), None) => false,
+                (Some(live), Some((restored, _))) => match self.reverse {
+                    true => restored > live,
+                    false => restored < live,
+                },
+            };
+
+            let item = match (take_restored, self.reverse) {
+                (true, true) => self.restored.pop_last().map(Ok),
+                (true, false) => self.restored.pop_first().map(Ok),
+                (false, _) => self.live.next(),
+            };
+            if let Some(Ok((key, _))) = &item {
+                self.reached = Some(key.clone());
+            }
+            return item;
+        }
+    }
+}
@@ -0,0 +1,203 @@
+//! Writes to several records, in one or more trees, made all together or not at all, eg. a
+//! captured log along with the DAG event covering it. See StorageEngine::transaction.
+
+use std::cell::RefCell;
+
+use anyhow::anyhow;
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionalTree},
+    IVec,
+};
+
+use super::index::TreeIndexes;
+use crate::error::AppError;
+
+/// What a transaction returns: Err if it's aborted, see `abort`, or if one of its reads or writes
+/// conflicted with another transaction's, in which case sled runs it again
+pub type TransactionResult<T> = Result<T, ConflictableTransactionError<AppError>>;
+
+/// Abort a transaction, failing it with this error
+pub fn abort<T>(err: impl Into<AppError>) -> TransactionResult<T> {
+    Err(ConflictableTransactionError::Abort(err.into()))
+}
+
+/// One of the trees a transaction was opened on, as seen from within it
+pub(super) struct TransactionTree<'a> {
+    pub name: &'a str,
+    pub primary: &'a TransactionalTree,
+    // the tree's indexes, if it has any, and their trees as seen from within the transaction
+    pub indexes: Option<(&'a TreeIndexes, &'a [TransactionalTree])>,
+}
+
+/// A write made in a transaction, to be recorded in the history and published once it's applied
+pub(super) struct Write {
+    pub tree: String,
+    pub key: IVec,
+    pub value: Option<IVec>,
+    pub previous: Option<IVec>,
+}
+
+/// Reads and writes within a transaction. Reads see the transaction's own writes.
+pub struct Transaction<'a> {
+    trees: Vec<TransactionTree<'a>>,
+    writes: RefCell<Vec<Write>>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(super) fn new(trees: Vec<TransactionTree<'a>>) -> Self {
+        Self {
+            trees,
+            writes: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub(super) fn into_writes(self) -> Vec<Write> {
+        self.writes.into_inner()
+    }
+
+    pub fn get(&self, tree: &str, key: impl AsRef<[u8]>) -> TransactionResult<Option<IVec>> {
+        Ok(self.tree(tree)?.primary.get(key)?)
+    }
+
+    /// Insert a record, returning the value it replaced
+    pub fn insert(
+        &self,
+        tree: &str,
+        key: impl AsRef<[u8]>,
+        value: impl Into<IVec>,
+    ) -> TransactionResult<Option<IVec>> {
+        self.write(tree, key.as_ref(), Some(value.into()))
+    }
+
+    /// Remove a record, returning its value if there was one
+    pub fn remove(&self, tree: &str, key: impl AsRef<[u8]>) -> TransactionResult<Option<IVec>> {
+        self.write(tree, key.as_ref(), None)
+    }
+
+    fn write(
+        &self,
+        tree: &str,
+        key: &[u8],
+        value: Option<IVec>,
+    ) -> TransactionResult<Option<IVec>> {
+        let target = self.tree(tree)?;
+        let previous = match &value {
+            Some(value) => target.primary.insert(key, value.clone())?,
+            None => target.primary.remove(key)?,
+        };
+        if let Some((indexes, views)) = target.indexes {
+            indexes.update(views, key, previous.as_deref(), value.as_deref())?;
+        }
+        // removing a record which isn't there changes nothing
+        if value.is_some() || previous.is_some() {
+            self.writes.borrow_mut().push(Write {
+                tree: tree.to_string(),
+                key: IVec::from(key),
+                value,
+                previous: previous.clone(),
+            });
+        }
+        Ok(previous)
+    }
+
+    fn tree(&self, name: &str) -> TransactionResult<&TransactionTree<'a>> {
+        match self.trees.iter().find(|tree| tree.name == name) {
+            Some(tree) => Ok(tree),
+            None => abort(anyhow!(
+                "{} is not one of the trees the transaction was opened on",
+                name
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+
+    use hydra_error::ErrorKind;
+    use hydra_proto::IngressLog;
+
+    use super::*;
+    use crate::{
+        storage::{Scan, StorageEngine, StorageOp},
+        test_util::ingress_log,
+    };
+
+    fn log(host: &str) -> Vec<u8> {
+        bincode::serialize(&IngressLog {
+            host: host.to_string(),
+            ..ingress_log("hooks", b"")
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_transaction() {
+        let storage = StorageEngine::new_test().unwrap();
+        storage
+            .add_indexes::<IngressLog>(|tree| tree == "ingress")
+            .unwrap();
+        let mut receiver = storage.subscribe();
+        let before = storage.token();
+
+        // a log, its index entries and an event, all at once
+        let written = storage
+            .transaction(&["ingress", "events"], |tx| {
+                tx.insert("ingress", b"log", log("a.example.com"))?;
+                assert!(tx.get("ingress", b"log")?.is_some());
+                tx.insert("events", b"event", &b"1"[..])?;
+                // nothing to remove, so nothing written
+                tx.remove("events", b"missing")?;
+                Ok(7)
+            })
+            .unwrap();
+        assert_eq!(written, 7);
+        assert!(storage.get("events", b"event").unwrap().is_some());
+        assert_eq!(
+            storage
+                .indexed("ingress", "host", b"a.example.com")
+                .unwrap()
+                .len(),
+            1
+        );
+        let events: Vec<_> = std::iter::from_fn(|| receiver.try_recv().ok()).collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            (events[0].tree.as_str(), events[0].op),
+            ("ingress", StorageOp::Insert)
+        );
+        assert_eq!(events[1].sequence, events[0].sequence + 1);
+        // and they're in the history, so a snapshot from before doesn't see them
+        let (seen, _) = storage
+            .read_as_of("events", Some(&before), |view| {
+                view.scan((Bound::Unbounded, Bound::Unbounded), false)
+                    .count()
+            })
+            .unwrap();
+        assert_eq!(seen, 0);
+
+        // an aborted transaction writes nothing, and fails with the error it was aborted with
+        let err = storage
+            .transaction(&["ingress", "events"], |tx| {
+                tx.remove("ingress", b"log")?;
+                tx.insert("events", b"other", &b"2"[..])?;
+                abort::<()>(AppError::conflict("changed my mind"))
+            })
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Conflict);
+        assert!(storage.get("ingress", b"log").unwrap().is_some());
+        assert!(storage.get("events", b"other").unwrap().is_none());
+        assert!(receiver.try_recv().is_err());
+
+        // as does one which writes to a tree it wasn't opened on
+        let err = storage
+            .transaction(&["events"], |tx| {
+                tx.insert("events", b"other", &b"2"[..])?;
+                tx.insert("elsewhere", b"key", &b"3"[..])
+            })
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Internal);
+        assert!(storage.get("events", b"other").unwrap().is_none());
+    }
+}
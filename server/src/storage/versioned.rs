@@ -0,0 +1,374 @@
+//! Versioned records, so that a record type such as IngressLog can change, eg. gain a field,
+//! without the records already stored becoming unreadable.
+//!
+//! A versioned value is a tag giving the codec the record was encoded with, the version of the
+//! record type it was written with, then the record. Records are written with the codec
+//! --storage-codec picks, and each is read with the one its tag gives, so changing it leaves those
+//! already written readable. Values without a tag were written with bincode before versions were
+//! recorded, and have the first version. A record type declares its current version, and a migration from
+//! each older version to the next, by implementing Versioned. StorageEngine::migrate_records then
+//! brings every record in the trees holding the type up to the current version at startup, so the
+//! rest of the server only ever reads records of the version it was built with.
+//!
+//! The version each tree's records were last brought up to is kept in the `meta` tree, so trees
+//! already up to date aren't read again, and a tree written by a newer hydra is refused rather than
+//! misread. Each record is rewritten on its own, so a run which stops part way carries on from
+//! there the next time.
+//!
+//! To change a record type, bump its VERSION and add a migration from the old version, which
+//! rewrites a record of the old layout as one of the new.
+//!
+//! With --compress-bodies, values larger than compression::COMPRESS_ABOVE, which are those of logs
+//! with large bodies, are stored compressed: a tag giving the compression, then the whole value
+//! compressed with it. They're decompressed as they're read, so readers needn't know.
+
+use std::borrow::Cow;
+
+use anyhow::{anyhow, bail, Result};
+use hydra_proto as proto;
+use serde::{de::DeserializeOwned, Serialize};
+use sled::Db;
+
+use super::format::META_TREE;
+use crate::{
+    codec::{Codec, CodecKind},
+    compression::{self, COMPRESS_ABOVE},
+};
+
+/// Marks a versioned value encoded with bincode. None of the tags can be mistaken for the start of
+/// an unversioned record, as bincode starts an IngressLog with the length of its ID, which is 26.
+pub const ENVELOPE_TAG: u8 = 0xfe;
+const CBOR_TAG: u8 = 0xfd;
+const MESSAGE_PACK_TAG: u8 = 0xfc;
+const DEFLATE_TAG: u8 = 0xfb;
+const ZSTD_TAG: u8 = 0xfa;
+
+/// Records written before versions were recorded have the first version
+const UNVERSIONED: u8 = 1;
+
+/// A record type whose stored records are versioned
+pub trait Versioned: Serialize + DeserializeOwned {
+    /// The version this binary reads and writes
+    const VERSION: u8;
+
+    /// Migrations from each older version to the next
+    fn migrations() -> Vec<Box<dyn RecordMigration>> {
+        Vec::new()
+    }
+}
+
+/// Rewrites a record from one version of its type to the next
+pub trait RecordMigration: Send + Sync {
+    /// The version migrated from
+    fn from(&self) -> u8;
+
+    /// The record in the next version, given that in this one, both encoded with `codec`
+    fn migrate(&self, codec: CodecKind, record: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Encode a record with a codec, in an envelope giving its type's current version
+pub fn encode<T: Versioned>(codec: CodecKind, record: &T) -> Result<Vec<u8>> {
+    let mut value = vec![tag(codec), T::VERSION];
+    value.extend(codec.encode(record)?);
+    Ok(value)
+}
+
+/// Compress an encoded value, if it's large enough to be worth it
+pub fn compress(value: Vec<u8>, compression: Option<proto::Compression>) -> Result<Vec<u8>> {
+    let Some(compression) = compression.filter(|_| value.len() > COMPRESS_ABOVE) else {
+        return Ok(value);
+    };
+    let mut compressed = vec![match compression {
+        proto::Compression::Deflate => DEFLATE_TAG,
+        proto::Compression::Zstd => ZSTD_TAG,
+    }];
+    compressed.extend(compression::compress(compression, &value)?);
+    // already compressed bodies, eg. images, don't shrink
+    Ok(match compressed.len() < value.len() {
+        true => compressed,
+        false => value,
+    })
+}
+
+/// Decode a stored record, whether it's in an envelope or not
+pub fn decode<T: DeserializeOwned>(value: &[u8]) -> Result<T> {
+    let (_, value) = decompress(value)?;
+    let (codec, _, record) = split(&value);
+    codec.decode(record)
+}
+
+/// The value as it was before it was compressed, and what it was compressed with, if it was
+fn decompress(value: &[u8]) -> Result<(Option<proto::Compression>, Cow<'_, [u8]>)> {
+    let (compression, compressed) = match value {
+        [DEFLATE_TAG, compressed @ ..] => (proto::Compression::Deflate, compressed),
+        [ZSTD_TAG, compressed @ ..] => (proto::Compression::Zstd, compressed),
+        _ => return Ok((None, Cow::Borrowed(value))),
+    };
+    let value = compression::decompress(compression, compressed)?;
+    Ok((Some(compression), Cow::Owned(value)))
+}
+
+fn tag(codec: CodecKind) -> u8 {
+    match codec {
+        CodecKind::Bincode => ENVELOPE_TAG,
+        CodecKind::Cbor => CBOR_TAG,
+        CodecKind::MessagePack => MESSAGE_PACK_TAG,
+    }
+}
+
+/// A value's codec and version, and the record in it
+fn split(value: &[u8]) -> (CodecKind, u8, &[u8]) {
+    match value {
+        [ENVELOPE_TAG, version, record @ ..] => (CodecKind::Bincode, *version, record),
+        [CBOR_TAG, version, record @ ..] => (CodecKind::Cbor, *version, record),
+        [MESSAGE_PACK_TAG, version, record @ ..] => (CodecKind::MessagePack, *version, record),
+        _ => (CodecKind::Bincode, UNVERSIONED, value),
+    }
+}
+
+/// The versions of a record type, and the migrations between them
+pub(super) struct RecordVersions {
+    applies_to: fn(&str) -> bool,
+    current: u8,
+    migrations: Vec<Box<dyn RecordMigration>>,
+}
+
+impl RecordVersions {
+    pub fn new<T: Versioned>(applies_to: fn(&str) -> bool) -> Self {
+        Self {
+            applies_to,
+            current: T::VERSION,
+            migrations: T::migrations(),
+        }
+    }
+
+    pub fn applies_to(&self, tree: &str) -> bool {
+        (self.applies_to)(tree)
+    }
+
+    /// Whether a tree's records need reading, to bring any older ones up to date. Fails if they
+    /// were written by a newer hydra.
+    pub fn outdated(&self, db: &Db, tree: &str) -> Result<bool> {
+        match recorded_version(db, tree)? {
+            Some(version) if version > self.current => bail!(
+                "The records in {} were written by a newer hydra (version {}, but this binary only \
+                 understands up to {}). Run the newer hydra instead, as this one would misread them.",
+                tree,
+                version,
+                self.current
+            ),
+            Some(version) => Ok(version < self.current),
+            None => Ok(true),
+        }
+    }
+
+    /// The value rewritten as the current version, in the codec and compression it was written
+    /// with, or None if it already is
+    pub fn upgrade(&self, value: &[u8]) -> Result<Option<Vec<u8>>> {
+        let (compression, value) = decompress(value)?;
+        let (codec, mut version, record) = split(&value);
+        if version > self.current {
+            bail!(
+                "Record version {} is newer than this binary's {}",
+                version,
+                self.current
+            );
+        }
+        if version == self.current {
+            return Ok(None);
+        }
+        let mut record = record.to_vec();
+        while version < self.current {
+            let migration = (self.migrations.iter())
+                .find(|migration| migration.from() == version)
+                .ok_or_else(|| anyhow!("No migration from record version {}", version))?;
+            record = migration.migrate(codec, &record)?;
+            version += 1;
+        }
+        let mut value = vec![tag(codec), self.current];
+        value.extend(record);
+        Ok(Some(compress(value, compression)?))
+    }
+
+    /// Note that every record in a tree has been brought up to the current version
+    pub fn migrated(&self, db: &Db, tree: &str) -> Result<()> {
+        db.open_tree(META_TREE)?
+            .insert(version_key(tree), &[self.current])?;
+        Ok(())
+    }
+}
+
+fn version_key(tree: &str) -> Vec<u8> {
+    format!("record_version/{}", tree).into_bytes()
+}
+
+/// The version a tree's records were last brought up to, if they ever were
+fn recorded_version(db: &Db, tree: &str) -> Result<Option<u8>> {
+    let meta = db.open_tree(META_TREE)?;
+    Ok(meta
+        .get(version_key(tree))?
+        .and_then(|value| value.first().copied()))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::storage::StorageEngine;
+
+    // a record type which gained a field in version 2
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Note {
+        text: String,
+        pinned: bool,
+    }
+
+    impl Versioned for Note {
+        const VERSION: u8 = 2;
+
+        fn migrations() -> Vec<Box<dyn RecordMigration>> {
+            vec![Box::new(AddPinned)]
+        }
+    }
+
+    struct AddPinned;
+
+    impl RecordMigration for AddPinned {
+        fn from(&self) -> u8 {
+            1
+        }
+
+        fn migrate(&self, codec: CodecKind, record: &[u8]) -> Result<Vec<u8>> {
+            let text: String = codec.decode(record)?;
+            codec.encode(&Note {
+                text,
+                pinned: false,
+            })
+        }
+    }
+
+    // the same type, a version later, as a newer hydra would have it
+    #[derive(Serialize, Deserialize)]
+    struct NewerNote;
+
+    impl Versioned for NewerNote {
+        const VERSION: u8 = 3;
+
+        fn migrations() -> Vec<Box<dyn RecordMigration>> {
+            vec![Box::new(Unchanged)]
+        }
+    }
+
+    struct Unchanged;
+
+    impl RecordMigration for Unchanged {
+        fn from(&self) -> u8 {
+            2
+        }
+
+        fn migrate(&self, _codec: CodecKind, record: &[u8]) -> Result<Vec<u8>> {
+            Ok(record.to_vec())
+        }
+    }
+
+    #[test]
+    fn test_migrate_records() {
+        let storage = StorageEngine::new_test().unwrap();
+        let note = |text: &str, pinned| Note {
+            text: text.to_string(),
+            pinned,
+        };
+        // written before versions were recorded, and since
+        let old = bincode::serialize(&"old".to_string()).unwrap();
+        storage.insert("notes", "a", old).unwrap();
+        let new = encode(CodecKind::Bincode, &note("new", true)).unwrap();
+        assert_eq!(new[..2], [ENVELOPE_TAG, 2]);
+        storage.insert("notes", "b", new.clone()).unwrap();
+        // and after switching to a self-describing codec, with a record of the old layout
+        let mut cbor = vec![CBOR_TAG, 1];
+        ciborium::into_writer(&"cbor", &mut cbor).unwrap();
+        storage.insert("notes", "c", cbor).unwrap();
+        storage
+            .insert("other", "a", b"left alone".to_vec())
+            .unwrap();
+
+        let migrated = storage
+            .migrate_records::<Note>(|tree| tree == "notes")
+            .unwrap();
+        assert_eq!(migrated, 2);
+        let read = |key| decode::<Note>(&storage.get("notes", key).unwrap().unwrap()).unwrap();
+        assert_eq!(read("a"), note("old", false));
+        assert_eq!(read("b"), note("new", true));
+        assert_eq!(read("c"), note("cbor", false));
+        assert_eq!(
+            storage.get("notes", "c").unwrap().unwrap()[..2],
+            [CBOR_TAG, 2]
+        );
+        assert_eq!(
+            storage.get("other", "a").unwrap().as_deref(),
+            Some(&b"left alone"[..])
+        );
+        assert_eq!(recorded_version(&storage.db, "notes").unwrap(), Some(2));
+
+        // once a tree's up to date it isn't read again, and a newer hydra's records are refused
+        assert_eq!(
+            storage
+                .migrate_records::<Note>(|tree| tree == "notes")
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            storage
+                .migrate_records::<NewerNote>(|tree| tree == "notes")
+                .unwrap(),
+            3
+        );
+        let error = storage
+            .migrate_records::<Note>(|tree| tree == "notes")
+            .unwrap_err();
+        assert!(error.to_string().contains("newer hydra"), "{}", error);
+    }
+
+    #[test]
+    fn test_compressed_records() {
+        let deflate = Some(proto::Compression::Deflate);
+        let large = Note {
+            text: "webhook ".repeat(1000),
+            pinned: true,
+        };
+        let encoded = encode(CodecKind::Cbor, &large).unwrap();
+        let compressed = compress(encoded.clone(), deflate).unwrap();
+        assert_eq!(compressed[0], DEFLATE_TAG);
+        assert!(compressed.len() < encoded.len() / 10);
+        assert_eq!(decode::<Note>(&compressed).unwrap(), large);
+        // left alone if they're small, or compression's off
+        let small = encode(
+            CodecKind::Cbor,
+            &Note {
+                text: "small".to_string(),
+                pinned: false,
+            },
+        )
+        .unwrap();
+        assert_eq!(compress(small.clone(), deflate).unwrap(), small);
+        assert_eq!(compress(encoded.clone(), None).unwrap(), encoded);
+
+        // and a migration keeps them compressed
+        let mut old = vec![CBOR_TAG, 1];
+        ciborium::into_writer(&"webhook ".repeat(1000), &mut old).unwrap();
+        let old = compress(old, deflate).unwrap();
+        let upgraded = RecordVersions::new::<Note>(|_| true)
+            .upgrade(&old)
+            .unwrap()
+            .unwrap();
+        assert_eq!(upgraded[0], DEFLATE_TAG);
+        assert_eq!(
+            decode::<Note>(&upgraded).unwrap(),
+            Note {
+                pinned: false,
+                ..large
+            }
+        );
+    }
+}
@@ -0,0 +1,1005 @@
+pub mod deliveries;
+pub mod live;
+mod sampling;
+mod watch;
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use hydra_error::{Classified, ErrorKind};
+use hydra_proto as proto;
+use serde::Serialize;
+use tokio::{
+    sync::{broadcast::error::RecvError, mpsc::error::TrySendError},
+    time::MissedTickBehavior,
+};
+
+use crate::{
+    alerts::ALERTS_TREE,
+    handler::{record::COLLECTION_PREFIX, removal::TOMBSTONES_TREE},
+    outbound::OutboundSender,
+    sources::is_ingress_tree,
+    storage::{versioned, StorageEvent, StorageOp},
+    AppState,
+};
+
+use self::{
+    deliveries::DeliveryLog,
+    live::LiveQuery,
+    sampling::{source_of, Sampler},
+    watch::KeyWatch,
+};
+
+/// Trees which can currently be subscribed to, besides the ingress trees, and by prefix the trees
+/// of record collections
+const SUBSCRIBABLE_TREES: &[&str] = &[ALERTS_TREE, TOMBSTONES_TREE];
+
+/// The most subscriptions a connection can have at once
+pub const MAX_SUBSCRIPTIONS_PER_CONNECTION: usize = 64;
+
+/// How often sampling summaries and debounced key changes are checked for. Debounce windows are
+/// rounded up to a multiple of this.
+const FLUSH_EVERY: Duration = Duration::from_millis(50);
+
+struct Subscription {
+    tree: String,
+    outbound: OutboundSender,
+    // set when the subscriber asked for busy sources to be sampled
+    sampler: Option<Sampler>,
+    // set when the subscriber asked for particular keys rather than the whole tree
+    watch: Option<KeyWatch>,
+    // set when the subscriber asked for the keys starting with this rather than the whole tree
+    prefix: Option<Vec<u8>>,
+    // set when the subscription was made by a LiveQuery
+    live: Option<LiveQuery>,
+    // pushes made so far, which number the subscription's deliveries if they're being logged
+    pushes: u64,
+}
+
+/// Tracks which WebSocket connections are interested in which trees.
+///
+/// Subscriptions are split across shards by connection, and each shard is fed storage events by
+/// its own broker task, so that pushing to thousands of subscribers is spread over several
+/// threads instead of queueing up behind a single one. All of a connection's subscriptions live
+/// in the same shard, so its pushes are still queued in the order the writes happened.
+pub struct SubscriptionRegistry {
+    next_connection_id: AtomicUsize,
+    shards: Vec<Shard>,
+}
+
+#[derive(Default)]
+struct Shard {
+    // keyed by (connection id, subscription id)
+    subscriptions: Mutex<HashMap<(usize, usize), Subscription>>,
+    stats: ShardStats,
+    // set when deliveries are being logged, see SubscriptionRegistry::log_deliveries
+    deliveries: Option<DeliveryLog>,
+}
+
+/// Running totals for a shard's fan-out
+#[derive(Default)]
+struct ShardStats {
+    // events which had at least one subscriber in the shard
+    events: AtomicU64,
+    pushes: AtomicU64,
+    dropped: AtomicU64,
+    // pushes left out by sampling subscriptions
+    sampled: AtomicU64,
+    // from the write being applied to the last push for it being queued
+    total_latency_us: AtomicU64,
+    max_latency_us: AtomicU64,
+}
+
+/// A snapshot of one shard's fan-out metrics, as reported on /status
+#[derive(Serialize, Debug)]
+pub struct FanoutStats {
+    pub shard: usize,
+    pub subscriptions: usize,
+    pub events: u64,
+    pub pushes: u64,
+    pub dropped: u64,
+    pub sampled: u64,
+    pub mean_latency_us: u64,
+    pub max_latency_us: u64,
+}
+
+impl SubscriptionRegistry {
+    /// A registry with a shard for each available core
+    pub fn new() -> Self {
+        let shards = std::thread::available_parallelism().map_or(1, |n| n.get());
+        Self::with_shards(shards)
+    }
+
+    pub fn with_shards(count: usize) -> Self {
+        Self {
+            next_connection_id: AtomicUsize::new(0),
+            shards: (0..count.max(1)).map(|_| Shard::default()).collect(),
+        }
+    }
+
+    /// Keep a log of what's pushed to every subscription, see ReplayDeliveriesRequest
+    pub fn log_deliveries(mut self) -> Self {
+        for shard in &mut self.shards {
+            shard.deliveries = Some(DeliveryLog::default());
+        }
+        self
+    }
+
+    pub fn logs_deliveries(&self) -> bool {
+        self.shards.iter().any(|shard| shard.deliveries.is_some())
+    }
+
+    pub fn next_connection_id(&self) -> usize {
+        self.next_connection_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    fn shard(&self, connection_id: usize) -> &Shard {
+        &self.shards[connection_id % self.shards.len()]
+    }
+
+    pub fn subscribe(
+        &self,
+        connection_id: usize,
+        subscription_id: usize,
+        request: proto::SubscribeRequest,
+        outbound: OutboundSender,
+    ) -> Result<()> {
+        let tree = request.tree;
+        if request.prefix.is_some() && (!request.keys.is_empty() || request.sample_above.is_some())
+        {
+            return Err(Classified::new(
+                ErrorKind::InvalidRequest,
+                "Prefix subscriptions can't watch keys or be sampled",
+            )
+            .field("prefix")
+            .into());
+        }
+        let watch = if request.keys.is_empty() {
+            let subscribable = is_ingress_tree(&tree)
+                || SUBSCRIBABLE_TREES.contains(&tree.as_str())
+                || (request.prefix.is_some() && tree.starts_with(COLLECTION_PREFIX));
+            if !subscribable {
+                return Err(Classified::new(
+                    ErrorKind::InvalidRequest,
+                    format!("Subscriptions are not supported for tree {}", tree),
+                )
+                .field("tree")
+                .into());
+            }
+            None
+        } else {
+            if request.sample_above.is_some() {
+                return Err(Classified::new(
+                    ErrorKind::InvalidRequest,
+                    "Key watches can't be sampled",
+                )
+                .field("sample_above")
+                .into());
+            }
+            let debounce = request
+                .debounce_ms
+                .map(|ms| Duration::from_millis(ms as u64));
+            Some(KeyWatch::new(request.keys, debounce))
+        };
+        self.insert(
+            connection_id,
+            subscription_id,
+            Subscription {
+                tree,
+                outbound,
+                sampler: request.sample_above.map(Sampler::new),
+                watch,
+                prefix: request.prefix,
+                live: None,
+                pushes: 0,
+            },
+        )
+    }
+
+    /// Subscribe to the logs a LiveQuery covers. Nothing is pushed until `release`.
+    pub fn subscribe_live(
+        &self,
+        connection_id: usize,
+        subscription_id: usize,
+        tree: String,
+        live: LiveQuery,
+        outbound: OutboundSender,
+    ) -> Result<()> {
+        self.insert(
+            connection_id,
+            subscription_id,
+            Subscription {
+                tree,
+                outbound,
+                sampler: None,
+                watch: None,
+                prefix: None,
+                live: Some(live),
+                pushes: 0,
+            },
+        )
+    }
+
+    /// Push what a LiveQuery held back while its first page was read, now that the page, read
+    /// at `snapshot`, has been queued, and whatever it covers from then on
+    pub fn release(&self, connection_id: usize, subscription_id: usize, snapshot: u64) {
+        let shard = self.shard(connection_id);
+        let mut subscriptions = shard.subscriptions.lock().unwrap();
+        let Some(subscription) = subscriptions.get_mut(&(connection_id, subscription_id)) else {
+            return;
+        };
+        let Some(live) = &mut subscription.live else {
+            return;
+        };
+        for payload in live.release(snapshot) {
+            let key = match &payload {
+                proto::ResponsePayload::LiveQueryItem(item) => item.key.clone(),
+                _ => vec![],
+            };
+            let pushed = push(
+                &subscription.outbound,
+                connection_id,
+                subscription_id,
+                payload,
+            );
+            shard.note(subscription_id, subscription, &key, pushed);
+        }
+    }
+
+    fn insert(
+        &self,
+        connection_id: usize,
+        subscription_id: usize,
+        subscription: Subscription,
+    ) -> Result<()> {
+        let mut subscriptions = self.shard(connection_id).subscriptions.lock().unwrap();
+        let held = subscriptions
+            .keys()
+            .filter(|&&(id, other)| id == connection_id && other != subscription_id)
+            .count();
+        if held >= MAX_SUBSCRIPTIONS_PER_CONNECTION {
+            return Err(Classified::new(
+                ErrorKind::InvalidRequest,
+                format!(
+                    "A connection can have at most {} subscriptions, unsubscribe from some first",
+                    MAX_SUBSCRIPTIONS_PER_CONNECTION
+                ),
+            )
+            .into());
+        }
+        subscriptions.insert((connection_id, subscription_id), subscription);
+        Ok(())
+    }
+
+    pub fn unsubscribe(&self, connection_id: usize, subscription_id: usize) -> bool {
+        self.shard(connection_id)
+            .subscriptions
+            .lock()
+            .unwrap()
+            .remove(&(connection_id, subscription_id))
+            .is_some()
+    }
+
+    /// Drop all subscriptions belonging to a connection, eg. when it disconnects
+    pub fn remove_connection(&self, connection_id: usize) {
+        self.shard(connection_id)
+            .subscriptions
+            .lock()
+            .unwrap()
+            .retain(|(id, _), _| *id != connection_id);
+    }
+
+    /// Drop every subscription, eg. for connections which didn't close as the server shut down.
+    /// Returns how many there were.
+    pub fn clear(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.subscriptions.lock().unwrap().drain().count())
+            .sum()
+    }
+
+    pub fn stats(&self) -> Vec<FanoutStats> {
+        self.shards
+            .iter()
+            .enumerate()
+            .map(|(index, shard)| {
+                let stats = &shard.stats;
+                let events = stats.events.load(Ordering::Relaxed);
+                FanoutStats {
+                    shard: index,
+                    subscriptions: shard.subscriptions.lock().unwrap().len(),
+                    events,
+                    pushes: stats.pushes.load(Ordering::Relaxed),
+                    dropped: stats.dropped.load(Ordering::Relaxed),
+                    sampled: stats.sampled.load(Ordering::Relaxed),
+                    mean_latency_us: stats
+                        .total_latency_us
+                        .load(Ordering::Relaxed)
+                        .checked_div(events)
+                        .unwrap_or(0),
+                    max_latency_us: stats.max_latency_us.load(Ordering::Relaxed),
+                }
+            })
+            .collect()
+    }
+
+    #[cfg(test)]
+    fn notify(&self, event: &StorageEvent) {
+        for shard in &self.shards {
+            shard.notify(event);
+        }
+    }
+
+    #[cfg(test)]
+    fn flush(&self, now: Instant) {
+        for shard in &self.shards {
+            shard.flush(now);
+        }
+    }
+}
+
+/// Queue a push for a subscription without waiting, returning the sequence number it was given,
+/// as an error if there wasn't room for it
+fn push(
+    outbound: &OutboundSender,
+    connection_id: usize,
+    subscription_id: usize,
+    payload: proto::ResponsePayload,
+) -> Result<u64, u64> {
+    let push = proto::Response {
+        request_id: subscription_id,
+        sequence: 0,
+        trace_id: None,
+        payload,
+    };
+    // Never block the broker on a slow client. It will see a gap in the sequence numbers
+    // and refetch what it missed
+    outbound.try_send(push).map_err(|e| {
+        println!(
+            "Dropping push for subscription {} on connection {}",
+            subscription_id, connection_id
+        );
+        match e {
+            TrySendError::Full(sequence) | TrySendError::Closed(sequence) => sequence,
+        }
+    })
+}
+
+/// A write which is pushed to subscribers of its tree
+enum Pushed {
+    // boxed, as a log is much larger than the others
+    IngressLog(Box<proto::IngressLog>),
+    Alert(proto::Alert),
+    Tombstone(proto::IngressLogTombstone),
+}
+
+impl Pushed {
+    fn decode(tree: &str, value: &[u8]) -> anyhow::Result<Self> {
+        Ok(match tree {
+            ALERTS_TREE => Pushed::Alert(bincode::deserialize(value)?),
+            TOMBSTONES_TREE => Pushed::Tombstone(bincode::deserialize(value)?),
+            _ => Pushed::IngressLog(Box::new(versioned::decode(value)?)),
+        })
+    }
+
+    /// Who the write is attributed to for sampling. Only ingress logs are sampled.
+    fn source(&self) -> Option<String> {
+        match self {
+            Pushed::IngressLog(log) => Some(source_of(log)),
+            Pushed::Alert(_) | Pushed::Tombstone(_) => None,
+        }
+    }
+
+    fn payload(&self) -> proto::ResponsePayload {
+        match self {
+            Pushed::IngressLog(log) => proto::ResponsePayload::IngressLogAppended((**log).clone()),
+            Pushed::Alert(alert) => proto::ResponsePayload::Alert(alert.clone()),
+            Pushed::Tombstone(tombstone) => {
+                proto::ResponsePayload::IngressLogRemoved(tombstone.clone())
+            }
+        }
+    }
+}
+
+impl Shard {
+    fn notify(&self, event: &StorageEvent) {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        // most shards won't have anyone interested, so check before decoding anything
+        let (mut whole_tree, mut watched) = (false, false);
+        for subscription in subscriptions.values() {
+            if subscription.tree == event.tree {
+                match (
+                    &subscription.watch,
+                    &subscription.prefix,
+                    &subscription.live,
+                ) {
+                    (None, None, None) => whole_tree = true,
+                    (None, Some(prefix), _) if !event.key.starts_with(prefix) => {}
+                    (None, None, Some(live)) if !live.covers(event) => {}
+                    _ => watched = true,
+                }
+            }
+        }
+        // whole tree subscriptions are only pushed new records, and a log which is rewritten, ie.
+        // redacted, isn't new
+        let whole_tree = whole_tree
+            && event.op == StorageOp::Insert
+            && (event.previous.is_none() || !is_ingress_tree(&event.tree));
+        if !whole_tree && !watched {
+            return;
+        }
+        let pushed = match &event.value {
+            Some(value) if whole_tree => match Pushed::decode(&event.tree, value) {
+                Ok(pushed) => Some(pushed),
+                Err(e) => {
+                    println!(
+                        "Failed to decode {} record for subscribers: {:?}",
+                        event.tree, e
+                    );
+                    None
+                }
+            },
+            _ => None,
+        };
+
+        let source = pushed.as_ref().and_then(Pushed::source);
+        let now = Instant::now();
+        let mut pushes = 0;
+        let mut dropped = 0;
+        let mut sampled = 0;
+        for (&(connection_id, subscription_id), subscription) in subscriptions.iter_mut() {
+            if subscription.tree != event.tree {
+                continue;
+            }
+            let payload = if let Some(live) = &mut subscription.live {
+                match live.admit(event) {
+                    Some(payload) => payload,
+                    // not covered, or held back until the first page has been queued
+                    None => continue,
+                }
+            } else {
+                match (&mut subscription.watch, &subscription.prefix, &pushed) {
+                    (Some(watch), _, _) => {
+                        if !watch.watches(&event.key) {
+                            continue;
+                        }
+                        let value = event.value.as_ref().map(|value| value.to_vec());
+                        match watch.change(&event.key, value, now) {
+                            Some(change) => proto::ResponsePayload::KeyChanged(change),
+                            // debounced, to be pushed by flush
+                            None => continue,
+                        }
+                    }
+                    (None, Some(prefix), _) => {
+                        if !event.key.starts_with(prefix) {
+                            continue;
+                        }
+                        proto::ResponsePayload::RecordChanged(proto::RecordChanged {
+                            key: event.key.to_vec(),
+                            value: event.value.as_ref().map(|value| value.to_vec()),
+                        })
+                    }
+                    (None, None, Some(pushed)) => {
+                        if let (Some(sampler), Some(source)) = (&mut subscription.sampler, &source)
+                        {
+                            let (deliver, summary) = sampler.admit(source, now);
+                            if let Some(summary) = summary {
+                                let payload = proto::ResponsePayload::IngressLogsSampled(summary);
+                                let _ = push(
+                                    &subscription.outbound,
+                                    connection_id,
+                                    subscription_id,
+                                    payload,
+                                );
+                            }
+                            if !deliver {
+                                sampled += 1;
+                                continue;
+                            }
+                        }
+                        pushed.payload()
+                    }
+                    (None, None, None) => continue,
+                }
+            };
+            let pushed = push(
+                &subscription.outbound,
+                connection_id,
+                subscription_id,
+                payload,
+            );
+            match pushed {
+                Ok(_) => pushes += 1,
+                Err(_) => dropped += 1,
+            }
+            self.note(subscription_id, subscription, &event.key, pushed);
+        }
+        drop(subscriptions);
+
+        let latency_us = event.published.elapsed().as_micros() as u64;
+        let stats = &self.stats;
+        stats.events.fetch_add(1, Ordering::Relaxed);
+        stats.pushes.fetch_add(pushes, Ordering::Relaxed);
+        stats.dropped.fetch_add(dropped, Ordering::Relaxed);
+        stats.sampled.fetch_add(sampled, Ordering::Relaxed);
+        stats
+            .total_latency_us
+            .fetch_add(latency_us, Ordering::Relaxed);
+        stats
+            .max_latency_us
+            .fetch_max(latency_us, Ordering::Relaxed);
+    }
+
+    /// Log a push of a record, if deliveries are being logged
+    fn note(
+        &self,
+        subscription_id: usize,
+        subscription: &mut Subscription,
+        key: &[u8],
+        pushed: Result<u64, u64>,
+    ) {
+        if let Some(log) = &self.deliveries {
+            let delivery = deliveries::delivery(subscription_id, &subscription.tree, key, pushed);
+            log.note(
+                subscription.outbound.session(),
+                &mut subscription.pushes,
+                delivery,
+            );
+        }
+    }
+
+    /// Push the summaries of sampling windows which have ended, and the changes to watched keys
+    /// whose debounce windows have, and drop live queries which have been cancelled
+    fn flush(&self, now: Instant) {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        subscriptions.retain(|_, subscription| {
+            !(subscription.live.as_ref()).is_some_and(LiveQuery::cancelled)
+        });
+        for (&(connection_id, subscription_id), subscription) in subscriptions.iter_mut() {
+            let payloads: Vec<_> = match (&mut subscription.sampler, &mut subscription.watch) {
+                (Some(sampler), _) => sampler
+                    .expire(now)
+                    .into_iter()
+                    .map(proto::ResponsePayload::IngressLogsSampled)
+                    .collect(),
+                (None, Some(watch)) => watch
+                    .expire(now)
+                    .into_iter()
+                    .map(proto::ResponsePayload::KeyChanged)
+                    .collect(),
+                (None, None) => continue,
+            };
+            for payload in payloads {
+                let key = match &payload {
+                    proto::ResponsePayload::KeyChanged(change) => Some(change.key.clone()),
+                    _ => None,
+                };
+                let pushed = push(
+                    &subscription.outbound,
+                    connection_id,
+                    subscription_id,
+                    payload,
+                );
+                // sampling summaries aren't about any one record, so aren't logged
+                if let Some(key) = key {
+                    self.note(subscription_id, subscription, &key, pushed);
+                }
+            }
+        }
+    }
+}
+
+/// Forward storage events to subscribers for as long as the server runs, with a task per shard
+pub fn spawn_broker(state: AppState) {
+    for index in 0..state.subscriptions.shards.len() {
+        let mut events = state.storage.subscribe();
+        let state = state.clone();
+        tokio::spawn(async move {
+            let shard = &state.subscriptions.shards[index];
+            let mut flush = tokio::time::interval(FLUSH_EVERY);
+            flush.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    received = events.recv() => match received {
+                        Ok(event) => shard.notify(&event),
+                        Err(RecvError::Lagged(missed)) => {
+                            println!(
+                                "Subscription broker shard {} lagged, {} events were not pushed",
+                                index, missed
+                            )
+                        }
+                        Err(RecvError::Closed) => break,
+                    },
+                    _ = flush.tick() => {
+                        shard.flush(Instant::now());
+                        if let Some(log) = &shard.deliveries {
+                            if let Err(e) = log.write(&state.storage) {
+                                println!("Failed to write deliveries: {:?}", e);
+                            }
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::subscription::sampling::SAMPLE_WINDOW;
+
+    fn ingress_event() -> StorageEvent {
+        let log = proto::IngressLog {
+            host: "localhost".to_string(),
+            ..crate::test_util::ingress_log("ingress", b"")
+        };
+        StorageEvent {
+            tree: "ingress".to_string(),
+            key: "test|1".into(),
+            op: StorageOp::Insert,
+            value: Some(bincode::serialize(&log).unwrap().into()),
+            previous: None,
+            sequence: 1,
+            published: std::time::Instant::now(),
+        }
+    }
+
+    fn request(tree: &str, sample_above: Option<u32>) -> proto::SubscribeRequest {
+        proto::SubscribeRequest {
+            tree: tree.to_string(),
+            sample_above,
+            keys: vec![],
+            debounce_ms: None,
+            prefix: None,
+        }
+    }
+
+    #[test]
+    fn test_subscription_push() {
+        let registry = SubscriptionRegistry::new();
+        let (outbound, mut receiver) = crate::outbound::channel(4);
+        let connection_id = registry.next_connection_id();
+
+        assert!(registry
+            .subscribe(connection_id, 1, request("other", None), outbound.clone())
+            .is_err());
+        registry
+            .subscribe(connection_id, 7, request("ingress", None), outbound)
+            .unwrap();
+
+        registry.notify(&ingress_event());
+        match receiver.try_recv().unwrap() {
+            proto::Message::Response(proto::Response {
+                request_id: 7,
+                payload: proto::ResponsePayload::IngressLogAppended(_),
+                ..
+            }) => {}
+            _ => panic!("expected an IngressLogAppended push"),
+        }
+
+        // nothing is pushed once the connection has gone away
+        registry.remove_connection(connection_id);
+        registry.notify(&ingress_event());
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_delivery_log() {
+        let storage = crate::storage::StorageEngine::new_test().unwrap();
+        let registry = SubscriptionRegistry::with_shards(1).log_deliveries();
+        assert!(registry.logs_deliveries());
+        let (outbound, _receiver) = crate::outbound::channel(2);
+        let session = outbound.session();
+        registry
+            .subscribe(0, 7, request("ingress", None), outbound)
+            .unwrap();
+        let log = registry.shards[0].deliveries.as_ref().unwrap();
+
+        // the third push doesn't fit, but is logged all the same
+        let events: Vec<_> = (0..3).map(|_| ingress_event()).collect();
+        for event in &events {
+            registry.notify(event);
+        }
+        log.write(&storage).unwrap();
+        let replayed = deliveries::replay(&storage, session, None).unwrap();
+        let summary: Vec<_> = replayed
+            .iter()
+            .map(|delivery| (delivery.sequence, delivery.dropped, delivery.key.clone()))
+            .collect();
+        let keys: Vec<_> = events.iter().map(|event| event.key.to_vec()).collect();
+        assert_eq!(
+            summary,
+            vec![
+                (0, false, keys[0].clone()),
+                (1, false, keys[1].clone()),
+                (2, true, keys[2].clone()),
+            ]
+        );
+        assert_eq!(replayed[0].tree, "ingress");
+        assert!(deliveries::replay(&storage, session, Some(8))
+            .unwrap()
+            .is_empty());
+        assert!(deliveries::replay(&storage, ulid::Ulid::new(), None)
+            .unwrap()
+            .is_empty());
+
+        // only the most recent deliveries are kept
+        for _ in 0..deliveries::MAX_PER_SUBSCRIPTION {
+            registry.notify(&ingress_event());
+        }
+        log.write(&storage).unwrap();
+        let replayed = deliveries::replay(&storage, session, Some(7)).unwrap();
+        assert_eq!(replayed.len() as u64, deliveries::MAX_PER_SUBSCRIPTION);
+        assert_eq!(replayed[0].sequence, 3);
+
+        let future = std::time::SystemTime::now() + Duration::from_secs(1);
+        let removed = deliveries::prune(&storage, future).unwrap();
+        assert_eq!(removed as u64, deliveries::MAX_PER_SUBSCRIPTION);
+    }
+
+    #[test]
+    fn test_sharded_fanout() {
+        let registry = SubscriptionRegistry::with_shards(3);
+        let mut receivers = Vec::new();
+        for _ in 0..6 {
+            let (outbound, receiver) = crate::outbound::channel(1);
+            let connection_id = registry.next_connection_id();
+            registry
+                .subscribe(connection_id, 1, request("ingress", None), outbound)
+                .unwrap();
+            receivers.push(receiver);
+        }
+
+        // every subscriber gets the first push, whichever shard it's in
+        registry.notify(&ingress_event());
+        for receiver in receivers.iter_mut() {
+            assert!(receiver.try_recv().is_ok());
+        }
+
+        // nobody drains their queue this time, so the third push is dropped for everyone
+        registry.notify(&ingress_event());
+        registry.notify(&ingress_event());
+
+        let stats = registry.stats();
+        assert_eq!(stats.len(), 3);
+        for shard in &stats {
+            assert_eq!(shard.subscriptions, 2);
+            assert_eq!(shard.events, 3);
+            assert_eq!(shard.pushes, 4);
+            assert_eq!(shard.dropped, 2);
+        }
+    }
+
+    #[test]
+    fn test_sampled_subscription() {
+        let registry = SubscriptionRegistry::with_shards(1);
+        let (outbound, mut receiver) = crate::outbound::channel(32);
+        let (full_outbound, mut full_receiver) = crate::outbound::channel(32);
+        registry
+            .subscribe(0, 1, request("ingress", Some(1)), outbound)
+            .unwrap();
+        registry
+            .subscribe(0, 2, request("ingress", None), full_outbound)
+            .unwrap();
+
+        for _ in 0..12 {
+            registry.notify(&ingress_event());
+        }
+        registry.flush(Instant::now() + SAMPLE_WINDOW);
+
+        let mut appended = 0;
+        let mut summaries = Vec::new();
+        while let Ok(message) = receiver.try_recv() {
+            match message {
+                proto::Message::Response(proto::Response {
+                    payload: proto::ResponsePayload::IngressLogAppended(_),
+                    ..
+                }) => appended += 1,
+                proto::Message::Response(proto::Response {
+                    payload: proto::ResponsePayload::IngressLogsSampled(summary),
+                    ..
+                }) => summaries.push(summary),
+                _ => panic!("unexpected push"),
+            }
+        }
+        // the first push, then one in ten, and the rest are accounted for in the summary
+        assert_eq!(appended, 2);
+        assert_eq!(
+            summaries,
+            vec![proto::IngressLogsSampled {
+                source: "localhost".to_string(),
+                delivered: 2,
+                skipped: 10,
+            }]
+        );
+        assert_eq!(registry.stats()[0].sampled, 10);
+
+        // the subscription which didn't ask for sampling got everything
+        let mut full = 0;
+        while full_receiver.try_recv().is_ok() {
+            full += 1;
+        }
+        assert_eq!(full, 12);
+    }
+
+    #[test]
+    fn test_key_watch() {
+        let registry = SubscriptionRegistry::with_shards(1);
+        let (outbound, mut receiver) = crate::outbound::channel(32);
+        let watch = |debounce_ms| proto::SubscribeRequest {
+            keys: vec![b"t\0hot".to_vec()],
+            debounce_ms,
+            ..request("kv", None)
+        };
+        // only specific keys can be watched on trees which can't be subscribed to whole
+        assert!(registry
+            .subscribe(0, 1, request("kv", None), outbound.clone())
+            .is_err());
+        registry
+            .subscribe(0, 1, watch(Some(100)), outbound.clone())
+            .unwrap();
+        registry.subscribe(0, 2, watch(None), outbound).unwrap();
+
+        let event = |key: &str, value: Option<u8>| StorageEvent {
+            tree: "kv".to_string(),
+            key: key.into(),
+            op: match value {
+                Some(_) => StorageOp::Insert,
+                None => StorageOp::Remove,
+            },
+            value: value.map(|value| vec![value].into()),
+            previous: None,
+            sequence: 1,
+            published: Instant::now(),
+        };
+        for value in 0..3 {
+            registry.notify(&event("t\0hot", Some(value)));
+        }
+        registry.notify(&event("t\0cold", Some(9)));
+        registry.notify(&event("t\0hot", None));
+        registry.flush(Instant::now() + Duration::from_millis(100));
+
+        let mut pushes = Vec::new();
+        while let Ok(proto::Message::Response(response)) = receiver.try_recv() {
+            match response.payload {
+                proto::ResponsePayload::KeyChanged(change) => {
+                    pushes.push((response.request_id, change.value, change.changes))
+                }
+                _ => panic!("unexpected push"),
+            }
+        }
+        // every change for the undebounced watch, then a single one for the debounced one
+        assert_eq!(
+            pushes,
+            vec![
+                (2, Some(vec![0]), 1),
+                (2, Some(vec![1]), 1),
+                (2, Some(vec![2]), 1),
+                (2, None, 1),
+                (1, None, 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_prefix_subscription() {
+        let registry = SubscriptionRegistry::with_shards(1);
+        let (outbound, mut receiver) = crate::outbound::channel(32);
+        let prefixed = |prefix: &[u8]| proto::SubscribeRequest {
+            prefix: Some(prefix.to_vec()),
+            ..request("records/notes", None)
+        };
+        // a collection's tree can be subscribed to by prefix, but not whole or sampled, and
+        // trees outside the allowlist can't be either way
+        assert!(registry
+            .subscribe(0, 1, request("records/notes", None), outbound.clone())
+            .is_err());
+        for tree in ["kv", "meta"] {
+            let err = registry
+                .subscribe(
+                    0,
+                    1,
+                    proto::SubscribeRequest {
+                        prefix: Some(b"tenant\0".to_vec()),
+                        ..request(tree, None)
+                    },
+                    outbound.clone(),
+                )
+                .unwrap_err();
+            assert!(err.to_string().contains("not supported"), "{}", err);
+        }
+        assert!(registry
+            .subscribe(
+                0,
+                1,
+                proto::SubscribeRequest {
+                    sample_above: Some(1),
+                    ..prefixed(b"a")
+                },
+                outbound.clone()
+            )
+            .is_err());
+        registry
+            .subscribe(0, 1, prefixed(b"2024-"), outbound.clone())
+            .unwrap();
+
+        let event = |tree: &str, key: &str, value: Option<u8>| StorageEvent {
+            tree: tree.to_string(),
+            key: key.into(),
+            op: match value {
+                Some(_) => StorageOp::Insert,
+                None => StorageOp::Remove,
+            },
+            value: value.map(|value| vec![value].into()),
+            previous: None,
+            sequence: 1,
+            published: Instant::now(),
+        };
+        registry.notify(&event("records/notes", "2024-06", Some(1)));
+        registry.notify(&event("records/notes", "2023-12", Some(2)));
+        registry.notify(&event("records/other", "2024-06", Some(3)));
+        registry.notify(&event("records/notes", "2024-06", None));
+        let mut pushes = Vec::new();
+        while let Ok(proto::Message::Response(response)) = receiver.try_recv() {
+            match response.payload {
+                proto::ResponsePayload::RecordChanged(change) => pushes.push(change),
+                _ => panic!("unexpected push"),
+            }
+        }
+        assert_eq!(
+            pushes,
+            vec![
+                proto::RecordChanged {
+                    key: b"2024-06".to_vec(),
+                    value: Some(vec![1]),
+                },
+                proto::RecordChanged {
+                    key: b"2024-06".to_vec(),
+                    value: None,
+                },
+            ]
+        );
+
+        // a connection can only hold so many, though resubscribing with the same id replaces one
+        for id in 2..=MAX_SUBSCRIPTIONS_PER_CONNECTION {
+            registry
+                .subscribe(0, id, prefixed(b""), outbound.clone())
+                .unwrap();
+        }
+        let err = registry
+            .subscribe(0, 999, prefixed(b""), outbound.clone())
+            .unwrap_err();
+        assert!(err.to_string().contains("at most"), "{}", err);
+        registry
+            .subscribe(0, 1, prefixed(b"2025-"), outbound.clone())
+            .unwrap();
+        // others' subscriptions don't count against it
+        registry
+            .subscribe(1, 1, prefixed(b""), outbound.clone())
+            .unwrap();
+        assert!(registry.unsubscribe(0, 2));
+        registry.subscribe(0, 999, prefixed(b""), outbound).unwrap();
+
+        // and they're all dropped when it disconnects
+        registry.remove_connection(0);
+        assert_eq!(registry.stats()[0].subscriptions, 1);
+        registry.notify(&event("records/notes", "2025-01", Some(4)));
+        // only the other connection's subscription is left to push to
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok(proto::Message::Response(proto::Response {
+                request_id: 1,
+                ..
+            }))
+        ));
+        assert!(receiver.try_recv().is_err());
+    }
+}
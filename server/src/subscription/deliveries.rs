@@ -0,0 +1,162 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use hydra_proto as proto;
+use ulid::Ulid;
+
+use crate::{
+    spill::{SpillBuffer, SPILL_ABOVE},
+    storage::StorageEngine,
+    AppState,
+};
+
+/// Where every session's deliveries are kept
+pub const DELIVERIES_TREE: &str = "deliveries";
+
+/// The most deliveries kept for any one subscription, the oldest being removed first
+pub const MAX_PER_SUBSCRIPTION: u64 = 1000;
+
+/// How often deliveries which are too old to keep are looked for
+const PRUNE_EVERY: Duration = Duration::from_secs(60);
+
+/// The session, then the subscription id, then the number of pushes made to the subscription
+/// before this one, so that a subscription's oldest delivery is easy to find and remove
+fn delivery_key(session: Ulid, subscription_id: usize, count: u64) -> Vec<u8> {
+    let mut key = Vec::with_capacity(32);
+    key.extend_from_slice(&session.to_bytes());
+    key.extend_from_slice(&(subscription_id as u64).to_be_bytes());
+    key.extend_from_slice(&count.to_be_bytes());
+    key
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// A shard's deliveries which have yet to be written out. They're written in a batch on every
+/// flush of the shard, rather than one by one while the subscriptions are locked.
+#[derive(Default)]
+pub struct DeliveryLog {
+    // None until something's noted, so idle shards don't write empty batches
+    pending: Mutex<Option<sled::Batch>>,
+}
+
+impl DeliveryLog {
+    /// Note a push made to a subscription, `count` being the number it has had before
+    pub fn note(&self, session: Ulid, count: &mut u64, delivery: proto::Delivery) {
+        let key = delivery_key(session, delivery.subscription_id, *count);
+        let value = bincode::serialize(&delivery).expect("deliveries can be serialized");
+        let mut pending = self.pending.lock().unwrap();
+        let pending = pending.get_or_insert_with(sled::Batch::default);
+        pending.insert(key, value);
+        if let Some(oldest) = count.checked_sub(MAX_PER_SUBSCRIPTION) {
+            pending.remove(delivery_key(session, delivery.subscription_id, oldest));
+        }
+        *count += 1;
+    }
+
+    pub fn write(&self, storage: &StorageEngine) -> Result<()> {
+        let Some(batch) = self.pending.lock().unwrap().take() else {
+            return Ok(());
+        };
+        // written straight to the tree, as nothing subscribes to deliveries and recording them
+        // mustn't create more to record
+        storage.subtree(DELIVERIES_TREE)?.apply_batch(batch)?;
+        Ok(())
+    }
+}
+
+/// What was pushed to a session's subscriptions, or just one of them, in the order it was pushed
+pub fn replay(
+    storage: &StorageEngine,
+    session: Ulid,
+    subscription_id: Option<usize>,
+) -> Result<Vec<proto::Delivery>> {
+    let mut prefix = session.to_bytes().to_vec();
+    if let Some(id) = subscription_id {
+        prefix.extend_from_slice(&(id as u64).to_be_bytes());
+    }
+    // stored by subscription, so sorted into the order they were pushed on the way out
+    let mut sorted = SpillBuffer::new(storage, SPILL_ABOVE);
+    for value in storage
+        .subtree(DELIVERIES_TREE)?
+        .scan_prefix(prefix)
+        .values()
+    {
+        let delivery: proto::Delivery = bincode::deserialize(&value?)?;
+        sorted.push(delivery.sequence.to_be_bytes().to_vec(), &delivery)?;
+    }
+    sorted
+        .into_items()?
+        .map(|item| item.map(|(_, delivery)| delivery))
+        .collect()
+}
+
+/// Remove deliveries pushed before `cutoff`, returning how many were removed
+pub fn prune(storage: &StorageEngine, cutoff: SystemTime) -> Result<usize> {
+    let cutoff = unix_millis(cutoff);
+    let tree = storage.subtree(DELIVERIES_TREE)?;
+    let mut batch = sled::Batch::default();
+    let mut removed = 0;
+    for entry in tree.iter() {
+        let (key, value) = entry?;
+        let delivery: proto::Delivery = bincode::deserialize(&value)?;
+        if delivery.pushed_at < cutoff {
+            batch.remove(key);
+            removed += 1;
+        }
+    }
+    tree.apply_batch(batch)?;
+    Ok(removed)
+}
+
+/// Remove deliveries once they're older than `retain_for`, for as long as the server runs
+pub fn spawn_pruning(state: AppState, retain_for: Duration) {
+    println!("Logging subscription deliveries for {:?}", retain_for);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PRUNE_EVERY);
+        loop {
+            interval.tick().await;
+            let pass_state = state.clone();
+            // sled calls block, so keep them off the async runtime
+            let result = tokio::task::spawn_blocking(move || {
+                let cutoff = SystemTime::now()
+                    .checked_sub(retain_for)
+                    .unwrap_or(UNIX_EPOCH);
+                prune(&pass_state.storage, cutoff)
+            })
+            .await;
+            match result {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => println!("Failed to prune deliveries: {:?}", e),
+                Err(e) => println!("Pruning deliveries panicked: {:?}", e),
+            }
+        }
+    });
+}
+
+/// A delivery of a record pushed just now
+pub fn delivery(
+    subscription_id: usize,
+    tree: &str,
+    key: &[u8],
+    pushed: Result<u64, u64>,
+) -> proto::Delivery {
+    let (sequence, dropped) = match pushed {
+        Ok(sequence) => (sequence, false),
+        Err(sequence) => (sequence, true),
+    };
+    proto::Delivery {
+        subscription_id,
+        sequence,
+        tree: tree.to_string(),
+        key: key.to_vec(),
+        pushed_at: unix_millis(SystemTime::now()),
+        dropped,
+    }
+}
@@ -0,0 +1,109 @@
+use std::ops::{Bound, RangeBounds};
+
+use hydra_proto::{self as proto, IngressLog};
+
+use crate::{
+    cancel::CancelToken,
+    storage::{versioned, StorageEvent, StorageOp},
+};
+
+/// The most writes held back while a live query's first page is read and sent. Any more are
+/// dropped, as pushes to a client which isn't keeping up are.
+pub const MAX_HELD: usize = 1000;
+
+/// Keys from one bound to another
+pub type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+/// Turns a log into the item pushed for it, as the first page's items were made
+pub type ItemBuilder =
+    Box<dyn Fn(Vec<u8>, IngressLog) -> anyhow::Result<proto::IngressLogItem> + Send>;
+
+/// State for a subscription made by a LiveQuery. The subscription is made before the first page
+/// is read, and writes are held back until the page has been queued, when those the page
+/// already covers are dropped, so the client sees every write after the page, and only after it.
+pub struct LiveQuery {
+    // every one of these contains the key of a log the query covers
+    ranges: Vec<KeyRange>,
+    filter: proto::IngressLogFilter,
+    // the sequence of the snapshot the page was read at, once it's been queued
+    snapshot: u64,
+    item: ItemBuilder,
+    // the LiveQuery request's, which the client cancels to end it
+    cancel: CancelToken,
+    // None once the first page has been queued
+    held: Option<Vec<StorageEvent>>,
+}
+
+impl LiveQuery {
+    pub fn new(
+        ranges: Vec<KeyRange>,
+        filter: proto::IngressLogFilter,
+        item: ItemBuilder,
+        cancel: CancelToken,
+    ) -> Self {
+        Self {
+            ranges,
+            filter,
+            snapshot: 0,
+            item,
+            cancel,
+            held: Some(Vec::new()),
+        }
+    }
+
+    pub fn cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    /// Whether a write could be one to push, without decoding it
+    pub fn covers(&self, event: &StorageEvent) -> bool {
+        event.op == StorageOp::Insert
+            && event.sequence > self.snapshot
+            && !self.cancelled()
+            && self
+                .ranges
+                .iter()
+                .all(|range| range.contains(&event.key.to_vec()))
+    }
+
+    /// Note a write, returning the push to make for it now, if it isn't held back
+    pub fn admit(&mut self, event: &StorageEvent) -> Option<proto::ResponsePayload> {
+        if !self.covers(event) {
+            return None;
+        }
+        if let Some(held) = &mut self.held {
+            if held.len() < MAX_HELD {
+                held.push(event.clone());
+            }
+            return None;
+        }
+        self.push(event)
+    }
+
+    /// The pushes for the writes held back which the first page, read at `snapshot`, doesn't
+    /// cover, now it's been queued
+    pub fn release(&mut self, snapshot: u64) -> Vec<proto::ResponsePayload> {
+        self.snapshot = snapshot;
+        let held = self.held.take().unwrap_or_default();
+        (held.iter())
+            .filter(|event| self.covers(event))
+            .filter_map(|event| self.push(event))
+            .collect()
+    }
+
+    fn push(&self, event: &StorageEvent) -> Option<proto::ResponsePayload> {
+        let log: IngressLog = versioned::decode(event.value.as_deref()?)
+            .map_err(|e| println!("Failed to decode log for a live query: {:?}", e))
+            .ok()?;
+        if !self.filter.matches(&log) {
+            return None;
+        }
+        match (self.item)(event.key.to_vec(), log) {
+            Ok(item) => Some(proto::ResponsePayload::LiveQueryItem(item)),
+            Err(e) => {
+                println!("Failed to make a live query item: {:?}", e);
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use hydra_proto as proto;
+
+/// How long a source's rate is measured over
+pub const SAMPLE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Once a source is over its threshold, one in this many of its further pushes is still delivered
+const SAMPLE_EVERY: u64 = 10;
+
+/// Who an ingress log is attributed to for sampling: the client's address if it was recorded,
+/// otherwise the host it was sent to
+pub fn source_of(log: &proto::IngressLog) -> String {
+    match log.remote_addr {
+        Some(addr) => addr.ip().to_string(),
+        None => log.host.clone(),
+    }
+}
+
+/// Smart-tail state for a subscription which asked for sampling. Each source is counted over
+/// windows of SAMPLE_WINDOW, and once it goes over the threshold within one only every
+/// SAMPLE_EVERY'th push is delivered until the window ends. A summary of what was skipped is
+/// pushed for each window in which anything was.
+pub struct Sampler {
+    threshold: u64,
+    sources: HashMap<String, SourceWindow>,
+}
+
+struct SourceWindow {
+    started: Instant,
+    seen: u64,
+    delivered: u64,
+}
+
+impl SourceWindow {
+    fn new(started: Instant) -> Self {
+        Self {
+            started,
+            seen: 0,
+            delivered: 0,
+        }
+    }
+
+    fn summary(&self, source: &str) -> Option<proto::IngressLogsSampled> {
+        let skipped = self.seen - self.delivered;
+        (skipped > 0).then(|| proto::IngressLogsSampled {
+            source: source.to_string(),
+            delivered: self.delivered,
+            skipped,
+        })
+    }
+}
+
+impl Sampler {
+    pub fn new(threshold: u32) -> Self {
+        Self {
+            threshold: threshold as u64,
+            sources: HashMap::new(),
+        }
+    }
+
+    /// Count a push for a source. Returns whether to deliver it, along with the summary of the
+    /// source's previous window if that one has just ended and had pushes skipped.
+    pub fn admit(
+        &mut self,
+        source: &str,
+        now: Instant,
+    ) -> (bool, Option<proto::IngressLogsSampled>) {
+        let mut summary = None;
+        let window = match self.sources.get_mut(source) {
+            Some(window) => {
+                if now.duration_since(window.started) >= SAMPLE_WINDOW {
+                    summary = window.summary(source);
+                    *window = SourceWindow::new(now);
+                }
+                window
+            }
+            None => self
+                .sources
+                .entry(source.to_string())
+                .or_insert_with(|| SourceWindow::new(now)),
+        };
+
+        window.seen += 1;
+        let deliver = window.seen <= self.threshold
+            || (window.seen - self.threshold).is_multiple_of(SAMPLE_EVERY);
+        if deliver {
+            window.delivered += 1;
+        }
+        (deliver, summary)
+    }
+
+    /// Summaries for every window which has ended, forgetting those sources until they're next
+    /// seen. Called periodically so a burst is summarised even if its source then goes quiet.
+    pub fn expire(&mut self, now: Instant) -> Vec<proto::IngressLogsSampled> {
+        let mut summaries = Vec::new();
+        self.sources.retain(|source, window| {
+            if now.duration_since(window.started) < SAMPLE_WINDOW {
+                return true;
+            }
+            summaries.extend(window.summary(source));
+            false
+        });
+        summaries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sampling_busy_source() {
+        let mut sampler = Sampler::new(3);
+        let start = Instant::now();
+
+        let mut delivered = 0;
+        for _ in 0..30 {
+            let (deliver, summary) = sampler.admit("203.0.113.7", start);
+            assert!(summary.is_none());
+            delivered += deliver as u64;
+        }
+        // the first three, then one in ten of the remaining 27
+        assert_eq!(delivered, 5);
+
+        // a quiet source is delivered in full, and never summarised
+        for _ in 0..3 {
+            assert!(sampler.admit("198.51.100.1", start).0);
+        }
+
+        let later = start + SAMPLE_WINDOW;
+        assert_eq!(
+            sampler.expire(later),
+            vec![proto::IngressLogsSampled {
+                source: "203.0.113.7".to_string(),
+                delivered: 5,
+                skipped: 25,
+            }]
+        );
+        assert!(sampler.expire(later).is_empty());
+
+        // a source which stays busy is summarised as its next window starts
+        for _ in 0..5 {
+            sampler.admit("203.0.113.7", later);
+        }
+        let (deliver, summary) = sampler.admit("203.0.113.7", later + SAMPLE_WINDOW);
+        assert!(deliver);
+        assert_eq!(summary.map(|s| (s.delivered, s.skipped)), Some((3, 2)));
+    }
+}
@@ -0,0 +1,121 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use hydra_proto as proto;
+
+/// State for a subscription which watches particular keys. Without a debounce every change is
+/// pushed straight away. With one, the first change to a key opens a window of that length, and
+/// the key's latest value is pushed once it closes, however many changes it saw.
+pub struct KeyWatch {
+    keys: HashSet<Vec<u8>>,
+    debounce: Option<Duration>,
+    // changes waiting for their window to close, by key
+    pending: HashMap<Vec<u8>, PendingChange>,
+}
+
+struct PendingChange {
+    opened: Instant,
+    value: Option<Vec<u8>>,
+    changes: u32,
+}
+
+impl KeyWatch {
+    pub fn new(keys: Vec<Vec<u8>>, debounce: Option<Duration>) -> Self {
+        Self {
+            keys: keys.into_iter().collect(),
+            debounce: debounce.filter(|debounce| !debounce.is_zero()),
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn watches(&self, key: &[u8]) -> bool {
+        self.keys.contains(key)
+    }
+
+    /// Note a change to a watched key, returning the push to make now, if it isn't debounced
+    pub fn change(
+        &mut self,
+        key: &[u8],
+        value: Option<Vec<u8>>,
+        now: Instant,
+    ) -> Option<proto::KeyChanged> {
+        if self.debounce.is_none() {
+            return Some(proto::KeyChanged {
+                key: key.to_vec(),
+                value,
+                changes: 1,
+            });
+        }
+        let pending = self
+            .pending
+            .entry(key.to_vec())
+            .or_insert_with(|| PendingChange {
+                opened: now,
+                value: None,
+                changes: 0,
+            });
+        pending.value = value;
+        pending.changes += 1;
+        None
+    }
+
+    /// Pushes for the keys whose debounce windows have closed
+    pub fn expire(&mut self, now: Instant) -> Vec<proto::KeyChanged> {
+        let Some(debounce) = self.debounce else {
+            return Vec::new();
+        };
+        let mut pushes = Vec::new();
+        self.pending.retain(|key, pending| {
+            if now.duration_since(pending.opened) < debounce {
+                return true;
+            }
+            pushes.push(proto::KeyChanged {
+                key: key.clone(),
+                value: pending.value.take(),
+                changes: pending.changes,
+            });
+            false
+        });
+        pushes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debounce() {
+        let start = Instant::now();
+        let debounce = Duration::from_millis(100);
+        let mut watch = KeyWatch::new(vec![b"hot".to_vec(), b"cold".to_vec()], Some(debounce));
+        assert!(!watch.watches(b"other"));
+
+        for i in 0..5u8 {
+            assert!(watch.change(b"hot", Some(vec![i]), start).is_none());
+        }
+        watch.change(b"cold", None, start + Duration::from_millis(50));
+        assert!(watch.expire(start + Duration::from_millis(99)).is_empty());
+
+        // only the window opened first has closed
+        let pushes = watch.expire(start + debounce);
+        assert_eq!(
+            pushes,
+            vec![proto::KeyChanged {
+                key: b"hot".to_vec(),
+                value: Some(vec![4]),
+                changes: 5,
+            }]
+        );
+        let pushes = watch.expire(start + Duration::from_millis(150));
+        assert_eq!(pushes.len(), 1);
+        assert_eq!((pushes[0].value.clone(), pushes[0].changes), (None, 1));
+
+        // without a debounce, every change goes straight out
+        let mut watch = KeyWatch::new(vec![b"hot".to_vec()], None);
+        assert!(watch.change(b"hot", Some(vec![1]), start).is_some());
+        assert!(watch.expire(start + debounce).is_empty());
+    }
+}
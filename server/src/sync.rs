@@ -0,0 +1,292 @@
+//! Keeping this server's DAG in step with its peers', given with --sync-peer. A task per peer
+//! connects to the peer's WebSocket endpoint with hydra-client, as any other client would, and
+//! every --sync-every seconds:
+//!
+//! - advertises this node's heads with AdvertiseHeads, and gets the peer's back
+//! - asks for the events behind any of the peer's heads it hasn't seen with RequestMissing, a
+//!   batch at a time, until it has them all
+//! - sends the peer the events behind its own heads which the peer hasn't seen with SendEvents
+//!
+//! Peers only accept SendEvents from connections which carry their admin token, see admin.rs, so
+//! servers which sync share one, and present it to each other when connecting.
+//!
+//! Both then have the same events and the same heads, until either adds another. Each side
+//! advertises a locator along with its heads, see Dag::locator, so the other can tell where
+//! their histories meet and only sends what's new, give or take a few events. Events are added
+//! to the events tree as they arrive, so what's been pulled survives a restart, and a round cut
+//! short by a dropped connection carries on from there next time. Syncing both ways, with each
+//! server naming the other as a peer, works too, it just does the work twice.
+//!
+//! Only events move between peers, not the logs they cover or any record, and none carries a
+//! proto::dag::resolve::Update. So nothing a peer sends is applied to stored state, and there's
+//! no conflict to resolve or audit. Were events to carry updates, they'd be applied here with
+//! proto::dag::resolve::Replica, and the Resolution it returns for each conflict kept.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use hydra_client::{Client, ClientConfig};
+use hydra_proto::{self as proto, dag::ID};
+
+use crate::{
+    admin, config::ServerConfig, error::AppError, handler::events::MAX_EVENTS_PER_BATCH, AppState,
+};
+
+#[derive(Clone, Debug)]
+pub struct SyncPolicy {
+    // ws:// URLs of the peers' WebSocket endpoints
+    pub peers: Vec<String>,
+    pub interval: Duration,
+    // the admin token, which the peers share
+    pub token: String,
+}
+
+impl SyncPolicy {
+    /// None unless the config gives peers to sync with. Errs if it doesn't give the admin token
+    /// to push events to them with.
+    pub fn from_config(config: &ServerConfig) -> Result<Option<Self>> {
+        if config.sync_peer.is_empty() {
+            return Ok(None);
+        }
+        let token = admin::configured_token(config)?.ok_or_else(|| {
+            anyhow!(
+                "--sync-peer needs the admin token the peers share, from --admin-token-file or {}",
+                admin::ADMIN_TOKEN_VAR
+            )
+        })?;
+        Ok(Some(Self {
+            peers: config.sync_peer.clone(),
+            interval: Duration::from_secs(config.sync_every.max(1)),
+            token,
+        }))
+    }
+}
+
+/// How many events one round moved each way
+#[derive(Debug, Default, PartialEq)]
+pub struct Synced {
+    pub pulled: usize,
+    pub pushed: usize,
+}
+
+/// Sync with every peer, every interval, for as long as the server runs
+pub fn spawn_sync(state: AppState, policy: SyncPolicy) {
+    for peer in policy.peers {
+        println!("Syncing the DAG with {} every {:?}", peer, policy.interval);
+        let (state, token) = (state.clone(), policy.token.clone());
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(policy.interval);
+            let mut client = None;
+            loop {
+                interval.tick().await;
+                if client.is_none() {
+                    let config = ClientConfig::new(&peer).bearer_token(&token);
+                    match Client::connect(config).await {
+                        Ok(connected) => client = Some(connected),
+                        Err(e) => {
+                            println!("Could not connect to sync peer {}: {}", peer, e);
+                            continue;
+                        }
+                    }
+                }
+                let Some(client) = &client else {
+                    continue;
+                };
+                match sync_with(&state, client).await {
+                    Ok(synced) if synced != Synced::default() => println!(
+                        "Synced the DAG with {}: pulled {} events, pushed {}",
+                        peer, synced.pulled, synced.pushed
+                    ),
+                    Ok(_) => {}
+                    Err(e) => println!("Failed to sync the DAG with {}: {:#}", peer, e),
+                }
+            }
+        });
+    }
+}
+
+/// One round of pulling what the peer has that this node hasn't, and then pushing what this node
+/// has that the peer hasn't
+pub async fn sync_with(state: &AppState, client: &Client) -> Result<Synced> {
+    let theirs = match request(
+        client,
+        proto::RequestPayload::AdvertiseHeads(state.dag.advertise()),
+    )
+    .await?
+    {
+        proto::ResponsePayload::AdvertiseHeads(theirs) => theirs,
+        _ => bail!("Unexpected response to AdvertiseHeads"),
+    };
+    let mut synced = Synced::default();
+
+    let want: Vec<ID> = theirs
+        .heads
+        .iter()
+        .filter(|id| !state.dag.contains(id))
+        .cloned()
+        .collect();
+    if !want.is_empty() {
+        let ours = state.dag.advertise();
+        let mut have = ours.heads;
+        have.extend(ours.locator);
+        loop {
+            let missing = proto::RequestMissing {
+                want: want.clone(),
+                have: have.clone(),
+                limit: MAX_EVENTS_PER_BATCH,
+            };
+            let events =
+                match request(client, proto::RequestPayload::RequestMissing(missing)).await? {
+                    proto::ResponsePayload::SendEvents(sent) => sent.events,
+                    _ => bail!("Unexpected response to RequestMissing"),
+                };
+            if events.is_empty() {
+                break;
+            }
+            have.extend(events.iter().map(|event| event.id.clone()));
+            synced.pulled += state
+                .dag
+                .receive(&state.storage, &state.event_ids, events)
+                .map_err(AppError::into_anyhow)?;
+        }
+    }
+
+    let ours: Vec<ID> = state.dag.heads().into_iter().collect();
+    let mut have = theirs.heads;
+    have.extend(theirs.locator);
+    loop {
+        let events = state.dag.missing(&ours, &have, MAX_EVENTS_PER_BATCH);
+        if events.is_empty() {
+            break;
+        }
+        have.extend(events.iter().map(|event| event.id.clone()));
+        match request(
+            client,
+            proto::RequestPayload::SendEvents(proto::SendEvents { events }),
+        )
+        .await?
+        {
+            proto::ResponsePayload::EventsReceived(received) => synced.pushed += received.added,
+            _ => bail!("Unexpected response to SendEvents"),
+        }
+    }
+    Ok(synced)
+}
+
+async fn request(
+    client: &Client,
+    payload: proto::RequestPayload,
+) -> Result<proto::ResponsePayload> {
+    match client.request(payload).await? {
+        proto::ResponsePayload::Error(e) => bail!("{}", e),
+        payload => Ok(payload),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::{routing::get, Router};
+    use hydra_proto::dag::Event;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::handler::events::{EventLog, EVENTS_TREE};
+
+    /// Chain `count` events on to a node's DAG, as captures would
+    fn capture(state: &AppState, count: usize) {
+        for n in 0..count {
+            state
+                .dag
+                .append(&state.event_ids, |event_id, precursors| {
+                    let event = Event::with_payload(n as i64, &event_id.to_bytes(), precursors);
+                    state.storage.insert(
+                        EVENTS_TREE,
+                        event_id.to_bytes(),
+                        bincode::serialize(&event)?,
+                    )?;
+                    Ok(event)
+                })
+                .unwrap();
+        }
+    }
+
+    /// Serve a node's WebSocket endpoint, returning its URL
+    async fn serve(state: AppState) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/ws", listener.local_addr().unwrap());
+        let app = Router::new()
+            .route("/ws", get(crate::ws_handler))
+            .with_state(state);
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap();
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn test_sync() {
+        let ours = AppState::new_test().unwrap();
+        let peer = AppState::new_test().unwrap();
+        let url = serve(peer.clone()).await;
+        let client = Client::connect(ClientConfig::new(&url).bearer_token(admin::TEST_TOKEN))
+            .await
+            .unwrap();
+
+        // events are only taken from peers with the admin token
+        let stranger = AppState::new_test().unwrap();
+        capture(&stranger, 1);
+        let without_token = Client::connect(ClientConfig::new(&url)).await.unwrap();
+        let err = sync_with(&stranger, &without_token).await.unwrap_err();
+        assert!(err.to_string().contains("admin token"), "{:#}", err);
+        assert_eq!(peer.dag.len(), 0);
+
+        // more than a batch of history, all on the peer
+        capture(&peer, MAX_EVENTS_PER_BATCH + 10);
+        let synced = sync_with(&ours, &client).await.unwrap();
+        assert_eq!(
+            synced,
+            Synced {
+                pulled: MAX_EVENTS_PER_BATCH + 10,
+                pushed: 0
+            }
+        );
+        assert_eq!(ours.dag.heads(), peer.dag.heads());
+
+        // then both carry on by themselves, and meet again
+        capture(&ours, 3);
+        capture(&peer, 2);
+        let synced = sync_with(&ours, &client).await.unwrap();
+        assert!((2..10).contains(&synced.pulled), "{:?}", synced);
+        assert!((3..10).contains(&synced.pushed), "{:?}", synced);
+        assert_eq!(ours.dag.heads(), peer.dag.heads());
+        assert_eq!(ours.dag.heads().len(), 2);
+        assert_eq!(ours.dag.len(), MAX_EVENTS_PER_BATCH + 15);
+        assert_eq!(sync_with(&ours, &client).await.unwrap(), Synced::default());
+
+        // the next capture merges them
+        capture(&ours, 1);
+        let head = ours.dag.heads();
+        assert_eq!(head.len(), 1);
+        let merge = ours.dag.missing(
+            &head.iter().cloned().collect::<Vec<_>>(),
+            &peer.dag.heads().into_iter().collect::<Vec<_>>(),
+            10,
+        );
+        assert_eq!(merge.len(), 1);
+        assert_eq!(merge[0].precursors, peer.dag.heads());
+        sync_with(&ours, &client).await.unwrap();
+        assert_eq!(peer.dag.heads(), head);
+
+        // and what's been pulled survives a restart
+        let events = ours.storage.subtree(EVENTS_TREE).unwrap().len();
+        assert_eq!(events, ours.dag.len());
+        assert_eq!(EventLog::load(&ours.storage).unwrap().heads(), head);
+    }
+}
@@ -0,0 +1,245 @@
+//! Tags on captured logs, eg. to mark a burst of related captures while triaging them.
+//!
+//! Tags are kept in the `tags` tree, an empty entry per tag on a log, keyed by the log's side key
+//! followed by a 0 and the tag, so a log's tags are found with a single prefix scan. A log's tags
+//! are removed along with it by retention.
+
+use anyhow::Result;
+use hydra_proto as proto;
+use proto::IngressLog;
+use sled::IVec;
+
+use crate::{
+    cancel::CancelToken,
+    error::AppError,
+    handler::record::side_key,
+    storage::{versioned, StorageEngine},
+};
+
+pub const TAGS_TREE: &str = "tags";
+
+/// How many logs a bulk tag reads between writing what it has changed and reporting progress
+pub const BATCH_SIZE: u64 = 500;
+
+const MAX_TAG_LEN: usize = 64;
+
+fn tag_prefix(tree: &str, key: &[u8]) -> Vec<u8> {
+    let mut prefix = side_key(tree, key);
+    prefix.push(0);
+    prefix
+}
+
+fn tag_key(tree: &str, key: &[u8], tag: &str) -> Vec<u8> {
+    let mut tag_key = tag_prefix(tree, key);
+    tag_key.extend_from_slice(tag.as_bytes());
+    tag_key
+}
+
+pub fn validate(tag: &str) -> Result<(), AppError> {
+    if tag.is_empty() || tag.len() > MAX_TAG_LEN || tag.contains('\0') {
+        return Err(AppError::invalid_field(
+            "tag",
+            format!(
+                "Tags must be between 1 and {} bytes, without NULs",
+                MAX_TAG_LEN
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// The tags on a log, sorted
+pub fn tags_of(storage: &StorageEngine, tree: &str, key: &[u8]) -> Result<Vec<String>> {
+    let prefix = tag_prefix(tree, key);
+    storage
+        .subtree(TAGS_TREE)?
+        .scan_prefix(&prefix)
+        .keys()
+        .map(|tag_key| Ok(String::from_utf8_lossy(&tag_key?[prefix.len()..]).into_owned()))
+        .collect()
+}
+
+/// Remove every tag on a log which has been removed
+pub fn forget(storage: &StorageEngine, tree: &str, key: &[u8]) -> Result<()> {
+    let tags = storage.subtree(TAGS_TREE)?;
+    let mut batch = sled::Batch::default();
+    for tag_key in tags.scan_prefix(tag_prefix(tree, key)).keys() {
+        batch.remove(tag_key?);
+    }
+    tags.apply_batch(batch)?;
+    Ok(())
+}
+
+/// Add a tag to, or remove it from, every one of a tree's `logs` which matches the request's
+/// filter, reporting progress after every BATCH_SIZE logs read. Changes are written a batch at a
+/// time, so a bulk tag which fails or is cancelled part way keeps what it had done by then.
+pub fn bulk_tag(
+    storage: &StorageEngine,
+    tree: &str,
+    logs: impl Iterator<Item = sled::Result<(IVec, IVec)>>,
+    request: &proto::TagIngressLogsRequest,
+    cancel: &CancelToken,
+    mut progress: impl FnMut(&proto::TagProgress),
+) -> Result<proto::TagProgress, AppError> {
+    validate(&request.tag)?;
+    let tags = storage.subtree(TAGS_TREE)?;
+    let mut totals = proto::TagProgress::default();
+    let mut batch = sled::Batch::default();
+    for entry in logs {
+        let (key, value) = entry?;
+        totals.scanned += 1;
+        let log: IngressLog = versioned::decode(&value)?;
+        if request.filter.matches(&log) {
+            totals.matched += 1;
+            let tag_key = tag_key(tree, &key, &request.tag);
+            // changed if the tag is to be removed and is there, or to be added and isn't
+            if tags.contains_key(&tag_key)? == request.remove {
+                totals.changed += 1;
+                match request.remove {
+                    true => batch.remove(tag_key),
+                    false => batch.insert(tag_key, &[]),
+                }
+            }
+        }
+        if totals.scanned % BATCH_SIZE == 0 {
+            cancel.check()?;
+            if !request.dry_run {
+                tags.apply_batch(std::mem::take(&mut batch))?;
+            }
+            progress(&totals);
+        }
+    }
+    if !request.dry_run {
+        tags.apply_batch(batch)?;
+    }
+    Ok(totals)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::ingress_log;
+    use std::ops::Bound;
+
+    fn log(n: u64, host: &str) -> (Vec<u8>, Vec<u8>) {
+        let log = IngressLog {
+            event_id: ulid::Ulid::from_parts(n, 0),
+            host: host.to_string(),
+            ..ingress_log("hooks", b"")
+        };
+        let key = format!("test|{}", log.event_id).into_bytes();
+        (key, bincode::serialize(&log).unwrap())
+    }
+
+    #[test]
+    fn test_bulk_tag() {
+        let storage = StorageEngine::new_test().unwrap();
+        let mut keys = Vec::new();
+        for n in 0..(BATCH_SIZE * 2 + 10) {
+            let host = if n % 2 == 0 { "github" } else { "stripe" };
+            let (key, value) = log(n, host);
+            storage.insert("ingress", &key, value).unwrap();
+            keys.push(key);
+        }
+        let ingress = storage.subtree("ingress").unwrap();
+        let mut request = proto::TagIngressLogsRequest {
+            source: None,
+            filter: proto::IngressLogFilter {
+                host: Some("GitHub".to_string()),
+                ..Default::default()
+            },
+            tag: "flaky".to_string(),
+            remove: false,
+            dry_run: true,
+        };
+        let cancel = CancelToken::new();
+
+        // a dry run counts but changes nothing
+        let mut reports = Vec::new();
+        let totals = bulk_tag(
+            &storage,
+            "ingress",
+            ingress.iter(),
+            &request,
+            &cancel,
+            |p| reports.push(p.clone()),
+        )
+        .unwrap();
+        assert_eq!(
+            totals,
+            proto::TagProgress {
+                scanned: BATCH_SIZE * 2 + 10,
+                matched: BATCH_SIZE + 5,
+                changed: BATCH_SIZE + 5,
+            }
+        );
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].scanned, BATCH_SIZE);
+        assert!(tags_of(&storage, "ingress", &keys[0]).unwrap().is_empty());
+
+        request.dry_run = false;
+        bulk_tag(
+            &storage,
+            "ingress",
+            ingress.iter(),
+            &request,
+            &cancel,
+            |_| {},
+        )
+        .unwrap();
+        assert_eq!(tags_of(&storage, "ingress", &keys[0]).unwrap(), ["flaky"]);
+        assert!(tags_of(&storage, "ingress", &keys[1]).unwrap().is_empty());
+        // tagging again changes nothing
+        let totals = bulk_tag(
+            &storage,
+            "ingress",
+            ingress.iter(),
+            &request,
+            &cancel,
+            |_| {},
+        )
+        .unwrap();
+        assert_eq!(totals.changed, 0);
+
+        // removing from a narrower range
+        request.remove = true;
+        let range = (Bound::Unbounded, Bound::Excluded(keys[4].clone()));
+        let totals = bulk_tag(
+            &storage,
+            "ingress",
+            ingress.range(range),
+            &request,
+            &cancel,
+            |_| {},
+        )
+        .unwrap();
+        assert_eq!((totals.scanned, totals.changed), (4, 2));
+        assert!(tags_of(&storage, "ingress", &keys[2]).unwrap().is_empty());
+        assert_eq!(tags_of(&storage, "ingress", &keys[4]).unwrap(), ["flaky"]);
+
+        forget(&storage, "ingress", &keys[4]).unwrap();
+        assert!(tags_of(&storage, "ingress", &keys[4]).unwrap().is_empty());
+
+        request.tag = String::new();
+        assert!(bulk_tag(
+            &storage,
+            "ingress",
+            ingress.iter(),
+            &request,
+            &cancel,
+            |_| {}
+        )
+        .is_err());
+        request.tag = "flaky".to_string();
+        cancel.cancel();
+        assert!(bulk_tag(
+            &storage,
+            "ingress",
+            ingress.iter(),
+            &request,
+            &cancel,
+            |_| {}
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,22 @@
+//! Fixtures shared by the tests of several modules
+
+use bytes::Bytes;
+use hydra_proto::IngressLog;
+use ulid::Ulid;
+
+/// A log captured just now, of a POST to /ingress/<path> with `body` and nothing more. Tests set
+/// whatever else they need with struct update syntax.
+pub fn ingress_log(path: &str, body: &[u8]) -> IngressLog {
+    IngressLog {
+        event_id: Ulid::new(),
+        date: chrono::Utc::now(),
+        remote_addr: None,
+        method: "POST".to_string(),
+        host: "example.com".to_string(),
+        path: path.to_string(),
+        query: Default::default(),
+        headers: Default::default(),
+        body: Bytes::copy_from_slice(body),
+        trace_id: None,
+    }
+}
@@ -0,0 +1,269 @@
+//! Thumbnails of captured images, for list views, so that a screenshot or avatar upload can be
+//! recognised without fetching its whole body.
+//!
+//! PNGs, JPEGs and the first frame of GIFs are decoded with the image crate, scaled down to fit
+//! within THUMBNAIL_SIZE and sent as PNGs. Logs never change once captured, so a log's thumbnail
+//! is made once and kept in the `thumbnails` tree under its side key, including when it has none,
+//! and is removed along with it by retention.
+
+use std::io::Cursor;
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use hydra_proto as proto;
+use image::{ImageFormat, ImageReader, Limits, Rgba, RgbaImage};
+use proto::IngressLog;
+
+use crate::{handler::record::side_key, storage::StorageEngine};
+
+pub const THUMBNAILS_TREE: &str = "thumbnails";
+
+/// Thumbnails fit within a square this many pixels wide
+pub const THUMBNAIL_SIZE: u32 = 128;
+
+/// Images with more pixels than this aren't decoded, so that a small body which decompresses to
+/// an enormous image can't exhaust memory
+const MAX_PIXELS: u64 = 25_000_000;
+
+/// Scale down to fit within a square `size` pixels wide, averaging the pixels each one covers.
+/// Colours are weighted by their alpha, so transparent pixels don't darken the edges.
+fn fit_within(image: &RgbaImage, size: u32) -> RgbaImage {
+    let (source_width, source_height) = image.dimensions();
+    if source_width <= size && source_height <= size {
+        return image.clone();
+    }
+    let longest = source_width.max(source_height);
+    let width = (source_width * size / longest).max(1);
+    let height = (source_height * size / longest).max(1);
+    RgbaImage::from_fn(width, height, |x, y| {
+        let (top, bottom) = (y * source_height / height, (y + 1) * source_height / height);
+        let (left, right) = (x * source_width / width, (x + 1) * source_width / width);
+        let (bottom, right) = (bottom.max(top + 1), right.max(left + 1));
+        let mut sums = [0u64; 4];
+        for source_y in top..bottom {
+            for source_x in left..right {
+                let pixel = image.get_pixel(source_x, source_y).0;
+                let alpha = u64::from(pixel[3]);
+                for channel in 0..3 {
+                    sums[channel] += u64::from(pixel[channel]) * alpha;
+                }
+                sums[3] += alpha;
+            }
+        }
+        let count = u64::from((bottom - top) * (right - left));
+        Rgba(match sums[3] {
+            0 => [0; 4],
+            total => [
+                (sums[0] / total) as u8,
+                (sums[1] / total) as u8,
+                (sums[2] / total) as u8,
+                (total / count) as u8,
+            ],
+        })
+    })
+}
+
+/// Decode a PNG, GIF or JPEG body as RGBA, or nothing if it's some other format. Only the first
+/// frame of an animated GIF is decoded.
+fn decode(body: &[u8]) -> Result<Option<RgbaImage>> {
+    // the body is trusted over its content type, which is often wrong or missing
+    let format = match image::guess_format(body) {
+        Ok(format @ (ImageFormat::Png | ImageFormat::Gif | ImageFormat::Jpeg)) => format,
+        _ => return Ok(None),
+    };
+    let (width, height) = ImageReader::with_format(Cursor::new(body), format).into_dimensions()?;
+    if u64::from(width) * u64::from(height) > MAX_PIXELS {
+        bail!("{}x{} image is too large to decode", width, height);
+    }
+    let mut reader = ImageReader::with_format(Cursor::new(body), format);
+    let mut limits = Limits::default();
+    // up to 16 bits for each of 4 channels, as the decoder sees it, and again as RGBA
+    limits.max_alloc = Some(MAX_PIXELS * 12);
+    reader.limits(limits);
+    Ok(Some(reader.decode()?.into_rgba8()))
+}
+
+/// A thumbnail of an image body, or nothing if it isn't an image which can be thumbnailed
+pub fn thumbnail(body: &[u8]) -> Result<Option<proto::Thumbnail>> {
+    let Some(image) = decode(body)? else {
+        return Ok(None);
+    };
+    let thumbnail = fit_within(&image, THUMBNAIL_SIZE);
+    let mut data = Vec::new();
+    thumbnail.write_to(&mut Cursor::new(&mut data), ImageFormat::Png)?;
+    Ok(Some(proto::Thumbnail {
+        content_type: "image/png".to_string(),
+        width: thumbnail.width(),
+        height: thumbnail.height(),
+        data: Bytes::from(data),
+    }))
+}
+
+/// The thumbnail of a log with an image body, made the first time it's asked for
+pub fn thumbnail_of(
+    storage: &StorageEngine,
+    tree: &str,
+    key: &[u8],
+    log: &IngressLog,
+) -> Result<Option<proto::Thumbnail>> {
+    let is_image = log.headers.iter().any(|(name, value)| {
+        name.eq_ignore_ascii_case("content-type")
+            && value
+                .trim_start()
+                .to_ascii_lowercase()
+                .starts_with("image/")
+    });
+    if !is_image {
+        return Ok(None);
+    }
+    let side_key = side_key(tree, key);
+    if let Some(cached) = storage.get(THUMBNAILS_TREE, &side_key)? {
+        return Ok(bincode::deserialize(&cached)?);
+    }
+    // an image which can't be decoded has no thumbnail, and isn't tried again
+    let thumbnail = thumbnail(&log.body).unwrap_or_else(|e| {
+        println!("No thumbnail for {}: {}", log.event_id, e);
+        None
+    });
+    storage.insert(THUMBNAILS_TREE, side_key, bincode::serialize(&thumbnail)?)?;
+    Ok(thumbnail)
+}
+
+/// Remove the thumbnail of a log which has been removed
+pub fn forget(storage: &StorageEngine, tree: &str, key: &[u8]) -> Result<()> {
+    storage.remove(THUMBNAILS_TREE, side_key(tree, key))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::ingress_log;
+
+    fn checkerboard(width: u32, height: u32) -> RgbaImage {
+        RgbaImage::from_fn(width, height, |x, y| match (x + y) % 2 {
+            0 => Rgba([255, 0, 0, 255]),
+            _ => Rgba([0, 0, 255, 0]),
+        })
+    }
+
+    fn encode(image: &RgbaImage, format: ImageFormat) -> Vec<u8> {
+        let mut data = Vec::new();
+        match format {
+            // JPEGs have no alpha channel
+            ImageFormat::Jpeg => image::DynamicImage::ImageRgba8(image.clone())
+                .into_rgb8()
+                .write_to(&mut Cursor::new(&mut data), format),
+            _ => image.write_to(&mut Cursor::new(&mut data), format),
+        }
+        .unwrap();
+        data
+    }
+
+    /// A GIF with a 2 colour global table, whose image data codes each pixel on its own,
+    /// clearing the table first
+    fn gif(width: u16, height: u16) -> Vec<u8> {
+        let mut gif = b"GIF89a".to_vec();
+        gif.extend_from_slice(&width.to_le_bytes());
+        gif.extend_from_slice(&height.to_le_bytes());
+        gif.extend_from_slice(&[0x80, 0, 0, 0, 0, 0, 255, 255, 255]);
+        gif.extend_from_slice(&[0x2c, 0, 0, 0, 0]);
+        gif.extend_from_slice(&width.to_le_bytes());
+        gif.extend_from_slice(&height.to_le_bytes());
+        gif.extend_from_slice(&[0, 2]);
+        // 3 bit codes: clear (4), then white (1), black (0), then end (5)
+        let codes = [4u32, 1, 0, 5];
+        let packed = codes
+            .iter()
+            .enumerate()
+            .fold(0u32, |packed, (n, code)| packed | code << (n * 3));
+        gif.extend_from_slice(&[2, packed as u8, (packed >> 8) as u8, 0, 0x3b]);
+        gif
+    }
+
+    #[test]
+    fn test_thumbnail() {
+        // a PNG is decoded keeping its transparency
+        let image = checkerboard(3, 2);
+        assert_eq!(
+            decode(&encode(&image, ImageFormat::Png)).unwrap(),
+            Some(image)
+        );
+
+        // large images are scaled to fit, keeping their aspect ratio, and small ones aren't
+        // scaled up
+        let thumbnail = thumbnail(&encode(&checkerboard(512, 256), ImageFormat::Png))
+            .unwrap()
+            .unwrap();
+        assert_eq!(thumbnail.content_type, "image/png");
+        assert_eq!((thumbnail.width, thumbnail.height), (128, 64));
+        let scaled = decode(&thumbnail.data).unwrap().unwrap();
+        // half the pixels covered are transparent, and don't darken the red ones
+        assert_eq!(scaled.get_pixel(0, 0).0, [255, 0, 0, 127]);
+        let small = super::thumbnail(&encode(&checkerboard(16, 8), ImageFormat::Png))
+            .unwrap()
+            .unwrap();
+        assert_eq!((small.width, small.height), (16, 8));
+
+        // JPEGs are decoded and scaled too, and sent as PNGs
+        let photo = RgbaImage::from_pixel(400, 300, Rgba([200, 100, 50, 255]));
+        let thumbnail = super::thumbnail(&encode(&photo, ImageFormat::Jpeg))
+            .unwrap()
+            .unwrap();
+        assert_eq!(thumbnail.content_type, "image/png");
+        assert_eq!((thumbnail.width, thumbnail.height), (128, 96));
+        let scaled = decode(&thumbnail.data).unwrap().unwrap();
+        let [red, green, blue, alpha] = scaled.get_pixel(64, 48).0;
+        assert!(red.abs_diff(200) < 4 && green.abs_diff(100) < 4 && blue.abs_diff(50) < 4);
+        assert_eq!(alpha, 255);
+
+        let decoded = decode(&gif(2, 1)).unwrap().unwrap();
+        assert_eq!(decoded.into_raw(), [255, 255, 255, 255, 0, 0, 0, 255]);
+
+        // bodies which aren't images, and images too large to decode, have no thumbnail
+        assert!(super::thumbnail(b"{\"not\": \"an image\"}")
+            .unwrap()
+            .is_none());
+        assert!(super::thumbnail(&gif(10_000, 10_000)).is_err());
+        assert!(super::thumbnail(b"\x89PNG\r\n\x1a\ntruncated").is_err());
+
+        // thumbnails are cached, including the lack of one
+        let storage = StorageEngine::new_test().unwrap();
+        let mut log = IngressLog {
+            headers: [("Content-Type".to_string(), "image/gif".to_string())].into(),
+            ..ingress_log("upload", &gif(2, 1))
+        };
+        let made = thumbnail_of(&storage, "ingress", b"a", &log)
+            .unwrap()
+            .unwrap();
+        assert_eq!((made.width, made.height), (2, 1));
+        log.body = Bytes::from_static(b"not a gif after all");
+        let cached = thumbnail_of(&storage, "ingress", b"a", &log)
+            .unwrap()
+            .unwrap();
+        assert_eq!(cached.data, made.data);
+        assert!(thumbnail_of(&storage, "ingress", b"b", &log)
+            .unwrap()
+            .is_none());
+        assert!(storage
+            .get(THUMBNAILS_TREE, side_key("ingress", b"b"))
+            .unwrap()
+            .is_some());
+        forget(&storage, "ingress", b"a").unwrap();
+        assert!(storage
+            .get(THUMBNAILS_TREE, side_key("ingress", b"a"))
+            .unwrap()
+            .is_none());
+
+        // logs without an image content type aren't thumbnailed, or cached
+        log.headers = [("Content-Type".to_string(), "text/plain".to_string())].into();
+        log.body = gif(2, 1).into();
+        assert!(thumbnail_of(&storage, "ingress", b"c", &log)
+            .unwrap()
+            .is_none());
+        assert!(storage
+            .get(THUMBNAILS_TREE, side_key("ingress", b"c"))
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,130 @@
+//! Trace ids, which tie together everything logged about a request. WebSocket requests carry
+//! theirs in proto::Request::trace_id, or are given one, and it comes back on their response and
+//! any error. HTTP requests carry theirs in an x-trace-id header, or are given one, and it comes
+//! back in the same header. Either way, the request is handled in a span with the id, and logs it
+//! captures keep it, so a log can be traced back to the request which stored it.
+
+use std::convert::Infallible;
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request},
+    http::{request::Parts, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use tracing::Span;
+use ulid::Ulid;
+
+pub const TRACE_HEADER: &str = "x-trace-id";
+
+/// A trace id for a request which didn't bring one. Ulid::default is the nil id, so isn't one.
+pub fn new_id() -> Ulid {
+    Ulid::new()
+}
+
+/// The trace id of an HTTP request, as assigned by `assign`
+#[derive(Clone, Copy, Debug)]
+pub struct TraceId(pub Ulid);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for TraceId {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        // routers built without the middleware, as in tests, still get one
+        Ok((parts.extensions.get().copied()).unwrap_or_else(|| TraceId(new_id())))
+    }
+}
+
+/// Middleware giving each HTTP request the trace id in its x-trace-id header, or a new one if it
+/// has none, and answering with it
+pub async fn assign(mut request: Request, next: Next) -> Response {
+    let trace_id = (request.headers().get(TRACE_HEADER))
+        .and_then(|value| value.to_str().ok()?.parse().ok())
+        .unwrap_or_else(new_id);
+    request.extensions_mut().insert(TraceId(trace_id));
+    let mut response = next.run(request).await;
+    if let Ok(value) = HeaderValue::from_str(&trace_id.to_string()) {
+        response.headers_mut().insert(TRACE_HEADER, value);
+    }
+    response
+}
+
+/// The span an HTTP request is handled in, for TraceLayer
+pub fn http_span<B>(request: &axum::http::Request<B>) -> Span {
+    let trace_id = request.extensions().get::<TraceId>().map(|id| id.0);
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        trace_id = tracing::field::debug(trace_id),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::{
+        body::Body,
+        extract::ConnectInfo,
+        http::{header, Method, StatusCode},
+        middleware, Extension, Router,
+    };
+    use hydra_proto::IngressLog;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        handler::ingress::{capture_route, INGRESS_PREFIX},
+        storage::versioned,
+        AppState,
+    };
+
+    #[tokio::test]
+    async fn test_trace_ids() {
+        let state = AppState::new_test().unwrap();
+        let app = Router::new()
+            .route("/ingress", capture_route())
+            .layer(middleware::from_fn(assign))
+            .layer(Extension(ConnectInfo(SocketAddr::from((
+                [127, 0, 0, 1],
+                0,
+            )))))
+            .with_state(state.clone());
+        let capture = |trace_id: Option<&str>| {
+            let mut request = axum::http::Request::builder()
+                .method(Method::POST)
+                .uri("/ingress")
+                .header(header::HOST, "hydra.example.com");
+            if let Some(trace_id) = trace_id {
+                request = request.header(TRACE_HEADER, trace_id);
+            }
+            app.clone().oneshot(request.body(Body::from("{}")).unwrap())
+        };
+        let trace_id = |response: &Response| -> Ulid {
+            response.headers()[TRACE_HEADER]
+                .to_str()
+                .unwrap()
+                .parse()
+                .unwrap()
+        };
+
+        // the caller's id is kept, and stored with the log
+        let sent = Ulid::new();
+        let response = capture(Some(&sent.to_string())).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(trace_id(&response), sent);
+        let logs = state.storage.subtree("ingress").unwrap();
+        let (_, stored) = logs.scan_prefix(INGRESS_PREFIX).next().unwrap().unwrap();
+        let log: IngressLog = versioned::decode(&stored).unwrap();
+        assert_eq!(log.trace_id, Some(sent));
+
+        // one which isn't a ulid is replaced, as is a missing one
+        let response = capture(Some("not a ulid")).await.unwrap();
+        assert_ne!(trace_id(&response), sent);
+        let response = capture(None).await.unwrap();
+        assert_ne!(trace_id(&response), sent);
+    }
+}
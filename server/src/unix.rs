@@ -0,0 +1,187 @@
+//! Serving the same routes over a Unix domain socket, for producers and consumers running on the
+//! same host. They skip TCP altogether, and who may connect is decided by the socket file's mode
+//! rather than by address.
+
+use std::{
+    fs,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use axum::{extract::ConnectInfo, Extension, Router};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+    service::TowerToHyperService,
+};
+use tokio::{
+    net::UnixListener,
+    task::{JoinHandle, JoinSet},
+};
+
+use crate::{appstate::AppState, shutdown::SHUTDOWN_TIMEOUT};
+
+/// The address handlers see for clients connected over the socket, which have none of their own
+pub const UNIX_PEER: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0));
+
+/// Listen on a socket at `path`, with the given file mode if there is one
+pub fn bind(path: &Path, mode: Option<u32>) -> Result<UnixListener> {
+    remove_stale(path)?;
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("Could not listen on {}", path.display()))?;
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .with_context(|| format!("Could not set the mode of {}", path.display()))?;
+    }
+    Ok(listener)
+}
+
+/// Remove a socket left behind by a server which didn't shut down cleanly, but not one which
+/// another server is still listening on, or anything which isn't a socket
+fn remove_stale(path: &Path) -> Result<()> {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    if !metadata.file_type().is_socket() {
+        bail!("{} already exists and is not a socket", path.display());
+    }
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        bail!("{} is in use by another server", path.display());
+    }
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+/// Serve `app` on the listener until the server shuts down, then remove the socket. The task
+/// finishes once the requests in flight have too, or SHUTDOWN_TIMEOUT has passed.
+pub fn spawn_listener(
+    listener: UnixListener,
+    path: PathBuf,
+    app: Router,
+    state: AppState,
+) -> JoinHandle<()> {
+    let app = app.layer(Extension(ConnectInfo(UNIX_PEER)));
+    let mut shutdown = state.shutdown.subscribe();
+    println!("Listening on {}", path.display());
+    tokio::spawn(async move {
+        let mut connections = JoinSet::new();
+        loop {
+            // forget connections which have finished
+            while connections.try_join_next().is_some() {}
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = shutdown.wait_for(|shutdown| *shutdown) => break,
+            };
+            let stream = match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    println!("Failed to accept on {}: {:?}", path.display(), e);
+                    // eg. out of file descriptors, which won't be fixed by trying again at once
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let service = TowerToHyperService::new(app.clone());
+            let mut shutdown = state.shutdown.subscribe();
+            connections.spawn(async move {
+                let builder = Builder::new(TokioExecutor::new());
+                let connection =
+                    builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+                tokio::pin!(connection);
+                let result = tokio::select! {
+                    result = connection.as_mut() => result,
+                    _ = async { drop(shutdown.wait_for(|shutdown| *shutdown).await) } => {
+                        // finish the request in flight, then close
+                        connection.as_mut().graceful_shutdown();
+                        connection.await
+                    }
+                };
+                if let Err(e) = result {
+                    println!("Unix socket connection failed: {:?}", e);
+                }
+            });
+        }
+        // stop accepting first, so nobody connects to a server which is going away
+        if let Err(e) = fs::remove_file(&path) {
+            println!("Could not remove {}: {:?}", path.display(), e);
+        }
+        // upgraded WebSocket connections aren't counted here, they close themselves
+        let finished = async { while connections.join_next().await.is_some() {} };
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, finished)
+            .await
+            .is_err()
+        {
+            println!(
+                "Unix socket requests did not finish within {:?}",
+                SHUTDOWN_TIMEOUT
+            );
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::routing::get;
+    use hydra_client::{Client, ClientConfig};
+    use hydra_proto as proto;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bind() {
+        let dir = std::env::temp_dir().join(format!("hydra-unix-{}", ulid::Ulid::new()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("hydra.sock");
+
+        let listener = bind(&path, Some(0o660)).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+        // a live socket is left alone
+        assert!(bind(&path, None).is_err());
+
+        // but one left behind by a server which has gone is replaced
+        drop(listener);
+        bind(&path, None).unwrap();
+
+        let file = dir.join("not-a-socket");
+        fs::write(&file, "").unwrap();
+        assert!(bind(&file, None).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_client_over_socket() {
+        let dir = std::env::temp_dir().join(format!("hydra-unix-{}", ulid::Ulid::new()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("hydra.sock");
+        let state = AppState::new_test().unwrap();
+        let app = Router::new()
+            .route("/ws", get(crate::ws_handler))
+            .with_state(state.clone());
+        let listener = spawn_listener(bind(&path, None).unwrap(), path.clone(), app, state.clone());
+
+        let url = format!("{}{}", hydra_client::UNIX_SCHEME, path.display());
+        let client = Client::connect(ClientConfig::new(&url)).await.unwrap();
+        client.ready().await;
+        let response = client
+            .request(proto::RequestPayload::GetKv(proto::GetKvRequest {
+                tenant: "test".to_string(),
+                key: "missing".to_string(),
+            }))
+            .await
+            .unwrap();
+        assert!(matches!(
+            response,
+            proto::ResponsePayload::GetKv(proto::GetKvResponse { value: None })
+        ));
+
+        drop(client);
+        state.shutdown.send_replace(true);
+        listener.await.unwrap();
+        assert!(!path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,122 @@
+use std::{
+    collections::HashMap,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use anyhow::{anyhow, Result};
+use hydra_error::{Classified, ErrorKind};
+use tokio::sync::{mpsc, oneshot, OwnedSemaphorePermit, Semaphore};
+
+use crate::error::AppError;
+
+/// How many jobs may be waiting for a worker before submitters have to wait
+const QUEUE_CAPACITY: usize = 256;
+
+/// Classes of CPU-heavy work, each with its own concurrency limit so that one kind of
+/// analytical load can't starve the others
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum JobClass {
+    Query,
+    Export,
+}
+
+impl JobClass {
+    const ALL: [JobClass; 2] = [JobClass::Query, JobClass::Export];
+
+    fn default_limit(&self, workers: usize) -> usize {
+        match self {
+            JobClass::Query => workers,
+            // exports are long running, so leave room for interactive queries
+            JobClass::Export => (workers / 2).max(1),
+        }
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A dedicated pool of OS threads for CPU-bound request handling, keeping the tokio
+/// runtime threads free to service I/O while heavy queries are running.
+pub struct WorkerPool {
+    queue: mpsc::Sender<Job>,
+    limits: HashMap<JobClass, Arc<Semaphore>>,
+}
+
+impl WorkerPool {
+    pub fn new() -> Self {
+        let workers = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(4);
+        Self::with_workers(workers)
+    }
+
+    pub fn with_workers(workers: usize) -> Self {
+        let (queue, receiver) = mpsc::channel::<Job>(QUEUE_CAPACITY);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for i in 0..workers {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("hydra-worker-{}", i))
+                .spawn(move || loop {
+                    // The lock is only held while waiting for the next job, not while running it
+                    let job = receiver.lock().unwrap().blocking_recv();
+                    match job {
+                        Some(job) => job(),
+                        // the pool has been dropped
+                        None => break,
+                    }
+                })
+                .expect("Failed to spawn worker thread");
+        }
+
+        let limits = JobClass::ALL
+            .iter()
+            .map(|class| {
+                let limit = class.default_limit(workers);
+                (*class, Arc::new(Semaphore::new(limit)))
+            })
+            .collect();
+
+        Self { queue, limits }
+    }
+
+    /// Run a job on the pool and wait for its result. Waits (asynchronously) if the job's class is
+    /// at its concurrency limit or the queue is full.
+    pub async fn run<F, T>(&self, class: JobClass, job: F) -> Result<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let permit: OwnedSemaphorePermit = self.limits[&class].clone().acquire_owned().await?;
+        let (result_sender, result_receiver) = oneshot::channel();
+
+        let job: Job = Box::new(move || {
+            let result = catch_unwind(AssertUnwindSafe(job));
+            drop(permit);
+            if let Ok(result) = result {
+                // the submitter may have gone away, which is fine
+                let _ = result_sender.send(result);
+            }
+        });
+
+        self.queue
+            .send(job)
+            .await
+            .map_err(|_| Classified::new(ErrorKind::Unavailable, "Worker pool has shut down"))?;
+
+        result_receiver
+            .await
+            .map_err(|_| anyhow!("{:?} job panicked", class))
+    }
+
+    /// Like run, for jobs which themselves return a Result
+    pub async fn try_run<F, T>(&self, class: JobClass, job: F) -> Result<T, AppError>
+    where
+        F: FnOnce() -> Result<T, AppError> + Send + 'static,
+        T: Send + 'static,
+    {
+        self.run(class, job).await?
+    }
+}
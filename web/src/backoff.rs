@@ -0,0 +1,111 @@
+use std::time::Duration;
+
+use wasm_bindgen::prelude::*;
+
+/// How long the client waits before each attempt to reconnect. The delay starts at
+/// `initial_delay_ms` and is multiplied by `multiplier` after every failed attempt, up to
+/// `max_delay_ms`. Up to `jitter` of each delay is taken off at random, so that clients which
+/// were disconnected together don't all come back at the same moment. A successful connection
+/// starts the sequence over.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BackoffPolicy {
+    initial_delay_ms: u32,
+    max_delay_ms: u32,
+    multiplier: f64,
+    jitter: f64,
+    max_attempts: Option<u32>,
+}
+
+#[wasm_bindgen]
+impl BackoffPolicy {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> BackoffPolicy {
+        BackoffPolicy {
+            initial_delay_ms: 500,
+            max_delay_ms: 10_000,
+            multiplier: 2.0,
+            jitter: 0.5,
+            max_attempts: None,
+        }
+    }
+
+    pub fn initial_delay_ms(mut self, ms: u32) -> BackoffPolicy {
+        self.initial_delay_ms = ms;
+        self
+    }
+
+    pub fn max_delay_ms(mut self, ms: u32) -> BackoffPolicy {
+        self.max_delay_ms = ms;
+        self
+    }
+
+    pub fn multiplier(mut self, multiplier: f64) -> BackoffPolicy {
+        self.multiplier = multiplier.max(1.0);
+        self
+    }
+
+    /// The fraction of each delay, from 0 to 1, which may be taken off at random
+    pub fn jitter(mut self, jitter: f64) -> BackoffPolicy {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Give up after this many attempts in a row fail, leaving the client disconnected until
+    /// Client::reconnect is called. Unlimited by default.
+    pub fn max_attempts(mut self, attempts: u32) -> BackoffPolicy {
+        self.max_attempts = Some(attempts);
+        self
+    }
+}
+
+impl Default for BackoffPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BackoffPolicy {
+    /// The delay before the given attempt, counting from 0, or None if there are to be no more.
+    /// `random` is a number from 0 to 1, which picks how much jitter to take off.
+    pub fn delay(&self, attempt: u32, random: f64) -> Option<Duration> {
+        if self.max_attempts.is_some_and(|max| attempt >= max) {
+            return None;
+        }
+        let exponential = self.initial_delay_ms as f64 * self.multiplier.powi(attempt as i32);
+        let capped = exponential.min(self.max_delay_ms as f64);
+        let jittered = capped * (1.0 - self.jitter * random.clamp(0.0, 1.0));
+        Some(Duration::from_millis(jittered as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        let policy = BackoffPolicy::new().jitter(0.0).max_attempts(6);
+        let delays: Vec<_> = (0..7)
+            .map(|attempt| policy.delay(attempt, 0.5).map(|d| d.as_millis()))
+            .collect();
+        assert_eq!(
+            delays,
+            vec![
+                Some(500),
+                Some(1000),
+                Some(2000),
+                Some(4000),
+                Some(8000),
+                Some(10_000),
+                None
+            ]
+        );
+
+        // jitter only ever shortens the delay
+        let policy = BackoffPolicy::new();
+        assert_eq!(policy.delay(1, 0.0), Some(Duration::from_millis(1000)));
+        assert_eq!(policy.delay(1, 1.0), Some(Duration::from_millis(500)));
+        assert_eq!(policy.delay(100, 0.0), Some(Duration::from_millis(10_000)));
+    }
+}
@@ -1,20 +1,36 @@
-use futures::future::{select, Either, FutureExt};
+use futures::channel::oneshot;
+use futures::future::{join_all, select, Either, FutureExt};
 use futures::io::Read;
 use futures::select;
-use futures_signals::signal::{Mutable, SignalExt};
+use futures_signals::signal::{Mutable, Signal, SignalExt};
 use futures_signals::signal::{MutableSignal, ReadOnlyMutable};
 use gloo_timers::future::sleep;
-use log::{error, info, warn};
-use std::cell::RefCell;
-use std::rc::Rc;
+use hydra_proto as proto;
+use log::{debug, error, info, warn};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::rc::{Rc, Weak};
 use std::time::Duration;
+use ulid::Ulid;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
 use wasm_bindgen_futures::spawn_local;
-use web_sys::{CloseEvent, Event, MessageEvent, WebSocket};
+use web_sys::{BinaryType, CloseEvent, Event, MessageEvent, WebSocket};
 
-const MAX_RECONNECT_DELAY: u64 = 10000;
+use crate::backoff::BackoffPolicy;
+use crate::lite::{self, HintListener};
+use crate::transport::{MessageHandler, Transport};
 
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_QUEUE_CAPACITY: usize = 256;
+const DEFAULT_ENVIRONMENT: &str = "local";
+const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(45);
+// where the server listens in development, for when there's no page to derive the URL from
+const DEFAULT_URL: &str = "ws://127.0.0.1:9797/ws";
+
+/// The state of the client's connection to the server. None while switching environments.
+#[wasm_bindgen]
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum ConnectionState {
     None,
@@ -24,9 +40,270 @@ pub enum ConnectionState {
     Error,
 }
 
+/// How a Client connects to the server
+#[wasm_bindgen]
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    url: Option<String>,
+    protocols: Vec<String>,
+    auto_reconnect: bool,
+    backoff: BackoffPolicy,
+    heartbeat_interval: Duration,
+    heartbeat_timeout: Duration,
+    lite_mode: bool,
+}
+
+#[wasm_bindgen]
+impl ClientConfig {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> ClientConfig {
+        ClientConfig {
+            url: None,
+            protocols: Vec::new(),
+            auto_reconnect: true,
+            backoff: BackoffPolicy::new(),
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            heartbeat_timeout: DEFAULT_HEARTBEAT_TIMEOUT,
+            lite_mode: false,
+        }
+    }
+
+    /// The WebSocket URL of the server, ws:// or wss://. By default it's `/ws` on the host which
+    /// served the page, over wss:// if the page came over HTTPS.
+    pub fn url(mut self, url: &str) -> ClientConfig {
+        self.url = Some(url.to_string());
+        self
+    }
+
+    /// Offer a WebSocket subprotocol when connecting. May be called more than once.
+    pub fn protocol(mut self, protocol: &str) -> ClientConfig {
+        self.protocols.push(protocol.to_string());
+        self
+    }
+
+    /// Whether to reconnect, with backoff, whenever the connection drops. On by default. When
+    /// off, the client stays disconnected until Client::reconnect is called.
+    pub fn auto_reconnect(mut self, enabled: bool) -> ClientConfig {
+        self.auto_reconnect = enabled;
+        self
+    }
+
+    /// How long to wait between attempts to reconnect
+    pub fn backoff(mut self, backoff: BackoffPolicy) -> ClientConfig {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Ping the server every `interval_ms` while connected, and drop the connection, to be
+    /// reconnected, once nothing has come back from the server for `timeout_ms`. Proxies often
+    /// close idle WebSockets without telling either end. Every 15 seconds, with a timeout of 45,
+    /// by default, and an interval of 0 turns it off.
+    pub fn heartbeat(mut self, interval_ms: u32, timeout_ms: u32) -> ClientConfig {
+        self.heartbeat_interval = Duration::from_millis(interval_ms as u64);
+        self.heartbeat_timeout = Duration::from_millis(timeout_ms as u64);
+        self
+    }
+
+    /// Start in lite mode, see Client::set_lite_mode. Off by default.
+    pub fn lite_mode(mut self, enabled: bool) -> ClientConfig {
+        self.lite_mode = enabled;
+        self
+    }
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The server URL to use when none is configured
+fn default_url() -> String {
+    let location = web_sys::window().map(|window| window.location());
+    let protocol = location.as_ref().and_then(|l| l.protocol().ok());
+    let host = location.as_ref().and_then(|l| l.host().ok());
+    match (protocol, host) {
+        (Some(protocol), Some(host)) => url_for_page(&protocol, &host),
+        _ => DEFAULT_URL.to_string(),
+    }
+}
+
+/// The WebSocket URL of the server which served a page, given the page's protocol and host
+fn url_for_page(protocol: &str, host: &str) -> String {
+    match protocol {
+        "https:" => format!("wss://{}/ws", host),
+        "http:" if !host.is_empty() => format!("ws://{}/ws", host),
+        // eg. file:// pages, which have no server
+        _ => DEFAULT_URL.to_string(),
+    }
+}
+
+/// A named server endpoint which the client can be switched to at runtime
+#[derive(Clone, Debug)]
+pub struct Environment {
+    pub name: String,
+    pub url: String,
+}
+
+/// What to do when a message is sent while the outgoing queue is full
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OverflowPolicy {
+    /// Discard the oldest queued message to make room
+    DropOldest,
+    /// Discard the message being sent
+    DropNewest,
+}
+
+/// A message waiting for the connection to open
+enum Outbound {
+    Text(String),
+    Request(Box<proto::Request>),
+}
+
+/// Why a request didn't get a response
+#[derive(Clone, PartialEq, Debug)]
+pub enum RequestError {
+    /// No response arrived within the request timeout
+    Timeout,
+    /// The request was abandoned, e.g. because the client switched environments or it was
+    /// dropped from a full outgoing queue
+    Cancelled,
+    /// The connection closed after the request was sent, so its response will never arrive
+    Disconnected,
+    /// The server responded with an error
+    Rejected(proto::ErrorPayload),
+}
+
+impl RequestError {
+    fn unexpected_response() -> Self {
+        RequestError::Rejected(proto::ErrorPayload::new(
+            proto::ErrorKind::Internal,
+            "Unexpected response",
+        ))
+    }
+
+    pub fn kind(&self) -> proto::ErrorKind {
+        match self {
+            RequestError::Timeout => proto::ErrorKind::Unavailable,
+            RequestError::Cancelled => proto::ErrorKind::Cancelled,
+            RequestError::Disconnected => proto::ErrorKind::Unavailable,
+            RequestError::Rejected(payload) => payload.kind,
+        }
+    }
+
+    /// The request field the server found fault with, if it said
+    pub fn field(&self) -> Option<&str> {
+        match self {
+            RequestError::Rejected(payload) => payload.field.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// How long the server asked us to wait before retrying, if it did
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            RequestError::Rejected(payload) => payload.retry_after_ms.map(Duration::from_millis),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for RequestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RequestError::Timeout => write!(f, "Request timed out"),
+            RequestError::Cancelled => write!(f, "Request was cancelled"),
+            RequestError::Disconnected => {
+                write!(f, "Connection closed before the response arrived")
+            }
+            RequestError::Rejected(payload) => {
+                write!(f, "Request was rejected: {}", payload.message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+/// Keeps the kind and details, so JavaScript sees eg. a NotFound error rather than a generic one
+impl From<RequestError> for JsValue {
+    fn from(err: RequestError) -> Self {
+        let payload = match &err {
+            RequestError::Rejected(payload) => proto::ErrorPayload {
+                message: err.to_string(),
+                ..payload.clone()
+            },
+            _ => proto::ErrorPayload::new(err.kind(), err.to_string()),
+        };
+        hydra_error::Error::from_payload(&payload).into()
+    }
+}
+
+/// Delivered to a subscription's handler
+pub enum SubscriptionEvent {
+    /// A push from the server, e.g. ResponsePayload::IngressLogAppended
+    Push(Box<proto::ResponsePayload>),
+    /// Messages from the server were lost, so pushes may have been missed. Anything built up from
+    /// them should be refetched.
+    Resync,
+}
+
+type SubscriptionHandler = Rc<dyn Fn(SubscriptionEvent)>;
+
+/// A request awaiting its response
+struct PendingRequest {
+    sender: oneshot::Sender<Result<proto::Response, RequestError>>,
+    // whether it has gone out on the current connection, rather than still being queued
+    sent: bool,
+    // The bincode encoding of a mutation, to send again as it was if the connection drops before
+    // it's answered. Request is not Clone.
+    resend: Option<Vec<u8>>,
+}
+
 struct ClientInner {
-    connection: RefCell<Option<Connection>>,
+    connection: RefCell<Option<Rc<Connection>>>,
+    // Used instead of a WebSocket connection when the client is constructed with_transport
+    transport: Option<Rc<dyn Transport>>,
+    // Requests awaiting a response, by request id
+    pending: RefCell<HashMap<usize, PendingRequest>>,
+    // Handlers for pushes, by subscription id
+    subscriptions: RefCell<HashMap<usize, SubscriptionHandler>>,
+    // Responses fetched ahead of time on the server's hints, by the bincode encoding of the
+    // request they answer. Each one answers the first matching request, and is then forgotten.
+    prefetched: RefCell<HashMap<Vec<u8>, proto::ResponsePayload>>,
+    // The sequence number we expect on the next message from the server. The server numbers the
+    // messages on each connection, so any other number means some were dropped.
+    next_sequence: Cell<u64>,
+    request_timeout: Cell<Duration>,
+    // Messages sent while the connection wasn't open, flushed in order once it is
+    queue: RefCell<VecDeque<Outbound>>,
+    queue_capacity: Cell<usize>,
+    overflow_policy: Cell<OverflowPolicy>,
     state: Mutable<ConnectionState>,
+    environments: RefCell<Vec<Environment>>,
+    current_environment: RefCell<String>,
+    // bumped whenever we switch environments so that stale reconnects can bail out
+    generation: Cell<usize>,
+    next_request_id: Cell<usize>,
+    // WebSocket subprotocols offered on every connection
+    protocols: Vec<String>,
+    auto_reconnect: bool,
+    backoff: BackoffPolicy,
+    // failed attempts to reconnect since the connection was last open
+    reconnect_attempts: Cell<u32>,
+    // The reconnect waiting out its backoff, if there is one. There's only ever one, and
+    // connecting by any other means cancels it.
+    pending_reconnect: Cell<Option<usize>>,
+    next_reconnect: Cell<usize>,
+    heartbeat_interval: Duration,
+    heartbeat_timeout: Duration,
+    // how long the server took to answer the last Ping
+    round_trip: Cell<Option<Duration>>,
+    // whether requests are cut down for a slow or metered connection, see crate::lite
+    lite: Mutable<bool>,
+    // switches lite mode as the browser's connection hints change, while it's being followed
+    hint_listener: RefCell<Option<HintListener>>,
 }
 
 #[wasm_bindgen]
@@ -36,13 +313,11 @@ pub struct Client {
 
 #[wasm_bindgen]
 impl Client {
-    pub fn new() -> Result<Client, JsValue> {
-        let inner = Rc::new(ClientInner {
-            connection: RefCell::new(None),
-            state: Mutable::new(ConnectionState::None),
-        });
+    /// Connect to the server, as configured or, without a config, using the defaults
+    pub fn new(config: Option<ClientConfig>) -> Result<Client, JsValue> {
+        let inner = Rc::new(ClientInner::new(None, config.unwrap_or_default()));
 
-        inner.connect(0)?;
+        inner.connect()?;
 
         Ok(Client { inner })
     }
@@ -53,40 +328,831 @@ impl Client {
             .wait_for(ConnectionState::Open)
             .await;
     }
+
+    /// Connect again straight away after the connection dropped, eg. for clients configured
+    /// without auto_reconnect, or which have run out of attempts. Does nothing while connected
+    /// or connecting.
+    pub fn reconnect(&self) -> Result<(), JsValue> {
+        match self.inner.state.get() {
+            ConnectionState::Open | ConnectionState::Connecting => Ok(()),
+            _ => {
+                self.inner.reconnect_attempts.set(0);
+                self.inner.connect()
+            }
+        }
+    }
+
+    pub fn connection_state(&self) -> ConnectionState {
+        self.inner.state.get()
+    }
+
+    /// How long, in milliseconds, the server took to answer the last heartbeat Ping, or
+    /// undefined if it hasn't answered one yet
+    pub fn round_trip_ms(&self) -> Option<f64> {
+        self.inner
+            .round_trip
+            .get()
+            .map(|round_trip| round_trip.as_millis() as f64)
+    }
+
+    /// Call `callback` with the ConnectionState straight away and again whenever it changes, eg.
+    /// to show a "reconnecting…" banner while it isn't Open
+    pub fn on_state_change(&self, callback: js_sys::Function) {
+        spawn_local(self.state_signal().for_each(move |state| {
+            if let Err(e) = callback.call1(&JsValue::NULL, &JsValue::from(state)) {
+                error!("on_state_change: callback threw {:?}", e);
+            }
+            futures::future::ready(())
+        }));
+    }
+
     pub fn send_message(&self, message: &str) {
         info!("send_message: Sending message: {}", message);
+        self.inner
+            .send_or_queue(Outbound::Text(message.to_string()));
+    }
+
+    /// How many messages to hold while disconnected before the overflow policy kicks in
+    pub fn set_queue_capacity(&self, capacity: usize) {
+        self.inner.queue_capacity.set(capacity);
+    }
+
+    pub fn set_overflow_policy(&self, policy: OverflowPolicy) {
+        self.inner.overflow_policy.set(policy);
+    }
+
+    /// Register (or update) a named server endpoint, e.g. "staging" => "wss://staging.example.com/ws"
+    pub fn add_environment(&self, name: &str, url: &str) {
+        let mut environments = self.inner.environments.borrow_mut();
+        match environments.iter_mut().find(|e| e.name == name) {
+            Some(environment) => environment.url = url.to_string(),
+            None => environments.push(Environment {
+                name: name.to_string(),
+                url: url.to_string(),
+            }),
+        }
+    }
+
+    /// Names of all registered environments
+    pub fn environments(&self) -> Vec<String> {
+        self.inner
+            .environments
+            .borrow()
+            .iter()
+            .map(|e| e.name.clone())
+            .collect()
+    }
+
+    /// Name of the environment the client is currently connected (or connecting) to
+    pub fn environment(&self) -> String {
+        self.inner.current_environment.borrow().clone()
+    }
+
+    /// Tear down the current connection and reconnect to the named environment. Nothing from the
+    /// old one carries over: requests awaiting responses fail as Cancelled, queued messages are
+    /// dropped, and prefetched responses and subscriptions are forgotten, so nothing the old
+    /// server said can answer for the new one, and nothing meant for it is sent to the new one.
+    pub fn switch_environment(&self, name: &str) -> Result<(), JsValue> {
+        if !self
+            .inner
+            .environments
+            .borrow()
+            .iter()
+            .any(|e| e.name == name)
+        {
+            return Err(
+                hydra_error::Error::not_found(format!("Unknown environment: {}", name)).into(),
+            );
+        }
+        info!("switch_environment: switching to {}", name);
+
+        self.inner.current_environment.replace(name.to_string());
+        self.inner.generation.set(self.inner.generation.get() + 1);
+        self.inner.connection.borrow_mut().take();
+        // custom transports manage their own connection, so stay as they are
+        if self.inner.transport.is_none() {
+            self.inner.state.set(ConnectionState::None);
+        }
+        // responses to these will never arrive from the new environment, so they fail now rather
+        // than at their timeouts
+        let pending: Vec<PendingRequest> = self
+            .inner
+            .pending
+            .borrow_mut()
+            .drain()
+            .map(|(_, p)| p)
+            .collect();
+        for pending in pending {
+            let _ = pending.sender.send(Err(RequestError::Cancelled));
+        }
+        self.inner.subscriptions.borrow_mut().clear();
+        self.inner.queue.borrow_mut().clear();
+        self.inner.prefetched.borrow_mut().clear();
+
+        self.inner.reconnect_attempts.set(0);
+        self.inner.connect()
+    }
+
+    /// Switch lite mode on or off, for users on metered or slow connections. While it's on,
+    /// ingress logs are fetched without bodies or thumbnails and in smaller pages, and key
+    /// watches are debounced for longer. It applies to requests made from then on, so refetch
+    /// what's shown after switching it. See crate::lite.
+    pub fn set_lite_mode(&self, enabled: bool) {
+        self.inner.hint_listener.borrow_mut().take();
+        self.inner.set_lite(enabled);
+    }
+
+    pub fn lite_mode(&self) -> bool {
+        self.inner.lite.get()
+    }
+
+    /// Follow the browser's connection hints, switching lite mode on while the user has asked to
+    /// save data or the connection is 3G or slower, and off otherwise, straight away and whenever
+    /// they change. Returns false if the browser gives no hints, leaving lite mode as it was.
+    /// Following them stops when called with false, or lite mode is switched by hand.
+    pub fn follow_connection_hints(&self, follow: bool) -> bool {
+        self.inner.hint_listener.borrow_mut().take();
+        if !follow {
+            return false;
+        }
+        let Some(hinted) = lite::hinted() else {
+            return false;
+        };
+        let inner = Rc::downgrade(&self.inner);
+        let Some(listener) = HintListener::new(move |lite| {
+            if let Some(inner) = inner.upgrade() {
+                inner.set_lite(lite);
+            }
+        }) else {
+            return false;
+        };
+        self.inner.set_lite(hinted);
+        self.inner.hint_listener.replace(Some(listener));
+        true
+    }
+}
+
+impl Client {
+    /// Construct a client which talks over the given transport rather than a WebSocket,
+    /// e.g. a MockTransport in tests
+    pub fn with_transport(transport: Rc<dyn Transport>) -> Client {
+        // the URL goes unused, and outside a browser there's no page to derive it from
+        let config = ClientConfig::new().url(DEFAULT_URL);
+        let inner = Rc::new(ClientInner::new(Some(transport.clone()), config));
+        inner.state.set(ConnectionState::Open);
+        transport.set_handler(ClientInner::handler(&inner));
+        Client { inner }
+    }
+
+    /// The ConnectionState as a signal, for UIs built on futures-signals. Frameworks which take
+    /// streams, such as Leptos, can follow it with `to_stream()`.
+    pub fn state_signal(&self) -> impl Signal<Item = ConnectionState> {
+        self.inner.state.signal().dedupe()
+    }
+
+    /// Whether lite mode is on, as a signal, eg. to refetch what's shown whenever it's switched
+    pub fn lite_mode_signal(&self) -> impl Signal<Item = bool> {
+        self.inner.lite.signal().dedupe()
+    }
+
+    /// How long send_request waits for a response before giving up
+    pub fn set_request_timeout(&self, timeout: Duration) {
+        self.inner.request_timeout.set(timeout);
+    }
+
+    /// Send a request and wait for the matching response
+    pub async fn send_request(
+        &self,
+        request: proto::Request,
+    ) -> Result<proto::Response, RequestError> {
+        let request_id = request.id;
+        let resend = (request.idempotency_key).and_then(|_| bincode::serialize(&request).ok());
+        let (sender, receiver) = oneshot::channel();
+        self.inner.pending.borrow_mut().insert(
+            request_id,
+            PendingRequest {
+                sender,
+                sent: false,
+                resend,
+            },
+        );
+        self.inner
+            .send_or_queue(Outbound::Request(Box::new(request)));
+
+        // the timer is created lazily so that an already delivered response never touches it
+        let timeout = self.inner.request_timeout.get();
+        let timer = async move { sleep(timeout).await }.boxed_local();
+        match select(receiver, timer).await {
+            Either::Left((Ok(result), _)) => result,
+            Either::Left((Err(_), _)) => Err(RequestError::Cancelled),
+            Either::Right(_) => {
+                warn!("send_request: request {} timed out", request_id);
+                self.inner.pending.borrow_mut().remove(&request_id);
+                // so the server doesn't keep working on something nobody is waiting for
+                let cancel =
+                    self.build_request(proto::RequestPayload::Cancel(proto::CancelRequest {
+                        request_id,
+                    }));
+                self.inner
+                    .send_or_queue(Outbound::Request(Box::new(cancel)));
+                Err(RequestError::Timeout)
+            }
+        }
+    }
+
+    /// Build and send a request for the given payload, returning the response payload
+    pub async fn request(
+        &self,
+        payload: proto::RequestPayload,
+    ) -> Result<proto::ResponsePayload, RequestError> {
+        // lightened first, so a prefetched response to a heavier request isn't used
+        let payload = self.inner.lighten(payload);
+        if let Some(prefetched) = self.inner.take_prefetched(&payload) {
+            return Ok(prefetched);
+        }
+        let request = self.build_request(payload);
+        Ok(self.send_request(request).await?.payload)
+    }
+
+    /// Say hello to the server and make the requests it hints the UI is going to make first,
+    /// keeping the responses to answer those requests when they come. Call it as early as
+    /// possible, eg. right after constructing the client. Returns how many responses were kept.
+    pub async fn prefetch(&self) -> Result<usize, RequestError> {
+        let hello = match self.request(proto::RequestPayload::Hello).await? {
+            proto::ResponsePayload::Hello(hello) => hello,
+            proto::ResponsePayload::Error(payload) => return Err(RequestError::Rejected(payload)),
+            _ => return Err(RequestError::unexpected_response()),
+        };
+
+        let fetches = hello
+            .prefetch
+            .into_iter()
+            // a hint should never be a mutation, but don't take the server's word for it
+            .filter(|hint| !hint.is_mutation())
+            .filter_map(|hint| Some((bincode::serialize(&hint).ok()?, hint)))
+            .map(|(key, hint)| async move { (key, self.request(hint).await) });
+        let responses = join_all(fetches).await;
+
+        let mut prefetched = self.inner.prefetched.borrow_mut();
+        for (key, response) in responses {
+            match response {
+                Ok(proto::ResponsePayload::Error(payload)) => {
+                    warn!("prefetch: hinted request failed: {}", payload)
+                }
+                Ok(payload) => {
+                    prefetched.insert(key, payload);
+                }
+                Err(e) => warn!("prefetch: hinted request failed: {}", e),
+            }
+        }
+        Ok(prefetched.len())
+    }
+
+    /// Subscribe to pushes for a tree, returning the subscription id. The handler is called with
+    /// each push, and with SubscriptionEvent::Resync if the client notices it may have missed some.
+    pub async fn subscribe<F>(&self, tree: &str, handler: F) -> Result<usize, RequestError>
+    where
+        F: Fn(SubscriptionEvent) + 'static,
+    {
+        self.subscribe_sampled(tree, None, handler).await
+    }
+
+    /// Like subscribe, but with `sample_above` set the server only pushes a sample of the writes
+    /// from any source busier than that many a second, along with IngressLogsSampled summaries of
+    /// what it left out. See proto::SubscribeRequest.
+    pub async fn subscribe_sampled<F>(
+        &self,
+        tree: &str,
+        sample_above: Option<u32>,
+        handler: F,
+    ) -> Result<usize, RequestError>
+    where
+        F: Fn(SubscriptionEvent) + 'static,
+    {
+        let request = proto::SubscribeRequest {
+            tree: tree.to_string(),
+            sample_above,
+            keys: vec![],
+            debounce_ms: None,
+            prefix: None,
+        };
+        self.subscribe_with(request, handler).await
+    }
+
+    /// Watch particular keys of a tree, which is pushed a KeyChanged whenever one of them is
+    /// written or removed. With a debounce, rapid changes to a key are pushed as one carrying the
+    /// latest value. See proto::SubscribeRequest.
+    pub async fn watch_keys<F>(
+        &self,
+        tree: &str,
+        keys: Vec<Vec<u8>>,
+        debounce: Option<Duration>,
+        handler: F,
+    ) -> Result<usize, RequestError>
+    where
+        F: Fn(SubscriptionEvent) + 'static,
+    {
+        let request = proto::SubscribeRequest {
+            tree: tree.to_string(),
+            sample_above: None,
+            keys,
+            debounce_ms: debounce.map(|debounce| debounce.as_millis() as u32),
+            prefix: None,
+        };
+        self.subscribe_with(request, handler).await
+    }
+
+    /// Subscribe to every key of a tree starting with `prefix`, which is pushed a RecordChanged
+    /// whenever one of them is written or removed. See proto::SubscribeRequest.
+    pub async fn subscribe_prefix<F>(
+        &self,
+        tree: &str,
+        prefix: Vec<u8>,
+        handler: F,
+    ) -> Result<usize, RequestError>
+    where
+        F: Fn(SubscriptionEvent) + 'static,
+    {
+        let request = proto::SubscribeRequest {
+            tree: tree.to_string(),
+            sample_above: None,
+            keys: vec![],
+            debounce_ms: None,
+            prefix: Some(prefix),
+        };
+        self.subscribe_with(request, handler).await
+    }
+
+    /// Fetch a page of ingress logs and follow it: `handler` is pushed a LiveQueryItem for every
+    /// log written after the page on the cursor's side of it which matches the filter, until the
+    /// returned subscription id is unsubscribed from. See proto::RequestPayload::LiveQuery.
+    pub async fn live_query<F>(
+        &self,
+        request: proto::FetchIngressLogsRequest,
+        handler: F,
+    ) -> Result<(usize, proto::FetchIngressLogsResponse), RequestError>
+    where
+        F: Fn(SubscriptionEvent) + 'static,
+    {
+        let payload = proto::RequestPayload::LiveQuery(request);
+        self.subscribe_to(payload, handler, |payload| match payload {
+            proto::ResponsePayload::LiveQuery(page) => Some(page),
+            _ => None,
+        })
+        .await
+    }
+
+    async fn subscribe_with<F>(
+        &self,
+        request: proto::SubscribeRequest,
+        handler: F,
+    ) -> Result<usize, RequestError>
+    where
+        F: Fn(SubscriptionEvent) + 'static,
+    {
+        let payload = proto::RequestPayload::Subscribe(request);
+        let subscribed = self.subscribe_to(payload, handler, |payload| match payload {
+            proto::ResponsePayload::Subscribed => Some(()),
+            _ => None,
+        });
+        Ok(subscribed.await?.0)
+    }
+
+    /// Make a request which starts a subscription, `accept` picking what's wanted out of its
+    /// response
+    async fn subscribe_to<F, T>(
+        &self,
+        payload: proto::RequestPayload,
+        handler: F,
+        accept: impl FnOnce(proto::ResponsePayload) -> Option<T>,
+    ) -> Result<(usize, T), RequestError>
+    where
+        F: Fn(SubscriptionEvent) + 'static,
+    {
+        let request = self.build_request(payload);
+        // the request id doubles as the subscription id. Pushes can arrive before the response,
+        // so the handler has to be in place before the request is sent
+        let subscription_id = request.id;
+        self.inner
+            .subscriptions
+            .borrow_mut()
+            .insert(subscription_id, Rc::new(handler));
 
-        if let Some(connection) = self.inner.connection.borrow_mut().as_ref() {
-            // TODO: queue these messages?
-            connection.send_message(message);
+        let result = match self.send_request(request).await {
+            Ok(proto::Response {
+                payload: proto::ResponsePayload::Error(payload),
+                ..
+            }) => Err(RequestError::Rejected(payload)),
+            Ok(response) => accept(response.payload)
+                .map(|accepted| (subscription_id, accepted))
+                .ok_or_else(RequestError::unexpected_response),
+            Err(e) => Err(e),
+        };
+        if result.is_err() {
+            self.inner
+                .subscriptions
+                .borrow_mut()
+                .remove(&subscription_id);
+        }
+        result
+    }
+
+    /// Stop receiving pushes for a subscription
+    pub async fn unsubscribe(&self, subscription_id: usize) -> Result<(), RequestError> {
+        self.inner
+            .subscriptions
+            .borrow_mut()
+            .remove(&subscription_id);
+        let payload = self
+            .request(proto::RequestPayload::Unsubscribe(
+                proto::UnsubscribeRequest { subscription_id },
+            ))
+            .await?;
+        match payload {
+            proto::ResponsePayload::Unsubscribed => Ok(()),
+            proto::ResponsePayload::Error(payload) => Err(RequestError::Rejected(payload)),
+            _ => Err(RequestError::unexpected_response()),
+        }
+    }
+
+    /// Wrap a payload in a Request with a fresh id. Mutating requests are given an idempotency key
+    /// so that if they're sent again after the connection drops, the server won't apply them
+    /// twice, see ClientInner::fail_in_flight. Each is given a
+    /// trace id too, which the server's logs of it carry. In lite mode the payload is cut down to
+    /// what that asks for.
+    pub fn build_request(&self, payload: proto::RequestPayload) -> proto::Request {
+        let payload = self.inner.lighten(payload);
+        let id = self.inner.next_request_id.get();
+        self.inner.next_request_id.set(id + 1);
+
+        let idempotency_key = if payload.is_mutation() {
+            Some(generate_ulid())
+        } else {
+            None
+        };
+
+        proto::Request {
+            id,
+            idempotency_key,
+            trace_id: Some(generate_ulid()),
+            payload,
         }
     }
 }
 
+/// Ulid::new() relies on SystemTime, which isn't available in the browser
+#[cfg(target_arch = "wasm32")]
+fn generate_ulid() -> Ulid {
+    let timestamp = js_sys::Date::now() as u64;
+    let random = (0..4).fold(0u128, |acc, _| {
+        (acc << 32) | (js_sys::Math::random() * u32::MAX as f64) as u128
+    });
+    Ulid::from_parts(timestamp, random)
+}
+
+/// but is natively, as in tests, where js_sys isn't
+#[cfg(not(target_arch = "wasm32"))]
+fn generate_ulid() -> Ulid {
+    Ulid::new()
+}
+
 impl ClientInner {
-    pub fn connect(self: &Rc<Self>, mut delay: u64) -> Result<(), JsValue> {
-        let connection = Connection::new()?;
+    fn new(transport: Option<Rc<dyn Transport>>, config: ClientConfig) -> Self {
+        ClientInner {
+            connection: RefCell::new(None),
+            transport,
+            pending: RefCell::new(HashMap::new()),
+            subscriptions: RefCell::new(HashMap::new()),
+            prefetched: RefCell::new(HashMap::new()),
+            next_sequence: Cell::new(0),
+            request_timeout: Cell::new(DEFAULT_REQUEST_TIMEOUT),
+            queue: RefCell::new(VecDeque::new()),
+            queue_capacity: Cell::new(DEFAULT_QUEUE_CAPACITY),
+            overflow_policy: Cell::new(OverflowPolicy::DropOldest),
+            state: Mutable::new(ConnectionState::None),
+            environments: RefCell::new(vec![Environment {
+                name: DEFAULT_ENVIRONMENT.to_string(),
+                url: config.url.unwrap_or_else(default_url),
+            }]),
+            current_environment: RefCell::new(DEFAULT_ENVIRONMENT.to_string()),
+            generation: Cell::new(0),
+            next_request_id: Cell::new(0),
+            protocols: config.protocols,
+            auto_reconnect: config.auto_reconnect,
+            backoff: config.backoff,
+            reconnect_attempts: Cell::new(0),
+            pending_reconnect: Cell::new(None),
+            next_reconnect: Cell::new(0),
+            heartbeat_interval: config.heartbeat_interval,
+            heartbeat_timeout: config.heartbeat_timeout,
+            round_trip: Cell::new(None),
+            lite: Mutable::new(config.lite_mode),
+            hint_listener: RefCell::new(None),
+        }
+    }
+
+    fn set_lite(&self, enabled: bool) {
+        if self.lite.replace(enabled) != enabled {
+            info!("set_lite: lite mode {}", if enabled { "on" } else { "off" });
+        }
+    }
+
+    fn lighten(&self, payload: proto::RequestPayload) -> proto::RequestPayload {
+        match self.lite.get() {
+            true => lite::lighten(payload),
+            false => payload,
+        }
+    }
+
+    fn current_transport(&self) -> Option<Rc<dyn Transport>> {
+        match &self.transport {
+            Some(transport) => Some(transport.clone()),
+            None => self
+                .connection
+                .borrow()
+                .clone()
+                .map(|connection| connection as Rc<dyn Transport>),
+        }
+    }
+
+    /// Send a message now if the connection is open, otherwise queue it until it is
+    fn send_or_queue(&self, message: Outbound) {
+        if self.state.get() == ConnectionState::Open && self.queue.borrow().is_empty() {
+            self.send_now(message);
+            return;
+        }
+
+        let dropped = {
+            let mut queue = self.queue.borrow_mut();
+            if queue.len() < self.queue_capacity.get() {
+                queue.push_back(message);
+                None
+            } else {
+                match self.overflow_policy.get() {
+                    OverflowPolicy::DropOldest => {
+                        let dropped = queue.pop_front();
+                        queue.push_back(message);
+                        dropped
+                    }
+                    OverflowPolicy::DropNewest => Some(message),
+                }
+            }
+        };
+
+        if let Some(dropped) = dropped {
+            warn!("send_or_queue: outgoing queue is full, dropping a message");
+            if let Outbound::Request(request) = dropped {
+                // fails the request rather than leaving it to time out
+                self.pending.borrow_mut().remove(&request.id);
+            }
+        }
+    }
+
+    fn send_now(&self, message: Outbound) {
+        match message {
+            Outbound::Text(text) => match self.connection.borrow().as_ref() {
+                Some(connection) => connection.send_message(&text),
+                None => warn!("send_now: no connection to send text message on"),
+            },
+            Outbound::Request(request) => match self.current_transport() {
+                Some(transport) => {
+                    if let Some(pending) = self.pending.borrow_mut().get_mut(&request.id) {
+                        pending.sent = true;
+                    }
+                    transport.send(*request)
+                }
+                None => {
+                    warn!("send_now: no transport for request {}", request.id);
+                    self.pending.borrow_mut().remove(&request.id);
+                }
+            },
+        }
+    }
+
+    /// Send everything which was queued while the connection wasn't open
+    fn flush_queue(&self) {
+        loop {
+            // don't hold the borrow while sending, as the transport may call back into us
+            let message = self.queue.borrow_mut().pop_front();
+            match message {
+                // requests which have already timed out or been cancelled aren't worth sending
+                Some(Outbound::Request(request))
+                    if !self.pending.borrow().contains_key(&request.id) => {}
+                Some(message) => self.send_now(message),
+                None => break,
+            }
+        }
+    }
+
+    /// A handler which routes incoming messages back to this client, without keeping it alive
+    fn handler(self: &Rc<Self>) -> MessageHandler {
+        let weak: Weak<Self> = Rc::downgrade(self);
+        Box::new(move |message| {
+            if let Some(inner) = weak.upgrade() {
+                inner.handle_message(message);
+            }
+        })
+    }
+
+    fn handle_message(&self, message: proto::Message) {
+        match message {
+            proto::Message::Response(response) => {
+                let expected = self.next_sequence.replace(response.sequence + 1);
+                if response.sequence != expected {
+                    warn!(
+                        "handle_message: expected message {} but got {}, resyncing subscriptions",
+                        expected, response.sequence
+                    );
+                    self.resync();
+                }
+
+                if matches!(
+                    response.payload,
+                    proto::ResponsePayload::IngressLogAppended(_)
+                        | proto::ResponsePayload::IngressLogsSampled(_)
+                        | proto::ResponsePayload::Alert(_)
+                        | proto::ResponsePayload::KeyChanged(_)
+                        | proto::ResponsePayload::RecordChanged(_)
+                        | proto::ResponsePayload::LiveQueryItem(_)
+                ) {
+                    self.push(response);
+                    return;
+                }
+                if let proto::ResponsePayload::GoingAway(going_away) = &response.payload {
+                    self.going_away(going_away);
+                    return;
+                }
+                // the request is still pending, and the client doesn't report progress yet
+                if let proto::ResponsePayload::TagProgress(progress) = &response.payload {
+                    debug!(
+                        "request {} has tagged {} of {} logs scanned",
+                        response.request_id, progress.changed, progress.scanned
+                    );
+                    return;
+                }
+
+                let pending = self.pending.borrow_mut().remove(&response.request_id);
+                match pending {
+                    // the requester may have given up waiting, which is fine
+                    Some(pending) => {
+                        let _ = pending.sender.send(Ok(response));
+                    }
+                    None => warn!("handle_message: no pending request {}", response.request_id),
+                }
+            }
+            proto::Message::Ping(ping) => {
+                if let Some(connection) = self.connection.borrow().as_ref() {
+                    let pong = ping.pong(js_sys::Date::now() as u64);
+                    connection.send_proto(&proto::Message::Pong(pong));
+                }
+            }
+            proto::Message::Pong(pong) => {
+                let elapsed = (js_sys::Date::now() as u64).saturating_sub(pong.ping_sent_at);
+                self.round_trip.set(Some(Duration::from_millis(elapsed)));
+            }
+            proto::Message::Request(_) => warn!("handle_message: unexpected request from server"),
+        }
+    }
+
+    /// The server is draining: point the environment at where it says to go, if anywhere, and
+    /// close the connection, which is then reconnected like any other which drops
+    fn going_away(&self, going_away: &proto::GoingAway) {
+        info!("going_away: the server is going away, reconnecting");
+        if let Some(url) = &going_away.reconnect_to {
+            let current = self.current_environment.borrow();
+            if let Some(environment) = self
+                .environments
+                .borrow_mut()
+                .iter_mut()
+                .find(|e| e.name == *current)
+            {
+                environment.url = url.clone();
+            }
+        }
+        if let Some(connection) = self.connection.borrow().as_ref() {
+            let _ = connection.ws.close();
+        }
+    }
+
+    fn push(&self, response: proto::Response) {
+        // clone the handler out so that it can subscribe or unsubscribe
+        let handler = self
+            .subscriptions
+            .borrow()
+            .get(&response.request_id)
+            .cloned();
+        match handler {
+            Some(handler) => handler(SubscriptionEvent::Push(Box::new(response.payload))),
+            None => warn!("push: no subscription {}", response.request_id),
+        }
+    }
+
+    /// The prefetched response to a request, if there is one
+    fn take_prefetched(&self, payload: &proto::RequestPayload) -> Option<proto::ResponsePayload> {
+        if self.prefetched.borrow().is_empty() {
+            return None;
+        }
+        let key = bincode::serialize(payload).ok()?;
+        self.prefetched.borrow_mut().remove(&key)
+    }
+
+    /// Fail the requests which were sent on a connection that has since closed. The server
+    /// abandons a connection's requests when it goes away, so their responses will never come.
+    /// Mutations may have been applied all the same, so rather than failing they're sent again
+    /// once we reconnect, ahead of anything queued, with the same idempotency key. The server
+    /// answers with the original result if it did apply them. Requests still queued are kept, to
+    /// be sent once we reconnect.
+    fn fail_in_flight(&self) {
+        let mut in_flight: Vec<usize> = self
+            .pending
+            .borrow()
+            .iter()
+            .filter(|(_, pending)| pending.sent)
+            .map(|(id, _)| *id)
+            .collect();
+        // queued in the order they were sent
+        in_flight.sort_unstable();
+        for id in in_flight.into_iter().rev() {
+            let resend = match self.pending.borrow_mut().get_mut(&id) {
+                Some(pending) => (pending.resend.as_deref())
+                    .and_then(|encoded| bincode::deserialize::<proto::Request>(encoded).ok())
+                    .inspect(|_| pending.sent = false),
+                None => continue,
+            };
+            match resend {
+                Some(request) => {
+                    (self.queue.borrow_mut()).push_front(Outbound::Request(Box::new(request)));
+                }
+                None => {
+                    let pending = self.pending.borrow_mut().remove(&id);
+                    if let Some(pending) = pending {
+                        let _ = pending.sender.send(Err(RequestError::Disconnected));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Tell every subscription that it may have missed pushes
+    fn resync(&self) {
+        let handlers: Vec<SubscriptionHandler> =
+            self.subscriptions.borrow().values().cloned().collect();
+        for handler in handlers {
+            handler(SubscriptionEvent::Resync);
+        }
+    }
+
+    fn current_url(&self) -> Result<String, JsValue> {
+        let current = self.current_environment.borrow();
+        self.environments
+            .borrow()
+            .iter()
+            .find(|e| e.name == *current)
+            .map(|e| e.url.clone())
+            .ok_or_else(|| {
+                hydra_error::Error::not_found(format!("Unknown environment: {}", current)).into()
+            })
+    }
+
+    /// Open a new connection, replacing the current one and cancelling any pending reconnect
+    pub fn connect(self: &Rc<Self>) -> Result<(), JsValue> {
+        if self.transport.is_some() {
+            // custom transports manage their own connection
+            return Ok(());
+        }
+        self.pending_reconnect.set(None);
+        let connection = Connection::new(&self.current_url()?, &self.protocols)?;
+        connection.set_handler(self.handler());
         let state = connection.state.clone();
+        let connection = Rc::new(connection);
+        let opened = Rc::downgrade(&connection);
         self.connection.borrow_mut().replace(connection);
 
         self.state.set(ConnectionState::Connecting);
-        let client_inner = Rc::clone(&self);
+        let client_inner = Rc::clone(self);
 
         info!("Connecting to websocket");
-        let self2 = self.clone();
+        let generation = self.generation.get();
         spawn_local(async move {
             state
                 .signal()
                 .for_each(|state| {
+                    if client_inner.generation.get() != generation {
+                        // this connection belongs to an environment we've since switched away from
+                        return futures::future::ready(());
+                    }
                     info!("connect: state changed to {:?}", state);
                     client_inner.state.set(state);
-                    // if state isn't open or connecting, reconnect
                     match state {
                         ConnectionState::Open => {
-                            delay = 0;
+                            client_inner.reconnect_attempts.set(0);
+                            // the server numbers each connection's messages from 0
+                            client_inner.next_sequence.set(0);
+                            client_inner.flush_queue();
+                            client_inner.start_heartbeat(opened.clone());
                         }
                         ConnectionState::Connecting => (),
-                        _ => self2.reconnect(delay + 500),
+                        _ => client_inner.connection_lost(),
                     }
                     futures::future::ready(())
                 })
@@ -94,33 +1160,97 @@ impl ClientInner {
             info!("connect: for_each future complete");
         });
 
-        // This isn't quite right, because we're not checking the latest connection
-        // we're closing over the connection state from this run
-        // and also we're not coordinating with the reconnect logic, so we've got
-        // dueling reconnects
-        // let state2 = state.clone();
-        // let self3 = self.clone();
-        // spawn_local(async move {
-        //     sleep(Duration::from_secs(30)).await;
-        //     if state2.get() != ConnectionState::Open {
-        //         warn!("connect: Connection timed out");
-        //         self3.reconnect(delay);
-        //     }
-        // });
-
         Ok(())
     }
-    pub fn reconnect(self: &Rc<Self>, mut delay: u64) {
-        delay = delay.min(MAX_RECONNECT_DELAY);
-        info!("reconnect: removing old connection");
+
+    /// Abandon the current connection, reconnecting if we're meant to
+    fn connection_lost(self: &Rc<Self>) {
+        self.fail_in_flight();
+        if self.auto_reconnect {
+            self.schedule_reconnect();
+        } else {
+            self.connection.borrow_mut().take();
+        }
+    }
+
+    /// Ping the server for as long as a connection stays open, and give up on it once nothing
+    /// has come back for the heartbeat timeout
+    fn start_heartbeat(self: &Rc<Self>, connection: Weak<Connection>) {
+        if self.heartbeat_interval.is_zero() {
+            return;
+        }
+        let weak = Rc::downgrade(self);
+        let interval = self.heartbeat_interval;
+        spawn_local(async move {
+            let mut sequence = 0;
+            loop {
+                sleep(interval).await;
+                let (Some(inner), Some(connection)) = (weak.upgrade(), connection.upgrade()) else {
+                    return;
+                };
+                let current = inner
+                    .connection
+                    .borrow()
+                    .as_ref()
+                    .is_some_and(|current| Rc::ptr_eq(current, &connection));
+                if !current || connection.state.get() != ConnectionState::Open {
+                    return;
+                }
+
+                let now = js_sys::Date::now();
+                let silent = Duration::from_millis((now - connection.last_heard.get()) as u64);
+                if silent >= inner.heartbeat_timeout {
+                    warn!(
+                        "heartbeat: nothing from the server for {:?}, reconnecting",
+                        silent
+                    );
+                    inner.state.set(ConnectionState::Closed);
+                    inner.connection_lost();
+                    return;
+                }
+                connection.send_proto(&proto::Message::Ping(proto::Ping {
+                    sequence,
+                    sent_at: now as u64,
+                }));
+                sequence += 1;
+            }
+        });
+    }
+
+    /// Reconnect once the backoff policy says to, unless a reconnect is already pending. A
+    /// failed connection reports both an error and a close, and only the first schedules one.
+    fn schedule_reconnect(self: &Rc<Self>) {
+        if self.pending_reconnect.get().is_some() {
+            return;
+        }
         self.connection.borrow_mut().take();
 
+        let attempt = self.reconnect_attempts.get();
+        let Some(delay) = self.backoff.delay(attempt, js_sys::Math::random()) else {
+            warn!(
+                "reconnect: giving up after {} attempts, call reconnect() to try again",
+                attempt
+            );
+            return;
+        };
+        self.reconnect_attempts.set(attempt + 1);
+        let ticket = self.next_reconnect.get();
+        self.next_reconnect.set(ticket + 1);
+        self.pending_reconnect.set(Some(ticket));
+
         let self2 = self.clone();
         spawn_local(async move {
-            info!("reconnect: sleeping for {}ms", delay);
-            sleep(Duration::from_millis(delay)).await;
-            info!("reconnect: reconnecting");
-            self2.connect(delay).expect("Failed to reconnect");
+            info!("reconnect: attempt {} in {:?}", attempt + 1, delay);
+            sleep(delay).await;
+            if self2.pending_reconnect.get() != Some(ticket) {
+                info!("reconnect: connected by other means, abandoning reconnect");
+                return;
+            }
+            if let Err(e) = self2.connect() {
+                error!("reconnect: failed to connect: {:?}", e);
+                self2.pending_reconnect.set(None);
+                self2.schedule_reconnect();
+            }
         });
     }
 }
@@ -132,19 +1262,45 @@ struct Connection {
     on_close: Closure<dyn FnMut(CloseEvent)>,
     on_open: Closure<dyn FnMut()>,
     state: ReadOnlyMutable<ConnectionState>,
+    handler: Rc<RefCell<Option<MessageHandler>>>,
+    // when anything last arrived from the server, in milliseconds since the epoch
+    last_heard: Rc<Cell<f64>>,
 }
 
 impl Connection {
-    fn new() -> Result<Connection, JsValue> {
-        let ws = WebSocket::new("ws://127.0.0.1:9797/ws")?;
+    fn new(url: &str, protocols: &[String]) -> Result<Connection, JsValue> {
+        let ws = if protocols.is_empty() {
+            WebSocket::new(url)?
+        } else {
+            let protocols: js_sys::Array = protocols.iter().map(JsValue::from).collect();
+            WebSocket::new_with_str_sequence(url, &protocols)?
+        };
+        // deliver binary frames as ArrayBuffers rather than Blobs so we can decode them synchronously
+        ws.set_binary_type(BinaryType::Arraybuffer);
+        let handler: Rc<RefCell<Option<MessageHandler>>> = Rc::new(RefCell::new(None));
+        let handler2 = handler.clone();
 
         let writable_state = Mutable::new(ConnectionState::Connecting);
         let writable_state2 = writable_state.clone();
         let writable_state3 = writable_state.clone();
         let state = writable_state.read_only();
+        let last_heard = Rc::new(Cell::new(0.0));
+        let last_heard2 = last_heard.clone();
+        let last_heard3 = last_heard.clone();
         let on_message =
             Closure::<dyn FnMut(MessageEvent)>::wrap(Box::new(move |e: MessageEvent| {
-                if let Ok(text) = e.data().dyn_into::<js_sys::JsString>() {
+                last_heard2.set(js_sys::Date::now());
+                if let Ok(buffer) = e.data().dyn_into::<js_sys::ArrayBuffer>() {
+                    let bytes = js_sys::Uint8Array::new(&buffer).to_vec();
+                    match bincode::deserialize::<proto::Message>(&bytes) {
+                        Ok(message) => {
+                            if let Some(handler) = handler2.borrow().as_ref() {
+                                handler(message);
+                            }
+                        }
+                        Err(err) => error!("Failed to decode message: {:?}", err),
+                    }
+                } else if let Ok(text) = e.data().dyn_into::<js_sys::JsString>() {
                     info!("Message received: {}", text);
                 }
             }));
@@ -162,6 +1318,7 @@ impl Connection {
         // convert ready into a future
         let on_open = Closure::<dyn FnMut()>::wrap(Box::new(move || {
             info!("Connection opened (event)");
+            last_heard3.set(js_sys::Date::now());
             writable_state3.set(ConnectionState::Open);
         }));
 
@@ -178,6 +1335,8 @@ impl Connection {
             on_close,
             on_open,
             state,
+            handler,
+            last_heard,
         })
     }
 
@@ -186,6 +1345,29 @@ impl Connection {
             info!("Failed to send message: {:?}", err);
         });
     }
+
+    fn send_proto(&self, message: &proto::Message) {
+        let bytes = match bincode::serialize(message) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                error!("Failed to encode message: {:?}", err);
+                return;
+            }
+        };
+        self.ws.send_with_u8_array(&bytes).unwrap_or_else(|err| {
+            info!("Failed to send message: {:?}", err);
+        });
+    }
+}
+
+impl Transport for Connection {
+    fn send(&self, request: proto::Request) {
+        self.send_proto(&proto::Message::Request(request));
+    }
+
+    fn set_handler(&self, handler: MessageHandler) {
+        self.handler.replace(Some(handler));
+    }
 }
 
 impl Drop for Connection {
@@ -198,3 +1380,314 @@ impl Drop for Connection {
         self.ws.close().unwrap();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockTransport;
+    use futures::executor::block_on;
+
+    #[test]
+    fn test_send_request() {
+        let mock = MockTransport::new();
+        mock.stub(|payload| match payload {
+            proto::RequestPayload::GetKv(_) => {
+                Some(proto::ResponsePayload::GetKv(proto::GetKvResponse {
+                    value: Some(b"world".to_vec()),
+                }))
+            }
+            _ => None,
+        });
+        let client = Client::with_transport(Rc::new(mock.clone()));
+
+        let first = client.build_request(proto::RequestPayload::GetKv(proto::GetKvRequest {
+            tenant: "test".to_string(),
+            key: "hello".to_string(),
+        }));
+        let response = block_on(client.send_request(first)).unwrap();
+        assert_eq!(response.request_id, 0);
+        assert!(matches!(
+            response.payload,
+            proto::ResponsePayload::GetKv(proto::GetKvResponse { value: Some(v) }) if v == b"world"
+        ));
+
+        // unstubbed requests still get a response, and ids keep counting up
+        let payload = block_on(client.request(proto::RequestPayload::Unsubscribe(
+            proto::UnsubscribeRequest { subscription_id: 0 },
+        )))
+        .unwrap();
+        assert!(matches!(payload, proto::ResponsePayload::Error(_)));
+        assert_eq!(mock.take_sent().last().unwrap().id, 1);
+        assert!(client.inner.pending.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_url_for_page() {
+        assert_eq!(
+            url_for_page("https:", "hydra.example.com"),
+            "wss://hydra.example.com/ws"
+        );
+        assert_eq!(
+            url_for_page("http:", "127.0.0.1:9797"),
+            "ws://127.0.0.1:9797/ws"
+        );
+        assert_eq!(url_for_page("file:", ""), DEFAULT_URL);
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let newest_page = || {
+            proto::RequestPayload::FetchIngressLogs(proto::FetchIngressLogsRequest {
+                direction: proto::Direction::Descending,
+                limit: 50,
+                cursor: proto::PaginatedCursor::StartingWith(vec![]),
+                preview_bytes: Some(256),
+                snapshot: None,
+                source: None,
+                filter: None,
+                thumbnails: false,
+            })
+        };
+        let mock = MockTransport::new();
+        mock.stub(move |payload| match payload {
+            proto::RequestPayload::Hello => {
+                Some(proto::ResponsePayload::Hello(proto::HelloResponse {
+                    prefetch: vec![newest_page()],
+                    session: Ulid::nil(),
+                }))
+            }
+            proto::RequestPayload::FetchIngressLogs(_) => Some(
+                proto::ResponsePayload::FetchIngressLogs(proto::FetchIngressLogsResponse {
+                    items: vec![],
+                    limit: 50,
+                    has_more_before: false,
+                    has_more_after: false,
+                    snapshot: proto::SnapshotToken {
+                        epoch: Ulid::nil(),
+                        sequence: 0,
+                    },
+                    total_count: Some(0),
+                    position: None,
+                }),
+            ),
+            _ => None,
+        });
+        let client = Client::with_transport(Rc::new(mock.clone()));
+
+        assert_eq!(block_on(client.prefetch()).unwrap(), 1);
+        assert_eq!(mock.take_sent().len(), 2);
+
+        // the hinted request is answered without going to the server, but only once
+        let response = block_on(client.request(newest_page())).unwrap();
+        assert!(matches!(
+            response,
+            proto::ResponsePayload::FetchIngressLogs(_)
+        ));
+        assert!(mock.take_sent().is_empty());
+        block_on(client.request(newest_page())).unwrap();
+        assert_eq!(mock.take_sent().len(), 1);
+    }
+
+    #[test]
+    fn test_subscription_resync() {
+        let mock = MockTransport::new();
+        mock.stub(|payload| match payload {
+            proto::RequestPayload::Subscribe(request) if request.tree == "ingress" => {
+                Some(proto::ResponsePayload::Subscribed)
+            }
+            _ => None,
+        });
+        let client = Client::with_transport(Rc::new(mock.clone()));
+
+        let events = Rc::new(RefCell::new(vec![]));
+        let events2 = events.clone();
+        let subscription_id = block_on(client.subscribe("ingress", move |event| {
+            events2.borrow_mut().push(match event {
+                SubscriptionEvent::Push(_) => "push",
+                SubscriptionEvent::Resync => "resync",
+            });
+        }))
+        .unwrap();
+
+        let log = proto::IngressLog {
+            event_id: Ulid::nil(),
+            date: chrono::Utc::now(),
+            remote_addr: None,
+            method: "POST".to_string(),
+            host: "localhost".to_string(),
+            path: "hooks".to_string(),
+            query: HashMap::new(),
+            headers: HashMap::new(),
+            body: Default::default(),
+            trace_id: None,
+        };
+        let appended = || proto::ResponsePayload::IngressLogAppended(log.clone());
+        mock.push(subscription_id, appended());
+        // a push is lost on the way
+        mock.drop_next();
+        mock.push(subscription_id, appended());
+
+        assert_eq!(*events.borrow(), vec!["push", "resync", "push"]);
+
+        // rejected subscriptions don't linger
+        let result = block_on(client.subscribe("nope", |_| {}));
+        match result {
+            Err(e) => assert_eq!(e.kind(), proto::ErrorKind::NotFound),
+            Ok(_) => panic!("expected the subscription to be rejected"),
+        }
+        assert_eq!(client.inner.subscriptions.borrow().len(), 1);
+    }
+
+    #[test]
+    fn test_queue_until_open() {
+        let mock = MockTransport::new();
+        let client = Client::with_transport(Rc::new(mock.clone()));
+        client.inner.state.set(ConnectionState::Connecting);
+        client.set_queue_capacity(2);
+
+        for _ in 0..3 {
+            let request = client.build_request(proto::RequestPayload::Unsubscribe(
+                proto::UnsubscribeRequest { subscription_id: 0 },
+            ));
+            let (sender, _receiver) = oneshot::channel();
+            client.inner.pending.borrow_mut().insert(
+                request.id,
+                PendingRequest {
+                    sender,
+                    sent: false,
+                    resend: None,
+                },
+            );
+            client
+                .inner
+                .send_or_queue(Outbound::Request(Box::new(request)));
+        }
+        assert!(mock.take_sent().is_empty());
+        // the oldest request was dropped to make room, and no longer awaits a response
+        assert!(!client.inner.pending.borrow().contains_key(&0));
+
+        client.inner.state.set(ConnectionState::Open);
+        client.inner.flush_queue();
+        let sent: Vec<usize> = mock.take_sent().iter().map(|r| r.id).collect();
+        assert_eq!(sent, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_switch_environment() {
+        let mock = MockTransport::new();
+        mock.stub(|payload| match payload {
+            proto::RequestPayload::Subscribe(_) => Some(proto::ResponsePayload::Subscribed),
+            _ => None,
+        });
+        let client = Client::with_transport(Rc::new(mock.clone()));
+        client.add_environment("staging", "ws://staging.example.com/ws");
+        block_on(client.subscribe("ingress", |_| {})).unwrap();
+        client
+            .inner
+            .prefetched
+            .borrow_mut()
+            .insert(vec![1], proto::ResponsePayload::Unsubscribed);
+        // one request sent and awaiting its response, and one queued behind a closed connection
+        let mut receivers = vec![];
+        client.inner.state.set(ConnectionState::Connecting);
+        for sent in [true, false] {
+            let request = client.build_request(proto::RequestPayload::Hello);
+            let (sender, receiver) = oneshot::channel();
+            client.inner.pending.borrow_mut().insert(
+                request.id,
+                PendingRequest {
+                    sender,
+                    sent,
+                    resend: None,
+                },
+            );
+            if !sent {
+                client
+                    .inner
+                    .send_or_queue(Outbound::Request(Box::new(request)));
+            }
+            receivers.push(receiver);
+        }
+        client.inner.state.set(ConnectionState::Open);
+        mock.take_sent();
+
+        client.switch_environment("staging").unwrap();
+        assert_eq!(client.environment(), "staging");
+        for receiver in receivers {
+            assert_eq!(
+                block_on(receiver).unwrap().err(),
+                Some(RequestError::Cancelled)
+            );
+        }
+        assert!(client.inner.pending.borrow().is_empty());
+        assert!(client.inner.queue.borrow().is_empty());
+        assert!(client.inner.prefetched.borrow().is_empty());
+        assert!(client.inner.subscriptions.borrow().is_empty());
+        // the queued request isn't sent on to the new environment, but new ones are
+        client.inner.flush_queue();
+        assert!(mock.take_sent().is_empty());
+        block_on(client.subscribe("ingress", |_| {})).unwrap();
+        assert_eq!(mock.take_sent().len(), 1);
+    }
+
+    #[test]
+    fn test_fail_in_flight_on_disconnect() {
+        let client = Client::with_transport(Rc::new(MockTransport::new()));
+        let mut receivers = vec![];
+        for sent in [true, false] {
+            let (sender, receiver) = oneshot::channel();
+            let id = client.inner.next_request_id.get();
+            client.inner.next_request_id.set(id + 1);
+            client.inner.pending.borrow_mut().insert(
+                id,
+                PendingRequest {
+                    sender,
+                    sent,
+                    resend: None,
+                },
+            );
+            receivers.push(receiver);
+        }
+        // and a mutation which was sent
+        let mutation = client.build_request(proto::RequestPayload::SetKv(proto::SetKvRequest {
+            tenant: "t".to_string(),
+            key: "k".to_string(),
+            value: Some(b"v".to_vec()),
+            expected: None,
+        }));
+        let (sender, mut mutation_receiver) = oneshot::channel();
+        client.inner.pending.borrow_mut().insert(
+            mutation.id,
+            PendingRequest {
+                sender,
+                sent: true,
+                resend: Some(bincode::serialize(&mutation).unwrap()),
+            },
+        );
+
+        client.inner.fail_in_flight();
+        // the sent request fails straight away rather than waiting for its timeout, while the
+        // queued one is still waiting to be sent
+        let mut receivers = receivers.into_iter();
+        let sent = block_on(receivers.next().unwrap()).unwrap();
+        assert_eq!(sent.err(), Some(RequestError::Disconnected));
+        assert_eq!(
+            RequestError::Disconnected.kind(),
+            proto::ErrorKind::Unavailable
+        );
+        assert!(client.inner.pending.borrow().contains_key(&1));
+
+        // the mutation is sent again as it was, to be answered with the result of the first
+        // attempt if that was applied
+        assert!(matches!(mutation_receiver.try_recv(), Ok(None)));
+        assert!(!client.inner.pending.borrow()[&mutation.id].sent);
+        let resent = client.inner.queue.borrow_mut().pop_front();
+        match resent {
+            Some(Outbound::Request(resent)) => {
+                assert_eq!(resent.id, mutation.id);
+                assert_eq!(resent.idempotency_key, mutation.idempotency_key);
+            }
+            _ => panic!("the mutation should be queued to be sent again"),
+        }
+    }
+}
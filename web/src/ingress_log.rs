@@ -0,0 +1,100 @@
+use hydra_proto as proto;
+use wasm_bindgen::prelude::*;
+
+use crate::inspector::RequestInspector;
+
+/// An IngressLog as JavaScript sees it.
+///
+/// The proto types are plain Rust structs, as their maps and byte buffers can't cross the JS
+/// boundary as they are, so logs are handed to JS wrapped in this, whose getters convert each
+/// field as it's read: headers and query as a `Map`, the body as a `Uint8Array`.
+#[wasm_bindgen(js_name = IngressLog)]
+#[derive(Clone)]
+pub struct IngressLogJs {
+    log: proto::IngressLog,
+}
+
+#[wasm_bindgen(js_class = IngressLog)]
+impl IngressLogJs {
+    /// Decode a bincode encoded IngressLog, as it arrives over the wire
+    pub fn from_bytes(data: &[u8]) -> Result<IngressLogJs, JsValue> {
+        let log: proto::IngressLog = bincode::deserialize(data).map_err(|e| {
+            hydra_error::Error::invalid_request(format!("Failed to decode IngressLog: {}", e))
+        })?;
+        Ok(log.into())
+    }
+
+    /// The log's ULID, in its 26 character form
+    #[wasm_bindgen(getter, js_name = eventId)]
+    pub fn event_id(&self) -> String {
+        self.log.event_id.to_string()
+    }
+
+    /// When the request was captured, in RFC 3339
+    #[wasm_bindgen(getter)]
+    pub fn date(&self) -> String {
+        self.log.date.to_rfc3339()
+    }
+
+    /// The address the request came from, eg. `127.0.0.1:52100`, if it's known
+    #[wasm_bindgen(getter, js_name = remoteAddr)]
+    pub fn remote_addr(&self) -> Option<String> {
+        self.log.remote_addr.map(|addr| addr.to_string())
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn method(&self) -> String {
+        self.log.method.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn host(&self) -> String {
+        self.log.host.clone()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn path(&self) -> String {
+        self.log.path.clone()
+    }
+
+    /// The request's headers, by name
+    pub fn headers(&self) -> js_sys::Map {
+        to_map(&self.log.headers)
+    }
+
+    /// The request's query parameters, by name
+    pub fn query(&self) -> js_sys::Map {
+        to_map(&self.log.query)
+    }
+
+    /// The body, copied into a new buffer
+    pub fn body(&self) -> js_sys::Uint8Array {
+        js_sys::Uint8Array::from(&self.log.body[..])
+    }
+
+    /// A detail view of the log
+    pub fn inspector(&self) -> RequestInspector {
+        RequestInspector::new(self.log.clone())
+    }
+}
+
+impl IngressLogJs {
+    /// The wrapped log, for Rust callers
+    pub fn log(&self) -> &proto::IngressLog {
+        &self.log
+    }
+}
+
+impl From<proto::IngressLog> for IngressLogJs {
+    fn from(log: proto::IngressLog) -> Self {
+        Self { log }
+    }
+}
+
+fn to_map(entries: &std::collections::HashMap<String, String>) -> js_sys::Map {
+    let map = js_sys::Map::new();
+    for (name, value) in entries {
+        map.set(&JsValue::from_str(name), &JsValue::from_str(value));
+    }
+    map
+}
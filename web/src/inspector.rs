@@ -0,0 +1,349 @@
+use bytes::Bytes;
+use html_escape::encode_text;
+use hydra_proto as proto;
+use std::fmt::Write;
+use wasm_bindgen::prelude::*;
+
+/// Headers that curl/fetch compute on their own, so we leave them out of the reproduction
+const SKIPPED_HEADERS: &[&str] = &["host", "content-length", "connection", "accept-encoding"];
+
+/// Detail view for a single captured request.
+///
+/// Renders the headers, query and body of an IngressLog as HTML (with JSON syntax highlighting)
+/// and can generate a cURL command or fetch() snippet which reproduces the request.
+/// The body may be supplied later via `set_body`, once it has been fetched separately.
+#[wasm_bindgen]
+pub struct RequestInspector {
+    log: proto::IngressLog,
+}
+
+#[wasm_bindgen]
+impl RequestInspector {
+    /// Construct an inspector from a bincode encoded IngressLog, as it arrives over the wire
+    pub fn from_bytes(data: &[u8]) -> Result<RequestInspector, JsValue> {
+        let log: proto::IngressLog = bincode::deserialize(data).map_err(|e| {
+            hydra_error::Error::invalid_request(format!("Failed to decode IngressLog: {}", e))
+        })?;
+        Ok(Self::new(log))
+    }
+
+    /// Replace the body, e.g. once the full body has been lazily fetched
+    pub fn set_body(&mut self, body: &[u8]) {
+        self.log.body = Bytes::copy_from_slice(body);
+    }
+
+    /// Render the detail view into the given element
+    pub fn mount(&self, element: &web_sys::Element) {
+        element.set_inner_html(&self.render());
+    }
+
+    /// Render the detail view as an HTML fragment
+    pub fn render(&self) -> String {
+        let log = &self.log;
+        let mut html = String::from(r#"<div class="hydra-inspector">"#);
+
+        let _ = write!(
+            html,
+            r#"<div class="hydra-inspector-summary"><span class="method">{}</span> <span class="url">{}</span> <span class="date">{}</span></div>"#,
+            encode_text(&log.method),
+            encode_text(&self.url()),
+            log.date.to_rfc3339(),
+        );
+
+        html.push_str(&render_table("Headers", sorted(&log.headers)));
+        html.push_str(&render_table("Query", sorted(&log.query)));
+
+        html.push_str(r#"<section class="hydra-inspector-body"><h3>Body</h3><pre>"#);
+        html.push_str(&self.render_body());
+        html.push_str("</pre></section></div>");
+        html
+    }
+
+    /// Generate a cURL command which reproduces this request
+    pub fn to_curl(&self) -> String {
+        let log = &self.log;
+        let mut lines = vec![format!(
+            "curl -X {} {}",
+            log.method,
+            shell_quote(&self.url())
+        )];
+
+        for (name, value) in self.replay_headers() {
+            lines.push(format!(
+                "-H {}",
+                shell_quote(&format!("{}: {}", name, value))
+            ));
+        }
+
+        if log.body.is_empty() {
+            return lines.join(" \\\n  ");
+        }
+
+        match std::str::from_utf8(&log.body) {
+            Ok(text) => {
+                lines.push(format!("--data-raw {}", shell_quote(text)));
+                lines.join(" \\\n  ")
+            }
+            Err(_) => {
+                // Binary bodies are piped in via printf so that NUL bytes survive
+                lines.push("--data-binary @-".to_string());
+                let escaped: String = log.body.iter().map(|b| format!("\\x{:02x}", b)).collect();
+                format!("printf '%b' '{}' | {}", escaped, lines.join(" \\\n  "))
+            }
+        }
+    }
+
+    /// Generate a JavaScript fetch() snippet which reproduces this request
+    pub fn to_fetch(&self) -> String {
+        let log = &self.log;
+        let mut options = format!("  method: {},\n", js_string(&log.method));
+
+        let headers = self.replay_headers();
+        if !headers.is_empty() {
+            options.push_str("  headers: {\n");
+            for (name, value) in headers {
+                let _ = writeln!(options, "    {}: {},", js_string(name), js_string(value));
+            }
+            options.push_str("  },\n");
+        }
+
+        if !log.body.is_empty() {
+            let body = match std::str::from_utf8(&log.body) {
+                Ok(text) => js_string(text),
+                Err(_) => format!(
+                    "new Uint8Array([{}])",
+                    log.body
+                        .iter()
+                        .map(|b| b.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            };
+            let _ = writeln!(options, "  body: {},", body);
+        }
+
+        format!("fetch({}, {{\n{}}});", js_string(&self.url()), options)
+    }
+}
+
+impl RequestInspector {
+    pub fn new(log: proto::IngressLog) -> Self {
+        Self { log }
+    }
+
+    /// Reconstruct the URL the request was originally sent to
+    fn url(&self) -> String {
+        let log = &self.log;
+        let scheme = log
+            .headers
+            .get("x-forwarded-proto")
+            .map(|s| s.as_str())
+            .unwrap_or("http");
+
+        let mut url = format!("{}://{}/{}", scheme, log.host, log.path);
+        if !log.query.is_empty() {
+            let query = sorted(&log.query)
+                .into_iter()
+                .map(|(k, v)| format!("{}={}", encode_query(k), encode_query(v)))
+                .collect::<Vec<_>>()
+                .join("&");
+            url.push('?');
+            url.push_str(&query);
+        }
+        url
+    }
+
+    fn replay_headers(&self) -> Vec<(&String, &String)> {
+        sorted(&self.log.headers)
+            .into_iter()
+            .filter(|(name, _)| !SKIPPED_HEADERS.contains(&name.to_ascii_lowercase().as_str()))
+            .collect()
+    }
+
+    fn render_body(&self) -> String {
+        let log = &self.log;
+        if log.body.is_empty() {
+            return r#"<span class="empty">(empty)</span>"#.to_string();
+        }
+
+        let is_json = log
+            .headers
+            .iter()
+            .any(|(k, v)| k.eq_ignore_ascii_case("content-type") && v.contains("json"));
+
+        if is_json {
+            if let Ok(value) = serde_json::from_slice::<serde_json::Value>(&log.body) {
+                if let Ok(pretty) = serde_json::to_string_pretty(&value) {
+                    return highlight_json(&pretty);
+                }
+            }
+        }
+
+        match std::str::from_utf8(&log.body) {
+            Ok(text) => encode_text(text).to_string(),
+            Err(_) => format!(
+                r#"<span class="binary">({} bytes of binary data)</span>"#,
+                log.body.len()
+            ),
+        }
+    }
+}
+
+fn sorted(map: &std::collections::HashMap<String, String>) -> Vec<(&String, &String)> {
+    let mut entries: Vec<_> = map.iter().collect();
+    entries.sort();
+    entries
+}
+
+fn render_table(title: &str, entries: Vec<(&String, &String)>) -> String {
+    let mut html = format!(
+        r#"<section class="hydra-inspector-{}"><h3>{}</h3>"#,
+        title.to_lowercase(),
+        title
+    );
+    if entries.is_empty() {
+        html.push_str(r#"<span class="empty">(none)</span>"#);
+    } else {
+        html.push_str("<table>");
+        for (name, value) in entries {
+            let _ = write!(
+                html,
+                "<tr><th>{}</th><td>{}</td></tr>",
+                encode_text(name),
+                encode_text(value)
+            );
+        }
+        html.push_str("</table>");
+    }
+    html.push_str("</section>");
+    html
+}
+
+/// Wrap the tokens of pretty-printed JSON in spans so they can be styled
+fn highlight_json(json: &str) -> String {
+    let mut html = String::with_capacity(json.len() * 2);
+    let mut chars = json.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        match c {
+            '"' => {
+                let mut end = start + 1;
+                let mut escaped = false;
+                for (i, c) in chars.by_ref() {
+                    end = i + c.len_utf8();
+                    match c {
+                        '\\' if !escaped => escaped = true,
+                        '"' if !escaped => break,
+                        _ => escaped = false,
+                    }
+                }
+                // a string followed by a colon is an object key
+                let is_key = json[end..].trim_start().starts_with(':');
+                let class = if is_key { "json-key" } else { "json-string" };
+                let _ = write!(
+                    html,
+                    r#"<span class="{}">{}</span>"#,
+                    class,
+                    encode_text(&json[start..end])
+                );
+            }
+            '-' | '0'..='9' => {
+                let mut end = start + 1;
+                while let Some(&(i, c)) = chars.peek() {
+                    if c.is_ascii_digit() || matches!(c, '.' | 'e' | 'E' | '+' | '-') {
+                        end = i + 1;
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                let _ = write!(
+                    html,
+                    r#"<span class="json-number">{}</span>"#,
+                    &json[start..end]
+                );
+            }
+            't' | 'f' | 'n' => {
+                let literal = ["true", "false", "null"]
+                    .into_iter()
+                    .find(|l| json[start..].starts_with(l));
+                match literal {
+                    Some(literal) => {
+                        for _ in 1..literal.len() {
+                            chars.next();
+                        }
+                        let _ = write!(html, r#"<span class="json-literal">{}</span>"#, literal);
+                    }
+                    None => html.push(c),
+                }
+            }
+            _ => html.push(c),
+        }
+    }
+    html
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r#"'\''"#))
+}
+
+fn js_string(s: &str) -> String {
+    serde_json::to_string(s).unwrap_or_else(|_| "\"\"".to_string())
+}
+
+fn encode_query(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn test_log(body: &[u8]) -> proto::IngressLog {
+        proto::IngressLog {
+            event_id: ulid::Ulid::nil(),
+            date: chrono::Utc::now(),
+            remote_addr: None,
+            method: "POST".to_string(),
+            host: "example.com".to_string(),
+            path: "hooks/github".to_string(),
+            query: HashMap::new(),
+            headers: HashMap::from([
+                ("content-type".to_string(), "application/json".to_string()),
+                ("content-length".to_string(), body.len().to_string()),
+            ]),
+            body: Bytes::copy_from_slice(body),
+            trace_id: None,
+        }
+    }
+
+    #[test]
+    fn test_curl() {
+        let inspector = RequestInspector::new(test_log(br#"{"it's":1}"#));
+        assert_eq!(
+            inspector.to_curl(),
+            "curl -X POST 'http://example.com/hooks/github' \\\n  -H 'content-type: application/json' \\\n  --data-raw '{\"it'\\''s\":1}'"
+        );
+
+        let inspector = RequestInspector::new(test_log(&[0, 255]));
+        assert!(inspector
+            .to_curl()
+            .starts_with(r"printf '%b' '\x00\xff' | curl"));
+    }
+
+    #[test]
+    fn test_highlight_json() {
+        assert_eq!(
+            highlight_json(r#"{"a": [1, true, "<b>"]}"#),
+            r#"{<span class="json-key">"a"</span>: [<span class="json-number">1</span>, <span class="json-literal">true</span>, <span class="json-string">"&lt;b&gt;"</span>]}"#
+        );
+    }
+}
@@ -1,4 +1,11 @@
+pub mod backoff;
 pub mod client;
+pub mod ingress_log;
+pub mod inspector;
+pub mod lite;
+pub mod logging;
+pub mod mock;
+pub mod transport;
 pub mod utils;
 
 pub use hydra_proto as proto;
@@ -7,7 +14,7 @@ use wasm_bindgen::prelude::*;
 #[cfg(feature = "start")]
 #[wasm_bindgen(start)]
 pub async fn start() -> Result<(), JsValue> {
-    wasm_logger::init(wasm_logger::Config::default());
+    // logging is left to the app, see logging::init_logging
     Ok(())
 }
 
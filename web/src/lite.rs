@@ -0,0 +1,165 @@
+//! Lite mode, for users on metered or slow connections. While it's on, the client asks for less
+//! than the app does: ingress logs come without their bodies, just the size in their previews,
+//! pages are at most LITE_PAGE_SIZE logs, thumbnails are left out, and key watches are debounced
+//! by at least LITE_DEBOUNCE. It changes the requests made after it's switched, not those already
+//! made, so a UI which follows it should refetch what it shows.
+
+use std::time::Duration;
+
+use hydra_proto as proto;
+use wasm_bindgen::{prelude::Closure, JsCast, JsValue};
+use web_sys::{Event, EventTarget};
+
+/// The most logs a page is fetched with in lite mode
+pub const LITE_PAGE_SIZE: usize = 20;
+
+/// The least key watches are debounced by in lite mode
+pub const LITE_DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// A payload cut down to what lite mode asks for. Anything but fetches, live queries and key
+/// watches is left as it is, as is anything already lighter than lite mode would make it.
+pub fn lighten(payload: proto::RequestPayload) -> proto::RequestPayload {
+    match payload {
+        proto::RequestPayload::FetchIngressLogs(request) => {
+            proto::RequestPayload::FetchIngressLogs(lighten_fetch(request))
+        }
+        proto::RequestPayload::LiveQuery(request) => {
+            proto::RequestPayload::LiveQuery(lighten_fetch(request))
+        }
+        proto::RequestPayload::Subscribe(request) if !request.keys.is_empty() => {
+            let debounce_ms = LITE_DEBOUNCE.as_millis() as u32;
+            proto::RequestPayload::Subscribe(proto::SubscribeRequest {
+                debounce_ms: Some(request.debounce_ms.unwrap_or(0).max(debounce_ms)),
+                ..request
+            })
+        }
+        payload => payload,
+    }
+}
+
+fn lighten_fetch(request: proto::FetchIngressLogsRequest) -> proto::FetchIngressLogsRequest {
+    proto::FetchIngressLogsRequest {
+        limit: request.limit.min(LITE_PAGE_SIZE),
+        // a preview of nothing still says how long the body is
+        preview_bytes: Some(0),
+        thumbnails: false,
+        ..request
+    }
+}
+
+/// Whether the browser's connection hints call for lite mode, ie. the user has asked to save
+/// data, or the connection is 3G or slower. None if the browser doesn't give any, as only
+/// Chromium based browsers do.
+pub fn hinted() -> Option<bool> {
+    let connection = connection()?;
+    let save_data = js_sys::Reflect::get(&connection, &"saveData".into())
+        .ok()
+        .and_then(|save_data| save_data.as_bool())
+        .unwrap_or(false);
+    let effective_type = js_sys::Reflect::get(&connection, &"effectiveType".into())
+        .ok()
+        .and_then(|effective_type| effective_type.as_string());
+    Some(lite_for(save_data, effective_type.as_deref()))
+}
+
+fn lite_for(save_data: bool, effective_type: Option<&str>) -> bool {
+    save_data || matches!(effective_type, Some("slow-2g" | "2g" | "3g"))
+}
+
+/// navigator.connection, which fires "change" whenever the hints do
+fn connection() -> Option<EventTarget> {
+    let window = web_sys::window()?;
+    let navigator = js_sys::Reflect::get(&window, &"navigator".into()).ok()?;
+    js_sys::Reflect::get(&navigator, &"connection".into())
+        .ok()
+        .filter(|connection| !connection.is_undefined() && !connection.is_null())
+        .and_then(|connection: JsValue| connection.dyn_into().ok())
+}
+
+/// Calls back with whether the hints call for lite mode whenever they change, until dropped
+pub struct HintListener {
+    connection: EventTarget,
+    on_change: Closure<dyn FnMut(Event)>,
+}
+
+impl HintListener {
+    /// None if the browser doesn't give any hints
+    pub fn new(callback: impl Fn(bool) + 'static) -> Option<HintListener> {
+        let connection = connection()?;
+        let on_change = Closure::<dyn FnMut(Event)>::wrap(Box::new(move |_| {
+            if let Some(lite) = hinted() {
+                callback(lite);
+            }
+        }));
+        connection
+            .add_event_listener_with_callback("change", on_change.as_ref().unchecked_ref())
+            .ok()?;
+        Some(HintListener {
+            connection,
+            on_change,
+        })
+    }
+}
+
+impl Drop for HintListener {
+    fn drop(&mut self) {
+        let _ = self
+            .connection
+            .remove_event_listener_with_callback("change", self.on_change.as_ref().unchecked_ref());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lighten() {
+        let fetch = |limit, preview_bytes| {
+            proto::RequestPayload::FetchIngressLogs(proto::FetchIngressLogsRequest {
+                direction: proto::Direction::Descending,
+                limit,
+                cursor: proto::PaginatedCursor::StartingWith(vec![]),
+                preview_bytes,
+                snapshot: None,
+                source: Some("github".to_string()),
+                filter: None,
+                thumbnails: true,
+            })
+        };
+        let proto::RequestPayload::FetchIngressLogs(light) = lighten(fetch(50, Some(256))) else {
+            panic!("not a fetch");
+        };
+        assert_eq!(light.limit, LITE_PAGE_SIZE);
+        assert_eq!(light.preview_bytes, Some(0));
+        assert!(!light.thumbnails);
+        assert_eq!(light.source.as_deref(), Some("github"));
+        let proto::RequestPayload::FetchIngressLogs(light) = lighten(fetch(5, None)) else {
+            panic!("not a fetch");
+        };
+        assert_eq!(light.limit, 5);
+
+        // key watches are debounced, by at least LITE_DEBOUNCE, but other subscriptions aren't
+        let subscribe = |keys: Vec<Vec<u8>>, debounce_ms| {
+            let request = proto::SubscribeRequest {
+                tree: "kv".to_string(),
+                sample_above: None,
+                keys,
+                debounce_ms,
+                prefix: None,
+            };
+            match lighten(proto::RequestPayload::Subscribe(request)) {
+                proto::RequestPayload::Subscribe(request) => request.debounce_ms,
+                _ => panic!("not a subscribe"),
+            }
+        };
+        assert_eq!(subscribe(vec![b"a".to_vec()], None), Some(2000));
+        assert_eq!(subscribe(vec![b"a".to_vec()], Some(5000)), Some(5000));
+        assert_eq!(subscribe(vec![], None), None);
+
+        assert!(lite_for(true, Some("4g")));
+        assert!(lite_for(false, Some("2g")));
+        assert!(!lite_for(false, Some("4g")));
+        assert!(!lite_for(false, None));
+    }
+}
@@ -0,0 +1,153 @@
+use log::{LevelFilter, Log, Metadata, Record};
+use wasm_bindgen::prelude::*;
+
+/// Which messages the client logs. Messages are filtered by the level of the most specific
+/// module they fall under, or the default level if they don't fall under any of them.
+#[wasm_bindgen]
+#[derive(Clone, Debug)]
+pub struct LoggingConfig {
+    level: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+#[wasm_bindgen]
+impl LoggingConfig {
+    /// Log at `level` ("off", "error", "warn", "info", "debug" or "trace") by default
+    #[wasm_bindgen(constructor)]
+    pub fn new(level: &str) -> Result<LoggingConfig, JsValue> {
+        Ok(LoggingConfig {
+            level: parse_level(level)?,
+            modules: Vec::new(),
+        })
+    }
+
+    /// Log a module, and the modules under it, at a different level, eg.
+    /// `module("hydra_web::client", "warn")`
+    pub fn module(mut self, module: &str, level: &str) -> Result<LoggingConfig, JsValue> {
+        let level = parse_level(level)?;
+        self.modules.retain(|(existing, _)| existing != module);
+        self.modules.push((module.to_string(), level));
+        Ok(self)
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: LevelFilter::Info,
+            modules: Vec::new(),
+        }
+    }
+}
+
+impl LoggingConfig {
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter(|(module, _)| {
+                target == module
+                    || target
+                        .strip_prefix(module.as_str())
+                        .is_some_and(|rest| rest.starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.level, |(_, level)| *level)
+    }
+
+    /// The most verbose level anything is logged at
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.level, |max, level| max.max(level))
+    }
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter, JsValue> {
+    level.parse().map_err(|_| {
+        hydra_error::Error::invalid_request(format!("Unknown log level: {}", level)).into()
+    })
+}
+
+/// Filters records by the config before handing them to the backend
+struct Logger {
+    config: LoggingConfig,
+    backend: fn(&Record),
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.config.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            (self.backend)(record);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Send log records to the browser console. Which backend writes them is chosen at build time:
+/// the `tracing-wasm` feature forwards them to tracing and installs tracing-wasm as its
+/// subscriber, otherwise the `console-log` feature writes them with console_log. With neither,
+/// this does nothing, and no logging code is bundled.
+///
+/// Logging can only be set up once, so apps which set up their own logger shouldn't call this.
+#[wasm_bindgen]
+pub fn init_logging(config: LoggingConfig) -> Result<(), JsValue> {
+    let Some(backend) = backend()? else {
+        return Ok(());
+    };
+    log::set_max_level(config.max_level());
+    log::set_boxed_logger(Box::new(Logger { config, backend }))
+        .map_err(|e| hydra_error::Error::conflict(format!("Logging is already set up: {}", e)))?;
+    Ok(())
+}
+
+#[cfg(feature = "tracing-wasm")]
+fn backend() -> Result<Option<fn(&Record)>, JsValue> {
+    tracing_wasm::try_set_as_global_default()
+        .map_err(|e| hydra_error::Error::conflict(format!("Tracing is already set up: {}", e)))?;
+    Ok(Some(|record| {
+        let _ = tracing_log::format_trace(record);
+    }))
+}
+
+#[cfg(all(feature = "console-log", not(feature = "tracing-wasm")))]
+fn backend() -> Result<Option<fn(&Record)>, JsValue> {
+    Ok(Some(console_log::log))
+}
+
+#[cfg(not(any(feature = "console-log", feature = "tracing-wasm")))]
+fn backend() -> Result<Option<fn(&Record)>, JsValue> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_module_levels() {
+        let config = LoggingConfig {
+            level: LevelFilter::Warn,
+            modules: vec![
+                ("hydra_web".to_string(), LevelFilter::Info),
+                ("hydra_web::client".to_string(), LevelFilter::Error),
+                ("hydra_web::inspector".to_string(), LevelFilter::Trace),
+            ],
+        };
+        assert_eq!(config.level_for("hydra_web::client"), LevelFilter::Error);
+        assert_eq!(config.level_for("hydra_web::mock"), LevelFilter::Info);
+        // only whole path segments match
+        assert_eq!(config.level_for("hydra_web_extra"), LevelFilter::Warn);
+        assert_eq!(config.level_for("leptos"), LevelFilter::Warn);
+        assert_eq!(config.max_level(), LevelFilter::Trace);
+
+        let config = config.module("hydra_web::inspector", "off").unwrap();
+        assert_eq!(config.level_for("hydra_web::inspector"), LevelFilter::Off);
+        assert_eq!(config.max_level(), LevelFilter::Info);
+    }
+}
@@ -0,0 +1,156 @@
+use hydra_proto as proto;
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
+
+use crate::transport::{MessageHandler, Transport};
+
+type Stub = Box<dyn Fn(&proto::RequestPayload) -> Option<proto::ResponsePayload>>;
+
+#[derive(Default)]
+struct MockInner {
+    stubs: RefCell<Vec<Stub>>,
+    handler: RefCell<Option<Rc<MessageHandler>>>,
+    sent: RefCell<Vec<proto::Request>>,
+    next_sequence: Cell<u64>,
+}
+
+/// An in-memory Transport for application tests.
+///
+/// Responses are produced by stubs, which are tried from the most recently added to the oldest
+/// until one returns Some. Requests no stub answers get a ResponsePayload::Error. Responses are
+/// delivered synchronously from within `send`, which keeps tests deterministic. Clones share
+/// state, so keep one around to add stubs, emit pushes and inspect what was sent.
+#[derive(Clone, Default)]
+pub struct MockTransport {
+    inner: Rc<MockInner>,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer requests matching a payload type, eg.
+    /// `mock.stub(|p| matches!(p, RequestPayload::GetKv(_)).then(|| ResponsePayload::GetKv(..)))`
+    pub fn stub<F>(&self, stub: F)
+    where
+        F: Fn(&proto::RequestPayload) -> Option<proto::ResponsePayload> + 'static,
+    {
+        self.inner.stubs.borrow_mut().push(Box::new(stub));
+    }
+
+    /// Emit a synthetic server push, as if for the subscription created by request `request_id`
+    pub fn push(&self, request_id: usize, payload: proto::ResponsePayload) {
+        let sequence = self.inner.next_sequence.get();
+        self.inner.next_sequence.set(sequence + 1);
+        self.deliver(proto::Message::Response(proto::Response {
+            request_id,
+            sequence,
+            trace_id: None,
+            payload,
+        }));
+    }
+
+    /// Skip a sequence number, as if the server had dropped a message
+    pub fn drop_next(&self) {
+        self.inner
+            .next_sequence
+            .set(self.inner.next_sequence.get() + 1);
+    }
+
+    /// Take the requests sent through the transport since the last call
+    pub fn take_sent(&self) -> Vec<proto::Request> {
+        self.inner.sent.take()
+    }
+
+    fn deliver(&self, message: proto::Message) {
+        // clone the handler out so that it can call back into the transport
+        let handler = self.inner.handler.borrow().clone();
+        if let Some(handler) = handler {
+            handler(message);
+        }
+    }
+}
+
+impl Transport for MockTransport {
+    fn send(&self, request: proto::Request) {
+        let payload = self
+            .inner
+            .stubs
+            .borrow()
+            .iter()
+            .rev()
+            .find_map(|stub| stub(&request.payload))
+            .unwrap_or_else(|| {
+                proto::ResponsePayload::Error(proto::ErrorPayload::new(
+                    proto::ErrorKind::NotFound,
+                    "No stub for request",
+                ))
+            });
+
+        let request_id = request.id;
+        self.inner.sent.borrow_mut().push(request);
+        self.push(request_id, payload);
+    }
+
+    fn set_handler(&self, handler: MessageHandler) {
+        self.inner.handler.replace(Some(Rc::new(handler)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_kv(key: &str) -> proto::Request {
+        proto::Request {
+            id: 1,
+            idempotency_key: None,
+            trace_id: None,
+            payload: proto::RequestPayload::GetKv(proto::GetKvRequest {
+                tenant: "test".to_string(),
+                key: key.to_string(),
+            }),
+        }
+    }
+
+    #[test]
+    fn test_mock_transport() {
+        let mock = MockTransport::new();
+        let received = Rc::new(RefCell::new(vec![]));
+        let received2 = received.clone();
+        mock.set_handler(Box::new(move |message| {
+            if let proto::Message::Response(response) = message {
+                received2.borrow_mut().push(response);
+            }
+        }));
+
+        mock.stub(|payload| match payload {
+            proto::RequestPayload::GetKv(request) => {
+                Some(proto::ResponsePayload::GetKv(proto::GetKvResponse {
+                    value: Some(request.key.clone().into_bytes()),
+                }))
+            }
+            _ => None,
+        });
+
+        mock.send(get_kv("hello"));
+        mock.push(1, proto::ResponsePayload::Unsubscribed);
+
+        let received = received.borrow();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[1].sequence, 1);
+        assert!(matches!(
+            &received[0].payload,
+            proto::ResponsePayload::GetKv(proto::GetKvResponse { value: Some(v) }) if v == b"hello"
+        ));
+        assert!(matches!(
+            received[1].payload,
+            proto::ResponsePayload::Unsubscribed
+        ));
+        assert_eq!(mock.take_sent().len(), 1);
+        assert!(mock.take_sent().is_empty());
+    }
+}
@@ -0,0 +1,18 @@
+use hydra_proto as proto;
+
+/// Callback invoked for every message received from the server
+pub type MessageHandler = Box<dyn Fn(proto::Message)>;
+
+/// Carries protocol messages between a client and a hydra server.
+///
+/// The client is written against this rather than a WebSocket directly, so that applications
+/// can swap in a MockTransport for unit tests. Implementations must not invoke the handler
+/// while `send` is holding any borrow the handler might need.
+pub trait Transport {
+    /// Send a request to the server. Delivery is best-effort; failures are reported via the
+    /// connection state rather than here.
+    fn send(&self, request: proto::Request);
+
+    /// Set the handler for responses and pushes arriving from the server
+    fn set_handler(&self, handler: MessageHandler);
+}
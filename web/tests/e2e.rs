@@ -0,0 +1,193 @@
+//! End-to-end tests of the web client against a real server, in a headless browser. They're
+//! skipped unless built with HYDRA_E2E_URL set to the server's WebSocket endpoint, and expect the
+//! server to have loaded tests/fixtures. tests/e2e.sh starts the servers, from this tree and from
+//! an earlier version, and runs them against each.
+//!
+//! The protocol has no authentication step, so the flows covered are connecting, fetching and
+//! paginating, subscribing, and reconnecting and resuming where a pagination left off.
+
+#![cfg(target_arch = "wasm32")]
+
+use std::{future::Future, time::Duration};
+
+use futures::{
+    channel::mpsc,
+    future::{select, Either, FutureExt},
+    StreamExt,
+};
+use gloo_timers::future::sleep;
+use hydra_web::{
+    client::{Client, ClientConfig, SubscriptionEvent},
+    proto,
+};
+use wasm_bindgen_test::*;
+
+wasm_bindgen_test_configure!(run_in_browser);
+
+const SERVER_URL: Option<&str> = option_env!("HYDRA_E2E_URL");
+
+/// The event ids of the logs in tests/fixtures/ingress.ndjson, oldest first
+const FIXTURE_IDS: [&str; 5] = [
+    "01HZX3K6Y0QJ7V8T4R2N5M9B1A",
+    "01HZX3K6Y0QJ7V8T4R2N5M9B1B",
+    "01HZX3K6Y0QJ7V8T4R2N5M9B1C",
+    "01HZX3K6Y0QJ7V8T4R2N5M9B1D",
+    "01HZX3K6Y0QJ7V8T4R2N5M9B1E",
+];
+
+/// A client connected to the server under test, or None if there isn't one
+async fn connect() -> Option<Client> {
+    let Some(url) = SERVER_URL else {
+        console_log!("HYDRA_E2E_URL isn't set, skipping");
+        return None;
+    };
+    let client = Client::new(Some(ClientConfig::new().url(url))).unwrap();
+    within(client.ready()).await;
+    Some(client)
+}
+
+/// Fail rather than hang if the server doesn't come through
+async fn within<T>(future: impl Future<Output = T>) -> T {
+    match select(
+        future.boxed_local(),
+        sleep(Duration::from_secs(10)).boxed_local(),
+    )
+    .await
+    {
+        Either::Left((value, _)) => value,
+        Either::Right(_) => panic!("Timed out waiting for the server"),
+    }
+}
+
+fn page_request(
+    cursor: proto::PaginatedCursor,
+    snapshot: Option<proto::SnapshotToken>,
+) -> proto::RequestPayload {
+    proto::RequestPayload::FetchIngressLogs(proto::FetchIngressLogsRequest {
+        direction: proto::Direction::Ascending,
+        limit: 2,
+        cursor,
+        preview_bytes: None,
+        snapshot,
+        source: None,
+        filter: None,
+        thumbnails: false,
+    })
+}
+
+async fn fetch(client: &Client, request: proto::RequestPayload) -> proto::FetchIngressLogsResponse {
+    match within(client.request(request)).await.unwrap() {
+        proto::ResponsePayload::FetchIngressLogs(page) => page,
+        _ => panic!("Unexpected response to FetchIngressLogs"),
+    }
+}
+
+/// The next page after `page`, with the snapshot it was read at
+fn next_page(page: &proto::FetchIngressLogsResponse) -> proto::RequestPayload {
+    let last = page.items.last().expect("an empty page has no next page");
+    page_request(
+        proto::PaginatedCursor::After(last.key.clone()),
+        Some(page.snapshot),
+    )
+}
+
+fn event_ids(page: &proto::FetchIngressLogsResponse) -> Vec<String> {
+    page.items
+        .iter()
+        .map(|item| item.log.event_id.to_string())
+        .collect()
+}
+
+#[wasm_bindgen_test]
+async fn test_connect() {
+    let Some(client) = connect().await else {
+        return;
+    };
+    match within(client.request(proto::RequestPayload::Hello)).await {
+        Ok(proto::ResponsePayload::Hello(hello)) => assert!(!hello.session.is_nil()),
+        _ => panic!("Unexpected response to Hello"),
+    }
+}
+
+#[wasm_bindgen_test]
+async fn test_paginate() {
+    let Some(client) = connect().await else {
+        return;
+    };
+    let mut page = fetch(
+        &client,
+        page_request(proto::PaginatedCursor::StartingWith(vec![]), None),
+    )
+    .await;
+    let mut fetched = event_ids(&page);
+    assert!(!page.has_more_before);
+    while page.has_more_after {
+        page = fetch(&client, next_page(&page)).await;
+        assert!(page.has_more_before);
+        fetched.extend(event_ids(&page));
+    }
+    assert_eq!(fetched, FIXTURE_IDS);
+}
+
+/// Watch a fresh key, then set it, and check the change is pushed
+async fn assert_pushed(client: &Client) {
+    let tenant = "e2e";
+    let key = format!("watched-{}", js_sys::Math::random());
+    // keys in the kv tree are scoped by tenant, see handler/kv.rs
+    let scoped = format!("{}\0{}", tenant, key).into_bytes();
+    let (sender, mut changes) = mpsc::unbounded();
+    within(
+        client.watch_keys("kv", vec![scoped.clone()], None, move |event| {
+            if let SubscriptionEvent::Push(payload) = event {
+                if let proto::ResponsePayload::KeyChanged(changed) = *payload {
+                    let _ = sender.unbounded_send(changed);
+                }
+            }
+        }),
+    )
+    .await
+    .unwrap();
+
+    let set = proto::RequestPayload::SetKv(proto::SetKvRequest {
+        tenant: tenant.to_string(),
+        key,
+        value: Some(b"hello".to_vec()),
+        expected: None,
+    });
+    within(client.request(set)).await.unwrap();
+    let changed = within(changes.next()).await.unwrap();
+    assert_eq!(changed.key, scoped);
+    assert_eq!(changed.value.as_deref(), Some(&b"hello"[..]));
+}
+
+#[wasm_bindgen_test]
+async fn test_subscribe() {
+    let Some(client) = connect().await else {
+        return;
+    };
+    assert_pushed(&client).await;
+}
+
+#[wasm_bindgen_test]
+async fn test_reconnect_and_resume() {
+    let Some(client) = connect().await else {
+        return;
+    };
+    let first = fetch(
+        &client,
+        page_request(proto::PaginatedCursor::StartingWith(vec![]), None),
+    )
+    .await;
+
+    // a new connection to the same server, as after a reconnect
+    client.add_environment("again", SERVER_URL.unwrap());
+    client.switch_environment("again").unwrap();
+    within(client.ready()).await;
+
+    // the snapshot carries over, so paging picks up where it left off
+    let second = fetch(&client, next_page(&first)).await;
+    assert_eq!(event_ids(&second), FIXTURE_IDS[2..4]);
+
+    // subscriptions belong to the connection they were made on, and work on the new one
+    assert_pushed(&client).await;
+}
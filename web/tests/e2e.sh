@@ -0,0 +1,57 @@
+#!/bin/sh
+# Run the browser end-to-end tests in tests/e2e.rs against a real server built from this tree, and
+# then against one built from $HYDRA_E2E_PREVIOUS, if it names a git ref, eg. the last release. The
+# web client is built from this tree both times, so a change to the protocol which breaks clients
+# talking to servers which haven't been upgraded yet fails the second run.
+#
+#     HYDRA_E2E_PREVIOUS=v0.1.0 web/tests/e2e.sh --firefox
+#
+# Needs wasm-pack and a headless browser, --chrome by default. Each server gets a throwaway
+# database with tests/fixtures loaded, on the usual port, 9797, which has to be free.
+set -eu
+
+browser=${1:---chrome}
+root=$(cd "$(dirname "$0")/../.." && pwd)
+work=$(mktemp -d)
+server=
+
+cleanup() {
+    if [ -n "$server" ]; then
+        kill "$server" 2>/dev/null || true
+        wait "$server" 2>/dev/null || true
+    fi
+    if [ -d "$work/previous" ]; then
+        git -C "$root" worktree remove --force "$work/previous"
+    fi
+    rm -rf "$work"
+}
+trap cleanup EXIT
+
+# run_against <name> <source tree>
+run_against() {
+    echo "--- web client against the $1 server"
+    (cd "$2" && cargo build --quiet --bin hydra-server)
+    "$2/target/debug/hydra-server" --db-path "$work/$1" --fixtures "$root/web/tests/fixtures" \
+        > "$work/$1.log" 2>&1 &
+    server=$!
+    tries=0
+    until curl --silent --fail http://127.0.0.1:9797/health > /dev/null; do
+        tries=$((tries + 1))
+        if [ "$tries" -gt 100 ] || ! kill -0 "$server" 2>/dev/null; then
+            echo "The $1 server didn't start:"
+            cat "$work/$1.log"
+            exit 1
+        fi
+        sleep 0.2
+    done
+    HYDRA_E2E_URL=ws://127.0.0.1:9797/ws wasm-pack test --headless "$browser" "$root/web" -- --test e2e
+    kill "$server"
+    wait "$server" 2>/dev/null || true
+    server=
+}
+
+run_against current "$root"
+if [ -n "${HYDRA_E2E_PREVIOUS:-}" ]; then
+    git -C "$root" worktree add --detach "$work/previous" "$HYDRA_E2E_PREVIOUS"
+    run_against previous "$work/previous"
+fi
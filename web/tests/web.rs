@@ -11,3 +11,32 @@ wasm_bindgen_test_configure!(run_in_browser);
 fn pass() {
     assert_eq!(1 + 1, 2);
 }
+
+#[wasm_bindgen_test]
+fn ingress_log_getters() {
+    use std::collections::HashMap;
+
+    use hydra_web::{ingress_log::IngressLogJs, proto};
+    use wasm_bindgen::JsValue;
+
+    let log = proto::IngressLog {
+        event_id: ulid::Ulid::nil(),
+        date: chrono::Utc::now(),
+        remote_addr: Some("127.0.0.1:52100".parse().unwrap()),
+        method: "POST".to_string(),
+        host: "example.com".to_string(),
+        path: "hooks/github".to_string(),
+        query: HashMap::from([("a".to_string(), "1".to_string())]),
+        headers: HashMap::from([("content-type".to_string(), "application/json".to_string())]),
+        body: bytes::Bytes::from_static(b"{}"),
+    };
+    let js = IngressLogJs::from_bytes(&bincode::serialize(&log).unwrap()).unwrap();
+    assert_eq!(js.event_id(), ulid::Ulid::nil().to_string());
+    assert_eq!(js.remote_addr().as_deref(), Some("127.0.0.1:52100"));
+    assert_eq!(
+        js.headers().get(&JsValue::from_str("content-type")),
+        JsValue::from_str("application/json")
+    );
+    assert_eq!(js.query().size(), 1);
+    assert_eq!(js.body().to_vec(), b"{}");
+}